@@ -0,0 +1,29 @@
+#![no_main]
+
+use hydroxos_fuzz::io::keymap::{get_keymap, KeyboardLockState, Keycode, KeycodeMap, ModifierState};
+use libfuzzer_sys::fuzz_target;
+
+// Each 4-byte chunk of the input describes a key event: 3 bytes of raw keycode, which may not be a valid keycode, and a byte whose lowest bit
+// says whether the key was pressed or released. The lock and modifier state built up by the events is used to look up every key in the
+// keymaps.
+fuzz_target!(|data: &[u8]| {
+    let keymaps = [KeycodeMap::fallback(), get_keymap("qwerty-us").unwrap()];
+    let mut locks = KeyboardLockState::none();
+    let mut mods = ModifierState::none();
+
+    for chunk in data.chunks_exact(4) {
+        let Ok(key) = Keycode::try_from(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], 0]) as usize) else {
+            continue;
+        };
+        let pressed = chunk[3] & 1 != 0;
+
+        if pressed {
+            locks.handle_key_pressed(key);
+        }
+        mods.handle_key_state_changed(key, pressed);
+
+        for keymap in keymaps {
+            let _ = keymap.get(key, locks, mods);
+        }
+    }
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+use hydroxos_fuzz::ps2::scancode::Scancode;
+use hydroxos_fuzz::ps2::scancode_2_map;
+use libfuzzer_sys::fuzz_target;
+
+// Bytes are fed to the parser one at a time, just like the PS/2 keyboard driver does, checking the invariants that the driver relies on: a
+// scancode is parsed as soon as its last byte arrives, so it always takes up every byte received so far, and no scancode is longer than the
+// driver's buffer.
+fuzz_target!(|data: &[u8]| {
+    let mut buf = [0; 5];
+    let mut len = 0;
+
+    for &b in data {
+        buf[len] = b;
+        len += 1;
+
+        match Scancode::try_parse(&buf[..len]) {
+            Some((scancode, parsed_len)) => {
+                assert_eq!(len, parsed_len);
+                len = 0;
+
+                let _ = scancode_2_map::MAP.get(scancode.key);
+            },
+            None => {
+                assert!(len < buf.len());
+            },
+        }
+    }
+});
@@ -0,0 +1,2 @@
+#[path = "../../../kernel/src/io/keymap/mod.rs"]
+pub mod keymap;
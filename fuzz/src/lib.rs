@@ -0,0 +1,13 @@
+//! Host builds of the kernel modules that parse untrusted input, for use by the fuzz targets.
+//!
+//! Each module is included straight from the kernel's source tree, at the same path relative to the crate root that it has in the kernel
+//! so that its `crate::` imports still resolve. This only works for modules that do not depend on anything else in the kernel, so parsers
+//! should be kept free of such dependencies if they are to be fuzzed.
+//!
+//! The targets currently cover PS/2 scancode decoding and keymap lookups. ELF loading, filesystem and network packet parsers should get
+//! targets here once the kernel has them.
+
+extern crate alloc;
+
+pub mod io;
+pub mod ps2;
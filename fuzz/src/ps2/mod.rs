@@ -0,0 +1,4 @@
+#[path = "../../../kernel/src/arch/x86_64/dev/ps2/scancode.rs"]
+pub mod scancode;
+#[path = "../../../kernel/src/arch/x86_64/dev/ps2/scancode_2_map.rs"]
+pub mod scancode_2_map;
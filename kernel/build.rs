@@ -1,5 +1,52 @@
+use std::env;
+use std::process::Command;
+
+/// Runs a command and returns its trimmed output, or `None` if it could not be run or failed.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+fn emit_build_info() {
+    let commit = match command_output("git", &["rev-parse", "--short=12", "HEAD"]) {
+        Some(commit) => match command_output("git", &["status", "--porcelain", "--untracked-files=no"]) {
+            Some(status) if !status.is_empty() => format!("{}-dirty", commit),
+            _ => commit,
+        },
+        None => String::from("unknown"),
+    };
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| String::from("unknown"));
+
+    let mut features: Vec<_> = env::vars()
+        .filter_map(|(key, _)| Some(key.strip_prefix("CARGO_FEATURE_")?.to_ascii_lowercase()))
+        .collect();
+
+    features.sort();
+
+    println!("cargo::rustc-env=HYDROXOS_GIT_COMMIT={}", commit);
+    println!("cargo::rustc-env=HYDROXOS_RUSTC_VERSION={}", rustc_version);
+    println!("cargo::rustc-env=HYDROXOS_PROFILE={}", env::var("PROFILE").unwrap_or_default());
+    println!("cargo::rustc-env=HYDROXOS_FEATURES={}", features.join(","));
+
+    // Rebuild when a commit is made or checked out, or when the working tree is staged, so that the embedded commit stays accurate
+    println!("cargo::rerun-if-changed=../.git/HEAD");
+    println!("cargo::rerun-if-changed=../.git/index");
+}
+
 fn main() {
     println!("cargo::rustc-link-arg=-Tlinker.ld");
     println!("cargo::rerun-if-changed=linker.ld");
     println!("cargo::rerun-if-env-changed=HYDROXOS_OPTIONS");
+
+    if std::env::var_os("CARGO_FEATURE_UNWIND").is_some() {
+        println!("cargo::rustc-link-arg=--eh-frame-hdr");
+    }
+
+    emit_build_info();
 }
@@ -0,0 +1,9 @@
+//! Machine code is never executed by the simulated architecture, so it has no instruction encoding to decode.
+
+/// The maximum length of a single instruction in bytes.
+pub const MAX_INSTRUCTION_LEN: usize = 1;
+
+/// Always returns [`None`], since the simulated architecture has no instruction encoding.
+pub fn instruction_len(_bytes: &[u8]) -> Option<usize> {
+    None
+}
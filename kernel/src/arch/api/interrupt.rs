@@ -1,35 +1,233 @@
-use crate::arch::regs::SavedBasicRegisters;
+//! A fake interrupt controller for the simulated architecture.
+//!
+//! IRQs are raised by calling [`inject_irq`], which marks them as pending. Pending IRQs are delivered in order of priority (lowest IRQ
+//! number first) as soon as interrupts are enabled on the simulated CPU, either immediately if they already were or once [`enable`] is
+//! next called, so interrupt handlers can run at any point where they could on real hardware. IRQs are also held pending while the
+//! interrupt level is at or above their own.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use super::regs::SavedBasicRegisters;
+use super::tls::TlsBlock;
+use crate::sched;
+use crate::sync::level::InterruptLevel;
+use crate::sync::UninterruptibleSpinlock;
+
+pub const NUM_IRQS: usize = 16;
+
+pub type InterruptHandler = Box<dyn Fn(&mut InterruptFrame) + Send + Sync>;
+const EMPTY_INTERRUPT: Option<InterruptHandler> = None;
+
+static IRQ_HANDLERS: UninterruptibleSpinlock<[Option<InterruptHandler>; NUM_IRQS]> =
+    UninterruptibleSpinlock::new([EMPTY_INTERRUPT; NUM_IRQS]);
+
+static IRQ_COUNTS: [AtomicU64; NUM_IRQS] = [const { AtomicU64::new(0) }; NUM_IRQS];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+// Atomics are used rather than a spinlock, since releasing a spinlock can enable interrupts and deliver pending IRQs
+static IRQ_LEVELS: [AtomicU8; NUM_IRQS] = [const { AtomicU8::new(InterruptLevel::Device as u8) }; NUM_IRQS];
+static LEVEL: AtomicU8 = AtomicU8::new(InterruptLevel::Passive as u8);
+static BLOCKED: AtomicU32 = AtomicU32::new(0);
+
+/// The frame of the context interrupted by the IRQ currently being delivered. Since thread contexts are never actually executed, this only
+/// records which thread's registers would be resumed once the IRQ returns.
+static CURRENT_FRAME: UninterruptibleSpinlock<InterruptFrame> = UninterruptibleSpinlock::new(InterruptFrame::new());
 
 #[non_exhaustive]
 #[derive(Debug, Clone)]
-pub struct InterruptFrame {}
+pub struct InterruptFrame {
+    pub regs: SavedBasicRegisters,
+    pub is_idle: bool,
+}
 
 impl InterruptFrame {
+    const fn new() -> InterruptFrame {
+        InterruptFrame {
+            regs: SavedBasicRegisters::new(),
+            is_idle: true,
+        }
+    }
+
     pub fn save(&self, saved: &mut SavedBasicRegisters) {
-        unimplemented!()
+        *saved = self.regs.clone();
     }
 
     pub fn restore(&mut self, saved: &SavedBasicRegisters) {
-        unimplemented!()
+        self.regs = saved.clone();
+        self.is_idle = false;
     }
 
     pub fn set_to_idle(&mut self) {
-        unimplemented!()
+        self.regs = SavedBasicRegisters::new();
+        self.is_idle = true;
     }
 
-    pub fn setup_kernel_mode_thread_locals(&mut self) {
-        unimplemented!()
-    }
+    /// Thread-local storage is not simulated, so there is nothing to set up.
+    pub fn setup_kernel_mode_thread_locals(&mut self, _tls: Option<&TlsBlock>) {}
 }
 
 pub fn are_enabled() -> bool {
-    unimplemented!()
+    ENABLED.load(Ordering::Relaxed)
 }
 
+/// Enables interrupts on the simulated CPU, immediately delivering any IRQs that were injected while they were disabled.
 pub fn enable() {
-    unimplemented!()
+    ENABLED.store(true, Ordering::Relaxed);
+    deliver_pending();
 }
 
 pub fn disable() {
-    unimplemented!()
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// The simulated machine only has a single CPU core, so there are never any other cores to stop.
+pub fn stop_other_cpus() -> usize {
+    0
+}
+
+/// The state a CPU core was interrupted in when it was stopped by [`stop_other_cpus`].
+#[derive(Debug, Clone)]
+pub struct StoppedCpu {
+    pub hw_id: u32,
+    pub regs: SavedBasicRegisters,
+}
+
+/// Since no other cores are ever stopped, there is never any saved state to return.
+pub fn stopped_cpus() -> impl Iterator<Item = &'static StoppedCpu> {
+    core::iter::empty()
+}
+
+/// The simulated machine only has a single CPU core, so there is never anything to send an inter-processor interrupt to.
+pub fn send_call_ipi(_hw_id: u32) -> bool {
+    false
+}
+
+/// Registers the handler for an IRQ of the fake interrupt controller.
+///
+/// # Safety
+///
+/// The handler must be safe to run whenever interrupts are enabled on the simulated CPU.
+///
+/// # Panics
+///
+/// This function will panic if the IRQ already has a handler registered.
+pub unsafe fn register_irq(n: usize, handler: InterruptHandler) {
+    let mut handlers = IRQ_HANDLERS.lock();
+
+    assert!(handlers[n].is_none());
+    handlers[n] = Some(handler);
+}
+
+/// Unregisters the handler for an IRQ of the fake interrupt controller.
+///
+/// # Safety
+///
+/// Nothing may rely on the IRQ being handled after this is called.
+///
+/// # Panics
+///
+/// This function will panic if the IRQ does not have a handler registered.
+pub unsafe fn unregister_irq(n: usize) {
+    let mut handlers = IRQ_HANDLERS.lock();
+
+    assert!(handlers[n].is_some());
+    handlers[n] = None;
+}
+
+/// Gets the number of times that an IRQ of the fake interrupt controller has been delivered to a registered handler.
+pub fn irq_count(n: usize) -> u64 {
+    IRQ_COUNTS[n].load(Ordering::Relaxed)
+}
+
+fn update_blocked() {
+    let level = LEVEL.load(Ordering::Relaxed);
+    let blocked = (0..NUM_IRQS)
+        .filter(|&n| IRQ_LEVELS[n].load(Ordering::Relaxed) <= level)
+        .fold(0, |blocked, n| blocked | (1 << n));
+
+    BLOCKED.store(blocked, Ordering::Relaxed);
+}
+
+/// Sets the interrupt level of an IRQ of the fake interrupt controller, which is [`InterruptLevel::Device`] until this is called.
+///
+/// # Panics
+///
+/// This function will panic if the provided level is [`InterruptLevel::Passive`].
+pub fn set_irq_level(n: usize, level: InterruptLevel) {
+    assert!(level != InterruptLevel::Passive);
+
+    IRQ_LEVELS[n].store(level as u8, Ordering::Relaxed);
+    update_blocked();
+    deliver_pending();
+}
+
+/// Updates the fake interrupt controller after the interrupt level of the simulated CPU changed, delivering any IRQs that were held pending
+/// if it was lowered.
+///
+/// # Safety
+///
+/// This should only be called by [`crate::sync::level`] when changing the current interrupt level.
+pub unsafe fn change_level(old_level: InterruptLevel, new_level: InterruptLevel) {
+    LEVEL.store(new_level as u8, Ordering::Relaxed);
+    update_blocked();
+
+    if new_level < old_level {
+        deliver_pending();
+    }
+}
+
+/// Raises an IRQ on the fake interrupt controller. The IRQ is delivered immediately if interrupts are enabled, or otherwise as soon as
+/// they next become enabled. Raising an IRQ that is already pending has no further effect.
+///
+/// # Panics
+///
+/// This function will panic if the IRQ number is out of range.
+pub fn inject_irq(n: usize) {
+    assert!(n < NUM_IRQS);
+
+    PENDING.fetch_or(1 << n, Ordering::Relaxed);
+    deliver_pending();
+}
+
+/// Delivers all pending IRQs if interrupts are enabled. Returns whether any IRQs were delivered.
+pub(super) fn deliver_pending() -> bool {
+    let mut delivered = false;
+
+    while are_enabled() {
+        let pending = PENDING.load(Ordering::Relaxed) & !BLOCKED.load(Ordering::Relaxed);
+
+        if pending == 0 {
+            break;
+        }
+
+        let n = pending.trailing_zeros() as usize;
+
+        PENDING.fetch_and(!(1 << n), Ordering::Relaxed);
+        delivered = true;
+
+        disable();
+
+        // SAFETY: Interrupts were enabled and have just been disabled, just like real hardware does when delivering an interrupt
+        unsafe {
+            let mut frame = CURRENT_FRAME.lock().clone();
+
+            sched::begin_interrupt();
+
+            if let Some(ref handler) = IRQ_HANDLERS.lock()[n] {
+                IRQ_COUNTS[n].fetch_add(1, Ordering::Relaxed);
+                sched::replay::record(|| sched::replay::ReplayEvent::Irq(n as u8));
+                crate::io::dev::recovery::run_irq_handler(|| handler(&mut frame));
+            }
+
+            sched::end_interrupt(&mut frame);
+            *CURRENT_FRAME.lock() = frame;
+        }
+
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+
+    delivered
 }
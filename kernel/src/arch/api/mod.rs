@@ -1,28 +1,63 @@
+//! A simulated architecture implementing the architecture API entirely in software.
+//!
+//! This backend is used instead of a real architecture when the `real_arch_api` feature is disabled. Besides checking that
+//! architecture-independent code only relies on the API that every architecture provides, it is the skeleton of a simulation that a host
+//! process could link the kernel against and drive through the hooks in [`sim`]:
+//!
+//! - Physical memory is a single large allocation provided by the host, whose address is passed in as the physical memory offset of the
+//!   [`BootInfo`].
+//! - Page tables are software-walked tables stored in that physical memory (see [`page`]). Since nothing translates the host's memory
+//!   accesses, the host must alias kernel pages itself if kernel virtual memory is to be dereferenced, and accesses to user memory are
+//!   simulated using [`AddressSpace::simulate_access`](page::AddressSpace::simulate_access).
+//! - Interrupts are raised by injecting them into a fake interrupt controller using [`interrupt::inject_irq`], and the only timer is a
+//!   fake clock event device driven by advancing simulated time using [`sim::advance_time`].
+//!
+//! The simulated machine has a single CPU core, and since thread contexts are never actually executed, context switches only change which
+//! thread's registers are recorded in the current [`InterruptFrame`](interrupt::InterruptFrame).
+//!
+//! This is not yet enough to run the scheduler or memory management code as a host process. There is no host-side harness to boot the
+//! kernel and alias its pages, and blocking a thread still context-switches using a raw `int 0x30`, which the simulation cannot handle.
+
 #![allow(dead_code)]
-#![allow(unused_variables)]
 
 use core::ops::{Add, AddAssign, Sub};
 
 use bootloader::BootInfo;
 
+use crate::io::dev::probe::ProbeSet;
+
+pub mod insn;
 pub mod interrupt;
 pub mod page;
+pub mod recovery;
 pub mod regs;
+pub mod sim;
+pub mod tls;
+pub mod topology;
+pub mod unwind;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysAddr(u64);
 
 impl PhysAddr {
     pub const fn new(val: u64) -> PhysAddr {
-        unimplemented!()
+        PhysAddr(val)
     }
 
     pub const fn zero() -> PhysAddr {
-        unimplemented!()
+        PhysAddr(0)
     }
 
     pub const fn as_u64(self) -> u64 {
-        unimplemented!()
+        self.0
+    }
+}
+
+impl Add<u64> for PhysAddr {
+    type Output = PhysAddr;
+
+    fn add(self, rhs: u64) -> Self::Output {
+        PhysAddr(self.0 + rhs)
     }
 }
 
@@ -30,36 +65,38 @@ impl PhysAddr {
 pub struct VirtAddr(u64);
 
 impl VirtAddr {
+    /// Creates a new virtual address. Unlike on real architectures, all 64-bit values are valid virtual addresses in the simulation.
     pub fn new(val: u64) -> VirtAddr {
-        unimplemented!()
+        VirtAddr(val)
     }
 
     pub const fn new_truncate(val: u64) -> VirtAddr {
-        unimplemented!()
+        VirtAddr(val)
     }
 
     pub const fn from_ptr<T: ?Sized>(ptr: *const T) -> VirtAddr {
-        unimplemented!()
+        VirtAddr(ptr as *const () as u64)
     }
 
     pub const fn zero() -> VirtAddr {
-        unimplemented!()
+        VirtAddr(0)
     }
 
     pub const fn as_ptr<T>(self) -> *const T {
-        unimplemented!()
+        self.0 as *const T
     }
 
     pub const fn as_mut_ptr<T>(self) -> *mut T {
-        unimplemented!()
+        self.0 as *mut T
     }
 
     pub const fn as_u64(self) -> u64 {
-        unimplemented!()
+        self.0
     }
 
     pub fn is_aligned(self, align: u64) -> bool {
-        unimplemented!()
+        assert!(align.is_power_of_two());
+        self.0 & (align - 1) == 0
     }
 }
 
@@ -67,7 +104,23 @@ impl Sub for VirtAddr {
     type Output = u64;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        unimplemented!()
+        self.0.checked_sub(rhs.0).expect("attempt to subtract with overflow")
+    }
+}
+
+impl Sub<u64> for VirtAddr {
+    type Output = VirtAddr;
+
+    fn sub(self, rhs: u64) -> Self::Output {
+        VirtAddr(self.0.checked_sub(rhs).expect("attempt to subtract with overflow"))
+    }
+}
+
+impl Add<u64> for VirtAddr {
+    type Output = VirtAddr;
+
+    fn add(self, rhs: u64) -> Self::Output {
+        VirtAddr(self.0.checked_add(rhs).expect("attempt to add with overflow"))
     }
 }
 
@@ -75,24 +128,62 @@ impl Add<usize> for VirtAddr {
     type Output = VirtAddr;
 
     fn add(self, rhs: usize) -> Self::Output {
-        unimplemented!()
+        self + rhs as u64
     }
 }
 
 impl AddAssign<usize> for VirtAddr {
     fn add_assign(&mut self, rhs: usize) {
-        unimplemented!()
+        *self = *self + rhs;
+    }
+}
+
+/// Gets the number of cycles that the simulated CPU has run for. The simulated CPU runs at [`sim::CYCLE_FREQUENCY`], so this only
+/// advances along with simulated time.
+pub fn cycle_counter() -> u64 {
+    sim::cycles()
+}
+
+/// Backtraces are not supported by the simulation, since the frame pointers of the host process cannot be trusted to be present.
+pub fn backtrace(_skip: usize, _out: &mut [usize]) -> usize {
+    0
+}
+
+/// Enables interrupts and waits for one to arrive. If none are pending, simulated time is advanced until the next timer interrupt.
+pub fn idle_wait() {
+    interrupt::enable();
+
+    if !interrupt::deliver_pending() {
+        sim::advance_to_next_event();
     }
 }
 
 pub fn halt() -> ! {
-    unimplemented!()
+    interrupt::disable();
+    sim::exit(sim::SimExit::Halt)
+}
+
+pub fn reboot() -> ! {
+    interrupt::disable();
+    sim::exit(sim::SimExit::Reboot)
+}
+
+pub fn power_off() -> ! {
+    interrupt::disable();
+    sim::exit(sim::SimExit::PowerOff)
 }
 
 pub(crate) unsafe fn init_phase_1(boot_info: &BootInfo) {
-    unimplemented!()
+    page::init_phys_mem_base(boot_info.physical_memory_offset as *mut u8);
+    crate::io::dev::init_device_root();
 }
 
 pub(crate) unsafe fn init_phase_2() {
-    unimplemented!()
+    page::init_kernel_addrspace();
+    crate::mem::set_use_early_alloc(false);
+    sim::init_timer();
+    crate::sched::clockevent::start();
 }
+
+/// The simulated machine has no devices to probe.
+pub(crate) fn add_device_probes(_probes: &mut ProbeSet) {}
@@ -1,92 +1,637 @@
-use core::marker::PhantomData;
+//! Software-emulated page tables for the simulated architecture.
+//!
+//! Page tables are four-level radix trees of 512 64-bit entries per table, stored in frames of simulated physical memory just like the page
+//! tables of a real architecture. Nothing translates the host's own memory accesses through them, so they serve two purposes:
+//!
+//! - Kernel mappings are reported to the host using [`SimHost::map_kernel_page`](sim::SimHost::map_kernel_page), which should alias the kernel virtual page to the frame's
+//!   memory so that kernel code can dereference it.
+//! - Accesses made by simulated user code are checked against the page tables using [`AddressSpace::simulate_access`], which behaves like
+//!   an MMU: it records that the page was accessed, or describes the page fault that the access would cause.
+//!
+//! User address spaces cover the range below [`USER_SPACE_END`], while kernel virtual memory is allocated from the range of host addresses
+//! provided in [`SimHost::kernel_virt`](sim::SimHost::kernel_virt), which lies above it. The top-level entries covering the kernel range are shared by all address
+//! spaces.
 
-use bitflags::bitflags;
+use core::alloc::AllocError;
+use core::ops::Range;
+use core::ptr;
 
-use super::{PhysAddr, VirtAddr};
-use crate::mem::virt::VirtualAllocator;
+use super::{sim, PhysAddr, VirtAddr};
+use crate::mem::fault::{PageFault, PageFaultFlags};
+use crate::mem::frame::{self, FrameAllocator};
+use crate::mem::swap::SwapError;
+use crate::mem::virt::{VirtualAllocRegion, VirtualAllocator};
 use crate::sync::uninterruptible::UninterruptibleSpinlockGuard;
+use crate::sync::UninterruptibleSpinlock;
+use crate::util::{OneShotManualInit, SyncPtr};
 
 pub const PAGE_SIZE: usize = 4096;
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
 pub const IS_PHYS_MEM_ALWAYS_MAPPED: bool = true;
+pub const MAX_SWAP_SLOTS: u64 = 1 << 40;
 
-bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-    pub struct PageFlags: u16 {
-        const USER = 0x1;
-        const WRITEABLE = 0x2;
-        const EXECUTABLE = 0x4;
-    }
+/// The end of the range of virtual addresses available to user address spaces.
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+/// The end of the range of virtual addresses that can be translated by the simulated page tables.
+const ADDRESS_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// The range of virtual addresses from which the kernel's virtual allocator can hand out address space. The part of this range that is
+/// actually available is provided by the host.
+pub const KERNEL_VIRT_ALLOC_RANGE: Range<u64> = USER_SPACE_END..ADDRESS_SPACE_END;
+
+const NUM_LEVELS: u32 = 4;
+const ENTRIES_PER_TABLE: usize = 512;
+const KERNEL_L4_ENTRIES: Range<usize> = table_index(USER_SPACE_END, NUM_LEVELS)..ENTRIES_PER_TABLE / 2;
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const ENTRY_ACCESSED: u64 = 1 << 1;
+const ENTRY_HUGE: u64 = 1 << 2;
+const ENTRY_SWAPPED: u64 = 1 << 3;
+const ENTRY_FLAGS_SHIFT: u32 = 4;
+const ENTRY_FLAGS_MASK: u64 = 0xff << ENTRY_FLAGS_SHIFT;
+const ENTRY_ADDR_MASK: u64 = !0xfff;
+
+pub use crate::arch::page_flags::PageFlags;
+
+static PHYS_MEM_BASE: OneShotManualInit<SyncPtr<u8>> = OneShotManualInit::uninit();
+static KERNEL_ADDRESS_SPACE: OneShotManualInit<UninterruptibleSpinlock<AddressSpace>> = OneShotManualInit::uninit();
+
+pub fn init_phys_mem_base(phys_mem_base: *mut u8) {
+    PHYS_MEM_BASE.set(SyncPtr::new(phys_mem_base));
 }
 
-#[derive(Debug)]
-pub struct PhysMemPtr<T: ?Sized> {
-    _data: PhantomData<*mut T>,
+pub fn get_phys_mem_base() -> *mut u8 {
+    **PHYS_MEM_BASE.get()
 }
 
+#[derive(Debug)]
+pub struct PhysMemPtr<T: ?Sized>(*mut T);
+
 impl<T: ?Sized> PhysMemPtr<T> {
     pub fn ptr(&self) -> *mut T {
-        unimplemented!()
+        self.0
     }
 
     pub fn phys_addr(&self) -> PhysAddr {
-        unimplemented!()
+        PhysAddr::new(self.0 as *const () as u64 - get_phys_mem_base() as u64)
     }
 
     pub fn into_raw(self) -> *mut T {
-        unimplemented!()
+        self.0
     }
 
     pub unsafe fn from_raw(ptr: *mut T) -> Self {
-        unimplemented!()
+        PhysMemPtr(ptr)
     }
 }
 
-impl<T: ?Sized> Drop for PhysMemPtr<T> {
-    fn drop(&mut self) {
-        unimplemented!()
+pub fn get_phys_mem_ptr<T>(phys_addr: PhysAddr) -> PhysMemPtr<T> {
+    PhysMemPtr(get_phys_mem_base().wrapping_offset(phys_addr.as_u64() as isize) as *mut T)
+}
+
+pub fn get_phys_mem_ptr_slice<T>(phys_addr: PhysAddr, len: usize) -> PhysMemPtr<[T]> {
+    PhysMemPtr(ptr::slice_from_raw_parts_mut(get_phys_mem_ptr::<T>(phys_addr).ptr(), len))
+}
+
+/// Translations are never cached by the simulation, so there is nothing to invalidate.
+pub fn flush_tlb_local(_addr: VirtAddr) {}
+
+/// Translations are never cached by the simulation, so there is nothing to invalidate.
+pub fn flush_tlb_all_local() {}
+
+const fn table_index(addr: u64, level: u32) -> usize {
+    ((addr >> (12 + 9 * (level - 1))) as usize) % ENTRIES_PER_TABLE
+}
+
+unsafe fn table_mut(table: PhysAddr) -> &'static mut [u64; ENTRIES_PER_TABLE] {
+    &mut *get_phys_mem_ptr(table).ptr()
+}
+
+fn alloc_table() -> PhysAddr {
+    try_alloc_table().expect("Out of memory while allocating page table")
+}
+
+fn try_alloc_table() -> Result<PhysAddr, AllocError> {
+    let table = frame::get_allocator().alloc_one().ok_or(AllocError)?;
+
+    // SAFETY: The frame was just allocated, so nothing else can be using it
+    unsafe {
+        table_mut(table).fill(0);
     }
+
+    Ok(table)
 }
 
-pub fn get_phys_mem_ptr<T>(phys_addr: PhysAddr) -> PhysMemPtr<T> {
-    unimplemented!()
+fn entry_addr(entry: u64) -> PhysAddr {
+    PhysAddr::new(entry & ENTRY_ADDR_MASK)
 }
 
-pub fn get_phys_mem_ptr_slice<T>(phys_addr: PhysAddr, len: usize) -> PhysMemPtr<[T]> {
-    unimplemented!()
+fn entry_flags(entry: u64) -> PageFlags {
+    PageFlags::from_bits_truncate(((entry & ENTRY_FLAGS_MASK) >> ENTRY_FLAGS_SHIFT) as u16)
 }
 
-pub struct AddressSpace;
+fn make_entry(frame: PhysAddr, flags: PageFlags) -> u64 {
+    assert_eq!(0, frame.as_u64() & !ENTRY_ADDR_MASK, "bad frame for page mapping");
+
+    frame.as_u64() | (u64::from(flags.bits()) << ENTRY_FLAGS_SHIFT) | ENTRY_PRESENT
+}
+
+pub struct AddressSpace {
+    page_table: PhysAddr,
+    virtual_alloc: VirtualAllocator,
+    is_kernel: bool,
+}
 
 impl AddressSpace {
-    pub unsafe fn new_kernel() -> AddressSpace {
-        unimplemented!()
+    pub(crate) unsafe fn new_kernel() -> AddressSpace {
+        AddressSpace {
+            page_table: alloc_table(),
+            virtual_alloc: VirtualAllocator::new(),
+            is_kernel: true,
+        }
     }
 
     pub fn kernel() -> UninterruptibleSpinlockGuard<'static, AddressSpace> {
-        unimplemented!()
+        (*KERNEL_ADDRESS_SPACE.get()).lock()
+    }
+
+    pub fn try_kernel() -> Option<UninterruptibleSpinlockGuard<'static, AddressSpace>> {
+        (*KERNEL_ADDRESS_SPACE.get()).try_lock()
     }
 
     pub fn new() -> AddressSpace {
-        unimplemented!()
+        let mut addrspace = AddressSpace::new_user_empty();
+
+        // SAFETY: Nothing is mapped in the user range of a new address space yet
+        unsafe {
+            addrspace.virtual_alloc.free(VirtualAllocRegion::new(
+                VirtAddr::new(PAGE_SIZE as u64),
+                VirtAddr::new(USER_SPACE_END - PAGE_SIZE as u64),
+            ));
+        }
+
+        addrspace
+    }
+
+    /// Creates a new user address space with nothing mapped in its user range and no free virtual memory in its virtual allocator.
+    fn new_user_empty() -> AddressSpace {
+        let addrspace = AddressSpace {
+            page_table: alloc_table(),
+            virtual_alloc: VirtualAllocator::new(),
+            is_kernel: false,
+        };
+
+        // SAFETY: Both tables are top-level tables, and the kernel entries of the kernel address space's table are never changed after it is
+        //         initialized
+        unsafe {
+            let l4_table = table_mut(addrspace.page_table);
+            let kl4_table = table_mut(AddressSpace::kernel().page_table);
+
+            l4_table[KERNEL_L4_ENTRIES].copy_from_slice(&kl4_table[KERNEL_L4_ENTRIES]);
+        }
+
+        addrspace
+    }
+
+    /// Creates a new user address space for a new process, with the same user mappings as this one and a virtual allocator seeded with the
+    /// same free regions. Writeable pages become copy-on-write in both address spaces and swapped out pages are copied into new swap slots,
+    /// just like on real architectures.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on the kernel address space.
+    #[track_caller]
+    pub fn clone_for_new_process(&mut self) -> Result<AddressSpace, SwapError> {
+        if self.is_kernel {
+            panic!("clone_for_new_process cannot be called on the kernel address space");
+        }
+
+        let mut clone = AddressSpace::new_user_empty();
+
+        for region in self.virtual_alloc.free_regions() {
+            // SAFETY: The region is free in this address space, so nothing will be mapped there in the clone either
+            unsafe {
+                clone.virtual_alloc.free(region);
+            }
+        }
+
+        // SAFETY: This address space is borrowed mutably, so nothing else can be modifying its page tables. The clone is new, so its user
+        //         range is empty.
+        unsafe { clone_user_page_table(self.page_table, NUM_LEVELS, 0..KERNEL_L4_ENTRIES.start, 0, &mut clone) }.map(|()| clone)
     }
 
+    /// Makes the range of host addresses provided by the host available for allocating kernel virtual memory.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if no host has been registered or if the range it provided is not page-aligned or lies outside of the range
+    /// reserved for the kernel.
     pub(crate) unsafe fn init_kernel_virtual_alloc(&mut self) {
-        unimplemented!()
+        let (start, end) = sim::host().kernel_virt;
+
+        assert!(start.is_aligned(PAGE_SIZE as u64) && end.is_aligned(PAGE_SIZE as u64));
+        assert!(start.as_u64() >= USER_SPACE_END && end.as_u64() <= ADDRESS_SPACE_END && start < end);
+
+        self.virtual_alloc.free(VirtualAllocRegion::new(start, end));
     }
 
     pub fn virtual_alloc(&mut self) -> &mut VirtualAllocator {
-        unimplemented!()
+        &mut self.virtual_alloc
+    }
+
+    /// Gets a pointer to the leaf entry mapping the provided address along with its level, which is 1 for 4KiB pages and 2 for huge pages.
+    /// Returns [`None`] if the page tables leading to the entry do not exist.
+    unsafe fn leaf_entry_ptr(&self, addr: VirtAddr) -> Option<(*mut u64, u32)> {
+        if addr.as_u64() >= ADDRESS_SPACE_END {
+            return None;
+        }
+
+        let mut table = self.page_table;
+
+        for level in (2..=NUM_LEVELS).rev() {
+            let entry = &mut table_mut(table)[table_index(addr.as_u64(), level)];
+
+            if *entry & ENTRY_HUGE != 0 {
+                return Some((entry as *mut u64, level));
+            } else if *entry & ENTRY_PRESENT == 0 {
+                return None;
+            }
+
+            table = entry_addr(*entry);
+        }
+
+        Some((&mut table_mut(table)[table_index(addr.as_u64(), 1)] as *mut u64, 1))
+    }
+
+    /// Gets a pointer to the entry at the provided level for the provided address, allocating any missing page tables leading to it if
+    /// `create` is set. Returns an error if a page table could not be allocated.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the address is already mapped by a huge page at a higher level.
+    unsafe fn entry_ptr(&mut self, addr: VirtAddr, level: u32, create: bool) -> Result<Option<*mut u64>, AllocError> {
+        let mut table = self.page_table;
+
+        for l in (level + 1..=NUM_LEVELS).rev() {
+            let entry = &mut table_mut(table)[table_index(addr.as_u64(), l)];
+
+            assert!(*entry & ENTRY_HUGE == 0, "{:#x} is already mapped by a huge page", addr.as_u64());
+
+            if *entry & ENTRY_PRESENT == 0 {
+                if !create {
+                    return Ok(None);
+                }
+
+                *entry = try_alloc_table()?.as_u64() | ENTRY_PRESENT;
+            }
+
+            table = entry_addr(*entry);
+        }
+
+        Ok(Some(&mut table_mut(table)[table_index(addr.as_u64(), level)] as *mut u64))
     }
 
     pub fn get_page(&self, addr: VirtAddr) -> Option<(PhysAddr, PageFlags)> {
-        unimplemented!()
+        // SAFETY: This only reads the page tables of this address space
+        unsafe {
+            let (entry, level) = self.leaf_entry_ptr(addr)?;
+
+            if *entry & ENTRY_PRESENT == 0 {
+                return None;
+            }
+
+            let offset = addr.as_u64() & (((PAGE_SIZE as u64) << (9 * (level - 1))) - 1);
+
+            Some((entry_addr(*entry) + offset, entry_flags(*entry)))
+        }
     }
 
-    pub fn set_page_user(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageFlags)>) {
-        unimplemented!()
+    #[track_caller]
+    unsafe fn set_entry(&mut self, addr: VirtAddr, entry: Option<u64>) {
+        assert!(addr.is_aligned(PAGE_SIZE as u64), "bad address for page mapping");
+
+        let entry_ptr = self.entry_ptr(addr, 1, entry.is_some());
+
+        if let Some(entry_ptr) = entry_ptr.expect("Out of memory while allocating page table") {
+            *entry_ptr = entry.unwrap_or(0);
+        }
     }
 
+    #[track_caller]
+    pub unsafe fn set_page_user(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageFlags)>) {
+        if self.is_kernel {
+            panic!("set_page_user cannot be called on the kernel address space");
+        }
+
+        if addr.as_u64() >= USER_SPACE_END {
+            panic!("set_page_user can only be used on user virtual addresses");
+        }
+
+        self.set_entry(addr, mapping.map(|(frame, flags)| make_entry(frame, flags)));
+    }
+
+    /// Maps or unmaps a page of kernel virtual memory, reporting the change to the host so that it can alias the page to the frame.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on a user address space or if the address is not a kernel virtual address.
+    #[track_caller]
     pub unsafe fn set_page_kernel(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageFlags)>) {
-        unimplemented!()
+        if !self.is_kernel {
+            panic!("set_page_kernel cannot be called on a user address space");
+        }
+
+        if addr.as_u64() < USER_SPACE_END || addr.as_u64() >= ADDRESS_SPACE_END {
+            panic!("set_page_kernel can only be used on kernel virtual addresses");
+        }
+
+        self.set_entry(addr, mapping.map(|(frame, flags)| make_entry(frame, flags)));
+        (sim::host().map_kernel_page)(addr, mapping.map(|(frame, _)| frame));
+    }
+
+    pub fn is_huge_page(&self, addr: VirtAddr) -> bool {
+        // SAFETY: This only reads the page tables of this address space
+        unsafe {
+            self.leaf_entry_ptr(addr)
+                .is_some_and(|(entry, level)| level == 2 && *entry & ENTRY_PRESENT != 0)
+        }
+    }
+
+    /// Maps or unmaps a huge page of kernel virtual memory. The host is told about each 4KiB page covered by the huge page separately.
+    /// Returns an error without changing anything if a page table needed to map the huge page could not be allocated.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on a user address space, if the address is not a kernel virtual address, if either address is not
+    /// aligned to [`HUGE_PAGE_SIZE`], or if any 4KiB pages are still mapped in the range that the huge page would cover.
+    #[track_caller]
+    pub unsafe fn set_huge_page_kernel(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageFlags)>) -> Result<(), AllocError> {
+        if !self.is_kernel {
+            panic!("set_huge_page_kernel cannot be called on a user address space");
+        }
+
+        if addr.as_u64() < USER_SPACE_END || addr.as_u64() >= ADDRESS_SPACE_END {
+            panic!("set_huge_page_kernel can only be used on kernel virtual addresses");
+        }
+
+        assert!(addr.is_aligned(HUGE_PAGE_SIZE as u64), "bad address for huge page mapping");
+
+        let Some(entry) = self.entry_ptr(addr, 2, mapping.is_some())? else {
+            return Ok(());
+        };
+
+        if let Some((frame, flags)) = mapping {
+            assert_eq!(0, frame.as_u64() % HUGE_PAGE_SIZE as u64, "bad frame for huge page mapping");
+
+            if *entry & ENTRY_PRESENT != 0 && *entry & ENTRY_HUGE == 0 {
+                let l1_table = entry_addr(*entry);
+
+                assert!(
+                    table_mut(l1_table).iter().all(|&e| e == 0),
+                    "huge page mapping at {:#x} would replace existing pages",
+                    addr.as_u64()
+                );
+                frame::get_allocator().free_one(l1_table);
+            }
+
+            *entry = make_entry(frame, flags) | ENTRY_HUGE;
+        } else {
+            assert!(
+                *entry & ENTRY_PRESENT == 0 || *entry & ENTRY_HUGE != 0,
+                "no huge page is mapped at {:#x}",
+                addr.as_u64()
+            );
+            *entry = 0;
+        }
+
+        for i in 0..(HUGE_PAGE_SIZE / PAGE_SIZE) as u64 {
+            (sim::host().map_kernel_page)(addr + i * PAGE_SIZE as u64, mapping.map(|(frame, _)| frame + i * PAGE_SIZE as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Gets a pointer to the 4KiB page table entry for the provided address, if the page tables leading to it exist.
+    unsafe fn l1_entry_ptr(&self, addr: VirtAddr) -> Option<*mut u64> {
+        self.leaf_entry_ptr(addr).and_then(|(entry, level)| (level == 1).then_some(entry))
+    }
+
+    pub fn get_swap_entry(&mut self, addr: VirtAddr) -> Option<u64> {
+        // SAFETY: This only reads the page tables of this address space
+        let entry = unsafe { *self.l1_entry_ptr(addr)? };
+
+        (entry & ENTRY_SWAPPED != 0).then_some(entry >> 12)
+    }
+
+    #[track_caller]
+    pub unsafe fn set_swap_entry_user(&mut self, addr: VirtAddr, slot: u64) {
+        if self.is_kernel {
+            panic!("set_swap_entry_user cannot be called on the kernel address space");
+        }
+
+        if addr.as_u64() >= USER_SPACE_END {
+            panic!("set_swap_entry_user can only be used on user virtual addresses");
+        }
+
+        assert!(slot < MAX_SWAP_SLOTS, "swap slot {} cannot be encoded in a page table entry", slot);
+
+        self.set_entry(addr, Some((slot << 12) | ENTRY_SWAPPED));
+    }
+
+    pub fn test_and_clear_accessed(&mut self, addr: VirtAddr) -> bool {
+        // SAFETY: Clearing the accessed bit does not change which frame the page maps
+        unsafe {
+            match self.l1_entry_ptr(addr) {
+                Some(entry) if *entry & (ENTRY_PRESENT | ENTRY_ACCESSED) == ENTRY_PRESENT | ENTRY_ACCESSED => {
+                    *entry &= !ENTRY_ACCESSED;
+                    true
+                },
+                _ => false,
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn make_page_cow_user(&mut self, addr: VirtAddr) -> Option<(PhysAddr, PageFlags)> {
+        if self.is_kernel {
+            panic!("make_page_cow_user cannot be called on the kernel address space");
+        }
+
+        // SAFETY: Write-protecting a page does not change which frame it maps
+        unsafe {
+            let entry = self.l1_entry_ptr(addr)?;
+
+            if *entry & ENTRY_PRESENT == 0 {
+                return None;
+            }
+
+            let flags = entry_flags(*entry);
+
+            if flags.contains(PageFlags::WRITEABLE) {
+                *entry = make_entry(entry_addr(*entry), flags | PageFlags::COPY_ON_WRITE) | (*entry & ENTRY_ACCESSED);
+            }
+        }
+
+        self.get_page(addr)
+    }
+
+    /// Simulates an access to the provided virtual address by checking it against the page tables like an MMU would. On success, the page
+    /// is marked as accessed and the physical address being accessed is returned. Otherwise, the page fault that the access would cause is
+    /// returned, which can be passed to [`handle_page_fault`](crate::mem::fault::handle_page_fault) before retrying the access.
+    pub fn simulate_access(&mut self, addr: VirtAddr, access: PageFaultFlags) -> Result<PhysAddr, PageFault> {
+        let fault = |flags| PageFault {
+            addr,
+            flags: access | flags,
+        };
+
+        // SAFETY: Setting the accessed bit does not change which frame the page maps
+        let entry = unsafe {
+            match self.leaf_entry_ptr(addr) {
+                Some((entry, _)) if *entry & ENTRY_PRESENT != 0 => entry,
+                _ => return Err(fault(PageFaultFlags::empty())),
+            }
+        };
+
+        // SAFETY: The entry was found above and belongs to this address space's page tables
+        let flags = entry_flags(unsafe { *entry });
+        let permitted = (!access.contains(PageFaultFlags::USER) || flags.contains(PageFlags::USER))
+            && (!access.contains(PageFaultFlags::WRITE)
+                || flags.contains(PageFlags::WRITEABLE) && !flags.contains(PageFlags::COPY_ON_WRITE))
+            && (!access.contains(PageFaultFlags::INSTRUCTION) || flags.contains(PageFlags::EXECUTABLE));
+
+        if !permitted {
+            return Err(fault(PageFaultFlags::PRESENT));
+        }
+
+        // SAFETY: As above
+        unsafe {
+            *entry |= ENTRY_ACCESSED;
+        }
+
+        Ok(self.get_page(addr).unwrap().0)
+    }
+}
+
+/// Frees a page table belonging to a user address space, along with all page tables and frames mapped by it.
+unsafe fn free_user_page_table(table: PhysAddr, level: u32, entries: Range<usize>) {
+    for &entry in &table_mut(table)[entries] {
+        if entry & ENTRY_PRESENT == 0 {
+            if level == 1 && entry & ENTRY_SWAPPED != 0 {
+                crate::mem::swap::free_slot(entry >> 12);
+            }
+        } else if level > 1 {
+            free_user_page_table(entry_addr(entry), level - 1, 0..ENTRIES_PER_TABLE);
+        } else {
+            frame::release(entry_addr(entry));
+        }
+    }
+
+    frame::get_allocator().free_one(table);
+}
+
+/// Duplicates the mappings in a page table belonging to a user address space into another user address space. See
+/// [`AddressSpace::clone_for_new_process`] for details.
+unsafe fn clone_user_page_table(
+    table: PhysAddr,
+    level: u32,
+    entries: Range<usize>,
+    base: u64,
+    clone: &mut AddressSpace,
+) -> Result<(), SwapError> {
+    let page_table = table_mut(table);
+
+    for i in entries {
+        let addr = VirtAddr::new(base + ((i as u64) << (12 + 9 * (level - 1))));
+        let entry = &mut page_table[i];
+
+        if *entry & ENTRY_PRESENT == 0 {
+            if level == 1 && *entry & ENTRY_SWAPPED != 0 {
+                let slot = crate::mem::swap::duplicate_slot(*entry >> 12)?;
+
+                clone.set_entry(addr, Some((slot << 12) | ENTRY_SWAPPED));
+            }
+        } else if level > 1 {
+            clone_user_page_table(entry_addr(*entry), level - 1, 0..ENTRIES_PER_TABLE, addr.as_u64(), clone)?;
+        } else {
+            let flags = entry_flags(*entry);
+
+            if flags.contains(PageFlags::WRITEABLE) {
+                *entry = make_entry(entry_addr(*entry), flags | PageFlags::COPY_ON_WRITE) | (*entry & ENTRY_ACCESSED);
+            }
+
+            frame::share(entry_addr(*entry));
+            clone.set_entry(addr, Some(*entry & !ENTRY_ACCESSED));
+        }
+    }
+
+    Ok(())
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_kernel {
+            panic!("Attempt to drop the kernel address space");
+        }
+
+        // SAFETY: The kernel entries of the top-level table are shared with the kernel address space, so only the user entries (which are
+        //         owned by this address space) are freed.
+        unsafe {
+            free_user_page_table(self.page_table, NUM_LEVELS, 0..KERNEL_L4_ENTRIES.start);
+        }
+    }
+}
+
+/// Creates the kernel address space, filling in all of its top-level entries covering the kernel range so that they can be shared with
+/// every user address space.
+///
+/// # Panics
+///
+/// This function will panic if no host has been registered using [`sim::set_host`], since the kernel's virtual memory is provided by the
+/// host.
+pub(super) unsafe fn init_kernel_addrspace() {
+    let mut kernel_addrspace = AddressSpace::new_kernel();
+    let kl4_table = table_mut(kernel_addrspace.page_table);
+
+    for entry in &mut kl4_table[KERNEL_L4_ENTRIES] {
+        *entry = alloc_table().as_u64() | ENTRY_PRESENT;
+    }
+
+    kernel_addrspace.init_kernel_virtual_alloc();
+    KERNEL_ADDRESS_SPACE.set(UninterruptibleSpinlock::new(kernel_addrspace));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_simulate_access() {
+        let mut addrspace = AddressSpace::new();
+        let frame = frame::get_allocator().alloc_one().unwrap();
+        let addr = VirtAddr::new(0x1000_0000);
+        let user_write = PageFaultFlags::USER | PageFaultFlags::WRITE;
+
+        assert_eq!(
+            Err(PageFault { addr, flags: user_write }),
+            addrspace.simulate_access(addr, user_write)
+        );
+
+        unsafe {
+            addrspace.set_page_user(addr, Some((frame, PageFlags::USER | PageFlags::WRITEABLE)));
+        }
+        assert_eq!(Ok(frame + 0x10), addrspace.simulate_access(addr + 0x10usize, user_write));
+        assert!(addrspace.test_and_clear_accessed(addr));
+        assert!(!addrspace.test_and_clear_accessed(addr));
+
+        addrspace.make_page_cow_user(addr);
+        assert_eq!(
+            Err(PageFault {
+                addr,
+                flags: user_write | PageFaultFlags::PRESENT
+            }),
+            addrspace.simulate_access(addr, user_write)
+        );
+        assert_eq!(Ok(frame), addrspace.simulate_access(addr, PageFaultFlags::USER));
     }
 }
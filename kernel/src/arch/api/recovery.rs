@@ -0,0 +1,35 @@
+//! The simulated architecture has no stack that could be rewound, so recovery points can never be resumed.
+
+/// The state needed to resume execution at a recovery point, which is empty for the simulated architecture.
+#[derive(Debug, Default)]
+pub struct RecoveryPoint;
+
+impl RecoveryPoint {
+    pub const fn new() -> RecoveryPoint {
+        RecoveryPoint
+    }
+}
+
+/// Calls `f` with the provided data and returns `false`, since recovery points can never be resumed.
+///
+/// # Safety
+///
+/// `f` must be safe to call with the provided data.
+pub unsafe extern "C" fn call_with_recovery_point(_point: *mut RecoveryPoint, f: unsafe extern "C" fn(*mut u8), data: *mut u8) -> bool {
+    f(data);
+    false
+}
+
+/// Never called, since [`can_resume`] always returns `false`.
+///
+/// # Safety
+///
+/// This function must never be called.
+pub unsafe extern "C" fn resume_recovery_point(_point: *const RecoveryPoint) -> ! {
+    unreachable!("recovery points cannot be resumed by the simulated architecture")
+}
+
+/// Always returns `false`, since recovery points can never be resumed.
+pub fn can_resume(_point: &RecoveryPoint) -> bool {
+    false
+}
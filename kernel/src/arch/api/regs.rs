@@ -1,37 +1,56 @@
+/// The basic registers of a simulated thread. Since simulated threads are never actually executed, only the values that threads are created
+/// with are recorded.
 #[non_exhaustive]
 #[derive(Debug, Clone)]
-pub struct SavedBasicRegisters {}
+pub struct SavedBasicRegisters {
+    pub ip: u64,
+    pub sp: u64,
+    pub arg: u64,
+    pub is_user: bool,
+}
 
 impl SavedBasicRegisters {
-    pub fn new() -> SavedBasicRegisters {
-        unimplemented!()
+    pub const fn new() -> SavedBasicRegisters {
+        SavedBasicRegisters {
+            ip: 0,
+            sp: 0,
+            arg: 0,
+            is_user: false,
+        }
     }
 
     pub fn new_kernel_thread(f: extern "C" fn(*mut u8) -> !, arg: *mut u8, stack: *mut u8) -> SavedBasicRegisters {
-        unimplemented!()
+        SavedBasicRegisters {
+            ip: f as usize as u64,
+            sp: stack as u64,
+            arg: arg as u64,
+            is_user: false,
+        }
     }
 
     pub fn new_user_thread(f: u64, arg: u64, stack: u64) -> SavedBasicRegisters {
-        unimplemented!()
+        SavedBasicRegisters {
+            ip: f,
+            sp: stack,
+            arg,
+            is_user: true,
+        }
     }
 }
 
+/// The extended registers of a simulated thread. The simulated CPU has no extended register state, so there is nothing to save or restore.
 #[non_exhaustive]
 #[derive(Clone, Debug)]
 pub struct SavedExtendedRegisters {}
 
 impl SavedExtendedRegisters {
     pub fn new() -> SavedExtendedRegisters {
-        unimplemented!()
+        SavedExtendedRegisters {}
     }
 
-    pub fn save(&mut self) {
-        unimplemented!()
-    }
+    pub fn save(&mut self) {}
 
-    pub fn restore(&self) {
-        unimplemented!()
-    }
+    pub fn restore(&self) {}
 }
 
 pub struct SavedRegisters {
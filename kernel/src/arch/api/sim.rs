@@ -0,0 +1,188 @@
+//! Hooks used by the host process running the simulated architecture.
+//!
+//! Before booting the kernel, the host registers a [`SimHost`] describing how the simulation should interact with it using [`set_host`].
+//! Once booted, the host drives the simulation by advancing simulated time using [`advance_time`], which raises timer interrupts as their
+//! deadlines pass, and by injecting other interrupts using [`inject_irq`](super::interrupt::inject_irq).
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::{interrupt, PhysAddr, VirtAddr};
+use crate::sched::clockevent::{self, ClockEventDevice, ClockEventFeatures};
+use crate::sync::level::InterruptLevel;
+use crate::sync::UninterruptibleSpinlock;
+use crate::util::OneShotManualInit;
+
+/// The frequency at which the simulated CPU's cycle counter advances, in Hz.
+pub const CYCLE_FREQUENCY: u64 = 1_000_000_000;
+
+/// The IRQ raised by the simulated timer.
+pub const TIMER_IRQ: usize = 0;
+
+/// The reason that the simulated machine stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimExit {
+    Halt,
+    Reboot,
+    PowerOff,
+}
+
+/// The services that the host process provides to the simulation.
+pub struct SimHost {
+    /// The range of host addresses that the kernel may use as kernel virtual memory. This must lie entirely above
+    /// [`USER_SPACE_END`](super::page::USER_SPACE_END) and should be reserved by the host so that nothing else is placed there.
+    pub kernel_virt: (VirtAddr, VirtAddr),
+    /// Called whenever a page of kernel virtual memory is mapped to a frame of physical memory, or unmapped when no frame is provided. The
+    /// host should alias the page to the frame's memory so that the kernel can dereference it.
+    pub map_kernel_page: fn(VirtAddr, Option<PhysAddr>),
+    /// Called when the simulated machine stops running. This must not return.
+    pub exit: fn(SimExit) -> !,
+}
+
+static HOST: OneShotManualInit<&'static SimHost> = OneShotManualInit::uninit();
+
+/// Registers the host process that is running the simulation.
+///
+/// # Panics
+///
+/// This function will panic if a host has already been registered.
+pub fn set_host(host: &'static SimHost) {
+    HOST.set(host);
+}
+
+/// Gets the host process that is running the simulation.
+///
+/// # Panics
+///
+/// This function will panic if no host has been registered using [`set_host`].
+pub fn host() -> &'static SimHost {
+    HOST.get()
+}
+
+/// Stops the simulated machine, or spins forever if no host has been registered to stop it.
+pub fn exit(reason: SimExit) -> ! {
+    if let Some(host) = HOST.try_get() {
+        (host.exit)(reason);
+    }
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+static NOW_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Gets the amount of simulated time that has passed since the simulation started.
+pub fn now() -> Duration {
+    Duration::from_nanos(NOW_NANOS.load(Ordering::Relaxed))
+}
+
+pub(super) fn cycles() -> u64 {
+    (u128::from(NOW_NANOS.load(Ordering::Relaxed)) * u128::from(CYCLE_FREQUENCY) / 1_000_000_000) as u64
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TimerMode {
+    Periodic { period: Duration, next: Duration },
+    OneShot { at: Duration },
+    Stopped,
+}
+
+static TIMER_MODE: UninterruptibleSpinlock<TimerMode> = UninterruptibleSpinlock::new(TimerMode::Stopped);
+
+/// Advances simulated time by the provided amount, raising a timer interrupt for each deadline of the simulated timer that passes. Each
+/// interrupt is raised with simulated time set to its deadline, so handlers observe time advancing in the same steps as on real hardware.
+pub fn advance_time(delta: Duration) {
+    let end = now() + delta;
+
+    while let Some(deadline) = next_deadline().filter(|&deadline| deadline <= end) {
+        NOW_NANOS.store(deadline.as_nanos() as u64, Ordering::Relaxed);
+
+        {
+            let mut mode = TIMER_MODE.lock();
+
+            *mode = match *mode {
+                TimerMode::Periodic { period, next } => TimerMode::Periodic {
+                    period,
+                    next: next + period,
+                },
+                TimerMode::OneShot { .. } | TimerMode::Stopped => TimerMode::Stopped,
+            };
+        }
+
+        interrupt::inject_irq(TIMER_IRQ);
+    }
+
+    NOW_NANOS.store(end.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Advances simulated time until the simulated timer's next deadline, if it has one.
+pub fn advance_to_next_event() {
+    if let Some(deadline) = next_deadline() {
+        advance_time(deadline.saturating_sub(now()));
+    }
+}
+
+fn next_deadline() -> Option<Duration> {
+    match *TIMER_MODE.lock() {
+        TimerMode::Periodic { next, .. } => Some(next),
+        TimerMode::OneShot { at } => Some(at),
+        TimerMode::Stopped => None,
+    }
+}
+
+/// A fake timer raising [`TIMER_IRQ`] as simulated time passes.
+struct SimTimer;
+
+impl ClockEventDevice for SimTimer {
+    fn name(&self) -> &'static str {
+        "simtimer"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn features(&self) -> ClockEventFeatures {
+        ClockEventFeatures::PERIODIC | ClockEventFeatures::ONESHOT
+    }
+
+    fn max_delta(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    unsafe fn set_periodic(&self, period: Duration) -> Duration {
+        let period = period.clamp(Duration::from_nanos(1), self.max_delta());
+
+        *TIMER_MODE.lock() = TimerMode::Periodic {
+            period,
+            next: now() + period,
+        };
+        period
+    }
+
+    unsafe fn set_oneshot(&self, delay: Duration) -> Duration {
+        let delay = delay.clamp(Duration::from_nanos(1), self.max_delta());
+
+        *TIMER_MODE.lock() = TimerMode::OneShot { at: now() + delay };
+        delay
+    }
+
+    unsafe fn shutdown(&self) {
+        *TIMER_MODE.lock() = TimerMode::Stopped;
+    }
+}
+
+static SIM_TIMER: SimTimer = SimTimer;
+
+pub(super) unsafe fn init_timer() {
+    interrupt::register_irq(
+        TIMER_IRQ,
+        Box::new(|_| {
+            clockevent::handle_event(&SIM_TIMER);
+        }),
+    );
+    clockevent::register(&SIM_TIMER);
+    interrupt::set_irq_level(TIMER_IRQ, InterruptLevel::Clock);
+}
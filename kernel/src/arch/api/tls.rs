@@ -0,0 +1,26 @@
+/// Thread-local storage is not simulated, since the host process's own thread-local storage cannot be switched between simulated threads.
+#[derive(Debug)]
+pub struct TlsBlock {}
+
+impl TlsBlock {
+    pub fn alloc() -> TlsBlock {
+        TlsBlock {}
+    }
+}
+
+/// Always returns zero sizes, since the simulated architecture has no TLS template.
+pub fn template_size() -> (usize, usize) {
+    (0, 0)
+}
+
+/// The simulated machine only has a single CPU core, so CPU-local variables are simply the template variables themselves.
+pub fn cpu_local_ptr<T>(ptr: *const T) -> *const T {
+    ptr
+}
+
+/// # Panics
+///
+/// The simulated machine only has a single CPU core, so this function always panics.
+pub unsafe fn init_ap() {
+    panic!("the simulated machine has no application processors");
+}
@@ -0,0 +1,18 @@
+use alloc::vec;
+
+use crate::sched::topology::{CpuTopology, LogicalCpu};
+
+/// The simulated machine always has a single CPU core with a hardware ID of 0.
+pub fn detect() -> CpuTopology {
+    CpuTopology::new(vec![LogicalCpu {
+        hw_id: 0,
+        package: 0,
+        core: 0,
+        thread: 0,
+        enabled: true,
+    }])
+}
+
+pub fn current_hw_id() -> u32 {
+    0
+}
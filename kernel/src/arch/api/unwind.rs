@@ -0,0 +1,36 @@
+//! Unwinding is not supported by the simulation, since the simulated threads don't have real call stacks, so backtraces are always empty.
+
+use core::marker::PhantomData;
+
+use super::regs::SavedBasicRegisters;
+
+/// The register state of a single frame while unwinding, which is empty for the simulated architecture.
+#[derive(Debug, Clone)]
+pub struct UnwindRegs;
+
+impl UnwindRegs {
+    pub fn current() -> UnwindRegs {
+        UnwindRegs
+    }
+
+    pub fn from_saved(_saved: &SavedBasicRegisters) -> UnwindRegs {
+        UnwindRegs
+    }
+}
+
+/// An iterator over the instruction addresses of the frames on a call stack, which never returns any frames.
+pub struct Backtrace<F: FnMut(u64) -> Option<u64>>(PhantomData<F>);
+
+impl<F: FnMut(u64) -> Option<u64>> Backtrace<F> {
+    pub fn new(_regs: UnwindRegs, _read: F) -> Backtrace<F> {
+        Backtrace(PhantomData)
+    }
+}
+
+impl<F: FnMut(u64) -> Option<u64>> Iterator for Backtrace<F> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        None
+    }
+}
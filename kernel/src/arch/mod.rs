@@ -1,5 +1,6 @@
-#[cfg_attr(feature = "real_arch_api", allow(unused))]
+#[cfg(not(feature = "real_arch_api"))]
 mod api;
+mod page_flags;
 
 cfg_if::cfg_if! {
     if #[cfg(not(feature = "real_arch_api"))] {
@@ -0,0 +1,15 @@
+use bitflags::bitflags;
+
+bitflags! {
+    /// The architecture-independent protection flags with which a page of virtual memory is mapped. Every architecture's `page` module
+    /// re-exports this type.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PageFlags: u16 {
+        const USER = 0x1;
+        const WRITEABLE = 0x2;
+        const EXECUTABLE = 0x4;
+        const WRITE_THROUGH = 0x8;
+        const UNCACHED = 0x10;
+        const COPY_ON_WRITE = 0x20;
+    }
+}
@@ -0,0 +1,228 @@
+//! Minimal support for locating and reading ACPI tables.
+//!
+//! This only supports finding the RSDP by scanning the legacy BIOS areas of memory, which works on all BIOS-based machines. Tables are read
+//! directly from physical memory and are validated using their checksums before being used.
+
+use alloc::vec::Vec;
+
+use super::page::get_phys_mem_ptr_slice;
+use super::PhysAddr;
+use crate::log;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+
+const SDT_HEADER_LEN: usize = 36;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const MADT_ENTRIES_OFFSET: usize = SDT_HEADER_LEN + 8;
+
+const MADT_ENTRY_LOCAL_APIC: u8 = 0;
+const MADT_ENTRY_LOCAL_X2APIC: u8 = 9;
+
+const MADT_PROCESSOR_ENABLED: u32 = 1 << 0;
+const MADT_PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Gets a slice referring to a range of physical memory.
+///
+/// # Safety
+///
+/// The provided range of physical memory must be mapped and must not be modified while the returned slice is in use.
+unsafe fn phys_bytes<'a>(addr: PhysAddr, len: usize) -> &'a [u8] {
+    &*get_phys_mem_ptr_slice::<u8>(addr, len).ptr()
+}
+
+fn read_u16(bytes: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(bytes[off..off + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(bytes[off..off + 8].try_into().unwrap())
+}
+
+fn is_checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0_u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RootTable {
+    Rsdt(PhysAddr),
+    Xsdt(PhysAddr),
+}
+
+fn find_rsdp_in(start: u64, len: u64) -> Option<RootTable> {
+    for addr in (start..start + len).step_by(16) {
+        // SAFETY: The legacy BIOS areas are always mapped and are never modified.
+        let rsdp = unsafe { phys_bytes(PhysAddr::new(addr), RSDP_V1_LEN) };
+
+        if &rsdp[0..8] != RSDP_SIGNATURE || !is_checksum_valid(rsdp) {
+            continue;
+        }
+
+        if rsdp[15] >= 2 {
+            // SAFETY: As above
+            let rsdp = unsafe { phys_bytes(PhysAddr::new(addr), RSDP_V2_LEN) };
+            let xsdt = read_u64(rsdp, 24);
+
+            if is_checksum_valid(rsdp) && xsdt != 0 {
+                return Some(RootTable::Xsdt(PhysAddr::new(xsdt)));
+            }
+        }
+
+        return Some(RootTable::Rsdt(PhysAddr::new(u64::from(read_u32(rsdp, 16)))));
+    }
+
+    None
+}
+
+fn find_root_table() -> Option<RootTable> {
+    // SAFETY: The BIOS data area is always mapped and is never modified.
+    let ebda = u64::from(read_u16(unsafe { phys_bytes(PhysAddr::new(0x40e), 2) }, 0)) << 4;
+
+    (if ebda != 0 { find_rsdp_in(ebda, 1024) } else { None }).or_else(|| find_rsdp_in(0xe0000, 0x20000))
+}
+
+fn root_table() -> Option<RootTable> {
+    let root_table = find_root_table();
+
+    if root_table.is_none() {
+        log!(Warning, "acpi", "No ACPI tables were found");
+    }
+
+    root_table
+}
+
+/// Reads the ACPI table at the provided physical address, returning [`None`] if its checksum is invalid.
+fn read_table<'a>(addr: PhysAddr) -> Option<&'a [u8]> {
+    // SAFETY: ACPI tables are in memory reserved by the firmware, which is mapped but never used by the kernel.
+    let len = read_u32(unsafe { phys_bytes(addr, SDT_HEADER_LEN) }, 4) as usize;
+
+    if len < SDT_HEADER_LEN {
+        return None;
+    }
+
+    // SAFETY: As above
+    let table = unsafe { phys_bytes(addr, len) };
+
+    if is_checksum_valid(table) {
+        Some(table)
+    } else {
+        log!(Warning, "acpi", "Table at {:#x} has an invalid checksum", addr.as_u64());
+        None
+    }
+}
+
+/// Finds the ACPI table with the provided signature, returning its full contents including its header.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let (root, entry_size) = match root_table()? {
+        RootTable::Rsdt(addr) => (read_table(addr)?, 4),
+        RootTable::Xsdt(addr) => (read_table(addr)?, 8),
+    };
+
+    root[SDT_HEADER_LEN..]
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            PhysAddr::new(if entry_size == 8 {
+                read_u64(entry, 0)
+            } else {
+                u64::from(read_u32(entry, 0))
+            })
+        })
+        .filter_map(read_table)
+        .find(|table| &table[0..4] == signature)
+}
+
+/// A processor listed in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MadtProcessor {
+    /// The ACPI processor UID of this processor.
+    pub uid: u32,
+    /// The local APIC ID (or x2APIC ID) of this processor.
+    pub apic_id: u32,
+    /// Whether this processor is enabled. Processors that are not enabled may be brought online later.
+    pub enabled: bool,
+}
+
+fn parse_madt_processors(madt: &[u8]) -> Vec<MadtProcessor> {
+    let mut processors = Vec::new();
+    let mut off = MADT_ENTRIES_OFFSET;
+
+    while off + 2 <= madt.len() {
+        let entry_type = madt[off];
+        let entry_len = madt[off + 1] as usize;
+
+        if entry_len < 2 || off + entry_len > madt.len() {
+            log!(Warning, "acpi", "MADT has malformed entry at offset {}", off);
+            break;
+        }
+
+        let entry = &madt[off..off + entry_len];
+        let processor = match entry_type {
+            MADT_ENTRY_LOCAL_APIC if entry_len >= 8 => Some((u32::from(entry[2]), u32::from(entry[3]), read_u32(entry, 4))),
+            MADT_ENTRY_LOCAL_X2APIC if entry_len >= 16 => Some((read_u32(entry, 12), read_u32(entry, 4), read_u32(entry, 8))),
+            _ => None,
+        };
+
+        if let Some((uid, apic_id, flags)) = processor {
+            // Processors that are neither enabled nor online capable can never be used and should be ignored
+            if flags & (MADT_PROCESSOR_ENABLED | MADT_PROCESSOR_ONLINE_CAPABLE) != 0 {
+                processors.push(MadtProcessor {
+                    uid,
+                    apic_id,
+                    enabled: flags & MADT_PROCESSOR_ENABLED != 0,
+                });
+            }
+        }
+
+        off += entry_len;
+    }
+
+    processors
+}
+
+/// Gets a list of all processors listed in the MADT, or [`None`] if no MADT was found.
+pub fn madt_processors() -> Option<Vec<MadtProcessor>> {
+    find_table(MADT_SIGNATURE).map(parse_madt_processors)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test_case]
+    fn test_parse_madt_processors() {
+        let mut madt = vec![0_u8; MADT_ENTRIES_OFFSET];
+
+        // Enabled local APIC with UID 0 and ID 0
+        madt.extend_from_slice(&[MADT_ENTRY_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        // Unusable local APIC
+        madt.extend_from_slice(&[MADT_ENTRY_LOCAL_APIC, 8, 1, 1, 0, 0, 0, 0]);
+        // I/O APIC, which should be skipped
+        madt.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+        // Online capable x2APIC with UID 2 and ID 0x100
+        madt.extend_from_slice(&[MADT_ENTRY_LOCAL_X2APIC, 16, 0, 0, 0, 1, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0]);
+
+        assert_eq!(
+            vec![
+                MadtProcessor {
+                    uid: 0,
+                    apic_id: 0,
+                    enabled: true
+                },
+                MadtProcessor {
+                    uid: 2,
+                    apic_id: 0x100,
+                    enabled: false
+                },
+            ],
+            parse_madt_processors(&madt)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use core::arch::asm;
+pub use core::arch::x86_64::CpuidResult;
 
 use crate::util::OneShotManualInit;
 
@@ -24,6 +25,26 @@ impl CpuFeature {
         feature_vec_bit: 1 << 26,
         name: "xsave",
     };
+    pub const MONITOR: CpuFeature = CpuFeature {
+        feature_vec_idx: CpuFeature::FEATURE_VEC_IDX_01_ECX,
+        feature_vec_bit: 1 << 3,
+        name: "monitor",
+    };
+    pub const APIC: CpuFeature = CpuFeature {
+        feature_vec_idx: CpuFeature::FEATURE_VEC_IDX_01_EDX,
+        feature_vec_bit: 1 << 9,
+        name: "apic",
+    };
+    pub const MCE: CpuFeature = CpuFeature {
+        feature_vec_idx: CpuFeature::FEATURE_VEC_IDX_01_EDX,
+        feature_vec_bit: 1 << 7,
+        name: "mce",
+    };
+    pub const MCA: CpuFeature = CpuFeature {
+        feature_vec_idx: CpuFeature::FEATURE_VEC_IDX_01_EDX,
+        feature_vec_bit: 1 << 14,
+        name: "mca",
+    };
 }
 
 pub struct CpuFeatureSet([u32; CpuFeatureSet::NUM_FEATURE_VECS]);
@@ -59,6 +80,18 @@ impl CpuFeatureSet {
     }
 }
 
+/// Gets the highest basic leaf supported by the `cpuid` instruction.
+pub fn max_leaf() -> u32 {
+    query(0, 0).eax
+}
+
+/// Executes the `cpuid` instruction with the provided leaf and subleaf. The caller is responsible for checking that the leaf is supported
+/// using [`max_leaf`].
+pub fn query(leaf: u32, subleaf: u32) -> CpuidResult {
+    // SAFETY: All processors supported by the kernel have the cpuid instruction.
+    unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) }
+}
+
 static MIN_FEATURES: OneShotManualInit<CpuFeatureSet> = OneShotManualInit::uninit();
 
 pub(super) fn init_bsp() {
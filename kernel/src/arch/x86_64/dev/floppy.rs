@@ -0,0 +1,963 @@
+//! Driver for 82077AA-compatible floppy disk controllers.
+//!
+//! Each drive reported by the CMOS is registered as a [`BlockDevice`] under the `::block` hub (`fd0` and `fd1`), assuming that the inserted
+//! media uses the highest-capacity format supported by the drive. The controller can only perform one operation at a time and most
+//! operations involve waiting for a motor to spin up or a head to settle, so all requests are performed by a dedicated worker thread that
+//! talks to the controller directly and blocks while waiting for its IRQ. Data is transferred using ISA DMA channel 2 through a bounce
+//! buffer below 16MiB, one cylinder at a time.
+//!
+//! Drive motors are turned on when a request arrives and turned off again once the controller has been idle for a few seconds. Before each
+//! request, the disk change line of the drive is checked: if the media was removed or replaced since the previous request, that request
+//! fails with [`BlockDeviceError::MediaChanged`] so that anything cached about the old media can be thrown away, and requests fail with
+//! [`BlockDeviceError::NoMedia`] for as long as the drive is empty.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use core::{fmt, ptr, slice};
+
+use dyn_dyn::dyn_dyn_impl;
+use x86_64::instructions::port::Port;
+
+use super::isadma::{self, IsaDmaChannel, IsaDmaDirection, IsaDmaError, IsaDmaMode};
+use crate::arch::pic;
+use crate::io::block::queue::{BlockRequest, BlockRequestHandler, RequestQueue};
+use crate::io::block::{self, BlockDevice, BlockDeviceError};
+use crate::io::dev::driver::{DeviceInfo, Driver, MatchRule, ProbeError};
+use crate::io::dev::iostat::IoDirection;
+use crate::io::dev::{device_root, Device, DeviceNode, DeviceRef};
+use crate::log;
+use crate::mem::dma::DmaBuffer;
+use crate::sched::task::{Process, Thread};
+use crate::sched::timer;
+use crate::sched::wait::{ThreadWaitList, WaitResult};
+use crate::sync::future::FutureWriter;
+use crate::sync::{Future, UninterruptibleSpinlock};
+
+const FDC_IRQ: u8 = 6;
+const FDC_DMA_CHANNEL: u8 = 2;
+const FDC_STACK_SIZE: usize = 4 * 4096;
+
+const PORT_DOR: u16 = 0x3f2;
+const PORT_MSR: u16 = 0x3f4;
+const PORT_FIFO: u16 = 0x3f5;
+const PORT_DIR: u16 = 0x3f7;
+const PORT_CCR: u16 = 0x3f7;
+
+const PORT_CMOS_ADDR: u16 = 0x70;
+const PORT_CMOS_DATA: u16 = 0x71;
+const CMOS_FLOPPY_TYPES: u8 = 0x10;
+
+const DOR_NOT_RESET: u8 = 0x04;
+const DOR_IRQ_DMA: u8 = 0x08;
+const DOR_MOTOR: u8 = 0x10;
+const DOR_MOTORS: u8 = 0xf0;
+
+const MSR_DIO: u8 = 0x40;
+const MSR_RQM: u8 = 0x80;
+
+const DIR_DISK_CHANGE: u8 = 0x80;
+
+const CMD_SPECIFY: u8 = 0x03;
+const CMD_WRITE_DATA: u8 = 0x05;
+const CMD_READ_DATA: u8 = 0x06;
+const CMD_RECALIBRATE: u8 = 0x07;
+const CMD_SENSE_INTERRUPT: u8 = 0x08;
+const CMD_SEEK: u8 = 0x0f;
+const CMD_VERSION: u8 = 0x10;
+const CMD_CONFIGURE: u8 = 0x13;
+const CMD_LOCK: u8 = 0x94;
+const CMD_MFM: u8 = 0x40;
+const CMD_MT: u8 = 0x80;
+
+/// Enables the FIFO with a threshold of 8 bytes and disables drive polling and implied seeks.
+const CONFIGURE_FLAGS: u8 = 0x17;
+/// A step rate of 3ms, a head unload time of 240ms and a head load time of 4ms, with DMA enabled.
+const SPECIFY_PARAMS: [u8; 2] = [0xdf, 0x02];
+
+const ST0_INT_CODE: u8 = 0xc0;
+const ST0_INVALID_COMMAND: u8 = 0x80;
+const ST1_NOT_WRITABLE: u8 = 0x02;
+
+const VERSION_82077: u8 = 0x90;
+
+/// The size of a sector on a floppy disk in bytes.
+pub const FLOPPY_SECTOR_SIZE: usize = 512;
+
+/// The sector size code passed to read and write commands, where a sector is 128 << code bytes long.
+const SECTOR_SIZE_CODE: u8 = 2;
+
+/// The number of times the FIFO is polled while waiting for the controller to accept or produce a byte before giving up.
+const FIFO_TIMEOUT: u32 = 100000;
+const IRQ_TIMEOUT: Duration = Duration::from_secs(3);
+const MOTOR_SPIN_UP: Duration = Duration::from_millis(500);
+const MOTOR_OFF_DELAY: Duration = Duration::from_secs(3);
+const HEAD_SETTLE: Duration = Duration::from_millis(15);
+const MAX_RETRIES: u32 = 3;
+
+/// The layout of the media in a floppy drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloppyGeometry {
+    pub cylinders: u8,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+    /// The value written to the configuration control register to select the data rate used by the media.
+    data_rate: u8,
+    /// The length of the gap between sectors passed to read and write commands.
+    gap3: u8,
+}
+
+impl FloppyGeometry {
+    /// Gets the geometry of the highest-capacity format supported by the drive type with the provided code, as reported by the CMOS.
+    pub fn from_cmos_type(ty: u8) -> Option<FloppyGeometry> {
+        let (cylinders, sectors_per_track, data_rate, gap3) = match ty {
+            // 360KiB 5.25"
+            1 => (40, 9, 2, 0x2a),
+            // 1.2MiB 5.25"
+            2 => (80, 15, 0, 0x1b),
+            // 720KiB 3.5"
+            3 => (80, 9, 2, 0x2a),
+            // 1.44MiB 3.5"
+            4 => (80, 18, 0, 0x1b),
+            // 2.88MiB 3.5"
+            5 => (80, 36, 3, 0x1b),
+            _ => {
+                return None;
+            },
+        };
+
+        Some(FloppyGeometry {
+            cylinders,
+            heads: 2,
+            sectors_per_track,
+            data_rate,
+            gap3,
+        })
+    }
+
+    /// Gets the number of sectors in a single cylinder, which is the most that can be transferred by a single command.
+    pub fn sectors_per_cylinder(&self) -> u64 {
+        u64::from(self.heads) * u64::from(self.sectors_per_track)
+    }
+
+    /// Gets the total number of sectors on the media.
+    pub fn num_sectors(&self) -> u64 {
+        u64::from(self.cylinders) * self.sectors_per_cylinder()
+    }
+
+    /// Converts a sector number into the cylinder, head and (1-based) sector at which it is stored.
+    pub fn to_chs(&self, sector: u64) -> (u8, u8, u8) {
+        let cylinder = sector / self.sectors_per_cylinder();
+        let head = sector % self.sectors_per_cylinder() / u64::from(self.sectors_per_track);
+        let sector = sector % u64::from(self.sectors_per_track) + 1;
+
+        (cylinder as u8, head as u8, sector as u8)
+    }
+}
+
+/// An error that can occur while performing an operation on a floppy drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FloppyError {
+    /// The controller did not respond in time.
+    Timeout,
+    /// The controller was expecting to send data while a command was being sent, or vice versa.
+    UnexpectedPhase,
+    /// The head could not be moved to the requested cylinder.
+    SeekFailed,
+    /// There is no media in the drive.
+    NoMedia,
+    /// The media was replaced since the drive was last accessed.
+    MediaChanged,
+    /// The media is write-protected.
+    WriteProtected,
+    /// A read or write command failed with the provided status bytes.
+    Transfer(u8, u8, u8),
+    /// The DMA channel could not be programmed.
+    Dma(IsaDmaError),
+}
+
+impl fmt::Display for FloppyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FloppyError::Timeout => write!(f, "controller timed out"),
+            FloppyError::UnexpectedPhase => write!(f, "unexpected controller phase"),
+            FloppyError::SeekFailed => write!(f, "seek failed"),
+            FloppyError::NoMedia => write!(f, "no media"),
+            FloppyError::MediaChanged => write!(f, "media changed"),
+            FloppyError::WriteProtected => write!(f, "media is write-protected"),
+            FloppyError::Transfer(st0, st1, st2) => write!(f, "transfer failed (ST0={:02x} ST1={:02x} ST2={:02x})", st0, st1, st2),
+            FloppyError::Dma(err) => write!(f, "DMA error: {}", err),
+        }
+    }
+}
+
+impl From<FloppyError> for BlockDeviceError {
+    fn from(err: FloppyError) -> BlockDeviceError {
+        match err {
+            FloppyError::NoMedia => BlockDeviceError::NoMedia,
+            FloppyError::MediaChanged => BlockDeviceError::MediaChanged,
+            FloppyError::WriteProtected => BlockDeviceError::ReadOnly,
+            _ => BlockDeviceError::IoError,
+        }
+    }
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    Port::new(port).read()
+}
+
+unsafe fn outb(port: u16, val: u8) {
+    Port::new(port).write(val)
+}
+
+/// Waits for the controller to be ready to transfer a byte through its FIFO in the provided direction.
+unsafe fn wait_fifo(controller_to_cpu: bool) -> Result<(), FloppyError> {
+    for _ in 0..FIFO_TIMEOUT {
+        let msr = inb(PORT_MSR);
+
+        if msr & MSR_RQM != 0 {
+            return if (msr & MSR_DIO != 0) == controller_to_cpu {
+                Ok(())
+            } else {
+                Err(FloppyError::UnexpectedPhase)
+            };
+        }
+
+        core::hint::spin_loop();
+    }
+
+    Err(FloppyError::Timeout)
+}
+
+unsafe fn send_command(bytes: &[u8]) -> Result<(), FloppyError> {
+    for &b in bytes {
+        wait_fifo(false)?;
+        outb(PORT_FIFO, b);
+    }
+
+    Ok(())
+}
+
+unsafe fn read_result_byte() -> Result<u8, FloppyError> {
+    wait_fifo(true)?;
+    Ok(inb(PORT_FIFO))
+}
+
+unsafe fn read_cmos(reg: u8) -> u8 {
+    outb(PORT_CMOS_ADDR, reg);
+    inb(PORT_CMOS_DATA)
+}
+
+/// A contiguous buffer in memory that is part of a request being performed by the worker thread.
+#[derive(Debug, Clone, Copy)]
+struct Segment(*mut [u8]);
+
+// SAFETY: The submitter of a request guarantees that its buffer is not accessed by anything else until the request completes, so the
+//         request can be handed to the worker thread
+unsafe impl Send for Segment {}
+
+/// Copies between a contiguous buffer and the bytes of a request starting at the provided offset into its segments.
+///
+/// # Safety
+///
+/// The segments must be valid for reads and writes and must not overlap the provided buffer.
+unsafe fn copy_segments(segments: &[Segment], mut offset: usize, buf: &mut [u8], to_segments: bool) {
+    let mut done = 0;
+
+    for &Segment(seg) in segments {
+        if done == buf.len() {
+            break;
+        } else if offset >= seg.len() {
+            offset -= seg.len();
+            continue;
+        }
+
+        let len = (seg.len() - offset).min(buf.len() - done);
+        let seg_ptr = (seg as *mut u8).add(offset);
+
+        if to_segments {
+            ptr::copy_nonoverlapping(buf[done..].as_ptr(), seg_ptr, len);
+        } else {
+            ptr::copy_nonoverlapping(seg_ptr, buf[done..].as_mut_ptr(), len);
+        }
+
+        done += len;
+        offset = 0;
+    }
+}
+
+struct FloppyJob {
+    drive: u8,
+    dir: IoDirection,
+    sector: u64,
+    num_sectors: u64,
+    segments: Vec<Segment>,
+    writer: FutureWriter<Result<(), BlockDeviceError>>,
+}
+
+/// The state of the controller shared between the worker thread, the IRQ handler and the drives submitting requests.
+struct FdcShared {
+    jobs: UninterruptibleSpinlock<VecDeque<FloppyJob>>,
+    wait: ThreadWaitList,
+    irq: UninterruptibleSpinlock<Option<FutureWriter<()>>>,
+}
+
+impl FdcShared {
+    fn handle_irq(&self) {
+        if let Some(irq) = self.irq.lock().take() {
+            irq.finish(());
+        }
+    }
+}
+
+impl fmt::Debug for FdcShared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdcShared")
+            .field("pending", &self.jobs.lock().len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct FloppyDriveHandler {
+    drive: u8,
+    geometry: FloppyGeometry,
+    shared: Arc<FdcShared>,
+}
+
+impl BlockRequestHandler for FloppyDriveHandler {
+    fn sector_size(&self) -> usize {
+        FLOPPY_SECTOR_SIZE
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.geometry.num_sectors()
+    }
+
+    fn max_sectors(&self) -> u64 {
+        self.geometry.sectors_per_cylinder()
+    }
+
+    fn handle(&self, req: &BlockRequest) -> Future<Result<(), BlockDeviceError>> {
+        let (future, writer) = Future::new();
+
+        self.shared.jobs.lock().push_back(FloppyJob {
+            drive: self.drive,
+            dir: req.dir(),
+            sector: req.sector(),
+            num_sectors: req.num_sectors(),
+            segments: req.segments().map(Segment).collect(),
+            writer,
+        });
+        self.shared.wait.wake_one();
+        future
+    }
+}
+
+/// A floppy drive connected to an 82077AA-compatible floppy disk controller.
+#[derive(Debug)]
+pub struct FloppyDrive {
+    queue: Arc<RequestQueue<FloppyDriveHandler>>,
+}
+
+impl FloppyDrive {
+    /// Gets the geometry that the media in this drive is assumed to have.
+    pub fn geometry(&self) -> FloppyGeometry {
+        self.queue.handler().geometry
+    }
+}
+
+impl BlockDevice for FloppyDrive {
+    fn sector_size(&self) -> usize {
+        FLOPPY_SECTOR_SIZE
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.queue.handler().num_sectors()
+    }
+
+    unsafe fn read_sectors(&self, sector: u64, buf: *mut [u8]) -> Future<Result<(), BlockDeviceError>> {
+        self.queue.read(sector, buf)
+    }
+
+    unsafe fn write_sectors(&self, sector: u64, buf: *const [u8]) -> Future<Result<(), BlockDeviceError>> {
+        self.queue.write(sector, buf)
+    }
+}
+
+#[dyn_dyn_impl(BlockDevice)]
+impl Device for FloppyDrive {}
+
+/// An 82077AA-compatible floppy disk controller.
+#[derive(Debug)]
+pub struct FloppyController {}
+
+#[dyn_dyn_impl]
+impl Device for FloppyController {}
+
+struct FloppyDriveState {
+    dev: DeviceRef<FloppyDrive>,
+    geometry: FloppyGeometry,
+    /// The cylinder that the head is known to be over, or [`None`] if it needs to be recalibrated.
+    cylinder: Option<u8>,
+    /// Whether media was present the last time the disk change line was checked, or [`None`] if it hasn't been checked yet.
+    has_media: Option<bool>,
+}
+
+/// The state of the worker thread, which is the only thing that accesses the controller's registers once it has been started.
+struct FloppyWorker {
+    shared: Arc<FdcShared>,
+    controller: DeviceRef<FloppyController>,
+    dma: IsaDmaChannel,
+    dma_buf: DmaBuffer,
+    drives: [Option<FloppyDriveState>; 2],
+    dor: u8,
+    initialized: bool,
+}
+
+impl FloppyWorker {
+    fn drive(&mut self, drive: u8) -> &mut FloppyDriveState {
+        self.drives[usize::from(drive)].as_mut().unwrap()
+    }
+
+    fn expect_irq(&self) -> Future<()> {
+        let (future, writer) = Future::new();
+
+        if let Some(old) = self.shared.irq.lock().replace(writer) {
+            old.finish(());
+        }
+
+        future
+    }
+
+    fn wait_irq(&self, irq: Future<()>) -> Result<(), FloppyError> {
+        match Future::any([irq, timer::after(IRQ_TIMEOUT)]).unwrap().unwrap_blocking() {
+            0 => Ok(()),
+            _ => {
+                if let Some(irq) = self.shared.irq.lock().take() {
+                    irq.finish(());
+                }
+
+                Err(FloppyError::Timeout)
+            },
+        }
+    }
+
+    fn sense_interrupt(&self) -> Result<(u8, u8), FloppyError> {
+        // SAFETY: Only the worker thread accesses the controller
+        unsafe {
+            send_command(&[CMD_SENSE_INTERRUPT])?;
+
+            let st0 = read_result_byte()?;
+
+            // There is no cylinder number when no interrupt was pending
+            if st0 == ST0_INVALID_COMMAND {
+                return Ok((st0, 0));
+            }
+
+            Ok((st0, read_result_byte()?))
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), FloppyError> {
+        self.initialized = false;
+
+        for drive in self.drives.iter_mut().flatten() {
+            drive.cylinder = None;
+        }
+
+        let irq = self.expect_irq();
+
+        self.dor = DOR_NOT_RESET | DOR_IRQ_DMA;
+
+        // SAFETY: Only the worker thread accesses the controller
+        unsafe {
+            outb(PORT_DOR, 0);
+            outb(PORT_DOR, self.dor);
+        }
+
+        self.wait_irq(irq)?;
+
+        // The controller raises a separate interrupt for each of the 4 drives it could be polling after a reset
+        for _ in 0..4 {
+            self.sense_interrupt()?;
+        }
+
+        // SAFETY: Only the worker thread accesses the controller
+        unsafe {
+            send_command(&[CMD_CONFIGURE, 0, CONFIGURE_FLAGS, 0])?;
+            send_command(&[CMD_LOCK])?;
+            read_result_byte()?;
+            send_command(&[CMD_SPECIFY, SPECIFY_PARAMS[0], SPECIFY_PARAMS[1]])?;
+        }
+
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn motors_on(&self) -> bool {
+        self.dor & DOR_MOTORS != 0
+    }
+
+    fn motors_off(&mut self) {
+        self.dor &= !DOR_MOTORS;
+
+        // SAFETY: Only the worker thread accesses the controller
+        unsafe {
+            outb(PORT_DOR, self.dor);
+        }
+    }
+
+    /// Selects the provided drive and turns on its motor, waiting for it to spin up if it was off.
+    fn select(&mut self, drive: u8) {
+        let motor = DOR_MOTOR << drive;
+        let was_on = self.dor & motor != 0;
+        let data_rate = self.drive(drive).geometry.data_rate;
+
+        self.dor = (self.dor & !0x03) | motor | drive;
+
+        // SAFETY: Only the worker thread accesses the controller
+        unsafe {
+            outb(PORT_DOR, self.dor);
+            outb(PORT_CCR, data_rate);
+        }
+
+        if !was_on {
+            Thread::sleep(MOTOR_SPIN_UP);
+        }
+    }
+
+    fn recalibrate(&mut self, drive: u8) -> Result<(), FloppyError> {
+        self.drive(drive).cylinder = None;
+
+        // A single recalibration steps the head at most 79 times, which may not be enough to reach cylinder 0 on an 80-cylinder drive
+        for _ in 0..2 {
+            let irq = self.expect_irq();
+
+            // SAFETY: Only the worker thread accesses the controller
+            unsafe {
+                send_command(&[CMD_RECALIBRATE, drive])?;
+            }
+
+            self.wait_irq(irq)?;
+
+            let (st0, cylinder) = self.sense_interrupt()?;
+
+            if st0 & ST0_INT_CODE == 0 && cylinder == 0 {
+                self.drive(drive).cylinder = Some(0);
+                return Ok(());
+            }
+        }
+
+        Err(FloppyError::SeekFailed)
+    }
+
+    fn seek(&mut self, drive: u8, cylinder: u8) -> Result<(), FloppyError> {
+        if self.drive(drive).cylinder == Some(cylinder) {
+            return Ok(());
+        } else if self.drive(drive).cylinder.is_none() {
+            self.recalibrate(drive)?;
+
+            if cylinder == 0 {
+                return Ok(());
+            }
+        }
+
+        self.drive(drive).cylinder = None;
+
+        let irq = self.expect_irq();
+
+        // SAFETY: Only the worker thread accesses the controller
+        unsafe {
+            send_command(&[CMD_SEEK, drive, cylinder])?;
+        }
+
+        self.wait_irq(irq)?;
+
+        let (st0, new_cylinder) = self.sense_interrupt()?;
+
+        if st0 & ST0_INT_CODE != 0 || new_cylinder != cylinder {
+            return Err(FloppyError::SeekFailed);
+        }
+
+        self.drive(drive).cylinder = Some(cylinder);
+        Thread::sleep(HEAD_SETTLE);
+
+        Ok(())
+    }
+
+    /// Checks the disk change line of the currently selected drive, returning an error if there is no media in it or if the media was
+    /// replaced since the last time it was checked.
+    fn check_media(&mut self, drive: u8) -> Result<(), FloppyError> {
+        // SAFETY: Only the worker thread accesses the controller
+        if unsafe { inb(PORT_DIR) } & DIR_DISK_CHANGE == 0 {
+            self.drive(drive).has_media = Some(true);
+            return Ok(());
+        }
+
+        // The disk change line stays set until the head is stepped with media in the drive
+        self.seek(drive, 1)?;
+        self.recalibrate(drive)?;
+
+        // SAFETY: Only the worker thread accesses the controller
+        let has_media = unsafe { inb(PORT_DIR) } & DIR_DISK_CHANGE == 0;
+        let state = self.drive(drive);
+        let had_media = state.has_media.replace(has_media);
+
+        if !has_media {
+            if had_media != Some(false) {
+                log!(Info, "floppy", "No media in {}", state.dev.name());
+                state.dev.set_status(Some("no media"));
+            }
+
+            Err(FloppyError::NoMedia)
+        } else if had_media == Some(true) {
+            log!(Info, "floppy", "Media in {} was changed", state.dev.name());
+            Err(FloppyError::MediaChanged)
+        } else {
+            if had_media == Some(false) {
+                log!(Info, "floppy", "Media was inserted into {}", state.dev.name());
+            }
+
+            state.dev.set_status(None);
+            Ok(())
+        }
+    }
+
+    /// Transfers up to a cylinder's worth of sectors between the drive and the DMA buffer.
+    fn transfer(&mut self, drive: u8, dir: IoDirection, sector: u64, count: u64) -> Result<(), FloppyError> {
+        let geometry = self.drive(drive).geometry;
+        let (cylinder, head, first_sector) = geometry.to_chs(sector);
+        let len = count as usize * FLOPPY_SECTOR_SIZE;
+        let (command, dma_dir) = match dir {
+            IoDirection::Read => (CMD_READ_DATA, IsaDmaDirection::DeviceToMemory),
+            IoDirection::Write => (CMD_WRITE_DATA, IsaDmaDirection::MemoryToDevice),
+        };
+
+        self.seek(drive, cylinder)?;
+
+        let result: Result<[u8; 7], FloppyError> = try {
+            // SAFETY: The DMA buffer is owned by this worker and the channel is stopped below before it is touched again
+            unsafe {
+                self.dma
+                    .start(&self.dma_buf, len, dma_dir, IsaDmaMode::Single, false)
+                    .map_err(FloppyError::Dma)?;
+            }
+
+            let irq = self.expect_irq();
+
+            // SAFETY: Only the worker thread accesses the controller
+            unsafe {
+                send_command(&[
+                    command | CMD_MT | CMD_MFM,
+                    (head << 2) | drive,
+                    cylinder,
+                    head,
+                    first_sector,
+                    SECTOR_SIZE_CODE,
+                    geometry.sectors_per_track,
+                    geometry.gap3,
+                    0xff,
+                ])?;
+            }
+
+            self.wait_irq(irq)?;
+
+            let mut status = [0; 7];
+
+            for b in status.iter_mut() {
+                // SAFETY: Only the worker thread accesses the controller
+                *b = unsafe { read_result_byte()? };
+            }
+
+            status
+        };
+
+        self.dma.stop();
+
+        let [st0, st1, st2, ..] = result?;
+
+        if st0 & ST0_INT_CODE == 0 {
+            Ok(())
+        } else if st1 & ST1_NOT_WRITABLE != 0 {
+            Err(FloppyError::WriteProtected)
+        } else {
+            Err(FloppyError::Transfer(st0, st1, st2))
+        }
+    }
+
+    fn transfer_with_retries(&mut self, drive: u8, dir: IoDirection, sector: u64, count: u64) -> Result<(), FloppyError> {
+        let mut retries = 0;
+
+        loop {
+            let err = match self.transfer(drive, dir, sector, count) {
+                Ok(()) => {
+                    return Ok(());
+                },
+                Err(err @ (FloppyError::WriteProtected | FloppyError::Dma(_))) => {
+                    return Err(err);
+                },
+                Err(err) => err,
+            };
+
+            let dev = self.drive(drive).dev.clone();
+
+            dev.record_error();
+
+            if retries == MAX_RETRIES {
+                log!(Error, "floppy", "Failed to access sector {} of {}: {}", sector, dev.name(), err);
+                return Err(err);
+            }
+
+            log!(Warning, "floppy", "Retrying access to sector {} of {}: {}", sector, dev.name(), err);
+            retries += 1;
+
+            // A timeout leaves the controller in an unknown state, while other errors may have been caused by the head being somewhere
+            // other than where it was thought to be
+            if err == FloppyError::Timeout {
+                self.reset()?;
+                self.select(drive);
+            } else {
+                self.recalibrate(drive)?;
+            }
+        }
+    }
+
+    fn perform(&mut self, job: &FloppyJob) -> Result<(), FloppyError> {
+        if !self.initialized {
+            self.reset()?;
+        }
+
+        self.select(job.drive);
+        self.check_media(job.drive)?;
+
+        let sectors_per_cylinder = self.drive(job.drive).geometry.sectors_per_cylinder();
+        let end = job.sector + job.num_sectors;
+        let mut sector = job.sector;
+        let mut offset = 0;
+
+        while sector < end {
+            let count = (end - sector).min(sectors_per_cylinder - sector % sectors_per_cylinder);
+            let len = count as usize * FLOPPY_SECTOR_SIZE;
+
+            // SAFETY: The DMA buffer is only accessed by this worker and is large enough to hold a whole cylinder
+            let buf = unsafe { slice::from_raw_parts_mut(self.dma_buf.as_ptr(), len) };
+
+            if job.dir == IoDirection::Write {
+                // SAFETY: The submitter of the request guarantees that its segments are valid until it is completed
+                unsafe {
+                    copy_segments(&job.segments, offset, buf, false);
+                }
+            }
+
+            self.transfer_with_retries(job.drive, job.dir, sector, count)?;
+
+            if job.dir == IoDirection::Read {
+                // SAFETY: As above
+                unsafe {
+                    copy_segments(&job.segments, offset, buf, true);
+                }
+            }
+
+            sector += count;
+            offset += len;
+        }
+
+        Ok(())
+    }
+
+    fn run(mut self) {
+        if let Err(err) = self.reset() {
+            log!(Error, "floppy", "Failed to reset floppy controller: {}", err);
+            self.controller.record_error();
+        }
+
+        loop {
+            let mut jobs = self.shared.jobs.lock();
+
+            if let Some(job) = jobs.pop_front() {
+                drop(jobs);
+
+                let result = self.perform(&job);
+
+                if let Err(FloppyError::Timeout) = result {
+                    // Make sure the next request starts from a known state
+                    self.initialized = false;
+                }
+
+                job.writer.finish(result.map_err(BlockDeviceError::from));
+            } else if self.motors_on() {
+                let wait = self.shared.wait.wait_timeout(MOTOR_OFF_DELAY);
+
+                drop(jobs);
+
+                if wait.suspend() == WaitResult::TimedOut {
+                    self.motors_off();
+                }
+            } else {
+                let wait = self.shared.wait.wait();
+
+                drop(jobs);
+                wait.suspend();
+            }
+        }
+    }
+}
+
+/// The driver for 82077AA-compatible floppy disk controllers.
+pub struct FloppyDriver;
+
+pub static DRIVER: FloppyDriver = FloppyDriver;
+
+impl Driver for FloppyDriver {
+    fn name(&self) -> &'static str {
+        "floppy"
+    }
+
+    fn match_rules(&self) -> &[MatchRule] {
+        &[MatchRule::Platform("PNP0700")]
+    }
+
+    fn probe(&self, _dev: &DeviceInfo) -> Result<(), ProbeError> {
+        // SAFETY: Reading the drive types from the CMOS has no side effects
+        let types = unsafe { read_cmos(CMOS_FLOPPY_TYPES) };
+        let geometries = [
+            FloppyGeometry::from_cmos_type(types >> 4),
+            FloppyGeometry::from_cmos_type(types & 0xf),
+        ];
+
+        if geometries.iter().all(Option::is_none) {
+            log!(Info, "floppy", "No floppy drives are installed");
+            return Err(ProbeError::Unsupported);
+        }
+
+        let version: Result<u8, FloppyError> = try {
+            // SAFETY: Nothing else uses the floppy controller until the worker thread is started
+            unsafe {
+                send_command(&[CMD_VERSION])?;
+                read_result_byte()?
+            }
+        };
+
+        match version {
+            Ok(VERSION_82077) => {},
+            Ok(version) => {
+                log!(Warning, "floppy", "Unsupported floppy controller version {:02x}", version);
+                return Err(ProbeError::Unsupported);
+            },
+            Err(err) => {
+                log!(Error, "floppy", "Failed to get floppy controller version: {}", err);
+                return Err(ProbeError::Failed);
+            },
+        }
+
+        let dma = isadma::reserve(FDC_DMA_CHANNEL, "floppy").map_err(|err| {
+            log!(Error, "floppy", "Failed to reserve DMA channel {}: {}", FDC_DMA_CHANNEL, err);
+            ProbeError::Failed
+        })?;
+        let max_transfer = geometries.iter().flatten().map(|g| g.sectors_per_cylinder()).max().unwrap() as usize * FLOPPY_SECTOR_SIZE;
+        let dma_buf = dma.alloc_buffer(max_transfer).map_err(|err| {
+            log!(Error, "floppy", "Failed to allocate DMA buffer: {}", err);
+            ProbeError::Failed
+        })?;
+
+        let shared = Arc::new(FdcShared {
+            jobs: UninterruptibleSpinlock::new(VecDeque::new()),
+            wait: ThreadWaitList::new(),
+            irq: UninterruptibleSpinlock::new(None),
+        });
+        let controller = device_root()
+            .dev()
+            .add_device(DeviceNode::new(Box::from("fdc"), FloppyController {}));
+
+        let shared_for_irq = shared.clone();
+
+        // SAFETY: The IRQ handler only completes the future that the worker thread is waiting on
+        unsafe {
+            controller.register_irq(FDC_IRQ as usize, Box::new(move |_| shared_for_irq.handle_irq()));
+            pic::set_irq_masked(FDC_IRQ, false);
+        }
+
+        let drives = [0, 1].map(|drive| {
+            let geometry = geometries[usize::from(drive)]?;
+            let name = format!("fd{}", drive);
+            let queue = RequestQueue::new(&name, FloppyDriveHandler {
+                drive,
+                geometry,
+                shared: shared.clone(),
+            });
+
+            Some(FloppyDriveState {
+                dev: block::register(&name, FloppyDrive { queue }),
+                geometry,
+                cylinder: None,
+                has_media: None,
+            })
+        });
+
+        let worker = FloppyWorker {
+            shared,
+            controller,
+            dma,
+            dma_buf,
+            drives,
+            dor: 0,
+            initialized: false,
+        };
+
+        let thread = Process::kernel()
+            .lock()
+            .create_kernel_thread("floppy", move || worker.run(), FDC_STACK_SIZE);
+
+        thread.lock().wake();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test_case]
+    fn test_floppy_geometry() {
+        let geometry = FloppyGeometry::from_cmos_type(4).unwrap();
+
+        assert_eq!(2880, geometry.num_sectors());
+        assert_eq!((0, 0, 1), geometry.to_chs(0));
+        assert_eq!((0, 1, 1), geometry.to_chs(18));
+        assert_eq!((1, 0, 3), geometry.to_chs(38));
+        assert_eq!((79, 1, 18), geometry.to_chs(2879));
+
+        assert_eq!(720, FloppyGeometry::from_cmos_type(1).unwrap().num_sectors());
+        assert_eq!(None, FloppyGeometry::from_cmos_type(0));
+    }
+
+    #[test_case]
+    fn test_copy_segments() {
+        let mut a = [0u8; 3];
+        let mut b = [0u8; 5];
+        let segments = [Segment(&mut a[..]), Segment(&mut b[..])];
+        let mut buf = [1, 2, 3, 4];
+
+        unsafe {
+            copy_segments(&segments, 2, &mut buf, true);
+        }
+
+        assert_eq!([0, 0, 1], a);
+        assert_eq!([2, 3, 4, 0, 0], b);
+
+        let mut buf = vec![0; 8];
+
+        unsafe {
+            copy_segments(&segments, 0, &mut buf, false);
+        }
+
+        assert_eq!(vec![0, 0, 1, 2, 3, 4, 0, 0], buf);
+    }
+}
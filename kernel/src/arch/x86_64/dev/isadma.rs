@@ -0,0 +1,464 @@
+//! Driver for the pair of 8237 DMA controllers found on PC-compatible machines.
+//!
+//! Legacy ISA devices such as floppy controllers and SoundBlaster-compatible sound cards can't perform DMA themselves, and instead ask one of
+//! the 8 channels of the ISA DMA controllers to transfer data on their behalf. Channels 0-3 belong to the first controller and transfer one
+//! byte at a time, while channels 4-7 belong to the second controller and transfer 16-bit words. Channel 4 connects the two controllers
+//! and can't be used for transfers.
+//!
+//! A driver must first reserve a channel using [`reserve`], which gives it exclusive use of that channel until the returned
+//! [`IsaDmaChannel`] is dropped. The controllers can only address the first 16MiB of physical memory and a single transfer can't cross a
+//! 64KiB boundary (128KiB for 16-bit channels), so transfers must go through a buffer allocated using [`IsaDmaChannel::alloc_buffer`],
+//! which satisfies these requirements. Data is copied between that buffer and wherever it is actually needed.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+
+use dyn_dyn::dyn_dyn_impl;
+use x86_64::instructions::port::Port;
+
+use crate::arch::PhysAddr;
+use crate::io::dev::driver::{DeviceInfo, Driver, MatchRule, ProbeError};
+use crate::io::dev::{device_root, Device, DeviceNode, DeviceRef};
+use crate::log;
+use crate::mem::dma::{DmaAllocError, DmaBuffer, DmaCacheMode};
+use crate::mem::frame::FrameZone;
+use crate::sync::UninterruptibleSpinlock;
+use crate::util::OneShotManualInit;
+
+/// The number of DMA channels provided by the two controllers.
+pub const NUM_CHANNELS: usize = 8;
+
+/// The channel used to connect the second controller to the first, which can't be used for transfers.
+const CASCADE_CHANNEL: u8 = 4;
+
+/// The highest physical address that the controllers can access, plus one.
+const ISA_DMA_LIMIT: u64 = 16 << 20;
+
+/// The I/O ports of the page registers holding bits 16-23 of the address for each channel.
+const PAGE_PORTS: [u16; NUM_CHANNELS] = [0x87, 0x83, 0x81, 0x82, 0x8f, 0x8b, 0x89, 0x8a];
+
+const MODE_TRANSFER_WRITE: u8 = 0x04;
+const MODE_TRANSFER_READ: u8 = 0x08;
+const MODE_AUTO_INIT: u8 = 0x10;
+const MODE_DEMAND: u8 = 0x00;
+const MODE_SINGLE: u8 = 0x40;
+const MODE_BLOCK: u8 = 0x80;
+
+const SINGLE_MASK_SET: u8 = 0x04;
+
+/// An error that can occur when using an ISA DMA channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsaDmaError {
+    /// No ISA DMA controller was found.
+    NoController,
+    /// The requested channel does not exist or can't be used for transfers.
+    InvalidChannel,
+    /// The requested channel is already reserved by the driver with the provided name.
+    ChannelInUse(&'static str),
+    /// The transfer is empty, too long for the channel, or not a whole number of words on a 16-bit channel.
+    InvalidLength,
+    /// The buffer is not entirely below 16MiB or crosses a boundary that the channel can't transfer across.
+    BufferNotAddressable,
+    /// A buffer for the channel could not be allocated.
+    Alloc(DmaAllocError),
+}
+
+impl fmt::Display for IsaDmaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IsaDmaError::NoController => write!(f, "no ISA DMA controller"),
+            IsaDmaError::InvalidChannel => write!(f, "invalid channel"),
+            IsaDmaError::ChannelInUse(owner) => write!(f, "channel in use by {}", owner),
+            IsaDmaError::InvalidLength => write!(f, "invalid transfer length"),
+            IsaDmaError::BufferNotAddressable => write!(f, "buffer not addressable by the channel"),
+            IsaDmaError::Alloc(err) => write!(f, "failed to allocate buffer: {}", err),
+        }
+    }
+}
+
+/// The direction in which data is transferred by an ISA DMA channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsaDmaDirection {
+    /// Data is read from the device and written to memory.
+    DeviceToMemory,
+    /// Data is read from memory and written to the device.
+    MemoryToDevice,
+}
+
+/// How an ISA DMA channel paces a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsaDmaMode {
+    /// A single byte or word is transferred each time the device requests it.
+    Single,
+    /// The whole transfer is performed at once as soon as the device requests it.
+    Block,
+    /// Data is transferred for as long as the device keeps requesting it.
+    Demand,
+}
+
+fn is_16bit(channel: u8) -> bool {
+    channel >= 4
+}
+
+/// Gets the size of the blocks of memory that a transfer on the provided channel can't cross, which is also the longest possible transfer.
+fn max_transfer_len(channel: u8) -> usize {
+    if is_16bit(channel) {
+        128 << 10
+    } else {
+        64 << 10
+    }
+}
+
+/// Checks whether the provided channel can transfer `len` bytes starting at the provided physical address.
+fn check_transfer(channel: u8, phys: u64, len: usize) -> Result<(), IsaDmaError> {
+    let max_len = max_transfer_len(channel) as u64;
+    let len = len as u64;
+
+    if len == 0 || len > max_len || (is_16bit(channel) && len % 2 != 0) {
+        Err(IsaDmaError::InvalidLength)
+    } else if phys + len > ISA_DMA_LIMIT || phys / max_len != (phys + len - 1) / max_len || (is_16bit(channel) && phys % 2 != 0) {
+        Err(IsaDmaError::BufferNotAddressable)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct IsaDmaControllerInternals {
+    owners: [Option<&'static str>; NUM_CHANNELS],
+}
+
+impl IsaDmaControllerInternals {
+    fn status(&self) -> Option<String> {
+        let mut status = None;
+
+        for (channel, owner) in self.owners.iter().enumerate() {
+            if let Some(owner) = owner {
+                let status = status.get_or_insert_with(|| String::from("reserved"));
+
+                status.push_str(&format!(" {}:{}", channel, owner));
+            }
+        }
+
+        status
+    }
+
+    /// Writes to the single mask register of the controller that the provided channel belongs to, masking or unmasking its DMA requests.
+    unsafe fn set_masked(&mut self, channel: u8, masked: bool) {
+        let port = if is_16bit(channel) { 0xd4 } else { 0x0a };
+
+        Port::new(port).write((channel & 3) | if masked { SINGLE_MASK_SET } else { 0 });
+    }
+
+    /// Resets the flip-flop that selects whether the low or high byte of a 16-bit register of the controller is accessed next.
+    unsafe fn reset_flip_flop(&mut self, channel: u8) {
+        let port = if is_16bit(channel) { 0xd8 } else { 0x0c };
+
+        Port::new(port).write(0xff_u8);
+    }
+
+    fn address_port(channel: u8) -> u16 {
+        if is_16bit(channel) {
+            0xc0 + 4 * u16::from(channel & 3)
+        } else {
+            2 * u16::from(channel)
+        }
+    }
+
+    fn count_port(channel: u8) -> u16 {
+        IsaDmaControllerInternals::address_port(channel) + if is_16bit(channel) { 2 } else { 1 }
+    }
+
+    unsafe fn program(&mut self, channel: u8, phys: u64, len: usize, dir: IsaDmaDirection, mode: IsaDmaMode, auto_init: bool) {
+        // 16-bit channels count in words and are programmed with the address shifted right by one, except for the page register
+        let (address, count) = if is_16bit(channel) {
+            (phys >> 1, len / 2 - 1)
+        } else {
+            (phys, len - 1)
+        };
+        let mode = (channel & 3)
+            | match dir {
+                IsaDmaDirection::DeviceToMemory => MODE_TRANSFER_WRITE,
+                IsaDmaDirection::MemoryToDevice => MODE_TRANSFER_READ,
+            }
+            | match mode {
+                IsaDmaMode::Single => MODE_SINGLE,
+                IsaDmaMode::Block => MODE_BLOCK,
+                IsaDmaMode::Demand => MODE_DEMAND,
+            }
+            | if auto_init { MODE_AUTO_INIT } else { 0 };
+
+        self.set_masked(channel, true);
+
+        Port::new(if is_16bit(channel) { 0xd6 } else { 0x0b }).write(mode);
+
+        let mut address_port: Port<u8> = Port::new(IsaDmaControllerInternals::address_port(channel));
+        let mut count_port: Port<u8> = Port::new(IsaDmaControllerInternals::count_port(channel));
+
+        self.reset_flip_flop(channel);
+        address_port.write(address as u8);
+        address_port.write((address >> 8) as u8);
+        Port::new(PAGE_PORTS[usize::from(channel)]).write((phys >> 16) as u8);
+
+        self.reset_flip_flop(channel);
+        count_port.write(count as u8);
+        count_port.write((count >> 8) as u8);
+
+        self.set_masked(channel, false);
+    }
+
+    unsafe fn read_count(&mut self, channel: u8) -> u16 {
+        let mut count_port: Port<u8> = Port::new(IsaDmaControllerInternals::count_port(channel));
+
+        self.reset_flip_flop(channel);
+
+        let low = count_port.read();
+        let high = count_port.read();
+
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/// The pair of 8237 DMA controllers found on PC-compatible machines.
+#[derive(Debug)]
+pub struct IsaDmaController {
+    internal: UninterruptibleSpinlock<IsaDmaControllerInternals>,
+}
+
+impl IsaDmaController {
+    /// Reserves the provided channel for the driver with the provided name, which is shown in the status of the controller.
+    pub fn reserve(this: &DeviceRef<IsaDmaController>, channel: u8, owner: &'static str) -> Result<IsaDmaChannel, IsaDmaError> {
+        if usize::from(channel) >= NUM_CHANNELS || channel == CASCADE_CHANNEL {
+            return Err(IsaDmaError::InvalidChannel);
+        }
+
+        let mut internal = this.dev().internal.lock();
+
+        if let Some(owner) = internal.owners[usize::from(channel)] {
+            return Err(IsaDmaError::ChannelInUse(owner));
+        }
+
+        internal.owners[usize::from(channel)] = Some(owner);
+        this.set_status(internal.status().as_deref());
+
+        Ok(IsaDmaChannel {
+            controller: this.clone(),
+            channel,
+        })
+    }
+}
+
+#[dyn_dyn_impl]
+impl Device for IsaDmaController {}
+
+/// An ISA DMA channel that has been reserved for use by a driver. The channel is masked and released when this is dropped.
+#[derive(Debug)]
+pub struct IsaDmaChannel {
+    controller: DeviceRef<IsaDmaController>,
+    channel: u8,
+}
+
+impl IsaDmaChannel {
+    /// Gets the number of this channel.
+    pub fn number(&self) -> u8 {
+        self.channel
+    }
+
+    /// Checks whether this channel transfers 16-bit words rather than single bytes.
+    pub fn is_16bit(&self) -> bool {
+        is_16bit(self.channel)
+    }
+
+    /// Gets the longest transfer in bytes that this channel can perform.
+    pub fn max_transfer_len(&self) -> usize {
+        max_transfer_len(self.channel)
+    }
+
+    /// Allocates a zero-filled buffer of at least `len` bytes that this channel can transfer to or from in a single transfer.
+    pub fn alloc_buffer(&self, len: usize) -> Result<DmaBuffer, IsaDmaError> {
+        if len > self.max_transfer_len() {
+            return Err(IsaDmaError::InvalidLength);
+        }
+
+        // DMA buffers are aligned to their size, so a buffer no larger than the longest transfer never crosses a boundary. ISA DMA is
+        // coherent with the CPU caches, so the buffer doesn't need a special caching mode.
+        DmaBuffer::alloc_in(len, FrameZone::Dma, DmaCacheMode::WriteBack).map_err(IsaDmaError::Alloc)
+    }
+
+    /// Programs this channel to transfer the first `len` bytes of the provided buffer and then unmasks it, so that the transfer starts once
+    /// the device requests it. If `auto_init` is set, the channel starts the same transfer over once it completes, e.g. for the ring buffers
+    /// of sound cards.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be freed until the transfer has completed or [`IsaDmaChannel::stop`] is called, and the device on the other end
+    /// of this channel must be ready to transfer data in the provided direction.
+    pub unsafe fn start(
+        &self,
+        buf: &DmaBuffer,
+        len: usize,
+        dir: IsaDmaDirection,
+        mode: IsaDmaMode,
+        auto_init: bool,
+    ) -> Result<(), IsaDmaError> {
+        if len > buf.size() {
+            return Err(IsaDmaError::InvalidLength);
+        }
+
+        check_transfer(self.channel, buf.phys_addr().as_u64(), len)?;
+        self.controller
+            .dev()
+            .internal
+            .lock()
+            .program(self.channel, buf.phys_addr().as_u64(), len, dir, mode, auto_init);
+
+        Ok(())
+    }
+
+    /// Starts a transfer to or from an arbitrary physical address without checking that it is in a buffer owned by the caller.
+    ///
+    /// # Safety
+    ///
+    /// The memory being transferred must stay valid until the transfer has completed or [`IsaDmaChannel::stop`] is called.
+    pub unsafe fn start_phys(
+        &self,
+        phys: PhysAddr,
+        len: usize,
+        dir: IsaDmaDirection,
+        mode: IsaDmaMode,
+        auto_init: bool,
+    ) -> Result<(), IsaDmaError> {
+        check_transfer(self.channel, phys.as_u64(), len)?;
+        self.controller
+            .dev()
+            .internal
+            .lock()
+            .program(self.channel, phys.as_u64(), len, dir, mode, auto_init);
+
+        Ok(())
+    }
+
+    /// Masks this channel, stopping any transfer in progress.
+    pub fn stop(&self) {
+        // SAFETY: Masking a channel reserved by this driver only stops its own transfers
+        unsafe {
+            self.controller.dev().internal.lock().set_masked(self.channel, true);
+        }
+    }
+
+    /// Gets the number of bytes that remain to be transferred by the current transfer. This is 0 once a transfer that isn't using
+    /// auto-initialization completes.
+    pub fn remaining(&self) -> usize {
+        // SAFETY: Reading the count register of a channel reserved by this driver doesn't affect other channels
+        let count = unsafe { self.controller.dev().internal.lock().read_count(self.channel) };
+
+        // The count register holds one less than the number of bytes or words left and wraps around to 0xffff once the transfer is done
+        let remaining = (usize::from(count) + 1) & 0xffff;
+
+        if self.is_16bit() {
+            remaining * 2
+        } else {
+            remaining
+        }
+    }
+}
+
+impl Drop for IsaDmaChannel {
+    fn drop(&mut self) {
+        let mut internal = self.controller.dev().internal.lock();
+
+        // SAFETY: This channel was reserved by the driver dropping it, so nothing else can be relying on it
+        unsafe {
+            internal.set_masked(self.channel, true);
+        }
+
+        internal.owners[usize::from(self.channel)] = None;
+        self.controller.set_status(internal.status().as_deref());
+    }
+}
+
+static CONTROLLER: OneShotManualInit<DeviceRef<IsaDmaController>> = OneShotManualInit::uninit();
+
+/// Gets the ISA DMA controller, if one was found.
+pub fn controller() -> Option<&'static DeviceRef<IsaDmaController>> {
+    CONTROLLER.try_get()
+}
+
+/// Reserves the provided channel of the ISA DMA controller for the driver with the provided name.
+pub fn reserve(channel: u8, owner: &'static str) -> Result<IsaDmaChannel, IsaDmaError> {
+    IsaDmaController::reserve(controller().ok_or(IsaDmaError::NoController)?, channel, owner)
+}
+
+/// The driver for the pair of 8237 DMA controllers found on PC-compatible machines.
+pub struct IsaDmaDriver;
+
+pub static DRIVER: IsaDmaDriver = IsaDmaDriver;
+
+impl Driver for IsaDmaDriver {
+    fn name(&self) -> &'static str {
+        "isadma"
+    }
+
+    fn match_rules(&self) -> &[MatchRule] {
+        &[MatchRule::Platform("PNP0200")]
+    }
+
+    fn probe(&self, _dev: &DeviceInfo) -> Result<(), ProbeError> {
+        if CONTROLLER.is_init() {
+            return Err(ProbeError::Unsupported);
+        }
+
+        let controller = device_root()
+            .dev()
+            .add_device(DeviceNode::new(Box::from("isadma"), IsaDmaController {
+                internal: UninterruptibleSpinlock::new(IsaDmaControllerInternals {
+                    owners: [None; NUM_CHANNELS],
+                }),
+            }));
+
+        // Nothing can have reserved a channel yet, so mask all of them in case the firmware left any transfers running
+        {
+            let mut internal = controller.dev().internal.lock();
+
+            for channel in (0..NUM_CHANNELS as u8).filter(|&c| c != CASCADE_CHANNEL) {
+                // SAFETY: No driver has reserved any channels yet
+                unsafe {
+                    internal.set_masked(channel, true);
+                }
+            }
+        }
+
+        CONTROLLER.set(controller);
+        log!(Info, "isadma", "Initialized ISA DMA controller");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_check_transfer() {
+        assert_eq!(Ok(()), check_transfer(2, 0x10000, 0x10000));
+        assert_eq!(Ok(()), check_transfer(5, 0x20000, 0x20000));
+
+        assert_eq!(Err(IsaDmaError::InvalidLength), check_transfer(2, 0x10000, 0));
+        assert_eq!(Err(IsaDmaError::InvalidLength), check_transfer(2, 0x10000, 0x10001));
+        assert_eq!(Err(IsaDmaError::InvalidLength), check_transfer(5, 0x20000, 3));
+
+        // 8-bit channels can't cross a 64KiB boundary, but 16-bit channels only can't cross a 128KiB boundary
+        assert_eq!(Err(IsaDmaError::BufferNotAddressable), check_transfer(2, 0xff00, 0x200));
+        assert_eq!(Ok(()), check_transfer(5, 0x1ff00, 0x200));
+        assert_eq!(Err(IsaDmaError::BufferNotAddressable), check_transfer(5, 0x3ff00, 0x200));
+        assert_eq!(Err(IsaDmaError::BufferNotAddressable), check_transfer(5, 0x1001, 0x200));
+
+        assert_eq!(
+            Err(IsaDmaError::BufferNotAddressable),
+            check_transfer(2, ISA_DMA_LIMIT - 0x100, 0x200)
+        );
+    }
+}
@@ -1,3 +1,5 @@
+pub mod floppy;
+pub mod isadma;
 pub mod ps2;
 pub mod qemu_dbg_exit;
 pub mod serial;
@@ -1,119 +1,33 @@
 use alloc::boxed::Box;
+use alloc::format;
 use alloc::string::String;
 use core::cell::SyncUnsafeCell;
+use core::ptr;
+use core::time::Duration;
 
 use dyn_dyn::dyn_dyn_impl;
 
-use crate::arch::{interrupt, pic};
-use crate::io::dev::hub::DeviceHub;
-use crate::io::dev::kbd::{KeyPress, Keyboard, KeyboardError, KeyboardHeldKeys, KeyboardLockState, ModifierState};
-use crate::io::dev::{device_root, Device, DeviceNode, DeviceRef};
-use crate::io::keymap::{CommonKeycode, KeyAction, Keycode, KeycodeMap};
-use crate::io::vt;
+use self::scancode::{Scancode, ScancodeMap};
+use crate::arch::pic;
+use crate::io::dev::driver::{DeviceInfo, Driver, MatchRule, ProbeError};
+use crate::io::dev::hub::{DeviceHub, DeviceRemoveError};
+use crate::io::dev::kbd::{
+    AccessibilityConfig, KeyFilter, KeyPress, KeyRepeater, Keyboard, KeyboardError, KeyboardHeldKeys, KeyboardLockState, ModifierState,
+    TypematicConfig,
+};
+use crate::io::dev::mouse::{Mouse, MouseButtons, MouseError, MouseEvent};
+use crate::io::dev::recovery::{self, RecoveryDomain};
+use crate::io::dev::{device_root, Device, DeviceNode, DeviceRef, DeviceWeak};
+use crate::io::keymap::{self, CommonKeycode, KeyAction, Keycode, KeycodeMap};
 use crate::sync::future::FutureWriter;
 use crate::sync::uninterruptible::{UninterruptibleSpinlockGuard, UninterruptibleSpinlockReadGuard};
 use crate::sync::{Future, UninterruptibleSpinlock};
 use crate::util::ArrayDeque;
 use crate::{log, sched};
 
+mod scancode;
 mod scancode_2_map;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ScancodeKey {
-    Basic(u8),
-    Extended(u8),
-    DualExtended(u8, u8),
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct Scancode {
-    key: ScancodeKey,
-    released: bool,
-}
-
-impl Scancode {
-    pub fn try_parse(buf: &[u8]) -> Option<(Scancode, usize)> {
-        match buf.first().copied() {
-            Some(0xE0) => match buf.get(1).copied() {
-                Some(0xF0) if buf.len() >= 3 => Some((
-                    Scancode {
-                        key: ScancodeKey::Extended(buf[2]),
-                        released: true,
-                    },
-                    3,
-                )),
-                Some(0xF0) => None,
-                Some(key) => Some((
-                    Scancode {
-                        key: ScancodeKey::Extended(key),
-                        released: false,
-                    },
-                    2,
-                )),
-                None => None,
-            },
-            Some(0xE1) => match buf.get(1).copied() {
-                Some(0xF0) if buf.len() >= 5 => Some((
-                    Scancode {
-                        key: ScancodeKey::DualExtended(buf[2], buf[4]),
-                        released: true,
-                    },
-                    5,
-                )),
-                Some(0xF0) => None,
-                _ if buf.len() >= 3 => Some((
-                    Scancode {
-                        key: ScancodeKey::DualExtended(buf[1], buf[2]),
-                        released: false,
-                    },
-                    3,
-                )),
-                _ => None,
-            },
-            Some(0xF0) if buf.len() >= 2 => Some((
-                Scancode {
-                    key: ScancodeKey::Basic(buf[1]),
-                    released: true,
-                },
-                2,
-            )),
-            Some(0xF0) => None,
-            Some(key) => Some((
-                Scancode {
-                    key: ScancodeKey::Basic(key),
-                    released: false,
-                },
-                1,
-            )),
-            None => None,
-        }
-    }
-}
-
-#[derive(Debug)]
-enum ScancodeMapDualList {
-    Static(&'static [(u8, u8, Keycode)]),
-}
-
-#[derive(Debug)]
-struct ScancodeMap {
-    basic: [Option<Keycode>; 256],
-    extended: [Option<Keycode>; 256],
-    dual_extended: ScancodeMapDualList,
-}
-
-impl ScancodeMap {
-    pub fn get(&self, key: ScancodeKey) -> Option<Keycode> {
-        match key {
-            ScancodeKey::Basic(b) => self.basic[b as usize],
-            ScancodeKey::Extended(b) => self.extended[b as usize],
-            ScancodeKey::DualExtended(b0, b1) => match self.dual_extended {
-                ScancodeMapDualList::Static(list) => list.iter().find(|&&(m0, m1, _)| m0 == b0 && m1 == b1).map(|&(_, _, k)| k),
-            },
-        }
-    }
-}
-
 #[derive(Debug)]
 pub enum Ps2Error {
     ControllerError(ps2::error::ControllerError),
@@ -207,6 +121,18 @@ impl KeyboardHeldKeys for Ps2KeyboardHeldKeys {
     }
 }
 
+/// The progress of an update of the keyboard's LEDs started from the interrupt handler. The keyboard acknowledges each byte of the command
+/// separately, and since acknowledgements arrive as interrupts just like scancodes do, each byte is only sent once the previous one has
+/// been acknowledged rather than waiting for the acknowledgement, which would discard any scancodes arriving in the meantime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ps2LedUpdate {
+    Idle,
+    /// Waiting for the keyboard to acknowledge [`KEYBOARD_CMD_SET_LEDS`].
+    SentCommand,
+    /// Waiting for the keyboard to acknowledge the provided LED state.
+    SentState(u8),
+}
+
 #[derive(Debug)]
 struct Ps2KeyboardInternals {
     lock_state: KeyboardLockState,
@@ -218,6 +144,128 @@ struct Ps2KeyboardInternals {
     scancode_buf_pos: usize,
     scancode_map: &'static ScancodeMap,
     keycode_map: &'static KeycodeMap,
+    typematic: u8,
+    key_filter: KeyFilter,
+    key_repeater: KeyRepeater,
+    /// The LED state most recently acknowledged by the keyboard.
+    leds: u8,
+    led_update: Ps2LedUpdate,
+    led_retries: u8,
+}
+
+const KEYBOARD_CMD_SET_LEDS: u8 = 0xed;
+const KEYBOARD_CMD_ECHO: u8 = 0xee;
+const KEYBOARD_CMD_SET_TYPEMATIC: u8 = 0xf3;
+
+const KEYBOARD_RESPONSE_ACK: u8 = 0xfa;
+const KEYBOARD_RESPONSE_RESEND: u8 = 0xfe;
+const KEYBOARD_RESPONSE_ECHO: u8 = 0xee;
+
+/// The number of times a byte of an LED update is resent at the keyboard's request before giving up.
+const KEYBOARD_MAX_LED_RETRIES: u8 = 3;
+
+const KEYBOARD_LED_SCROLL_LOCK: u8 = 0x01;
+const KEYBOARD_LED_NUM_LOCK: u8 = 0x02;
+const KEYBOARD_LED_CAPS_LOCK: u8 = 0x04;
+
+/// Sent by a PS/2 device once it has finished its power-on self-test, including when it is plugged in while the system is running.
+const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+
+/// Sent by a PS/2 device if its power-on self-test failed.
+const DEVICE_SELF_TEST_FAILED: u8 = 0xfc;
+
+/// Sent by a mouse immediately after [`DEVICE_SELF_TEST_PASSED`] to identify itself as a standard PS/2 mouse.
+const MOUSE_ID_STANDARD: u8 = 0x00;
+
+/// The ID reported by an IntelliMouse-compatible mouse once its scroll wheel has been enabled.
+const MOUSE_ID_WHEEL: u8 = 0x03;
+
+/// The ID reported by an IntelliMouse Explorer-compatible mouse once its side buttons have been enabled.
+const MOUSE_ID_FIVE_BUTTONS: u8 = 0x04;
+
+/// The sample rate that mice use after being reset, in reports per second.
+const MOUSE_DEFAULT_SAMPLE_RATE: u8 = 100;
+
+/// Always set in the first byte of a movement packet, which makes it possible to get back in sync after a byte has been lost.
+const MOUSE_PACKET_SYNC: u8 = 0x08;
+const MOUSE_PACKET_X_SIGN: u8 = 0x10;
+const MOUSE_PACKET_Y_SIGN: u8 = 0x20;
+const MOUSE_PACKET_X_OVERFLOW: u8 = 0x40;
+const MOUSE_PACKET_Y_OVERFLOW: u8 = 0x80;
+
+/// The typematic configuration that keyboards use after being reset: 10.9 Hz repeat rate with a 500ms delay.
+const DEFAULT_TYPEMATIC: u8 = 0x2b;
+
+const TYPEMATIC_DELAYS_MS: [u32; 4] = [250, 500, 750, 1000];
+
+fn decode_typematic(typematic: u8) -> TypematicConfig {
+    // The repeat period is (8 + A) * 2^B * 4.17ms, where A is given by bits 0-2 and B is given by bits 3-4
+    let a = u32::from(typematic & 0x7);
+    let b = u32::from((typematic >> 3) & 0x3);
+    let period_10us = (8 + a) * (1 << b) * 417;
+
+    TypematicConfig {
+        delay_ms: TYPEMATIC_DELAYS_MS[usize::from((typematic >> 5) & 0x3)],
+        rate_decihz: (2_000_000 + period_10us) / (2 * period_10us),
+    }
+}
+
+fn encode_typematic(config: TypematicConfig) -> u8 {
+    let delay = (0..4_u8)
+        .min_by_key(|&d| TYPEMATIC_DELAYS_MS[usize::from(d)].abs_diff(config.delay_ms))
+        .unwrap();
+    let rate = (0..32_u8)
+        .min_by_key(|&r| decode_typematic(r).rate_decihz.abs_diff(config.rate_decihz))
+        .unwrap();
+
+    (delay << 5) | rate
+}
+
+fn encode_leds(lock_state: KeyboardLockState) -> u8 {
+    let mut leds = 0;
+
+    if lock_state.scroll_lock {
+        leds |= KEYBOARD_LED_SCROLL_LOCK;
+    }
+
+    if lock_state.num_lock {
+        leds |= KEYBOARD_LED_NUM_LOCK;
+    }
+
+    if lock_state.caps_lock {
+        leds |= KEYBOARD_LED_CAPS_LOCK;
+    }
+
+    leds
+}
+
+/// Sends a single-byte command to the device on the first PS/2 port and returns its response, resending the command if requested to.
+/// Any scancodes that arrive while waiting for the response are discarded.
+fn send_keyboard_command(controller: &mut ps2::Controller, cmd: u8) -> Result<u8, ps2::error::ControllerError> {
+    for _ in 0..3 {
+        controller.write_data(cmd)?;
+
+        for _ in 0..8 {
+            match controller.read_data()? {
+                KEYBOARD_RESPONSE_RESEND => break,
+                resp @ (KEYBOARD_RESPONSE_ACK | KEYBOARD_RESPONSE_ECHO) => return Ok(resp),
+                _ => {},
+            }
+        }
+    }
+
+    Err(ps2::error::ControllerError::Timeout)
+}
+
+/// Sets the LEDs of the keyboard on the first PS/2 port, waiting for the keyboard to acknowledge each byte of the command.
+fn send_keyboard_leds(controller: &mut ps2::Controller, leds: u8) -> Result<(), ps2::error::ControllerError> {
+    if send_keyboard_command(controller, KEYBOARD_CMD_SET_LEDS)? != KEYBOARD_RESPONSE_ACK
+        || send_keyboard_command(controller, leds)? != KEYBOARD_RESPONSE_ACK
+    {
+        Err(ps2::error::ControllerError::Timeout)?;
+    }
+
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -227,6 +275,40 @@ pub struct Ps2Keyboard {
 }
 
 impl Ps2Keyboard {
+    fn create(controller: &DeviceRef<Ps2Controller>) -> DeviceRef<Ps2Keyboard> {
+        DeviceNode::new(Box::from("keyboard"), Ps2Keyboard {
+            controller: controller.clone(),
+            internal: SyncUnsafeCell::new(Ps2KeyboardInternals {
+                lock_state: KeyboardLockState::none(),
+                mod_state: ModifierState::none(),
+                held_keys: Ps2KeyboardHeldKeys::new(),
+                input_buf: ArrayDeque::new(),
+                input_future: None,
+                scancode_buf: [0; 5],
+                scancode_buf_pos: 0,
+                scancode_map: &scancode_2_map::MAP,
+                keycode_map: keymap::default_keymap(),
+                typematic: DEFAULT_TYPEMATIC,
+                key_filter: KeyFilter::new(),
+                key_repeater: Ps2Keyboard::default_key_repeater(),
+                leds: 0,
+                led_update: Ps2LedUpdate::Idle,
+                led_retries: 0,
+            }),
+        })
+        .connect(DeviceRef::<Ps2Controller>::downgrade(controller))
+    }
+
+    fn default_key_repeater() -> KeyRepeater {
+        let mut repeater = KeyRepeater::new();
+
+        if crate::options::get().get_flag("kbd_soft_repeat").unwrap_or(true) {
+            repeater.set_config(Some(decode_typematic(DEFAULT_TYPEMATIC)));
+        }
+
+        repeater
+    }
+
     fn lock(&self) -> Ps2KeyboardGuard {
         self.lock_from_controller(self.controller.dev().internal.lock())
     }
@@ -245,19 +327,15 @@ impl Ps2Keyboard {
         }
 
         if pressed {
+            let mods = guard.keyboard.key_filter.apply_latched(key, guard.keyboard.mod_state);
             let keypress = KeyPress {
                 code: key,
                 lock_state: guard.keyboard.lock_state,
-                mods: guard.keyboard.mod_state,
-                str: match guard
-                    .keyboard
-                    .keycode_map
-                    .get(key, guard.keyboard.lock_state, guard.keyboard.mod_state)
-                {
+                mods,
+                str: match guard.keyboard.keycode_map.get(key, guard.keyboard.lock_state, mods) {
                     None | Some(&KeyAction::None) => String::new(),
                     Some(&KeyAction::Char(ch)) => String::from(ch),
                     Some(&KeyAction::Str(s)) => String::from(s),
-                    Some(&KeyAction::String(ref s)) => s.clone(),
                 },
             };
 
@@ -267,14 +345,132 @@ impl Ps2Keyboard {
                 let _ = guard.keyboard.input_buf.push_back(keypress);
             }
 
-            guard.keyboard.lock_state.handle_key_pressed(key);
+            if guard.keyboard.lock_state.handle_key_pressed(key) {
+                Self::start_led_update(guard);
+            }
         }
 
         guard.keyboard.mod_state.handle_key_state_changed(key, pressed);
     }
 
-    fn handle_interrupt(guard: &mut Ps2KeyboardGuard) {
+    fn send_led_byte(guard: &mut Ps2KeyboardGuard, b: u8, next: Ps2LedUpdate) {
+        match guard.controller().controller.write_data(b) {
+            Ok(()) => {
+                guard.keyboard.led_update = next;
+            },
+            Err(err) => {
+                log!(Warning, "ps2", "Failed to send LED update to keyboard: {:?}", err);
+                guard.keyboard.led_update = Ps2LedUpdate::Idle;
+            },
+        }
+    }
+
+    /// Starts updating the keyboard's LEDs to match its lock state from the interrupt handler, unless they already match. If an update is
+    /// already in progress, the LEDs are checked again once it finishes.
+    fn start_led_update(guard: &mut Ps2KeyboardGuard) {
+        if guard.keyboard.led_update == Ps2LedUpdate::Idle && encode_leds(guard.keyboard.lock_state) != guard.keyboard.leds {
+            guard.keyboard.led_retries = 0;
+            Self::send_led_byte(guard, KEYBOARD_CMD_SET_LEDS, Ps2LedUpdate::SentCommand);
+        }
+    }
+
+    fn handle_led_response(guard: &mut Ps2KeyboardGuard, acked: bool) {
+        let update = guard.keyboard.led_update;
+        let (b, next) = match (update, acked) {
+            (Ps2LedUpdate::SentCommand, true) => {
+                let leds = encode_leds(guard.keyboard.lock_state);
+                (leds, Ps2LedUpdate::SentState(leds))
+            },
+            (Ps2LedUpdate::SentState(leds), true) => {
+                guard.keyboard.leds = leds;
+                guard.keyboard.led_update = Ps2LedUpdate::Idle;
+
+                // The lock state may have changed again while the update was in progress
+                Self::start_led_update(guard);
+                return;
+            },
+            (_, false) if guard.keyboard.led_retries == KEYBOARD_MAX_LED_RETRIES => {
+                log!(Warning, "ps2", "Keyboard did not accept LED update");
+                guard.keyboard.led_update = Ps2LedUpdate::Idle;
+                return;
+            },
+            (Ps2LedUpdate::SentCommand, false) => {
+                guard.keyboard.led_retries += 1;
+                (KEYBOARD_CMD_SET_LEDS, update)
+            },
+            (Ps2LedUpdate::SentState(leds), false) => {
+                guard.keyboard.led_retries += 1;
+                (leds, update)
+            },
+            (Ps2LedUpdate::Idle, _) => unreachable!(),
+        };
+
+        Self::send_led_byte(guard, b, next);
+    }
+
+    /// Sets the keyboard's LEDs without going through the interrupt handler, cancelling any LED update that it had in progress.
+    fn set_leds_blocking(guard: &mut Ps2KeyboardGuard, leds: u8) -> Result<(), KeyboardError> {
+        let result = send_keyboard_leds(&mut guard.controller().controller, leds);
+        let keyboard = guard.keyboard();
+
+        keyboard.led_update = Ps2LedUpdate::Idle;
+
+        match result {
+            Ok(()) => {
+                keyboard.leds = leds;
+                Ok(())
+            },
+            Err(err) => {
+                log!(Warning, "ps2", "Failed to set keyboard LEDs: {:?}", err);
+                Err(KeyboardError)
+            },
+        }
+    }
+
+    /// Redoes an LED update that the interrupt handler had in progress after sending a command to the keyboard directly, since waiting for
+    /// the response to that command discards the acknowledgements that the interrupt handler was waiting for.
+    fn resume_led_update(guard: &mut Ps2KeyboardGuard) {
+        if guard.keyboard.led_update != Ps2LedUpdate::Idle {
+            let leds = encode_leds(guard.keyboard.lock_state);
+            let _ = Self::set_leds_blocking(guard, leds);
+        }
+    }
+
+    /// Handles a byte received from the keyboard. Returns `true` if the byte indicates that a keyboard has just been plugged in and needs
+    /// to be reinitialized.
+    fn start_repeat_timer(node: &DeviceRef<Ps2Keyboard>, deadline: Duration, generation: u64) {
+        let node = node.clone();
+
+        sched::timer::at(deadline).when_resolved_soft(move |()| {
+            Ps2Keyboard::handle_repeat_timer(&node, generation);
+        });
+    }
+
+    fn handle_repeat_timer(node: &DeviceRef<Ps2Keyboard>, generation: u64) {
+        if !node.is_connected() {
+            return;
+        }
+
+        let mut guard = node.dev().lock();
+
+        if let Some((key, next_repeat)) = guard.keyboard.key_repeater.handle_timer(generation, sched::timer::now()) {
+            Self::handle_key_state_changed(&mut guard, key, true);
+            drop(guard);
+
+            Self::start_repeat_timer(node, next_repeat, generation);
+        }
+    }
+
+    fn handle_interrupt(node: &DeviceRef<Ps2Keyboard>, guard: &mut Ps2KeyboardGuard) -> bool {
         match guard.controller().controller.read_data() {
+            // Scancode set 2 never uses these bytes, so receiving one between scancodes means that a keyboard was just plugged in
+            Ok(DEVICE_SELF_TEST_PASSED | DEVICE_SELF_TEST_FAILED) if guard.keyboard.scancode_buf_pos == 0 => {
+                return true;
+            },
+            // Scancode set 2 never uses these bytes either, so while an LED update is in progress they are responses to it
+            Ok(b @ (KEYBOARD_RESPONSE_ACK | KEYBOARD_RESPONSE_RESEND)) if guard.keyboard.led_update != Ps2LedUpdate::Idle => {
+                Self::handle_led_response(guard, b == KEYBOARD_RESPONSE_ACK);
+            },
             Ok(b) => {
                 guard.keyboard.scancode_buf[guard.keyboard.scancode_buf_pos] = b;
                 guard.keyboard.scancode_buf_pos += 1;
@@ -285,7 +481,20 @@ impl Ps2Keyboard {
                         guard.keyboard.scancode_buf_pos = 0;
 
                         if let Some(key) = guard.keyboard.scancode_map.get(scancode.key) {
-                            Self::handle_key_state_changed(guard, key, !scancode.released)
+                            let now = sched::timer::now();
+                            let events = guard.keyboard.key_filter.process(key, !scancode.released, now);
+
+                            for &(key, pressed) in events.iter() {
+                                let (deliver, timer) = guard.keyboard.key_repeater.process(key, pressed, now);
+
+                                if deliver {
+                                    Self::handle_key_state_changed(guard, key, pressed);
+                                }
+
+                                if let Some((deadline, generation)) = timer {
+                                    Self::start_repeat_timer(node, deadline, generation);
+                                }
+                            }
                         }
                     },
                     None => {
@@ -296,13 +505,26 @@ impl Ps2Keyboard {
             Err(ps2::error::ControllerError::Timeout) => {},
             Err(err) => {
                 log!(Error, "ps2", "Error reading data from keyboard: {:?}", err);
+                node.record_error();
             },
         }
+
+        false
     }
 }
 
 #[dyn_dyn_impl(Keyboard)]
-impl Device for Ps2Keyboard {}
+impl Device for Ps2Keyboard {
+    unsafe fn on_disconnected(&self) {
+        let mut guard = self.lock().into_keyboard();
+
+        guard.key_repeater.stop();
+
+        if let Some(input_future) = guard.input_future.take() {
+            input_future.finish(Err(KeyboardError));
+        }
+    }
+}
 
 impl Keyboard for Ps2Keyboard {
     fn lock_state(&self) -> Result<KeyboardLockState, KeyboardError> {
@@ -310,8 +532,10 @@ impl Keyboard for Ps2Keyboard {
     }
 
     fn set_lock_state(&self, lock_state: KeyboardLockState) -> Result<(), KeyboardError> {
-        self.lock().keyboard().lock_state = lock_state;
-        Ok(())
+        let mut guard = self.lock();
+
+        guard.keyboard().lock_state = lock_state;
+        Self::set_leds_blocking(&mut guard, encode_leds(lock_state))
     }
 
     fn mod_state(&self) -> Result<ModifierState, KeyboardError> {
@@ -344,37 +568,227 @@ impl Keyboard for Ps2Keyboard {
             future
         }
     }
+
+    fn echo(&self) -> Result<(), KeyboardError> {
+        let mut guard = self.lock();
+        let result = send_keyboard_command(&mut guard.controller().controller, KEYBOARD_CMD_ECHO);
+
+        Self::resume_led_update(&mut guard);
+
+        match result {
+            Ok(KEYBOARD_RESPONSE_ECHO) => Ok(()),
+            Ok(resp) => {
+                log!(Warning, "ps2", "Keyboard responded to echo with {:#04x}", resp);
+                Err(KeyboardError)
+            },
+            Err(err) => {
+                log!(Warning, "ps2", "Keyboard did not respond to echo: {:?}", err);
+                Err(KeyboardError)
+            },
+        }
+    }
+
+    fn self_test(&self) -> Result<(), KeyboardError> {
+        let mut guard = self.lock();
+        let typematic = guard.keyboard().typematic;
+        let leds = encode_leds(guard.keyboard().lock_state);
+        let result: Result<(), Ps2Error> = try {
+            let controller = &mut guard.controller().controller;
+
+            controller.keyboard().reset_and_self_test()?;
+            controller.keyboard().set_scancode_set(2)?;
+
+            if send_keyboard_command(controller, KEYBOARD_CMD_SET_TYPEMATIC)? != KEYBOARD_RESPONSE_ACK
+                || send_keyboard_command(controller, typematic)? != KEYBOARD_RESPONSE_ACK
+            {
+                Err(ps2::error::ControllerError::Timeout)?;
+            }
+
+            send_keyboard_leds(controller, leds)?;
+        };
+
+        // Any partially received scancode and any keys that were held are lost when the keyboard is reset, though the lock state is kept
+        let keyboard = guard.keyboard();
+
+        keyboard.scancode_buf_pos = 0;
+        keyboard.held_keys = Ps2KeyboardHeldKeys::new();
+        keyboard.mod_state = ModifierState::none();
+        keyboard.key_repeater.stop();
+        keyboard.leds = if result.is_ok() { leds } else { 0 };
+        keyboard.led_update = Ps2LedUpdate::Idle;
+
+        result.map_err(|err| {
+            log!(Error, "ps2", "Keyboard self-test failed: {:?}", err);
+            KeyboardError
+        })
+    }
+
+    fn set_leds(&self, lock_state: KeyboardLockState) -> Result<(), KeyboardError> {
+        Self::set_leds_blocking(&mut self.lock(), encode_leds(lock_state))
+    }
+
+    fn typematic(&self) -> Option<TypematicConfig> {
+        Some(decode_typematic(self.lock().keyboard().typematic))
+    }
+
+    fn soft_repeat(&self) -> Option<TypematicConfig> {
+        self.lock().keyboard().key_repeater.config()
+    }
+
+    fn set_soft_repeat(&self, config: Option<TypematicConfig>) -> Result<(), KeyboardError> {
+        self.lock().keyboard().key_repeater.set_config(config);
+        Ok(())
+    }
+
+    fn accessibility(&self) -> Option<AccessibilityConfig> {
+        Some(self.lock().keyboard().key_filter.config())
+    }
+
+    fn set_accessibility(&self, config: AccessibilityConfig) -> Result<(), KeyboardError> {
+        self.lock().keyboard().key_filter.set_config(config);
+        Ok(())
+    }
+
+    fn set_typematic(&self, config: TypematicConfig) -> Result<TypematicConfig, KeyboardError> {
+        let typematic = encode_typematic(config);
+        let mut guard = self.lock();
+        let controller = &mut guard.controller().controller;
+        let result = send_keyboard_command(controller, KEYBOARD_CMD_SET_TYPEMATIC).and_then(|resp| {
+            if resp == KEYBOARD_RESPONSE_ACK {
+                send_keyboard_command(controller, typematic)
+            } else {
+                Ok(resp)
+            }
+        });
+
+        Self::resume_led_update(&mut guard);
+
+        match result {
+            Ok(KEYBOARD_RESPONSE_ACK) => {
+                guard.keyboard().typematic = typematic;
+                Ok(decode_typematic(typematic))
+            },
+            Ok(resp) => {
+                log!(Warning, "ps2", "Keyboard rejected typematic configuration with {:#04x}", resp);
+                Err(KeyboardError)
+            },
+            Err(err) => {
+                log!(Warning, "ps2", "Failed to set keyboard typematic configuration: {:?}", err);
+                Err(KeyboardError)
+            },
+        }
+    }
 }
 
-#[allow(dead_code)]
 struct Ps2MouseGuard<'a> {
     controller: UninterruptibleSpinlockGuard<'a, Ps2ControllerInternals>,
     mouse: &'a mut Ps2MouseInternals,
 }
 
-#[allow(dead_code)]
 impl<'a> Ps2MouseGuard<'a> {
-    pub fn controller(&mut self) -> &mut Ps2ControllerInternals {
-        &mut self.controller
-    }
-
     pub fn mouse(&mut self) -> &mut Ps2MouseInternals {
         self.mouse
     }
+
+    pub fn into_mouse(self) -> UninterruptibleSpinlockGuard<'a, Ps2MouseInternals> {
+        UninterruptibleSpinlockGuard::replace_data(self.controller, self.mouse)
+    }
+}
+
+/// The format of the movement packets sent by a PS/2 mouse, which depends on which extensions the mouse has agreed to enable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MouseProtocol {
+    /// 3-byte packets reporting three buttons.
+    Standard,
+    /// 4-byte IntelliMouse packets, which add a scroll wheel.
+    Wheel,
+    /// 4-byte IntelliMouse Explorer packets, which add a scroll wheel and two side buttons.
+    WheelFiveButtons,
+}
+
+impl MouseProtocol {
+    fn packet_len(self) -> u8 {
+        match self {
+            MouseProtocol::Standard => 3,
+            MouseProtocol::Wheel | MouseProtocol::WheelFiveButtons => 4,
+        }
+    }
+
+    fn supported_buttons(self) -> MouseButtons {
+        match self {
+            MouseProtocol::Standard | MouseProtocol::Wheel => MouseButtons::LEFT | MouseButtons::RIGHT | MouseButtons::MIDDLE,
+            MouseProtocol::WheelFiveButtons => MouseButtons::all(),
+        }
+    }
+}
+
+fn decode_mouse_packet(protocol: MouseProtocol, packet: &[u8]) -> MouseEvent {
+    let flags = packet[0];
+
+    // Movement is sent as 9-bit two's complement numbers whose sign bits are in the first byte. If the movement was too large to fit,
+    // the delta is meaningless and is dropped.
+    let delta = |b: u8, sign: u8, overflow: u8| {
+        if flags & overflow != 0 {
+            0
+        } else if flags & sign != 0 {
+            i32::from(b) - 256
+        } else {
+            i32::from(b)
+        }
+    };
+
+    let mut buttons = MouseButtons::from_bits_truncate(flags & 0x07);
+    let wheel = match protocol {
+        MouseProtocol::Standard => 0,
+        MouseProtocol::Wheel => i32::from(packet[3] as i8),
+        MouseProtocol::WheelFiveButtons => {
+            buttons.set(MouseButtons::BUTTON_4, packet[3] & 0x10 != 0);
+            buttons.set(MouseButtons::BUTTON_5, packet[3] & 0x20 != 0);
+
+            // Only the low 4 bits hold the wheel movement
+            i32::from(((packet[3] << 4) as i8) >> 4)
+        },
+    };
+
+    MouseEvent {
+        buttons,
+        dx: delta(packet[1], MOUSE_PACKET_X_SIGN, MOUSE_PACKET_X_OVERFLOW),
+        // PS/2 mice count upwards movement as positive, unlike screen coordinates
+        dy: -delta(packet[2], MOUSE_PACKET_Y_SIGN, MOUSE_PACKET_Y_OVERFLOW),
+        wheel,
+    }
 }
 
 #[derive(Debug)]
-struct Ps2MouseInternals {}
+struct Ps2MouseInternals {
+    packet: [u8; 4],
+    buttons: MouseButtons,
+    input_buf: ArrayDeque<MouseEvent, 32>,
+    input_future: Option<FutureWriter<Result<MouseEvent, MouseError>>>,
+}
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct Ps2Mouse {
     internal: SyncUnsafeCell<Ps2MouseInternals>,
     controller: DeviceRef<Ps2Controller>,
+    protocol: MouseProtocol,
 }
 
-#[allow(dead_code)]
 impl Ps2Mouse {
+    fn create(controller: &DeviceRef<Ps2Controller>, protocol: MouseProtocol) -> DeviceRef<Ps2Mouse> {
+        DeviceNode::new(Box::from("mouse"), Ps2Mouse {
+            controller: controller.clone(),
+            internal: SyncUnsafeCell::new(Ps2MouseInternals {
+                packet: [0; 4],
+                buttons: MouseButtons::empty(),
+                input_buf: ArrayDeque::new(),
+                input_future: None,
+            }),
+            protocol,
+        })
+        .connect(DeviceRef::<Ps2Controller>::downgrade(controller))
+    }
+
     fn lock(&self) -> Ps2MouseGuard {
         self.lock_from_controller(self.controller.dev().internal.lock())
     }
@@ -387,24 +801,83 @@ impl Ps2Mouse {
         }
     }
 
-    fn handle_interrupt(guard: &mut Ps2MouseGuard) {
-        match guard.controller().controller.read_data() {
-            Ok(_) => {},
-            Err(err) => {
-                log!(Error, "ps2", "Error reading data from mouse: {:?}", err);
-            },
+    /// Handles a byte received from the mouse, which is at the provided position within the current movement packet.
+    fn handle_data(&self, guard: &mut Ps2MouseGuard, pos: u8, b: u8) {
+        let packet_len = self.protocol.packet_len();
+        let mouse = guard.mouse();
+
+        mouse.packet[usize::from(pos)] = b;
+
+        if pos + 1 != packet_len {
+            return;
+        }
+
+        let event = decode_mouse_packet(self.protocol, &mouse.packet[..usize::from(packet_len)]);
+
+        mouse.buttons = event.buttons;
+
+        if let Some(input_future) = mouse.input_future.take() {
+            input_future.finish(Ok(event));
+        } else if mouse.input_buf.is_full() {
+            // Nobody is keeping up with the mouse, so fold the movement into the last queued event rather than losing it
+            let last = mouse.input_buf.back_mut().unwrap();
+
+            last.buttons = event.buttons;
+            last.dx += event.dx;
+            last.dy += event.dy;
+            last.wheel += event.wheel;
+        } else {
+            let _ = mouse.input_buf.push_back(event);
         }
     }
 }
 
-#[dyn_dyn_impl]
-impl Device for Ps2Mouse {}
+#[dyn_dyn_impl(Mouse)]
+impl Device for Ps2Mouse {
+    unsafe fn on_disconnected(&self) {
+        let mut guard = self.lock().into_mouse();
+
+        if let Some(input_future) = guard.input_future.take() {
+            input_future.finish(Err(MouseError));
+        }
+    }
+}
+
+impl Mouse for Ps2Mouse {
+    fn buttons(&self) -> Result<MouseButtons, MouseError> {
+        Ok(self.lock().mouse().buttons)
+    }
+
+    fn supported_buttons(&self) -> MouseButtons {
+        self.protocol.supported_buttons()
+    }
+
+    fn has_wheel(&self) -> bool {
+        self.protocol != MouseProtocol::Standard
+    }
+
+    fn next_event(&self) -> Future<Result<MouseEvent, MouseError>> {
+        let mut guard = self.lock().into_mouse();
+        if let Some(event) = guard.input_buf.pop_front() {
+            Future::done(Ok(event))
+        } else if let Some(ref input_future) = guard.input_future {
+            input_future.as_future()
+        } else {
+            let (future, writer) = Future::new();
+            guard.input_future = Some(writer);
+            future
+        }
+    }
+}
 
 #[derive(Debug)]
 struct Ps2ControllerInternals {
+    own_ref: DeviceWeak<Ps2Controller>,
     controller: ps2::Controller,
     keyboard: Option<DeviceRef<Ps2Keyboard>>,
     mouse: Option<DeviceRef<Ps2Mouse>>,
+    mouse_packet_pos: u8,
+    mouse_self_test_passed: bool,
 }
 
 #[derive(Debug)]
@@ -412,8 +885,148 @@ pub struct Ps2Controller {
     internal: UninterruptibleSpinlock<Ps2ControllerInternals>,
 }
 
+impl Ps2Controller {
+    fn update_status(this: &DeviceNode<Ps2Controller>, internal: &Ps2ControllerInternals) {
+        let attached = |attached: bool| if attached { "attached" } else { "not attached" };
+
+        this.set_status(Some(&format!(
+            "keyboard {}, mouse {}",
+            attached(internal.keyboard.is_some()),
+            attached(internal.mouse.is_some())
+        )));
+    }
+
+    fn handle_keyboard_interrupt(this: &DeviceRef<Ps2Controller>) {
+        let mut internal = this.dev().internal.lock();
+
+        let reconnected = if let Some(keyboard) = internal.keyboard.clone() {
+            Ps2Keyboard::handle_interrupt(&keyboard, &mut keyboard.dev().lock_from_controller(internal))
+        } else {
+            match internal.controller.read_data() {
+                Ok(DEVICE_SELF_TEST_PASSED | DEVICE_SELF_TEST_FAILED) => true,
+                Ok(b) => {
+                    log!(Warning, "ps2", "Received byte {:#04x} with no keyboard attached", b);
+                    false
+                },
+                Err(_) => false,
+            }
+        };
+
+        if reconnected {
+            let this = this.clone();
+            sched::enqueue_soft_interrupt(move || Ps2Controller::reconnect_keyboard(&this));
+        }
+    }
+
+    fn handle_mouse_interrupt(this: &DeviceRef<Ps2Controller>) {
+        let mut internal = this.dev().internal.lock();
+
+        let b = match internal.controller.read_data() {
+            Ok(b) => b,
+            Err(ps2::error::ControllerError::Timeout) => {
+                return;
+            },
+            Err(err) => {
+                log!(Error, "ps2", "Error reading data from mouse: {:?}", err);
+
+                match internal.mouse {
+                    Some(ref mouse) => mouse.record_error(),
+                    None => this.record_error(),
+                }
+                return;
+            },
+        };
+
+        // A freshly plugged in mouse sends a self-test result followed by its ID. While a mouse is attached, the self-test result is only
+        // recognized where the first byte of a movement packet is expected, since 0xaa is a perfectly valid movement delta. A packet that
+        // starts with 0xaa 0x00 would still be mistaken for a reconnect, but that requires a vertical overflow and the only consequence is
+        // that the mouse gets reinitialized.
+        let packet_pos = internal.mouse_packet_pos;
+        let reconnected = internal.mouse_self_test_passed && b == MOUSE_ID_STANDARD;
+
+        internal.mouse_self_test_passed = b == DEVICE_SELF_TEST_PASSED && (internal.mouse.is_none() || packet_pos == 0);
+
+        if reconnected {
+            internal.mouse_self_test_passed = false;
+            drop(internal);
+
+            let this = this.clone();
+            sched::enqueue_soft_interrupt(move || Ps2Controller::reconnect_mouse(&this));
+        } else if let Some(mouse) = internal.mouse.clone() {
+            if packet_pos == 0 && b & MOUSE_PACKET_SYNC == 0 {
+                log!(Debug, "ps2", "Discarding out-of-sync mouse byte {:#04x}", b);
+                return;
+            }
+
+            let mouse = mouse.dev();
+
+            internal.mouse_packet_pos = (packet_pos + 1) % mouse.protocol.packet_len();
+            mouse.handle_data(&mut mouse.lock_from_controller(internal), packet_pos, b);
+        }
+    }
+
+    /// Reinitializes the device on the keyboard port after it has been plugged in again, replacing the old keyboard device (if any) with
+    /// a new one. Since the state of the old keyboard was lost when it was unplugged, it is disconnected rather than reused.
+    fn reconnect_keyboard(this: &DeviceRef<Ps2Controller>) {
+        log!(Info, "ps2", "Keyboard was plugged in, reinitializing");
+
+        let old_keyboard = this.dev().internal.lock().keyboard.take();
+
+        // The old keyboard is disconnected first so that the terminals have detached it by the time the new one shows up, which lets the
+        // new keyboard take its place on the same display
+        if let Some(old_keyboard) = old_keyboard {
+            old_keyboard.disconnect();
+        }
+
+        let mut internal = this.dev().internal.lock();
+        let new_keyboard = match probe_keyboard(&mut internal.controller) {
+            Ok(true) => Some(Ps2Keyboard::create(this)),
+            Ok(false) => {
+                log!(Error, "ps2", "Keyboard does not support scancode set 2");
+                None
+            },
+            Err(err) => {
+                log!(Error, "ps2", "Failed to initialize keyboard: {:?}", err);
+                None
+            },
+        };
+
+        internal.keyboard = new_keyboard;
+        Ps2Controller::update_status(this, &internal);
+    }
+
+    /// Reinitializes the device on the mouse port after it has been plugged in again, replacing the old mouse device (if any) with a new
+    /// one.
+    fn reconnect_mouse(this: &DeviceRef<Ps2Controller>) {
+        log!(Info, "ps2", "Mouse was plugged in, reinitializing");
+
+        let old_mouse = this.dev().internal.lock().mouse.take();
+
+        if let Some(old_mouse) = old_mouse {
+            old_mouse.disconnect();
+        }
+
+        let mut internal = this.dev().internal.lock();
+        let new_mouse = match probe_mouse(&mut internal.controller) {
+            Ok(protocol) => Some(Ps2Mouse::create(this, protocol)),
+            Err(err) => {
+                log!(Error, "ps2", "Failed to initialize mouse: {:?}", err);
+                None
+            },
+        };
+
+        internal.mouse_packet_pos = 0;
+        internal.mouse = new_mouse;
+        Ps2Controller::update_status(this, &internal);
+    }
+}
+
 #[dyn_dyn_impl(DeviceHub)]
-impl Device for Ps2Controller {}
+impl Device for Ps2Controller {
+    unsafe fn on_connected(&self, own_ref: &DeviceRef<Ps2Controller>) {
+        self.internal.lock().own_ref = DeviceRef::downgrade(own_ref);
+    }
+}
 
 impl DeviceHub for Ps2Controller {
     fn for_children(&self, f: &mut dyn FnMut(&DeviceRef<dyn Device>) -> bool) -> bool {
@@ -426,8 +1039,104 @@ impl DeviceHub for Ps2Controller {
             }
         }
 
+        if let Some(mouse) = internal.mouse.as_ref() {
+            let mouse: DeviceRef<dyn Device> = mouse.clone();
+            if !f(&mouse) {
+                return false;
+            }
+        }
+
         true
     }
+
+    fn remove_child(&self, child: &DeviceRef<dyn Device>) -> Result<(), DeviceRemoveError> {
+        let mut internal = self.internal.lock();
+        let child = &**child as *const _ as *const ();
+
+        // The port is left enabled, so a device plugged into it later is still detected
+        if internal
+            .keyboard
+            .as_ref()
+            .is_some_and(|k| ptr::eq(&**k as *const _ as *const (), child))
+        {
+            internal.keyboard = None;
+        } else if internal
+            .mouse
+            .as_ref()
+            .is_some_and(|m| ptr::eq(&**m as *const _ as *const (), child))
+        {
+            internal.mouse = None;
+            internal.mouse_packet_pos = 0;
+        } else {
+            return Err(DeviceRemoveError::NotConnected);
+        }
+
+        if let Some(this) = internal.own_ref.upgrade() {
+            Ps2Controller::update_status(&this, &internal);
+        }
+
+        Ok(())
+    }
+}
+
+/// Resets the keyboard and switches it to scancode set 2. Returns `false` if the keyboard does not support scancode set 2.
+fn probe_keyboard(controller: &mut ps2::Controller) -> Result<bool, ps2::error::KeyboardError> {
+    controller.keyboard().reset_and_self_test()?;
+    controller.keyboard().set_scancode_set(2)?;
+
+    Ok(controller.keyboard().get_scancode_set()? == 2)
+}
+
+/// Sets the sample rate of the mouse to each of the provided rates in turn and then returns its ID. Mice that support protocol extensions
+/// use particular sequences of rates as a signal to enable them, which they confirm by changing their ID.
+fn knock_mouse(controller: &mut ps2::Controller, rates: [u8; 3]) -> Result<u8, ps2::error::MouseError> {
+    for rate in rates {
+        controller.mouse().set_sample_rate(rate)?;
+    }
+
+    controller.mouse().get_device_id()
+}
+
+/// Resets the mouse, enables any protocol extensions it supports and enables reporting of movement data. Returns the protocol that the
+/// mouse will use to report movement.
+fn probe_mouse(controller: &mut ps2::Controller) -> Result<MouseProtocol, ps2::error::MouseError> {
+    controller.mouse().reset_and_self_test()?;
+
+    let protocol = if knock_mouse(controller, [200, 100, 80])? != MOUSE_ID_WHEEL {
+        MouseProtocol::Standard
+    } else if knock_mouse(controller, [200, 200, 80])? != MOUSE_ID_FIVE_BUTTONS {
+        MouseProtocol::Wheel
+    } else {
+        MouseProtocol::WheelFiveButtons
+    };
+
+    controller.mouse().set_sample_rate(MOUSE_DEFAULT_SAMPLE_RATE)?;
+    controller.mouse().enable_data_reporting()?;
+
+    Ok(protocol)
+}
+
+/// The driver for the 8042 PS/2 controller found on PC-compatible machines.
+pub struct Ps2Driver;
+
+pub static DRIVER: Ps2Driver = Ps2Driver;
+
+impl Driver for Ps2Driver {
+    fn name(&self) -> &'static str {
+        "ps2"
+    }
+
+    fn match_rules(&self) -> &[MatchRule] {
+        &[MatchRule::Platform("PNP0303")]
+    }
+
+    fn probe(&self, _dev: &DeviceInfo) -> Result<(), ProbeError> {
+        // SAFETY: There is only ever one 8042 controller, so it can only be announced once
+        match unsafe { init() } {
+            Some(_) => Ok(()),
+            None => Err(ProbeError::Failed),
+        }
+    }
 }
 
 pub unsafe fn init() -> Option<DeviceRef<Ps2Controller>> {
@@ -452,152 +1161,124 @@ pub unsafe fn init() -> Option<DeviceRef<Ps2Controller>> {
         controller.test_controller()?;
         controller.write_config(config)?;
 
-        let has_keyboard = match controller.test_keyboard() {
+        // Ports that pass their interface test are left enabled even if no working device is found on them, so that a device that gets
+        // plugged in later can announce itself by sending its self-test result.
+        let keyboard_port_ok = match controller.test_keyboard() {
             Err(err) => {
                 log!(Error, "ps2", "Failed to initialize keyboard: {:?}", err);
                 false
             },
             Ok(()) => {
-                let result: Result<u8, ps2::error::KeyboardError> = try {
-                    controller.enable_keyboard()?;
-                    controller.keyboard().reset_and_self_test()?;
-                    controller.keyboard().set_scancode_set(2)?;
-                    controller.keyboard().get_scancode_set()?
-                };
-
-                match result {
-                    Err(err) => {
-                        log!(Error, "ps2", "Failed to initialize keyboard: {:?}", err);
-                        controller.disable_keyboard()?;
-                        false
-                    },
-                    Ok(2) => {
-                        config.set(ps2::flags::ControllerConfigFlags::DISABLE_KEYBOARD, false);
-                        config.set(ps2::flags::ControllerConfigFlags::ENABLE_KEYBOARD_INTERRUPT, true);
-                        true
-                    },
-                    Ok(_) => {
-                        log!(Error, "ps2", "Keyboard does not support scancode set 2");
-                        controller.disable_keyboard()?;
-                        false
-                    },
-                }
+                controller.enable_keyboard()?;
+                config.set(ps2::flags::ControllerConfigFlags::DISABLE_KEYBOARD, false);
+                config.set(ps2::flags::ControllerConfigFlags::ENABLE_KEYBOARD_INTERRUPT, true);
+                true
             },
         };
 
-        let has_mouse = match controller.test_mouse() {
+        let has_keyboard = keyboard_port_ok
+            && match probe_keyboard(&mut controller) {
+                Err(err) => {
+                    log!(Error, "ps2", "Failed to initialize keyboard: {:?}", err);
+                    false
+                },
+                Ok(true) => true,
+                Ok(false) => {
+                    log!(Error, "ps2", "Keyboard does not support scancode set 2");
+                    false
+                },
+            };
+
+        let mouse_port_ok = match controller.test_mouse() {
             Err(err) => {
                 log!(Error, "ps2", "Failed to initialize mouse: {:?}", err);
                 false
             },
             Ok(()) => {
                 controller.enable_mouse()?;
-                match controller.mouse().reset_and_self_test() {
-                    Err(err) => {
-                        log!(Error, "ps2", "Failed to initialize mouse: {:?}", err);
-                        controller.disable_mouse()?;
-                        false
-                    },
-                    Ok(()) => {
-                        config.set(ps2::flags::ControllerConfigFlags::DISABLE_MOUSE, false);
-                        config.set(ps2::flags::ControllerConfigFlags::ENABLE_MOUSE_INTERRUPT, true);
-
-                        controller.mouse().enable_data_reporting()?;
-                        true
-                    },
-                }
+                config.set(ps2::flags::ControllerConfigFlags::DISABLE_MOUSE, false);
+                config.set(ps2::flags::ControllerConfigFlags::ENABLE_MOUSE_INTERRUPT, true);
+                true
             },
         };
 
+        let mouse_protocol = if mouse_port_ok {
+            match probe_mouse(&mut controller) {
+                Err(err) => {
+                    log!(Error, "ps2", "Failed to initialize mouse: {:?}", err);
+                    None
+                },
+                Ok(protocol) => Some(protocol),
+            }
+        } else {
+            None
+        };
+
         controller.write_config(config)?;
 
         let controller = device_root().dev().add_device(DeviceNode::new(Box::from("ps2"), Ps2Controller {
             internal: UninterruptibleSpinlock::new(Ps2ControllerInternals {
+                own_ref: DeviceWeak::new(),
                 controller,
                 keyboard: None,
                 mouse: None,
+                mouse_packet_pos: 0,
+                mouse_self_test_passed: false,
             }),
         }));
 
+        recovery::attach_to_current(DeviceRef::downgrade(&controller));
+
         let keyboard = if has_keyboard {
-            Some(
-                DeviceNode::new(Box::from("keyboard"), Ps2Keyboard {
-                    controller: controller.clone(),
-                    internal: SyncUnsafeCell::new(Ps2KeyboardInternals {
-                        lock_state: KeyboardLockState::none(),
-                        mod_state: ModifierState::none(),
-                        held_keys: Ps2KeyboardHeldKeys::new(),
-                        input_buf: ArrayDeque::new(),
-                        input_future: None,
-                        scancode_buf: [0; 5],
-                        scancode_buf_pos: 0,
-                        scancode_map: &scancode_2_map::MAP,
-                        keycode_map: KeycodeMap::fallback(),
-                    }),
-                })
-                .connect(DeviceRef::<Ps2Controller>::downgrade(&controller)),
-            )
+            Some(Ps2Keyboard::create(&controller))
         } else {
             None
         };
+        let mouse = mouse_protocol.map(|protocol| Ps2Mouse::create(&controller, protocol));
+
+        // Each port's IRQ handler runs in its own recovery domain, so that a bug in handling one device only disconnects that device
+        let keyboard_domain = RecoveryDomain::new("ps2 keyboard");
+        let mouse_domain = RecoveryDomain::new("ps2 mouse");
 
         if let Some(ref keyboard) = keyboard {
-            let keyboard = keyboard.clone();
-            sched::enqueue_soft_interrupt(move || {
-                vt::get_global_manager().dev().attach_keyboard(0, keyboard);
-            });
-        }
-
-        let mouse = if has_mouse {
-            Some(
-                DeviceNode::new(Box::from("mouse"), Ps2Mouse {
-                    controller: controller.clone(),
-                    internal: SyncUnsafeCell::new(Ps2MouseInternals {}),
-                })
-                .connect(DeviceRef::<Ps2Controller>::downgrade(&controller)),
-            )
-        } else {
-            None
-        };
+            keyboard_domain.attach(DeviceRef::downgrade(keyboard));
+        }
+
+        if let Some(ref mouse) = mouse {
+            mouse_domain.attach(DeviceRef::downgrade(mouse));
+        }
 
         let mut controller_lock = controller.dev().internal.lock();
         controller_lock.keyboard = keyboard;
         controller_lock.mouse = mouse;
+        Ps2Controller::update_status(&controller, &controller_lock);
         drop(controller_lock);
 
-        if has_keyboard {
+        if keyboard_port_ok {
             let controller_for_keyboard_interrupt = controller.clone();
-            interrupt::register_irq(
+            controller.register_irq(
                 1,
-                Box::new(move |_| {
-                    let mut internal = controller_for_keyboard_interrupt.dev().internal.lock();
-
-                    if let Some(keyboard) = internal.keyboard.clone() {
-                        Ps2Keyboard::handle_interrupt(&mut keyboard.dev().lock_from_controller(internal))
-                    } else {
-                        log!(Warning, "ps2", "Received keyboard interrupt with no keyboard attached?");
-                        let _ = internal.controller.read_data();
-                    }
-                }),
+                recovery::wrap_irq_handler(
+                    keyboard_domain,
+                    Box::new(move |_| {
+                        Ps2Controller::handle_keyboard_interrupt(&controller_for_keyboard_interrupt);
+                    }),
+                ),
             );
 
             pic::set_irq_masked(1, false);
         }
 
-        if has_mouse {
+        if mouse_port_ok {
             let controller_for_mouse_interrupt = controller.clone();
-            interrupt::register_irq(
+            controller.register_irq(
                 12,
-                Box::new(move |_| {
-                    let mut internal = controller_for_mouse_interrupt.dev().internal.lock();
-
-                    if let Some(mouse) = internal.mouse.clone() {
-                        Ps2Mouse::handle_interrupt(&mut mouse.dev().lock_from_controller(internal))
-                    } else {
-                        log!(Warning, "ps2", "Received mouse interrupt with no mouse attached?");
-                        let _ = internal.controller.read_data();
-                    }
-                }),
+                recovery::wrap_irq_handler(
+                    mouse_domain,
+                    Box::new(move |_| {
+                        Ps2Controller::handle_mouse_interrupt(&controller_for_mouse_interrupt);
+                    }),
+                ),
             );
             pic::set_irq_masked(12, false);
         }
@@ -634,3 +1315,77 @@ pub unsafe fn init() -> Option<DeviceRef<Ps2Controller>> {
         },
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_mouse_packet_decoding() {
+        let event = |buttons, dx, dy, wheel| MouseEvent { buttons, dx, dy, wheel };
+
+        assert_eq!(
+            event(MouseButtons::LEFT, 5, -3, 0),
+            decode_mouse_packet(MouseProtocol::Standard, &[0x09, 5, 3])
+        );
+        assert_eq!(
+            event(MouseButtons::RIGHT | MouseButtons::MIDDLE, -2, 4, 0),
+            decode_mouse_packet(MouseProtocol::Standard, &[0x3e, 0xfe, 0xfc])
+        );
+
+        // Overflowed deltas are dropped
+        assert_eq!(
+            event(MouseButtons::empty(), 0, -1, 0),
+            decode_mouse_packet(MouseProtocol::Standard, &[0x48, 0xff, 1])
+        );
+
+        assert_eq!(
+            event(MouseButtons::empty(), 0, 0, -1),
+            decode_mouse_packet(MouseProtocol::Wheel, &[0x08, 0, 0, 0xff])
+        );
+        assert_eq!(
+            event(MouseButtons::BUTTON_5, 0, 0, -2),
+            decode_mouse_packet(MouseProtocol::WheelFiveButtons, &[0x08, 0, 0, 0x2e])
+        );
+    }
+
+    #[test_case]
+    fn test_led_encoding() {
+        let lock_state = |scroll_lock, num_lock, caps_lock| KeyboardLockState {
+            scroll_lock,
+            num_lock,
+            caps_lock,
+        };
+
+        assert_eq!(0x00, encode_leds(lock_state(false, false, false)));
+        assert_eq!(0x01, encode_leds(lock_state(true, false, false)));
+        assert_eq!(0x02, encode_leds(lock_state(false, true, false)));
+        assert_eq!(0x04, encode_leds(lock_state(false, false, true)));
+        assert_eq!(0x06, encode_leds(lock_state(false, true, true)));
+    }
+
+    #[test_case]
+    fn test_typematic_encoding() {
+        assert_eq!(
+            TypematicConfig {
+                delay_ms: 500,
+                rate_decihz: 109
+            },
+            decode_typematic(DEFAULT_TYPEMATIC)
+        );
+        assert_eq!(300, decode_typematic(0x00).rate_decihz);
+        assert_eq!(20, decode_typematic(0x1f).rate_decihz);
+
+        for typematic in 0..0x80 {
+            assert_eq!(typematic, encode_typematic(decode_typematic(typematic)));
+        }
+
+        assert_eq!(
+            0x60,
+            encode_typematic(TypematicConfig {
+                delay_ms: 5000,
+                rate_decihz: 1000
+            })
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Parsing of PS/2 scancode set 2 and translation of scancodes into keycodes.
+//!
+//! This module only depends on [`crate::io::keymap`], so that it can also be built for the host by the fuzzing harness, which feeds it
+//! arbitrary bytes since they come straight from the keyboard.
+
+use crate::io::keymap::Keycode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeKey {
+    Basic(u8),
+    Extended(u8),
+    DualExtended(u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scancode {
+    pub key: ScancodeKey,
+    pub released: bool,
+}
+
+impl Scancode {
+    /// Parses the scancode at the start of the provided buffer, returning it along with the number of bytes it takes up. Returns [`None`]
+    /// if the buffer only contains the start of a scancode.
+    pub fn try_parse(buf: &[u8]) -> Option<(Scancode, usize)> {
+        match buf.first().copied() {
+            Some(0xE0) => match buf.get(1).copied() {
+                Some(0xF0) if buf.len() >= 3 => Some((
+                    Scancode {
+                        key: ScancodeKey::Extended(buf[2]),
+                        released: true,
+                    },
+                    3,
+                )),
+                Some(0xF0) => None,
+                Some(key) => Some((
+                    Scancode {
+                        key: ScancodeKey::Extended(key),
+                        released: false,
+                    },
+                    2,
+                )),
+                None => None,
+            },
+            Some(0xE1) => match buf.get(1).copied() {
+                Some(0xF0) if buf.len() >= 5 => Some((
+                    Scancode {
+                        key: ScancodeKey::DualExtended(buf[2], buf[4]),
+                        released: true,
+                    },
+                    5,
+                )),
+                Some(0xF0) => None,
+                _ if buf.len() >= 3 => Some((
+                    Scancode {
+                        key: ScancodeKey::DualExtended(buf[1], buf[2]),
+                        released: false,
+                    },
+                    3,
+                )),
+                _ => None,
+            },
+            Some(0xF0) if buf.len() >= 2 => Some((
+                Scancode {
+                    key: ScancodeKey::Basic(buf[1]),
+                    released: true,
+                },
+                2,
+            )),
+            Some(0xF0) => None,
+            Some(key) => Some((
+                Scancode {
+                    key: ScancodeKey::Basic(key),
+                    released: false,
+                },
+                1,
+            )),
+            None => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScancodeMapDualList {
+    Static(&'static [(u8, u8, Keycode)]),
+}
+
+#[derive(Debug)]
+pub struct ScancodeMap {
+    pub basic: [Option<Keycode>; 256],
+    pub extended: [Option<Keycode>; 256],
+    pub dual_extended: ScancodeMapDualList,
+}
+
+impl ScancodeMap {
+    pub fn get(&self, key: ScancodeKey) -> Option<Keycode> {
+        match key {
+            ScancodeKey::Basic(b) => self.basic[b as usize],
+            ScancodeKey::Extended(b) => self.extended[b as usize],
+            ScancodeKey::DualExtended(b0, b1) => match self.dual_extended {
+                ScancodeMapDualList::Static(list) => list.iter().find(|&&(m0, m1, _)| m0 == b0 && m1 == b1).map(|&(_, _, k)| k),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_scancode() {
+        assert_eq!(None, Scancode::try_parse(&[]));
+        assert_eq!(None, Scancode::try_parse(&[0xf0]));
+        assert_eq!(None, Scancode::try_parse(&[0xe1, 0x14]));
+        assert_eq!(
+            Some((
+                Scancode {
+                    key: ScancodeKey::Extended(0x75),
+                    released: true
+                },
+                3
+            )),
+            Scancode::try_parse(&[0xe0, 0xf0, 0x75, 0x1c])
+        );
+        assert_eq!(
+            Some((
+                Scancode {
+                    key: ScancodeKey::DualExtended(0x14, 0x77),
+                    released: true
+                },
+                5
+            )),
+            Scancode::try_parse(&[0xe1, 0xf0, 0x14, 0xf0, 0x77])
+        );
+    }
+}
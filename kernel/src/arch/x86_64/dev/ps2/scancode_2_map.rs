@@ -1,4 +1,4 @@
-use super::{ScancodeMap, ScancodeMapDualList};
+use super::scancode::{ScancodeMap, ScancodeMapDualList};
 use crate::io::keymap::{CommonKeycode, Keycode};
 
 const DUAL_LIST: [(u8, u8, Keycode); 1] = [(0x14, 0x77, Keycode::Common(CommonKeycode::Pause))];
@@ -1,59 +1,494 @@
+//! Driver for 16550-compatible UARTs.
+//!
+//! The serial port is needed for logging very early during boot, before interrupts can be handled, so it starts out transmitting each
+//! byte synchronously by polling the line status register. Once [`enable_irq`] has been called, the port switches to being driven by its
+//! IRQ: written bytes are placed in a transmit ring buffer that the IRQ handler feeds into the UART's FIFO whenever it has room, and
+//! received bytes are handed directly to pending reads or kept in a receive ring buffer until they are read. Reads through both [`Tty`]
+//! and [`CharDevice`] return futures that resolve once data has arrived, so readers like the debug console no longer spin while waiting
+//! for input.
+//!
+//! If the transmit buffer fills up (e.g. because lots of messages are logged at once), writers fall back to polling until there is room
+//! again, so that writes never need to block or drop data.
+
 use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::fmt;
 
 use dyn_dyn::dyn_dyn_impl;
+use x86_64::instructions::port::Port;
 
+use crate::arch::pic;
+use crate::io::dev::chardev::{self, CharDevice, CharDeviceError};
+use crate::io::dev::recovery::{self, RecoveryDomain};
 use crate::io::dev::{self, Device, DeviceNode, DeviceRef};
-use crate::io::tty::Tty;
+use crate::io::tty::{Tty, TtyCapabilities};
+use crate::options;
+use crate::shutdown::{self, ShutdownStage};
+use crate::sync::future::FutureWriter;
 use crate::sync::{Future, UninterruptibleSpinlock};
+use crate::util::{ArrayDeque, OneShotManualInit};
+
+const COM1_BASE: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+
+const REG_DATA: u16 = 0;
+const REG_IER: u16 = 1;
+const REG_IIR: u16 = 2;
+const REG_LSR: u16 = 5;
+const REG_MSR: u16 = 6;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_TX_EMPTY: u8 = 0x02;
+const IER_LINE_STATUS: u8 = 0x04;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_ID_MASK: u8 = 0x0e;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RX_TIMEOUT: u8 = 0x0c;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_OVERRUN: u8 = 0x02;
+const LSR_PARITY_ERROR: u8 = 0x04;
+const LSR_FRAMING_ERROR: u8 = 0x08;
+const LSR_TX_EMPTY: u8 = 0x20;
+
+/// The number of bytes that can be written to the UART's transmit FIFO at once after it signals that it is empty.
+const TX_FIFO_SIZE: usize = 16;
+
+const TX_BUF_SIZE: usize = 4096;
+const RX_BUF_SIZE: usize = 256;
+
+enum ReadWriter {
+    Tty(FutureWriter<Result<usize, ()>>),
+    Char(FutureWriter<Result<usize, CharDeviceError>>),
+}
+
+/// A read waiting for data to arrive. Reads through [`Tty`] translate carriage returns into newlines and are only completed once the
+/// buffer is full, while reads through [`CharDevice`] pass bytes through unmodified and are completed as soon as any data is available.
+struct ReadRequest {
+    buf: *mut [u8],
+    pos: usize,
+    writer: ReadWriter,
+}
+
+impl ReadRequest {
+    fn is_tty(&self) -> bool {
+        matches!(self.writer, ReadWriter::Tty(_))
+    }
+
+    /// Stores a received byte in this request's buffer, returning `true` if the request should be completed.
+    unsafe fn push(&mut self, mut b: u8) -> bool {
+        // TODO This should be controllable, or binary data would be a real problem
+        if self.is_tty() && b == b'\r' {
+            b = b'\n';
+        }
+
+        *(*self.buf).get_unchecked_mut(self.pos) = b;
+        self.pos += 1;
+
+        self.pos == self.buf.len() || !self.is_tty()
+    }
+
+    fn complete(self) {
+        match self.writer {
+            ReadWriter::Tty(writer) => writer.finish(Ok(self.pos)),
+            ReadWriter::Char(writer) => writer.finish(Ok(self.pos)),
+        }
+    }
+}
+
+/// Counters describing how the serial port has been used since it was initialized.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// The number of received bytes that were dropped because the receive buffer was full or the UART's FIFO overflowed.
+    pub rx_dropped: u64,
+    /// The number of times a writer had to wait for the transmit buffer to drain by polling the UART.
+    pub tx_stalls: u64,
+    /// The number of parity and framing errors reported by the UART.
+    pub line_errors: u64,
+}
+
+struct SerialPortInternals {
+    base: u16,
+    irq_enabled: bool,
+    ier: u8,
+    tx_buf: ArrayDeque<u8, TX_BUF_SIZE>,
+    rx_buf: ArrayDeque<u8, RX_BUF_SIZE>,
+    reads: VecDeque<ReadRequest>,
+    flushes: VecDeque<FutureWriter<Result<(), ()>>>,
+    stats: SerialStats,
+}
+
+// SAFETY: The buffers referenced by pending reads are guaranteed by the readers to stay valid and unaliased until the reads complete
+unsafe impl Send for SerialPortInternals {}
+
+impl SerialPortInternals {
+    unsafe fn read_reg(&self, reg: u16) -> u8 {
+        Port::<u8>::new(self.base + reg).read()
+    }
+
+    unsafe fn write_reg(&self, reg: u16, val: u8) {
+        Port::<u8>::new(self.base + reg).write(val)
+    }
+
+    unsafe fn set_ier(&mut self, ier: u8) {
+        if ier != self.ier {
+            self.ier = ier;
+            self.write_reg(REG_IER, ier);
+        }
+    }
+
+    /// Sends a single byte by waiting for the UART's transmitter to become empty. This is used when the IRQ cannot be relied upon.
+    unsafe fn send_polled(&mut self, b: u8) {
+        while self.read_reg(REG_LSR) & LSR_TX_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+
+        self.write_reg(REG_DATA, b);
+        self.stats.bytes_sent += 1;
+    }
+
+    unsafe fn write(&mut self, bytes: &[u8]) {
+        if !self.irq_enabled {
+            for &b in bytes {
+                self.send_polled(b);
+            }
+
+            return;
+        }
+
+        for &b in bytes {
+            if self.tx_buf.is_full() {
+                self.stats.tx_stalls += 1;
+
+                while self.tx_buf.len() > TX_BUF_SIZE / 2 {
+                    let b = self.tx_buf.pop_front().unwrap();
+
+                    self.send_polled(b);
+                }
+            }
+
+            assert!(self.tx_buf.push_back(b).is_ok());
+        }
+
+        // Enabling the transmitter empty interrupt while the transmitter is already empty raises the interrupt immediately
+        if !self.tx_buf.is_empty() {
+            self.set_ier(self.ier | IER_TX_EMPTY);
+        }
+    }
+
+    /// Synchronously sends everything remaining in the transmit buffer.
+    unsafe fn drain_polled(&mut self) {
+        while let Some(b) = self.tx_buf.pop_front() {
+            self.send_polled(b);
+        }
+
+        self.finish_flushes();
+    }
+
+    fn finish_flushes(&mut self) {
+        for flush in self.flushes.drain(..) {
+            flush.finish(Ok(()));
+        }
+    }
+
+    unsafe fn flush(&mut self) -> Future<Result<(), ()>> {
+        if self.tx_buf.is_empty() {
+            return Future::done(Ok(()));
+        }
+
+        let (future, writer) = Future::new();
+
+        self.flushes.push_back(writer);
+        future
+    }
+
+    unsafe fn read(&mut self, buf: *mut [u8], writer: ReadWriter) {
+        let mut req = ReadRequest { buf, pos: 0, writer };
+
+        if buf.is_empty() {
+            req.complete();
+            return;
+        }
+
+        // Bytes can only be taken from the buffer directly if nobody else is already waiting for them
+        if self.reads.is_empty() {
+            while let Some(b) = self.rx_buf.pop_front() {
+                if req.push(b) {
+                    req.complete();
+                    return;
+                }
+            }
+        }
+
+        self.reads.push_back(req);
+    }
+
+    unsafe fn receive(&mut self, b: u8) {
+        self.stats.bytes_received += 1;
+
+        if let Some(req) = self.reads.front_mut() {
+            if req.push(b) {
+                self.reads.pop_front().unwrap().complete();
+            }
+        } else if self.rx_buf.push_back(b).is_err() {
+            self.stats.rx_dropped += 1;
+        }
+    }
+
+    /// Handles all conditions that the UART is currently raising an interrupt for, returning `true` if any line errors were found.
+    unsafe fn handle_interrupt(&mut self) -> bool {
+        let mut had_errors = false;
+
+        loop {
+            let iir = self.read_reg(REG_IIR);
+
+            if iir & IIR_NO_INTERRUPT != 0 {
+                break;
+            }
+
+            match iir & IIR_ID_MASK {
+                IIR_LINE_STATUS => {
+                    let lsr = self.read_reg(REG_LSR);
+
+                    if lsr & LSR_OVERRUN != 0 {
+                        self.stats.rx_dropped += 1;
+                    }
+
+                    if lsr & (LSR_PARITY_ERROR | LSR_FRAMING_ERROR) != 0 {
+                        self.stats.line_errors += 1;
+                    }
+
+                    had_errors = true;
+                },
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => {
+                    while self.read_reg(REG_LSR) & LSR_DATA_READY != 0 {
+                        let b = self.read_reg(REG_DATA);
+
+                        self.receive(b);
+                    }
+                },
+                IIR_TX_EMPTY => {
+                    for _ in 0..TX_FIFO_SIZE {
+                        let Some(b) = self.tx_buf.pop_front() else {
+                            break;
+                        };
+
+                        self.write_reg(REG_DATA, b);
+                        self.stats.bytes_sent += 1;
+                    }
+
+                    if self.tx_buf.is_empty() {
+                        self.set_ier(self.ier & !IER_TX_EMPTY);
+                        self.finish_flushes();
+                    }
+                },
+                IIR_MODEM_STATUS => {
+                    self.read_reg(REG_MSR);
+                },
+                _ => {},
+            }
+        }
+
+        had_errors
+    }
+}
 
-#[derive(Debug)]
 pub struct SerialPort {
-    port: UninterruptibleSpinlock<uart_16550::SerialPort>,
+    internal: UninterruptibleSpinlock<SerialPortInternals>,
+    capabilities: TtyCapabilities,
+    size: Option<(usize, usize)>,
+}
+
+impl fmt::Debug for SerialPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SerialPort").finish_non_exhaustive()
+    }
+}
+
+impl SerialPort {
+    /// Gets counters describing how the serial port has been used since it was initialized.
+    pub fn stats(&self) -> SerialStats {
+        self.internal.lock().stats
+    }
+
+    /// Checks whether the serial port is being driven by its IRQ rather than by polling.
+    pub fn is_irq_driven(&self) -> bool {
+        self.internal.lock().irq_enabled
+    }
 }
 
-#[dyn_dyn_impl(Tty)]
+#[dyn_dyn_impl(Tty, CharDevice)]
 impl Device for SerialPort {}
 
 impl Tty for SerialPort {
     unsafe fn write(&self, bytes: *const [u8]) -> Future<Result<(), ()>> {
-        let mut port = self.port.lock();
-
-        for &b in bytes.as_ref().unwrap() {
-            port.send_raw(b);
-        }
-
+        self.internal.lock().write(&*bytes);
         Future::done(Ok(()))
     }
 
     unsafe fn flush(&self) -> Future<Result<(), ()>> {
-        Future::done(Ok(()))
+        self.internal.lock().flush()
     }
 
     unsafe fn read(&self, bytes: *mut [u8]) -> Future<Result<usize, ()>> {
-        let mut port = self.port.lock();
+        let (future, writer) = Future::new();
 
-        for i in 0..bytes.len() {
-            let mut b = port.receive();
+        self.internal.lock().read(bytes, ReadWriter::Tty(writer));
+        future
+    }
 
-            // TODO This should be controllable, or binary data would be a real problem
-            if b == b'\r' {
-                b = b'\n';
-            }
+    fn size(&self) -> Result<(usize, usize), ()> {
+        self.size.ok_or(())
+    }
 
-            *bytes.get_unchecked_mut(i) = b;
-        }
+    fn capabilities(&self) -> TtyCapabilities {
+        self.capabilities
+    }
+}
+
+impl CharDevice for SerialPort {
+    unsafe fn read(&self, buf: *mut [u8]) -> Future<Result<usize, CharDeviceError>> {
+        let (future, writer) = Future::new();
+
+        self.internal.lock().read(buf, ReadWriter::Char(writer));
+        future
+    }
+
+    unsafe fn write(&self, buf: *const [u8]) -> Future<Result<usize, CharDeviceError>> {
+        self.internal.lock().write(&*buf);
+        Future::done(Ok(buf.len()))
+    }
+
+    fn control(&self, cmd: u32, _arg: usize) -> Result<usize, CharDeviceError> {
+        match cmd {
+            chardev::CTRL_FLUSH => {
+                let flushed = unsafe { self.internal.lock().flush() };
 
-        Future::done(Ok(bytes.len()))
+                flushed.unwrap_blocking().map_err(|()| CharDeviceError::IoError)?;
+                Ok(0)
+            },
+            chardev::CTRL_DISCARD_INPUT => {
+                self.internal.lock().rx_buf.clear();
+                Ok(0)
+            },
+            _ => Err(CharDeviceError::Unsupported),
+        }
     }
 }
 
+static SERIAL0: OneShotManualInit<DeviceRef<SerialPort>> = OneShotManualInit::uninit();
+
 pub unsafe fn init() -> DeviceRef<SerialPort> {
-    let mut port = uart_16550::SerialPort::new(0x3f8);
+    let mut port = uart_16550::SerialPort::new(COM1_BASE);
     port.init();
 
-    dev::device_root()
+    // There's no way to ask what is connected to the other end of the port, so assume it's an ANSI terminal emulator of unknown size
+    // unless told otherwise
+    let mut capabilities = TtyCapabilities::empty();
+    let size = options::get().get::<&str>("serial_size").and_then(|size| {
+        let size = size
+            .split_once('x')
+            .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?)))
+            .filter(|&(cols, rows)| cols != 0 && rows != 0);
+
+        if size.is_none() {
+            options::get().warn_invalid_once("serial_size");
+        }
+
+        size
+    });
+
+    if options::get().get_flag("serial_ansi").unwrap_or(true) {
+        capabilities |= TtyCapabilities::CURSOR;
+
+        if options::get().get_flag("serial_color").unwrap_or(true) {
+            capabilities |= TtyCapabilities::COLOR;
+        }
+    }
+
+    if size.is_some() {
+        capabilities |= TtyCapabilities::SIZE;
+    }
+
+    let dev = dev::device_root()
         .dev()
         .add_device(DeviceNode::new(Box::from("serial0"), SerialPort {
-            port: UninterruptibleSpinlock::new(port),
-        }))
+            internal: UninterruptibleSpinlock::new(SerialPortInternals {
+                base: COM1_BASE,
+                irq_enabled: false,
+                ier: 0,
+                tx_buf: ArrayDeque::new(),
+                rx_buf: ArrayDeque::new(),
+                reads: VecDeque::new(),
+                flushes: VecDeque::new(),
+                stats: SerialStats {
+                    bytes_sent: 0,
+                    bytes_received: 0,
+                    rx_dropped: 0,
+                    tx_stalls: 0,
+                    line_errors: 0,
+                },
+            }),
+            capabilities,
+            size,
+        }));
+
+    // Interrupts can't be handled yet, so make sure the UART doesn't raise any until enable_irq is called
+    dev.dev().internal.lock().write_reg(REG_IER, 0);
+
+    SERIAL0.set(dev.clone());
+    dev
+}
+
+/// Switches the serial port from polling to being driven by its IRQ. This must be called once the interrupt controller has been set up.
+pub unsafe fn enable_irq() {
+    let dev = SERIAL0.get();
+    let domain = RecoveryDomain::new("serial0");
+    let dev_for_interrupt = dev.clone();
+
+    domain.attach(DeviceRef::downgrade(dev));
+    dev.register_irq(
+        COM1_IRQ as usize,
+        recovery::wrap_irq_handler(
+            domain,
+            Box::new(move |_| {
+                if dev_for_interrupt.dev().internal.lock().handle_interrupt() {
+                    dev_for_interrupt.record_error();
+                }
+            }),
+        ),
+    );
+
+    {
+        let mut internal = dev.dev().internal.lock();
+
+        internal.irq_enabled = true;
+        internal.set_ier(IER_RX_AVAILABLE | IER_LINE_STATUS);
+    }
+
+    pic::set_irq_masked(COM1_IRQ, false);
+    shutdown::register_hook(ShutdownStage::Devices, "serial0", Box::new(|_| flush_polled()));
+}
+
+/// Synchronously sends any data still waiting in the transmit buffer. This must be called before the kernel stops handling interrupts
+/// for good (e.g. when it panics or exits the emulator after running tests), since the IRQ that would normally send the data will never
+/// arrive.
+pub fn flush_polled() {
+    let Some(dev) = SERIAL0.try_get() else {
+        return;
+    };
+
+    // If the lock is held, the panic happened while writing to the serial port and its state can't be trusted
+    if let Some(mut internal) = dev.dev().internal.try_lock() {
+        unsafe {
+            internal.set_ier(0);
+            internal.drain_polled();
+        }
+    }
 }
@@ -7,7 +7,7 @@ use super::super::page::PhysMemPtr;
 use crate::arch::{page, PhysAddr};
 use crate::io::ansi::AnsiColor;
 use crate::io::dev::Device;
-use crate::io::vt::{TerminalDisplay, VTChar, VirtualTerminalInternals};
+use crate::io::vt::{DisplayPowerError, DisplayPowerState, TerminalDisplay, VTChar, VirtualTerminalInternals};
 use crate::sync::uninterruptible::UninterruptibleSpinlockGuard;
 use crate::sync::UninterruptibleSpinlock;
 
@@ -72,17 +72,34 @@ struct ScreenChar {
     color: ColorCode,
 }
 
+const VGA_SEQ_INDEX: u16 = 0x3c4;
+const VGA_SEQ_DATA: u16 = 0x3c5;
+const VGA_CRTC_INDEX: u16 = 0x3d4;
+const VGA_CRTC_DATA: u16 = 0x3d5;
+
+const VGA_SEQ_CLOCKING_MODE: u8 = 0x01;
+const VGA_SEQ_CLOCKING_MODE_SCREEN_DISABLE: u8 = 0x20;
+
+const VGA_CRTC_MODE_CONTROL: u8 = 0x17;
+const VGA_CRTC_MODE_CONTROL_SYNC_ENABLE: u8 = 0x80;
+
 #[derive(Debug)]
 pub struct VgaTextBuffer {
     buf: PhysMemPtr<[ScreenChar]>,
     width: usize,
     height: usize,
+    power_state: DisplayPowerState,
 }
 
 impl VgaTextBuffer {
     unsafe fn new(buf: PhysMemPtr<[ScreenChar]>, width: usize, height: usize) -> VgaTextBuffer {
         assert_eq!(width.checked_mul(height), Some(buf.ptr().len()));
-        VgaTextBuffer { buf, width, height }
+        VgaTextBuffer {
+            buf,
+            width,
+            height,
+            power_state: DisplayPowerState::On,
+        }
     }
 
     pub unsafe fn for_primary_display() -> VgaTextBuffer {
@@ -133,8 +150,8 @@ impl VgaTextBuffer {
     }
 
     fn move_cursor_internal(&mut self, pos: usize) {
-        let mut index_reg: Port<u8> = Port::new(0x3d4);
-        let mut data_reg: Port<u8> = Port::new(0x3d5);
+        let mut index_reg: Port<u8> = Port::new(VGA_CRTC_INDEX);
+        let mut data_reg: Port<u8> = Port::new(VGA_CRTC_DATA);
 
         unsafe {
             index_reg.write(0x0f);
@@ -155,6 +172,43 @@ impl VgaTextBuffer {
     pub fn hide_cursor(&mut self) {
         self.move_cursor_internal(self.width * self.height);
     }
+
+    unsafe fn update_register(index_port: u16, data_port: u16, index: u8, mask: u8, set: bool) {
+        let mut index_reg: Port<u8> = Port::new(index_port);
+        let mut data_reg: Port<u8> = Port::new(data_port);
+
+        index_reg.write(index);
+
+        let val = data_reg.read();
+        data_reg.write(if set { val | mask } else { val & !mask });
+    }
+
+    pub fn power_state(&self) -> DisplayPowerState {
+        self.power_state
+    }
+
+    /// Changes the power state of the display. In standby, the VGA sequencer stops fetching characters so that the screen goes blank. When
+    /// off, the horizontal and vertical sync signals are also stopped, which tells an attached monitor that it can power down.
+    pub fn set_power_state(&mut self, state: DisplayPowerState) {
+        unsafe {
+            Self::update_register(
+                VGA_SEQ_INDEX,
+                VGA_SEQ_DATA,
+                VGA_SEQ_CLOCKING_MODE,
+                VGA_SEQ_CLOCKING_MODE_SCREEN_DISABLE,
+                state != DisplayPowerState::On,
+            );
+            Self::update_register(
+                VGA_CRTC_INDEX,
+                VGA_CRTC_DATA,
+                VGA_CRTC_MODE_CONTROL,
+                VGA_CRTC_MODE_CONTROL_SYNC_ENABLE,
+                state != DisplayPowerState::Off,
+            );
+        }
+
+        self.power_state = state;
+    }
 }
 
 unsafe impl Send for VgaTextBuffer {}
@@ -213,6 +267,15 @@ impl TerminalDisplay for VgaTextBufferDevice {
             internal.move_cursor(vt.cursor_pos.0, vt.cursor_pos.1);
         };
     }
+
+    fn power_state(&self) -> DisplayPowerState {
+        self.internal.lock().power_state()
+    }
+
+    fn set_power_state(&self, state: DisplayPowerState) -> Result<(), DisplayPowerError> {
+        self.internal.lock().set_power_state(state);
+        Ok(())
+    }
 }
 
 #[dyn_dyn_impl(TerminalDisplay)]
@@ -1,4 +1,11 @@
+use core::cell::SyncUnsafeCell;
+
+use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::util::{OneShotManualInit, PageAligned};
 
 struct GdtConst {
     gdt: GlobalDescriptorTable,
@@ -27,7 +34,21 @@ impl GdtConst {
     }
 }
 
-static GDT: GlobalDescriptorTable = GdtConst::new().gdt;
+/// The interrupt stack table slot (1-based, as used in interrupt descriptors) holding the stack used to handle double faults.
+///
+/// Double faults need to run on a known-good stack, since the most common reason for one to occur in the kernel is a page fault caused by
+/// overflowing a kernel stack into its guard page, in which case the processor is unable to push the page fault's interrupt frame.
+pub const DOUBLE_FAULT_IST: u16 = 1;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4 * 4096;
+
+static DOUBLE_FAULT_STACK: PageAligned<SyncUnsafeCell<[u8; DOUBLE_FAULT_STACK_SIZE]>> =
+    PageAligned::new(SyncUnsafeCell::new([0; DOUBLE_FAULT_STACK_SIZE]));
+
+crate::static_footprint!("arch", DOUBLE_FAULT_STACK);
+
+static TSS: OneShotManualInit<TaskStateSegment> = OneShotManualInit::uninit();
+static GDT: OneShotManualInit<GlobalDescriptorTable> = OneShotManualInit::uninit();
 
 pub const KERNEL_CS: SegmentSelector = GdtConst::new().kernel_cs;
 pub const KERNEL_DS: SegmentSelector = GdtConst::new().kernel_ds;
@@ -35,5 +56,17 @@ pub const USER_CS: SegmentSelector = GdtConst::new().user_cs;
 pub const USER_DS: SegmentSelector = GdtConst::new().user_ds;
 
 pub(super) unsafe fn init() {
-    GDT.load();
+    let mut tss = TaskStateSegment::new();
+
+    tss.interrupt_stack_table[usize::from(DOUBLE_FAULT_IST - 1)] =
+        VirtAddr::from_ptr(DOUBLE_FAULT_STACK.get()) + DOUBLE_FAULT_STACK_SIZE as u64;
+
+    let tss = TSS.set(tss);
+
+    // The TSS descriptor can't be created in a const context, so it's added after all other segments to avoid changing their selectors
+    let mut gdt = GdtConst::new().gdt;
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    GDT.set(gdt).load();
+    load_tss(tss_selector);
 }
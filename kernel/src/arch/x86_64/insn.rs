@@ -0,0 +1,297 @@
+//! A minimal x86_64 length disassembler.
+//!
+//! This only determines how long each instruction is without decoding what the instruction actually does, which is enough to split a
+//! range of machine code into instructions for display.
+
+/// The maximum length of a single instruction in bytes.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Imm {
+    None,
+    Imm8,
+    Imm16,
+    /// A 16-bit immediate if the operand size is overridden, otherwise a 32-bit immediate.
+    ImmZ,
+    /// A 64-bit immediate if REX.W is set, otherwise the same as [`Imm::ImmZ`].
+    ImmV,
+    /// An immediate as wide as an address, used by the `mov` forms with an absolute memory offset.
+    MOffs,
+    /// A 16-bit immediate followed by an 8-bit immediate, used by `enter`.
+    Enter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpcodeMap {
+    Primary,
+    Map0F,
+    Map0F38,
+    Map0F3A,
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        if self.pos + n <= self.bytes.len() {
+            self.pos += n;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Skips over a ModRM byte along with any SIB byte and displacement that it requires, returning the ModRM byte.
+    fn modrm(&mut self) -> Option<u8> {
+        let modrm = self.byte()?;
+        let md = modrm >> 6;
+        let rm = modrm & 0x7;
+
+        if md == 3 {
+            return Some(modrm);
+        }
+
+        let base = if rm == 4 { self.byte()? & 0x7 } else { rm };
+
+        match md {
+            0 if rm == 5 || base == 5 => self.skip(4)?,
+            0 => {},
+            1 => self.skip(1)?,
+            _ => self.skip(4)?,
+        }
+
+        Some(modrm)
+    }
+}
+
+fn primary_imm(opcode: u8, modrm_reg: u8) -> Imm {
+    match opcode {
+        0x00..=0x3f => match opcode & 0x7 {
+            4 => Imm::Imm8,
+            5 => Imm::ImmZ,
+            _ => Imm::None,
+        },
+        0x68 | 0x69 | 0x81 | 0xa9 | 0xc7 => Imm::ImmZ,
+        0x6a | 0x6b | 0x70..=0x7f | 0x80 | 0x83 | 0xa8 | 0xb0..=0xb7 | 0xc0 | 0xc1 | 0xc6 | 0xcd | 0xe0..=0xe7 | 0xeb => Imm::Imm8,
+        0xa0..=0xa3 => Imm::MOffs,
+        0xb8..=0xbf => Imm::ImmV,
+        0xc2 | 0xca => Imm::Imm16,
+        0xc8 => Imm::Enter,
+        // Relative call and jmp always use a 32-bit displacement in 64-bit mode
+        0xe8 | 0xe9 => Imm::ImmZ,
+        0xf6 if modrm_reg < 2 => Imm::Imm8,
+        0xf7 if modrm_reg < 2 => Imm::ImmZ,
+        _ => Imm::None,
+    }
+}
+
+fn primary_has_modrm(opcode: u8) -> bool {
+    match opcode {
+        0x00..=0x3f => opcode & 0x7 < 4,
+        0x63 | 0x69 | 0x6b | 0x80..=0x8f | 0xc0 | 0xc1 | 0xc6 | 0xc7 | 0xd0..=0xd3 | 0xd8..=0xdf | 0xf6 | 0xf7 | 0xfe | 0xff => true,
+        _ => false,
+    }
+}
+
+fn primary_is_invalid(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        0x06 | 0x07 | 0x0e | 0x16 | 0x17 | 0x1e | 0x1f | 0x27 | 0x2f | 0x37 | 0x3f | 0x60 | 0x61 | 0x82 | 0x9a | 0xce | 0xd4..=0xd6 | 0xea
+    )
+}
+
+fn map_0f_has_modrm(opcode: u8) -> bool {
+    !matches!(
+        opcode,
+        0x04..=0x0c | 0x0e | 0x30..=0x37 | 0x77 | 0x80..=0x8f | 0xa0..=0xa2 | 0xa8..=0xaa | 0xc8..=0xcf
+    )
+}
+
+fn map_0f_imm(opcode: u8) -> Imm {
+    match opcode {
+        0x0f | 0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => Imm::Imm8,
+        0x80..=0x8f => Imm::ImmZ,
+        _ => Imm::None,
+    }
+}
+
+fn map_0f_is_invalid(opcode: u8) -> bool {
+    matches!(opcode, 0x04 | 0x0a | 0x0c | 0x24..=0x27 | 0x36 | 0x39 | 0x3b..=0x3f | 0xa6 | 0xa7)
+}
+
+/// Decodes the length of the instruction at the start of the provided bytes. Returns [`None`] if the bytes do not start with a valid
+/// instruction, or if the instruction would extend past the end of the provided bytes.
+pub fn instruction_len(bytes: &[u8]) -> Option<usize> {
+    let mut d = Decoder {
+        bytes: &bytes[..bytes.len().min(MAX_INSTRUCTION_LEN)],
+        pos: 0,
+    };
+
+    let mut opsize_override = false;
+    let mut addrsize_override = false;
+    let mut rex_w = false;
+
+    loop {
+        match d.peek()? {
+            0x66 => opsize_override = true,
+            0x67 => addrsize_override = true,
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 | 0xf2 | 0xf3 => {},
+            _ => break,
+        }
+
+        d.pos += 1;
+    }
+
+    if let 0x40..=0x4f = d.peek()? {
+        rex_w = d.byte()? & 0x8 != 0;
+    }
+
+    let opcode = d.byte()?;
+
+    let (map, opcode) = match opcode {
+        0xc4 | 0xc5 | 0x62 => {
+            // VEX and EVEX prefixes encode the opcode map and always have a ModRM byte, except for vzeroupper and vzeroall
+            let map = if opcode == 0xc5 {
+                d.byte()?;
+                OpcodeMap::Map0F
+            } else {
+                let payload = d.byte()?;
+
+                d.skip(if opcode == 0x62 { 2 } else { 1 })?;
+
+                match payload & if opcode == 0x62 { 0x3 } else { 0x1f } {
+                    1 => OpcodeMap::Map0F,
+                    2 => OpcodeMap::Map0F38,
+                    3 => OpcodeMap::Map0F3A,
+                    _ => return None,
+                }
+            };
+            let opcode = d.byte()?;
+
+            if !(map == OpcodeMap::Map0F && opcode == 0x77) {
+                d.modrm()?;
+            }
+
+            if map == OpcodeMap::Map0F3A || (map == OpcodeMap::Map0F && map_0f_imm(opcode) == Imm::Imm8) {
+                d.skip(1)?;
+            }
+
+            return Some(d.pos);
+        },
+        0x0f => match d.byte()? {
+            0x38 => (OpcodeMap::Map0F38, d.byte()?),
+            0x3a => (OpcodeMap::Map0F3A, d.byte()?),
+            opcode => (OpcodeMap::Map0F, opcode),
+        },
+        opcode => (OpcodeMap::Primary, opcode),
+    };
+
+    let imm = match map {
+        OpcodeMap::Primary => {
+            if primary_is_invalid(opcode) {
+                return None;
+            }
+
+            let modrm_reg = if primary_has_modrm(opcode) { (d.modrm()? >> 3) & 0x7 } else { 0 };
+
+            primary_imm(opcode, modrm_reg)
+        },
+        OpcodeMap::Map0F => {
+            if map_0f_is_invalid(opcode) {
+                return None;
+            }
+
+            if map_0f_has_modrm(opcode) {
+                d.modrm()?;
+            }
+
+            map_0f_imm(opcode)
+        },
+        OpcodeMap::Map0F38 => {
+            d.modrm()?;
+            Imm::None
+        },
+        OpcodeMap::Map0F3A => {
+            d.modrm()?;
+            Imm::Imm8
+        },
+    };
+
+    let imm_z = if opsize_override && !rex_w { 2 } else { 4 };
+
+    d.skip(match imm {
+        Imm::None => 0,
+        Imm::Imm8 => 1,
+        Imm::Imm16 => 2,
+        Imm::ImmZ if map == OpcodeMap::Map0F || matches!(opcode, 0xe8 | 0xe9) => 4,
+        Imm::ImmZ => imm_z,
+        Imm::ImmV if rex_w => 8,
+        Imm::ImmV => imm_z,
+        Imm::MOffs if addrsize_override => 4,
+        Imm::MOffs => 8,
+        Imm::Enter => 3,
+    })?;
+
+    Some(d.pos)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_instruction_len() {
+        // nop
+        assert_eq!(Some(1), instruction_len(&[0x90]));
+        // mov rbp, rsp
+        assert_eq!(Some(3), instruction_len(&[0x48, 0x89, 0xe5]));
+        // mov eax, [rsp + 8]
+        assert_eq!(Some(4), instruction_len(&[0x8b, 0x44, 0x24, 0x08]));
+        // mov rax, [rip + 0x1000]
+        assert_eq!(Some(7), instruction_len(&[0x48, 0x8b, 0x05, 0x00, 0x10, 0x00, 0x00]));
+        // movabs rax, 0x1122334455667788
+        assert_eq!(
+            Some(10),
+            instruction_len(&[0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11])
+        );
+        // call rel32
+        assert_eq!(Some(5), instruction_len(&[0xe8, 0x00, 0x00, 0x00, 0x00]));
+        // test eax, 1 and not eax
+        assert_eq!(Some(6), instruction_len(&[0xf7, 0xc0, 0x01, 0x00, 0x00, 0x00]));
+        assert_eq!(Some(2), instruction_len(&[0xf7, 0xd0]));
+        // mov ax, 1
+        assert_eq!(Some(4), instruction_len(&[0x66, 0xb8, 0x01, 0x00]));
+        // syscall and ud2
+        assert_eq!(Some(2), instruction_len(&[0x0f, 0x05]));
+        assert_eq!(Some(2), instruction_len(&[0x0f, 0x0b]));
+        // jne rel32
+        assert_eq!(Some(6), instruction_len(&[0x0f, 0x85, 0x00, 0x00, 0x00, 0x00]));
+        // palignr xmm0, xmm1, 8
+        assert_eq!(Some(6), instruction_len(&[0x66, 0x0f, 0x3a, 0x0f, 0xc1, 0x08]));
+        // vzeroupper and vpxor ymm0, ymm0, ymm0
+        assert_eq!(Some(3), instruction_len(&[0xc5, 0xf8, 0x77]));
+        assert_eq!(Some(4), instruction_len(&[0xc5, 0xfd, 0xef, 0xc0]));
+    }
+
+    #[test_case]
+    fn test_instruction_len_invalid() {
+        assert_eq!(None, instruction_len(&[]));
+        assert_eq!(None, instruction_len(&[0x06]));
+        assert_eq!(None, instruction_len(&[0xe8, 0x00]));
+        assert_eq!(None, instruction_len(&[0x66; 16]));
+    }
+}
@@ -1,13 +1,17 @@
 use alloc::boxed::Box;
 use core::arch::asm;
-use core::mem;
+use core::cell::SyncUnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use x86_64::instructions::tables::lidt;
 use x86_64::structures::DescriptorTablePointer;
 use x86_64::{PrivilegeLevel, VirtAddr};
 
 use super::regs::{GeneralRegister, SavedBasicRegisters};
-use crate::log;
+use super::tls::TlsBlock;
+use crate::mem::fault::{PageFault, PageFaultFlags};
+use crate::sync::level::InterruptLevel;
 use crate::sync::uninterruptible::InterruptDisabler;
 use crate::sync::UninterruptibleSpinlock;
 use crate::util::OneShotManualInit;
@@ -146,22 +150,95 @@ pub const EXT_START: u8 = 0x30;
 
 pub const NUM_IRQS: usize = (EXT_START - IRQS_START) as usize;
 
+/// The interrupt vector used for inter-processor interrupts asking a core to run its queued cross-core function calls.
+pub const CALL_IPI_VECTOR: u8 = 0x31;
+
+/// The interrupt vector that the local APIC reports spurious interrupts on. These need no handling and must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
 pub type InterruptHandler = Box<dyn Fn(&mut InterruptFrame) + Send + Sync>;
 const EMPTY_INTERRUPT: Option<InterruptHandler> = None;
 
 static IRQ_HANDLERS: UninterruptibleSpinlock<[Option<InterruptHandler>; NUM_IRQS]> =
     UninterruptibleSpinlock::new([EMPTY_INTERRUPT; NUM_IRQS]);
 
+crate::static_footprint!("arch", IRQ_HANDLERS);
+
+static IRQ_COUNTS: [AtomicU64; NUM_IRQS] = [const { AtomicU64::new(0) }; NUM_IRQS];
+
+const NMI_VECTOR: u8 = 2;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+const PAGE_FAULT_VECTOR: u8 = 14;
+
+/// Checks whether a page fault or double fault was caused by the interrupted thread overflowing its kernel-mode stack, and if so, panics
+/// with a message saying as much rather than reporting it as a generic unhandled exception.
+fn check_stack_overflow(interrupt_num: u8, frame: &InterruptFrame) {
+    use crate::sched::task::Thread;
+
+    let fault_addr = x86_64::registers::control::Cr2::read();
+
+    if let Some(thread) = Thread::current_interrupted() {
+        if thread.is_stack_guard_page(fault_addr) {
+            panic!(
+                "Kernel stack overflow in thread {} (fault address {:#x}, rip {:#x}, rsp {:#x}, exception {})",
+                thread.debug_name(),
+                fault_addr,
+                frame.rip,
+                frame.rsp,
+                interrupt_num
+            );
+        }
+    }
+}
+
+/// Decodes the error code pushed by the processor for a page fault.
+fn page_fault_flags(error_code: u64) -> PageFaultFlags {
+    let mut flags = PageFaultFlags::empty();
+
+    flags.set(PageFaultFlags::PRESENT, error_code & 0x01 != 0);
+    flags.set(PageFaultFlags::WRITE, error_code & 0x02 != 0);
+    flags.set(PageFaultFlags::USER, error_code & 0x04 != 0);
+    flags.set(PageFaultFlags::RESERVED, error_code & 0x08 != 0);
+    flags.set(PageFaultFlags::INSTRUCTION, error_code & 0x10 != 0);
+
+    flags
+}
+
+/// Attempts to resolve a page fault, panicking with a description of the faulting access if it was not valid.
+fn handle_page_fault(frame: &InterruptFrame) {
+    let fault = PageFault {
+        addr: x86_64::registers::control::Cr2::read(),
+        flags: page_fault_flags(frame.error_code),
+    };
+
+    if !crate::mem::fault::handle_page_fault(&fault) {
+        panic!("Unhandled page fault: {} (rip {:#x}, rsp {:#x})", fault, frame.rip, frame.rsp);
+    }
+}
+
+fn is_handled_exception(interrupt_num: u8) -> bool {
+    matches!(interrupt_num, super::mce::MACHINE_CHECK_VECTOR)
+}
+
 unsafe extern "C" fn handle_interrupt(frame: &mut InterruptFrame) {
     use crate::sched;
 
-    // TODO Load correct FS_BASE based on processor for SMP
-    x86_64::registers::model_specific::Msr::new(0xc0000100).write(*super::KERNEL_FS_BASE.get());
+    if frame.interrupt_num as u8 == NMI_VECTOR && STOP_REQUESTED.load(Ordering::Acquire) {
+        park_stopped_cpu(frame);
+    }
+
+    super::tls::load_cpu_base();
     sched::begin_interrupt();
 
     let interrupt_num = frame.interrupt_num as u8;
+    let mut exception_handled = is_handled_exception(interrupt_num);
 
     if (IRQS_START..EXT_START).contains(&interrupt_num) {
+        if super::unhandled::check_spurious_irq(interrupt_num - IRQS_START) {
+            sched::end_interrupt(frame);
+            return;
+        }
+
         sched::begin_interrupt();
     };
 
@@ -179,20 +256,43 @@ unsafe extern "C" fn handle_interrupt(frame: &mut InterruptFrame) {
 
             sched::perform_context_switch_interrupt(Some(core::ptr::read(frame.rax as *const sched::task::ThreadLock)), frame);
         },
+        CALL_IPI_VECTOR => {
+            sched::smp::handle_call_ipi();
+            super::lapic::send_eoi();
+        },
+        SPURIOUS_VECTOR => {},
+        super::mce::MACHINE_CHECK_VECTOR => {
+            super::mce::handle_machine_check(frame);
+        },
+        DOUBLE_FAULT_VECTOR => {
+            check_stack_overflow(interrupt_num, frame);
+        },
+        PAGE_FAULT_VECTOR => {
+            check_stack_overflow(interrupt_num, frame);
+            handle_page_fault(frame);
+            exception_handled = true;
+        },
         IRQS_START..EXT_START => {
             let mut handlers = IRQ_HANDLERS.lock();
 
             if let &mut Some(ref mut handler) = &mut handlers[usize::from(interrupt_num - IRQS_START)] {
-                handler(frame);
+                IRQ_COUNTS[usize::from(interrupt_num - IRQS_START)].fetch_add(1, Ordering::Relaxed);
+                sched::replay::record(|| sched::replay::ReplayEvent::Irq(interrupt_num - IRQS_START));
+                crate::io::dev::recovery::run_irq_handler(|| handler(frame));
             } else {
-                log!(Warning, "kernel", "Unhandled irq{}", interrupt_num - IRQS_START);
+                super::unhandled::record_unhandled_irq(interrupt_num - IRQS_START);
             }
         },
-        _ => {},
+        0..IRQS_START => {},
+        _ => {
+            super::unhandled::record_unknown_vector(interrupt_num);
+        },
     }
 
     if interrupt_num < IRQS_START {
-        panic!("Unhandled exception {} (error code {})", interrupt_num, frame.error_code);
+        if !exception_handled {
+            panic!("Unhandled exception {} (error code {})", interrupt_num, frame.error_code);
+        }
     } else if interrupt_num < EXT_START {
         super::pic::send_eoi(interrupt_num - IRQS_START);
     }
@@ -250,7 +350,9 @@ handler_without_code!(begin_irq14, 46);
 handler_without_code!(begin_irq15, 47);
 
 handler_without_code!(begin_int30, 0x30);
+handler_without_code!(begin_int31, 0x31);
 handler_without_code!(begin_int80, 0x80);
+handler_without_code!(begin_intff, 0xff);
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -350,8 +452,13 @@ impl InterruptFrame {
         self.rip = super::idle as u64;
     }
 
-    pub fn setup_kernel_mode_thread_locals(&mut self) {
-        self.fsbase = unsafe { x86_64::registers::model_specific::Msr::new(0xc0000100).read() };
+    /// Sets up the FS and GS bases to be restored for a kernel-mode thread, using the provided TLS block or the current core's TLS block if
+    /// the thread does not have its own.
+    pub fn setup_kernel_mode_thread_locals(&mut self, tls: Option<&TlsBlock>) {
+        let cpu_base = super::tls::cpu_base();
+
+        self.fsbase = tls.map_or(cpu_base, TlsBlock::base);
+        self.gsbase = cpu_base;
     }
 }
 
@@ -367,6 +474,105 @@ pub fn disable() {
     x86_64::instructions::interrupts::disable();
 }
 
+/// The maximum number of stopped CPU cores whose state is saved by [`stop_other_cpus`]. Cores beyond this are still stopped, but their
+/// state is not shown.
+const MAX_STOPPED_CPUS: usize = 64;
+
+/// How many times [`stop_other_cpus`] polls for other cores to stop before giving up on any that have not responded.
+const STOP_WAIT_SPINS: u32 = 100_000_000;
+
+/// The state a CPU core was interrupted in when it was stopped by [`stop_other_cpus`].
+#[derive(Debug, Clone)]
+pub struct StoppedCpu {
+    pub hw_id: u32,
+    pub regs: SavedBasicRegisters,
+}
+
+struct StoppedCpuSlot {
+    ready: AtomicBool,
+    cpu: SyncUnsafeCell<MaybeUninit<StoppedCpu>>,
+}
+
+const EMPTY_STOPPED_CPU_SLOT: StoppedCpuSlot = StoppedCpuSlot {
+    ready: AtomicBool::new(false),
+    cpu: SyncUnsafeCell::new(MaybeUninit::uninit()),
+};
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static NEXT_STOPPED_CPU_SLOT: AtomicUsize = AtomicUsize::new(0);
+static NUM_STOPPED_CPUS: AtomicUsize = AtomicUsize::new(0);
+static STOPPED_CPUS: [StoppedCpuSlot; MAX_STOPPED_CPUS] = [EMPTY_STOPPED_CPU_SLOT; MAX_STOPPED_CPUS];
+
+/// Saves the state of the interrupted context into the next free slot in [`STOPPED_CPUS`] and halts this core forever. This runs in NMI
+/// context, so it must not take any locks.
+fn park_stopped_cpu(frame: &InterruptFrame) -> ! {
+    let slot = NEXT_STOPPED_CPU_SLOT.fetch_add(1, Ordering::Relaxed);
+
+    if let Some(slot) = STOPPED_CPUS.get(slot) {
+        let mut regs = SavedBasicRegisters::new();
+        frame.save(&mut regs);
+
+        // SAFETY: Each slot is handed out to exactly one core by NEXT_STOPPED_CPU_SLOT, and is not read until ready is set below
+        unsafe {
+            (*slot.cpu.get()).write(StoppedCpu {
+                hw_id: super::topology::current_hw_id(),
+                regs,
+            });
+        }
+        slot.ready.store(true, Ordering::Release);
+    }
+
+    NUM_STOPPED_CPUS.fetch_add(1, Ordering::Release);
+
+    loop {
+        disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Stops all other CPU cores so that they cannot modify any shared state while the current core is handling a panic. Returns the number of
+/// cores that were stopped. The state each stopped core was interrupted in can then be retrieved using [`stopped_cpus`].
+///
+/// Other cores are sent a non-maskable interrupt, so even a core spinning on a lock with interrupts disabled will be stopped. Cores that do
+/// not respond within a bounded amount of time are not waited for. If another core has already started stopping cores (e.g. because two
+/// cores panicked at the same time), this returns without waiting, since this core is about to be stopped as well.
+pub fn stop_other_cpus() -> usize {
+    let num_others = crate::sched::smp::num_online_cpus().saturating_sub(1);
+
+    if num_others == 0 || STOP_REQUESTED.swap(true, Ordering::AcqRel) {
+        return NUM_STOPPED_CPUS.load(Ordering::Acquire);
+    }
+
+    if !super::lapic::send_nmi_to_others() {
+        return 0;
+    }
+
+    for _ in 0..STOP_WAIT_SPINS {
+        if NUM_STOPPED_CPUS.load(Ordering::Acquire) >= num_others {
+            break;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    NUM_STOPPED_CPUS.load(Ordering::Acquire)
+}
+
+/// Gets the saved state of every CPU core that has been stopped by [`stop_other_cpus`].
+pub fn stopped_cpus() -> impl Iterator<Item = &'static StoppedCpu> {
+    STOPPED_CPUS
+        .iter()
+        .filter(|slot| slot.ready.load(Ordering::Acquire))
+        // SAFETY: The slot was fully written before ready was set, and is never written again afterwards
+        .map(|slot| unsafe { (*slot.cpu.get()).assume_init_ref() })
+}
+
+/// Sends an inter-processor interrupt to the logical processor with the provided hardware ID asking it to run its queued cross-core function
+/// calls. Returns whether the interrupt was sent.
+pub fn send_call_ipi(hw_id: u32) -> bool {
+    super::lapic::send_fixed_ipi(hw_id, CALL_IPI_VECTOR)
+}
+
 #[repr(C)]
 struct InterruptTableEntry {
     offset_0: u16,
@@ -475,6 +681,7 @@ pub unsafe fn register_irq(n: usize, handler: InterruptHandler) {
 
     assert!(handlers[n].is_none());
     handlers[n] = Some(handler);
+    super::unhandled::reset_irq(n as u8);
 }
 
 pub unsafe fn unregister_irq(n: usize) {
@@ -484,6 +691,40 @@ pub unsafe fn unregister_irq(n: usize) {
     handlers[n] = None;
 }
 
+/// Sets the interrupt level of an IRQ, which is [`InterruptLevel::Device`] until this is called.
+///
+/// IRQs are held off by masking them in the 8259 PICs, which is done for every core at once since the PICs only deliver IRQs to a single
+/// core anyway. The local APIC's task priority register can't be used for this yet: IRQs from the PICs reach the local APIC as ExtINT
+/// interrupts, which the TPR does not filter, and their vectors all share a single priority class. Levels can move to the TPR once IRQs are
+/// routed through an I/O APIC using a vector range for each level.
+///
+/// # Panics
+///
+/// This function will panic if the provided level is [`InterruptLevel::Passive`].
+pub fn set_irq_level(n: usize, level: InterruptLevel) {
+    // SAFETY: Changing the level of an IRQ only changes when it is masked, and it is still delivered once it is unmasked again
+    unsafe {
+        super::pic::set_irq_level(n as u8, level);
+    }
+}
+
+/// Updates the interrupt controller after the interrupt level of the current core changed.
+///
+/// This updates the masks of the 8259 PICs rather than the local APIC's task priority register, for the reasons given in
+/// [`set_irq_level`].
+///
+/// # Safety
+///
+/// This should only be called by [`crate::sync::level`] when changing the current interrupt level.
+pub unsafe fn change_level(old_level: InterruptLevel, new_level: InterruptLevel) {
+    super::pic::change_level(old_level, new_level);
+}
+
+/// Gets the number of times that an IRQ has been delivered to a registered handler since boot.
+pub fn irq_count(n: usize) -> u64 {
+    IRQ_COUNTS[n].load(Ordering::Relaxed)
+}
+
 pub(super) unsafe fn init_bsp() {
     let mut idt = InterruptTable::new();
     let handlers = [
@@ -541,18 +782,32 @@ pub(super) unsafe fn init_bsp() {
         idt.entries[i] = InterruptTableEntry::new(InterruptTableEntry::OPTION_TYPE_INTERRUPT_GATE, PrivilegeLevel::Ring0, 0, Some(f));
     }
 
+    idt.entries[usize::from(DOUBLE_FAULT_VECTOR)].set_ist(super::gdt::DOUBLE_FAULT_IST);
+
     idt.entries[0x30] = InterruptTableEntry::new(
         InterruptTableEntry::OPTION_TYPE_TRAP_GATE,
         PrivilegeLevel::Ring0,
         0,
         Some(begin_int30),
     );
+    idt.entries[usize::from(CALL_IPI_VECTOR)] = InterruptTableEntry::new(
+        InterruptTableEntry::OPTION_TYPE_INTERRUPT_GATE,
+        PrivilegeLevel::Ring0,
+        0,
+        Some(begin_int31),
+    );
     idt.entries[0x80] = InterruptTableEntry::new(
         InterruptTableEntry::OPTION_TYPE_TRAP_GATE,
         PrivilegeLevel::Ring3,
         0,
         Some(begin_int80),
     );
+    idt.entries[usize::from(SPURIOUS_VECTOR)] = InterruptTableEntry::new(
+        InterruptTableEntry::OPTION_TYPE_INTERRUPT_GATE,
+        PrivilegeLevel::Ring0,
+        0,
+        Some(begin_intff),
+    );
 
     let idt = IDT.set(idt);
 
@@ -0,0 +1,160 @@
+//! Minimal support for sending inter-processor interrupts through the local APIC.
+//!
+//! Device interrupts are still delivered through the legacy PIC, so the local APIC is only used to send interrupts to other cores and to
+//! receive the ones they send. Its registers are accessed through MMIO in xAPIC mode or through MSRs in x2APIC mode, depending on which mode
+//! firmware left it in.
+
+use x86_64::registers::model_specific::Msr;
+
+use super::cpuid::{self, CpuFeature};
+use super::interrupt;
+use crate::arch::PhysAddr;
+use crate::log;
+use crate::mem::virt::{self, MmioMapping};
+use crate::util::OneShotManualInit;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_X2APIC_BASE: u32 = 0x800;
+
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const XAPIC_MMIO_SIZE: usize = 0x400;
+
+const REG_EOI: usize = 0xb0;
+const REG_SVR: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+const SVR_VECTOR_MASK: u32 = 0xff;
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+
+const ICR_DELIVERY_MODE_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DEST_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const XAPIC_MAX_DEST: u32 = 0xff;
+const MAX_DELIVERY_SPINS: u32 = 1_000_000;
+
+enum LocalApic {
+    XApic(MmioMapping),
+    X2Apic,
+}
+
+static LOCAL_APIC: OneShotManualInit<LocalApic> = OneShotManualInit::uninit();
+
+impl LocalApic {
+    fn x2apic_msr(reg: usize) -> Msr {
+        Msr::new(IA32_X2APIC_BASE + (reg >> 4) as u32)
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        match *self {
+            LocalApic::XApic(ref mmio) => mmio.read::<u32>(reg),
+            // SAFETY: The local APIC was seen to be in x2APIC mode during initialization, so its registers are accessible as MSRs
+            LocalApic::X2Apic => unsafe { Self::x2apic_msr(reg).read() as u32 },
+        }
+    }
+
+    fn write(&self, reg: usize, val: u32) {
+        match *self {
+            LocalApic::XApic(ref mmio) => mmio.write::<u32>(reg, val),
+            // SAFETY: The local APIC was seen to be in x2APIC mode during initialization, so its registers are accessible as MSRs
+            LocalApic::X2Apic => unsafe { Self::x2apic_msr(reg).write(u64::from(val)) },
+        }
+    }
+
+    fn send_ipi(&self, dest: u32, icr: u32) -> bool {
+        match *self {
+            LocalApic::XApic(ref mmio) => {
+                if dest > XAPIC_MAX_DEST {
+                    return false;
+                }
+
+                mmio.write::<u32>(REG_ICR_HIGH, dest << 24);
+                mmio.write::<u32>(REG_ICR_LOW, icr);
+
+                for _ in 0..MAX_DELIVERY_SPINS {
+                    if mmio.read::<u32>(REG_ICR_LOW) & ICR_DELIVERY_PENDING == 0 {
+                        return true;
+                    }
+
+                    core::hint::spin_loop();
+                }
+
+                false
+            },
+            LocalApic::X2Apic => {
+                // SAFETY: The local APIC was seen to be in x2APIC mode during initialization, so the ICR MSR exists. Writes to it in x2APIC
+                //         mode are always delivered, so there is no delivery status to wait on.
+                unsafe {
+                    Self::x2apic_msr(REG_ICR_LOW).write((u64::from(dest) << 32) | u64::from(icr));
+                }
+
+                true
+            },
+        }
+    }
+}
+
+/// Sends a non-maskable interrupt to every other logical processor. Returns whether the interrupt was sent.
+///
+/// This does not take any locks, so it is safe to call from the panic path.
+pub fn send_nmi_to_others() -> bool {
+    let Some(apic) = LOCAL_APIC.try_get() else {
+        return false;
+    };
+
+    apic.send_ipi(0, ICR_DELIVERY_MODE_NMI | ICR_LEVEL_ASSERT | ICR_DEST_ALL_EXCLUDING_SELF)
+}
+
+/// Sends an interrupt with the provided vector to the logical processor with the provided local APIC ID. Returns whether the interrupt was
+/// sent.
+pub fn send_fixed_ipi(apic_id: u32, vector: u8) -> bool {
+    let Some(apic) = LOCAL_APIC.try_get() else {
+        return false;
+    };
+
+    apic.send_ipi(apic_id, ICR_DELIVERY_MODE_FIXED | ICR_LEVEL_ASSERT | u32::from(vector))
+}
+
+/// Signals the end of an interrupt that was delivered through the local APIC, allowing interrupts of the same or lower priority to be
+/// delivered again.
+pub fn send_eoi() {
+    if let Some(apic) = LOCAL_APIC.try_get() {
+        apic.write(REG_EOI, 0);
+    }
+}
+
+pub(super) unsafe fn init_bsp() {
+    if !cpuid::get_minimum_features().supports(CpuFeature::APIC) {
+        log!(Notice, "lapic", "No local APIC is present, so other cores cannot be interrupted");
+        return;
+    }
+
+    let apic_base = Msr::new(IA32_APIC_BASE).read();
+
+    if apic_base & APIC_BASE_ENABLE == 0 {
+        log!(Notice, "lapic", "The local APIC is disabled, so other cores cannot be interrupted");
+        return;
+    }
+
+    let apic = if apic_base & APIC_BASE_X2APIC_ENABLE != 0 {
+        log!(Debug, "lapic", "Using local APIC in x2APIC mode");
+        LOCAL_APIC.set(LocalApic::X2Apic)
+    } else {
+        let phys = PhysAddr::new(apic_base & APIC_BASE_ADDR_MASK);
+        let mmio = virt::map_mmio(phys, XAPIC_MMIO_SIZE).expect("failed to map local APIC registers");
+
+        log!(Debug, "lapic", "Using local APIC in xAPIC mode at {:#x}", phys.as_u64());
+        LOCAL_APIC.set(LocalApic::XApic(mmio))
+    };
+
+    // Firmware may leave the local APIC software-disabled, in which case it can send interrupts but will not accept fixed interrupts from
+    // other cores. Enabling it leaves the LINT0 setup that lets the PIC's interrupts through alone.
+    let svr = (apic.read(REG_SVR) & !SVR_VECTOR_MASK) | u32::from(interrupt::SPURIOUS_VECTOR);
+    apic.write(REG_SVR, svr | SVR_APIC_ENABLE);
+}
@@ -0,0 +1,308 @@
+//! Machine check exception handling.
+//!
+//! When the processor detects a hardware error (e.g. an ECC error in memory or a cache), it records information about the error in a set of
+//! machine check banks. Uncorrected errors are additionally signalled by raising a machine check exception, while corrected errors are only
+//! recorded and must be polled for.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+use super::cpuid::{self, CpuFeature};
+use super::interrupt::InterruptFrame;
+use crate::arch::PhysAddr;
+use crate::log;
+use crate::mem::frame::{self, BadFrameSource};
+use crate::sched::timer;
+
+/// The interrupt vector on which machine check exceptions are raised.
+pub const MACHINE_CHECK_VECTOR: u8 = 18;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT_MASK: u64 = 0xff;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static NUM_BANKS: AtomicUsize = AtomicUsize::new(0);
+
+fn mci_ctl(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32)
+}
+
+fn mci_status(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32 + 1)
+}
+
+fn mci_addr(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32 + 2)
+}
+
+fn mci_misc(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32 + 3)
+}
+
+/// A decoded error record read from one of the processor's machine check banks.
+#[derive(Debug, Clone, Copy)]
+pub struct MachineCheckRecord {
+    pub bank: usize,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl MachineCheckRecord {
+    unsafe fn read(bank: usize) -> Option<MachineCheckRecord> {
+        let status = mci_status(bank).read();
+
+        if status & MCI_STATUS_VAL == 0 {
+            return None;
+        }
+
+        Some(MachineCheckRecord {
+            bank,
+            status,
+            addr: if status & MCI_STATUS_ADDRV != 0 {
+                Some(mci_addr(bank).read())
+            } else {
+                None
+            },
+            misc: if status & MCI_STATUS_MISCV != 0 {
+                Some(mci_misc(bank).read())
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Checks whether this error was not corrected by the hardware.
+    pub fn is_uncorrected(&self) -> bool {
+        self.status & MCI_STATUS_UC != 0
+    }
+
+    /// Checks whether the processor context may have been corrupted by this error, making it impossible to reliably continue execution.
+    pub fn is_context_corrupt(&self) -> bool {
+        self.status & MCI_STATUS_PCC != 0
+    }
+
+    /// Checks whether an earlier error in the same bank was overwritten by this one.
+    pub fn is_overflow(&self) -> bool {
+        self.status & MCI_STATUS_OVER != 0
+    }
+
+    /// Gets the architecturally-defined MCA error code of this error.
+    pub fn mca_error_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// Gets the model-specific error code of this error.
+    pub fn model_error_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    fn error_kind(&self) -> &'static str {
+        let code = self.mca_error_code();
+
+        if code == 0 {
+            "no error"
+        } else if code == 0x0001 {
+            "unclassified"
+        } else if code == 0x0002 {
+            "microcode ROM parity"
+        } else if code == 0x0003 {
+            "external"
+        } else if code == 0x0004 {
+            "FRC"
+        } else if code == 0x0005 {
+            "internal parity"
+        } else if code & 0xfffc == 0x000c {
+            "generic cache hierarchy"
+        } else if code & 0xfff0 == 0x0010 {
+            "TLB"
+        } else if code & 0xff80 == 0x0080 {
+            "memory controller"
+        } else if code & 0xff00 == 0x0100 {
+            "cache hierarchy"
+        } else if code & 0xf800 == 0x0800 {
+            "bus/interconnect"
+        } else if code & 0xfc00 == 0x0400 {
+            "internal"
+        } else {
+            "unknown"
+        }
+    }
+
+    unsafe fn clear(&self) {
+        mci_status(self.bank).write(0);
+    }
+}
+
+impl fmt::Display for MachineCheckRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "bank {}: {} {} error (MCA code {:#06x}, model code {:#06x}, status {:#018x})",
+            self.bank,
+            if self.is_uncorrected() { "uncorrected" } else { "corrected" },
+            self.error_kind(),
+            self.mca_error_code(),
+            self.model_error_code(),
+            self.status
+        )?;
+
+        if self.status & MCI_STATUS_EN == 0 {
+            write!(f, " [signalling disabled]")?;
+        }
+
+        if self.is_overflow() {
+            write!(f, " [overflow]")?;
+        }
+
+        if self.is_context_corrupt() {
+            write!(f, " [context corrupt]")?;
+        }
+
+        if let Some(addr) = self.addr {
+            write!(f, " at address {:#x}", addr)?;
+        }
+
+        if let Some(misc) = self.misc {
+            write!(f, " misc {:#x}", misc)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn report_affected_frame(record: &MachineCheckRecord) {
+    // Only memory controller and cache hierarchy errors are known to report physical addresses in MCi_ADDR. For other error types, the
+    // address (if any) may not refer to a physical memory location at all.
+    let code = record.mca_error_code();
+    let is_memory_error = code & 0xff80 == 0x0080 || code & 0xff00 == 0x0100;
+
+    if let (true, Some(addr)) = (is_memory_error, record.addr) {
+        frame::report_bad_frame(PhysAddr::new(addr), BadFrameSource::MachineCheck);
+    }
+}
+
+/// Reads and clears all valid error records from the machine check banks, calling the provided function for each of them.
+unsafe fn drain_banks(mut f: impl FnMut(&MachineCheckRecord)) {
+    for bank in 0..NUM_BANKS.load(Ordering::Relaxed) {
+        if let Some(record) = MachineCheckRecord::read(bank) {
+            f(&record);
+            record.clear();
+        }
+    }
+}
+
+/// Polls the machine check banks for errors that were corrected by the hardware and logs them.
+pub fn poll_corrected_errors() {
+    unsafe {
+        drain_banks(|record| {
+            if record.is_uncorrected() {
+                log!(Error, "mce", "Found uncorrected machine check error while polling: {}", record);
+            } else {
+                log!(Warning, "mce", "Corrected machine check error: {}", record);
+            }
+
+            report_affected_frame(record);
+        });
+    };
+}
+
+fn schedule_poll() {
+    timer::after(POLL_INTERVAL).when_resolved(|()| {
+        poll_corrected_errors();
+        schedule_poll();
+    });
+}
+
+/// Handles a machine check exception raised on the current CPU core.
+///
+/// A machine check can interrupt code holding any lock, including those used by the logger and the frame allocator, so this only reads the
+/// machine check banks and never takes a lock. Corrected errors are left in their banks to be logged by the next call to
+/// [`poll_corrected_errors`], while uncorrected errors are reported through a panic, whose crash screen does not depend on any locks.
+///
+/// # Panics
+///
+/// This function will panic if an uncorrected error is found or if the processor indicates that execution cannot be restarted.
+pub(super) unsafe fn handle_machine_check(frame: &InterruptFrame) {
+    let mcg_status = Msr::new(IA32_MCG_STATUS).read();
+    let mut fatal = None;
+    let mut num_uncorrected = 0;
+
+    for bank in 0..NUM_BANKS.load(Ordering::Relaxed) {
+        if let Some(record) = MachineCheckRecord::read(bank).filter(|record| record.is_uncorrected()) {
+            fatal.get_or_insert(record);
+            num_uncorrected += 1;
+        }
+    }
+
+    if let Some(record) = fatal {
+        panic!(
+            "Uncorrected machine check error at rip {:#x}: {} ({} uncorrected errors in total)",
+            frame.rip, record, num_uncorrected
+        );
+    } else if mcg_status & MCG_STATUS_RIPV == 0 {
+        panic!(
+            "Machine check exception at rip {:#x} cannot be restarted (MCG_STATUS {:#x}, EIPV {})",
+            frame.rip,
+            mcg_status,
+            mcg_status & MCG_STATUS_EIPV != 0
+        );
+    }
+
+    // Clearing MCIP indicates that we're done handling this machine check. If another machine check were to occur while MCIP is set, the
+    // processor would shut down.
+    Msr::new(IA32_MCG_STATUS).write(0);
+}
+
+pub(super) unsafe fn init_bsp() {
+    let features = cpuid::get_minimum_features();
+
+    if !features.supports(CpuFeature::MCE) {
+        log!(Notice, "mce", "Machine check exceptions are not supported by this processor");
+        return;
+    }
+
+    if features.supports(CpuFeature::MCA) {
+        let mcg_cap = Msr::new(IA32_MCG_CAP).read();
+        let num_banks = (mcg_cap & MCG_CAP_COUNT_MASK) as usize;
+
+        if mcg_cap & MCG_CAP_CTL_P != 0 {
+            Msr::new(IA32_MCG_CTL).write(!0);
+        }
+
+        // Errors may have been logged before the kernel took over (e.g. by firmware or across a warm reset), so report and clear them before
+        // enabling error reporting.
+        NUM_BANKS.store(num_banks, Ordering::Relaxed);
+        poll_corrected_errors();
+
+        for bank in 0..num_banks {
+            mci_ctl(bank).write(!0);
+        }
+
+        log!(Debug, "mce", "Enabled machine check architecture with {} banks", num_banks);
+        schedule_poll();
+    }
+
+    Cr4::write(Cr4::read() | Cr4Flags::MACHINE_CHECK_EXCEPTION);
+}
@@ -1,24 +1,35 @@
 use alloc::boxed::Box;
+use alloc::vec;
 use core::arch::asm;
-use core::ptr;
 
 use bootloader::BootInfo;
 pub use x86_64::{PhysAddr, VirtAddr};
 
 use crate::arch::dev::vgabuf::VgaTextBufferDevice;
+use crate::io::dev::driver::{self, DeviceId, DeviceInfo};
+use crate::io::dev::probe::ProbeSet;
 use crate::io::dev::DeviceNode;
+use crate::log::LogFormat;
 use crate::options;
-use crate::util::OneShotManualInit;
+use crate::shutdown::ShutdownStage;
 
+pub mod acpi;
 pub mod cpuid;
 pub mod dev;
 pub mod gdt;
+pub mod insn;
 pub mod interrupt;
+pub mod lapic;
+pub mod mce;
 pub mod page;
 pub mod pic;
+pub mod pit;
+pub mod recovery;
 pub mod regs;
-
-static KERNEL_FS_BASE: OneShotManualInit<u64> = OneShotManualInit::uninit();
+pub mod tls;
+pub mod topology;
+pub mod unhandled;
+pub mod unwind;
 
 unsafe fn init_sse() {
     use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
@@ -28,26 +39,9 @@ unsafe fn init_sse() {
     asm!("fninit");
 }
 
-unsafe fn init_bootstrap_tls(boot_info: &BootInfo) {
-    if let Some(tls_template) = boot_info.tls_template() {
-        assert!(tls_template.file_size <= tls_template.mem_size);
-        assert_eq!(0, tls_template.mem_size & 0xf);
-
-        let tls = crate::mem::early::alloc(tls_template.mem_size as usize + 8, 16);
-        let tib = tls.add(tls_template.mem_size as usize);
-
-        ptr::write_bytes(tls, 0, tls_template.mem_size as usize);
-        ptr::copy_nonoverlapping(tls_template.start_addr as *mut u8, tls, tls_template.file_size as usize);
-        ptr::write::<*mut u8>(tib as *mut *mut u8, tib as *mut u8);
-
-        x86_64::registers::model_specific::Msr::new(0xc0000100).write(tib as u64);
-        KERNEL_FS_BASE.set(tib as u64);
-    };
-}
-
 pub(crate) unsafe fn init_phase_1(boot_info: &BootInfo) {
     page::init_phys_mem_base(boot_info.physical_memory_offset as *mut u8);
-    init_bootstrap_tls(boot_info);
+    tls::init_bsp(boot_info.tls_template());
     cpuid::init_bsp();
 
     crate::io::dev::init_device_root();
@@ -55,7 +49,7 @@ pub(crate) unsafe fn init_phase_1(boot_info: &BootInfo) {
     let serial = dev::serial::init();
 
     if options::get().get_flag("serial_log").unwrap_or(false) {
-        crate::log::add_tty(serial);
+        crate::log::add_tty_with_format(serial, options::get().get("serial_log_format").unwrap_or(LogFormat::Text));
     }
 
     let vga_text = crate::io::dev::device_root()
@@ -75,7 +69,34 @@ pub(crate) unsafe fn init_phase_1(boot_info: &BootInfo) {
 pub(crate) unsafe fn init_phase_2() {
     page::init_kernel_addrspace();
     crate::mem::set_use_early_alloc(false);
-    dev::ps2::init();
+    lapic::init_bsp();
+    pit::init();
+    crate::sched::clockevent::start();
+    mce::init_bsp();
+    dev::serial::enable_irq();
+
+    crate::shutdown::register_hook(
+        ShutdownStage::Devices,
+        "pic",
+        Box::new(|_| unsafe {
+            pic::mask_all_irqs();
+        }),
+    );
+}
+
+/// Registers the drivers for devices that are directly managed by architecture-specific code and adds probes announcing the legacy
+/// platform devices that they bind to.
+pub(crate) fn add_device_probes(probes: &mut ProbeSet) {
+    driver::register(&dev::isadma::DRIVER);
+    driver::register(&dev::floppy::DRIVER);
+    driver::register(&dev::ps2::DRIVER);
+
+    probes.add("platform", &[], || {
+        // TODO Check the ACPI tables for which legacy devices are actually present rather than assuming a PC-compatible machine
+        driver::announce(DeviceInfo::new(Box::from("dma"), vec![DeviceId::Platform("PNP0200")]));
+        driver::announce(DeviceInfo::new(Box::from("fdc"), vec![DeviceId::Platform("PNP0700")]));
+        driver::announce(DeviceInfo::new(Box::from("i8042"), vec![DeviceId::Platform("PNP0303")]));
+    });
 }
 
 #[naked]
@@ -89,8 +110,116 @@ unsafe extern "C" fn idle() {
     );
 }
 
+/// Reads the processor's timestamp counter, which counts up at a constant rate on all reasonably modern processors.
+pub fn cycle_counter() -> u64 {
+    // SAFETY: The rdtsc instruction is supported by all x86_64 processors
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Fills the provided buffer with the return addresses of the functions on the current call stack, starting with the address in the caller
+/// that this function will return to, after skipping the first `skip` addresses. Returns the number of addresses written.
+///
+/// The call stack is found by following the chain of saved frame pointers, which ends at the start of each thread and interrupt handler.
+/// The walk also stops early if a saved frame pointer does not point further up the stack, since that can only happen if some function on
+/// the stack was compiled without frame pointers.
+#[inline(never)]
+pub fn backtrace(mut skip: usize, out: &mut [usize]) -> usize {
+    let mut rbp: u64;
+    let mut n = 0;
+
+    // SAFETY: Reading rbp has no side effects
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+
+    while n < out.len() && rbp != 0 && rbp % 8 == 0 && VirtAddr::try_new(rbp).is_ok() {
+        // SAFETY: rbp points to a frame record pushed by a function prologue, which consists of the caller's saved frame pointer followed by
+        //         the return address
+        let (next_rbp, ret_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+
+        if skip == 0 {
+            out[n] = ret_addr as usize;
+            n += 1;
+        } else {
+            skip -= 1;
+        }
+
+        if next_rbp <= rbp {
+            break;
+        }
+
+        rbp = next_rbp;
+    }
+
+    n
+}
+
+#[thread_local]
+static IDLE_MONITOR: u64 = 0;
+
+/// Enables interrupts and waits for one to occur, using the lowest power state that the processor supports for doing so.
+pub fn idle_wait() {
+    if cpuid::get_minimum_features().supports(cpuid::CpuFeature::MONITOR) {
+        // SAFETY: The monitored address is valid and mwait with no hints or extensions simply waits for an interrupt or a write to the
+        //         monitored address. Since sti delays enabling interrupts until after the next instruction, an interrupt cannot arrive
+        //         between enabling interrupts and starting to wait.
+        unsafe {
+            asm!("monitor", in("rax") &IDLE_MONITOR as *const u64, in("ecx") 0, in("edx") 0, options(nostack));
+            asm!("sti", "mwait", in("eax") 0, in("ecx") 0, options(nostack));
+        }
+    } else {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
+
 pub fn halt() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
 }
+
+/// Resets the machine. If the keyboard controller fails to reset the machine, a triple fault is deliberately caused instead.
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+    use x86_64::structures::DescriptorTablePointer;
+
+    interrupt::disable();
+
+    unsafe {
+        // Pulse the CPU reset line using the PS/2 controller
+        Port::<u8>::new(0x64).write(0xfe);
+
+        for _ in 0..100_000 {
+            core::hint::spin_loop();
+        }
+
+        // Any exception raised with an empty IDT will cause a triple fault, which resets the processor
+        x86_64::instructions::tables::lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        asm!("int3");
+    }
+
+    halt();
+}
+
+/// Powers off the machine.
+///
+/// Since ACPI is not yet supported, this currently only works on emulators that provide a fixed power-off port (e.g. QEMU and Bochs). On
+/// other machines, this will simply halt.
+pub fn power_off() -> ! {
+    use x86_64::instructions::port::Port;
+
+    interrupt::disable();
+
+    unsafe {
+        // QEMU (with the PIIX4 ACPI device)
+        Port::<u16>::new(0x604).write(0x2000);
+
+        // Bochs and older versions of QEMU
+        Port::<u16>::new(0xb004).write(0x2000);
+    }
+
+    halt();
+}
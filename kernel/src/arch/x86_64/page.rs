@@ -1,3 +1,4 @@
+use core::alloc::AllocError;
 use core::ops::Range;
 use core::ptr;
 
@@ -10,15 +11,38 @@ use x86_64::structures::paging::{
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::mem::frame::{self, FrameAllocator};
+use crate::mem::swap::SwapError;
+use crate::mem::tlb::TlbShootdown;
 use crate::mem::virt::{VirtualAllocRegion, VirtualAllocator};
 use crate::sync::uninterruptible::UninterruptibleSpinlockGuard;
 use crate::sync::UninterruptibleSpinlock;
 use crate::util::{OneShotManualInit, SyncPtr};
 
 pub const PAGE_SIZE: usize = 4096;
+
+/// The size of a huge page, which maps a 2MiB region of virtual memory using a single L2 page table entry.
+pub const HUGE_PAGE_SIZE: usize = Size2MiB::SIZE as usize;
 pub const IS_PHYS_MEM_ALWAYS_MAPPED: bool = true;
 
-pub use crate::arch::api::page::PageFlags;
+/// The end of the lower half of the address space, which is available to user address spaces.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// The range of higher-half virtual addresses from which the kernel's virtual allocator can hand out address space. The last L4 entry is
+/// excluded since it contains the kernel image.
+pub const KERNEL_VIRT_ALLOC_RANGE: Range<u64> = 0xffff_8000_0000_0000..0xffff_ff80_0000_0000;
+
+/// The maximum number of swap slots that can be referred to by a swapped out page's page table entry.
+pub const MAX_SWAP_SLOTS: u64 = 1 << 40;
+
+// Non-present page table entries are ignored by the processor, so one of the bits available to the OS is used to distinguish entries that
+// refer to a swap slot from entries that are simply unmapped. The swap slot is stored in the address bits of the entry.
+const SWAP_ENTRY_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+
+// Copy-on-write pages are mapped read-only so that writes to them fault. Another bit available to the OS records that such a fault should
+// be resolved by copying the page rather than treated as a protection violation.
+const COW_FLAG: PageTableFlags = PageTableFlags::BIT_10;
+
+pub use crate::arch::page_flags::PageFlags;
 
 static PHYS_MEM_BASE: OneShotManualInit<SyncPtr<u8>> = OneShotManualInit::uninit();
 static KERNEL_ADDRESS_SPACE: OneShotManualInit<UninterruptibleSpinlock<AddressSpace>> = OneShotManualInit::uninit();
@@ -60,6 +84,17 @@ pub fn get_phys_mem_ptr_slice<T>(phys_addr: PhysAddr, len: usize) -> PhysMemPtr<
     PhysMemPtr(ptr::slice_from_raw_parts_mut(get_phys_mem_ptr::<T>(phys_addr).ptr(), len))
 }
 
+/// Invalidates any translation for the page containing the provided virtual address that is cached in the current CPU core's TLB. Other
+/// cores are not affected; use [`TlbShootdown`](crate::mem::tlb::TlbShootdown) to invalidate a translation on all cores.
+pub fn flush_tlb_local(addr: VirtAddr) {
+    x86_64::instructions::tlb::flush(addr);
+}
+
+/// Invalidates all non-global translations cached in the current CPU core's TLB. Other cores are not affected.
+pub fn flush_tlb_all_local() {
+    x86_64::instructions::tlb::flush_all();
+}
+
 struct PhysPageTableFrameMapping;
 
 unsafe impl PageTableFrameMapping for PhysPageTableFrameMapping {
@@ -100,7 +135,26 @@ impl AddressSpace {
         (*KERNEL_ADDRESS_SPACE.get()).lock()
     }
 
+    pub fn try_kernel() -> Option<UninterruptibleSpinlockGuard<'static, AddressSpace>> {
+        (*KERNEL_ADDRESS_SPACE.get()).try_lock()
+    }
+
     pub fn new() -> AddressSpace {
+        let mut addrspace = AddressSpace::new_user_empty();
+
+        // SAFETY: Nothing is mapped in the lower half of a new address space yet
+        unsafe {
+            addrspace.virtual_alloc.free(VirtualAllocRegion::new(
+                VirtAddr::new(PAGE_SIZE as u64),
+                VirtAddr::new(0x00007ffffffff000),
+            ));
+        }
+
+        addrspace
+    }
+
+    /// Creates a new user address space with nothing mapped in its lower half and no free virtual memory in its virtual allocator.
+    fn new_user_empty() -> AddressSpace {
         unsafe {
             let mut addrspace = AddressSpace::from_page_table(crate::mem::frame::get_allocator().alloc_one().unwrap(), false);
             let mut l4_table = addrspace.as_page_table();
@@ -122,15 +176,50 @@ impl AddressSpace {
                 }
             };
 
-            addrspace.virtual_alloc.free(VirtualAllocRegion::new(
-                VirtAddr::new(PAGE_SIZE as u64),
-                VirtAddr::new(0x00007ffffffff000),
-            ));
-
             addrspace
         }
     }
 
+    /// Creates a new user address space for a new process, with the same lower-half mappings as this one and a virtual allocator seeded
+    /// with the same free regions.
+    ///
+    /// Rather than copying every page eagerly, writeable pages are converted into copy-on-write pages in both address spaces and their
+    /// frames are shared, so that a copy is only made once either address space writes to the page. Read-only pages simply share their
+    /// frames. Swapped out pages are copied into a new swap slot, since swap slots cannot be shared.
+    ///
+    /// If a swapped out page cannot be copied, an error is returned. Pages that were already converted into copy-on-write pages remain so,
+    /// which is harmless since a write to them will find that their frame is no longer shared.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on the kernel address space.
+    #[track_caller]
+    pub fn clone_for_new_process(&mut self) -> Result<AddressSpace, SwapError> {
+        if self.is_kernel {
+            panic!("clone_for_new_process cannot be called on the kernel address space");
+        }
+
+        let mut clone = AddressSpace::new_user_empty();
+
+        for region in self.virtual_alloc.free_regions() {
+            // SAFETY: The region is free in this address space, so nothing will be mapped there in the clone either
+            unsafe {
+                clone.virtual_alloc.free(region);
+            }
+        }
+
+        // SAFETY: This address space is borrowed mutably, so nothing else can be modifying its page tables. The clone is new, so its lower
+        //         half is empty.
+        let result = unsafe { clone_user_page_table(self.page_table, 4, 0..256, 0, &mut clone) };
+
+        if Cr3::read().0.start_address() == self.page_table {
+            // TODO Flush on other cores
+            x86_64::instructions::tlb::flush_all();
+        }
+
+        result.map(|()| clone)
+    }
+
     pub(crate) unsafe fn init_kernel_virtual_alloc(&mut self) {
         fn find_free_regions_in(
             table: &PageTable,
@@ -180,7 +269,7 @@ impl AddressSpace {
         find_free_regions_in(
             &*get_phys_mem_ptr(self.page_table).ptr(),
             256..511,
-            VirtAddr::new(0xffff800000000000),
+            VirtAddr::new(KERNEL_VIRT_ALLOC_RANGE.start),
             4,
             &mut self.virtual_alloc,
             &mut pending_region,
@@ -221,6 +310,18 @@ impl AddressSpace {
             out_flags |= PageFlags::EXECUTABLE;
         }
 
+        if in_flags.contains(PageTableFlags::WRITE_THROUGH) {
+            out_flags |= PageFlags::WRITE_THROUGH;
+        }
+
+        if in_flags.contains(PageTableFlags::NO_CACHE) {
+            out_flags |= PageFlags::UNCACHED;
+        }
+
+        if in_flags.contains(COW_FLAG) {
+            out_flags |= PageFlags::WRITEABLE | PageFlags::COPY_ON_WRITE;
+        }
+
         out_flags
     }
 
@@ -231,7 +332,9 @@ impl AddressSpace {
             out_flags |= PageTableFlags::USER_ACCESSIBLE;
         }
 
-        if in_flags.contains(PageFlags::WRITEABLE) {
+        if in_flags.contains(PageFlags::COPY_ON_WRITE) {
+            out_flags |= COW_FLAG;
+        } else if in_flags.contains(PageFlags::WRITEABLE) {
             out_flags |= PageTableFlags::WRITABLE;
         }
 
@@ -239,6 +342,15 @@ impl AddressSpace {
             out_flags |= PageTableFlags::NO_EXECUTE;
         }
 
+        // With the default PAT, PWT selects write-through caching and PCD selects UC-. Setting both selects strong UC.
+        if in_flags.contains(PageFlags::WRITE_THROUGH) {
+            out_flags |= PageTableFlags::WRITE_THROUGH;
+        }
+
+        if in_flags.contains(PageFlags::UNCACHED) {
+            out_flags |= PageTableFlags::NO_CACHE;
+        }
+
         out_flags
     }
 
@@ -291,8 +403,138 @@ impl AddressSpace {
         }
     }
 
+    /// Gets a mutable reference to the L1 page table entry for the provided address, if the page tables leading to it exist.
+    unsafe fn l1_entry_mut(&mut self, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let mut table = &mut *(get_phys_mem_ptr(self.page_table).ptr() as *mut PageTable);
+
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            let entry = &table[index];
+
+            if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+
+            table = &mut *(get_phys_mem_ptr(entry.addr()).ptr() as *mut PageTable);
+        }
+
+        Some(&mut table[page.p1_index()])
+    }
+
+    /// Gets a pointer to the L2 page table entry for the provided address. If `create` is set, any missing page tables leading to it are
+    /// allocated. Returns [`None`] if the page tables do not exist and were not created, or if the address is mapped by a 1GiB page, and
+    /// returns an error if a page table could not be allocated.
+    unsafe fn l2_entry_ptr(&self, addr: VirtAddr, create: bool) -> Result<Option<*mut PageTableEntry>, AllocError> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let mut table = get_phys_mem_ptr::<PageTable>(self.page_table).ptr();
+
+        for index in [page.p4_index(), page.p3_index()] {
+            let entry = &mut (*table)[index];
+
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                if !create {
+                    return Ok(None);
+                }
+
+                let new_table = frame::get_allocator().alloc_one().ok_or(AllocError)?;
+                *get_phys_mem_ptr(new_table).ptr() = PageTable::new();
+
+                entry.set_addr(
+                    new_table,
+                    PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
+                );
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Ok(None);
+            }
+
+            table = get_phys_mem_ptr(entry.addr()).ptr();
+        }
+
+        Ok(Some(&mut (*table)[page.p2_index()]))
+    }
+
+    /// Checks whether the provided virtual address is currently mapped using a 2MiB huge page.
+    pub fn is_huge_page(&self, addr: VirtAddr) -> bool {
+        // SAFETY: No page tables are created, so this only reads the page tables of this address space
+        unsafe {
+            self.l2_entry_ptr(addr, false).ok().flatten().map_or(false, |entry| {
+                (*entry).flags().contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE)
+            })
+        }
+    }
+
+    /// Maps or unmaps a 2MiB huge page at the provided higher-half virtual address, which must be aligned to [`HUGE_PAGE_SIZE`]. When
+    /// mapping a huge page, the physical address must be aligned to [`HUGE_PAGE_SIZE`] as well. Returns an error without changing anything
+    /// if a page table needed to map the huge page could not be allocated.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on a user address space, if either address is misaligned, or if any 4KiB pages are still mapped in
+    /// the range that the huge page would cover.
+    ///
+    /// As with [`AddressSpace::set_page_kernel`], only the current CPU core's TLB is flushed for the huge page itself. If an empty page
+    /// table is replaced by the huge page, it is only freed once every core has stopped caching it.
+    #[track_caller]
+    pub unsafe fn set_huge_page_kernel(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageFlags)>) -> Result<(), AllocError> {
+        if !self.is_kernel {
+            panic!("set_huge_page_kernel cannot be called on a user address space");
+        }
+
+        if addr.as_u64() < 0xffff_8000_0000_0000 {
+            panic!("set_huge_page_kernel can only be used on higher-half virtual addresses");
+        }
+
+        Page::<Size2MiB>::from_start_address(addr).expect("bad address for huge page mapping");
+
+        let Some(entry) = self.l2_entry_ptr(addr, mapping.is_some())? else {
+            return Ok(());
+        };
+        let entry = &mut *entry;
+        let mut replaced_table = None;
+
+        if let Some((frame, flags)) = mapping {
+            PhysFrame::<Size2MiB>::from_start_address(frame).expect("bad frame for huge page mapping");
+
+            if entry.flags().contains(PageTableFlags::PRESENT) && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                // An L1 page table may be left behind after all of the 4KiB pages in this range have been unmapped. It can be freed as
+                // long as nothing is still mapped through it.
+                let l1_table = &*(get_phys_mem_ptr(entry.addr()).ptr() as *const PageTable);
+
+                assert!(
+                    l1_table.iter().all(|e| e.is_unused()),
+                    "huge page mapping at {:#x} would replace existing pages",
+                    addr.as_u64()
+                );
+                replaced_table = Some(entry.addr());
+            }
+
+            entry.set_addr(frame, Self::to_x86_64_flags(flags) | PageTableFlags::HUGE_PAGE);
+        } else {
+            assert!(
+                !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE),
+                "no huge page is mapped at {:#x}",
+                addr.as_u64()
+            );
+            entry.set_addr(PhysAddr::zero(), PageTableFlags::empty());
+        }
+
+        if let Some(table) = replaced_table {
+            // Other cores may still have the replaced page table in their paging-structure caches, and would walk through it if its frame
+            // were reused before they drop it
+            let mut shootdown = TlbShootdown::new();
+
+            shootdown.add(addr);
+            shootdown.flush();
+            frame::get_allocator().free_one(table);
+        } else {
+            flush_tlb_local(addr);
+        }
+
+        Ok(())
+    }
+
     #[track_caller]
-    unsafe fn set_page_internal(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageFlags)>) {
+    unsafe fn set_page_internal(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageTableFlags)>) {
         let page = Page::<Size4KiB>::from_start_address(addr).expect("bad address for page mapping");
 
         let l4_table = &mut *(get_phys_mem_ptr(self.page_table).ptr() as *mut PageTable);
@@ -355,7 +597,7 @@ impl AddressSpace {
         let l1_entry = &mut l1_table[page.p1_index()];
 
         if let Some((frame, flags)) = mapping {
-            l1_entry.set_addr(frame, Self::to_x86_64_flags(flags));
+            l1_entry.set_addr(frame, flags);
         } else {
             l1_entry.set_addr(PhysAddr::zero(), PageTableFlags::empty());
         }
@@ -367,18 +609,127 @@ impl AddressSpace {
             panic!("set_page_user cannot be called on the kernel address space");
         }
 
-        if addr.as_u64() >= 0x0000_8000_0000_0000 {
+        if addr.as_u64() >= USER_SPACE_END {
             panic!("set_page_user can only be used on lower-half virtual addresses");
         }
 
-        unsafe { self.set_page_internal(addr, mapping) };
+        unsafe { self.set_page_internal(addr, mapping.map(|(frame, flags)| (frame, Self::to_x86_64_flags(flags)))) };
+        self.flush_user(addr);
+    }
 
+    fn flush_user(&self, addr: VirtAddr) {
         if Cr3::read().0.start_address() == self.page_table {
             // TODO Flush on other cores
             x86_64::instructions::tlb::flush(addr);
         }
     }
 
+    /// Gets the swap slot recorded for the provided lower-half virtual address by [`AddressSpace::set_swap_entry_user`], or [`None`] if
+    /// the page is not currently swapped out.
+    pub fn get_swap_entry(&mut self, addr: VirtAddr) -> Option<u64> {
+        let entry = unsafe { self.l1_entry_mut(addr)? };
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) && entry.flags().contains(SWAP_ENTRY_FLAG) {
+            Some(entry.addr().as_u64() >> 12)
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the mapping for the provided lower-half virtual address with a non-present entry recording that its contents have been
+    /// written to the provided swap slot. Any accesses to the page will cause a page fault until it is mapped again.
+    ///
+    /// # Safety
+    ///
+    /// Any frame that was previously mapped at this address is not freed and is immediately unmapped, so the caller must ensure that its
+    /// contents have already been saved and that it is freed afterwards.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on the kernel address space, if the provided address is not a lower-half address, or if the swap
+    /// slot is too large to be encoded in a page table entry.
+    #[track_caller]
+    pub unsafe fn set_swap_entry_user(&mut self, addr: VirtAddr, slot: u64) {
+        if self.is_kernel {
+            panic!("set_swap_entry_user cannot be called on the kernel address space");
+        }
+
+        if addr.as_u64() >= USER_SPACE_END {
+            panic!("set_swap_entry_user can only be used on lower-half virtual addresses");
+        }
+
+        assert!(slot < MAX_SWAP_SLOTS, "swap slot {} cannot be encoded in a page table entry", slot);
+
+        unsafe { self.set_page_internal(addr, Some((PhysAddr::new(slot << 12), SWAP_ENTRY_FLAG))) };
+        self.flush_user(addr);
+    }
+
+    /// Checks whether the page mapped at the provided lower-half virtual address has been accessed since the last time this method was
+    /// called on it, and clears the processor's record of the access. Returns `false` if the page is not mapped.
+    pub fn test_and_clear_accessed(&mut self, addr: VirtAddr) -> bool {
+        let accessed = unsafe {
+            match self.l1_entry_mut(addr) {
+                Some(entry) if entry.flags().contains(PageTableFlags::PRESENT | PageTableFlags::ACCESSED) => {
+                    entry.set_flags(entry.flags() - PageTableFlags::ACCESSED);
+                    true
+                },
+                _ => false,
+            }
+        };
+
+        if accessed {
+            self.flush_user(addr);
+        }
+
+        accessed
+    }
+
+    /// Converts the writeable page mapped at the provided lower-half virtual address into a copy-on-write page, so that the frame backing
+    /// it can be shared with another address space. Returns the mapping of the page afterwards, or [`None`] if no page is mapped there.
+    ///
+    /// Read-only pages are left unchanged, since they can be shared as-is. Callers that map the returned frame elsewhere must first add a
+    /// reference to it using [`frame::share`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on the kernel address space.
+    #[track_caller]
+    pub fn make_page_cow_user(&mut self, addr: VirtAddr) -> Option<(PhysAddr, PageFlags)> {
+        if self.is_kernel {
+            panic!("make_page_cow_user cannot be called on the kernel address space");
+        }
+
+        // SAFETY: Write-protecting a page does not change which frame it maps, so the contents of the page remain accessible.
+        let changed = unsafe {
+            let entry = self.l1_entry_mut(addr)?;
+
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+
+            if entry.flags().contains(PageTableFlags::WRITABLE) {
+                entry.set_flags((entry.flags() - PageTableFlags::WRITABLE) | COW_FLAG);
+                true
+            } else {
+                false
+            }
+        };
+
+        if changed {
+            self.flush_user(addr);
+        }
+
+        self.get_page(addr)
+    }
+
+    /// Maps or unmaps a page at the provided higher-half virtual address.
+    ///
+    /// Only the current CPU core's TLB is flushed. When an existing mapping is removed or changed, the caller must use a
+    /// [`TlbShootdown`](crate::mem::tlb::TlbShootdown) to flush it on the other cores before reusing the frame or virtual address.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called on a user address space or if the address is not a higher-half address.
     #[track_caller]
     pub unsafe fn set_page_kernel(&mut self, addr: VirtAddr, mapping: Option<(PhysAddr, PageFlags)>) {
         if !self.is_kernel {
@@ -389,10 +740,101 @@ impl AddressSpace {
             panic!("set_page_kernel can only be used on higher-half virtual addresses");
         }
 
-        unsafe { self.set_page_internal(addr, mapping) };
+        unsafe { self.set_page_internal(addr, mapping.map(|(frame, flags)| (frame, Self::to_x86_64_flags(flags)))) };
+        flush_tlb_local(addr);
+    }
+}
+
+/// Frees a page table belonging to a user address space, along with all page tables and frames mapped by it.
+unsafe fn free_user_page_table(table: PhysAddr, level: u64, entries: Range<usize>) {
+    let page_table = &*(get_phys_mem_ptr(table).ptr() as *const PageTable);
+    let mut frame_alloc = frame::get_allocator();
+
+    for i in entries {
+        let entry = &page_table[i];
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            if level == 1 && entry.flags().contains(SWAP_ENTRY_FLAG) {
+                crate::mem::swap::free_slot(entry.addr().as_u64() >> 12);
+            }
+
+            continue;
+        }
+
+        assert!(!entry.flags().contains(PageTableFlags::HUGE_PAGE));
 
-        // TODO Flush on other cores
-        x86_64::instructions::tlb::flush(addr);
+        if level > 1 {
+            free_user_page_table(entry.addr(), level - 1, 0..512);
+        } else {
+            frame::release(entry.addr());
+        }
+    }
+
+    frame_alloc.free_one(table);
+}
+
+/// Duplicates the mappings in a page table belonging to a user address space into another user address space. See
+/// [`AddressSpace::clone_for_new_process`] for details.
+unsafe fn clone_user_page_table(
+    table: PhysAddr,
+    level: u64,
+    entries: Range<usize>,
+    base: u64,
+    clone: &mut AddressSpace,
+) -> Result<(), SwapError> {
+    let page_table = &mut *(get_phys_mem_ptr(table).ptr() as *mut PageTable);
+
+    for i in entries {
+        let addr = base + ((i as u64) << (12 + 9 * (level - 1)));
+        let entry = &mut page_table[i];
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            if level == 1 && entry.flags().contains(SWAP_ENTRY_FLAG) {
+                let slot = crate::mem::swap::duplicate_slot(entry.addr().as_u64() >> 12)?;
+
+                clone.set_page_internal(VirtAddr::new(addr), Some((PhysAddr::new(slot << 12), SWAP_ENTRY_FLAG)));
+            }
+
+            continue;
+        }
+
+        assert!(!entry.flags().contains(PageTableFlags::HUGE_PAGE));
+
+        if level > 1 {
+            clone_user_page_table(entry.addr(), level - 1, 0..512, addr, clone)?;
+        } else {
+            if entry.flags().contains(PageTableFlags::WRITABLE) {
+                entry.set_flags((entry.flags() - PageTableFlags::WRITABLE) | COW_FLAG);
+            }
+
+            frame::share(entry.addr());
+            clone.set_page_internal(
+                VirtAddr::new(addr),
+                Some((entry.addr(), entry.flags() - PageTableFlags::ACCESSED - PageTableFlags::DIRTY)),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_kernel {
+            panic!("Attempt to drop the kernel address space");
+        }
+
+        assert_ne!(
+            Cr3::read().0.start_address(),
+            self.page_table,
+            "Attempt to drop an address space that is currently in use"
+        );
+
+        // SAFETY: The address space is not currently loaded. The upper half of the L4 page table is shared with the kernel address space,
+        //         so only the lower half (which is owned by this address space) is freed.
+        unsafe {
+            free_user_page_table(self.page_table, 4, 0..256);
+        }
     }
 }
 
@@ -1,5 +1,8 @@
 use x86_64::instructions::port::Port;
 
+use crate::sync::level::InterruptLevel;
+use crate::sync::UninterruptibleSpinlock;
+
 const MASTER_PIC_COMMAND_PORT: u16 = 0x20;
 const MASTER_PIC_DATA_PORT: u16 = 0x21;
 const SLAVE_PIC_COMMAND_PORT: u16 = 0xA0;
@@ -55,21 +58,94 @@ pub(super) unsafe fn remap_pic(master_off: u8, slave_off: u8) {
     slave_pic_data_port.write(slave_mask);
 }
 
+/// IRQ2 is used for communicating between the master and slave PICs, so it should never be masked or the slave PIC won't work correctly.
+const CASCADE_IRQ: u8 = 2;
+
+/// The state used to compute the mask registers of the PICs.
+struct PicMasks {
+    /// The IRQ lines that were masked using [`set_irq_masked`], regardless of the current interrupt level.
+    masked: u16,
+    irq_levels: [InterruptLevel; 16],
+    /// The number of CPU cores currently running at each interrupt level. Since all IRQs from the PICs go through the same mask registers,
+    /// an IRQ is held off on every core while any core is running at or above its level.
+    num_at_level: [usize; InterruptLevel::COUNT],
+}
+
+impl PicMasks {
+    fn effective_mask(&self) -> u16 {
+        let mut mask = self.masked;
+
+        for irq in 0..16 {
+            let level = self.irq_levels[usize::from(irq)] as usize;
+
+            if irq != CASCADE_IRQ && self.num_at_level[level..].iter().any(|&n| n != 0) {
+                mask |= 1 << irq;
+            }
+        }
+
+        mask
+    }
+
+    unsafe fn write(&self) {
+        let mask = self.effective_mask();
+
+        Port::new(MASTER_PIC_DATA_PORT).write(mask as u8);
+        Port::new(SLAVE_PIC_DATA_PORT).write((mask >> 8) as u8);
+    }
+}
+
+static MASKS: UninterruptibleSpinlock<PicMasks> = UninterruptibleSpinlock::new(PicMasks {
+    masked: !(1 << CASCADE_IRQ),
+    irq_levels: [InterruptLevel::Device; 16],
+    num_at_level: [0; InterruptLevel::COUNT],
+});
+
 pub unsafe fn mask_all_irqs() {
-    // Mask all interrupts except IRQ2. IRQ2 is used for communicating between the master and slave PICs, so it should not be masked or the
-    // slave PIC won't work correctly.
-    Port::new(MASTER_PIC_DATA_PORT).write(0xfb_u8);
-    Port::new(SLAVE_PIC_DATA_PORT).write(0xff_u8);
+    let mut masks = MASKS.lock();
+
+    masks.masked = !(1 << CASCADE_IRQ);
+    masks.write();
 }
 
 pub unsafe fn set_irq_masked(irq: u8, masked: bool) {
     assert!(irq < 0x10);
 
-    let mut pic_data_port: Port<u8> = Port::new(if irq > 7 { SLAVE_PIC_DATA_PORT } else { MASTER_PIC_DATA_PORT });
-    let imr = pic_data_port.read();
-    let mask_bit = 1 << (irq & 0x7);
+    let mut masks = MASKS.lock();
+
+    if masked {
+        masks.masked |= 1 << irq;
+    } else {
+        masks.masked &= !(1 << irq);
+    }
 
-    pic_data_port.write(if masked { imr | mask_bit } else { imr & !mask_bit });
+    masks.write();
+}
+
+/// Sets the interrupt level of an IRQ line, masking it whenever any CPU core is running at or above that level.
+pub unsafe fn set_irq_level(irq: u8, level: InterruptLevel) {
+    assert!(irq < 0x10);
+    assert!(level != InterruptLevel::Passive);
+
+    let mut masks = MASKS.lock();
+
+    masks.irq_levels[usize::from(irq)] = level;
+    masks.write();
+}
+
+/// Updates the mask registers after a CPU core changed its interrupt level.
+pub unsafe fn change_level(old_level: InterruptLevel, new_level: InterruptLevel) {
+    let mut masks = MASKS.lock();
+
+    // Cores running at the passive level never mask anything, so they don't need to be counted
+    if old_level != InterruptLevel::Passive {
+        masks.num_at_level[old_level as usize] -= 1;
+    }
+
+    if new_level != InterruptLevel::Passive {
+        masks.num_at_level[new_level as usize] += 1;
+    }
+
+    masks.write();
 }
 
 pub fn read_isr() -> u16 {
@@ -96,3 +172,17 @@ pub unsafe fn send_eoi(irq: u8) {
     // this will acknowledge the IRQ2 that the slave PIC used to tell the master PIC about the interrupt.
     Port::new(MASTER_PIC_COMMAND_PORT).write(0x20_u8);
 }
+
+/// Checks whether an interrupt on the provided IRQ line was a spurious interrupt. The PICs raise spurious interrupts as IRQ7 (or IRQ15 for
+/// the slave PIC) when an IRQ is deasserted before it could be acknowledged, in which case the corresponding in-service bit is not set.
+pub fn is_spurious(irq: u8) -> bool {
+    (irq == 7 || irq == 15) && read_isr() & (1 << irq) == 0
+}
+
+/// Sends the end-of-interrupt required after a spurious interrupt on the provided IRQ line. The PIC that raised a spurious interrupt must
+/// not receive an EOI, but if it was the slave PIC, then the master PIC still needs one for the IRQ2 that the slave PIC used to raise it.
+pub unsafe fn send_spurious_eoi(irq: u8) {
+    if irq == 15 {
+        Port::new(MASTER_PIC_COMMAND_PORT).write(0x20_u8);
+    }
+}
@@ -0,0 +1,160 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+use super::{interrupt, pic};
+use crate::sched::clockevent::{self, ClockEventDevice, ClockEventFeatures};
+use crate::sync::level::InterruptLevel;
+
+const PIT_CHANNEL_0_DATA_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+
+/// The frequency of the oscillator driving the PIT, in Hz.
+pub const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+
+const PIT_IRQ: usize = 0;
+const MAX_DIVISOR: u32 = 0x10000;
+
+// Select channel 0, access mode lobyte/hibyte, binary mode, and either mode 2 (rate generator) or mode 0 (interrupt on terminal count).
+const PIT_MODE_RATE_GENERATOR: u8 = 0x34;
+const PIT_MODE_ONESHOT: u8 = 0x30;
+
+fn divisor_for(duration: Duration) -> u32 {
+    let divisor = duration.as_nanos() * u128::from(PIT_BASE_FREQUENCY) / 1_000_000_000;
+
+    divisor.clamp(1, u128::from(MAX_DIVISOR)) as u32
+}
+
+fn duration_of(divisor: u32) -> Duration {
+    Duration::from_nanos(u64::from(divisor) * 1_000_000_000 / u64::from(PIT_BASE_FREQUENCY))
+}
+
+/// Gets the time taken by a single period of the PIT with the provided divisor, rounded down to a whole number of nanoseconds, along with
+/// the new remainder after adding the part that was rounded off to the provided remainder. The remainder is in units of
+/// 1/[`PIT_BASE_FREQUENCY`] nanoseconds and is always less than one nanosecond.
+fn elapsed_with_remainder(divisor: u32, remainder: u64) -> (Duration, u64) {
+    let total = u64::from(divisor) * 1_000_000_000 + remainder;
+
+    (
+        Duration::from_nanos(total / u64::from(PIT_BASE_FREQUENCY)),
+        total % u64::from(PIT_BASE_FREQUENCY),
+    )
+}
+
+unsafe fn program(mode: u8, divisor: u32) {
+    // A divisor of 0 is interpreted by the PIT as 0x10000.
+    let divisor = if divisor == MAX_DIVISOR { 0 } else { divisor as u16 };
+
+    Port::new(PIT_COMMAND_PORT).write(mode);
+
+    let mut data_port: Port<u8> = Port::new(PIT_CHANNEL_0_DATA_PORT);
+    data_port.write(divisor as u8);
+    data_port.write((divisor >> 8) as u8);
+}
+
+/// Channel 0 of the legacy programmable interval timer, which is present on all PC-compatible machines but is slow to program and has a
+/// low resolution, so it is given a low rating.
+struct Pit;
+
+static PERIODIC_DIVISOR: AtomicU32 = AtomicU32::new(0);
+static PERIODIC_REMAINDER: AtomicU64 = AtomicU64::new(0);
+
+impl ClockEventDevice for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn features(&self) -> ClockEventFeatures {
+        ClockEventFeatures::PERIODIC | ClockEventFeatures::ONESHOT
+    }
+
+    fn max_delta(&self) -> Duration {
+        duration_of(MAX_DIVISOR)
+    }
+
+    unsafe fn set_periodic(&self, period: Duration) -> Duration {
+        let divisor = divisor_for(period);
+
+        PERIODIC_DIVISOR.store(divisor, Ordering::Relaxed);
+        PERIODIC_REMAINDER.store(0, Ordering::Relaxed);
+        program(PIT_MODE_RATE_GENERATOR, divisor);
+        duration_of(divisor)
+    }
+
+    fn periodic_elapsed(&self, _period: Duration) -> Duration {
+        // The PIT's period is almost never a whole number of nanoseconds, so the time is derived from the programmed divisor instead,
+        // carrying the fractional nanoseconds over to the next tick.
+        let (elapsed, remainder) =
+            elapsed_with_remainder(PERIODIC_DIVISOR.load(Ordering::Relaxed), PERIODIC_REMAINDER.load(Ordering::Relaxed));
+
+        PERIODIC_REMAINDER.store(remainder, Ordering::Relaxed);
+        elapsed
+    }
+
+    unsafe fn set_oneshot(&self, delay: Duration) -> Duration {
+        let divisor = divisor_for(delay);
+
+        program(PIT_MODE_ONESHOT, divisor);
+        duration_of(divisor)
+    }
+
+    unsafe fn shutdown(&self) {
+        // Writing the mode without a new count stops the counter until a count is written.
+        Port::new(PIT_COMMAND_PORT).write(PIT_MODE_ONESHOT);
+    }
+}
+
+static PIT: Pit = Pit;
+
+pub(super) unsafe fn init() {
+    interrupt::register_irq(
+        PIT_IRQ,
+        Box::new(|_| {
+            clockevent::handle_event(&PIT);
+        }),
+    );
+    clockevent::register(&PIT);
+    interrupt::set_irq_level(PIT_IRQ, InterruptLevel::Clock);
+    pic::set_irq_masked(PIT_IRQ as u8, false);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_pit_divisor() {
+        assert_eq!(1193, divisor_for(Duration::from_millis(1)));
+        assert_eq!(MAX_DIVISOR, divisor_for(Duration::from_secs(1)));
+        assert_eq!(1, divisor_for(Duration::ZERO));
+        assert_eq!(Duration::from_nanos(999_847), duration_of(1193));
+    }
+
+    #[test_case]
+    fn test_pit_elapsed_no_drift() {
+        let mut total = Duration::ZERO;
+        let mut remainder = 0;
+
+        for _ in 0..PIT_BASE_FREQUENCY {
+            let (elapsed, new_remainder) = elapsed_with_remainder(1, remainder);
+
+            total += elapsed;
+            remainder = new_remainder;
+        }
+
+        // A full second's worth of single-count periods must add up to exactly one second, despite each being shorter than 1us
+        assert_eq!(Duration::from_secs(1), total);
+        assert_eq!(0, remainder);
+
+        assert_eq!(
+            (duration_of(1193), 1_193_000_000_000 % u64::from(PIT_BASE_FREQUENCY)),
+            elapsed_with_remainder(1193, 0)
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Recording and resuming execution at recovery points.
+//!
+//! A recovery point works much like `setjmp`/`longjmp` in C, except that the code that can be abandoned is confined to a callback so that
+//! Rust code never observes a function returning twice. Resuming a recovery point discards every stack frame below it without running
+//! any destructors.
+
+use core::arch::asm;
+
+/// The maximum distance in bytes between the stack pointer at a recovery point and the stack pointer when resuming it. Anything further
+/// than this is assumed to be running on a different stack.
+const MAX_RESUME_DISTANCE: u64 = 1024 * 1024;
+
+/// The callee-saved register state needed to resume execution at a recovery point.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct RecoveryPoint {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+}
+
+impl RecoveryPoint {
+    pub const fn new() -> RecoveryPoint {
+        RecoveryPoint {
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rsp: 0,
+            rip: 0,
+        }
+    }
+}
+
+/// Records a recovery point and then calls `f` with the provided data. Returns `false` if `f` returned normally, or `true` if execution
+/// was resumed at the recovery point by [`resume_recovery_point`] before `f` returned.
+///
+/// # Safety
+///
+/// The recovery point must not be moved until this function returns. `f` must be safe to call with the provided data.
+#[naked]
+pub unsafe extern "C" fn call_with_recovery_point(point: *mut RecoveryPoint, f: unsafe extern "C" fn(*mut u8), data: *mut u8) -> bool {
+    asm!(
+        "mov [rdi], rbx",
+        "mov [rdi + 8], rbp",
+        "mov [rdi + 16], r12",
+        "mov [rdi + 24], r13",
+        "mov [rdi + 32], r14",
+        "mov [rdi + 40], r15",
+        // Resuming acts as if this function had returned, so record the stack pointer and return address as they would be after a ret
+        "lea rax, [rsp + 8]",
+        "mov [rdi + 48], rax",
+        "mov rax, [rsp]",
+        "mov [rdi + 56], rax",
+        "sub rsp, 8",
+        "mov rdi, rdx",
+        "call rsi",
+        "add rsp, 8",
+        "xor eax, eax",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// Resumes execution at a recovery point, causing the corresponding call to [`call_with_recovery_point`] to return `true`.
+///
+/// # Safety
+///
+/// The call to [`call_with_recovery_point`] that recorded the recovery point must still be in progress on the current stack, as checked by
+/// [`can_resume`]. No destructors are run for anything on the discarded part of the stack.
+#[naked]
+pub unsafe extern "C" fn resume_recovery_point(point: *const RecoveryPoint) -> ! {
+    asm!(
+        "mov rbx, [rdi]",
+        "mov rbp, [rdi + 8]",
+        "mov r12, [rdi + 16]",
+        "mov r13, [rdi + 24]",
+        "mov r14, [rdi + 32]",
+        "mov r15, [rdi + 40]",
+        "mov rsp, [rdi + 48]",
+        "mov eax, 1",
+        "cld",
+        "jmp [rdi + 56]",
+        options(noreturn)
+    );
+}
+
+/// Checks whether the current stack pointer is on the same stack as a recorded recovery point and below it, such that resuming the
+/// recovery point would only discard stack frames belonging to code called from within it.
+pub fn can_resume(point: &RecoveryPoint) -> bool {
+    let rsp: u64;
+
+    // SAFETY: Reading rsp has no side effects
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+
+    rsp < point.rsp && point.rsp - rsp <= MAX_RESUME_DISTANCE
+}
@@ -0,0 +1,201 @@
+//! Management of thread-local storage (TLS) blocks.
+//!
+//! Every kernel thread gets its own TLS block, which is created from the TLS template found in the kernel image. The FS base points at the
+//! thread's block while it runs, so `#[thread_local]` statics are truly per-thread. In addition, each CPU core gets its own block created
+//! from the same template, which is used while handling interrupts and by threads that do not have their own block.
+//!
+//! The GS base always points at the current core's block while running in kernel mode. Since both kinds of block have the same layout, a
+//! `#[thread_local]` static can be accessed in the current core's block instead of the current thread's block by translating its address
+//! from one block to the other. This is used to implement [`CpuLocal`](crate::util::CpuLocal) for state that belongs to a core rather
+//! than to a thread. The address of the current core's block is also stored in the `IA32_KERNEL_GS_BASE` MSR, which is otherwise unused
+//! since the kernel never executes `swapgs`, so that interrupt handlers can find it before any thread-local state is available.
+
+use alloc::alloc::{alloc, dealloc, handle_alloc_error};
+use core::alloc::Layout;
+use core::arch::asm;
+use core::ptr;
+
+use bootloader::bootinfo::TlsTemplate;
+use x86_64::registers::model_specific::Msr;
+
+use crate::util::OneShotManualInit;
+
+const FS_BASE_MSR: u32 = 0xc000_0100;
+const GS_BASE_MSR: u32 = 0xc000_0101;
+const KERNEL_GS_BASE_MSR: u32 = 0xc000_0102;
+
+/// The size of the thread information block that follows the TLS data, which only contains a pointer to itself.
+const TIB_SIZE: usize = 8;
+/// The minimum alignment of a TLS block, which is used even if the TLS segment itself requires less.
+const MIN_TLS_ALIGN: usize = 16;
+
+extern "C" {
+    /// The alignment of the kernel's TLS segment, which is not included in the bootloader's TLS template.
+    static __tls_align: u64;
+}
+
+static TLS_TEMPLATE: OneShotManualInit<TlsTemplate> = OneShotManualInit::uninit();
+
+fn template() -> &'static TlsTemplate {
+    TLS_TEMPLATE.get()
+}
+
+fn align() -> usize {
+    // SAFETY: The linker script stores the alignment of the TLS segment in this read-only symbol
+    let tls_align = unsafe { __tls_align } as usize;
+
+    tls_align.max(MIN_TLS_ALIGN)
+}
+
+/// Gets the offset of the thread information block from the start of a TLS block. The TLS data sits directly below the thread
+/// information block, which must be aligned to the TLS segment's alignment, so the data's size is rounded up to that alignment.
+fn tib_offset() -> usize {
+    (template().mem_size as usize).next_multiple_of(align())
+}
+
+fn layout() -> Layout {
+    Layout::from_size_align(tib_offset() + TIB_SIZE, align()).unwrap()
+}
+
+/// Initializes a TLS block at the provided address from the kernel's TLS template, returning the address of its thread information block.
+///
+/// # Safety
+///
+/// The provided address must point to a writeable region of memory of at least the size given by [`layout`].
+unsafe fn init_block(block: *mut u8) -> u64 {
+    let template = template();
+    let tib = block.add(tib_offset());
+
+    ptr::write_bytes(block, 0, tib_offset());
+    ptr::copy_nonoverlapping(template.start_addr as *const u8, block, template.file_size as usize);
+    ptr::write(tib as *mut *mut u8, tib);
+
+    tib as u64
+}
+
+/// A TLS block allocated for a thread, which is freed when dropped.
+#[derive(Debug)]
+pub struct TlsBlock {
+    block: *mut u8,
+    tib: u64,
+}
+
+unsafe impl Send for TlsBlock {}
+unsafe impl Sync for TlsBlock {}
+
+impl TlsBlock {
+    /// Allocates a new TLS block initialized from the kernel's TLS template.
+    pub fn alloc() -> TlsBlock {
+        let layout = layout();
+
+        // SAFETY: The layout is never zero-sized, since it always includes the thread information block
+        let block = unsafe { alloc(layout) };
+
+        if block.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        // SAFETY: The block was just allocated with the correct layout
+        let tib = unsafe { init_block(block) };
+
+        TlsBlock { block, tib }
+    }
+
+    /// Gets the address of this block's thread information block, which is the value that the FS base should be set to when running the
+    /// thread that owns this block.
+    pub fn base(&self) -> u64 {
+        self.tib
+    }
+}
+
+impl Drop for TlsBlock {
+    fn drop(&mut self) {
+        // SAFETY: The block was allocated with this layout in TlsBlock::alloc
+        unsafe {
+            dealloc(self.block, layout());
+        }
+    }
+}
+
+/// Gets the size in bytes of the initialized and zero-initialized parts of the kernel's TLS template, which are copied into every TLS
+/// block.
+pub fn template_size() -> (usize, usize) {
+    let template = template();
+
+    (template.file_size as usize, (template.mem_size - template.file_size) as usize)
+}
+
+/// Gets the address of the current core's TLS block, which should be used as the GS base in kernel mode and as the FS base for threads
+/// that do not have their own TLS block.
+pub fn cpu_base() -> u64 {
+    // SAFETY: IA32_KERNEL_GS_BASE is set up by init_bsp or init_ap before any interrupts can occur on a core
+    unsafe { Msr::new(KERNEL_GS_BASE_MSR).read() }
+}
+
+/// Loads the current core's TLS block into both the FS and GS bases. This must be called upon entering an interrupt handler before any
+/// thread-local state is accessed.
+///
+/// # Safety
+///
+/// The current core's TLS block must have been set up using [`init_bsp`] or [`init_ap`].
+pub(super) unsafe fn load_cpu_base() {
+    let base = cpu_base();
+
+    Msr::new(FS_BASE_MSR).write(base);
+    Msr::new(GS_BASE_MSR).write(base);
+}
+
+/// Translates a pointer to a `#[thread_local]` static in the current thread's TLS block into a pointer to the same static in the current
+/// core's TLS block.
+pub fn cpu_local_ptr<T>(ptr: *const T) -> *const T {
+    let fs: usize;
+    let gs: usize;
+
+    // SAFETY: Both the FS and GS segments point to TLS blocks whose first quadword is a pointer to themselves in kernel mode
+    unsafe {
+        asm!("mov {}, fs:[0]", out(reg) fs, options(nostack, readonly, preserves_flags));
+        asm!("mov {}, gs:[0]", out(reg) gs, options(nostack, readonly, preserves_flags));
+    }
+
+    ptr.wrapping_byte_sub(fs).wrapping_byte_add(gs)
+}
+
+unsafe fn set_cpu_base(base: u64) {
+    Msr::new(KERNEL_GS_BASE_MSR).write(base);
+    load_cpu_base();
+}
+
+/// Saves the kernel's TLS template and sets up the TLS block for the bootstrap processor. Since the heap is not available yet, the block is
+/// allocated from the early allocation pool.
+///
+/// # Safety
+///
+/// This function must be called exactly once on the bootstrap processor before any thread-local state is accessed.
+pub(super) unsafe fn init_bsp(template: Option<TlsTemplate>) {
+    let template = template.expect("kernel image has no TLS template");
+
+    assert!(template.file_size <= template.mem_size);
+    assert!(align().is_power_of_two());
+
+    TLS_TEMPLATE.set(template);
+
+    let layout = layout();
+    set_cpu_base(init_block(crate::mem::early::alloc(layout.size(), layout.align())));
+}
+
+/// Sets up the TLS block for an application processor. The block is allocated from the heap and is never freed.
+///
+/// # Safety
+///
+/// This function must be called exactly once on each application processor during its startup, before any thread-local state is accessed
+/// on it.
+pub unsafe fn init_ap() {
+    let layout = layout();
+    let block = alloc(layout);
+
+    if block.is_null() {
+        handle_alloc_error(layout);
+    }
+
+    set_cpu_base(init_block(block));
+}
@@ -0,0 +1,170 @@
+//! Detection of the processor topology using the `cpuid` topology leaves and the ACPI MADT.
+//!
+//! The APIC ID of each logical processor is made up of bit fields identifying its thread within its core, its core within its package and
+//! its package. The widths of these fields are determined using `cpuid` on the bootstrap processor and are assumed to be the same on all
+//! processors, while the MADT provides the list of APIC IDs that are actually present.
+
+use alloc::vec;
+
+use super::{acpi, cpuid};
+use crate::log;
+use crate::sched::topology::{CpuTopology, LogicalCpu};
+
+const LEAF_FEATURES: u32 = 0x1;
+const LEAF_CACHE_PARAMS: u32 = 0x4;
+const LEAF_EXTENDED_TOPOLOGY: u32 = 0xb;
+
+const EXTENDED_TOPOLOGY_LEVEL_SMT: u32 = 1;
+const EXTENDED_TOPOLOGY_LEVEL_CORE: u32 = 2;
+
+/// The widths of the fields making up an APIC ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ApicIdLayout {
+    /// The number of low bits of the APIC ID that identify the thread within its core.
+    smt_bits: u32,
+    /// The number of low bits of the APIC ID that identify the core and thread within their package.
+    package_shift: u32,
+}
+
+impl ApicIdLayout {
+    fn decode(self, apic_id: u32, enabled: bool) -> LogicalCpu {
+        let core_bits = self.package_shift - self.smt_bits;
+
+        LogicalCpu {
+            hw_id: apic_id,
+            package: apic_id.checked_shr(self.package_shift).unwrap_or(0),
+            core: (apic_id >> self.smt_bits) & ((1 << core_bits) - 1),
+            thread: apic_id & ((1 << self.smt_bits) - 1),
+            enabled,
+        }
+    }
+}
+
+fn bits_for_count(count: u32) -> u32 {
+    count.max(1).next_power_of_two().trailing_zeros()
+}
+
+fn detect_layout_extended() -> Option<ApicIdLayout> {
+    let mut smt_bits = None;
+    let mut package_shift = None;
+
+    for level in 0..8 {
+        let result = cpuid::query(LEAF_EXTENDED_TOPOLOGY, level);
+        let shift = result.eax & 0x1f;
+
+        match (result.ecx >> 8) & 0xff {
+            0 => break,
+            EXTENDED_TOPOLOGY_LEVEL_SMT => smt_bits = Some(shift),
+            EXTENDED_TOPOLOGY_LEVEL_CORE => package_shift = Some(shift),
+            // Levels above the core level (e.g. modules or dies) are treated as part of the package
+            _ => package_shift = Some(shift),
+        }
+    }
+
+    let smt_bits = smt_bits?;
+
+    Some(ApicIdLayout {
+        smt_bits,
+        package_shift: package_shift.unwrap_or(smt_bits).max(smt_bits),
+    })
+}
+
+fn detect_layout_legacy() -> ApicIdLayout {
+    let features = cpuid::query(LEAF_FEATURES, 0);
+
+    // The HTT flag indicates that the logical processor count in EBX is valid
+    let logical_per_package = if features.edx & (1 << 28) != 0 {
+        (features.ebx >> 16) & 0xff
+    } else {
+        1
+    };
+    let cores_per_package = if cpuid::max_leaf() >= LEAF_CACHE_PARAMS {
+        (cpuid::query(LEAF_CACHE_PARAMS, 0).eax >> 26) + 1
+    } else {
+        1
+    };
+
+    let package_shift = bits_for_count(logical_per_package);
+
+    ApicIdLayout {
+        smt_bits: package_shift.saturating_sub(bits_for_count(cores_per_package)),
+        package_shift,
+    }
+}
+
+fn detect_layout() -> ApicIdLayout {
+    if cpuid::max_leaf() >= LEAF_EXTENDED_TOPOLOGY {
+        if let Some(layout) = detect_layout_extended() {
+            return layout;
+        }
+    }
+
+    detect_layout_legacy()
+}
+
+/// Gets the hardware ID (i.e. the initial local APIC ID) of the logical processor that this is called on.
+pub fn current_hw_id() -> u32 {
+    cpuid::query(LEAF_FEATURES, 0).ebx >> 24
+}
+
+/// Detects the processor topology of the machine.
+pub fn detect() -> CpuTopology {
+    let layout = detect_layout();
+
+    log!(
+        Debug,
+        "topology",
+        "APIC ID layout: {} thread bits, {} core bits",
+        layout.smt_bits,
+        layout.package_shift - layout.smt_bits
+    );
+
+    let cpus = match acpi::madt_processors() {
+        Some(processors) if !processors.is_empty() => processors.iter().map(|p| layout.decode(p.apic_id, p.enabled)).collect(),
+        _ => {
+            log!(
+                Warning,
+                "topology",
+                "No processors found in MADT, assuming only the bootstrap processor is present"
+            );
+            vec![layout.decode(current_hw_id(), true)]
+        },
+    };
+
+    CpuTopology::new(cpus)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_decode_apic_id() {
+        let layout = ApicIdLayout {
+            smt_bits: 1,
+            package_shift: 4,
+        };
+        let cpu = layout.decode(0b10_101_1, true);
+
+        assert_eq!(0b10, cpu.package);
+        assert_eq!(0b101, cpu.core);
+        assert_eq!(0b1, cpu.thread);
+
+        let flat = ApicIdLayout {
+            smt_bits: 0,
+            package_shift: 0,
+        };
+        let cpu = flat.decode(3, false);
+
+        assert_eq!((3, 0, 0, false), (cpu.package, cpu.core, cpu.thread, cpu.enabled));
+    }
+
+    #[test_case]
+    fn test_bits_for_count() {
+        assert_eq!(0, bits_for_count(0));
+        assert_eq!(0, bits_for_count(1));
+        assert_eq!(1, bits_for_count(2));
+        assert_eq!(2, bits_for_count(3));
+        assert_eq!(3, bits_for_count(8));
+    }
+}
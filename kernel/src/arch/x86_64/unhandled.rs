@@ -0,0 +1,225 @@
+//! Accounting for spurious interrupts and interrupts that have no handler registered.
+//!
+//! On real hardware, the legacy PICs can raise spurious IRQ7 or IRQ15 interrupts (e.g. due to electrical noise on an IRQ line), and devices
+//! that the kernel has no driver for may raise interrupts on lines that nothing has registered a handler for. Neither should bring down the
+//! system or flood the log. Spurious interrupts are simply counted, while unhandled interrupts are logged once and then at most once every
+//! [`LOG_INTERVAL`]. An IRQ line that keeps raising unhandled interrupts (as a level-triggered line with no driver will, since nothing
+//! clears the interrupt condition) is masked at the PIC until a handler is registered for it.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::interrupt::{IRQS_START, NUM_IRQS};
+use super::pic;
+use crate::log;
+use crate::sched::timer;
+use crate::sync::UninterruptibleSpinlock;
+
+/// The minimum amount of time between log messages about unhandled interrupts on the same IRQ line or vector.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The window of time over which unhandled interrupts are counted for storm detection.
+const STORM_WINDOW: Duration = Duration::from_secs(1);
+
+/// The number of unhandled interrupts on a single IRQ line within [`STORM_WINDOW`] after which the line is masked.
+const STORM_THRESHOLD: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UnhandledAction {
+    /// If a message should be logged, the number of unhandled interrupts that were not logged since the last message.
+    log: Option<u64>,
+    /// Whether the IRQ line should be masked because it is storming.
+    mask: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UnhandledTracker {
+    count: u64,
+    last_logged: Option<Duration>,
+    suppressed: u64,
+    window_start: Duration,
+    window_count: u32,
+    masked: bool,
+}
+
+impl UnhandledTracker {
+    const fn new() -> UnhandledTracker {
+        UnhandledTracker {
+            count: 0,
+            last_logged: None,
+            suppressed: 0,
+            window_start: Duration::ZERO,
+            window_count: 0,
+            masked: false,
+        }
+    }
+
+    fn record(&mut self, now: Duration) -> UnhandledAction {
+        self.count += 1;
+
+        let log = match self.last_logged {
+            Some(last_logged) if now.saturating_sub(last_logged) < LOG_INTERVAL => {
+                self.suppressed += 1;
+                None
+            },
+            _ => {
+                self.last_logged = Some(now);
+                Some(core::mem::replace(&mut self.suppressed, 0))
+            },
+        };
+
+        if now.saturating_sub(self.window_start) >= STORM_WINDOW {
+            self.window_start = now;
+            self.window_count = 0;
+        }
+
+        self.window_count += 1;
+
+        let mask = !self.masked && self.window_count >= STORM_THRESHOLD;
+
+        if mask {
+            self.masked = true;
+        }
+
+        UnhandledAction { log, mask }
+    }
+}
+
+/// Statistics about spurious and unhandled interrupts received since boot.
+#[derive(Debug, Clone)]
+pub struct UnhandledStats {
+    /// The number of spurious interrupts raised by the PICs.
+    pub spurious: u64,
+    /// The number of interrupts received on each IRQ line while no handler was registered for it.
+    pub unhandled_irqs: [u64; NUM_IRQS],
+    /// A bitmask of the IRQ lines that have been masked because they were raising too many unhandled interrupts.
+    pub storm_masked: u16,
+    /// The number of interrupts received on vectors that are neither exceptions nor IRQs and have no handler.
+    pub unknown_vectors: u64,
+}
+
+static NUM_SPURIOUS: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED_IRQS: UninterruptibleSpinlock<[UnhandledTracker; NUM_IRQS]> =
+    UninterruptibleSpinlock::new([UnhandledTracker::new(); NUM_IRQS]);
+static UNKNOWN_VECTORS: UninterruptibleSpinlock<UnhandledTracker> = UninterruptibleSpinlock::new(UnhandledTracker::new());
+
+/// Checks whether an interrupt on the provided IRQ line is a spurious interrupt raised by the PICs. If so, it is accounted for and any
+/// end-of-interrupt required by the PICs is sent, so the interrupt should not be handled further.
+///
+/// # Safety
+///
+/// This must only be called from the interrupt handler for the provided IRQ line before an end-of-interrupt has been sent for it.
+pub(super) unsafe fn check_spurious_irq(irq: u8) -> bool {
+    if !pic::is_spurious(irq) {
+        return false;
+    }
+
+    NUM_SPURIOUS.fetch_add(1, Ordering::Relaxed);
+    pic::send_spurious_eoi(irq);
+    true
+}
+
+/// Records that an interrupt was received on an IRQ line that has no handler registered, logging it and masking the line if it is storming.
+///
+/// # Safety
+///
+/// This must only be called from the interrupt handler for the provided IRQ line.
+pub(super) unsafe fn record_unhandled_irq(irq: u8) {
+    let action = UNHANDLED_IRQS.lock()[usize::from(irq)].record(timer::now());
+
+    if let Some(suppressed) = action.log {
+        if suppressed == 0 {
+            log!(Warning, "kernel", "Unhandled irq{}", irq);
+        } else {
+            log!(Warning, "kernel", "Unhandled irq{} ({} more since last reported)", irq, suppressed);
+        }
+    }
+
+    if action.mask {
+        log!(Warning, "kernel", "Masking irq{} after too many unhandled interrupts", irq);
+        pic::set_irq_masked(irq, true);
+    }
+}
+
+/// Records that an interrupt was received on a vector that has no handler, logging it if it was not logged recently.
+pub(super) fn record_unknown_vector(vector: u8) {
+    debug_assert!(vector >= IRQS_START);
+
+    let action = UNKNOWN_VECTORS.lock().record(timer::now());
+
+    if let Some(suppressed) = action.log {
+        if suppressed == 0 {
+            log!(Warning, "kernel", "Unhandled interrupt vector {:#x}", vector);
+        } else {
+            log!(
+                Warning,
+                "kernel",
+                "Unhandled interrupt vector {:#x} ({} more since last reported)",
+                vector,
+                suppressed
+            );
+        }
+    }
+}
+
+/// Resets the accounting for an IRQ line once a handler has been registered for it, so that it will be masked again if it starts storming
+/// after the handler is later unregistered.
+pub(super) fn reset_irq(irq: u8) {
+    UNHANDLED_IRQS.lock()[usize::from(irq)].masked = false;
+}
+
+/// Gets statistics about spurious and unhandled interrupts received since boot.
+pub fn stats() -> UnhandledStats {
+    let irqs = UNHANDLED_IRQS.lock();
+
+    UnhandledStats {
+        spurious: NUM_SPURIOUS.load(Ordering::Relaxed),
+        unhandled_irqs: irqs.map(|tracker| tracker.count),
+        storm_masked: irqs
+            .iter()
+            .enumerate()
+            .filter(|(_, tracker)| tracker.masked)
+            .fold(0, |mask, (irq, _)| mask | (1 << irq)),
+        unknown_vectors: UNKNOWN_VECTORS.lock().count,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_unhandled_log_rate_limit() {
+        let mut tracker = UnhandledTracker::new();
+
+        assert_eq!(Some(0), tracker.record(Duration::from_secs(5)).log);
+        assert_eq!(None, tracker.record(Duration::from_secs(6)).log);
+        assert_eq!(None, tracker.record(Duration::from_secs(14)).log);
+        assert_eq!(Some(2), tracker.record(Duration::from_secs(15)).log);
+        assert_eq!(4, tracker.count);
+    }
+
+    #[test_case]
+    fn test_unhandled_storm_masks_once() {
+        let mut tracker = UnhandledTracker::new();
+        let now = Duration::from_secs(1);
+
+        for _ in 1..STORM_THRESHOLD {
+            assert!(!tracker.record(now).mask);
+        }
+
+        assert!(tracker.record(now).mask);
+        assert!(!tracker.record(now).mask);
+        assert!(tracker.masked);
+    }
+
+    #[test_case]
+    fn test_unhandled_no_storm_across_windows() {
+        let mut tracker = UnhandledTracker::new();
+
+        for i in 0..(2 * STORM_THRESHOLD) {
+            let now = STORM_WINDOW * (i / (STORM_THRESHOLD - 1));
+            assert!(!tracker.record(now).mask);
+        }
+    }
+}
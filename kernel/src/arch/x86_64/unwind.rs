@@ -0,0 +1,729 @@
+//! Unwinding of call stacks using the DWARF call frame information in the kernel's `.eh_frame` section.
+//!
+//! The FDE covering each return address is found using the binary search table in `.eh_frame_hdr`, and its call frame instructions are
+//! executed to find where the caller's registers were saved. Functions that have no call frame information (e.g. hand-written assembly,
+//! or all functions when the kernel is built without unwind tables) are unwound by following the saved frame pointer instead, which the
+//! kernel is always compiled to maintain.
+//!
+//! Since unwinding is mostly done while the kernel is panicking, all reads of stack memory go through a caller-provided function so that
+//! a corrupted stack can't cause a page fault.
+
+use core::arch::asm;
+use core::ptr;
+
+use super::regs::{GeneralRegister, SavedBasicRegisters};
+
+/// The number of registers tracked while unwinding, which are the general-purpose registers followed by the return address.
+const NUM_REGS: usize = 17;
+
+const REG_RBP: usize = 6;
+const REG_RSP: usize = 7;
+const REG_RA: usize = 16;
+
+/// The general-purpose registers in the order given by their DWARF register numbers.
+const DWARF_GPRS: [GeneralRegister; 16] = [
+    GeneralRegister::Rax,
+    GeneralRegister::Rdx,
+    GeneralRegister::Rcx,
+    GeneralRegister::Rbx,
+    GeneralRegister::Rsi,
+    GeneralRegister::Rdi,
+    GeneralRegister::Rbp,
+    GeneralRegister::Rsp,
+    GeneralRegister::R8,
+    GeneralRegister::R9,
+    GeneralRegister::R10,
+    GeneralRegister::R11,
+    GeneralRegister::R12,
+    GeneralRegister::R13,
+    GeneralRegister::R14,
+    GeneralRegister::R15,
+];
+
+/// The maximum number of frames that a [`Backtrace`] will walk before giving up, in case the stack contains a loop.
+const MAX_FRAMES: usize = 128;
+
+/// The maximum depth of the stack of rows saved by `DW_CFA_remember_state`.
+const MAX_REMEMBERED_ROWS: usize = 4;
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_DATAREL_SDATA4: u8 = 0x3b;
+
+extern "C" {
+    static __eh_frame_hdr_start: u8;
+    static __eh_frame_hdr_end: u8;
+}
+
+/// The register state of a single frame while unwinding. Registers whose values are not known are [`None`].
+#[derive(Debug, Clone)]
+pub struct UnwindRegs([Option<u64>; NUM_REGS]);
+
+impl UnwindRegs {
+    /// Gets the register state at the point where this is called, which will be the first frame of a backtrace started from it.
+    #[inline(always)]
+    pub fn current() -> UnwindRegs {
+        let (rip, rsp, rbp, rbx, r12, r13, r14, r15): (u64, u64, u64, u64, u64, u64, u64, u64);
+
+        // SAFETY: Reading registers has no side effects
+        unsafe {
+            asm!(
+                "lea {}, [rip]",
+                "mov {}, rsp",
+                "mov {}, rbp",
+                "mov {}, rbx",
+                "mov {}, r12",
+                "mov {}, r13",
+                "mov {}, r14",
+                "mov {}, r15",
+                out(reg) rip,
+                out(reg) rsp,
+                out(reg) rbp,
+                out(reg) rbx,
+                out(reg) r12,
+                out(reg) r13,
+                out(reg) r14,
+                out(reg) r15,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+
+        let mut regs = [None; NUM_REGS];
+
+        regs[3] = Some(rbx);
+        regs[REG_RBP] = Some(rbp);
+        regs[REG_RSP] = Some(rsp);
+        regs[12] = Some(r12);
+        regs[13] = Some(r13);
+        regs[14] = Some(r14);
+        regs[15] = Some(r15);
+        regs[REG_RA] = Some(rip);
+
+        UnwindRegs(regs)
+    }
+
+    /// Gets the register state saved for a thread that is not currently running.
+    pub fn from_saved(saved: &SavedBasicRegisters) -> UnwindRegs {
+        let mut regs = [None; NUM_REGS];
+
+        for (i, reg) in DWARF_GPRS.into_iter().enumerate() {
+            regs[i] = Some(saved.gpr(reg));
+        }
+
+        regs[REG_RA] = Some(saved.rip);
+        UnwindRegs(regs)
+    }
+}
+
+/// A bounds-checked reader over call frame information in the kernel image.
+struct Reader {
+    pos: *const u8,
+    end: *const u8,
+}
+
+impl Reader {
+    fn new(start: *const u8, end: *const u8) -> Reader {
+        Reader { pos: start, end }
+    }
+
+    fn addr(&self) -> u64 {
+        self.pos as u64
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.end
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if (self.end as usize).saturating_sub(self.pos as usize) < N {
+            return None;
+        }
+
+        // SAFETY: The bytes were just checked to be within the section being read, which is part of the kernel image
+        let val = unsafe { ptr::read_unaligned(self.pos as *const [u8; N]) };
+
+        self.pos = self.pos.wrapping_add(N);
+        Some(val)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn skip(&mut self, n: u64) -> Option<()> {
+        if ((self.end as usize).saturating_sub(self.pos as usize) as u64) < n {
+            return None;
+        }
+
+        self.pos = self.pos.wrapping_add(n as usize);
+        Some(())
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut val = 0;
+        let mut shift = 0;
+
+        loop {
+            let b = self.u8()?;
+
+            if shift < 64 {
+                val |= u64::from(b & 0x7f) << shift;
+            }
+
+            shift += 7;
+
+            if b & 0x80 == 0 {
+                return Some(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut val = 0;
+        let mut shift = 0;
+
+        loop {
+            let b = self.u8()?;
+
+            if shift < 64 {
+                val |= i64::from(b & 0x7f) << shift;
+            }
+
+            shift += 7;
+
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+
+                return Some(val);
+            }
+        }
+    }
+
+    /// Reads a pointer encoded using one of the `DW_EH_PE_*` encodings. Indirect pointers are not supported.
+    fn encoded(&mut self, enc: u8, datarel_base: u64) -> Option<u64> {
+        let base = match enc & 0x70 {
+            0x00 => 0,
+            0x10 => self.addr(),
+            0x30 => datarel_base,
+            _ => return None,
+        };
+
+        let val = match enc & 0x0f {
+            0x00 | 0x04 => self.u64()?,
+            0x01 => self.uleb()?,
+            0x02 => u64::from(self.u16()?),
+            0x03 => u64::from(self.u32()?),
+            0x09 => self.sleb()? as u64,
+            0x0a => self.u16()? as i16 as u64,
+            0x0b => self.u32()? as i32 as u64,
+            0x0c => self.u64()?,
+            _ => return None,
+        };
+
+        if enc & 0x80 != 0 {
+            return None;
+        }
+
+        Some(base.wrapping_add(val))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RegRule {
+    Undefined,
+    SameValue,
+    Offset(i64),
+    ValOffset(i64),
+    Register(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    cfa_reg: u16,
+    cfa_offset: i64,
+    regs: [RegRule; NUM_REGS],
+}
+
+impl Row {
+    fn new() -> Row {
+        Row {
+            cfa_reg: REG_RSP as u16,
+            cfa_offset: 8,
+            regs: [RegRule::SameValue; NUM_REGS],
+        }
+    }
+
+    fn set(&mut self, reg: u64, rule: RegRule) {
+        // Rules for registers that aren't tracked (e.g. vector registers) don't matter when only unwinding
+        if let Some(r) = self.regs.get_mut(reg as usize) {
+            *r = rule;
+        }
+    }
+}
+
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    ra_reg: u64,
+    fde_enc: u8,
+    has_aug_data: bool,
+    instructions: Reader,
+}
+
+struct Fde {
+    cie: Cie,
+    pc_begin: u64,
+    pc_end: u64,
+    instructions: Reader,
+}
+
+/// Reads the length of a CIE or FDE, returning a reader for its contents. Entries using the 64-bit DWARF format are not supported.
+fn entry(addr: *const u8) -> Option<Reader> {
+    let mut r = Reader::new(addr, addr.wrapping_add(4));
+    let len = r.u32()?;
+
+    if len == 0 || len == 0xffff_ffff {
+        return None;
+    }
+
+    Some(Reader::new(r.pos, r.pos.wrapping_add(len as usize)))
+}
+
+fn parse_cie(addr: *const u8) -> Option<Cie> {
+    let mut r = entry(addr)?;
+
+    if r.u32()? != 0 {
+        return None;
+    }
+
+    let version = r.u8()?;
+    let mut aug = [0u8; 8];
+    let mut aug_len = 0;
+
+    loop {
+        match r.u8()? {
+            0 => break,
+            _ if aug_len == aug.len() => return None,
+            c => {
+                aug[aug_len] = c;
+                aug_len += 1;
+            },
+        }
+    }
+
+    let aug = &aug[..aug_len];
+
+    if aug.starts_with(b"eh") {
+        r.skip(8)?;
+    }
+
+    let code_align = r.uleb()?;
+    let data_align = r.sleb()?;
+    let ra_reg = if version == 1 { u64::from(r.u8()?) } else { r.uleb()? };
+    let mut fde_enc = 0;
+    let has_aug_data = aug.first() == Some(&b'z');
+
+    if has_aug_data {
+        let aug_data_len = r.uleb()?;
+        let aug_data_end = r.pos.wrapping_add(aug_data_len as usize);
+
+        for &c in &aug[1..] {
+            match c {
+                b'R' => fde_enc = r.u8()?,
+                b'P' => {
+                    let enc = r.u8()?;
+                    r.encoded(enc & 0x7f, 0)?;
+                },
+                b'L' => {
+                    r.u8()?;
+                },
+                b'S' => {},
+                _ => break,
+            }
+        }
+
+        r.pos = aug_data_end;
+    }
+
+    Some(Cie {
+        code_align,
+        data_align,
+        ra_reg,
+        fde_enc,
+        has_aug_data,
+        instructions: r,
+    })
+}
+
+fn parse_fde(addr: *const u8) -> Option<Fde> {
+    let mut r = entry(addr)?;
+    let cie_ptr_addr = r.pos;
+    let cie_offset = r.u32()?;
+
+    if cie_offset == 0 {
+        return None;
+    }
+
+    let cie = parse_cie(cie_ptr_addr.wrapping_sub(cie_offset as usize))?;
+    let pc_begin = r.encoded(cie.fde_enc, 0)?;
+    let pc_range = r.encoded(cie.fde_enc & 0x0f, 0)?;
+
+    if cie.has_aug_data {
+        let len = r.uleb()?;
+        r.skip(len)?;
+    }
+
+    Some(Fde {
+        cie,
+        pc_begin,
+        pc_end: pc_begin.wrapping_add(pc_range),
+        instructions: r,
+    })
+}
+
+/// Finds the FDE covering the provided instruction address using the binary search table in `.eh_frame_hdr`.
+fn find_fde(pc: u64) -> Option<Fde> {
+    // SAFETY: These symbols are placed by the linker script and are only used to find the bounds of the section
+    let (hdr_start, hdr_end) = unsafe { (ptr::addr_of!(__eh_frame_hdr_start), ptr::addr_of!(__eh_frame_hdr_end)) };
+    let hdr = hdr_start as u64;
+    let mut r = Reader::new(hdr_start, hdr_end);
+
+    if r.u8()? != 1 {
+        return None;
+    }
+
+    let eh_frame_ptr_enc = r.u8()?;
+    let fde_count_enc = r.u8()?;
+    let table_enc = r.u8()?;
+
+    if eh_frame_ptr_enc == DW_EH_PE_OMIT || fde_count_enc == DW_EH_PE_OMIT || table_enc != DW_EH_PE_DATAREL_SDATA4 {
+        return None;
+    }
+
+    r.encoded(eh_frame_ptr_enc, hdr)?;
+    let count = r.encoded(fde_count_enc, hdr)? as usize;
+    let table = r.pos;
+
+    r.skip(count as u64 * 8)?;
+
+    let entry_at = |i: usize| {
+        let mut e = Reader::new(table.wrapping_add(i * 8), table.wrapping_add(i * 8 + 8));
+
+        (
+            hdr.wrapping_add(e.u32().unwrap() as i32 as u64),
+            hdr.wrapping_add(e.u32().unwrap() as i32 as u64),
+        )
+    };
+
+    // Find the last entry whose initial location is not after the provided address
+    let (mut lo, mut hi) = (0, count);
+
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+
+        if entry_at(mid).0 <= pc {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    let (_, fde_addr) = entry_at(lo.checked_sub(1)?);
+    let fde = parse_fde(fde_addr as *const u8)?;
+
+    (fde.pc_begin..fde.pc_end).contains(&pc).then_some(fde)
+}
+
+/// Executes call frame instructions, updating the provided row until the location passes the target address.
+fn execute(mut r: Reader, cie: &Cie, mut loc: u64, target: u64, row: &mut Row, initial: &Row) -> Option<()> {
+    let mut remembered = [Row::new(); MAX_REMEMBERED_ROWS];
+    let mut num_remembered = 0;
+
+    while !r.is_empty() {
+        let op = r.u8()?;
+        let low = u64::from(op & 0x3f);
+
+        let advance = match op & 0xc0 {
+            0x40 => Some(low),
+            0x80 => {
+                row.set(low, RegRule::Offset(r.uleb()? as i64 * cie.data_align));
+                None
+            },
+            0xc0 => {
+                row.set(low, initial.regs.get(low as usize).copied().unwrap_or(RegRule::SameValue));
+                None
+            },
+            _ => match op {
+                0x00 => None,
+                0x01 => {
+                    loc = r.encoded(cie.fde_enc, 0)?;
+
+                    if loc > target {
+                        return Some(());
+                    }
+
+                    None
+                },
+                0x02 => Some(u64::from(r.u8()?)),
+                0x03 => Some(u64::from(r.u16()?)),
+                0x04 => Some(u64::from(r.u32()?)),
+                0x05 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, RegRule::Offset(r.uleb()? as i64 * cie.data_align));
+                    None
+                },
+                0x06 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, initial.regs.get(reg as usize).copied().unwrap_or(RegRule::SameValue));
+                    None
+                },
+                0x07 => {
+                    row.set(r.uleb()?, RegRule::Undefined);
+                    None
+                },
+                0x08 => {
+                    row.set(r.uleb()?, RegRule::SameValue);
+                    None
+                },
+                0x09 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, RegRule::Register(r.uleb()? as u16));
+                    None
+                },
+                0x0a => {
+                    *remembered.get_mut(num_remembered)? = *row;
+                    num_remembered += 1;
+                    None
+                },
+                0x0b => {
+                    num_remembered = num_remembered.checked_sub(1)?;
+
+                    // The CFA is not part of the remembered state
+                    let (cfa_reg, cfa_offset) = (row.cfa_reg, row.cfa_offset);
+
+                    *row = remembered[num_remembered];
+                    row.cfa_reg = cfa_reg;
+                    row.cfa_offset = cfa_offset;
+                    None
+                },
+                0x0c => {
+                    row.cfa_reg = r.uleb()? as u16;
+                    row.cfa_offset = r.uleb()? as i64;
+                    None
+                },
+                0x0d => {
+                    row.cfa_reg = r.uleb()? as u16;
+                    None
+                },
+                0x0e => {
+                    row.cfa_offset = r.uleb()? as i64;
+                    None
+                },
+                0x11 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, RegRule::Offset(r.sleb()? * cie.data_align));
+                    None
+                },
+                0x12 => {
+                    row.cfa_reg = r.uleb()? as u16;
+                    row.cfa_offset = r.sleb()? * cie.data_align;
+                    None
+                },
+                0x13 => {
+                    row.cfa_offset = r.sleb()? * cie.data_align;
+                    None
+                },
+                0x14 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, RegRule::ValOffset(r.uleb()? as i64 * cie.data_align));
+                    None
+                },
+                0x15 => {
+                    let reg = r.uleb()?;
+                    row.set(reg, RegRule::ValOffset(r.sleb()? * cie.data_align));
+                    None
+                },
+                0x2e => {
+                    r.uleb()?;
+                    None
+                },
+                0x2f => {
+                    let reg = r.uleb()?;
+                    row.set(reg, RegRule::Offset(-(r.uleb()? as i64) * cie.data_align));
+                    None
+                },
+                // DWARF expressions are never emitted for the kernel's own code, so they aren't supported
+                _ => return None,
+            },
+        };
+
+        if let Some(delta) = advance {
+            loc = loc.wrapping_add(delta * cie.code_align);
+
+            if loc > target {
+                return Some(());
+            }
+        }
+    }
+
+    Some(())
+}
+
+/// Unwinds a single frame using call frame information, returning the register state of the caller.
+fn step_cfi(regs: &UnwindRegs, fde: &Fde, pc: u64, read: &mut impl FnMut(u64) -> Option<u64>) -> Option<UnwindRegs> {
+    if fde.cie.ra_reg != REG_RA as u64 {
+        return None;
+    }
+
+    let mut initial = Row::new();
+    let cie_instructions = Reader::new(fde.cie.instructions.pos, fde.cie.instructions.end);
+
+    execute(cie_instructions, &fde.cie, fde.pc_begin, u64::MAX, &mut initial, &Row::new())?;
+
+    let mut row = initial;
+    let fde_instructions = Reader::new(fde.instructions.pos, fde.instructions.end);
+
+    execute(fde_instructions, &fde.cie, fde.pc_begin, pc, &mut row, &initial)?;
+
+    let cfa = regs.0.get(row.cfa_reg as usize).copied()??.wrapping_add(row.cfa_offset as u64);
+    let mut next = [None; NUM_REGS];
+
+    for (i, rule) in row.regs.iter().enumerate() {
+        next[i] = match *rule {
+            RegRule::Undefined => None,
+            RegRule::SameValue => regs.0[i],
+            RegRule::Offset(off) => read(cfa.wrapping_add(off as u64)),
+            RegRule::ValOffset(off) => Some(cfa.wrapping_add(off as u64)),
+            RegRule::Register(reg) => regs.0.get(reg as usize).copied().flatten(),
+        };
+    }
+
+    next[REG_RSP] = Some(cfa);
+    Some(UnwindRegs(next))
+}
+
+/// Unwinds a single frame by following the saved frame pointer, returning the register state of the caller.
+fn step_frame_pointer(regs: &UnwindRegs, read: &mut impl FnMut(u64) -> Option<u64>) -> Option<UnwindRegs> {
+    let rbp = regs.0[REG_RBP]?;
+
+    if rbp == 0 || rbp % 8 != 0 {
+        return None;
+    }
+
+    let mut next = [None; NUM_REGS];
+
+    next[REG_RBP] = Some(read(rbp)?);
+    next[REG_RA] = Some(read(rbp + 8)?);
+    next[REG_RSP] = Some(rbp + 16);
+
+    Some(UnwindRegs(next))
+}
+
+/// An iterator over the instruction addresses of the frames on a call stack, starting with the address at which the first frame is
+/// executing followed by the return address of each frame.
+pub struct Backtrace<F: FnMut(u64) -> Option<u64>> {
+    regs: Option<UnwindRegs>,
+    read: F,
+    first: bool,
+    depth: usize,
+}
+
+impl<F: FnMut(u64) -> Option<u64>> Backtrace<F> {
+    /// Creates a backtrace starting from the provided register state. Stack memory is read using the provided function, which should
+    /// return [`None`] for addresses that cannot be safely read.
+    pub fn new(regs: UnwindRegs, read: F) -> Backtrace<F> {
+        Backtrace {
+            regs: Some(regs),
+            read,
+            first: true,
+            depth: 0,
+        }
+    }
+}
+
+impl<F: FnMut(u64) -> Option<u64>> Iterator for Backtrace<F> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let regs = self.regs.take()?;
+        let pc = regs.0[REG_RA].filter(|&pc| pc != 0)?;
+
+        if self.depth == MAX_FRAMES {
+            return None;
+        }
+
+        // Return addresses point after the call instruction, which may be the first instruction of a different function (or past the end
+        // of a function that never returns), so the call instruction itself is looked up instead
+        let lookup_pc = if self.first { pc } else { pc - 1 };
+        let next = match find_fde(lookup_pc) {
+            Some(fde) => step_cfi(&regs, &fde, lookup_pc, &mut self.read),
+            None => step_frame_pointer(&regs, &mut self.read),
+        };
+
+        // Each frame must be further up the stack than the last, otherwise a corrupted stack could cause an infinite loop
+        self.regs = next.filter(|next| matches!((next.0[REG_RSP], regs.0[REG_RSP]), (Some(new), Some(old)) if new > old));
+        self.first = false;
+        self.depth += 1;
+
+        Some(pc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn nested_backtrace(out: &mut [u64]) -> usize {
+        let bt = Backtrace::new(UnwindRegs::current(), |addr| {
+            let mut buf = [0; 8];
+
+            (crate::mem::read_kernel_mem(addr, &mut buf) == 8).then(|| u64::from_le_bytes(buf))
+        });
+        let mut n = 0;
+
+        for (slot, pc) in out.iter_mut().zip(bt) {
+            *slot = pc;
+            n += 1;
+        }
+
+        n
+    }
+
+    #[test_case]
+    fn test_backtrace_current() {
+        let mut out = [0; 8];
+        let n = nested_backtrace(&mut out);
+        let this_fn = test_backtrace_current as usize as u64;
+        let nested_fn = nested_backtrace as usize as u64;
+
+        assert!(n >= 2);
+        assert!((nested_fn..nested_fn + 0x1000).contains(&out[0]));
+        assert!((this_fn..this_fn + 0x1000).contains(&out[1]));
+    }
+
+    #[test_case]
+    fn test_reader_leb128() {
+        let bytes = [0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f];
+        let mut r = Reader::new(bytes.as_ptr(), bytes.as_ptr().wrapping_add(bytes.len()));
+
+        assert_eq!(Some(624485), r.uleb());
+        assert_eq!(Some(-1), r.sleb());
+        assert_eq!(Some(-128), r.sleb());
+        assert!(r.is_empty());
+        assert_eq!(None, r.u8());
+    }
+}
@@ -0,0 +1,204 @@
+//! Tracking of how long each stage of the boot process takes.
+//!
+//! Milestones are recorded using the CPU's cycle counter, since the system timer is not started until late in the boot process. Once boot
+//! has finished, the cycle counter is calibrated against the system timer so that the timeline can be reported in real time units.
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+use crate::sched::timer;
+use crate::sync::UninterruptibleSpinlock;
+use crate::{arch, log};
+
+const CALIBRATION_TIME: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+struct Milestone {
+    name: &'static str,
+    cycles: u64,
+}
+
+struct BootTimeline {
+    start_cycles: u64,
+    milestones: Vec<Milestone>,
+    calibration_start: Option<(u64, Duration)>,
+    cycles_per_sec: Option<u64>,
+}
+
+static TIMELINE: UninterruptibleSpinlock<BootTimeline> = UninterruptibleSpinlock::new(BootTimeline {
+    start_cycles: 0,
+    milestones: Vec::new(),
+    calibration_start: None,
+    cycles_per_sec: None,
+});
+
+/// Records the point in time at which the kernel started booting. All milestones are reported relative to this point.
+///
+/// # Safety
+///
+/// This function should only be called once from the bootstrap processor at the very start of the boot process.
+pub(crate) unsafe fn start() {
+    TIMELINE.lock().start_cycles = arch::cycle_counter();
+}
+
+/// Records that the boot process has reached a milestone, which usually marks the completion of a subsystem's initialization.
+pub fn mark(name: &'static str) {
+    let cycles = arch::cycle_counter();
+
+    TIMELINE.lock().milestones.push(Milestone { name, cycles });
+}
+
+/// Records that the boot process has finished. Once enough time has passed to calibrate the cycle counter against the system timer, the
+/// boot timeline is written to the log.
+///
+/// # Panics
+///
+/// This function will panic if called more than once or before the system timer has been started.
+pub fn finish() {
+    mark("boot complete");
+
+    let mut timeline = TIMELINE.lock();
+
+    assert!(timeline.calibration_start.is_none());
+    timeline.calibration_start = Some((arch::cycle_counter(), timer::now()));
+    drop(timeline);
+
+    timer::after(CALIBRATION_TIME).when_resolved(|_| {
+        calibrate();
+
+        for line in format!("{}", get()).lines() {
+            log!(Info, "boottime", "{}", line);
+        }
+    });
+}
+
+fn calibrate() {
+    let mut timeline = TIMELINE.lock();
+    let (start_cycles, start_time) = timeline.calibration_start.unwrap();
+    let elapsed = timer::now().saturating_sub(start_time).as_nanos();
+
+    if elapsed != 0 {
+        let cycles = u128::from(arch::cycle_counter().wrapping_sub(start_cycles));
+
+        timeline.cycles_per_sec = Some((cycles * 1_000_000_000 / elapsed) as u64);
+    }
+}
+
+/// A snapshot of the milestones reached during the boot process.
+#[derive(Debug, Clone)]
+pub struct BootTimelineSnapshot {
+    start_cycles: u64,
+    milestones: Vec<Milestone>,
+    cycles_per_sec: Option<u64>,
+}
+
+impl BootTimelineSnapshot {
+    fn since_start(&self, cycles: u64) -> u64 {
+        cycles.wrapping_sub(self.start_cycles)
+    }
+
+    fn to_duration(&self, cycles: u64) -> Option<Duration> {
+        let cycles_per_sec = self.cycles_per_sec.filter(|&c| c != 0)?;
+
+        Some(Duration::from_nanos(
+            (u128::from(cycles) * 1_000_000_000 / u128::from(cycles_per_sec)) as u64,
+        ))
+    }
+
+    /// Gets the milestones reached so far, along with the amount of time since the start of the boot process at which each milestone was
+    /// reached and the amount of time since the previous milestone. Times are [`None`] if the cycle counter has not been calibrated yet.
+    pub fn milestones(&self) -> impl Iterator<Item = (&'static str, Option<Duration>, Option<Duration>)> + '_ {
+        let mut prev_cycles = self.start_cycles;
+
+        self.milestones.iter().map(move |m| {
+            let step = m.cycles.wrapping_sub(prev_cycles);
+
+            prev_cycles = m.cycles;
+            (m.name, self.to_duration(self.since_start(m.cycles)), self.to_duration(step))
+        })
+    }
+}
+
+impl fmt::Display for BootTimelineSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cycles_per_sec.is_none() {
+            writeln!(f, "{:>14} {:>14}  MILESTONE", "CYCLES", "STEP")?;
+
+            let mut prev_cycles = self.start_cycles;
+
+            for m in self.milestones.iter() {
+                writeln!(
+                    f,
+                    "{:>14} {:>14}  {}",
+                    self.since_start(m.cycles),
+                    m.cycles.wrapping_sub(prev_cycles),
+                    m.name
+                )?;
+                prev_cycles = m.cycles;
+            }
+        } else {
+            writeln!(f, "{:>10} {:>10}  MILESTONE", "TIME", "STEP")?;
+
+            for (name, at, step) in self.milestones() {
+                let at = at.unwrap_or_default();
+                let step = step.unwrap_or_default();
+
+                writeln!(
+                    f,
+                    "{:>6}.{:03} {:>6}.{:03}  {}",
+                    at.as_millis(),
+                    at.subsec_micros() % 1000,
+                    step.as_millis(),
+                    step.subsec_micros() % 1000,
+                    name
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Gets a snapshot of the milestones reached during the boot process so far.
+pub fn get() -> BootTimelineSnapshot {
+    let timeline = TIMELINE.lock();
+
+    BootTimelineSnapshot {
+        start_cycles: timeline.start_cycles,
+        milestones: timeline.milestones.clone(),
+        cycles_per_sec: timeline.cycles_per_sec,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test_case]
+    fn test_timeline_durations() {
+        let timeline = BootTimelineSnapshot {
+            start_cycles: 1000,
+            milestones: vec![Milestone { name: "a", cycles: 3000 }, Milestone { name: "b", cycles: 4000 }],
+            cycles_per_sec: Some(1_000_000),
+        };
+
+        assert_eq!(
+            vec![
+                ("a", Some(Duration::from_millis(2)), Some(Duration::from_millis(2))),
+                ("b", Some(Duration::from_millis(3)), Some(Duration::from_millis(1))),
+            ],
+            timeline.milestones().collect::<Vec<_>>()
+        );
+
+        let uncalibrated = BootTimelineSnapshot {
+            cycles_per_sec: None,
+            ..timeline
+        };
+
+        assert_eq!(None, uncalibrated.milestones().next().unwrap().1);
+    }
+}
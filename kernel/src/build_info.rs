@@ -0,0 +1,63 @@
+//! Information about how the running kernel image was built.
+//!
+//! This is embedded into the image by the build script so that logs and crash reports can be traced back to the exact binary that
+//! produced them, and is displayed in the boot log, on the panic screen and by the `version` debug console command.
+
+use core::fmt;
+
+/// The version of the kernel crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The abbreviated hash of the git commit the kernel was built from, suffixed with `-dirty` if tracked files had uncommitted changes, or
+/// `unknown` if the kernel was not built from a git checkout.
+pub const GIT_COMMIT: &str = env!("HYDROXOS_GIT_COMMIT");
+
+/// The cargo profile the kernel was built with (e.g. `debug` or `release`).
+pub const PROFILE: &str = env!("HYDROXOS_PROFILE");
+
+/// The version string reported by the compiler used to build the kernel.
+pub const RUSTC_VERSION: &str = env!("HYDROXOS_RUSTC_VERSION");
+
+/// The cargo features that were enabled when building the kernel, as a comma-separated list.
+pub const FEATURES: &str = env!("HYDROXOS_FEATURES");
+
+/// Gets the names of the cargo features that were enabled when building the kernel. Note that cargo reports feature names in lowercase with
+/// dashes replaced by underscores.
+pub fn features() -> impl Iterator<Item = &'static str> {
+    FEATURES.split(',').filter(|f| !f.is_empty())
+}
+
+/// Checks whether the cargo feature with the provided name was enabled when building the kernel.
+pub fn is_feature_enabled(name: &str) -> bool {
+    let normalize = |b: u8| if b == b'-' { b'_' } else { b.to_ascii_lowercase() };
+
+    features().any(|f| f.len() == name.len() && f.bytes().zip(name.bytes()).all(|(a, b)| a == normalize(b)))
+}
+
+/// A one-line summary identifying the running kernel image, suitable for headers of logs and crash reports.
+#[derive(Debug, Clone, Copy)]
+pub struct BuildSummary;
+
+impl fmt::Display for BuildSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HydroxOS v{} ({}, {})", VERSION, GIT_COMMIT, PROFILE)
+    }
+}
+
+/// Gets a one-line summary identifying the running kernel image.
+pub fn summary() -> BuildSummary {
+    BuildSummary
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_features() {
+        assert_eq!(cfg!(feature = "real_arch_api"), is_feature_enabled("real_arch_api"));
+        assert_eq!(cfg!(feature = "spinlock_tracking"), is_feature_enabled("spinlock-tracking"));
+        assert!(!is_feature_enabled("not_a_feature"));
+        assert!(features().all(|f| !f.is_empty() && !f.contains(',')));
+    }
+}
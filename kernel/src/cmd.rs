@@ -1,20 +1,142 @@
 // Many functions here are accessing lists of arguments and I'd prefer to be consistent in how elements are accessed
 #![allow(clippy::get_first)]
 
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::{format, vec};
 use core::fmt::{self, Write};
 
+use crate::arch::interrupt;
 use crate::io::dev;
-use crate::io::tty::{Tty, TtyCharReader, TtyWriter};
-use crate::sched::task::Process;
+use crate::io::tty::{Tty, TtyCapabilities, TtyCharReader, TtyWriter};
+use crate::mem::region::RegionUsage;
+use crate::sched::task::{self, Process, ThreadCpuStats};
+use crate::sched::timer;
+use crate::shutdown::{self, ShutdownKind};
+use crate::sync::Future;
 use crate::util::ArrayDeque;
 
+// TODO Persist the history to a file across reboots once there's a VFS to store it in
 struct CommandHistory {
     buf: ArrayDeque<String, 64>,
 }
 
+impl CommandHistory {
+    fn push(&mut self, cmd: &str) {
+        if cmd.is_empty() || self.buf.back().is_some_and(|last| last == cmd) {
+            return;
+        }
+
+        if self.buf.is_full() {
+            self.buf.pop_front();
+        }
+
+        assert!(self.buf.push_back(String::from(cmd)).is_ok());
+    }
+
+    /// Finds the most recent history entry before index `before` that contains `query`.
+    fn search(&self, query: &str, before: usize) -> Option<usize> {
+        if query.is_empty() {
+            return None;
+        }
+
+        (0..before).rev().find(|&idx| self.buf.get(idx).unwrap().contains(query))
+    }
+}
+
+fn move_cursor<W: Write>(w: &mut W, from: usize, to: usize) {
+    if to < from {
+        let _ = write!(w, "\x1b[{}D", from - to);
+    } else if to > from {
+        let _ = write!(w, "\x1b[{}C", to - from);
+    }
+}
+
+fn replace_line<W: Write>(w: &mut W, s: &mut String, i: &mut usize, new: String) {
+    move_cursor(w, *i, 0);
+    let _ = write!(w, "\x1b[K{}", new);
+
+    *s = new;
+    *i = s.len();
+}
+
+fn delete_range<W: Write>(w: &mut W, s: &mut String, i: &mut usize, start: usize, end: usize) {
+    if start == end {
+        return;
+    }
+
+    move_cursor(w, *i, start);
+    s.replace_range(start..end, "");
+    *i = start;
+
+    let _ = write!(w, "\x1b[K{}", &s[start..]);
+    move_cursor(w, s.len(), start);
+}
+
+/// Runs an interactive reverse search through the command history, as started by pressing Ctrl+R. Returns `true` if the line that was
+/// found should be run immediately, or `false` if it should be edited further.
+fn reverse_search<T: Tty + ?Sized>(
+    r: &mut TtyCharReader<T>,
+    w: &mut TtyWriter<T>,
+    history: &CommandHistory,
+    s: &mut String,
+    i: &mut usize,
+) -> Result<bool, ()> {
+    let mut query = String::new();
+    let mut found = None;
+
+    move_cursor(w, *i, 0);
+    let _ = write!(w, "\x1b[K");
+
+    let run = loop {
+        let shown = found.map_or(s.as_str(), |idx| history.buf.get(idx).unwrap().as_str());
+        let prompt = format!("(reverse-i-search)'{}': {}", query, shown);
+        let _ = write!(w, "{}", prompt);
+
+        let ch = r.next_char().map_err(|_| ())?;
+        let _ = write!(w, "\x1b[{}D\x1b[K", prompt.len());
+
+        match ch {
+            '\n' => break true,
+            '\x12' => {
+                found = history.search(&query, found.unwrap_or(history.buf.len())).or(found);
+            },
+            '\x7f' => {
+                query.pop();
+                found = history.search(&query, history.buf.len());
+            },
+            '\x07' => {
+                found = None;
+                break false;
+            },
+            '\x1b' => {
+                // Escape sequences such as arrow keys end the search, but shouldn't leave the rest of the sequence to be inserted as text
+                if r.next_char().map_err(|_| ())? == '[' {
+                    r.next_char().map_err(|_| ())?;
+                }
+
+                break false;
+            },
+            '\x00'..='\x1f' => break false,
+            ch if ch.is_ascii() => {
+                query.push(ch);
+                found = history.search(&query, found.map_or(history.buf.len(), |idx| idx + 1));
+            },
+            _ => {},
+        }
+    };
+
+    if let Some(idx) = found {
+        *s = history.buf.get(idx).unwrap().clone();
+    }
+
+    *i = s.len();
+    let _ = write!(w, "{}", s);
+
+    Ok(run)
+}
+
 fn readline<T: Tty + ?Sized>(r: &mut TtyCharReader<T>, w: &mut TtyWriter<T>, history: &mut CommandHistory) -> Result<String, String> {
     let mut history_pos = history.buf.len();
     let mut history_modified = [const { None }; 65];
@@ -27,83 +149,108 @@ fn readline<T: Tty + ?Sized>(r: &mut TtyCharReader<T>, w: &mut TtyWriter<T>, his
     loop {
         match r.next_char() {
             Ok('\n') => {
-                if i != s.len() {
-                    let _ = write!(w, "\x1b[{}C", s.len() - i);
-                }
-
-                if history.buf.is_full() {
-                    history.buf.pop_front();
-                }
-
-                assert!(history.buf.push_back(s.clone()).is_ok());
+                move_cursor(w, i, s.len());
+                history.push(&s);
 
                 let _ = writeln!(w);
                 return Ok(s);
             },
             Ok('\x7f') => {
                 if i != 0 {
-                    s.remove(i - 1);
-                    i -= 1;
-
-                    let _ = write!(w, "\x1b[D");
-                    let _ = write!(w, "{}", &s[i..]);
-                    let _ = write!(w, " \x1b[{}D", s.len() - i + 1);
+                    delete_range(w, &mut s, &mut i, i - 1, i);
                 }
             },
+            Ok('\x01') => {
+                move_cursor(w, i, 0);
+                i = 0;
+            },
+            Ok('\x05') => {
+                move_cursor(w, i, s.len());
+                i = s.len();
+            },
+            Ok('\x0b') => {
+                let end = s.len();
+                delete_range(w, &mut s, &mut i, i, end);
+            },
+            Ok('\x15') => {
+                delete_range(w, &mut s, &mut i, 0, i);
+            },
+            Ok('\x17') => {
+                let start = s[..i].trim_end_matches(' ').rfind(' ').map_or(0, |idx| idx + 1);
+                delete_range(w, &mut s, &mut i, start, i);
+            },
+            Ok('\x12') => match reverse_search(r, w, history, &mut s, &mut i) {
+                Ok(true) => {
+                    history.push(&s);
+
+                    let _ = writeln!(w);
+                    return Ok(s);
+                },
+                Ok(false) => {},
+                Err(()) => {
+                    return Err(s);
+                },
+            },
             Ok('\x1b') => match r.next_char() {
                 Ok('[') => match r.next_char() {
-                    Ok('A') => {
-                        if history_pos != 0 {
-                            if !s.is_empty() {
-                                let _ = write!(w, "\x1b[{}D", i);
-                                let _ = write!(w, "\x1b[K");
-                            }
-
-                            history_modified[history_pos] = Some(s);
-                            history_pos -= 1;
+                    Ok(dir @ ('A' | 'B')) => {
+                        let new_pos = if dir == 'A' {
+                            history_pos.checked_sub(1)
+                        } else if history_pos != history.buf.len() {
+                            Some(history_pos + 1)
+                        } else {
+                            None
+                        };
 
-                            if let Some(modified) = history_modified[history_pos].take() {
-                                s = modified;
-                            } else {
-                                s = history.buf.get(history_pos).unwrap().clone();
-                            }
-
-                            i = s.len();
-                            let _ = write!(w, "{}", s);
-                        }
-                    },
-                    Ok('B') => {
-                        if history_pos != history.buf.len() {
-                            if !s.is_empty() {
-                                let _ = write!(w, "\x1b[{}D", i);
-                                let _ = write!(w, "\x1b[K");
-                            }
-
-                            history_modified[history_pos] = Some(s);
-                            history_pos += 1;
-
-                            if let Some(modified) = history_modified[history_pos].take() {
-                                s = modified;
-                            } else {
-                                s = history.buf.get(history_pos).unwrap().clone();
-                            }
+                        if let Some(new_pos) = new_pos {
+                            history_modified[history_pos] = Some(s.clone());
+                            history_pos = new_pos;
 
-                            i = s.len();
-                            let _ = write!(w, "{}", s);
+                            let new = history_modified[history_pos]
+                                .take()
+                                .unwrap_or_else(|| history.buf.get(history_pos).unwrap().clone());
+                            replace_line(w, &mut s, &mut i, new);
                         }
                     },
                     Ok('C') => {
                         if i != s.len() {
-                            let _ = write!(w, "\x1b[C");
+                            move_cursor(w, i, i + 1);
                             i += 1;
                         }
                     },
                     Ok('D') => {
                         if i != 0 {
-                            let _ = write!(w, "\x1b[D");
+                            move_cursor(w, i, i - 1);
                             i -= 1;
                         }
                     },
+                    Ok('H') => {
+                        move_cursor(w, i, 0);
+                        i = 0;
+                    },
+                    Ok('F') => {
+                        move_cursor(w, i, s.len());
+                        i = s.len();
+                    },
+                    Ok(n @ ('1' | '3' | '4')) => {
+                        if let Ok('~') = r.next_char() {
+                            match n {
+                                '1' => {
+                                    move_cursor(w, i, 0);
+                                    i = 0;
+                                },
+                                '3' => {
+                                    if i != s.len() {
+                                        delete_range(w, &mut s, &mut i, i, i + 1);
+                                    }
+                                },
+                                _ => {
+                                    move_cursor(w, i, s.len());
+                                    i = s.len();
+                                },
+                            }
+                        }
+                    },
                     _ => {},
                 },
                 _ => {},
@@ -119,7 +266,7 @@ fn readline<T: Tty + ?Sized>(r: &mut TtyCharReader<T>, w: &mut TtyWriter<T>, his
 
                     if i != s.len() {
                         let _ = write!(w, "{}", &s[i..]);
-                        let _ = write!(w, "\x1b[{}D", s.len() - i);
+                        move_cursor(w, s.len(), i);
                     }
 
                     s.insert(i, ch);
@@ -133,7 +280,51 @@ fn readline<T: Tty + ?Sized>(r: &mut TtyCharReader<T>, w: &mut TtyWriter<T>, his
     }
 }
 
-fn run_dev_cmd<T: Tty + ?Sized>(w: &mut TtyWriter<T>, args: &[&str]) -> Result<(), fmt::Error> {
+/// Reads a line from a terminal that can't move its cursor, where characters can only be added or erased at the end of the line.
+fn readline_basic<T: Tty + ?Sized>(r: &mut TtyCharReader<T>, w: &mut TtyWriter<T>, history: &mut CommandHistory) -> Result<String, String> {
+    let mut s = String::new();
+
+    loop {
+        match r.next_char() {
+            Ok('\n') => {
+                history.push(&s);
+
+                let _ = writeln!(w);
+                return Ok(s);
+            },
+            Ok('\x7f' | '\x08') => {
+                if s.pop().is_some() {
+                    let _ = write!(w, "\x08 \x08");
+                }
+            },
+            Ok('\x15') => {
+                for _ in s.drain(..) {
+                    let _ = write!(w, "\x08 \x08");
+                }
+            },
+            Ok('\x1b') => {
+                // Escape sequences such as arrow keys can't be acted on, but shouldn't leave the rest of the sequence to be inserted as text
+                if r.next_char() == Ok('[') {
+                    while let Ok(ch) = r.next_char() {
+                        if ch.is_ascii_alphabetic() || ch == '~' {
+                            break;
+                        }
+                    }
+                }
+            },
+            Ok(ch) if ch.is_ascii() && !ch.is_ascii_control() => {
+                s.push(ch);
+                let _ = write!(w, "{}", ch);
+            },
+            Ok(_) => {},
+            Err(_) => {
+                return Err(s);
+            },
+        }
+    }
+}
+
+fn run_dev_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
     match args.first() {
         Some(&"ls") => {
             let dev = if let Some(dev_name) = args.get(1) {
@@ -166,176 +357,2045 @@ fn run_dev_cmd<T: Tty + ?Sized>(w: &mut TtyWriter<T>, args: &[&str]) -> Result<(
             let s = format!("{:#?}", dev.dev());
             writeln!(w, "{}", s)?;
         },
-        subcmd => {
-            if let Some(&subcmd) = subcmd {
-                writeln!(w, "unknown dev subcommand '{}'", subcmd)?;
-            } else {
-                writeln!(w, "no subcommand provided")?;
-            }
+        Some(&"info") => {
+            let Some(dev_name) = args.get(1) else {
+                writeln!(w, "usage: dev info <dev>")?;
+                return Ok(());
+            };
 
-            writeln!(w, "run 'help dev' for more information")?;
-        },
-    }
+            let dev = if let Ok(dev) = dev::get_device_by_name(dev_name) {
+                dev
+            } else {
+                writeln!(w, "device '{}' was not found", dev_name)?;
+                return Ok(());
+            };
 
-    Ok(())
-}
+            writeln!(w, "name:   {}", dev.full_name())?;
+            writeln!(w, "type:   {}", dev.dev().type_name())?;
 
-fn run_proc_cmd<T: Tty + ?Sized>(w: &mut TtyWriter<T>, args: &[&str]) -> Result<(), fmt::Error> {
-    match args.get(0) {
-        Some(&"ls") => {
-            for p in &*Process::list() {
-                writeln!(w, "{}: {}", p.pid(), p.cmd().get(0).map_or("???", |s| s))?;
+            if let Some(uptime) = dev.uptime() {
+                writeln!(w, "uptime: {}.{:03}s", uptime.as_secs(), uptime.subsec_millis())?;
+            } else {
+                writeln!(w, "uptime: (disconnected)")?;
             }
+
+            writeln!(w, "errors: {}", dev.error_count())?;
+            writeln!(w, "status: {}", dev.status().as_deref().unwrap_or("(none)"))?;
         },
-        Some(&"threads") => {
-            let pid = if let Some(pid) = args.get(1).and_then(|a| a.parse::<u64>().ok()) {
-                pid
-            } else {
-                writeln!(w, "usage: dev threads <pid>")?;
+        Some(&"remove") => {
+            let Some(dev_name) = args.get(1) else {
+                writeln!(w, "usage: dev remove <dev>")?;
                 return Ok(());
             };
 
-            let p = Process::list();
-            let p = if let Some(p) = p.get(pid) {
-                p
+            let dev = if let Ok(dev) = dev::get_device_by_name(dev_name) {
+                dev
             } else {
-                writeln!(w, "no process found with pid {}", pid)?;
+                writeln!(w, "device '{}' was not found", dev_name)?;
                 return Ok(());
             };
 
-            for t in p.lock().threads() {
-                writeln!(w, "{}: {:?}", t.thread_id(), t.lock().state())?;
+            match dev::hub::remove_from_parent(&dev) {
+                Ok(()) => writeln!(w, "removed {}", dev.full_name())?,
+                Err(err) => writeln!(w, "failed to remove {}: {}", dev.full_name(), err)?,
+            }
+        },
+        Some(&"drivers") => {
+            for driver in dev::driver::drivers() {
+                writeln!(w, "{}", driver)?;
+
+                for binding in dev::driver::bindings().iter().filter(|b| b.driver == driver) {
+                    writeln!(w, "  {}", binding.dev.name())?;
+                }
+            }
+
+            for unbound in dev::driver::unbound_devices() {
+                write!(w, "(unbound) {}:", unbound.name())?;
+
+                for id in unbound.ids() {
+                    write!(w, " {}", id)?;
+                }
+
+                writeln!(w)?;
             }
         },
         subcmd => {
             if let Some(&subcmd) = subcmd {
-                writeln!(w, "unknown proc subcommand '{}'", subcmd)?;
+                writeln!(w, "unknown dev subcommand '{}'", subcmd)?;
             } else {
                 writeln!(w, "no subcommand provided")?;
             }
 
-            writeln!(w, "run 'help proc' for more information")?;
+            writeln!(w, "run 'help dev' for more information")?;
         },
     }
 
     Ok(())
 }
 
-fn run_slab_cmd<T: Tty + ?Sized>(w: &mut TtyWriter<T>, args: &[&str]) -> Result<(), fmt::Error> {
-    use crate::mem::slab;
+fn run_iostat_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::io::dev::iostat;
+
+    let all = iostat::all();
 
     match args.get(0) {
-        None | Some(&"stats") => {
-            for alloc in slab::registered_slab_allocs() {
-                let (allocated, total) = alloc.lock().count();
-                writeln!(w, "{}: {}/{}", alloc.name(), allocated, total)?;
+        None => {
+            if all.is_empty() {
+                writeln!(w, "no devices are collecting I/O statistics")?;
+            }
+
+            for (name, stats) in all.iter() {
+                writeln!(
+                    w,
+                    "{}: {} reads ({} KiB), {} writes ({} KiB), {} errors, {} in flight, avg latency {}",
+                    name,
+                    stats.reads,
+                    stats.bytes_read / 1024,
+                    stats.writes,
+                    stats.bytes_written / 1024,
+                    stats.errors,
+                    stats.in_flight,
+                    match stats.avg_latency() {
+                        Some(latency) => format!("{:?}", latency),
+                        None => String::from("-"),
+                    }
+                )?;
             }
         },
-        Some(subcmd) => {
-            writeln!(w, "unknown slab subcommand '{}'", subcmd)?;
-            writeln!(w, "run 'help slab' for more information")?;
+        Some(dev) => {
+            let Some((_, stats)) = all.iter().find(|(name, _)| &**name == *dev) else {
+                writeln!(w, "no I/O statistics for device '{}'", dev)?;
+                return Ok(());
+            };
+
+            for (i, &count) in stats.latency.iter().enumerate().filter(|&(_, &count)| count != 0) {
+                if i == 0 {
+                    writeln!(w, "      < 1us: {}", count)?;
+                } else if i == iostat::NUM_LATENCY_BUCKETS - 1 {
+                    writeln!(w, "  >= {:>6}us: {}", 1u64 << (i - 1), count)?;
+                } else {
+                    writeln!(w, "  < {:>7}us: {}", 1u64 << i, count)?;
+                }
+            }
         },
     }
 
     Ok(())
 }
 
-fn run_debug_console_command<T: Tty + ?Sized>(w: &mut TtyWriter<T>, cmd: &[&str]) -> Result<(), fmt::Error> {
-    match cmd[0] {
-        "dev" => {
-            run_dev_cmd(w, &cmd[1..])?;
-        },
-        "proc" => {
-            run_proc_cmd(w, &cmd[1..])?;
+fn run_kbd_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use dyn_dyn::dyn_dyn_cast;
+
+    use crate::io::dev::kbd::{AccessibilityConfig, Keyboard, KeyboardLockState, TypematicConfig};
+    use crate::io::dev::Device;
+    use crate::io::{keymap, vt};
+
+    if args.first() == Some(&"list") {
+        for attached in vt::get_global_manager().dev().keyboards() {
+            writeln!(
+                w,
+                "{}: display {}, keymap {}{}",
+                attached.keyboard.full_name(),
+                attached.display_id,
+                attached.keyboard.dev().keymap().name(),
+                if attached.enabled { "" } else { " (disabled)" }
+            )?;
+        }
+
+        return Ok(());
+    }
+
+    let (dev_name, subcmd) = if let (Some(&dev_name), Some(&subcmd)) = (args.get(0), args.get(1)) {
+        (dev_name, subcmd)
+    } else {
+        writeln!(w, "usage: kbd <dev> <subcommand>")?;
+        writeln!(w, "run 'help kbd' for more information")?;
+        return Ok(());
+    };
+
+    let dev = if let Ok(dev) = dev::get_device_by_name(dev_name) {
+        dev
+    } else {
+        writeln!(w, "device '{}' was not found", dev_name)?;
+        return Ok(());
+    };
+
+    let kbd_ref = if let Ok(kbd) = dyn_dyn_cast!(move Device => Keyboard, dev) {
+        kbd
+    } else {
+        writeln!(w, "device '{}' is not a keyboard", dev_name)?;
+        return Ok(());
+    };
+    let kbd = kbd_ref.dev();
+
+    // NOTE: These commands talk to the keyboard directly, so their results are collected before anything is written to the TTY in case
+    //       the keyboard is also being used as the input device for this TTY.
+    match subcmd {
+        "echo" => {
+            let result = kbd.echo();
+
+            match result {
+                Ok(()) => writeln!(w, "keyboard responded to echo")?,
+                Err(_) => writeln!(w, "keyboard did not respond to echo")?,
+            }
         },
-        "slab" => {
-            run_slab_cmd(w, &cmd[1..])?;
+        "test" => {
+            let result = kbd.self_test();
+
+            match result {
+                Ok(()) => writeln!(w, "keyboard self-test passed")?,
+                Err(_) => writeln!(w, "keyboard self-test failed")?,
+            }
         },
-        "help" => match cmd.get(1) {
-            None => {
-                writeln!(w, "available commands are:")?;
-                writeln!(w, "  dev - device information")?;
-                writeln!(w, "  proc - process information")?;
-                writeln!(w, "  slab - slab alloc statistics")?;
-                writeln!(w)?;
-                writeln!(w, "run 'help <cmd>' for more information")?;
-            },
-            Some(&"dev") => {
-                writeln!(w, "available subcommands are:")?;
-                writeln!(w, "  dev ls [dev] - list devices")?;
-                writeln!(w, "  dev print [dev] - print device")?;
-            },
-            Some(&"proc") => {
-                writeln!(w, "available subcommands are:")?;
-                writeln!(w, "  proc ls - list processes")?;
-                writeln!(w, "  proc threads <pid> - list threads in process")?;
+        "typematic" => match (args.get(2), args.get(3)) {
+            (None, None) => {
+                let config = kbd.typematic();
+
+                match config {
+                    Some(config) => writeln!(w, "{}", config)?,
+                    None => writeln!(w, "keyboard does not support typematic configuration")?,
+                }
             },
-            Some(&"slab") => {
-                writeln!(w, "available subcommands are:")?;
-                writeln!(w, "  slab stats - print slab allocator statistics")?;
+            (Some(delay_ms), Some(rate_hz)) => {
+                let config = match (delay_ms.parse::<u32>(), rate_hz.parse::<f64>()) {
+                    (Ok(delay_ms), Ok(rate_hz)) if rate_hz > 0.0 => TypematicConfig {
+                        delay_ms,
+                        rate_decihz: (rate_hz * 10.0 + 0.5) as u32,
+                    },
+                    _ => {
+                        writeln!(w, "usage: kbd <dev> typematic [delay_ms rate_hz]")?;
+                        return Ok(());
+                    },
+                };
+                let result = kbd.set_typematic(config);
+
+                match result {
+                    Ok(config) => writeln!(w, "typematic set to {}", config)?,
+                    Err(_) => writeln!(w, "failed to set typematic configuration")?,
+                }
             },
-            Some(cmd) => {
-                writeln!(w, "unknown command '{}'", cmd)?;
+            _ => {
+                writeln!(w, "usage: kbd <dev> typematic [delay_ms rate_hz]")?;
             },
         },
-        _ => {
-            writeln!(w, "unknown command '{}'", cmd[0])?;
-        },
-    }
+        "repeat" => {
+            let config = match (args.get(2), args.get(3)) {
+                (None, None) => {
+                    match kbd.soft_repeat() {
+                        Some(config) => writeln!(w, "{}", config)?,
+                        None => writeln!(w, "software repeat is off")?,
+                    }
+                    return Ok(());
+                },
+                (Some(&"off"), None) => None,
+                (Some(delay_ms), Some(rate_hz)) => match (delay_ms.parse::<u32>(), rate_hz.parse::<f64>()) {
+                    (Ok(delay_ms), Ok(rate_hz)) if rate_hz > 0.0 => Some(TypematicConfig {
+                        delay_ms,
+                        rate_decihz: (rate_hz * 10.0 + 0.5) as u32,
+                    }),
+                    _ => {
+                        writeln!(w, "usage: kbd <dev> repeat [off|delay_ms rate_hz]")?;
+                        return Ok(());
+                    },
+                },
+                _ => {
+                    writeln!(w, "usage: kbd <dev> repeat [off|delay_ms rate_hz]")?;
+                    return Ok(());
+                },
+            };
 
-    Ok(())
-}
+            match (kbd.set_soft_repeat(config), config) {
+                (Ok(()), Some(config)) => writeln!(w, "software repeat set to {}", config)?,
+                (Ok(()), None) => writeln!(w, "software repeat turned off")?,
+                (Err(_), _) => writeln!(w, "keyboard does not support software repeat")?,
+            }
+        },
+        "sticky" | "slowkeys" => {
+            let Some(config) = kbd.accessibility() else {
+                writeln!(w, "keyboard does not support accessibility features")?;
+                return Ok(());
+            };
 
-fn parse_command(mut cmd: &str) -> Result<Vec<&str>, (usize, &'static str)> {
-    let mut result = vec![];
-    let mut idx = 0;
+            let new_config = match (subcmd, args.get(2).copied()) {
+                (_, None) => {
+                    writeln!(w, "{}", config)?;
+                    return Ok(());
+                },
+                ("sticky", Some("on")) => AccessibilityConfig {
+                    sticky_keys: true,
+                    ..config
+                },
+                ("sticky", Some("off")) => AccessibilityConfig {
+                    sticky_keys: false,
+                    ..config
+                },
+                ("slowkeys", Some(ms)) if ms.parse::<u32>().is_ok() => AccessibilityConfig {
+                    slow_keys_ms: ms.parse().unwrap(),
+                    ..config
+                },
+                _ => {
+                    writeln!(w, "usage: kbd <dev> sticky [on|off]")?;
+                    writeln!(w, "       kbd <dev> slowkeys [ms]")?;
+                    return Ok(());
+                },
+            };
 
-    while !cmd.is_empty() {
-        while cmd.starts_with(' ') {
-            cmd = &cmd[1..];
-            idx += 1;
-        }
+            let result = kbd.set_accessibility(new_config);
 
-        if cmd.starts_with('"') {
-            // TODO Escape sequences?
-            if let Some(quote_len) = cmd[1..].find('"') {
-                result.push(&cmd[1..quote_len + 1]);
-                cmd = &cmd[quote_len + 2..];
-                idx += quote_len + 2;
-            } else {
-                return Err((idx, "unterminated quoted argument"));
+            match result {
+                Ok(()) => writeln!(w, "{}", new_config)?,
+                Err(_) => writeln!(w, "failed to set accessibility features")?,
             }
-        } else if let Some((cmd_a, cmd_b)) = cmd.split_once(' ') {
-            result.push(cmd_a);
-            cmd = cmd_b;
-            idx += cmd_a.len() + 1;
-        } else {
-            result.push(cmd);
-            cmd = &cmd[cmd.len()..];
-        }
-    }
+        },
+        "locks" if args.len() == 2 => {
+            let lock_state = kbd.lock_state();
 
-    if result.is_empty() {
-        result.push("");
-    }
+            match lock_state {
+                Ok(lock_state) => {
+                    let names = [
+                        (lock_state.caps_lock, "caps"),
+                        (lock_state.num_lock, "num"),
+                        (lock_state.scroll_lock, "scroll"),
+                    ];
+                    let mut any = false;
 
-    Ok(result)
-}
+                    for (_, name) in names.iter().filter(|&&(on, _)| on) {
+                        write!(w, "{}{}", if any { " " } else { "" }, name)?;
+                        any = true;
+                    }
 
-pub fn show_debug_console<T: Tty + ?Sized>(tty: &T) {
-    let mut r = TtyCharReader::new(tty);
-    let mut w = TtyWriter::new(tty);
+                    writeln!(w, "{}", if any { "" } else { "none" })?;
+                },
+                Err(_) => writeln!(w, "failed to get lock state")?,
+            }
+        },
+        "locks" | "leds" => {
+            let mut lock_state = KeyboardLockState::none();
+
+            for &name in &args[2..] {
+                match name {
+                    "caps" => lock_state.caps_lock = true,
+                    "num" => lock_state.num_lock = true,
+                    "scroll" => lock_state.scroll_lock = true,
+                    "none" => {},
+                    _ => {
+                        writeln!(w, "usage: kbd <dev> {} [caps] [num] [scroll] [none]", subcmd)?;
+                        return Ok(());
+                    },
+                }
+            }
+
+            let result = if subcmd == "locks" {
+                kbd.set_lock_state(lock_state)
+            } else {
+                kbd.set_leds(lock_state)
+            };
+
+            match (result, subcmd) {
+                (Ok(()), _) => {},
+                (Err(_), "locks") => writeln!(w, "failed to set keyboard lock state")?,
+                (Err(_), _) => writeln!(w, "failed to set keyboard LEDs")?,
+            }
+        },
+        "enable" | "disable" => {
+            if !vt::get_global_manager().dev().set_keyboard_enabled(&kbd_ref, subcmd == "enable") {
+                writeln!(w, "keyboard is not attached to a display")?;
+            }
+        },
+        "keymap" => match args.get(2) {
+            None => writeln!(w, "{}", kbd.keymap().name())?,
+            Some(name) => match keymap::get_keymap(name) {
+                Some(map) => kbd.set_keymap(map),
+                None => writeln!(w, "keymap '{}' was not found", name)?,
+            },
+        },
+        subcmd => {
+            writeln!(w, "unknown kbd subcommand '{}'", subcmd)?;
+            writeln!(w, "run 'help kbd' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_keymap_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::io::keymap::{self, parse};
+
+    match args.get(0) {
+        Some(&"list") => {
+            let default = keymap::default_keymap();
+
+            for map in keymap::keymaps() {
+                writeln!(w, "{}{}", map.name(), if core::ptr::eq(map, default) { " (default)" } else { "" })?;
+            }
+        },
+        Some(&"default") => match args.get(1) {
+            None => writeln!(w, "{}", keymap::default_keymap().name())?,
+            Some(name) => match keymap::get_keymap(name) {
+                Some(map) => keymap::set_default_keymap(map),
+                None => writeln!(w, "keymap '{}' was not found", name)?,
+            },
+        },
+        Some(&"define") => {
+            let Some(name) = args.get(1) else {
+                writeln!(w, "usage: keymap define <name> [entry]...")?;
+                return Ok(());
+            };
+
+            let mut text = String::new();
+
+            for line in &args[2..] {
+                text.push_str(line);
+                text.push('\n');
+            }
+
+            match parse::parse(name, &text) {
+                Ok(map) => {
+                    if keymap::register_keymap(map).is_err() {
+                        writeln!(w, "keymap '{}' already exists", name)?;
+                    }
+                },
+                Err(err) => writeln!(w, "bad keymap entry: {}", err)?,
+            }
+        },
+        Some(&"strings") => {
+            let (count, size) = keymap::intern::stats();
+            writeln!(w, "{} interned strings, {} bytes", count, size)?;
+        },
+        _ => {
+            writeln!(w, "usage: keymap list|default|define|strings")?;
+            writeln!(w, "run 'help keymap' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_display_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use core::time::Duration;
+
+    use crate::io::vt::{self, DisplayPowerState};
+
+    let vtmgr = vt::get_global_manager().dev();
+
+    match args.get(0) {
+        Some(&"list") => {
+            for id in 0..vtmgr.num_displays() {
+                let (Some(display), Some((terminal_id, blanked))) = (vtmgr.get_display(id), vtmgr.display_state(id)) else {
+                    continue;
+                };
+                let (width, height) = display.dev().size();
+
+                write!(
+                    w,
+                    "{}: {} {}x{}, vt{}, power {}",
+                    id,
+                    display.full_name(),
+                    width,
+                    height,
+                    terminal_id,
+                    display.dev().power_state()
+                )?;
+
+                if blanked {
+                    write!(w, " (idle)")?;
+                }
+
+                match display.dev().brightness() {
+                    Some((level, max)) => writeln!(w, ", brightness {}/{}", level, max)?,
+                    None => writeln!(w)?,
+                }
+            }
+        },
+        Some(&"blank") => match args.get(1) {
+            None => match vtmgr.blank_timeout() {
+                Some(timeout) => writeln!(w, "idle displays are blanked after {}s", timeout.as_secs())?,
+                None => writeln!(w, "idle displays are not blanked")?,
+            },
+            Some(&"off") => vtmgr.set_blank_timeout(None),
+            Some(secs) => match secs.parse::<u32>() {
+                Ok(secs) if secs > 0 => vtmgr.set_blank_timeout(Some(Duration::from_secs(secs.into()))),
+                _ => writeln!(w, "invalid timeout '{}'", secs)?,
+            },
+        },
+        Some(id) => {
+            let Some(id) = id.parse::<usize>().ok().filter(|&id| id < vtmgr.num_displays()) else {
+                writeln!(w, "display '{}' was not found", id)?;
+                return Ok(());
+            };
+            let display = vtmgr.get_display(id).unwrap();
+
+            match (args.get(1), args.get(2)) {
+                (Some(&"power"), None) => writeln!(w, "{}", display.dev().power_state())?,
+                (Some(&"power"), Some(state)) => {
+                    let state = match *state {
+                        "on" => DisplayPowerState::On,
+                        "standby" => DisplayPowerState::Standby,
+                        "off" => DisplayPowerState::Off,
+                        _ => {
+                            writeln!(w, "invalid power state '{}'", state)?;
+                            return Ok(());
+                        },
+                    };
+
+                    if let Err(err) = vtmgr.set_display_power(id, state) {
+                        writeln!(w, "failed to set power state: {}", err)?;
+                    }
+                },
+                (Some(&"brightness"), None) => match display.dev().brightness() {
+                    Some((level, max)) => writeln!(w, "{}/{}", level, max)?,
+                    None => writeln!(w, "display does not have adjustable brightness")?,
+                },
+                (Some(&"brightness"), Some(level)) => match level.parse::<u32>() {
+                    Ok(level) => {
+                        if let Err(err) = display.dev().set_brightness(level) {
+                            writeln!(w, "failed to set brightness: {}", err)?;
+                        }
+                    },
+                    Err(_) => writeln!(w, "invalid brightness level '{}'", level)?,
+                },
+                _ => {
+                    writeln!(w, "usage: display <id> power|brightness [value]")?;
+                    writeln!(w, "run 'help display' for more information")?;
+                },
+            }
+        },
+        None => {
+            writeln!(w, "usage: display list|blank|<id>")?;
+            writeln!(w, "run 'help display' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_ramdisk_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::io::block::{ramdisk, BlockDevice, BlockDeviceExt};
+
+    match (args.get(0), args.get(1), args.get(2)) {
+        (Some(&"list"), None, None) => {
+            for dev in ramdisk::ramdisks() {
+                writeln!(
+                    w,
+                    "{}: {} KiB{}",
+                    dev.full_name(),
+                    dev.dev().capacity() / 1024,
+                    if dev.dev().is_read_only() { " (read-only)" } else { "" }
+                )?;
+            }
+        },
+        (Some(&"create"), Some(name), Some(size_kib)) => {
+            let Some(size) = size_kib.parse::<usize>().ok().and_then(|kib| kib.checked_mul(1024)) else {
+                writeln!(w, "invalid size '{}'", size_kib)?;
+                return Ok(());
+            };
+
+            match ramdisk::create(name, size) {
+                Ok(dev) => writeln!(w, "created {}", dev.full_name())?,
+                Err(err) => writeln!(w, "failed to create ramdisk: {}", err)?,
+            }
+        },
+        (Some(&"remove"), Some(name), None) => {
+            if !ramdisk::remove(name) {
+                writeln!(w, "ramdisk '{}' was not found", name)?;
+            }
+        },
+        _ => {
+            writeln!(w, "usage: ramdisk list|create|remove")?;
+            writeln!(w, "run 'help ramdisk' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_mouse_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::io::vt;
+
+    if !args.is_empty() {
+        writeln!(w, "usage: mouse")?;
+        return Ok(());
+    }
+
+    for mouse in vt::get_global_manager().dev().mice() {
+        let held = match mouse.dev().buttons() {
+            Ok(held) => format!("{}", held),
+            Err(_) => String::from("unknown"),
+        };
+
+        writeln!(
+            w,
+            "{}: buttons {}, {}, held {}",
+            mouse.full_name(),
+            mouse.dev().supported_buttons(),
+            if mouse.dev().has_wheel() { "wheel" } else { "no wheel" },
+            held
+        )?;
+    }
+
+    Ok(())
+}
+
+fn run_version_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::build_info;
+
+    if !args.is_empty() {
+        writeln!(w, "usage: version")?;
+        return Ok(());
+    }
+
+    writeln!(w, "HydroxOS v{}", build_info::VERSION)?;
+    writeln!(w, "commit:   {}", build_info::GIT_COMMIT)?;
+    writeln!(w, "profile:  {}", build_info::PROFILE)?;
+    writeln!(w, "compiler: {}", build_info::RUSTC_VERSION)?;
+    write!(w, "features:")?;
+
+    for feature in build_info::features() {
+        write!(w, " {}", feature)?;
+    }
+
+    writeln!(w)?;
+    Ok(())
+}
+
+fn run_bootchart_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    if !args.is_empty() {
+        writeln!(w, "usage: bootchart")?;
+        return Ok(());
+    }
+
+    write!(w, "{}", crate::boottime::get())?;
+
+    Ok(())
+}
+
+fn run_cpuinfo_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::sched::topology;
+
+    if !args.is_empty() {
+        writeln!(w, "usage: cpuinfo")?;
+        return Ok(());
+    }
+
+    let topology = topology::get();
+
+    writeln!(
+        w,
+        "{} packages, {} cores, {} threads",
+        topology.num_packages(),
+        topology.num_cores(),
+        topology.num_threads()
+    )?;
+
+    for cpu in topology.cpus() {
+        writeln!(
+            w,
+            "hw id {:>4}: package {} core {} thread {}{}",
+            cpu.hw_id,
+            cpu.package,
+            cpu.core,
+            cpu.thread,
+            if cpu.enabled { "" } else { " (disabled)" }
+        )?;
+    }
+
+    Ok(())
+}
+
+fn run_futures_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use core::time::Duration;
+
+    use crate::sync::future;
+
+    if args.get(0) == Some(&"stats") {
+        let stats = match future::future_stats() {
+            Ok(stats) => stats,
+            Err(_) => {
+                writeln!(w, "future verification is not enabled (build with the future_verify feature)")?;
+                return Ok(());
+            },
+        };
+
+        writeln!(w, "created:       {}", stats.created)?;
+        writeln!(w, "destroyed:     {}", stats.destroyed)?;
+        writeln!(w, "live:          {}", stats.created - stats.destroyed)?;
+        writeln!(w, "resolved:      {}", stats.resolved)?;
+        writeln!(w, "actions added: {}", stats.actions_added)?;
+        return Ok(());
+    }
+
+    let min_age = match args.get(0).map(|a| a.parse::<u64>()) {
+        None => Duration::from_secs(1),
+        Some(Ok(ms)) => Duration::from_millis(ms),
+        Some(Err(_)) => {
+            writeln!(w, "usage: futures [min_age_ms | stats]")?;
+            return Ok(());
+        },
+    };
+
+    let mut pending = match future::pending_futures() {
+        Ok(pending) => pending,
+        Err(_) => {
+            writeln!(w, "future tracking is not enabled (build with the future_tracking feature)")?;
+            return Ok(());
+        },
+    };
+    let now = timer::now();
+
+    pending.retain(|f| now.saturating_sub(f.created_time) >= min_age);
+    pending.sort_by_key(|f| f.created_time);
+
+    for f in pending.iter() {
+        writeln!(
+            w,
+            "{:#x}: pending for {} ms, Future<{}> created at {}",
+            f.addr,
+            (now - f.created_time).as_millis(),
+            f.type_name,
+            f.created_at
+        )?;
+    }
+
+    writeln!(w, "{} futures pending for at least {} ms", pending.len(), min_age.as_millis())?;
+    Ok(())
+}
+
+fn run_allocprof_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::mem::profile;
+
+    let n = match args.get(0).map(|a| a.parse::<usize>()) {
+        None => 10,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            writeln!(w, "usage: allocprof [count]")?;
+            return Ok(());
+        },
+    };
+
+    let sites = match profile::top_call_sites(n) {
+        Ok(sites) => sites,
+        Err(_) => {
+            writeln!(w, "allocation profiling is not enabled (build with the alloc_profile feature)")?;
+            return Ok(());
+        },
+    };
+
+    for site in sites.iter() {
+        write!(w, "{} bytes in {} allocations from", site.bytes, site.count)?;
+
+        for addr in site.site.addrs() {
+            write!(w, " {:#x}", addr)?;
+        }
+
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+fn run_replay_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::sched::replay;
+
+    let n = match args.get(0).map(|a| a.parse::<usize>()) {
+        None => 50,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            writeln!(w, "usage: replay [count]")?;
+            return Ok(());
+        },
+    };
+
+    let events = match replay::events(n) {
+        Ok(events) => events,
+        Err(_) => {
+            writeln!(w, "replay logging is not enabled (build with the replay_log feature)")?;
+            return Ok(());
+        },
+    };
+
+    for record in events.iter() {
+        writeln!(w, "{}", record)?;
+    }
+
+    Ok(())
+}
+
+fn run_proc_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    match args.get(0) {
+        Some(&"ls") => {
+            for p in task::all_processes() {
+                let num_threads = p.lock().threads().count();
+                writeln!(w, "{}: {} ({} threads)", p.pid(), p.cmd().get(0).map_or("???", |s| s), num_threads)?;
+            }
+        },
+        Some(&"threads") => {
+            let pid = if let Some(pid) = args.get(1).and_then(|a| a.parse::<u64>().ok()) {
+                pid
+            } else {
+                writeln!(w, "usage: proc threads <pid>")?;
+                return Ok(());
+            };
+
+            let p = if let Some(p) = Process::by_pid(pid) {
+                p
+            } else {
+                writeln!(w, "no process found with pid {}", pid)?;
+                return Ok(());
+            };
+
+            for t in p.lock().threads() {
+                let t = t.lock();
+                writeln!(
+                    w,
+                    "{}: {} {:?} ({} priority, {} ms cpu)",
+                    t.thread().thread_id(),
+                    t.thread().name().unwrap_or("-"),
+                    t.state(),
+                    t.priority(),
+                    t.cpu_stats().cpu_time.as_millis()
+                )?;
+            }
+        },
+        subcmd => {
+            if let Some(&subcmd) = subcmd {
+                writeln!(w, "unknown proc subcommand '{}'", subcmd)?;
+            } else {
+                writeln!(w, "no subcommand provided")?;
+            }
+
+            writeln!(w, "run 'help proc' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_slab_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::mem::slab;
+
+    match args.get(0) {
+        None | Some(&"stats") => {
+            for alloc in slab::registered_slab_allocs() {
+                let (allocated, total) = alloc.lock().count();
+                writeln!(w, "{}: {}/{}", alloc.name(), allocated, total)?;
+            }
+        },
+        Some(subcmd) => {
+            writeln!(w, "unknown slab subcommand '{}'", subcmd)?;
+            writeln!(w, "run 'help slab' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_mem_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::arch::page::PAGE_SIZE;
+    use crate::mem;
+
+    match args.get(0) {
+        None | Some(&"stats") => {
+            let stats = mem::stats();
+
+            writeln!(
+                w,
+                "slab: {}/{} KiB",
+                stats.slab_bytes_allocated() / 1024,
+                stats.slab_bytes_total() / 1024
+            )?;
+
+            for slab in stats.slabs.iter().filter(|slab| slab.total != 0) {
+                writeln!(
+                    w,
+                    "  {}: {}/{} objects ({}/{} KiB)",
+                    slab.name,
+                    slab.allocated,
+                    slab.total,
+                    slab.allocated * slab.object_size / 1024,
+                    slab.total * slab.object_size / 1024
+                )?;
+            }
+
+            writeln!(
+                w,
+                "page: {} pages ({} KiB)",
+                stats.page_alloc_pages,
+                stats.page_alloc_pages * PAGE_SIZE / 1024
+            )?;
+            writeln!(w, "early: {}/{} KiB", stats.early_used / 1024, stats.early_total / 1024)?;
+            writeln!(
+                w,
+                "frames: {}/{} available ({}/{} KiB)",
+                stats.frames_available,
+                stats.frames_total,
+                stats.frames_available * PAGE_SIZE / 1024,
+                stats.frames_total * PAGE_SIZE / 1024
+            )?;
+        },
+        Some(&"vmap") => {
+            use crate::mem::virt::{self, KernelVirtUsage};
+
+            for entry in virt::kernel_virt_map() {
+                writeln!(
+                    w,
+                    "{:#018x}-{:#018x} {:<8} {} KiB",
+                    entry.region.start().as_u64(),
+                    entry.region.end().as_u64(),
+                    match entry.usage {
+                        KernelVirtUsage::Free => "free",
+                        KernelVirtUsage::Mapped => "mapped",
+                        KernelVirtUsage::Mmio => "mmio",
+                        KernelVirtUsage::Reserved => "reserved",
+                    },
+                    entry.region.size() / 1024
+                )?;
+            }
+        },
+        Some(&"frag") => {
+            let stats = mem::virt::kernel_virt_stats();
+
+            writeln!(w, "free regions: {}", stats.free_regions)?;
+            writeln!(w, "free: {} KiB", stats.free_bytes / 1024)?;
+            writeln!(w, "largest free region: {} KiB", stats.largest_free_region / 1024)?;
+            writeln!(w, "fragmentation: {}%", stats.fragmentation_percent())?;
+        },
+        Some(&"image") => {
+            use crate::mem::image;
+
+            let size = image::image_size();
+            let (early_used, early_total) = mem::early::usage();
+
+            writeln!(w, "text: {} KiB", size.text / 1024)?;
+            writeln!(w, "rodata: {} KiB", size.rodata / 1024)?;
+            writeln!(w, "data: {} KiB", size.data / 1024)?;
+            writeln!(w, "bss: {} KiB", size.bss / 1024)?;
+            writeln!(w, "total: {} KiB", size.total() / 1024)?;
+            writeln!(w, "tls: {} B per block ({} B tdata, {} B tbss)", size.tls(), size.tdata, size.tbss)?;
+            writeln!(w, "early: {}/{} KiB", early_used / 1024, early_total / 1024)?;
+            writeln!(w, "statics:")?;
+
+            for (subsys, total) in image::static_footprint_by_subsys() {
+                writeln!(w, "  {}: {} KiB", subsys, total / 1024)?;
+
+                for footprint in image::static_footprints().iter().filter(|f| f.subsys() == subsys) {
+                    writeln!(w, "    {}: {} B", footprint.name(), footprint.size())?;
+                }
+            }
+        },
+        Some(subcmd) => {
+            writeln!(w, "unknown mem subcommand '{}'", subcmd)?;
+            writeln!(w, "run 'help mem' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_frame_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::arch::page::PAGE_SIZE;
+    use crate::mem::frame::{self, FrameAllocator};
+
+    match args.get(0) {
+        None | Some(&"stats") => {
+            let available = frame::get_allocator().num_frames_available();
+            let total = frame::num_total_frames();
+
+            writeln!(
+                w,
+                "{}/{} frames available ({}/{} KiB)",
+                available,
+                total,
+                available * PAGE_SIZE / 1024,
+                total * PAGE_SIZE / 1024
+            )?;
+        },
+        Some(&"bad") => {
+            let mut result = Ok(());
+            let mut n = 0;
+
+            frame::for_each_bad_frame(|bad_frame| {
+                n += 1;
+                if result.is_ok() {
+                    result = writeln!(
+                        w,
+                        "{:#x}: {:?} (reported by {:?})",
+                        bad_frame.frame.as_u64(),
+                        bad_frame.state,
+                        bad_frame.source
+                    );
+                }
+            });
+            result?;
+
+            if n == 0 {
+                writeln!(w, "no bad frames reported")?;
+            }
+        },
+        Some(subcmd) => {
+            writeln!(w, "unknown frame subcommand '{}'", subcmd)?;
+            writeln!(w, "run 'help frame' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_swap_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::arch::page::PAGE_SIZE;
+    use crate::io::block;
+    use crate::mem::{swap, zram};
+
+    match args.get(0) {
+        None | Some(&"stats") => {
+            if let Some(stats) = swap::stats() {
+                writeln!(
+                    w,
+                    "{}/{} slots used ({}/{} KiB)",
+                    stats.used_slots,
+                    stats.total_slots,
+                    stats.used_slots * PAGE_SIZE as u64 / 1024,
+                    stats.total_slots * PAGE_SIZE as u64 / 1024
+                )?;
+            } else {
+                writeln!(w, "swap is not enabled")?;
+            }
+
+            if let Some(stats) = zram::stats() {
+                writeln!(
+                    w,
+                    "zram: {} pages stored ({} zero, {} incompressible), {}/{} KiB",
+                    stats.pages_stored(),
+                    stats.zero_pages(),
+                    stats.incompressible_pages(),
+                    stats.stored_bytes() / 1024,
+                    stats.original_bytes() / 1024
+                )?;
+
+                if let Some(ratio) = stats.compression_ratio() {
+                    writeln!(w, "zram: compression ratio {:.2}", ratio)?;
+                }
+            }
+        },
+        Some(&"on") => {
+            let Some(&name) = args.get(1) else {
+                writeln!(w, "usage: swap on <device>")?;
+                return Ok(());
+            };
+            let Some(dev) = block::block_devices().into_iter().find(|dev| dev.name() == name) else {
+                writeln!(w, "block device '{}' was not found", name)?;
+                return Ok(());
+            };
+
+            if let Err(err) = swap::BlockSwapBackend::new(dev).and_then(|backend| swap::enable(Box::new(backend))) {
+                writeln!(w, "failed to enable swap: {}", err)?;
+            }
+        },
+        Some(&"off") => {
+            if let Err(err) = swap::disable() {
+                writeln!(w, "failed to disable swap: {}", err)?;
+            }
+        },
+        Some(subcmd) => {
+            writeln!(w, "unknown swap subcommand '{}'", subcmd)?;
+            writeln!(w, "run 'help swap' for more information")?;
+        },
+    }
+
+    Ok(())
+}
+
+fn run_grep_cmd<W: Write>(w: &mut W, args: &[&str], input: &str) -> Result<(), fmt::Error> {
+    let mut invert = false;
+    let mut ignore_case = false;
+    let mut pattern = None;
+
+    for &arg in args {
+        match arg {
+            "-v" => invert = true,
+            "-i" => ignore_case = true,
+            arg if pattern.is_none() => pattern = Some(arg),
+            _ => {
+                writeln!(w, "usage: grep [-v] [-i] <pattern>")?;
+                return Ok(());
+            },
+        }
+    }
+
+    let Some(pattern) = pattern else {
+        writeln!(w, "usage: grep [-v] [-i] <pattern>")?;
+        return Ok(());
+    };
+    let pattern = if ignore_case {
+        pattern.to_lowercase()
+    } else {
+        String::from(pattern)
+    };
+
+    for line in input.lines() {
+        let matches = if ignore_case {
+            line.to_lowercase().contains(&pattern)
+        } else {
+            line.contains(&pattern)
+        };
+
+        if matches != invert {
+            writeln!(w, "{}", line)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn run_head_cmd<W: Write>(w: &mut W, args: &[&str], input: &str) -> Result<(), fmt::Error> {
+    let n = match (args.get(0).map(|a| a.parse::<usize>()), args.len()) {
+        (None, _) => 10,
+        (Some(Ok(n)), 1) => n,
+        _ => {
+            writeln!(w, "usage: head [count]")?;
+            return Ok(());
+        },
+    };
+
+    for line in input.lines().take(n) {
+        writeln!(w, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn run_xxd_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::mem;
+
+    // TODO Support dumping files once there's a VFS
+    let (addr, len) = match (args.get(0).map(|a| parse_u64(a)), args.get(1).map(|a| parse_u64(a)), args.len()) {
+        (Some(Some(addr)), None, 1) => (addr, 256),
+        (Some(Some(addr)), Some(Some(len)), 2) if len <= 0x10000 => (addr, len as usize),
+        _ => {
+            writeln!(w, "usage: xxd <addr> [len]")?;
+            return Ok(());
+        },
+    };
+
+    let mut buf = vec![0; len];
+    let read = mem::read_kernel_mem(addr, &mut buf);
+
+    for (i, line) in buf[..read].chunks(16).enumerate() {
+        write!(w, "{:016x}:", addr + (i * 16) as u64)?;
+
+        for j in 0..16 {
+            if j % 2 == 0 {
+                write!(w, " ")?;
+            }
+
+            if let Some(b) = line.get(j) {
+                write!(w, "{:02x}", b)?;
+            } else {
+                write!(w, "  ")?;
+            }
+        }
+
+        write!(w, "  ")?;
+
+        for &b in line {
+            write!(w, "{}", if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })?;
+        }
+
+        writeln!(w)?;
+    }
+
+    if read < len {
+        writeln!(w, "{:016x} is not mapped", addr + read as u64)?;
+    }
+
+    Ok(())
+}
+
+fn run_dis_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::arch::insn::{self, MAX_INSTRUCTION_LEN};
+    use crate::mem;
+
+    let (addr, count) = match (args.get(0).map(|a| parse_u64(a)), args.get(1).map(|a| parse_u64(a)), args.len()) {
+        (Some(Some(addr)), None, 1) => (addr, 16),
+        (Some(Some(addr)), Some(Some(count)), 2) if count <= 256 => (addr, count as usize),
+        _ => {
+            writeln!(w, "usage: dis <addr> [count]")?;
+            return Ok(());
+        },
+    };
+
+    let mut buf = vec![0; count * MAX_INSTRUCTION_LEN];
+    let read = mem::read_kernel_mem(addr, &mut buf);
+    let mut pos = 0;
+
+    // TODO Symbolize addresses once the kernel has a symbol table
+    for _ in 0..count {
+        if pos == read {
+            writeln!(w, "{:016x} is not mapped", addr + pos as u64)?;
+            break;
+        }
+
+        let len = insn::instruction_len(&buf[pos..read]);
+
+        write!(w, "{:016x}:", addr + pos as u64)?;
+
+        for b in &buf[pos..pos + len.unwrap_or(1)] {
+            write!(w, " {:02x}", b)?;
+        }
+
+        if len.is_none() {
+            write!(w, " (bad)")?;
+        }
+
+        writeln!(w)?;
+        pos += len.unwrap_or(1);
+    }
+
+    Ok(())
+}
+
+fn sample_thread_cpu_stats() -> BTreeMap<(u64, u64), ThreadCpuStats> {
+    let mut stats = BTreeMap::new();
+
+    for p in task::all_processes() {
+        for t in p.lock().threads() {
+            stats.insert((p.pid(), t.thread_id()), t.lock().cpu_stats());
+        }
+    }
+
+    stats
+}
+
+fn run_top_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use core::time::Duration;
+
+    use crate::sched::task::Thread;
+
+    let interval = match args.get(0).map(|a| a.parse::<u64>()) {
+        None => Duration::from_secs(1),
+        Some(Ok(ms)) if ms > 0 => Duration::from_millis(ms),
+        Some(_) => {
+            writeln!(w, "usage: top [interval_ms]")?;
+            return Ok(());
+        },
+    };
+
+    let before = sample_thread_cpu_stats();
+    Thread::sleep(interval);
+    let after = sample_thread_cpu_stats();
+
+    let mut rows: Vec<_> = after
+        .iter()
+        .map(|(&id, stats)| {
+            let prev = before.get(&id).copied().unwrap_or_default();
+            (id, stats, stats.cpu_time.saturating_sub(prev.cpu_time))
+        })
+        .collect();
+
+    rows.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+
+    writeln!(w, "  pid   tid   cpu%      total ms   switches    wakeups")?;
+    for ((pid, tid), stats, delta) in rows {
+        writeln!(
+            w,
+            "{:5} {:5} {:5.1}% {:13} {:10} {:10}",
+            pid,
+            tid,
+            delta.as_secs_f64() * 100.0 / interval.as_secs_f64(),
+            stats.cpu_time.as_millis(),
+            stats.context_switches,
+            stats.wakeups
+        )?;
+    }
+
+    writeln!(w)?;
+    for p in task::all_processes() {
+        writeln!(
+            w,
+            "pid {} ({}): {} ms total",
+            p.pid(),
+            p.cmd().get(0).map_or("???", |s| s),
+            p.cpu_time().as_millis()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The order in which the system monitor lists threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MonitorSort {
+    Cpu,
+    Switches,
+    Id,
+}
+
+impl MonitorSort {
+    fn next(self) -> MonitorSort {
+        match self {
+            MonitorSort::Cpu => MonitorSort::Switches,
+            MonitorSort::Switches => MonitorSort::Id,
+            MonitorSort::Id => MonitorSort::Cpu,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            MonitorSort::Cpu => "cpu",
+            MonitorSort::Switches => "switches",
+            MonitorSort::Id => "id",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MonitorThread {
+    name: String,
+    is_idle: bool,
+    stats: ThreadCpuStats,
+}
+
+/// A snapshot of everything shown by the system monitor. Rates are computed from the difference between two consecutive snapshots.
+#[derive(Debug, Clone)]
+struct MonitorSample {
+    time: core::time::Duration,
+    threads: BTreeMap<(u64, u64), MonitorThread>,
+    num_processes: usize,
+    irqs: [u64; interrupt::NUM_IRQS],
+    mem: crate::mem::MemStats,
+}
+
+impl MonitorSample {
+    fn take() -> MonitorSample {
+        let processes = task::all_processes();
+        let mut threads = BTreeMap::new();
+
+        for p in processes.iter() {
+            let is_kernel = core::ptr::eq(&**p, &**Process::kernel());
+            let process_name = p.cmd().get(0).map_or("-", |s| s);
+
+            for t in p.lock().threads() {
+                let thread = MonitorThread {
+                    name: String::from(t.name().unwrap_or(process_name)),
+                    is_idle: is_kernel && t.name() == Some("idle"),
+                    stats: t.lock().cpu_stats(),
+                };
+
+                threads.insert((p.pid(), t.thread_id()), thread);
+            }
+        }
+
+        MonitorSample {
+            time: timer::now(),
+            threads,
+            num_processes: processes.len(),
+            irqs: core::array::from_fn(interrupt::irq_count),
+            mem: crate::mem::stats(),
+        }
+    }
+}
+
+struct MonitorView {
+    interval: core::time::Duration,
+    sort: MonitorSort,
+    scroll: usize,
+}
+
+impl MonitorView {
+    const MIN_INTERVAL: core::time::Duration = core::time::Duration::from_millis(100);
+    const MAX_INTERVAL: core::time::Duration = core::time::Duration::from_secs(10);
+
+    /// The number of lines shown above the thread list.
+    const HEADER_LINES: usize = 6;
+
+    /// Renders a single frame of the monitor showing the changes between two samples, with one string per line. Every line fits within
+    /// the provided terminal size, and enough lines are returned to fill all but the last row of the terminal, so that the cursor can be
+    /// left below the frame without scrolling it.
+    fn render(&mut self, prev: &MonitorSample, cur: &MonitorSample, (width, height): (usize, usize)) -> Vec<String> {
+        use crate::arch::page::PAGE_SIZE;
+
+        let elapsed = cur.time.saturating_sub(prev.time).as_secs_f64();
+        let rate = |delta: u64| if elapsed > 0.0 { delta as f64 / elapsed } else { 0.0 };
+
+        let mut num_cpus = 0;
+        let mut busy_secs = 0.0;
+        let mut rows = vec![];
+
+        for (&(pid, tid), thread) in cur.threads.iter() {
+            let prev_stats = prev.threads.get(&(pid, tid)).map_or_else(ThreadCpuStats::default, |t| t.stats);
+            let cpu_secs = thread.stats.cpu_time.saturating_sub(prev_stats.cpu_time).as_secs_f64();
+
+            // Every core runs its own idle thread, which is left out of the list since its CPU time is just the time nothing else ran
+            if thread.is_idle {
+                num_cpus += 1;
+                continue;
+            }
+
+            busy_secs += cpu_secs;
+            rows.push((
+                pid,
+                tid,
+                thread,
+                if elapsed > 0.0 { cpu_secs * 100.0 / elapsed } else { 0.0 },
+                thread.stats.context_switches.saturating_sub(prev_stats.context_switches),
+                thread.stats.wakeups.saturating_sub(prev_stats.wakeups),
+            ));
+        }
+
+        match self.sort {
+            MonitorSort::Cpu => rows.sort_by(|a, b| b.3.total_cmp(&a.3).then((a.0, a.1).cmp(&(b.0, b.1)))),
+            MonitorSort::Switches => rows.sort_by(|a, b| b.4.cmp(&a.4).then((a.0, a.1).cmp(&(b.0, b.1)))),
+            MonitorSort::Id => {},
+        }
+
+        let num_cpus = num_cpus.max(1);
+        let busy = if elapsed > 0.0 {
+            (busy_secs * 100.0 / (elapsed * num_cpus as f64)).min(100.0)
+        } else {
+            0.0
+        };
+
+        let mut irqs = String::new();
+        for (n, (&count, &prev_count)) in cur.irqs.iter().zip(prev.irqs.iter()).enumerate() {
+            if count != 0 {
+                let _ = write!(irqs, " {}:{:.0}", n, rate(count - prev_count));
+            }
+        }
+
+        let mut lines = vec![
+            format!(
+                "monitor - up {:.1}s, refresh every {} ms, sorted by {}",
+                cur.time.as_secs_f64(),
+                self.interval.as_millis(),
+                self.sort.name()
+            ),
+            format!(
+                "cpu: {:5.1}% busy on {} cores, {} threads in {} processes",
+                busy,
+                num_cpus,
+                rows.len(),
+                cur.num_processes
+            ),
+            format!(
+                "mem: slab {} / {} KiB, pages {} KiB, frames {} / {} KiB free",
+                cur.mem.slab_bytes_allocated() / 1024,
+                cur.mem.slab_bytes_total() / 1024,
+                cur.mem.page_alloc_pages * PAGE_SIZE / 1024,
+                cur.mem.frames_available * PAGE_SIZE / 1024,
+                cur.mem.frames_total * PAGE_SIZE / 1024
+            ),
+            format!("irq/s:{}", if irqs.is_empty() { " none" } else { irqs.as_str() }),
+            String::new(),
+            String::from("  pid   tid   cpu%   switch/s   wakeup/s  name"),
+        ];
+
+        let num_lines = height.saturating_sub(1).max(Self::HEADER_LINES + 1);
+        let visible_rows = num_lines - Self::HEADER_LINES - 1;
+
+        self.scroll = self.scroll.min(rows.len().saturating_sub(visible_rows));
+
+        for &(pid, tid, thread, cpu, switches, wakeups) in rows.iter().skip(self.scroll).take(visible_rows) {
+            lines.push(format!(
+                "{:5} {:5} {:5.1}% {:10.0} {:10.0}  {}",
+                pid,
+                tid,
+                cpu,
+                rate(switches),
+                rate(wakeups),
+                thread.name
+            ));
+        }
+
+        lines.resize(num_lines - 1, String::new());
+        lines.push(format!(
+            "q: quit  +/-: interval  j/k: scroll  s: sort  (threads {}-{} of {})",
+            (self.scroll + 1).min(rows.len()),
+            (self.scroll + visible_rows).min(rows.len()),
+            rows.len()
+        ));
+
+        // Writing into the last column would wrap onto the next line on some terminals, so leave it empty
+        for line in lines.iter_mut() {
+            let end = line.char_indices().nth(width.saturating_sub(1)).map_or(line.len(), |(idx, _)| idx);
+            line.truncate(end);
+        }
+
+        lines
+    }
+
+    /// Handles a key pressed while the monitor is shown. Returns `false` if the monitor should exit.
+    fn handle_key(&mut self, key: MonitorKey) -> bool {
+        match key {
+            MonitorKey::Char('q' | '\x03') => {
+                return false;
+            },
+            MonitorKey::Char('+') => {
+                self.interval = (self.interval * 2).min(Self::MAX_INTERVAL);
+            },
+            MonitorKey::Char('-') => {
+                self.interval = (self.interval / 2).max(Self::MIN_INTERVAL);
+            },
+            MonitorKey::Char('j') | MonitorKey::Down => {
+                // This is clamped to the number of threads when the next frame is rendered
+                self.scroll = self.scroll.saturating_add(1);
+            },
+            MonitorKey::Char('k') | MonitorKey::Up => {
+                self.scroll = self.scroll.saturating_sub(1);
+            },
+            MonitorKey::Char('s') => {
+                self.sort = self.sort.next();
+            },
+            MonitorKey::Char(_) => {},
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MonitorKey {
+    Char(char),
+    Up,
+    Down,
+}
+
+/// Splits bytes read from a TTY into the keys understood by the system monitor. Escape sequences may be split across multiple reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MonitorKeyDecoder {
+    Normal,
+    Escape,
+    Csi,
+}
+
+impl MonitorKeyDecoder {
+    fn decode(&mut self, b: u8) -> Option<MonitorKey> {
+        match (*self, b) {
+            (MonitorKeyDecoder::Normal, b'\x1b') => {
+                *self = MonitorKeyDecoder::Escape;
+                None
+            },
+            (MonitorKeyDecoder::Normal, _) => Some(MonitorKey::Char(b as char)),
+            (MonitorKeyDecoder::Escape, b'[') => {
+                *self = MonitorKeyDecoder::Csi;
+                None
+            },
+            (MonitorKeyDecoder::Escape, _) => {
+                *self = MonitorKeyDecoder::Normal;
+                None
+            },
+            (MonitorKeyDecoder::Csi, b'@'..=b'~') => {
+                *self = MonitorKeyDecoder::Normal;
+
+                match b {
+                    b'A' => Some(MonitorKey::Up),
+                    b'B' => Some(MonitorKey::Down),
+                    _ => None,
+                }
+            },
+            (MonitorKeyDecoder::Csi, _) => None,
+        }
+    }
+}
+
+/// Shows a full-screen system monitor on the provided TTY, refreshing it periodically until `q` is pressed. Unlike other commands, this
+/// needs to read from the TTY while it is running, so it cannot be used as part of a pipeline.
+fn run_monitor_cmd<T: Tty + ?Sized>(tty: &T, w: &mut TtyWriter<T>, args: &[&str]) -> Result<(), fmt::Error> {
+    let interval = match args.get(0).map(|a| a.parse::<u64>()) {
+        None => core::time::Duration::from_secs(1),
+        Some(Ok(ms)) if ms > 0 => core::time::Duration::from_millis(ms).clamp(MonitorView::MIN_INTERVAL, MonitorView::MAX_INTERVAL),
+        Some(_) => {
+            writeln!(w, "usage: monitor [interval_ms]")?;
+            return Ok(());
+        },
+    };
+
+    if !tty.capabilities().contains(TtyCapabilities::CURSOR) {
+        writeln!(w, "monitor needs a terminal with cursor control, use 'top' instead")?;
+        return Ok(());
+    }
+
+    let mut view = MonitorView {
+        interval,
+        sort: MonitorSort::Cpu,
+        scroll: 0,
+    };
+    let mut decoder = MonitorKeyDecoder::Normal;
+
+    let mut prev = MonitorSample::take();
+    let mut cur = prev.clone();
+    let mut next_sample = cur.time + view.interval;
+    let mut lines_drawn = 0;
+
+    let mut buf = [0_u8; 16];
+
+    // SAFETY: The loop below only exits once this read (or a read started to replace it) has completed, so buf outlives it
+    let mut read = unsafe { tty.read(&mut buf[..]) };
+    let mut resized = Some(tty.size_changed()).filter(|f| !f.is_ready());
+
+    let result = loop {
+        let lines = view.render(&prev, &cur, tty.size().unwrap_or((80, 25)));
+        let mut frame = String::new();
+
+        // The frame is redrawn in place by moving back up to where the previous frame started
+        if lines_drawn != 0 {
+            let _ = write!(frame, "\x1b[{}A", lines_drawn);
+        }
+
+        for line in lines.iter() {
+            let _ = writeln!(frame, "{}\x1b[K", line);
+        }
+
+        if let Err(err) = w.write_str(&frame) {
+            break Err(err);
+        }
+        lines_drawn = lines.len();
+
+        let mut wakeups = vec![read.without_val(), timer::at(next_sample)];
+        if let Some(ref resized) = resized {
+            wakeups.push(resized.without_val());
+        }
+        Future::any(wakeups).unwrap().unwrap_blocking();
+
+        if timer::now() >= next_sample {
+            prev = core::mem::replace(&mut cur, MonitorSample::take());
+            next_sample = cur.time + view.interval;
+        }
+
+        // There's nothing to do when the size changes other than redrawing, which happens on every iteration anyway
+        resized = match resized.map(Future::try_unwrap) {
+            Some(Ok(Ok(_))) => Some(tty.size_changed()),
+            Some(Err(resized)) => Some(resized),
+            Some(Ok(Err(()))) | None => None,
+        };
+
+        match read.try_unwrap() {
+            Ok(Ok(len)) => {
+                let old_interval = view.interval;
+
+                if !buf[..len].iter().filter_map(|&b| decoder.decode(b)).all(|key| view.handle_key(key)) {
+                    return Ok(());
+                }
+
+                if view.interval != old_interval {
+                    next_sample = cur.time + view.interval;
+                }
+
+                // SAFETY: See above
+                read = unsafe { tty.read(&mut buf[..]) };
+            },
+            Ok(Err(())) => {
+                return Err(fmt::Error);
+            },
+            Err(pending) => {
+                read = pending;
+            },
+        }
+    };
+
+    // The buffer can't be freed until the TTY is done with it, even though the monitor has already stopped
+    let _ = read.unwrap_blocking();
+    result
+}
+
+fn run_vmmap_cmd<W: Write>(w: &mut W, args: &[&str]) -> Result<(), fmt::Error> {
+    use crate::arch::page::{PageFlags, PAGE_SIZE};
+
+    let pid = if let Some(pid) = args.get(0).and_then(|a| a.parse::<u64>().ok()) {
+        pid
+    } else {
+        writeln!(w, "usage: vmmap <pid>")?;
+        return Ok(());
+    };
+
+    let p = if let Some(p) = Process::by_pid(pid) {
+        p
+    } else {
+        writeln!(w, "no process found with pid {}", pid)?;
+        return Ok(());
+    };
+
+    if p.is_kernel_process() {
+        writeln!(w, "the kernel process has no user address space")?;
+        return Ok(());
+    }
+
+    let regions = p.lock().region_usage();
+    let mut total = RegionUsage::default();
+
+    for (region, usage) in regions.iter() {
+        let flags = region.flags();
+
+        writeln!(
+            w,
+            "{:#014x}-{:#014x} {}{}{} {:<12} rss {} KiB swap {} KiB {}",
+            region.range().start().as_u64(),
+            region.range().end().as_u64(),
+            if flags.contains(PageFlags::USER) { 'u' } else { '-' },
+            if flags.contains(PageFlags::WRITEABLE) { 'w' } else { '-' },
+            if flags.contains(PageFlags::EXECUTABLE) { 'x' } else { '-' },
+            format!("{}", region.backing()),
+            usage.resident_pages * PAGE_SIZE / 1024,
+            usage.swapped_pages * PAGE_SIZE / 1024,
+            region.name()
+        )?;
+
+        total.resident_pages += usage.resident_pages;
+        total.swapped_pages += usage.swapped_pages;
+    }
+
+    writeln!(
+        w,
+        "{} regions, rss {} KiB, swap {} KiB",
+        regions.len(),
+        total.resident_pages * PAGE_SIZE / 1024,
+        total.swapped_pages * PAGE_SIZE / 1024
+    )?;
+
+    Ok(())
+}
+
+fn run_debug_console_command<W: Write>(w: &mut W, cmd: &[&str]) -> Result<(), fmt::Error> {
+    match cmd[0] {
+        "allocprof" => {
+            run_allocprof_cmd(w, &cmd[1..])?;
+        },
+        "bootchart" => {
+            run_bootchart_cmd(w, &cmd[1..])?;
+        },
+        "cpuinfo" => {
+            run_cpuinfo_cmd(w, &cmd[1..])?;
+        },
+        "dev" => {
+            run_dev_cmd(w, &cmd[1..])?;
+        },
+        "dis" => {
+            run_dis_cmd(w, &cmd[1..])?;
+        },
+        "display" => {
+            run_display_cmd(w, &cmd[1..])?;
+        },
+        "proc" => {
+            run_proc_cmd(w, &cmd[1..])?;
+        },
+        "futures" => {
+            run_futures_cmd(w, &cmd[1..])?;
+        },
+        "iostat" => {
+            run_iostat_cmd(w, &cmd[1..])?;
+        },
+        "kbd" => {
+            run_kbd_cmd(w, &cmd[1..])?;
+        },
+        "keymap" => {
+            run_keymap_cmd(w, &cmd[1..])?;
+        },
+        "frame" => {
+            run_frame_cmd(w, &cmd[1..])?;
+        },
+        "mem" => {
+            run_mem_cmd(w, &cmd[1..])?;
+        },
+        "mouse" => {
+            run_mouse_cmd(w, &cmd[1..])?;
+        },
+        "ramdisk" => {
+            run_ramdisk_cmd(w, &cmd[1..])?;
+        },
+        "replay" => {
+            run_replay_cmd(w, &cmd[1..])?;
+        },
+        "slab" => {
+            run_slab_cmd(w, &cmd[1..])?;
+        },
+        "swap" => {
+            run_swap_cmd(w, &cmd[1..])?;
+        },
+        "top" => {
+            run_top_cmd(w, &cmd[1..])?;
+        },
+        "version" => {
+            run_version_cmd(w, &cmd[1..])?;
+        },
+        "vmmap" => {
+            run_vmmap_cmd(w, &cmd[1..])?;
+        },
+        "xxd" => {
+            run_xxd_cmd(w, &cmd[1..])?;
+        },
+        "reboot" => {
+            writeln!(w, "rebooting...")?;
+            shutdown::shutdown(ShutdownKind::Reboot);
+        },
+        "shutdown" => {
+            writeln!(w, "shutting down...")?;
+            shutdown::shutdown(ShutdownKind::PowerOff);
+        },
+        "help" => match cmd.get(1) {
+            None => {
+                writeln!(w, "available commands are:")?;
+                writeln!(w, "  allocprof [count] - call sites with the most live allocations")?;
+                writeln!(w, "  bootchart - boot timeline")?;
+                writeln!(w, "  cpuinfo - processor topology")?;
+                writeln!(w, "  dev - device information")?;
+                writeln!(w, "  dis <addr> [count] - split kernel machine code into instructions")?;
+                writeln!(w, "  display - display power and brightness control")?;
+                writeln!(w, "  frame - physical frame information")?;
+                writeln!(w, "  futures [min_age_ms] - list long-pending futures")?;
+                writeln!(w, "  futures stats - show counters of created, freed and resolved futures")?;
+                writeln!(w, "  iostat [dev] - device I/O statistics and latency histograms")?;
+                writeln!(w, "  kbd - keyboard diagnostics")?;
+                writeln!(w, "  keymap - list and define keymaps")?;
+                writeln!(w, "  mem - kernel memory usage")?;
+                writeln!(w, "  monitor [interval_ms] - full-screen view of CPU, memory and interrupt load")?;
+                writeln!(w, "  mouse - list mice and their buttons")?;
+                writeln!(w, "  proc - process information")?;
+                writeln!(w, "  ramdisk - create and remove RAM-backed block devices")?;
+                writeln!(w, "  reboot - reboot the machine")?;
+                writeln!(w, "  replay [count] - recent interrupt and scheduling events")?;
+                writeln!(w, "  shutdown - power off the machine")?;
+                writeln!(w, "  slab - slab alloc statistics")?;
+                writeln!(w, "  swap - swap space statistics")?;
+                writeln!(w, "  top [interval_ms] - thread CPU usage")?;
+                writeln!(w, "  version - kernel version, commit and build configuration")?;
+                writeln!(w, "  vmmap <pid> - process memory map")?;
+                writeln!(w, "  xxd <addr> [len] - hexdump of kernel memory")?;
+                writeln!(w)?;
+                writeln!(w, "output can be filtered by piping it into another command:")?;
+                writeln!(w, "  <cmd> | grep [-v] [-i] <pattern> - only show matching lines")?;
+                writeln!(w, "  <cmd> | head [count] - only show the first lines")?;
+                writeln!(w)?;
+                writeln!(w, "run 'help <cmd>' for more information")?;
+            },
+            Some(&"dev") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  dev ls [dev] - list devices")?;
+                writeln!(w, "  dev print [dev] - print device")?;
+                writeln!(w, "  dev info <dev> - show uptime, error count and status of a device")?;
+                writeln!(w, "  dev remove <dev> - disconnect a device and everything connected below it")?;
+                writeln!(w, "  dev drivers - list drivers and the devices bound to them")?;
+            },
+            Some(&"display") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  display list - list displays with their power state and brightness")?;
+                writeln!(w, "  display <id> power [on|standby|off] - get or set the power state of a display")?;
+                writeln!(w, "  display <id> brightness [level] - get or set the backlight brightness")?;
+                writeln!(w, "  display blank [secs|off] - get or set how long displays stay on without input")?;
+            },
+            Some(&"frame") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  frame stats - print frame allocator statistics")?;
+                writeln!(w, "  frame bad - list frames reported as bad")?;
+            },
+            Some(&"kbd") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  kbd <dev> echo - check that the keyboard responds to an echo command")?;
+                writeln!(w, "  kbd <dev> test - reset and self-test the keyboard")?;
+                writeln!(w, "  kbd <dev> typematic [delay_ms rate_hz] - get or set hardware key repeat")?;
+                writeln!(w, "  kbd <dev> repeat [off|delay_ms rate_hz] - get or set software key repeat")?;
+                writeln!(w, "  kbd <dev> enable|disable - start or stop delivering key presses")?;
+                writeln!(w, "  kbd <dev> keymap [name] - get or set the keymap used by the keyboard")?;
+                writeln!(w, "  kbd <dev> locks [caps] [num] [scroll] [none] - get or set active lock keys")?;
+                writeln!(w, "  kbd <dev> leds [caps] [num] [scroll] [none] - light LEDs until a lock changes")?;
+                writeln!(w, "  kbd <dev> sticky [on|off] - latch modifier keys until the next key is pressed")?;
+                writeln!(w, "  kbd <dev> slowkeys [ms] - only accept keys held for some time (0 to disable)")?;
+                writeln!(w, "  kbd list - list keyboards attached to displays")?;
+            },
+            Some(&"keymap") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  keymap list - list available keymaps")?;
+                writeln!(w, "  keymap default [name] - get or set the keymap for newly connected keyboards")?;
+                writeln!(w, "  keymap define <name> [entry]... - create a keymap from one line per argument")?;
+                writeln!(w, "  keymap strings - show how many strings runtime keymaps have interned")?;
+            },
+            Some(&"mem") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  mem stats - print memory usage of each kernel allocator")?;
+                writeln!(w, "  mem vmap - print a map of kernel virtual address space")?;
+                writeln!(w, "  mem frag - print kernel virtual address space fragmentation statistics")?;
+                writeln!(w, "  mem image - print the size of the kernel image and its largest statics")?;
+            },
+            Some(&"monitor") => {
+                writeln!(w, "while the monitor is shown, the following keys can be used:")?;
+                writeln!(w, "  q - exit the monitor")?;
+                writeln!(w, "  + / - - double or halve the refresh interval")?;
+                writeln!(w, "  j / k / up / down - scroll the thread list")?;
+                writeln!(w, "  s - sort threads by cpu usage, context switches or id")?;
+            },
+            Some(&"proc") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  proc ls - list processes")?;
+                writeln!(w, "  proc threads <pid> - list threads in process")?;
+            },
+            Some(&"ramdisk") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  ramdisk list - list ramdisks with their sizes")?;
+                writeln!(w, "  ramdisk create <name> <size_kib> - create a zeroed ramdisk under ::block")?;
+                writeln!(w, "  ramdisk remove <name> - remove a ramdisk and free its contents")?;
+            },
+            Some(&"slab") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  slab stats - print slab allocator statistics")?;
+            },
+            Some(&"swap") => {
+                writeln!(w, "available subcommands are:")?;
+                writeln!(w, "  swap stats - print swap space and zram statistics")?;
+                writeln!(w, "  swap on <device> - swap to a block device, overwriting its contents")?;
+                writeln!(w, "  swap off - stop swapping once no pages are swapped out")?;
+            },
+            Some(cmd) => {
+                writeln!(w, "unknown command '{}'", cmd)?;
+            },
+        },
+        _ => {
+            writeln!(w, "unknown command '{}'", cmd[0])?;
+        },
+    }
+
+    Ok(())
+}
+
+struct ParsedCommand<'a> {
+    stages: Vec<Vec<&'a str>>,
+    redirect: Option<&'a str>,
+}
+
+fn parse_command(mut cmd: &str) -> Result<ParsedCommand<'_>, (usize, &'static str)> {
+    let mut stages = vec![];
+    let mut result = vec![];
+    let mut redirect = None;
+    let mut in_redirect = false;
+    let mut idx = 0;
+
+    loop {
+        while cmd.starts_with(' ') {
+            cmd = &cmd[1..];
+            idx += 1;
+        }
+
+        if cmd.is_empty() {
+            break;
+        }
+
+        let arg = if cmd.starts_with('|') || cmd.starts_with('>') {
+            if in_redirect || redirect.is_some() {
+                return Err((idx, "unexpected token after redirection"));
+            } else if result.is_empty() {
+                return Err((idx, "missing command"));
+            }
+
+            if cmd.starts_with('|') {
+                stages.push(core::mem::take(&mut result));
+            } else {
+                in_redirect = true;
+            }
+
+            cmd = &cmd[1..];
+            idx += 1;
+            continue;
+        } else if cmd.starts_with('"') {
+            // TODO Escape sequences?
+            if let Some(quote_len) = cmd[1..].find('"') {
+                let arg = &cmd[1..quote_len + 1];
+                cmd = &cmd[quote_len + 2..];
+                idx += quote_len + 2;
+                arg
+            } else {
+                return Err((idx, "unterminated quoted argument"));
+            }
+        } else {
+            let len = cmd.find([' ', '|', '>']).unwrap_or(cmd.len());
+            let arg = &cmd[..len];
+            cmd = &cmd[len..];
+            idx += len;
+            arg
+        };
+
+        if in_redirect {
+            redirect = Some(arg);
+            in_redirect = false;
+        } else if redirect.is_some() {
+            return Err((idx - arg.len(), "unexpected token after redirection"));
+        } else {
+            result.push(arg);
+        }
+    }
+
+    if in_redirect {
+        return Err((idx, "missing redirection target"));
+    } else if result.is_empty() && !stages.is_empty() {
+        return Err((idx, "missing command"));
+    }
+
+    if result.is_empty() {
+        result.push("");
+    }
+
+    stages.push(result);
+    Ok(ParsedCommand { stages, redirect })
+}
+
+fn run_pipeline_stage<W: Write>(w: &mut W, cmd: &[&str], input: Option<&str>) -> Result<(), fmt::Error> {
+    match (cmd[0], input) {
+        ("grep", Some(input)) => run_grep_cmd(w, &cmd[1..], input),
+        ("head", Some(input)) => run_head_cmd(w, &cmd[1..], input),
+        ("grep" | "head", None) => writeln!(w, "{} can only be used to filter piped output", cmd[0]),
+        ("monitor", _) => writeln!(w, "monitor cannot be used in a pipeline"),
+        _ => run_debug_console_command(w, cmd),
+    }
+}
+
+fn run_pipeline<T: Tty + ?Sized>(tty: &T, w: &mut TtyWriter<T>, cmd: &ParsedCommand) -> Result<(), fmt::Error> {
+    if let Some(path) = cmd.redirect {
+        // TODO Write the output to the file once there's a VFS to open it through
+        writeln!(w, "cannot redirect to '{}': no filesystem is available", path)?;
+        return Ok(());
+    }
+
+    if let [stage] = &cmd.stages[..] {
+        if stage[0] == "monitor" {
+            return run_monitor_cmd(tty, w, &stage[1..]);
+        }
+    }
+
+    let (last, rest) = cmd.stages.split_last().unwrap();
+    let mut input = None;
+
+    for stage in rest {
+        let mut output = String::new();
+        run_pipeline_stage(&mut output, stage, input.as_deref())?;
+        input = Some(output);
+    }
+
+    run_pipeline_stage(w, last, input.as_deref())
+}
+
+pub fn show_debug_console<T: Tty + ?Sized>(tty: &T) {
+    let mut r = TtyCharReader::new(tty);
+    let mut w = TtyWriter::new(tty);
 
     let mut history = CommandHistory { buf: ArrayDeque::new() };
 
     loop {
         let _ = write!(w, "hkd> ");
-        let cmd = readline(&mut r, &mut w, &mut history);
+
+        // Checked before every line, since the terminal on the other end of the TTY may change while the console is running
+        let cmd = if tty.capabilities().contains(TtyCapabilities::CURSOR) {
+            readline(&mut r, &mut w, &mut history)
+        } else {
+            readline_basic(&mut r, &mut w, &mut history)
+        };
 
         if let Ok(cmd) = cmd {
             match parse_command(&cmd) {
                 Ok(parsed_cmd) => {
-                    let _ = run_debug_console_command(&mut w, &parsed_cmd);
+                    let _ = run_pipeline(tty, &mut w, &parsed_cmd);
                 },
                 Err((_, msg)) => {
                     let _ = writeln!(w, "parse error: {}", msg);
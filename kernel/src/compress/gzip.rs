@@ -0,0 +1,374 @@
+//! Decompression of data in the gzip format (RFC 1952).
+//!
+//! A gzip member is a DEFLATE stream wrapped in a header and a trailer holding the CRC-32 and length of the decompressed data. Like the
+//! underlying [`Inflater`], [`GzipDecoder`] is streaming and uses a bounded amount of memory regardless of the size of the data. Only the
+//! first member of the input is decompressed; anything following it is left unconsumed.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::inflate::{InflateError, InflateProgress, Inflater};
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const METHOD_DEFLATE: u8 = 8;
+
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+const FLAG_RESERVED: u8 = 0xe0;
+
+const HEADER_LEN: usize = 10;
+const TRAILER_LEN: usize = 8;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+
+        while j < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            j += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+/// Updates a running CRC-32 (as used by gzip and zlib) with the provided data. The CRC of an empty buffer is 0.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data
+        .iter()
+        .fold(!crc, |crc, &b| CRC32_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// An error that occurred while decompressing gzip data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GzipError {
+    /// The data did not start with a valid gzip header.
+    InvalidHeader,
+    /// The data was compressed using a method other than DEFLATE.
+    UnsupportedMethod(u8),
+    /// The compressed data was not valid.
+    Inflate(InflateError),
+    /// The CRC-32 of the decompressed data did not match the one in the trailer.
+    ChecksumMismatch,
+    /// The length of the decompressed data did not match the one in the trailer.
+    LengthMismatch,
+    /// The data ended before the end of the gzip member.
+    Truncated,
+    /// The decompressed data was larger than allowed.
+    TooLarge,
+}
+
+impl From<InflateError> for GzipError {
+    fn from(err: InflateError) -> GzipError {
+        GzipError::Inflate(err)
+    }
+}
+
+impl fmt::Display for GzipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GzipError::InvalidHeader => write!(f, "invalid gzip header"),
+            GzipError::UnsupportedMethod(method) => write!(f, "unsupported compression method {}", method),
+            GzipError::Inflate(err) => write!(f, "{}", err),
+            GzipError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            GzipError::LengthMismatch => write!(f, "length mismatch"),
+            GzipError::Truncated => write!(f, "truncated data"),
+            GzipError::TooLarge => write!(f, "decompressed data too large"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GzipState {
+    Header,
+    ExtraLen,
+    Extra { remaining: usize },
+    Name,
+    Comment,
+    HeaderCrc,
+    Body,
+    Trailer,
+    Done,
+}
+
+/// A streaming decompressor for a gzip member.
+pub struct GzipDecoder {
+    state: GzipState,
+    flags: u8,
+    fixed: [u8; HEADER_LEN],
+    fixed_len: usize,
+    inflater: Inflater,
+    crc: u32,
+}
+
+impl GzipDecoder {
+    /// Creates a decompressor positioned at the start of a gzip member.
+    pub fn new() -> GzipDecoder {
+        GzipDecoder {
+            state: GzipState::Header,
+            flags: 0,
+            fixed: [0; HEADER_LEN],
+            fixed_len: 0,
+            inflater: Inflater::new(),
+            crc: 0,
+        }
+    }
+
+    /// Checks whether the end of the gzip member has been reached and its trailer has been verified.
+    pub fn is_done(&self) -> bool {
+        self.state == GzipState::Done
+    }
+
+    /// Collects bytes of input into the fixed-size buffer until it holds `len` bytes, returning `false` if the input runs out first.
+    fn fill_fixed(&mut self, input: &[u8], pos: &mut usize, len: usize) -> bool {
+        let n = (len - self.fixed_len).min(input.len() - *pos);
+
+        self.fixed[self.fixed_len..self.fixed_len + n].copy_from_slice(&input[*pos..*pos + n]);
+        self.fixed_len += n;
+        *pos += n;
+
+        if self.fixed_len == len {
+            self.fixed_len = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skips input up to and including the next zero byte, returning `false` if the input runs out first.
+    fn skip_string(input: &[u8], pos: &mut usize) -> bool {
+        match input[*pos..].iter().position(|&b| b == 0) {
+            Some(len) => {
+                *pos += len + 1;
+                true
+            },
+            None => {
+                *pos = input.len();
+                false
+            },
+        }
+    }
+
+    /// Moves on to the next optional header field after `state` that is present according to the header flags.
+    fn next_header_field(&self, state: GzipState) -> GzipState {
+        let fields = [
+            (GzipState::ExtraLen, FLAG_EXTRA),
+            (GzipState::Name, FLAG_NAME),
+            (GzipState::Comment, FLAG_COMMENT),
+            (GzipState::HeaderCrc, FLAG_HCRC),
+        ];
+        let start = match state {
+            GzipState::Header => 0,
+            GzipState::ExtraLen | GzipState::Extra { .. } => 1,
+            GzipState::Name => 2,
+            GzipState::Comment => 3,
+            _ => 4,
+        };
+
+        fields[start..]
+            .iter()
+            .find(|&&(_, flag)| self.flags & flag != 0)
+            .map_or(GzipState::Body, |&(state, _)| state)
+    }
+
+    /// Decompresses as much of the provided input as possible into the provided output buffer, following the same rules as
+    /// [`Inflater::inflate`]. The CRC-32 and length of the decompressed data are verified once the trailer is reached.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Result<InflateProgress, GzipError> {
+        let mut pos = 0;
+        let mut out = 0;
+
+        loop {
+            match self.state {
+                GzipState::Header => {
+                    if !self.fill_fixed(input, &mut pos, HEADER_LEN) {
+                        break;
+                    }
+
+                    if self.fixed[..2] != MAGIC || self.fixed[3] & FLAG_RESERVED != 0 {
+                        return Err(GzipError::InvalidHeader);
+                    } else if self.fixed[2] != METHOD_DEFLATE {
+                        return Err(GzipError::UnsupportedMethod(self.fixed[2]));
+                    }
+
+                    self.flags = self.fixed[3];
+                    self.state = self.next_header_field(GzipState::Header);
+                },
+                GzipState::ExtraLen => {
+                    if !self.fill_fixed(input, &mut pos, 2) {
+                        break;
+                    }
+
+                    self.state = GzipState::Extra {
+                        remaining: usize::from(u16::from_le_bytes([self.fixed[0], self.fixed[1]])),
+                    };
+                },
+                GzipState::Extra { remaining } => {
+                    let n = remaining.min(input.len() - pos);
+
+                    pos += n;
+
+                    if n != remaining {
+                        self.state = GzipState::Extra { remaining: remaining - n };
+                        break;
+                    }
+
+                    self.state = self.next_header_field(self.state);
+                },
+                GzipState::Name | GzipState::Comment => {
+                    if !GzipDecoder::skip_string(input, &mut pos) {
+                        break;
+                    }
+
+                    self.state = self.next_header_field(self.state);
+                },
+                GzipState::HeaderCrc => {
+                    // The header CRC only covers the header itself and is rarely present, so it isn't checked
+                    if !self.fill_fixed(input, &mut pos, 2) {
+                        break;
+                    }
+
+                    self.state = GzipState::Body;
+                },
+                GzipState::Body => {
+                    let progress = self.inflater.inflate(&input[pos..], &mut output[out..])?;
+
+                    self.crc = crc32_update(self.crc, &output[out..out + progress.produced]);
+                    pos += progress.consumed;
+                    out += progress.produced;
+
+                    if !progress.done {
+                        break;
+                    }
+
+                    self.state = GzipState::Trailer;
+                },
+                GzipState::Trailer => {
+                    if !self.fill_fixed(input, &mut pos, TRAILER_LEN) {
+                        break;
+                    }
+
+                    let crc = u32::from_le_bytes(self.fixed[0..4].try_into().unwrap());
+                    let len = u32::from_le_bytes(self.fixed[4..8].try_into().unwrap());
+
+                    if crc != self.crc {
+                        return Err(GzipError::ChecksumMismatch);
+                    } else if len != self.inflater.total_out() as u32 {
+                        return Err(GzipError::LengthMismatch);
+                    }
+
+                    self.state = GzipState::Done;
+                },
+                GzipState::Done => {
+                    break;
+                },
+            }
+        }
+
+        Ok(InflateProgress {
+            consumed: pos,
+            produced: out,
+            done: self.is_done(),
+        })
+    }
+}
+
+impl Default for GzipDecoder {
+    fn default() -> GzipDecoder {
+        GzipDecoder::new()
+    }
+}
+
+/// Decompresses the first gzip member in the provided data, returning the decompressed data. Fails with [`GzipError::TooLarge`] if the
+/// decompressed data would be larger than `max_len` bytes.
+pub fn decompress_to_vec(src: &[u8], max_len: usize) -> Result<Vec<u8>, GzipError> {
+    let mut decoder = GzipDecoder::new();
+    let mut data = Vec::new();
+    let mut buf = [0; 4096];
+    let mut pos = 0;
+
+    loop {
+        let progress = decoder.decompress(&src[pos..], &mut buf)?;
+
+        pos += progress.consumed;
+
+        if data.len() + progress.produced > max_len {
+            return Err(GzipError::TooLarge);
+        }
+
+        data.extend_from_slice(&buf[..progress.produced]);
+
+        if progress.done {
+            return Ok(data);
+        } else if progress.produced == 0 && pos == src.len() {
+            return Err(GzipError::Truncated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HELLO: [u8; 35] = [
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x61, 0x2e, 0x74, 0x78, 0x74, 0x00, 0xcb, 0xa8, 0x4c, 0x29, 0xca, 0xaf,
+        0xc8, 0x2f, 0xe6, 0x02, 0x00, 0x4c, 0x5e, 0x1b, 0x99, 0x09, 0x00, 0x00, 0x00,
+    ];
+
+    #[test_case]
+    fn test_crc32() {
+        assert_eq!(0, crc32_update(0, b""));
+        assert_eq!(0xcbf43926, crc32_update(0, b"123456789"));
+        assert_eq!(0xcbf43926, crc32_update(crc32_update(0, b"1234"), b"56789"));
+    }
+
+    #[test_case]
+    fn test_decompress() {
+        assert_eq!(Ok(b"hydroxos\n".to_vec()), decompress_to_vec(&HELLO, 1024));
+
+        let mut decoder = GzipDecoder::new();
+        let mut data = Vec::new();
+
+        for b in HELLO {
+            let mut buf = [0; 16];
+            let progress = decoder.decompress(&[b], &mut buf).unwrap();
+
+            assert_eq!(1, progress.consumed);
+            data.extend_from_slice(&buf[..progress.produced]);
+        }
+
+        assert!(decoder.is_done());
+        assert_eq!(b"hydroxos\n", &data[..]);
+    }
+
+    #[test_case]
+    fn test_decompress_invalid() {
+        let mut data = HELLO;
+
+        assert_eq!(Err(GzipError::TooLarge), decompress_to_vec(&data, 4));
+        assert_eq!(Err(GzipError::Truncated), decompress_to_vec(&data[..30], 1024));
+
+        data[27] ^= 1;
+        assert_eq!(Err(GzipError::ChecksumMismatch), decompress_to_vec(&data, 1024));
+
+        data = HELLO;
+        data[31] ^= 1;
+        assert_eq!(Err(GzipError::LengthMismatch), decompress_to_vec(&data, 1024));
+
+        data = HELLO;
+        data[0] = 0;
+        assert_eq!(Err(GzipError::InvalidHeader), decompress_to_vec(&data, 1024));
+
+        data = HELLO;
+        data[2] = 0;
+        assert_eq!(Err(GzipError::UnsupportedMethod(0)), decompress_to_vec(&data, 1024));
+    }
+}
@@ -0,0 +1,608 @@
+//! Decompression of data in the DEFLATE format (RFC 1951), as used by gzip and zlib.
+//!
+//! The decompressor is streaming: [`Inflater::inflate`] can be given the compressed data in chunks of any size and fills an output buffer
+//! of any size, picking up where it left off on the next call. This makes it possible to decompress large images (e.g. a compressed
+//! initramfs) without holding the whole compressed or decompressed data in memory at once. Apart from the [`Inflater`] itself, the only
+//! memory used is the 32KiB window of previously decompressed data that back-references can refer to.
+//!
+//! Huffman codes are decoded one bit at a time rather than using lookup tables. This is slower than most decompressors, but keeps the
+//! tables small and the code simple, which is the right trade-off for data that is only decompressed once during boot.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The size of the window of previously decompressed data that back-references can refer to.
+const WINDOW_SIZE: usize = 32768;
+
+const MAX_BITS: u32 = 15;
+const MAX_LIT_CODES: usize = 286;
+const MAX_DIST_CODES: usize = 30;
+const NUM_FIXED_LIT_CODES: usize = 288;
+const NUM_CODE_LENGTH_CODES: usize = 19;
+const END_OF_BLOCK: u16 = 256;
+
+/// The order in which the code lengths of the code length alphabet are stored in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; NUM_CODE_LENGTH_CODES] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385,
+    24577,
+];
+const DIST_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// An error indicating that data passed to an [`Inflater`] was not valid DEFLATE-compressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    /// A block header specified the reserved block type.
+    InvalidBlockType,
+    /// The length of a stored block did not match its one's complement.
+    InvalidStoredLength,
+    /// A dynamic block header described a Huffman code that is over-subscribed or has too many codes.
+    InvalidHuffmanTable,
+    /// The data contained a code that is not part of the current Huffman code or that is reserved.
+    InvalidCode,
+    /// A back-reference pointed before the start of the decompressed data.
+    InvalidDistance,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InflateError::InvalidBlockType => write!(f, "invalid block type"),
+            InflateError::InvalidStoredLength => write!(f, "invalid stored block length"),
+            InflateError::InvalidHuffmanTable => write!(f, "invalid Huffman table"),
+            InflateError::InvalidCode => write!(f, "invalid code"),
+            InflateError::InvalidDistance => write!(f, "invalid distance"),
+        }
+    }
+}
+
+/// The result of a call to [`Inflater::inflate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InflateProgress {
+    /// The number of bytes of input that were consumed.
+    pub consumed: usize,
+    /// The number of bytes of decompressed data that were written to the output buffer.
+    pub produced: usize,
+    /// Whether the end of the compressed data has been reached. Any input after the end is not consumed.
+    pub done: bool,
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length and the symbols in order of their codes.
+struct Huffman {
+    counts: [u16; MAX_BITS as usize + 1],
+    symbols: [u16; NUM_FIXED_LIT_CODES],
+}
+
+impl Huffman {
+    const fn empty() -> Huffman {
+        Huffman {
+            counts: [0; MAX_BITS as usize + 1],
+            symbols: [0; NUM_FIXED_LIT_CODES],
+        }
+    }
+
+    /// Builds the Huffman code in which the symbol at each index has a code of the provided length, where 0 means that the symbol is not
+    /// used. Incomplete codes are allowed, since they are valid when only a single distance code is used.
+    fn build(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut huffman = Huffman::empty();
+
+        for &len in lengths {
+            huffman.counts[usize::from(len)] += 1;
+        }
+
+        huffman.counts[0] = 0;
+
+        let mut left = 1_i32;
+
+        for len in 1..=MAX_BITS as usize {
+            left = (left << 1) - i32::from(huffman.counts[len]);
+
+            if left < 0 {
+                return Err(InflateError::InvalidHuffmanTable);
+            }
+        }
+
+        let mut offsets = [0_u16; MAX_BITS as usize + 2];
+
+        for len in 1..=MAX_BITS as usize {
+            offsets[len + 1] = offsets[len] + huffman.counts[len];
+        }
+
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                huffman.symbols[usize::from(offsets[usize::from(len)])] = sym as u16;
+                offsets[usize::from(len)] += 1;
+            }
+        }
+
+        Ok(huffman)
+    }
+
+    fn fixed_lit() -> Huffman {
+        let mut lengths = [0; NUM_FIXED_LIT_CODES];
+
+        lengths[..144].fill(8);
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        lengths[280..].fill(8);
+
+        Huffman::build(&lengths).unwrap()
+    }
+
+    fn fixed_dist() -> Huffman {
+        Huffman::build(&[5; MAX_DIST_CODES]).unwrap()
+    }
+}
+
+/// Bits of input that have been taken from the input buffer but not yet consumed, in the order they appear in the stream.
+struct BitReader {
+    buf: u64,
+    count: u32,
+}
+
+impl BitReader {
+    /// Takes bytes from the input until at least `n` bits are available, returning `false` if the input runs out first.
+    fn ensure(&mut self, input: &[u8], pos: &mut usize, n: u32) -> bool {
+        while self.count < n {
+            let Some(&b) = input.get(*pos) else {
+                return false;
+            };
+
+            self.buf |= u64::from(b) << self.count;
+            self.count += 8;
+            *pos += 1;
+        }
+
+        true
+    }
+
+    /// Gets `n` bits starting `offset` bits into the available bits without consuming them.
+    fn peek(&self, offset: u32, n: u32) -> u32 {
+        debug_assert!(offset + n <= self.count);
+        ((self.buf >> offset) & ((1 << n) - 1)) as u32
+    }
+
+    fn consume(&mut self, n: u32) {
+        debug_assert!(n <= self.count);
+        self.buf >>= n;
+        self.count -= n;
+    }
+
+    /// Decodes a symbol using the provided Huffman code starting `offset` bits into the available bits, returning the symbol and the length
+    /// of its code without consuming it. Returns [`None`] if the input runs out first.
+    fn decode(&mut self, huffman: &Huffman, input: &[u8], pos: &mut usize, offset: u32) -> Result<Option<(u16, u32)>, InflateError> {
+        let mut code = 0_i32;
+        let mut first = 0_i32;
+        let mut index = 0_i32;
+
+        // Huffman codes are packed starting from their most significant bit, so they have to be read one bit at a time
+        for len in 1..=MAX_BITS {
+            if !self.ensure(input, pos, offset + len) {
+                return Ok(None);
+            }
+
+            code |= self.peek(offset + len - 1, 1) as i32;
+
+            let count = i32::from(huffman.counts[len as usize]);
+
+            if code - count < first {
+                return Ok(Some((huffman.symbols[(index + code - first) as usize], len)));
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(InflateError::InvalidCode)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InflateState {
+    BlockHeader,
+    StoredHeader,
+    Stored { remaining: usize },
+    DynamicHeader,
+    CodeLengthCodes { index: usize },
+    CodeLengths { index: usize },
+    Block,
+    Match { len: usize, dist: usize },
+    Done,
+}
+
+/// A streaming decompressor for DEFLATE-compressed data.
+pub struct Inflater {
+    state: InflateState,
+    last_block: bool,
+    bits: BitReader,
+    window: Box<[u8]>,
+    window_pos: usize,
+    total_out: u64,
+    lit: Huffman,
+    dist: Huffman,
+    num_lit_codes: usize,
+    num_dist_codes: usize,
+    num_code_length_codes: usize,
+    code_lengths: Huffman,
+    lengths: [u8; MAX_LIT_CODES + MAX_DIST_CODES],
+}
+
+impl Inflater {
+    /// Creates a decompressor positioned at the start of a DEFLATE stream.
+    pub fn new() -> Inflater {
+        Inflater {
+            state: InflateState::BlockHeader,
+            last_block: false,
+            bits: BitReader { buf: 0, count: 0 },
+            window: vec![0; WINDOW_SIZE].into_boxed_slice(),
+            window_pos: 0,
+            total_out: 0,
+            lit: Huffman::empty(),
+            dist: Huffman::empty(),
+            num_lit_codes: 0,
+            num_dist_codes: 0,
+            num_code_length_codes: 0,
+            code_lengths: Huffman::empty(),
+            lengths: [0; MAX_LIT_CODES + MAX_DIST_CODES],
+        }
+    }
+
+    /// Checks whether the end of the compressed data has been reached.
+    pub fn is_done(&self) -> bool {
+        self.state == InflateState::Done
+    }
+
+    /// Gets the total number of bytes of decompressed data that have been produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    fn emit(&mut self, output: &mut [u8], out: &mut usize, b: u8) {
+        output[*out] = b;
+        *out += 1;
+
+        self.window[self.window_pos] = b;
+        self.window_pos = (self.window_pos + 1) % WINDOW_SIZE;
+        self.total_out += 1;
+    }
+
+    fn end_block(&mut self) {
+        if self.last_block {
+            // Whatever is left of the last byte is padding
+            debug_assert!(self.bits.count < 8);
+            self.bits.consume(self.bits.count);
+            self.state = InflateState::Done;
+        } else {
+            self.state = InflateState::BlockHeader;
+        }
+    }
+
+    /// Performs a single step of decompression, returning `false` if no progress can be made until more input or room for more output is
+    /// provided.
+    fn step(&mut self, input: &[u8], pos: &mut usize, output: &mut [u8], out: &mut usize) -> Result<bool, InflateError> {
+        match self.state {
+            InflateState::BlockHeader => {
+                if !self.bits.ensure(input, pos, 3) {
+                    return Ok(false);
+                }
+
+                let header = self.bits.peek(0, 3);
+
+                self.bits.consume(3);
+                self.last_block = header & 1 != 0;
+                self.state = match header >> 1 {
+                    0 => {
+                        // Stored blocks start at the next byte boundary
+                        self.bits.consume(self.bits.count % 8);
+                        InflateState::StoredHeader
+                    },
+                    1 => {
+                        self.lit = Huffman::fixed_lit();
+                        self.dist = Huffman::fixed_dist();
+                        InflateState::Block
+                    },
+                    2 => InflateState::DynamicHeader,
+                    _ => {
+                        return Err(InflateError::InvalidBlockType);
+                    },
+                };
+            },
+            InflateState::StoredHeader => {
+                if !self.bits.ensure(input, pos, 32) {
+                    return Ok(false);
+                }
+
+                let len = self.bits.peek(0, 16);
+
+                if len != !self.bits.peek(16, 16) & 0xffff {
+                    return Err(InflateError::InvalidStoredLength);
+                }
+
+                self.bits.consume(32);
+                self.state = InflateState::Stored { remaining: len as usize };
+            },
+            InflateState::Stored { remaining: 0 } => {
+                self.end_block();
+            },
+            InflateState::Stored { remaining } => {
+                if *out == output.len() {
+                    return Ok(false);
+                }
+
+                let b = if self.bits.count >= 8 {
+                    let b = self.bits.peek(0, 8) as u8;
+
+                    self.bits.consume(8);
+                    b
+                } else if let Some(&b) = input.get(*pos) {
+                    *pos += 1;
+                    b
+                } else {
+                    return Ok(false);
+                };
+
+                self.emit(output, out, b);
+                self.state = InflateState::Stored { remaining: remaining - 1 };
+            },
+            InflateState::DynamicHeader => {
+                if !self.bits.ensure(input, pos, 14) {
+                    return Ok(false);
+                }
+
+                self.num_lit_codes = self.bits.peek(0, 5) as usize + 257;
+                self.num_dist_codes = self.bits.peek(5, 5) as usize + 1;
+                self.num_code_length_codes = self.bits.peek(10, 4) as usize + 4;
+                self.bits.consume(14);
+
+                if self.num_lit_codes > MAX_LIT_CODES || self.num_dist_codes > MAX_DIST_CODES {
+                    return Err(InflateError::InvalidHuffmanTable);
+                }
+
+                self.lengths = [0; MAX_LIT_CODES + MAX_DIST_CODES];
+                self.state = InflateState::CodeLengthCodes { index: 0 };
+            },
+            InflateState::CodeLengthCodes { index } if index == self.num_code_length_codes => {
+                self.code_lengths = Huffman::build(&self.lengths[..NUM_CODE_LENGTH_CODES])?;
+                self.lengths = [0; MAX_LIT_CODES + MAX_DIST_CODES];
+                self.state = InflateState::CodeLengths { index: 0 };
+            },
+            InflateState::CodeLengthCodes { index } => {
+                if !self.bits.ensure(input, pos, 3) {
+                    return Ok(false);
+                }
+
+                self.lengths[CODE_LENGTH_ORDER[index]] = self.bits.peek(0, 3) as u8;
+                self.bits.consume(3);
+                self.state = InflateState::CodeLengthCodes { index: index + 1 };
+            },
+            InflateState::CodeLengths { index } if index == self.num_lit_codes + self.num_dist_codes => {
+                let (lit_lengths, dist_lengths) = self.lengths[..index].split_at(self.num_lit_codes);
+
+                if lit_lengths[usize::from(END_OF_BLOCK)] == 0 {
+                    return Err(InflateError::InvalidHuffmanTable);
+                }
+
+                self.lit = Huffman::build(lit_lengths)?;
+                self.dist = Huffman::build(dist_lengths)?;
+                self.state = InflateState::Block;
+            },
+            InflateState::CodeLengths { index } => {
+                let Some((sym, len)) = self.bits.decode(&self.code_lengths, input, pos, 0)? else {
+                    return Ok(false);
+                };
+
+                let (extra, base, val) = match sym {
+                    0..=15 => (0, 1, sym as u8),
+                    16 => (2, 3, *self.lengths[..index].last().ok_or(InflateError::InvalidHuffmanTable)?),
+                    17 => (3, 3, 0),
+                    _ => (7, 11, 0),
+                };
+
+                if !self.bits.ensure(input, pos, len + extra) {
+                    return Ok(false);
+                }
+
+                let repeat = base + self.bits.peek(len, extra) as usize;
+                let end = index + repeat;
+
+                if end > self.num_lit_codes + self.num_dist_codes {
+                    return Err(InflateError::InvalidHuffmanTable);
+                }
+
+                self.bits.consume(len + extra);
+                self.lengths[index..end].fill(val);
+                self.state = InflateState::CodeLengths { index: end };
+            },
+            InflateState::Block => {
+                if *out == output.len() {
+                    return Ok(false);
+                }
+
+                let Some((sym, len)) = self.bits.decode(&self.lit, input, pos, 0)? else {
+                    return Ok(false);
+                };
+
+                if sym < END_OF_BLOCK {
+                    self.bits.consume(len);
+                    self.emit(output, out, sym as u8);
+                    return Ok(true);
+                } else if sym == END_OF_BLOCK {
+                    self.bits.consume(len);
+                    self.end_block();
+                    return Ok(true);
+                }
+
+                // The whole back-reference is decoded before any of it is consumed, so that running out of input part way through leaves
+                // the stream positioned at its start
+                let idx = usize::from(sym - 257);
+                let len_extra = *LEN_EXTRA.get(idx).ok_or(InflateError::InvalidCode)?;
+
+                if !self.bits.ensure(input, pos, len + len_extra) {
+                    return Ok(false);
+                }
+
+                let match_len = usize::from(LEN_BASE[idx]) + self.bits.peek(len, len_extra) as usize;
+                let dist_offset = len + len_extra;
+                let Some((dist_sym, dist_len)) = self.bits.decode(&self.dist, input, pos, dist_offset)? else {
+                    return Ok(false);
+                };
+
+                let dist_idx = usize::from(dist_sym);
+                let dist_extra = *DIST_EXTRA.get(dist_idx).ok_or(InflateError::InvalidCode)?;
+
+                if !self.bits.ensure(input, pos, dist_offset + dist_len + dist_extra) {
+                    return Ok(false);
+                }
+
+                let dist = usize::from(DIST_BASE[dist_idx]) + self.bits.peek(dist_offset + dist_len, dist_extra) as usize;
+
+                if dist as u64 > self.total_out {
+                    return Err(InflateError::InvalidDistance);
+                }
+
+                self.bits.consume(dist_offset + dist_len + dist_extra);
+                self.state = InflateState::Match { len: match_len, dist };
+            },
+            InflateState::Match { len: 0, .. } => {
+                self.state = InflateState::Block;
+            },
+            InflateState::Match { len, dist } => {
+                if *out == output.len() {
+                    return Ok(false);
+                }
+
+                let b = self.window[(self.window_pos + WINDOW_SIZE - dist) % WINDOW_SIZE];
+
+                self.emit(output, out, b);
+                self.state = InflateState::Match { len: len - 1, dist };
+            },
+            InflateState::Done => {
+                return Ok(false);
+            },
+        }
+
+        Ok(true)
+    }
+
+    /// Decompresses as much of the provided input as possible into the provided output buffer. Decompression stops once the input has
+    /// been used up, the output buffer is full, or the end of the compressed data is reached. Input that has been consumed must not be
+    /// passed in again, but any input that was not consumed must be passed in again on the next call.
+    pub fn inflate(&mut self, input: &[u8], output: &mut [u8]) -> Result<InflateProgress, InflateError> {
+        let mut pos = 0;
+        let mut out = 0;
+
+        while self.step(input, &mut pos, output, &mut out)? {}
+
+        Ok(InflateProgress {
+            consumed: pos,
+            produced: out,
+            done: self.is_done(),
+        })
+    }
+}
+
+impl Default for Inflater {
+    fn default() -> Inflater {
+        Inflater::new()
+    }
+}
+
+/// Decompresses a complete DEFLATE stream, returning the decompressed data. Returns [`None`] if the compressed data is truncated or would
+/// decompress to more than `max_len` bytes.
+pub fn inflate_to_vec(src: &[u8], max_len: usize) -> Result<Option<Vec<u8>>, InflateError> {
+    let mut inflater = Inflater::new();
+    let mut data = Vec::new();
+    let mut buf = [0; 4096];
+    let mut pos = 0;
+
+    loop {
+        let progress = inflater.inflate(&src[pos..], &mut buf)?;
+
+        pos += progress.consumed;
+
+        if data.len() + progress.produced > max_len {
+            return Ok(None);
+        }
+
+        data.extend_from_slice(&buf[..progress.produced]);
+
+        if progress.done {
+            return Ok(Some(data));
+        } else if progress.produced == 0 && pos == src.len() {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIXED: [u8; 17] = [
+        0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0xc8, 0x40, 0xa2, 0x14, 0xca, 0xf3, 0x8b, 0x72, 0x52, 0x00,
+    ];
+    const DYNAMIC: [u8; 25] = [
+        0x05, 0xc1, 0x07, 0x01, 0x00, 0x00, 0x0c, 0x02, 0xa0, 0xac, 0xbe, 0x69, 0xff, 0x04, 0x03, 0x30, 0xa2, 0x6a, 0x65, 0xc9, 0xa1, 0x57,
+        0xad, 0x30, 0x1e,
+    ];
+
+    #[test_case]
+    fn test_inflate() {
+        assert_eq!(Ok(Some(b"hello, hello, hello world".to_vec())), inflate_to_vec(&FIXED, 1024));
+        assert_eq!(Ok(Some(b"abecbcgdceheefagfgchgada".to_vec())), inflate_to_vec(&DYNAMIC, 1024));
+        assert_eq!(
+            Ok(Some(b"abc".to_vec())),
+            inflate_to_vec(&[0x01, 0x03, 0x00, 0xfc, 0xff, 0x61, 0x62, 0x63], 1024)
+        );
+
+        assert_eq!(Ok(None), inflate_to_vec(&FIXED, 10));
+        assert_eq!(Ok(None), inflate_to_vec(&FIXED[..8], 1024));
+    }
+
+    #[test_case]
+    fn test_inflate_streaming() {
+        let mut inflater = Inflater::new();
+        let mut data = Vec::new();
+        let mut pos = 0;
+
+        // Feeding a byte at a time stops decompression part way through codes, and a 1-byte output buffer stops it part way through
+        // back-references
+        while !inflater.is_done() {
+            let mut buf = [0];
+            let end = (pos + 1).min(FIXED.len());
+            let progress = inflater.inflate(&FIXED[pos..end], &mut buf).unwrap();
+
+            pos += progress.consumed;
+            data.extend_from_slice(&buf[..progress.produced]);
+        }
+
+        assert_eq!(FIXED.len(), pos);
+        assert_eq!(b"hello, hello, hello world", &data[..]);
+    }
+
+    #[test_case]
+    fn test_inflate_invalid() {
+        let mut buf = [0; 16];
+
+        assert_eq!(Err(InflateError::InvalidBlockType), Inflater::new().inflate(&[0x07], &mut buf));
+        assert_eq!(
+            Err(InflateError::InvalidStoredLength),
+            Inflater::new().inflate(&[0x01, 0x03, 0x00, 0x00, 0x00], &mut buf)
+        );
+
+        // A fixed block starting with a back-reference to before the start of the data
+        assert_eq!(Err(InflateError::InvalidDistance), Inflater::new().inflate(&[0x03, 0x02], &mut buf));
+    }
+}
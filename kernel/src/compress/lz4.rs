@@ -0,0 +1,251 @@
+//! Compression and decompression of data using the LZ4 block format.
+//!
+//! The compressor implemented here is a simple greedy single-pass compressor using a small hash table to find matches. It favours speed over
+//! compression ratio, which makes it suitable for compressing data on hot paths such as swapping out pages. The output is compatible with
+//! the standard LZ4 block format and can be decompressed by any conforming LZ4 decompressor.
+
+use alloc::vec;
+use core::fmt;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xffff;
+
+/// The last match must start at least this many bytes before the end of the input.
+const MF_LIMIT: usize = 12;
+
+/// The last this many bytes of the input must always be encoded as literals.
+const LAST_LITERALS: usize = 5;
+
+const HASH_LOG: u32 = 12;
+
+/// An error indicating that data passed to [`decompress`] was not valid LZ4-compressed data or did not fit in the provided buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecompressError;
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid LZ4 data")
+    }
+}
+
+/// Gets the largest possible size of the result of compressing `len` bytes of data. Passing a buffer of at least this size to [`compress`]
+/// guarantees that compression will succeed.
+pub const fn compress_bound(len: usize) -> usize {
+    len + len / 255 + 16
+}
+
+struct Output<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Output<'a> {
+    fn push(&mut self, b: u8) -> Option<()> {
+        *self.buf.get_mut(self.pos)? = b;
+        self.pos += 1;
+        Some(())
+    }
+
+    fn push_slice(&mut self, s: &[u8]) -> Option<()> {
+        self.buf.get_mut(self.pos..self.pos + s.len())?.copy_from_slice(s);
+        self.pos += s.len();
+        Some(())
+    }
+
+    fn push_length(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.push(255)?;
+            len -= 255;
+        }
+
+        self.push(len as u8)
+    }
+
+    fn push_sequence(&mut self, literals: &[u8], matched: Option<(usize, usize)>) -> Option<()> {
+        let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+
+        self.push(((literals.len().min(15) as u8) << 4) | (match_len.min(15) as u8))?;
+
+        if literals.len() >= 15 {
+            self.push_length(literals.len() - 15)?;
+        }
+        self.push_slice(literals)?;
+
+        if let Some((offset, _)) = matched {
+            self.push_slice(&(offset as u16).to_le_bytes())?;
+
+            if match_len >= 15 {
+                self.push_length(match_len - 15)?;
+            }
+        }
+
+        Some(())
+    }
+}
+
+fn read_u32(src: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(src[i..i + 4].try_into().unwrap())
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Compresses the provided data into `dst`, returning the size of the compressed data. If the compressed data does not fit into `dst`,
+/// `None` is returned instead. This will never happen if `dst` is at least [`compress_bound`] bytes long.
+pub fn compress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut out = Output { buf: dst, pos: 0 };
+    let mut anchor = 0;
+
+    if src.len() > MF_LIMIT {
+        // Positions are stored offset by 1 so that 0 can represent an empty hash table entry
+        let mut table = vec![0_u32; 1 << HASH_LOG];
+        let match_limit = src.len() - MF_LIMIT;
+        let match_end_limit = src.len() - LAST_LITERALS;
+        let mut i = 0;
+
+        while i < match_limit {
+            let seq = read_u32(src, i);
+            let h = hash(seq);
+            let candidate = table[h] as usize;
+
+            table[h] = i as u32 + 1;
+
+            if candidate != 0 && i - (candidate - 1) <= MAX_OFFSET && read_u32(src, candidate - 1) == seq {
+                let candidate = candidate - 1;
+                let mut len = MIN_MATCH;
+
+                while i + len < match_end_limit && src[candidate + len] == src[i + len] {
+                    len += 1;
+                }
+
+                out.push_sequence(&src[anchor..i], Some((i - candidate, len)))?;
+
+                i += len;
+                anchor = i;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    out.push_sequence(&src[anchor..], None)?;
+    Some(out.pos)
+}
+
+fn read_byte(src: &[u8], si: &mut usize) -> Result<u8, DecompressError> {
+    let b = *src.get(*si).ok_or(DecompressError)?;
+
+    *si += 1;
+    Ok(b)
+}
+
+fn read_length(src: &[u8], si: &mut usize, base: usize) -> Result<usize, DecompressError> {
+    let mut len = base;
+
+    if base == 15 {
+        loop {
+            let b = read_byte(src, si)?;
+
+            len += usize::from(b);
+            if b != 255 {
+                break;
+            }
+        }
+    }
+
+    Ok(len)
+}
+
+/// Decompresses LZ4-compressed data into `dst`, returning the size of the decompressed data.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Result<usize, DecompressError> {
+    let mut si = 0;
+    let mut out = 0;
+
+    loop {
+        let token = read_byte(src, &mut si)?;
+        let literal_len = read_length(src, &mut si, usize::from(token >> 4))?;
+        let literals = src.get(si..si + literal_len).ok_or(DecompressError)?;
+
+        dst.get_mut(out..out + literal_len)
+            .ok_or(DecompressError)?
+            .copy_from_slice(literals);
+        si += literal_len;
+        out += literal_len;
+
+        if si == src.len() {
+            break;
+        }
+
+        let offset = usize::from(u16::from_le_bytes([read_byte(src, &mut si)?, read_byte(src, &mut si)?]));
+
+        if offset == 0 || offset > out {
+            return Err(DecompressError);
+        }
+
+        let match_len = read_length(src, &mut si, usize::from(token & 0xf))? + MIN_MATCH;
+
+        if out + match_len > dst.len() {
+            return Err(DecompressError);
+        }
+
+        // Matches may overlap the data they produce, so they need to be copied one byte at a time
+        for j in out..out + match_len {
+            dst[j] = dst[j - offset];
+        }
+        out += match_len;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> usize {
+        let mut compressed = vec![0; compress_bound(data.len())];
+        let compressed_len = compress(data, &mut compressed).unwrap();
+        let mut decompressed = vec![0; data.len()];
+
+        assert_eq!(Ok(data.len()), decompress(&compressed[..compressed_len], &mut decompressed));
+        assert_eq!(data, &decompressed[..]);
+
+        compressed_len
+    }
+
+    #[test_case]
+    fn test_round_trip() {
+        let repetitive: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        let mut noisy: Vec<u8> = vec![0; 4096];
+        let mut state = 0x12345678_u32;
+
+        for b in noisy.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *b = state as u8;
+        }
+
+        assert_eq!(1, round_trip(&[]));
+        round_trip(b"hello");
+        round_trip(b"abcabcabcabcabcabcabcabcabcabc");
+        round_trip(&noisy);
+        assert!(round_trip(&repetitive) < 64);
+        assert!(round_trip(&[0; 4096]) < 64);
+    }
+
+    #[test_case]
+    fn test_decompress_invalid() {
+        let mut buf = [0; 16];
+
+        assert_eq!(Err(DecompressError), decompress(&[], &mut buf));
+        assert_eq!(Err(DecompressError), decompress(&[0xf0], &mut buf));
+        assert_eq!(Err(DecompressError), decompress(&[0x10, b'a', 0x02, 0x00], &mut buf));
+        assert_eq!(Ok(0), decompress(&[0x00], &mut []));
+        assert_eq!(Err(DecompressError), decompress(&[0x20, b'a', b'b'], &mut buf[..1]));
+    }
+}
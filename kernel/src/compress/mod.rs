@@ -0,0 +1,5 @@
+//! Data compression algorithms usable from within the kernel.
+
+pub mod gzip;
+pub mod inflate;
+pub mod lz4;
@@ -0,0 +1,132 @@
+//! Registration of functions to be run during the boot process.
+//!
+//! Rather than requiring each subsystem's initialization function to be called explicitly from [`crate::init_phase_1`] or
+//! [`crate::init_phase_2`], subsystems can register an initcall using the [`initcall!`](crate::initcall) macro. Each initcall declares an
+//! [`InitLevel`] indicating which parts of the kernel it depends on having been initialized already. Initcalls are collected by the linker
+//! into the `.initcalls` section, where they are sorted by level, and are run by the boot process once each level is reached.
+//!
+//! The order in which initcalls of the same level are run is unspecified, so an initcall that depends on another must use a later level.
+
+use core::{ptr, slice};
+
+use crate::{boottime, log};
+
+/// The point in the boot process at which an initcall is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InitLevel {
+    /// Run once the early allocation pool, kernel options, and logging are available, before the architecture has been initialized.
+    Early,
+    /// Run once the architecture has been fully initialized, including interrupts and the frame allocator.
+    Arch,
+    /// Run once the scheduler has been initialized and kernel threads can be created.
+    Subsys,
+    /// Run once all device probes have finished.
+    Device,
+    /// Run at the very end of the boot process.
+    Late,
+}
+
+/// A function registered to be run during the boot process using the [`initcall!`](crate::initcall) macro.
+#[derive(Debug)]
+pub struct InitCall {
+    #[doc(hidden)]
+    pub level: InitLevel,
+    #[doc(hidden)]
+    pub name: &'static str,
+    #[doc(hidden)]
+    pub func: unsafe fn(),
+}
+
+impl InitCall {
+    /// Gets the level at which this initcall is run.
+    pub fn level(&self) -> InitLevel {
+        self.level
+    }
+
+    /// Gets the name of this initcall, which is used when recording boot milestones.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Registers a function to be run once the boot process has reached the provided level. The level is one of `early`, `arch`, `subsys`,
+/// `device`, or `late`, matching the variants of [`InitLevel`](crate::initcall::InitLevel).
+///
+/// # Safety
+///
+/// The provided function may be an `unsafe fn()`, in which case registering it as an initcall asserts that it is safe to call exactly once
+/// from the bootstrap processor at the provided level.
+#[macro_export]
+macro_rules! initcall {
+    (early, $name:expr, $func:path) => {
+        $crate::initcall!(@section ".initcalls.0", Early, $name, $func);
+    };
+    (arch, $name:expr, $func:path) => {
+        $crate::initcall!(@section ".initcalls.1", Arch, $name, $func);
+    };
+    (subsys, $name:expr, $func:path) => {
+        $crate::initcall!(@section ".initcalls.2", Subsys, $name, $func);
+    };
+    (device, $name:expr, $func:path) => {
+        $crate::initcall!(@section ".initcalls.3", Device, $name, $func);
+    };
+    (late, $name:expr, $func:path) => {
+        $crate::initcall!(@section ".initcalls.4", Late, $name, $func);
+    };
+    (@section $section:literal, $level:ident, $name:expr, $func:path) => {
+        const _: () = {
+            #[used]
+            #[link_section = $section]
+            static INITCALL: $crate::initcall::InitCall = $crate::initcall::InitCall {
+                level: $crate::initcall::InitLevel::$level,
+                name: $name,
+                func: $func,
+            };
+        };
+    };
+}
+
+extern "C" {
+    static __initcalls_start: u8;
+    static __initcalls_end: u8;
+}
+
+/// Gets all registered initcalls, sorted by level.
+pub fn all() -> &'static [InitCall] {
+    // SAFETY: The linker script places these symbols at the start and end of the section containing all InitCall statics registered using
+    //         the initcall! macro.
+    unsafe {
+        let start = ptr::addr_of!(__initcalls_start) as *const InitCall;
+        let end = ptr::addr_of!(__initcalls_end) as *const InitCall;
+
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Runs all initcalls registered at the provided level, recording a boot milestone after each one.
+///
+/// # Safety
+///
+/// This function should only be called once for each level from the bootstrap processor during the boot process, once the boot process
+/// has reached that level.
+pub(crate) unsafe fn run(level: InitLevel) {
+    for call in all().iter().filter(|call| call.level == level) {
+        log!(Debug, "initcall", "Running initcall {} ({:?})", call.name, call.level);
+
+        (call.func)();
+        boottime::mark(call.name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_initcalls_sorted() {
+        let calls = all();
+
+        assert!(calls.windows(2).all(|w| w[0].level <= w[1].level));
+        assert!(calls.iter().any(|call| call.name == "topology" && call.level == InitLevel::Arch));
+    }
+}
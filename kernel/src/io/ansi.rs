@@ -1,5 +1,7 @@
 use core::{fmt, str};
 
+use crate::options::{InvalidOptionValue, KernelOptionParseable};
+
 #[derive(Debug)]
 enum AnsiParserState {
     Normal,
@@ -75,6 +77,30 @@ impl AnsiColor {
     }
 }
 
+impl<'a> KernelOptionParseable<'a> for AnsiColor {
+    fn try_parse_kopt(s: &'a str) -> Result<Self, InvalidOptionValue> {
+        match s {
+            "black" => Ok(AnsiColor::Black),
+            "red" => Ok(AnsiColor::Red),
+            "green" => Ok(AnsiColor::Green),
+            "brown" => Ok(AnsiColor::Brown),
+            "blue" => Ok(AnsiColor::Blue),
+            "magenta" => Ok(AnsiColor::Magenta),
+            "cyan" => Ok(AnsiColor::Cyan),
+            "lightgray" => Ok(AnsiColor::LightGray),
+            "darkgray" => Ok(AnsiColor::DarkGray),
+            "lightred" => Ok(AnsiColor::LightRed),
+            "lightgreen" => Ok(AnsiColor::LightGreen),
+            "yellow" => Ok(AnsiColor::Yellow),
+            "lightblue" => Ok(AnsiColor::LightBlue),
+            "pink" => Ok(AnsiColor::Pink),
+            "lightcyan" => Ok(AnsiColor::LightCyan),
+            "white" => Ok(AnsiColor::White),
+            _ => Err(InvalidOptionValue),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum AnsiParserSgrAction {
     Reset,
@@ -0,0 +1,161 @@
+//! Block devices, which store data in fixed-size sectors that can be read and written in any order.
+//!
+//! Any device that stores data addressed by sector number (e.g. a disk) should implement [`BlockDevice`] so that filesystems and other
+//! consumers can access it without knowing what kind of device it is. Storage drivers will usually not implement [`BlockDevice`] by hand,
+//! but will instead implement [`queue::BlockRequestHandler`] and pass requests through a [`queue::RequestQueue`], which takes care of
+//! validating, merging and ordering requests before they reach the hardware.
+//!
+//! Block devices are registered under the `::block` device hub using [`register`], which makes them show up in [`block_devices`].
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+use dyn_dyn::dyn_dyn_cast;
+
+use super::dev::hub::{DeviceHubExt, VirtualDeviceHub};
+use super::dev::{device_root, Device, DeviceNode, DeviceRef};
+use crate::log;
+use crate::sync::Future;
+use crate::util::OneShotManualInit;
+
+pub mod queue;
+pub mod ramdisk;
+
+/// An error that can occur when performing an operation on a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockDeviceError {
+    /// The request was not a whole number of sectors or extended past the end of the device.
+    InvalidRequest,
+    /// A write was attempted to a device that cannot be written to.
+    ReadOnly,
+    /// The device reported an error while transferring data.
+    IoError,
+    /// The device uses removable media and none is currently inserted.
+    NoMedia,
+    /// The media was replaced since the device was last accessed, so anything cached about its contents is no longer valid. Retrying the
+    /// request will access the new media.
+    MediaChanged,
+}
+
+impl fmt::Display for BlockDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlockDeviceError::InvalidRequest => write!(f, "invalid request"),
+            BlockDeviceError::ReadOnly => write!(f, "read-only device"),
+            BlockDeviceError::IoError => write!(f, "I/O error"),
+            BlockDeviceError::NoMedia => write!(f, "no media"),
+            BlockDeviceError::MediaChanged => write!(f, "media changed"),
+        }
+    }
+}
+
+/// A device that stores data in fixed-size sectors.
+pub trait BlockDevice: Device {
+    /// Gets the size of a single sector in bytes. This is always a power of two.
+    fn sector_size(&self) -> usize;
+
+    /// Gets the number of sectors that can be stored on the device.
+    fn num_sectors(&self) -> u64;
+
+    /// Checks whether writes to the device will be rejected with [`BlockDeviceError::ReadOnly`].
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads consecutive sectors starting at the provided sector into the provided buffer, whose length must be a multiple of the sector
+    /// size.
+    ///
+    /// # Safety
+    ///
+    /// The provided buffer must remain valid and must not be accessed by anything else until the returned future has resolved.
+    unsafe fn read_sectors(&self, sector: u64, buf: *mut [u8]) -> Future<Result<(), BlockDeviceError>>;
+
+    /// Writes the provided buffer, whose length must be a multiple of the sector size, to consecutive sectors starting at the provided
+    /// sector.
+    ///
+    /// # Safety
+    ///
+    /// The provided buffer must remain valid and must not be modified until the returned future has resolved.
+    unsafe fn write_sectors(&self, sector: u64, buf: *const [u8]) -> Future<Result<(), BlockDeviceError>>;
+}
+
+pub trait BlockDeviceExt: BlockDevice {
+    /// Gets the number of bytes that can be stored on the device.
+    fn capacity(&self) -> u64 {
+        self.num_sectors() * self.sector_size() as u64
+    }
+
+    /// Checks that a request for `len` bytes starting at the provided sector covers a whole number of sectors and does not extend past the
+    /// end of the device, returning the number of sectors covered.
+    fn check_request(&self, sector: u64, len: usize) -> Result<u64, BlockDeviceError> {
+        if len % self.sector_size() != 0 {
+            return Err(BlockDeviceError::InvalidRequest);
+        }
+
+        let count = (len / self.sector_size()) as u64;
+
+        match sector.checked_add(count) {
+            Some(end) if end <= self.num_sectors() => Ok(count),
+            _ => Err(BlockDeviceError::InvalidRequest),
+        }
+    }
+
+    /// Reads consecutive sectors into the provided buffer, blocking the current thread until the read has completed.
+    fn read_blocking(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockDeviceError> {
+        unsafe { self.read_sectors(sector, buf).unwrap_blocking() }
+    }
+
+    /// Writes the provided buffer to consecutive sectors, blocking the current thread until the write has completed.
+    fn write_blocking(&self, sector: u64, buf: &[u8]) -> Result<(), BlockDeviceError> {
+        unsafe { self.write_sectors(sector, buf).unwrap_blocking() }
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDeviceExt for T {}
+
+static BLOCK_HUB: OneShotManualInit<DeviceRef<VirtualDeviceHub>> = OneShotManualInit::uninit();
+
+/// Adds a block device to the device tree under the `::block` hub with the provided name.
+pub fn register<T: BlockDevice>(name: &str, dev: T) -> DeviceRef<T> {
+    let dev = BLOCK_HUB.get().dev().add_device(DeviceNode::new(Box::from(name), dev));
+
+    log!(
+        Info,
+        "block",
+        "Registered block device {} with {} sectors of {} bytes{}",
+        name,
+        dev.dev().num_sectors(),
+        dev.dev().sector_size(),
+        if dev.dev().is_read_only() { " (read-only)" } else { "" }
+    );
+    dev
+}
+
+/// Removes a block device that was registered using [`register`] from the device tree and disconnects it.
+pub fn unregister<T: BlockDevice>(dev: &DeviceRef<T>) {
+    BLOCK_HUB.get().dev().remove_device::<T>(&(dev.clone() as DeviceRef<dyn Device>));
+    dev.disconnect();
+    log!(Info, "block", "Unregistered block device {}", dev.name());
+}
+
+/// Gets all block devices that have been registered using [`register`] and have not since been disconnected.
+pub fn block_devices() -> Vec<DeviceRef<dyn BlockDevice>> {
+    BLOCK_HUB
+        .get()
+        .dev()
+        .children()
+        .into_iter()
+        .filter_map(|dev| dyn_dyn_cast!(move Device => BlockDevice, dev).ok())
+        .collect()
+}
+
+fn init() {
+    BLOCK_HUB.set(
+        device_root()
+            .dev()
+            .add_device(DeviceNode::new(Box::from("block"), VirtualDeviceHub::new())),
+    );
+}
+
+crate::initcall!(arch, "block devices", init);
@@ -0,0 +1,454 @@
+//! Queueing of block device requests so that they reach the hardware in an efficient order.
+//!
+//! A storage driver implements [`BlockRequestHandler`] to perform a single request at a time and wraps it in a [`RequestQueue`], which
+//! its [`BlockDevice`](super::BlockDevice) implementation then forwards reads and writes to. While the handler is busy, newly submitted
+//! requests wait in the queue, where a request for sectors directly before or after another pending request in the same direction is merged
+//! into it, so that many small sequential transfers reach the hardware as a single larger one. When the handler becomes free, the next
+//! request is chosen using a one-way elevator: the pending request with the lowest sector number at or after the end of the previous
+//! request is dispatched next, wrapping around to the lowest sector number once the end of the device is reached.
+//!
+//! Pending requests that overlap are not ordered with respect to each other, so callers must wait for a write to complete before issuing
+//! other requests for the same sectors.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::BlockDeviceError;
+use crate::io::dev::iostat::{self, IoDirection, IoRequest, IoStats};
+use crate::sync::future::FutureWriter;
+use crate::sync::{Future, UninterruptibleSpinlock};
+
+/// A contiguous buffer in memory that is transferred to or from the device as part of a [`BlockRequest`].
+#[derive(Debug, Clone, Copy)]
+struct Segment(*mut [u8]);
+
+// SAFETY: The submitter of a request guarantees that its buffer is not accessed by anything else until the request completes, so the
+//         request can be handed to whichever thread or interrupt handler ends up performing it
+unsafe impl Send for Segment {}
+
+/// A request for a range of sectors, possibly made up of several requests that were merged together while they were waiting in the queue.
+#[derive(Debug)]
+pub struct BlockRequest {
+    dir: IoDirection,
+    sector: u64,
+    num_sectors: u64,
+    segments: Vec<Segment>,
+}
+
+impl BlockRequest {
+    /// Gets the direction in which data is to be transferred.
+    pub fn dir(&self) -> IoDirection {
+        self.dir
+    }
+
+    /// Gets the first sector to be transferred.
+    pub fn sector(&self) -> u64 {
+        self.sector
+    }
+
+    /// Gets the number of consecutive sectors to be transferred.
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// Gets the buffers that the sectors are to be transferred to or from, in order. Each buffer is a whole number of sectors long and
+    /// their combined length is the length of the request.
+    ///
+    /// The buffers remain valid until the future returned by [`BlockRequestHandler::handle`] for this request has resolved. Buffers for a
+    /// write request must only be read from.
+    pub fn segments(&self) -> impl Iterator<Item = *mut [u8]> + '_ {
+        self.segments.iter().map(|seg| seg.0)
+    }
+
+    fn end(&self) -> u64 {
+        self.sector + self.num_sectors
+    }
+}
+
+/// The part of a storage driver that performs requests taken from a [`RequestQueue`].
+pub trait BlockRequestHandler: Send + Sync + 'static {
+    /// Gets the size of a single sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// Gets the number of sectors that can be stored on the device.
+    fn num_sectors(&self) -> u64;
+
+    /// Checks whether the device rejects writes.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Gets the largest number of sectors that the device can transfer in a single request. Requests are never merged past this limit,
+    /// although a single request submitted to the queue can still be larger.
+    fn max_sectors(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// Starts performing the provided request, returning a future that resolves once it has completed. Only one request is performed at a
+    /// time, so this will not be called again until the returned future has resolved.
+    ///
+    /// This may be called from the callback of the previous request's future and thus in the context of an interrupt handler, so it must
+    /// not block.
+    fn handle(&self, req: &BlockRequest) -> Future<Result<(), BlockDeviceError>>;
+}
+
+struct Waiter {
+    writer: FutureWriter<Result<(), BlockDeviceError>>,
+    io: IoRequest<'static>,
+    bytes: usize,
+}
+
+struct PendingRequest {
+    req: BlockRequest,
+    waiters: Vec<Waiter>,
+}
+
+impl PendingRequest {
+    fn complete(self, result: Result<(), BlockDeviceError>) {
+        for waiter in self.waiters {
+            match result {
+                Ok(()) => waiter.io.finish(waiter.bytes),
+                Err(_) => waiter.io.fail(),
+            }
+
+            waiter.writer.finish(result);
+        }
+    }
+}
+
+struct QueueState {
+    pending: Vec<PendingRequest>,
+    busy: bool,
+    head: u64,
+    merged: u64,
+}
+
+impl QueueState {
+    /// Tries to merge a new request into an existing pending request, returning the new request back if no suitable request was found.
+    fn try_merge(&mut self, mut new: PendingRequest, max_sectors: u64) -> Result<(), PendingRequest> {
+        let pos = self.pending.iter().position(|p| {
+            p.req.dir == new.req.dir
+                && p.req.num_sectors + new.req.num_sectors <= max_sectors
+                && (p.req.end() == new.req.sector || new.req.end() == p.req.sector)
+        });
+
+        let Some(pos) = pos else {
+            return Err(new);
+        };
+
+        let p = &mut self.pending[pos];
+
+        if p.req.end() == new.req.sector {
+            p.req.segments.append(&mut new.req.segments);
+        } else {
+            new.req.segments.append(&mut p.req.segments);
+            p.req.segments = new.req.segments;
+            p.req.sector = new.req.sector;
+        }
+
+        p.req.num_sectors += new.req.num_sectors;
+        p.waiters.append(&mut new.waiters);
+        self.merged += 1;
+
+        // Merging at the front moves the start of a request, which may put it out of order
+        self.pending.sort_by_key(|p| p.req.sector);
+        Ok(())
+    }
+
+    fn insert(&mut self, new: PendingRequest) {
+        let pos = self.pending.partition_point(|p| p.req.sector <= new.req.sector);
+
+        self.pending.insert(pos, new);
+    }
+
+    fn take_next(&mut self) -> Option<PendingRequest> {
+        if self.busy || self.pending.is_empty() {
+            return None;
+        }
+
+        let pos = self.pending.partition_point(|p| p.req.sector < self.head);
+        let next = self.pending.remove(if pos < self.pending.len() { pos } else { 0 });
+
+        self.busy = true;
+        self.head = next.req.end();
+        Some(next)
+    }
+}
+
+/// Statistics about the requests that have passed through a [`RequestQueue`].
+#[derive(Debug, Clone, Copy)]
+pub struct QueueStats {
+    /// The number of requests currently waiting in the queue, not including the one being performed.
+    pub pending: usize,
+    /// The number of submitted requests that were merged into another request rather than being performed separately.
+    pub merged: u64,
+}
+
+/// A queue of requests waiting to be performed by a [`BlockRequestHandler`].
+pub struct RequestQueue<H: BlockRequestHandler> {
+    handler: H,
+    state: UninterruptibleSpinlock<QueueState>,
+    stats: Arc<IoStats>,
+}
+
+impl<H: BlockRequestHandler> RequestQueue<H> {
+    /// Creates a new request queue feeding the provided handler. Statistics about the requests submitted to the queue are registered with
+    /// [`iostat`] under the provided name.
+    pub fn new(name: &str, handler: H) -> Arc<RequestQueue<H>> {
+        let stats = Arc::new(IoStats::new());
+
+        iostat::register(name, &stats);
+        Arc::new(RequestQueue {
+            handler,
+            state: UninterruptibleSpinlock::new(QueueState {
+                pending: vec![],
+                busy: false,
+                head: 0,
+                merged: 0,
+            }),
+            stats,
+        })
+    }
+
+    /// Gets the handler that requests in this queue are passed to.
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Gets statistics about the requests that have passed through this queue.
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock();
+
+        QueueStats {
+            pending: state.pending.len(),
+            merged: state.merged,
+        }
+    }
+
+    /// Queues a read of consecutive sectors into the provided buffer. This has the same requirements as
+    /// [`BlockDevice::read_sectors`](super::BlockDevice::read_sectors).
+    ///
+    /// # Safety
+    ///
+    /// The provided buffer must remain valid and must not be accessed by anything else until the returned future has resolved.
+    pub unsafe fn read(self: &Arc<Self>, sector: u64, buf: *mut [u8]) -> Future<Result<(), BlockDeviceError>> {
+        self.submit(IoDirection::Read, sector, buf)
+    }
+
+    /// Queues a write of the provided buffer to consecutive sectors. This has the same requirements as
+    /// [`BlockDevice::write_sectors`](super::BlockDevice::write_sectors).
+    ///
+    /// # Safety
+    ///
+    /// The provided buffer must remain valid and must not be modified until the returned future has resolved.
+    pub unsafe fn write(self: &Arc<Self>, sector: u64, buf: *const [u8]) -> Future<Result<(), BlockDeviceError>> {
+        if self.handler.is_read_only() {
+            return Future::done(Err(BlockDeviceError::ReadOnly));
+        }
+
+        self.submit(IoDirection::Write, sector, buf as *mut [u8])
+    }
+
+    fn submit(self: &Arc<Self>, dir: IoDirection, sector: u64, buf: *mut [u8]) -> Future<Result<(), BlockDeviceError>> {
+        let sector_size = self.handler.sector_size();
+
+        if buf.len() % sector_size != 0 {
+            return Future::done(Err(BlockDeviceError::InvalidRequest));
+        }
+
+        let num_sectors = (buf.len() / sector_size) as u64;
+
+        match sector.checked_add(num_sectors) {
+            Some(end) if end <= self.handler.num_sectors() => {},
+            _ => return Future::done(Err(BlockDeviceError::InvalidRequest)),
+        };
+
+        if num_sectors == 0 {
+            return Future::done(Ok(()));
+        }
+
+        let (future, writer) = Future::new();
+        let req = PendingRequest {
+            req: BlockRequest {
+                dir,
+                sector,
+                num_sectors,
+                segments: vec![Segment(buf)],
+            },
+            waiters: vec![Waiter {
+                writer,
+                io: self.stats.begin_owned(dir),
+                bytes: buf.len(),
+            }],
+        };
+
+        {
+            let mut state = self.state.lock();
+
+            if let Err(req) = state.try_merge(req, self.handler.max_sectors()) {
+                state.insert(req);
+            }
+        }
+
+        self.dispatch();
+        future
+    }
+
+    fn dispatch(self: &Arc<Self>) {
+        loop {
+            let Some(next) = self.state.lock().take_next() else {
+                return;
+            };
+
+            match self.handler.handle(&next.req).try_unwrap() {
+                Ok(result) => {
+                    self.state.lock().busy = false;
+                    next.complete(result);
+                },
+                Err(future) => {
+                    let queue = self.clone();
+
+                    future.when_resolved(move |result| {
+                        queue.state.lock().busy = false;
+                        next.complete(result);
+                        queue.dispatch();
+                    });
+                    return;
+                },
+            }
+        }
+    }
+}
+
+impl<H: BlockRequestHandler + fmt::Debug> fmt::Debug for RequestQueue<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestQueue")
+            .field("handler", &self.handler)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECTOR_SIZE: usize = 16;
+
+    #[derive(Debug)]
+    struct DeferredHandler {
+        handled: UninterruptibleSpinlock<Vec<(u64, u64, usize)>>,
+        writers: UninterruptibleSpinlock<Vec<FutureWriter<Result<(), BlockDeviceError>>>>,
+    }
+
+    impl BlockRequestHandler for DeferredHandler {
+        fn sector_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn num_sectors(&self) -> u64 {
+            64
+        }
+
+        fn handle(&self, req: &BlockRequest) -> Future<Result<(), BlockDeviceError>> {
+            let (future, writer) = Future::new();
+
+            self.handled.lock().push((req.sector(), req.num_sectors(), req.segments().count()));
+            self.writers.lock().push(writer);
+            future
+        }
+    }
+
+    #[derive(Debug)]
+    struct MemoryHandler {
+        data: UninterruptibleSpinlock<Vec<u8>>,
+    }
+
+    impl BlockRequestHandler for MemoryHandler {
+        fn sector_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn num_sectors(&self) -> u64 {
+            4
+        }
+
+        fn handle(&self, req: &BlockRequest) -> Future<Result<(), BlockDeviceError>> {
+            let mut data = self.data.lock();
+            let mut offset = req.sector() as usize * SECTOR_SIZE;
+
+            for seg in req.segments() {
+                let sectors = &mut data[offset..offset + seg.len()];
+
+                // SAFETY: The queue guarantees that the segments are valid until the request completes
+                unsafe {
+                    match req.dir() {
+                        IoDirection::Read => (*seg).copy_from_slice(sectors),
+                        IoDirection::Write => sectors.copy_from_slice(&*seg),
+                    }
+                }
+
+                offset += seg.len();
+            }
+
+            Future::done(Ok(()))
+        }
+    }
+
+    #[test_case]
+    fn test_merge_and_order() {
+        let queue = RequestQueue::new("test queue", DeferredHandler {
+            handled: UninterruptibleSpinlock::new(vec![]),
+            writers: UninterruptibleSpinlock::new(vec![]),
+        });
+        let mut bufs = [[0u8; SECTOR_SIZE]; 5];
+        let [ref mut a, ref mut b, ref mut c, ref mut d, ref mut e] = bufs;
+
+        let futures = unsafe {
+            [
+                queue.read(5, a),
+                queue.read(11, b),
+                queue.read(10, c),
+                queue.read(12, d),
+                queue.read(2, e),
+            ]
+        };
+
+        assert_eq!(2, queue.stats().pending);
+        assert_eq!(2, queue.stats().merged);
+
+        for _ in 0..3 {
+            let writer = queue.handler().writers.lock().pop().unwrap();
+
+            writer.finish(Ok(()));
+        }
+
+        assert_eq!(&[(5, 1, 1), (10, 3, 3), (2, 1, 1)], &queue.handler().handled.lock()[..]);
+
+        for future in futures {
+            assert_eq!(Some(Ok(())), future.try_unwrap().ok());
+        }
+    }
+
+    #[test_case]
+    fn test_read_write() {
+        let queue = RequestQueue::new("test memory queue", MemoryHandler {
+            data: UninterruptibleSpinlock::new(vec![0; SECTOR_SIZE * 4]),
+        });
+        let data: Vec<u8> = (0..SECTOR_SIZE as u8 * 2).collect();
+        let mut buf = [0; SECTOR_SIZE * 2];
+
+        unsafe {
+            assert_eq!(Ok(()), queue.write(1, &data[..]).unwrap_blocking());
+            assert_eq!(Ok(()), queue.read(1, &mut buf[..]).unwrap_blocking());
+            assert_eq!(&data[..], &buf[..]);
+
+            assert_eq!(Err(BlockDeviceError::InvalidRequest), queue.read(3, &mut buf[..]).unwrap_blocking());
+            assert_eq!(
+                Err(BlockDeviceError::InvalidRequest),
+                queue.read(0, &mut buf[1..]).unwrap_blocking()
+            );
+        }
+    }
+}
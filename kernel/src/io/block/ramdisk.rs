@@ -0,0 +1,221 @@
+//! Block devices whose contents are stored in kernel memory.
+//!
+//! Ramdisks make it possible to exercise the block layer and anything built on top of it without any storage hardware. They can be
+//! created empty using [`create`] (e.g. from the debug console) or from an existing disk image using [`create_from_image`], and are
+//! registered under the `::block` hub like any other block device. The contents of a ramdisk are lost once it is removed using
+//! [`remove`].
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use dyn_dyn::dyn_dyn_impl;
+
+use super::queue::{BlockRequest, BlockRequestHandler, RequestQueue};
+use super::{BlockDevice, BlockDeviceError};
+use crate::io::dev::iostat::IoDirection;
+use crate::io::dev::{Device, DeviceRef};
+use crate::sync::{Future, UninterruptibleSpinlock};
+
+/// The size of a sector of a ramdisk in bytes.
+pub const RAMDISK_SECTOR_SIZE: usize = 512;
+
+/// An error that can occur when creating a ramdisk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamdiskError {
+    /// The requested size is zero or is not a whole number of sectors.
+    InvalidSize,
+    /// There is not enough free memory to hold the ramdisk's contents.
+    OutOfMemory,
+    /// A block device with the requested name already exists.
+    NameInUse,
+}
+
+impl fmt::Display for RamdiskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RamdiskError::InvalidSize => write!(f, "size must be a non-zero multiple of {} bytes", RAMDISK_SECTOR_SIZE),
+            RamdiskError::OutOfMemory => write!(f, "out of memory"),
+            RamdiskError::NameInUse => write!(f, "name already in use"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RamdiskStorage {
+    data: UninterruptibleSpinlock<Box<[u8]>>,
+    num_sectors: u64,
+    read_only: bool,
+}
+
+impl BlockRequestHandler for RamdiskStorage {
+    fn sector_size(&self) -> usize {
+        RAMDISK_SECTOR_SIZE
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn handle(&self, req: &BlockRequest) -> Future<Result<(), BlockDeviceError>> {
+        let mut data = self.data.lock();
+        let mut offset = req.sector() as usize * RAMDISK_SECTOR_SIZE;
+
+        for seg in req.segments() {
+            let sectors = &mut data[offset..offset + seg.len()];
+
+            // SAFETY: The queue guarantees that the segments are valid until the request completes
+            unsafe {
+                match req.dir() {
+                    IoDirection::Read => (*seg).copy_from_slice(sectors),
+                    IoDirection::Write => sectors.copy_from_slice(&*seg),
+                }
+            }
+
+            offset += seg.len();
+        }
+
+        Future::done(Ok(()))
+    }
+}
+
+/// A block device whose contents are stored in kernel memory.
+#[derive(Debug)]
+pub struct Ramdisk {
+    queue: Arc<RequestQueue<RamdiskStorage>>,
+}
+
+impl BlockDevice for Ramdisk {
+    fn sector_size(&self) -> usize {
+        RAMDISK_SECTOR_SIZE
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.queue.handler().num_sectors()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.queue.handler().is_read_only()
+    }
+
+    unsafe fn read_sectors(&self, sector: u64, buf: *mut [u8]) -> Future<Result<(), BlockDeviceError>> {
+        self.queue.read(sector, buf)
+    }
+
+    unsafe fn write_sectors(&self, sector: u64, buf: *const [u8]) -> Future<Result<(), BlockDeviceError>> {
+        self.queue.write(sector, buf)
+    }
+}
+
+#[dyn_dyn_impl(BlockDevice)]
+impl Device for Ramdisk {}
+
+static RAMDISKS: UninterruptibleSpinlock<Vec<DeviceRef<Ramdisk>>> = UninterruptibleSpinlock::new(Vec::new());
+
+fn register(name: &str, data: Box<[u8]>, read_only: bool) -> Result<DeviceRef<Ramdisk>, RamdiskError> {
+    if data.is_empty() || data.len() % RAMDISK_SECTOR_SIZE != 0 {
+        return Err(RamdiskError::InvalidSize);
+    }
+
+    let mut ramdisks = RAMDISKS.lock();
+
+    if super::block_devices().iter().any(|dev| dev.name() == name) {
+        return Err(RamdiskError::NameInUse);
+    }
+
+    let queue = RequestQueue::new(name, RamdiskStorage {
+        num_sectors: (data.len() / RAMDISK_SECTOR_SIZE) as u64,
+        data: UninterruptibleSpinlock::new(data),
+        read_only,
+    });
+    let dev = super::register(name, Ramdisk { queue });
+
+    ramdisks.push(dev.clone());
+    Ok(dev)
+}
+
+/// Creates a writable ramdisk of the provided size in bytes, which must be a multiple of [`RAMDISK_SECTOR_SIZE`], with all sectors
+/// initially zeroed.
+pub fn create(name: &str, size: usize) -> Result<DeviceRef<Ramdisk>, RamdiskError> {
+    let mut data = Vec::new();
+
+    if data.try_reserve_exact(size).is_err() {
+        return Err(RamdiskError::OutOfMemory);
+    }
+
+    data.resize(size, 0);
+    register(name, data.into_boxed_slice(), false)
+}
+
+/// Creates a ramdisk initially containing the provided disk image, whose length must be a multiple of [`RAMDISK_SECTOR_SIZE`].
+pub fn create_from_image(name: &str, image: Box<[u8]>, read_only: bool) -> Result<DeviceRef<Ramdisk>, RamdiskError> {
+    register(name, image, read_only)
+}
+
+/// Removes the ramdisk with the provided name from the device tree and frees its contents once nothing else refers to it. Returns `false`
+/// if there is no ramdisk with that name.
+pub fn remove(name: &str) -> bool {
+    let mut ramdisks = RAMDISKS.lock();
+    let Some(pos) = ramdisks.iter().position(|dev| dev.name() == name) else {
+        return false;
+    };
+
+    super::unregister(&ramdisks.remove(pos));
+    true
+}
+
+/// Gets all ramdisks that have been created and not yet removed.
+pub fn ramdisks() -> Vec<DeviceRef<Ramdisk>> {
+    RAMDISKS.lock().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+    use crate::io::block::BlockDeviceExt;
+
+    #[test_case]
+    fn test_ramdisk() {
+        let dev = create("test-ram0", RAMDISK_SECTOR_SIZE * 4).unwrap();
+        let data: Vec<u8> = (0..RAMDISK_SECTOR_SIZE * 2).map(|i| i as u8).collect();
+        let mut buf = vec![0xff; RAMDISK_SECTOR_SIZE * 2];
+
+        assert_eq!(4, dev.dev().num_sectors());
+        assert_eq!(Ok(()), dev.dev().read_blocking(0, &mut buf));
+        assert!(buf.iter().all(|&b| b == 0));
+
+        assert_eq!(Ok(()), dev.dev().write_blocking(2, &data));
+        assert_eq!(Ok(()), dev.dev().read_blocking(2, &mut buf));
+        assert_eq!(data, buf);
+
+        assert_eq!(Err(RamdiskError::NameInUse), create("test-ram0", RAMDISK_SECTOR_SIZE).map(|_| ()));
+        assert_eq!(
+            Err(RamdiskError::InvalidSize),
+            create("test-ram1", RAMDISK_SECTOR_SIZE + 1).map(|_| ())
+        );
+
+        assert!(remove("test-ram0"));
+        assert!(!remove("test-ram0"));
+        assert!(!dev.is_connected());
+    }
+
+    #[test_case]
+    fn test_read_only_image() {
+        let image = vec![0x5a; RAMDISK_SECTOR_SIZE].into_boxed_slice();
+        let dev = create_from_image("test-ram2", image, true).unwrap();
+        let mut buf = [0; RAMDISK_SECTOR_SIZE];
+
+        assert_eq!(Ok(()), dev.dev().read_blocking(0, &mut buf));
+        assert!(buf.iter().all(|&b| b == 0x5a));
+        assert_eq!(Err(BlockDeviceError::ReadOnly), dev.dev().write_blocking(0, &buf));
+
+        assert!(remove("test-ram2"));
+    }
+}
@@ -0,0 +1,134 @@
+//! Character devices, which transfer data as unstructured streams of bytes rather than in fixed-size blocks.
+//!
+//! Any device that can be read from or written to as a byte stream (e.g. a serial port) should implement [`CharDevice`] so that it can be
+//! accessed generically without knowing what kind of device it is. Device-specific operations that do not fit the byte stream model are
+//! performed through [`CharDevice::control`], which takes a command number and an argument in the style of `ioctl`.
+
+use core::fmt;
+
+use super::Device;
+use crate::sync::Future;
+
+/// Waits for all data written to the device to be transmitted. The argument is ignored and `0` is returned on success.
+pub const CTRL_FLUSH: u32 = 0x0001;
+
+/// Discards any data that has been received by the device but not yet read. The argument is ignored and `0` is returned on success.
+pub const CTRL_DISCARD_INPUT: u32 = 0x0002;
+
+/// An error that can occur when performing an operation on a character device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharDeviceError {
+    /// The device does not support the requested operation or control command.
+    Unsupported,
+    /// The argument passed to a control command was not valid.
+    InvalidArgument,
+    /// The device reported an error while transferring data.
+    IoError,
+    /// The device has no space left to store written data.
+    NoSpace,
+}
+
+impl fmt::Display for CharDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CharDeviceError::Unsupported => write!(f, "operation not supported"),
+            CharDeviceError::InvalidArgument => write!(f, "invalid argument"),
+            CharDeviceError::IoError => write!(f, "I/O error"),
+            CharDeviceError::NoSpace => write!(f, "no space left on device"),
+        }
+    }
+}
+
+/// A device that can be read from and written to as a stream of bytes.
+pub trait CharDevice: Device {
+    /// Reads bytes from the device into the provided buffer, returning the number of bytes that were read once at least one byte is
+    /// available. A result of `Ok(0)` indicates that the end of the stream has been reached.
+    ///
+    /// # Safety
+    ///
+    /// The provided buffer must remain valid and must not be accessed by anything else until the returned future has resolved.
+    unsafe fn read(&self, buf: *mut [u8]) -> Future<Result<usize, CharDeviceError>>;
+
+    /// Writes bytes from the provided buffer to the device, returning the number of bytes that were written. This may be fewer than the
+    /// length of the buffer if the device cannot accept any more data at the moment.
+    ///
+    /// # Safety
+    ///
+    /// The provided buffer must remain valid and must not be modified until the returned future has resolved.
+    unsafe fn write(&self, buf: *const [u8]) -> Future<Result<usize, CharDeviceError>>;
+
+    /// Performs a device-specific control operation. Commands common to all character devices are defined as the `CTRL_*` constants in
+    /// this module, while devices may accept additional commands of their own. Returns [`CharDeviceError::Unsupported`] if the device does
+    /// not recognize the command.
+    fn control(&self, cmd: u32, arg: usize) -> Result<usize, CharDeviceError> {
+        let _ = (cmd, arg);
+        Err(CharDeviceError::Unsupported)
+    }
+}
+
+pub trait CharDeviceExt: CharDevice {
+    /// Reads bytes from the device into the provided buffer, blocking the current thread until the read has completed.
+    fn read_blocking(&self, buf: &mut [u8]) -> Result<usize, CharDeviceError> {
+        unsafe { self.read(buf).unwrap_blocking() }
+    }
+
+    /// Writes all bytes in the provided buffer to the device, blocking the current thread until they have all been written.
+    fn write_all_blocking(&self, mut buf: &[u8]) -> Result<(), CharDeviceError> {
+        while !buf.is_empty() {
+            match unsafe { self.write(buf).unwrap_blocking() }? {
+                0 => return Err(CharDeviceError::IoError),
+                n => buf = &buf[n..],
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: CharDevice + ?Sized> CharDeviceExt for T {}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use dyn_dyn::dyn_dyn_impl;
+
+    use super::*;
+    use crate::sync::UninterruptibleSpinlock;
+
+    #[derive(Debug)]
+    struct ChunkedSink {
+        chunk: usize,
+        data: UninterruptibleSpinlock<Vec<u8>>,
+    }
+
+    #[dyn_dyn_impl(CharDevice)]
+    impl Device for ChunkedSink {}
+
+    impl CharDevice for ChunkedSink {
+        unsafe fn read(&self, _buf: *mut [u8]) -> Future<Result<usize, CharDeviceError>> {
+            Future::done(Ok(0))
+        }
+
+        unsafe fn write(&self, buf: *const [u8]) -> Future<Result<usize, CharDeviceError>> {
+            let buf = &*buf;
+            let len = buf.len().min(self.chunk);
+
+            self.data.lock().extend_from_slice(&buf[..len]);
+            Future::done(Ok(len))
+        }
+    }
+
+    #[test_case]
+    fn test_write_all_blocking() {
+        let dev = ChunkedSink {
+            chunk: 3,
+            data: UninterruptibleSpinlock::new(Vec::new()),
+        };
+
+        assert_eq!(Ok(()), dev.write_all_blocking(b"hello world"));
+        assert_eq!(b"hello world", &dev.data.lock()[..]);
+        assert_eq!(Ok(0), dev.read_blocking(&mut [0; 4]));
+        assert_eq!(Err(CharDeviceError::Unsupported), dev.control(CTRL_FLUSH, 0));
+    }
+}
@@ -0,0 +1,356 @@
+//! Binding of drivers to the devices they support.
+//!
+//! Rather than each driver being set up by hand, drivers are registered using [`register`] along with rules describing which devices they
+//! can handle, and bus code announces each device it finds using [`announce`] along with the IDs identifying it. Whenever a device is
+//! announced, the drivers whose rules match one of its IDs are probed in the order they were registered until one of them accepts it. A
+//! device that no driver accepts is remembered, so that it can be bound if a driver that supports it is registered later.
+//!
+//! Devices can be announced at any time, not just while the kernel is booting, so hotplugged devices are bound the same way as devices
+//! found during boot. Each probe runs in its own [`RecoveryDomain`], so a driver that panics while probing a device is treated as having
+//! rejected it.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::{fmt, mem};
+
+use super::recovery::RecoveryDomain;
+use crate::log;
+use crate::sync::UninterruptibleSpinlock;
+
+/// An ID by which bus code identifies a device that it has found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceId {
+    /// A device on a PCI bus, identified by the IDs and class codes in its configuration space.
+    Pci { vendor: u16, device: u16, class: u8, subclass: u8 },
+    /// A device at a fixed location that cannot be discovered by enumerating a bus, identified by its PnP ID (e.g. `PNP0303` for a PS/2
+    /// keyboard controller).
+    Platform(&'static str),
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceId::Pci {
+                vendor,
+                device,
+                class,
+                subclass,
+            } => write!(f, "pci:{:04x}:{:04x} class {:02x}:{:02x}", vendor, device, class, subclass),
+            DeviceId::Platform(id) => write!(f, "platform:{}", id),
+        }
+    }
+}
+
+/// A rule describing a set of devices that a driver supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchRule {
+    /// Matches PCI devices with the provided vendor and device IDs.
+    Pci { vendor: u16, device: u16 },
+    /// Matches PCI devices with the provided class and subclass codes.
+    PciClass { class: u8, subclass: u8 },
+    /// Matches platform devices with the provided PnP ID.
+    Platform(&'static str),
+}
+
+impl MatchRule {
+    /// Checks whether this rule matches a device with the provided ID.
+    pub fn matches(&self, id: &DeviceId) -> bool {
+        match (*self, *id) {
+            (MatchRule::Pci { vendor, device }, DeviceId::Pci { vendor: v, device: d, .. }) => vendor == v && device == d,
+            (MatchRule::PciClass { class, subclass }, DeviceId::Pci { class: c, subclass: s, .. }) => class == c && subclass == s,
+            (MatchRule::Platform(rule_id), DeviceId::Platform(id)) => rule_id == id,
+            _ => false,
+        }
+    }
+}
+
+/// A device that has been announced by bus code, but that may not yet have been bound to a driver.
+#[derive(Debug)]
+pub struct DeviceInfo {
+    name: Box<str>,
+    ids: Vec<DeviceId>,
+}
+
+impl DeviceInfo {
+    /// Creates a description of a device with the provided name and IDs. The name should describe where the device was found (e.g. its
+    /// PCI address), while the IDs describe what it is.
+    pub fn new(name: Box<str>, ids: Vec<DeviceId>) -> DeviceInfo {
+        DeviceInfo { name, ids }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ids(&self) -> &[DeviceId] {
+        &self.ids
+    }
+}
+
+/// An error returned by a driver to indicate that it did not bind to a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The driver does not support this device after all, so other matching drivers should be probed instead.
+    Unsupported,
+    /// The driver supports this device, but failed to set it up.
+    Failed,
+}
+
+/// A driver that can be bound to devices matching a set of rules.
+pub trait Driver: Send + Sync {
+    /// Gets the name of this driver.
+    fn name(&self) -> &'static str;
+
+    /// Gets the rules describing the devices that this driver supports.
+    fn match_rules(&self) -> &[MatchRule];
+
+    /// Sets up the provided device, which matches at least one of this driver's rules. This is called from a kernel thread, so it can block.
+    fn probe(&self, dev: &DeviceInfo) -> Result<(), ProbeError>;
+}
+
+/// A device that has been bound to a driver.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub dev: Arc<DeviceInfo>,
+    pub driver: &'static str,
+}
+
+struct Registry {
+    drivers: Vec<&'static dyn Driver>,
+    unbound: Vec<Arc<DeviceInfo>>,
+    bound: Vec<Binding>,
+}
+
+static REGISTRY: UninterruptibleSpinlock<Registry> = UninterruptibleSpinlock::new(Registry {
+    drivers: Vec::new(),
+    unbound: Vec::new(),
+    bound: Vec::new(),
+});
+
+fn driver_matches(driver: &dyn Driver, dev: &DeviceInfo) -> bool {
+    driver.match_rules().iter().any(|rule| dev.ids().iter().any(|id| rule.matches(id)))
+}
+
+/// Probes a single driver for a device, returning `true` if the driver bound to it.
+fn try_bind(driver: &'static dyn Driver, dev: &Arc<DeviceInfo>) -> bool {
+    log!(Debug, "driver", "Probing {} for {}", driver.name(), dev.name());
+
+    match RecoveryDomain::new(driver.name()).run(|| driver.probe(dev)) {
+        Ok(Ok(())) => {
+            log!(Info, "driver", "Bound {} to {}", driver.name(), dev.name());
+            REGISTRY.lock().bound.push(Binding {
+                dev: dev.clone(),
+                driver: driver.name(),
+            });
+            true
+        },
+        Ok(Err(ProbeError::Unsupported)) => false,
+        Ok(Err(ProbeError::Failed)) => {
+            log!(Warning, "driver", "{} failed to set up {}", driver.name(), dev.name());
+            false
+        },
+        Err(err) => {
+            log!(Error, "driver", "{} failed to probe {}: {}", driver.name(), dev.name(), err);
+            false
+        },
+    }
+}
+
+/// Registers a driver, immediately probing it for any announced devices that are not yet bound to a driver and that it supports.
+///
+/// # Panics
+///
+/// This function will panic if a driver with the same name has already been registered.
+pub fn register(driver: &'static dyn Driver) {
+    // Matching devices are taken out of the list of unbound devices while they're being probed, so that they can't be probed by two drivers
+    // being registered at the same time
+    let matching: Vec<_> = {
+        let mut registry = REGISTRY.lock();
+
+        assert!(
+            registry.drivers.iter().all(|d| d.name() != driver.name()),
+            "Duplicate driver {}",
+            driver.name()
+        );
+        registry.drivers.push(driver);
+
+        let (matching, rest) = mem::take(&mut registry.unbound)
+            .into_iter()
+            .partition(|dev| driver_matches(driver, dev));
+
+        registry.unbound = rest;
+        matching
+    };
+
+    for dev in matching {
+        if !try_bind(driver, &dev) {
+            REGISTRY.lock().unbound.push(dev);
+        }
+    }
+}
+
+/// Announces that bus code has found a device, probing the registered drivers that support it until one of them binds to it. Returns
+/// `true` if a driver was bound to the device. Otherwise, the device is remembered so that a driver registered later can bind to it.
+///
+/// This can block while drivers are being probed, so it must be called from a kernel thread.
+pub fn announce(dev: DeviceInfo) -> bool {
+    let dev = Arc::new(dev);
+    let mut num_checked = 0;
+
+    loop {
+        let candidates: Vec<_> = {
+            let registry = REGISTRY.lock();
+            let candidates = registry.drivers[num_checked..]
+                .iter()
+                .copied()
+                .filter(|&driver| driver_matches(driver, &dev))
+                .collect();
+
+            num_checked = registry.drivers.len();
+            candidates
+        };
+
+        if candidates.into_iter().any(|driver| try_bind(driver, &dev)) {
+            return true;
+        }
+
+        // A driver may have been registered while probing, in which case it wouldn't have seen this device yet
+        let mut registry = REGISTRY.lock();
+
+        if registry.drivers.len() == num_checked {
+            log!(Debug, "driver", "No driver found for {}", dev.name());
+            registry.unbound.push(dev);
+            return false;
+        }
+    }
+}
+
+/// Gets the names of all registered drivers, in the order they were registered.
+pub fn drivers() -> Vec<&'static str> {
+    REGISTRY.lock().drivers.iter().map(|d| d.name()).collect()
+}
+
+/// Gets all devices that have been bound to a driver.
+pub fn bindings() -> Vec<Binding> {
+    REGISTRY.lock().bound.clone()
+}
+
+/// Gets all announced devices that are not bound to any driver.
+pub fn unbound_devices() -> Vec<Arc<DeviceInfo>> {
+    REGISTRY.lock().unbound.clone()
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct TestDriver {
+        name: &'static str,
+        rules: [MatchRule; 1],
+        accept: bool,
+        probes: AtomicUsize,
+    }
+
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn match_rules(&self) -> &[MatchRule] {
+            &self.rules
+        }
+
+        fn probe(&self, _dev: &DeviceInfo) -> Result<(), ProbeError> {
+            self.probes.fetch_add(1, Ordering::Relaxed);
+
+            if self.accept {
+                Ok(())
+            } else {
+                Err(ProbeError::Unsupported)
+            }
+        }
+    }
+
+    fn is_bound(name: &str, driver: &str) -> bool {
+        bindings().iter().any(|b| b.dev.name() == name && b.driver == driver)
+    }
+
+    #[test_case]
+    fn test_match_rules() {
+        let pci = DeviceId::Pci {
+            vendor: 0x8086,
+            device: 0x100e,
+            class: 0x02,
+            subclass: 0x00,
+        };
+
+        let by_id = MatchRule::Pci {
+            vendor: 0x8086,
+            device: 0x100e,
+        };
+        let by_class = MatchRule::PciClass {
+            class: 0x02,
+            subclass: 0x00,
+        };
+        let by_other_class = MatchRule::PciClass {
+            class: 0x01,
+            subclass: 0x00,
+        };
+
+        assert!(by_id.matches(&pci));
+        assert!(by_class.matches(&pci));
+        assert!(!by_other_class.matches(&pci));
+        assert!(!MatchRule::Platform("PNP0303").matches(&pci));
+        assert!(MatchRule::Platform("PNP0303").matches(&DeviceId::Platform("PNP0303")));
+    }
+
+    #[test_case]
+    fn test_bind_on_announce() {
+        static REJECT: TestDriver = TestDriver {
+            name: "test reject",
+            rules: [MatchRule::Platform("TEST0001")],
+            accept: false,
+            probes: AtomicUsize::new(0),
+        };
+        static ACCEPT: TestDriver = TestDriver {
+            name: "test accept",
+            rules: [MatchRule::Platform("TEST0001")],
+            accept: true,
+            probes: AtomicUsize::new(0),
+        };
+
+        register(&REJECT);
+        register(&ACCEPT);
+
+        assert!(announce(DeviceInfo::new(Box::from("test0"), vec![DeviceId::Platform("TEST0001")])));
+        assert_eq!(1, REJECT.probes.load(Ordering::Relaxed));
+        assert_eq!(1, ACCEPT.probes.load(Ordering::Relaxed));
+        assert!(is_bound("test0", "test accept"));
+
+        assert!(!announce(DeviceInfo::new(Box::from("test1"), vec![DeviceId::Platform("TEST0002")])));
+        assert!(unbound_devices().iter().any(|dev| dev.name() == "test1"));
+    }
+
+    #[test_case]
+    fn test_bind_on_register() {
+        static LATE: TestDriver = TestDriver {
+            name: "test late",
+            rules: [MatchRule::Platform("TEST0003")],
+            accept: true,
+            probes: AtomicUsize::new(0),
+        };
+
+        assert!(!announce(DeviceInfo::new(Box::from("test2"), vec![DeviceId::Platform("TEST0003")])));
+
+        register(&LATE);
+
+        assert_eq!(1, LATE.probes.load(Ordering::Relaxed));
+        assert!(is_bound("test2", "test late"));
+        assert!(!unbound_devices().iter().any(|dev| dev.name() == "test2"));
+    }
+}
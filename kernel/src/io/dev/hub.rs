@@ -1,9 +1,9 @@
 use alloc::vec;
 use alloc::vec::Vec;
-use core::fmt::Debug;
+use core::fmt::{self, Debug};
 use core::ptr;
 
-use dyn_dyn::dyn_dyn_impl;
+use dyn_dyn::{dyn_dyn_cast, dyn_dyn_impl};
 use itertools::Itertools;
 
 use crate::io::dev::{Device, DeviceNode, DeviceRef, DeviceWeak};
@@ -12,11 +12,52 @@ use crate::sync::UninterruptibleSpinlock;
 #[derive(Debug)]
 pub struct DeviceHubLockedError;
 
+/// An error that can occur when removing a device from its hub.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceRemoveError {
+    /// The device has already been disconnected or removed from its hub.
+    NotConnected,
+    /// The hub that the device is connected to does not support removing its children.
+    NotRemovable,
+}
+
+impl fmt::Display for DeviceRemoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceRemoveError::NotConnected => write!(f, "device is not connected"),
+            DeviceRemoveError::NotRemovable => write!(f, "device cannot be removed from its hub"),
+        }
+    }
+}
+
 pub trait DeviceHub: Device {
     fn for_children(&self, f: &mut dyn FnMut(&DeviceRef<dyn Device>) -> bool) -> bool;
     fn try_for_children(&self, f: &mut dyn FnMut(&DeviceRef<dyn Device>) -> bool) -> Result<bool, DeviceHubLockedError> {
         Ok(self.for_children(f))
     }
+
+    /// Removes the provided child from this hub without disconnecting it. Once this returns successfully, the child is no longer returned
+    /// by [`DeviceHub::for_children`] and the hub no longer communicates with it. This is usually called through [`remove_from_parent`],
+    /// which also disconnects the child.
+    fn remove_child(&self, child: &DeviceRef<dyn Device>) -> Result<(), DeviceRemoveError> {
+        let _ = child;
+        Err(DeviceRemoveError::NotRemovable)
+    }
+}
+
+/// Removes a device from the hub that it is connected to and then disconnects it, along with every device connected below it.
+pub fn remove_from_parent(dev: &DeviceRef<dyn Device>) -> Result<(), DeviceRemoveError> {
+    if !dev.is_connected() {
+        return Err(DeviceRemoveError::NotConnected);
+    }
+
+    let parent = dev.parent_dev().upgrade().ok_or(DeviceRemoveError::NotConnected)?;
+    let hub = dyn_dyn_cast!(Device => DeviceHub, parent.dev()).map_err(|_| DeviceRemoveError::NotRemovable)?;
+
+    hub.remove_child(dev)?;
+    dev.disconnect();
+
+    Ok(())
 }
 
 pub trait DeviceHubExt: DeviceHub {
@@ -84,12 +125,13 @@ impl VirtualDeviceHubInternals {
         dev
     }
 
-    fn remove_device(&mut self, dev: &DeviceRef<dyn Device>) {
+    fn remove_device(&mut self, dev: &DeviceRef<dyn Device>) -> Result<(), DeviceRemoveError> {
         let dev = &**dev;
         if let Some((idx, _)) = self.children.iter().find_position(|&child| ptr::eq(&**child, dev)) {
             self.children.remove(idx);
+            Ok(())
         } else {
-            panic!("Attempt to remove device from VirtualDeviceHub that it's not connected to");
+            Err(DeviceRemoveError::NotConnected)
         }
     }
 
@@ -98,10 +140,9 @@ impl VirtualDeviceHubInternals {
     }
 
     unsafe fn on_disconnected(&mut self) {
+        // The children have already been disconnected along with this hub
         self.own_ref = DeviceWeak::new();
-        for child in self.children.drain(..) {
-            child.disconnect();
-        }
+        self.children.clear();
     }
 
     fn for_children(&self, f: &mut dyn FnMut(&DeviceRef<dyn Device>) -> bool) -> bool {
@@ -132,7 +173,9 @@ impl VirtualDeviceHub {
     }
 
     pub fn remove_device<T: Device>(&self, dev: &DeviceRef<dyn Device>) {
-        self.internal.lock().remove_device(dev)
+        if self.internal.lock().remove_device(dev).is_err() {
+            panic!("Attempt to remove device from VirtualDeviceHub that it's not connected to");
+        }
     }
 }
 
@@ -158,4 +201,8 @@ impl DeviceHub for VirtualDeviceHub {
             None => Err(DeviceHubLockedError),
         }
     }
+
+    fn remove_child(&self, child: &DeviceRef<dyn Device>) -> Result<(), DeviceRemoveError> {
+        self.internal.lock().remove_device(child)
+    }
 }
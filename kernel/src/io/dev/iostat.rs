@@ -0,0 +1,273 @@
+//! Generic statistics about the I/O requests processed by devices.
+//!
+//! Core I/O layers keep an [`IoStats`] for each device they manage and register it under a name using [`register`], which makes it show up
+//! in [`all`] (and thus the `iostat` console command). Every request passed to the device is wrapped by calling [`IoStats::begin`] before
+//! submitting it and [`IoRequest::finish`] once it has completed, which keeps track of the number of requests in flight, the number of
+//! requests and bytes transferred in each direction, and a histogram of how long requests took to complete.
+//!
+//! Latencies are measured using the system clock, so they have a resolution no better than the period of the system tick.
+
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::sched::timer;
+use crate::sync::UninterruptibleSpinlock;
+
+/// The number of buckets in the latency histogram. Bucket 0 counts requests that took less than 1µs and bucket `i` counts requests that
+/// took at least 2<sup>i-1</sup>µs but less than 2<sup>i</sup>µs, except for the last bucket, which counts all slower requests.
+pub const NUM_LATENCY_BUCKETS: usize = 24;
+
+/// The direction in which an I/O request transfers data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoDirection {
+    /// Data is transferred from the device into memory.
+    Read,
+    /// Data is transferred from memory to the device.
+    Write,
+}
+
+impl IoDirection {
+    fn idx(self) -> usize {
+        match self {
+            IoDirection::Read => 0,
+            IoDirection::Write => 1,
+        }
+    }
+}
+
+/// Gets the index of the latency histogram bucket that a request with the provided latency is counted in.
+pub fn latency_bucket(latency: Duration) -> usize {
+    let us = latency.as_micros();
+
+    if us == 0 {
+        0
+    } else {
+        ((u128::BITS - us.leading_zeros()) as usize).min(NUM_LATENCY_BUCKETS - 1)
+    }
+}
+
+/// Statistics about the I/O requests processed by a single device.
+#[derive(Debug)]
+pub struct IoStats {
+    requests: [AtomicU64; 2],
+    bytes: [AtomicU64; 2],
+    errors: AtomicU64,
+    in_flight: AtomicUsize,
+    total_latency_ns: AtomicU64,
+    latency: [AtomicU64; NUM_LATENCY_BUCKETS],
+}
+
+impl IoStats {
+    /// Creates a new set of statistics with no requests recorded.
+    pub const fn new() -> IoStats {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        IoStats {
+            requests: [ZERO; 2],
+            bytes: [ZERO; 2],
+            errors: ZERO,
+            in_flight: AtomicUsize::new(0),
+            total_latency_ns: ZERO,
+            latency: [ZERO; NUM_LATENCY_BUCKETS],
+        }
+    }
+
+    /// Records that a request is being submitted to the device. The returned [`IoRequest`] should be finished once the request has
+    /// completed; if it is dropped without being finished, the request is counted as having failed.
+    pub fn begin(&self, dir: IoDirection) -> IoRequest {
+        self.start(StatsRef::Borrowed(self), dir)
+    }
+
+    /// Records that a request is being submitted to the device, like [`IoStats::begin`], but returns an [`IoRequest`] that keeps the
+    /// statistics alive by itself. This is useful for requests that complete asynchronously.
+    pub fn begin_owned(self: &Arc<Self>, dir: IoDirection) -> IoRequest<'static> {
+        self.start(StatsRef::Owned(self.clone()), dir)
+    }
+
+    fn start<'a>(&self, stats: StatsRef<'a>, dir: IoDirection) -> IoRequest<'a> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        IoRequest {
+            stats,
+            dir,
+            start: timer::now(),
+            bytes: None,
+        }
+    }
+
+    /// Gets a copy of the current statistics.
+    pub fn snapshot(&self) -> IoStatsSnapshot {
+        IoStatsSnapshot {
+            reads: self.requests[0].load(Ordering::Relaxed),
+            writes: self.requests[1].load(Ordering::Relaxed),
+            bytes_read: self.bytes[0].load(Ordering::Relaxed),
+            bytes_written: self.bytes[1].load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            total_latency: Duration::from_nanos(self.total_latency_ns.load(Ordering::Relaxed)),
+            latency: core::array::from_fn(|i| self.latency[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Default for IoStats {
+    fn default() -> Self {
+        IoStats::new()
+    }
+}
+
+#[derive(Debug)]
+enum StatsRef<'a> {
+    Borrowed(&'a IoStats),
+    Owned(Arc<IoStats>),
+}
+
+/// A request that has been submitted to a device and is being tracked by its [`IoStats`].
+#[derive(Debug)]
+#[must_use]
+pub struct IoRequest<'a> {
+    stats: StatsRef<'a>,
+    dir: IoDirection,
+    start: Duration,
+    bytes: Option<u64>,
+}
+
+impl<'a> IoRequest<'a> {
+    /// Records that the request completed successfully after transferring the provided number of bytes.
+    pub fn finish(mut self, bytes: usize) {
+        self.bytes = Some(bytes as u64);
+    }
+
+    /// Records that the request failed.
+    pub fn fail(self) {}
+}
+
+impl<'a> Drop for IoRequest<'a> {
+    fn drop(&mut self) {
+        let stats = match self.stats {
+            StatsRef::Borrowed(stats) => stats,
+            StatsRef::Owned(ref stats) => stats,
+        };
+        let latency = timer::now().saturating_sub(self.start);
+
+        if let Some(bytes) = self.bytes {
+            stats.requests[self.dir.idx()].fetch_add(1, Ordering::Relaxed);
+            stats.bytes[self.dir.idx()].fetch_add(bytes, Ordering::Relaxed);
+        } else {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        stats
+            .total_latency_ns
+            .fetch_add(latency.as_nanos().min(u128::from(u64::MAX)) as u64, Ordering::Relaxed);
+        stats.latency[latency_bucket(latency)].fetch_add(1, Ordering::Relaxed);
+        stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A copy of the statistics of a device at a point in time.
+#[derive(Debug, Clone)]
+pub struct IoStatsSnapshot {
+    /// The number of read requests that completed successfully.
+    pub reads: u64,
+    /// The number of write requests that completed successfully.
+    pub writes: u64,
+    /// The number of bytes transferred by successful read requests.
+    pub bytes_read: u64,
+    /// The number of bytes transferred by successful write requests.
+    pub bytes_written: u64,
+    /// The number of requests that failed.
+    pub errors: u64,
+    /// The number of requests that have been submitted but have not yet completed.
+    pub in_flight: usize,
+    /// The total time taken by all completed requests, including ones that failed.
+    pub total_latency: Duration,
+    /// The number of completed requests in each latency histogram bucket. See [`NUM_LATENCY_BUCKETS`] for the range of each bucket.
+    pub latency: [u64; NUM_LATENCY_BUCKETS],
+}
+
+impl IoStatsSnapshot {
+    /// Gets the total number of requests that have completed, including ones that failed.
+    pub fn completed(&self) -> u64 {
+        self.reads + self.writes + self.errors
+    }
+
+    /// Gets the average time taken by a completed request, or [`None`] if no requests have completed.
+    pub fn avg_latency(&self) -> Option<Duration> {
+        u32::try_from(self.completed())
+            .ok()
+            .filter(|&n| n != 0)
+            .map(|n| self.total_latency / n)
+    }
+}
+
+static REGISTERED: UninterruptibleSpinlock<Vec<(Box<str>, Weak<IoStats>)>> = UninterruptibleSpinlock::new(Vec::new());
+
+/// Registers the statistics of a device under the provided name so that they are returned by [`all`]. The statistics are automatically
+/// unregistered once they are dropped.
+pub fn register(name: &str, stats: &Arc<IoStats>) {
+    REGISTERED.lock().push((Box::from(name), Arc::downgrade(stats)));
+}
+
+/// Gets a snapshot of the statistics of all registered devices, in the order in which they were registered.
+pub fn all() -> Vec<(Box<str>, IoStatsSnapshot)> {
+    let mut registered = REGISTERED.lock();
+    let mut result = Vec::with_capacity(registered.len());
+
+    registered.retain(|(name, stats)| match stats.upgrade() {
+        Some(stats) => {
+            result.push((name.clone(), stats.snapshot()));
+            true
+        },
+        None => false,
+    });
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_latency_bucket() {
+        assert_eq!(0, latency_bucket(Duration::ZERO));
+        assert_eq!(1, latency_bucket(Duration::from_micros(1)));
+        assert_eq!(2, latency_bucket(Duration::from_micros(3)));
+        assert_eq!(10, latency_bucket(Duration::from_millis(1)));
+        assert_eq!(NUM_LATENCY_BUCKETS - 1, latency_bucket(Duration::from_secs(3600)));
+    }
+
+    #[test_case]
+    fn test_io_stats_requests() {
+        let stats = Arc::new(IoStats::new());
+
+        register("test", &stats);
+
+        let read = stats.begin(IoDirection::Read);
+        let write = stats.begin(IoDirection::Write);
+
+        assert_eq!(2, stats.snapshot().in_flight);
+
+        read.finish(512);
+        write.fail();
+        drop(stats.begin(IoDirection::Write));
+
+        let snapshot = stats.snapshot();
+
+        assert_eq!(0, snapshot.in_flight);
+        assert_eq!(1, snapshot.reads);
+        assert_eq!(0, snapshot.writes);
+        assert_eq!(512, snapshot.bytes_read);
+        assert_eq!(2, snapshot.errors);
+        assert_eq!(3, snapshot.latency.iter().sum::<u64>());
+        assert!(all().iter().any(|(name, _)| &**name == "test"));
+
+        drop(stats);
+        assert!(!all().iter().any(|(name, _)| &**name == "test"));
+    }
+}
@@ -1,159 +1,325 @@
 use alloc::string::String;
+use core::fmt;
+use core::time::Duration;
 
 use super::Device;
-use crate::io::keymap::{CommonKeycode, Keycode, KeycodeMap};
+pub use crate::io::keymap::{KeyboardLockState, ModifierState};
+use crate::io::keymap::{Keycode, KeycodeMap};
 use crate::sync::uninterruptible::UninterruptibleSpinlockReadGuard;
 use crate::sync::Future;
+use crate::util::ArrayDeque;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPress {
+    pub code: Keycode,
+    pub lock_state: KeyboardLockState,
+    pub mods: ModifierState,
+    // TODO: Not great that we always need to alloc here
+    pub str: String,
+}
+
+pub trait KeyboardHeldKeys {
+    fn is_held(&self, k: Keycode) -> bool;
+    fn for_all_held_impl(&self, f: &mut dyn FnMut(&[Keycode]));
+}
+
+pub trait KeyboardHeldKeysExt: KeyboardHeldKeys {
+    fn for_all_held(&self, f: &mut impl FnMut(Keycode));
+}
+
+impl<T: KeyboardHeldKeys + ?Sized> KeyboardHeldKeysExt for T {
+    fn for_all_held(&self, f: &mut impl FnMut(Keycode)) {
+        self.for_all_held_impl(&mut |keys| {
+            for &k in keys {
+                f(k);
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyboardError;
+
+/// The auto-repeat (typematic) behaviour of a keyboard, i.e. how long a key must be held before it starts repeating and how quickly it
+/// repeats afterwards.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct KeyboardLockState {
-    pub scroll_lock: bool,
-    pub num_lock: bool,
-    pub caps_lock: bool,
-}
-
-impl KeyboardLockState {
-    pub const fn none() -> KeyboardLockState {
-        KeyboardLockState {
-            scroll_lock: false,
-            num_lock: false,
-            caps_lock: false,
-        }
+pub struct TypematicConfig {
+    pub delay_ms: u32,
+    /// The repeat rate in tenths of a key press per second.
+    pub rate_decihz: u32,
+}
+
+impl fmt::Display for TypematicConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ms delay, {}.{} Hz repeat",
+            self.delay_ms,
+            self.rate_decihz / 10,
+            self.rate_decihz % 10
+        )
     }
+}
 
-    pub fn handle_key_pressed(&mut self, key: Keycode) -> bool {
-        match key {
-            Keycode::Common(CommonKeycode::ScrollLock) => {
-                self.scroll_lock = !self.scroll_lock;
-                true
-            },
-            Keycode::Common(CommonKeycode::NumLock) => {
-                self.num_lock = !self.num_lock;
-                true
-            },
-            Keycode::Common(CommonKeycode::CapsLock) => {
-                self.caps_lock = !self.caps_lock;
-                true
-            },
-            _ => false,
+/// Accessibility features that change how key presses from a keyboard are interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccessibilityConfig {
+    /// Whether pressing and releasing a modifier key on its own latches it, so that it applies to the next key pressed. Doing so again
+    /// before pressing another key unlatches it.
+    pub sticky_keys: bool,
+    /// How long a key must be held before its press is accepted, or 0 to accept key presses immediately.
+    pub slow_keys_ms: u32,
+}
+
+impl fmt::Display for AccessibilityConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sticky keys {}, ", if self.sticky_keys { "on" } else { "off" })?;
+
+        match self.slow_keys_ms {
+            0 => write!(f, "slow keys off"),
+            ms => write!(f, "slow keys {} ms", ms),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct ModifierState {
-    pub left_ctrl: bool,
-    pub right_ctrl: bool,
-    pub left_alt: bool,
-    pub right_alt: bool,
-    pub left_shift: bool,
-    pub right_shift: bool,
-    pub left_super_key: bool,
-    pub right_super_key: bool,
-}
-
-impl ModifierState {
-    pub const fn none() -> ModifierState {
-        ModifierState {
-            left_ctrl: false,
-            right_ctrl: false,
-            left_alt: false,
-            right_alt: false,
-            left_shift: false,
-            right_shift: false,
-            left_super_key: false,
-            right_super_key: false,
+/// Applies the accessibility features in an [`AccessibilityConfig`] to the key state changes reported by a keyboard before they are turned
+/// into key presses. Keyboard drivers pass every key state change through [`KeyFilter::process`] and use [`KeyFilter::apply_latched`] to
+/// get the modifiers for each key press they generate.
+///
+/// Since key state changes are only seen as they arrive, a slow key is accepted once the keyboard's auto-repeat reports it as still being
+/// pressed after the required time, or when it is released if that happens first.
+#[derive(Debug, Clone)]
+pub struct KeyFilter {
+    config: AccessibilityConfig,
+    latched: ModifierState,
+    sticky_candidate: Option<Keycode>,
+    slow_pending: Option<(Keycode, Duration)>,
+    slow_accepted: Option<Keycode>,
+}
+
+impl KeyFilter {
+    pub const fn new() -> KeyFilter {
+        KeyFilter {
+            config: AccessibilityConfig {
+                sticky_keys: false,
+                slow_keys_ms: 0,
+            },
+            latched: ModifierState::none(),
+            sticky_candidate: None,
+            slow_pending: None,
+            slow_accepted: None,
         }
     }
 
-    pub fn ctrl(&self) -> bool {
-        self.left_ctrl || self.right_ctrl
+    pub fn config(&self) -> AccessibilityConfig {
+        self.config
     }
 
-    pub fn alt(&self) -> bool {
-        self.left_alt || self.right_alt
+    /// Changes the accessibility features being applied, forgetting any latched modifiers and keys waiting to be accepted.
+    pub fn set_config(&mut self, config: AccessibilityConfig) {
+        *self = KeyFilter::new();
+        self.config = config;
     }
 
-    pub fn shift(&self) -> bool {
-        self.left_shift || self.right_shift
+    /// Processes a key state change reported by the keyboard at the provided time, returning the key state changes that should actually
+    /// be acted upon in order.
+    pub fn process(&mut self, key: Keycode, pressed: bool, now: Duration) -> ArrayDeque<(Keycode, bool), 2> {
+        let mut events = ArrayDeque::new();
+        let slow_keys = Duration::from_millis(u64::from(self.config.slow_keys_ms));
+
+        if pressed {
+            if !slow_keys.is_zero() && self.slow_accepted != Some(key) {
+                match self.slow_pending {
+                    Some((pending, start)) if pending == key && now.saturating_sub(start) >= slow_keys => {
+                        self.slow_pending = None;
+                        self.slow_accepted = Some(key);
+                    },
+                    Some((pending, _)) if pending == key => {
+                        return events;
+                    },
+                    _ => {
+                        self.slow_pending = Some((key, now));
+                        self.slow_accepted = None;
+                        return events;
+                    },
+                }
+            }
+
+            self.handle_sticky_press(key);
+            let _ = events.push_back((key, true));
+        } else {
+            if let Some((pending, start)) = self.slow_pending {
+                if pending == key {
+                    self.slow_pending = None;
+
+                    if now.saturating_sub(start) >= slow_keys {
+                        self.handle_sticky_press(key);
+                        let _ = events.push_back((key, true));
+                    }
+                }
+            }
+
+            if self.slow_accepted == Some(key) {
+                self.slow_accepted = None;
+            }
+
+            self.handle_sticky_release(key);
+
+            // Releasing a key whose press was never accepted is harmless, so releases are always passed through
+            let _ = events.push_back((key, false));
+        }
+
+        events
     }
 
-    pub fn super_key(&self) -> bool {
-        self.left_super_key || self.right_super_key
+    fn handle_sticky_press(&mut self, key: Keycode) {
+        self.sticky_candidate = if ModifierState::is_modifier(key) { Some(key) } else { None };
     }
 
-    pub fn handle_key_state_changed(&mut self, key: Keycode, pressed: bool) -> bool {
-        match key {
-            Keycode::Common(CommonKeycode::LeftCtrl) => {
-                self.left_ctrl = pressed;
-                true
-            },
-            Keycode::Common(CommonKeycode::RightCtrl) => {
-                self.right_ctrl = pressed;
-                true
-            },
-            Keycode::Common(CommonKeycode::LeftAlt) => {
-                self.left_alt = pressed;
-                true
-            },
-            Keycode::Common(CommonKeycode::RightAlt) => {
-                self.right_alt = pressed;
-                true
-            },
-            Keycode::Common(CommonKeycode::LeftShift) => {
-                self.left_shift = pressed;
-                true
-            },
-            Keycode::Common(CommonKeycode::RightShift) => {
-                self.right_shift = pressed;
-                true
-            },
-            Keycode::Common(CommonKeycode::LeftSuper) => {
-                self.left_super_key = pressed;
-                true
-            },
-            Keycode::Common(CommonKeycode::RightSuper) => {
-                self.right_super_key = pressed;
-                true
-            },
-            _ => false,
+    fn handle_sticky_release(&mut self, key: Keycode) {
+        if self.sticky_candidate != Some(key) {
+            return;
+        }
+
+        self.sticky_candidate = None;
+
+        if self.config.sticky_keys {
+            let mut key_mods = ModifierState::none();
+
+            key_mods.handle_key_state_changed(key, true);
+
+            let was_latched = self.latched.union(key_mods) == self.latched;
+
+            self.latched.handle_key_state_changed(key, !was_latched);
         }
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct KeyPress {
-    pub code: Keycode,
-    pub lock_state: KeyboardLockState,
-    pub mods: ModifierState,
-    // TODO: Not great that we always need to alloc here
-    pub str: String,
+    /// Gets the modifiers that apply to a press of the provided key, given the modifier keys that are actually being held. Any latched
+    /// modifiers are added and, unless the key is itself a modifier, unlatched.
+    pub fn apply_latched(&mut self, key: Keycode, mods: ModifierState) -> ModifierState {
+        let mods = mods.union(self.latched);
+
+        if !ModifierState::is_modifier(key) {
+            self.latched = ModifierState::none();
+        }
+
+        mods
+    }
 }
 
-pub trait KeyboardHeldKeys {
-    fn is_held(&self, k: Keycode) -> bool;
-    fn for_all_held_impl(&self, f: &mut dyn FnMut(&[Keycode]));
+impl Default for KeyFilter {
+    fn default() -> Self {
+        KeyFilter::new()
+    }
 }
 
-pub trait KeyboardHeldKeysExt: KeyboardHeldKeys {
-    fn for_all_held(&self, f: &mut impl FnMut(Keycode));
+/// Repeats held keys in software, so that keys repeat at the configured delay and rate regardless of whether and how the keyboard itself
+/// repeats them. Keyboard drivers pass every key state change through [`KeyRepeater::process`], which decides whether it should generate a
+/// key press and when a timer should next fire. When that timer fires, the driver calls [`KeyRepeater::handle_timer`] to find out which key
+/// to generate another press of.
+///
+/// While software repeat is enabled, any repeated presses reported by the keyboard itself are ignored. Modifier and lock keys are never
+/// repeated, since repeating them would either do nothing or toggle them back and forth.
+#[derive(Debug, Clone)]
+pub struct KeyRepeater {
+    config: Option<TypematicConfig>,
+    repeating: Option<Keycode>,
+    next_repeat: Duration,
+    /// Incremented whenever the key being repeated changes, so that timers started for an earlier key can be recognized and ignored.
+    generation: u64,
 }
 
-impl<T: KeyboardHeldKeys + ?Sized> KeyboardHeldKeysExt for T {
-    fn for_all_held(&self, f: &mut impl FnMut(Keycode)) {
-        self.for_all_held_impl(&mut |keys| {
-            for &k in keys {
-                f(k);
-            }
+impl KeyRepeater {
+    pub const fn new() -> KeyRepeater {
+        KeyRepeater {
+            config: None,
+            repeating: None,
+            next_repeat: Duration::ZERO,
+            generation: 0,
+        }
+    }
+
+    /// Gets the software repeat configuration, or `None` if software repeat is disabled.
+    pub fn config(&self) -> Option<TypematicConfig> {
+        self.config
+    }
+
+    /// Enables software repeat with the provided configuration, or disables it if `None` is provided. Any key currently being repeated
+    /// stops repeating until it is pressed again.
+    pub fn set_config(&mut self, config: Option<TypematicConfig>) {
+        self.config = config.map(|config| TypematicConfig {
+            rate_decihz: config.rate_decihz.max(1),
+            ..config
         });
+        self.stop();
+    }
+
+    /// Stops repeating the key currently being repeated, e.g. because the keyboard was reset and its held keys were forgotten.
+    pub fn stop(&mut self) {
+        self.repeating = None;
+        self.generation += 1;
+    }
+
+    fn period(config: TypematicConfig) -> Duration {
+        Duration::from_secs(10) / config.rate_decihz
+    }
+
+    /// Processes a key state change that the keyboard reported at the provided time. Returns whether the key state change should be acted
+    /// upon, along with the time at which a timer should call [`KeyRepeater::handle_timer`] and the generation to pass to it, if one is
+    /// needed.
+    pub fn process(&mut self, key: Keycode, pressed: bool, now: Duration) -> (bool, Option<(Duration, u64)>) {
+        let Some(config) = self.config else {
+            return (true, None);
+        };
+
+        if !pressed {
+            if self.repeating == Some(key) {
+                self.stop();
+            }
+
+            (true, None)
+        } else if self.repeating == Some(key) {
+            (false, None)
+        } else if ModifierState::is_modifier(key) || KeyboardLockState::is_lock_key(key) {
+            (true, None)
+        } else {
+            // Like on other consoles, only the most recently pressed key repeats
+            self.stop();
+            self.repeating = Some(key);
+            self.next_repeat = now + Duration::from_millis(u64::from(config.delay_ms));
+
+            (true, Some((self.next_repeat, self.generation)))
+        }
+    }
+
+    /// Handles a timer started for the provided generation firing at the provided time. If the key it was started for is still being
+    /// repeated, returns that key, which should be pressed again, along with the time at which the timer should fire next.
+    pub fn handle_timer(&mut self, generation: u64, now: Duration) -> Option<(Keycode, Duration)> {
+        let (Some(config), Some(key)) = (self.config, self.repeating) else {
+            return None;
+        };
+
+        if generation != self.generation {
+            return None;
+        }
+
+        // If the timer fired late, skip the repeats that were missed rather than delivering them all at once
+        self.next_repeat = (self.next_repeat + KeyRepeater::period(config)).max(now);
+
+        Some((key, self.next_repeat))
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct KeyboardError;
+impl Default for KeyRepeater {
+    fn default() -> Self {
+        KeyRepeater::new()
+    }
+}
 
 pub trait Keyboard: Device {
     fn lock_state(&self) -> Result<KeyboardLockState, KeyboardError>;
+
+    /// Changes the lock state of the keyboard. Keyboards with indicator LEDs also update them to match.
     fn set_lock_state(&self, lock_state: KeyboardLockState) -> Result<(), KeyboardError>;
 
     fn mod_state(&self) -> Result<ModifierState, KeyboardError>;
@@ -163,4 +329,171 @@ pub trait Keyboard: Device {
     fn set_keymap(&self, map: &'static KeycodeMap);
 
     fn next_key(&self) -> Future<Result<KeyPress, KeyboardError>>;
+
+    /// Checks whether the keyboard is responding to commands without changing any of its state. Keyboards that cannot be queried in this
+    /// way return an error.
+    fn echo(&self) -> Result<(), KeyboardError> {
+        Err(KeyboardError)
+    }
+
+    /// Makes the keyboard run its built-in self-test and then restores its configuration. Returns an error if the self-test fails or if the
+    /// keyboard does not support self-testing.
+    fn self_test(&self) -> Result<(), KeyboardError> {
+        Err(KeyboardError)
+    }
+
+    /// Lights the keyboard's indicator LEDs according to the provided lock state without changing the lock state itself. Keyboards keep
+    /// their LEDs in sync with their own lock state, so the LEDs go back to showing it the next time a lock key is pressed. Keyboards
+    /// without indicator LEDs return an error.
+    fn set_leds(&self, lock_state: KeyboardLockState) -> Result<(), KeyboardError> {
+        let _ = lock_state;
+        Err(KeyboardError)
+    }
+
+    /// Gets the auto-repeat configuration of the keyboard, if it can be configured.
+    fn typematic(&self) -> Option<TypematicConfig> {
+        None
+    }
+
+    /// Configures the auto-repeat behaviour of the keyboard. Since keyboards generally only support a fixed set of delays and rates, the
+    /// closest supported configuration is used and returned.
+    fn set_typematic(&self, config: TypematicConfig) -> Result<TypematicConfig, KeyboardError> {
+        let _ = config;
+        Err(KeyboardError)
+    }
+
+    /// Gets the configuration of software auto-repeat for the keyboard, or `None` if keys only repeat if the keyboard itself repeats them.
+    fn soft_repeat(&self) -> Option<TypematicConfig> {
+        None
+    }
+
+    /// Enables software auto-repeat for the keyboard with the provided configuration, or disables it if `None` is provided. Unlike
+    /// [`Keyboard::set_typematic`], the configuration is used exactly as provided.
+    fn set_soft_repeat(&self, config: Option<TypematicConfig>) -> Result<(), KeyboardError> {
+        let _ = config;
+        Err(KeyboardError)
+    }
+
+    /// Gets the accessibility features applied to key presses from the keyboard, if they are supported.
+    fn accessibility(&self) -> Option<AccessibilityConfig> {
+        None
+    }
+
+    /// Changes the accessibility features applied to key presses from the keyboard.
+    fn set_accessibility(&self, config: AccessibilityConfig) -> Result<(), KeyboardError> {
+        let _ = config;
+        Err(KeyboardError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::io::keymap::CommonKeycode;
+
+    const SHIFT: Keycode = Keycode::Common(CommonKeycode::LeftShift);
+    const A: Keycode = Keycode::Common(CommonKeycode::A);
+
+    fn process(filter: &mut KeyFilter, key: Keycode, pressed: bool, ms: u64) -> Vec<(Keycode, bool)> {
+        filter.process(key, pressed, Duration::from_millis(ms)).drain().collect()
+    }
+
+    #[test_case]
+    fn test_sticky_keys() {
+        let mut filter = KeyFilter::new();
+
+        filter.set_config(AccessibilityConfig {
+            sticky_keys: true,
+            slow_keys_ms: 0,
+        });
+
+        assert_eq!(vec![(SHIFT, true)], process(&mut filter, SHIFT, true, 0));
+        assert_eq!(vec![(SHIFT, false)], process(&mut filter, SHIFT, false, 10));
+
+        process(&mut filter, A, true, 20);
+        assert!(filter.apply_latched(A, ModifierState::none()).left_shift);
+
+        process(&mut filter, A, false, 30);
+        process(&mut filter, A, true, 40);
+        assert!(!filter.apply_latched(A, ModifierState::none()).left_shift);
+
+        // Pressing the modifier twice unlatches it again
+        for ms in [50, 60, 70, 80] {
+            process(&mut filter, SHIFT, ms % 20 == 10, ms);
+        }
+
+        process(&mut filter, A, true, 90);
+        assert!(!filter.apply_latched(A, ModifierState::none()).left_shift);
+    }
+
+    #[test_case]
+    fn test_slow_keys() {
+        let mut filter = KeyFilter::new();
+
+        filter.set_config(AccessibilityConfig {
+            sticky_keys: false,
+            slow_keys_ms: 100,
+        });
+
+        // A brief tap is ignored entirely, apart from the harmless release
+        assert!(process(&mut filter, A, true, 0).is_empty());
+        assert_eq!(vec![(A, false)], process(&mut filter, A, false, 50));
+
+        // A key held down is accepted once auto-repeat reports it after the required time
+        assert!(process(&mut filter, A, true, 100).is_empty());
+        assert!(process(&mut filter, A, true, 150).is_empty());
+        assert_eq!(vec![(A, true)], process(&mut filter, A, true, 200));
+        assert_eq!(vec![(A, true)], process(&mut filter, A, true, 230));
+        assert_eq!(vec![(A, false)], process(&mut filter, A, false, 260));
+
+        // A key held long enough without repeating is accepted when it is released
+        assert!(process(&mut filter, A, true, 300).is_empty());
+        assert_eq!(vec![(A, true), (A, false)], process(&mut filter, A, false, 450));
+    }
+
+    #[test_case]
+    fn test_key_repeat() {
+        const B: Keycode = Keycode::Common(CommonKeycode::B);
+
+        let ms = Duration::from_millis;
+        let mut repeater = KeyRepeater::new();
+
+        // Nothing is repeated in software until it is enabled
+        assert_eq!((true, None), repeater.process(A, true, ms(0)));
+        assert_eq!((true, None), repeater.process(A, true, ms(30)));
+
+        repeater.set_config(Some(TypematicConfig {
+            delay_ms: 500,
+            rate_decihz: 200,
+        }));
+
+        let (deliver, timer) = repeater.process(A, true, ms(1000));
+        let (deadline, generation) = timer.unwrap();
+
+        assert!(deliver);
+        assert_eq!(ms(1500), deadline);
+
+        // Repeats from the keyboard itself are ignored
+        assert_eq!((false, None), repeater.process(A, true, ms(1030)));
+
+        assert_eq!(Some((A, ms(1550))), repeater.handle_timer(generation, ms(1500)));
+        assert_eq!(Some((A, ms(1600))), repeater.handle_timer(generation, ms(1550)));
+
+        // A late timer skips the repeats that were missed
+        assert_eq!(Some((A, ms(1900))), repeater.handle_timer(generation, ms(1900)));
+
+        // Pressing another key stops the first one from repeating
+        let (_, timer) = repeater.process(B, true, ms(2000));
+
+        assert_eq!(None, repeater.handle_timer(generation, ms(2050)));
+        assert_eq!(Some((B, ms(2550))), repeater.handle_timer(timer.unwrap().1, ms(2500)));
+
+        // Releasing the key stops it from repeating, and modifiers never repeat
+        assert_eq!((true, None), repeater.process(B, false, ms(2600)));
+        assert_eq!(None, repeater.handle_timer(timer.unwrap().1, ms(2600)));
+        assert_eq!((true, None), repeater.process(SHIFT, true, ms(2700)));
+    }
 }
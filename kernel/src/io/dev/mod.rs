@@ -7,18 +7,30 @@ use core::fmt::Debug;
 use core::marker::Unsize;
 use core::ops::{CoerceUnsized, Deref};
 use core::ptr::Pointee;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 use core::{fmt, ptr};
 
 use dyn_dyn::{dyn_dyn_base, dyn_dyn_cast, dyn_dyn_impl, DowncastUnchecked, DynDynBase, DynDynTable, GetDynDynTable};
 
+use crate::arch::interrupt::{self, InterruptHandler};
 use crate::io::dev::hub::{DeviceHub, DeviceHubExt, DeviceHubLockedError, VirtualDeviceHub};
 use crate::log;
+use crate::sched::timer;
 use crate::sync::future::FutureWriter;
 use crate::sync::{Future, UninterruptibleSpinlock};
 use crate::util::OneShotManualInit;
 
+pub mod chardev;
+pub mod driver;
 pub mod hub;
+pub mod iostat;
 pub mod kbd;
+pub mod mouse;
+pub mod null;
+pub mod observer;
+pub mod probe;
+pub mod recovery;
 
 pub struct DeviceRef<T: ?Sized>(Arc<DeviceNode<T>>);
 
@@ -125,6 +137,24 @@ pub trait Device: Send + Sync + Debug + 'static {
     unsafe fn on_disconnected(&self) {}
 }
 
+/// Gets a reference to a device as a `dyn Device`, whether or not its type is already known. This is needed to check whether a device is a
+/// hub while disconnecting it.
+pub trait AsDynDevice {
+    fn as_dyn_device(&self) -> &dyn Device;
+}
+
+impl<T: Device> AsDynDevice for T {
+    fn as_dyn_device(&self) -> &dyn Device {
+        self
+    }
+}
+
+impl AsDynDevice for dyn Device {
+    fn as_dyn_device(&self) -> &dyn Device {
+        self
+    }
+}
+
 #[derive(Debug)]
 struct DummyDevice {}
 
@@ -136,6 +166,9 @@ pub struct DeviceNode<T: ?Sized> {
     parent: DeviceWeak<dyn Device>,
     name: Box<str>,
     disconnect_event: UninterruptibleSpinlock<Option<FutureWriter<()>>>,
+    connected_at: Duration,
+    errors: AtomicU64,
+    status: UninterruptibleSpinlock<Option<Box<str>>>,
     dev: T,
 }
 
@@ -145,6 +178,9 @@ impl<T: Device> DeviceNode<T> {
             parent: <DeviceWeak<DummyDevice>>::new(),
             name,
             disconnect_event: UninterruptibleSpinlock::new(None),
+            connected_at: timer::now(),
+            errors: AtomicU64::new(0),
+            status: UninterruptibleSpinlock::new(None),
             dev,
         }
     }
@@ -152,6 +188,7 @@ impl<T: Device> DeviceNode<T> {
     pub fn connect(mut self, parent: DeviceWeak<dyn Device>) -> DeviceRef<T> {
         self.parent = parent;
         self.disconnect_event = UninterruptibleSpinlock::new(Some(FutureWriter::new()));
+        self.connected_at = timer::now();
 
         let dev = DeviceRef::new(self);
 
@@ -159,20 +196,39 @@ impl<T: Device> DeviceNode<T> {
             dev.dev.on_connected(&dev);
         }
 
+        observer::notify_connected(dev.clone());
         dev
     }
 }
 
-impl<T: Device + ?Sized> DeviceNode<T> {
+impl<T: AsDynDevice + ?Sized> DeviceNode<T> {
+    /// Disconnects this device. If this device is a hub, every device connected below it that is still connected is disconnected first,
+    /// so that drivers of child devices can still communicate with their parent while handling the disconnection.
+    ///
+    /// Disconnecting a device does not remove it from its parent hub. Use [`hub::remove_from_parent`] to do both.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if this device has already been disconnected.
     pub fn disconnect(&self) {
-        if let Some(disconnect_event) = self.disconnect_event.lock().take() {
-            disconnect_event.finish(());
-        } else {
+        let Some(disconnect_event) = self.disconnect_event.lock().take() else {
             panic!("Cannot disconnect an already disconnected device");
+        };
+
+        let dev = self.dev.as_dyn_device();
+
+        if let Ok(hub) = dyn_dyn_cast!(Device => DeviceHub, dev) {
+            for child in hub.children() {
+                if child.is_connected() {
+                    child.disconnect();
+                }
+            }
         }
 
+        disconnect_event.finish(());
+
         unsafe {
-            self.dev.on_disconnected();
+            dev.on_disconnected();
         }
     }
 }
@@ -194,12 +250,62 @@ impl<T: ?Sized> DeviceNode<T> {
         &self.dev
     }
 
+    pub fn is_connected(&self) -> bool {
+        self.disconnect_event.lock().is_some()
+    }
+
     pub fn when_disconnected(&self) -> Future<()> {
         self.disconnect_event
             .lock()
             .as_ref()
             .map_or_else(|| Future::done(()), |w| w.as_future())
     }
+
+    /// Registers a handler for an IRQ raised by this device. The handler is unregistered once this device is disconnected, which also
+    /// drops anything that it captured (e.g. references to this device).
+    ///
+    /// # Safety
+    ///
+    /// The same requirements apply as for [`interrupt::register_irq`].
+    pub unsafe fn register_irq(&self, n: usize, handler: InterruptHandler) {
+        interrupt::register_irq(n, handler);
+
+        // This device may be disconnected from inside its own IRQ handler, while the IRQ handler table is locked
+        self.when_disconnected().when_resolved_soft(move |()| unsafe {
+            interrupt::unregister_irq(n);
+        });
+    }
+
+    /// Gets how long this device has been connected, or [`None`] if it has been disconnected.
+    pub fn uptime(&self) -> Option<Duration> {
+        if self.is_connected() {
+            Some(timer::now().saturating_sub(self.connected_at))
+        } else {
+            None
+        }
+    }
+
+    /// Records that the driver for this device encountered an error while communicating with it. Errors that the driver recovered from
+    /// should still be recorded, so that flaky devices can be identified by their error counts.
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gets the number of errors that have been recorded using [`DeviceNode::record_error`] since this device was created.
+    pub fn error_count(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Sets a short human-readable description of the state of this device, replacing any previously set status. The status is shown
+    /// alongside the device when printing the device tree.
+    pub fn set_status(&self, status: Option<&str>) {
+        *self.status.lock() = status.map(Box::from);
+    }
+
+    /// Gets the status most recently set using [`DeviceNode::set_status`].
+    pub fn status(&self) -> Option<Box<str>> {
+        self.status.lock().clone()
+    }
 }
 
 unsafe impl DynDynBase for DeviceNode<dyn Device> {
@@ -341,6 +447,23 @@ fn print_device_tree_internal<E>(root: &DeviceRef<dyn Device>, mut f: impl FnMut
             write!(line, " ]").unwrap();
         }
 
+        if let Some(uptime) = dev.uptime() {
+            write!(line, " up {}s", uptime.as_secs()).unwrap();
+        }
+
+        match dev.error_count() {
+            0 => {},
+            1 => write!(line, ", 1 error").unwrap(),
+            errors => write!(line, ", {} errors", errors).unwrap(),
+        }
+
+        // The status lock is never held for long, but this may be printing the device tree while panicking
+        if let Some(status) = dev.status.try_lock() {
+            if let Some(ref status) = *status {
+                write!(line, ", {}", status).unwrap();
+            }
+        }
+
         f(line)?;
 
         match children {
@@ -380,3 +503,62 @@ pub fn log_device_tree() {
     })
     .unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_device_health() {
+        let dev = device_root()
+            .dev()
+            .add_device(DeviceNode::new(Box::from("test_health"), DummyDevice {}));
+
+        assert!(dev.uptime().is_some());
+        assert_eq!(0, dev.error_count());
+        assert_eq!(None, dev.status());
+
+        dev.record_error();
+        dev.record_error();
+        dev.set_status(Some("degraded"));
+
+        assert_eq!(2, dev.error_count());
+        assert_eq!(Some("degraded"), dev.status().as_deref());
+
+        let mut tree = String::new();
+
+        print_device_tree(&mut tree, &(dev.clone() as DeviceRef<dyn Device>)).unwrap();
+        assert!(tree.contains(", 2 errors, degraded"));
+
+        hub::remove_from_parent(&(dev.clone() as DeviceRef<dyn Device>)).unwrap();
+
+        assert_eq!(None, dev.uptime());
+    }
+
+    #[test_case]
+    fn test_disconnect_hub() {
+        let hub = device_root()
+            .dev()
+            .add_device(DeviceNode::new(Box::from("test_hub"), VirtualDeviceHub::new()));
+        let inner_hub = hub.dev().add_device(DeviceNode::new(Box::from("inner"), VirtualDeviceHub::new()));
+        let dev = inner_hub.dev().add_device(DeviceNode::new(Box::from("dev"), DummyDevice {}));
+        let other = hub.dev().add_device(DeviceNode::new(Box::from("other"), DummyDevice {}));
+
+        assert!(get_device_by_name("::test_hub::inner::dev").is_ok());
+
+        hub::remove_from_parent(&(inner_hub.clone() as DeviceRef<dyn Device>)).unwrap();
+
+        assert!(!inner_hub.is_connected());
+        assert!(!dev.is_connected());
+        assert!(other.is_connected());
+        assert!(get_device_by_name("::test_hub::inner").is_err());
+        assert_eq!(
+            Err(hub::DeviceRemoveError::NotConnected),
+            hub::remove_from_parent(&(dev.clone() as DeviceRef<dyn Device>))
+        );
+
+        hub::remove_from_parent(&(hub.clone() as DeviceRef<dyn Device>)).unwrap();
+
+        assert!(!other.is_connected());
+    }
+}
@@ -0,0 +1,61 @@
+use core::fmt;
+
+use bitflags::bitflags;
+
+use super::Device;
+use crate::sync::Future;
+
+bitflags! {
+    /// A set of mouse buttons.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct MouseButtons: u8 {
+        const LEFT = 0x01;
+        const RIGHT = 0x02;
+        const MIDDLE = 0x04;
+        /// The first side button, which is usually used to go back.
+        const BUTTON_4 = 0x08;
+        /// The second side button, which is usually used to go forward.
+        const BUTTON_5 = 0x10;
+    }
+}
+
+impl fmt::Display for MouseButtons {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+
+        bitflags::parser::to_writer(self, f)
+    }
+}
+
+/// A single report of the state of a mouse.
+///
+/// Movement is relative to the previous report. Following screen coordinates, positive `dx` is to the right and positive `dy` is
+/// downwards. Positive `wheel` means that the wheel was scrolled down (towards the user).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    /// The buttons that are held down at the time of this report.
+    pub buttons: MouseButtons,
+    pub dx: i32,
+    pub dy: i32,
+    pub wheel: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct MouseError;
+
+pub trait Mouse: Device {
+    /// Gets the buttons that the mouse currently reports as being held down.
+    fn buttons(&self) -> Result<MouseButtons, MouseError>;
+
+    /// Gets the buttons that the mouse can report.
+    fn supported_buttons(&self) -> MouseButtons;
+
+    /// Checks whether the mouse has a scroll wheel.
+    fn has_wheel(&self) -> bool;
+
+    /// Gets the next report from the mouse. Reports that arrive while nobody is waiting for them are queued, though consecutive reports may
+    /// be merged together if they are not read quickly enough. If the mouse is disconnected, an error is returned instead.
+    fn next_event(&self) -> Future<Result<MouseEvent, MouseError>>;
+}
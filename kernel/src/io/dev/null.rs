@@ -0,0 +1,111 @@
+//! Trivial character devices that discard written data or produce an endless stream of zeroes.
+//!
+//! These devices are registered directly under the device root as `null`, `zero`, and `full`:
+//!
+//! - Reading from `null` always reaches the end of the stream immediately, while writes succeed and discard the data.
+//! - Reading from `zero` produces zeroes, while writes succeed and discard the data.
+//! - Reading from `full` produces zeroes, while writes always fail as if the device had no space left.
+
+use alloc::boxed::Box;
+
+use dyn_dyn::dyn_dyn_impl;
+
+use super::chardev::{CharDevice, CharDeviceError};
+use super::{device_root, Device, DeviceNode};
+use crate::sync::Future;
+
+/// A character device that produces no data and discards everything written to it.
+#[derive(Debug)]
+pub struct NullDevice;
+
+#[dyn_dyn_impl(CharDevice)]
+impl Device for NullDevice {}
+
+impl CharDevice for NullDevice {
+    unsafe fn read(&self, _buf: *mut [u8]) -> Future<Result<usize, CharDeviceError>> {
+        Future::done(Ok(0))
+    }
+
+    unsafe fn write(&self, buf: *const [u8]) -> Future<Result<usize, CharDeviceError>> {
+        Future::done(Ok(buf.len()))
+    }
+}
+
+/// A character device that produces an endless stream of zeroes and discards everything written to it.
+#[derive(Debug)]
+pub struct ZeroDevice;
+
+#[dyn_dyn_impl(CharDevice)]
+impl Device for ZeroDevice {}
+
+impl CharDevice for ZeroDevice {
+    unsafe fn read(&self, buf: *mut [u8]) -> Future<Result<usize, CharDeviceError>> {
+        (*buf).fill(0);
+        Future::done(Ok(buf.len()))
+    }
+
+    unsafe fn write(&self, buf: *const [u8]) -> Future<Result<usize, CharDeviceError>> {
+        Future::done(Ok(buf.len()))
+    }
+}
+
+/// A character device that produces an endless stream of zeroes and fails all writes as if it were full.
+#[derive(Debug)]
+pub struct FullDevice;
+
+#[dyn_dyn_impl(CharDevice)]
+impl Device for FullDevice {}
+
+impl CharDevice for FullDevice {
+    unsafe fn read(&self, buf: *mut [u8]) -> Future<Result<usize, CharDeviceError>> {
+        (*buf).fill(0);
+        Future::done(Ok(buf.len()))
+    }
+
+    unsafe fn write(&self, buf: *const [u8]) -> Future<Result<usize, CharDeviceError>> {
+        Future::done(if buf.is_empty() { Ok(0) } else { Err(CharDeviceError::NoSpace) })
+    }
+}
+
+fn init() {
+    let root = device_root().dev();
+
+    root.add_device(DeviceNode::new(Box::from("null"), NullDevice));
+    root.add_device(DeviceNode::new(Box::from("zero"), ZeroDevice));
+    root.add_device(DeviceNode::new(Box::from("full"), FullDevice));
+}
+
+crate::initcall!(arch, "null devices", init);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::dev::chardev::CharDeviceExt;
+
+    #[test_case]
+    fn test_null_zero_full() {
+        let mut buf = [0xff; 8];
+
+        assert_eq!(Ok(0), NullDevice.read_blocking(&mut buf));
+        assert_eq!([0xff; 8], buf);
+        assert_eq!(Ok(()), NullDevice.write_all_blocking(b"discarded"));
+
+        assert_eq!(Ok(8), ZeroDevice.read_blocking(&mut buf));
+        assert_eq!([0; 8], buf);
+        assert_eq!(Ok(()), ZeroDevice.write_all_blocking(b"discarded"));
+
+        buf = [0xff; 8];
+        assert_eq!(Ok(8), FullDevice.read_blocking(&mut buf));
+        assert_eq!([0; 8], buf);
+        assert_eq!(Err(CharDeviceError::NoSpace), FullDevice.write_all_blocking(b"discarded"));
+    }
+
+    #[test_case]
+    fn test_devices_registered() {
+        for name in ["::null", "::zero", "::full"] {
+            let dev = crate::io::dev::get_device_by_name(name).ok().unwrap();
+
+            assert!(dyn_dyn::dyn_dyn_cast!(Device => CharDevice, dev.dev()).is_ok());
+        }
+    }
+}
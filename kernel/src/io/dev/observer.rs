@@ -0,0 +1,163 @@
+//! Notifications about changes to the device tree.
+//!
+//! Subsystems that need to react to devices coming and going (e.g. the virtual terminal manager attaching new keyboards) can register a
+//! [`DeviceTreeObserver`] using [`add_observer`] rather than having every driver call into them directly. Observers are called in a soft
+//! interrupt for every device that is connected to a hub after they are registered, and again once that device is disconnected. Devices
+//! that were already connected when an observer was registered are not reported, but can be found by walking the tree from
+//! [`device_root`](super::device_root).
+//!
+//! Since observers run in soft interrupts, they must not block. Anything that can block should be handed off to a thread.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Device, DeviceRef};
+use crate::sched;
+use crate::sync::UninterruptibleSpinlock;
+
+/// A change to the device tree that is reported to observers.
+#[derive(Debug, Clone)]
+pub enum DeviceTreeEvent {
+    /// The provided device was connected to a hub.
+    Connected(DeviceRef<dyn Device>),
+    /// The provided device was disconnected. It may or may not have been removed from its hub yet.
+    Disconnected(DeviceRef<dyn Device>),
+}
+
+impl DeviceTreeEvent {
+    /// Gets the device that this event is about.
+    pub fn dev(&self) -> &DeviceRef<dyn Device> {
+        match *self {
+            DeviceTreeEvent::Connected(ref dev) => dev,
+            DeviceTreeEvent::Disconnected(ref dev) => dev,
+        }
+    }
+}
+
+/// A function to be called whenever the device tree changes.
+pub type DeviceTreeObserver = Box<dyn Fn(&DeviceTreeEvent) + Send + Sync>;
+
+/// An identifier for a registered observer, which can be passed to [`remove_observer`] to stop it from being called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceTreeObserverId(u64);
+
+struct DeviceTreeObserverEntry {
+    id: DeviceTreeObserverId,
+    name: &'static str,
+    observer: Arc<dyn Fn(&DeviceTreeEvent) + Send + Sync>,
+}
+
+static OBSERVERS: UninterruptibleSpinlock<Vec<DeviceTreeObserverEntry>> = UninterruptibleSpinlock::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Registers an observer to be called for every change to the device tree from now on, until it is removed using [`remove_observer`].
+pub fn add_observer(name: &'static str, observer: DeviceTreeObserver) -> DeviceTreeObserverId {
+    let id = DeviceTreeObserverId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    OBSERVERS.lock().push(DeviceTreeObserverEntry {
+        id,
+        name,
+        observer: Arc::from(observer),
+    });
+    id
+}
+
+/// Removes an observer registered using [`add_observer`]. Returns `false` if the observer had already been removed.
+pub fn remove_observer(id: DeviceTreeObserverId) -> bool {
+    let mut observers = OBSERVERS.lock();
+
+    if let Some(idx) = observers.iter().position(|o| o.id == id) {
+        observers.remove(idx);
+        true
+    } else {
+        false
+    }
+}
+
+/// Gets the names of all registered observers, in the order in which they are called.
+pub fn observers() -> Vec<&'static str> {
+    OBSERVERS.lock().iter().map(|o| o.name).collect()
+}
+
+fn notify(event: DeviceTreeEvent) {
+    // The device tree is usually changed with a hub locked, so observers are called later once it's safe for them to look at the tree
+    sched::enqueue_soft_interrupt(move || {
+        // Observers may add or remove observers themselves, so they can't be called with the list locked
+        let observers: Vec<_> = OBSERVERS.lock().iter().map(|o| o.observer.clone()).collect();
+
+        for observer in observers {
+            observer(&event);
+        }
+    });
+}
+
+/// Reports that the provided device was just connected and arranges for its disconnection to be reported once it happens.
+pub(super) fn notify_connected(dev: DeviceRef<dyn Device>) {
+    notify(DeviceTreeEvent::Connected(dev.clone()));
+
+    dev.when_disconnected().when_resolved(move |()| {
+        notify(DeviceTreeEvent::Disconnected(dev));
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+    use alloc::vec;
+
+    use super::*;
+    use crate::io::dev::hub::{self, VirtualDeviceHub};
+    use crate::io::dev::{device_root, DeviceNode};
+
+    #[test_case]
+    fn test_observer() {
+        static EVENTS: UninterruptibleSpinlock<Vec<(bool, String)>> = UninterruptibleSpinlock::new(Vec::new());
+
+        let id = add_observer(
+            "test",
+            Box::new(|event| {
+                if event.dev().name().starts_with("test_observer") {
+                    let connected = matches!(*event, DeviceTreeEvent::Connected(_));
+
+                    EVENTS.lock().push((connected, String::from(event.dev().name())));
+                }
+            }),
+        );
+
+        let hub = device_root()
+            .dev()
+            .add_device(DeviceNode::new(Box::from("test_observer_hub"), VirtualDeviceHub::new()));
+        let dev = hub
+            .dev()
+            .add_device(DeviceNode::new(Box::from("test_observer_dev"), VirtualDeviceHub::new()));
+
+        assert_eq!(
+            vec![(true, String::from("test_observer_hub")), (true, String::from("test_observer_dev"))],
+            *EVENTS.lock()
+        );
+
+        hub::remove_from_parent(&(hub.clone() as DeviceRef<dyn Device>)).unwrap();
+
+        assert!(!dev.is_connected());
+        assert_eq!(
+            vec![
+                (true, String::from("test_observer_hub")),
+                (true, String::from("test_observer_dev")),
+                (false, String::from("test_observer_dev")),
+                (false, String::from("test_observer_hub")),
+            ],
+            *EVENTS.lock()
+        );
+
+        assert!(remove_observer(id));
+        assert!(!remove_observer(id));
+
+        device_root()
+            .dev()
+            .add_device(DeviceNode::new(Box::from("test_observer_late"), VirtualDeviceHub::new()));
+
+        assert_eq!(4, EVENTS.lock().len());
+    }
+}
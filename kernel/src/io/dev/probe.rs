@@ -0,0 +1,126 @@
+//! Concurrent probing of devices during boot.
+//!
+//! Probing some devices can take a long time (e.g. waiting for a device to time out when it isn't present), so rather than probing devices
+//! one after another, each probe is run on its own kernel thread. A probe can depend on other probes, in which case it doesn't start until
+//! all of its dependencies have finished, e.g. so that the devices on a bus are only probed once the bus controller itself is set up.
+//!
+//! Each probe also runs in its own [`RecoveryDomain`], so a probe that panics only disconnects the devices it attached to its domain
+//! rather than crashing the kernel. Probes that depend on a failed probe still run once it has failed.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::recovery::RecoveryDomain;
+use crate::sched::task::Process;
+use crate::sync::future::FutureWriter;
+use crate::sync::Future;
+use crate::{boottime, log};
+
+const PROBE_STACK_SIZE: usize = 4 * 4096;
+
+struct Probe {
+    name: &'static str,
+    deps: Vec<usize>,
+    f: Box<dyn FnOnce() + Send>,
+}
+
+/// A set of device probes to be run concurrently.
+pub struct ProbeSet {
+    probes: Vec<Probe>,
+}
+
+impl ProbeSet {
+    /// Creates a new empty set of device probes.
+    pub fn new() -> ProbeSet {
+        ProbeSet { probes: Vec::new() }
+    }
+
+    /// Adds a probe with the provided name to this set. The probe will not start running until all probes named in `deps` have finished.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if a probe with the same name has already been added or if any of the dependencies have not already been
+    /// added. Requiring dependencies to be added first ensures that there can never be any dependency cycles.
+    pub fn add(&mut self, name: &'static str, deps: &[&'static str], f: impl FnOnce() + Send + 'static) {
+        assert!(self.probes.iter().all(|p| p.name != name), "Duplicate device probe {}", name);
+
+        let deps = deps
+            .iter()
+            .map(|&dep| {
+                self.probes
+                    .iter()
+                    .position(|p| p.name == dep)
+                    .unwrap_or_else(|| panic!("Device probe {} depends on unknown probe {}", name, dep))
+            })
+            .collect();
+
+        self.probes.push(Probe {
+            name,
+            deps,
+            f: Box::new(f),
+        });
+    }
+
+    /// Starts running all probes in this set, returning a future that resolves once all of them have finished.
+    pub fn start(self) -> Future<()> {
+        let (done, writers): (Vec<Future<()>>, Vec<FutureWriter<()>>) = self.probes.iter().map(|_| Future::new()).unzip();
+
+        for (probe, writer) in self.probes.into_iter().zip(writers) {
+            let deps = Future::all(probe.deps.iter().map(|&i| done[i].without_val()));
+            let thread = Process::kernel().lock().create_kernel_thread(
+                probe.name,
+                move || {
+                    deps.unwrap_blocking();
+
+                    log!(Debug, "probe", "Probing {}", probe.name);
+
+                    if RecoveryDomain::new(probe.name).run(probe.f).is_ok() {
+                        boottime::mark(probe.name);
+                    }
+
+                    writer.finish(());
+                },
+                PROBE_STACK_SIZE,
+            );
+
+            thread.lock().wake();
+        }
+
+        Future::all(done)
+    }
+
+    /// Runs all probes in this set, blocking until all of them have finished.
+    pub fn run(self) {
+        self.start().unwrap_blocking();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::sync::UninterruptibleSpinlock;
+
+    #[test_case]
+    fn test_probe_dependencies() {
+        let order = Arc::new(UninterruptibleSpinlock::new(Vec::new()));
+        let mut probes = ProbeSet::new();
+
+        for (name, deps) in [("a", &[][..]), ("b", &["a"][..]), ("c", &[][..]), ("d", &["b", "c"][..])] {
+            let order = order.clone();
+
+            probes.add(name, deps, move || order.lock().push(name));
+        }
+
+        probes.run();
+
+        let order = order.lock();
+        let pos = |name| order.iter().position(|&n| n == name).unwrap();
+
+        assert_eq!(4, order.len());
+        assert!(pos("a") < pos("b"));
+        assert!(pos("b") < pos("d"));
+        assert!(pos("c") < pos("d"));
+    }
+}
@@ -0,0 +1,369 @@
+//! Recovery from panics in device drivers.
+//!
+//! Drivers for non-critical devices are often the least tested code in the kernel, and a bug in one of them shouldn't bring down the
+//! whole system (e.g. a keyboard driver panicking during bring-up). To limit the damage, driver code such as device probes and IRQ handlers
+//! can be run inside a [`RecoveryDomain`]. If a panic occurs while running inside a domain, the panic handler rewinds the stack back to
+//! where the domain was entered rather than showing the crash screen. The domain is then marked as failed, every device attached to it is
+//! disconnected, and the call that entered the domain returns [`RecoveryError::Panicked`].
+//!
+//! Since kernel panics abort rather than unwinding, recovery is best-effort: no destructors are run for anything on the discarded part of
+//! the stack. Interrupt-disabling guards, raised interrupt levels and tracked spinlocks acquired inside the domain are forcibly released,
+//! but any other resources (e.g. heap allocations, [`Mutex`](crate::sync::mutex::Mutex) guards and spinlocks when spinlock tracking is
+//! disabled) are leaked, and any data structure that was being modified may be left in an inconsistent state. Recovery is therefore only
+//! suitable for isolating drivers whose state is discarded once they fail.
+//!
+//! Panics in an IRQ handler are only recovered if the handler itself entered a domain (see [`wrap_irq_handler`]), while panics caused by
+//! exceptions (e.g. page faults) are recovered by the domain of the code that caused the exception.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Device, DeviceWeak};
+use crate::arch::interrupt::{self, InterruptHandler};
+use crate::arch::recovery::{self as arch_recovery, RecoveryPoint};
+use crate::sched::task::Thread;
+use crate::sync::level::{self, InterruptLevel};
+use crate::sync::uninterruptible::{InterruptDisabler, RawSpinlock};
+use crate::sync::UninterruptibleSpinlock;
+use crate::{log, sched};
+
+const MAX_MESSAGE_LEN: usize = 256;
+
+crate::cpu_local! {
+    static IN_IRQ_HANDLER: Cell<bool> = Cell::new(false);
+    static IRQ_FRAME: Cell<*mut RecoveryFrame> = Cell::new(ptr::null_mut());
+    static RECOVERING: Cell<bool> = Cell::new(false);
+}
+
+/// A fixed-size buffer for the panic message, since the heap may not be usable while the panic is being handled.
+struct MessageBuf {
+    buf: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl MessageBuf {
+    const fn new() -> MessageBuf {
+        MessageBuf {
+            buf: [0; MAX_MESSAGE_LEN],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        let bytes = &self.buf[..self.len];
+
+        // The message may have been truncated in the middle of a character
+        core::str::from_utf8(bytes).unwrap_or_else(|err| core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap())
+    }
+}
+
+impl Write for MessageBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MAX_MESSAGE_LEN - self.len);
+
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// The state recorded when entering a recovery domain, which is needed to resume execution there if a panic occurs.
+pub(crate) struct RecoveryFrame {
+    point: RecoveryPoint,
+    domain: *const RecoveryDomain,
+    prev: *mut RecoveryFrame,
+    in_irq_handler: bool,
+    in_interrupt: bool,
+    interrupts_enabled: bool,
+    num_disablers: usize,
+    num_spinlocks: usize,
+    level: InterruptLevel,
+    message: MessageBuf,
+}
+
+/// An error returned when code running in a [`RecoveryDomain`] could not run to completion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryError {
+    /// The domain had already failed, so the code was not run.
+    AlreadyFailed,
+    /// The code panicked with the provided message, causing the domain to fail.
+    Panicked(Box<str>),
+}
+
+impl fmt::Display for RecoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RecoveryError::AlreadyFailed => write!(f, "recovery domain has already failed"),
+            RecoveryError::Panicked(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// A domain in which driver code can run, such that a panic in that code only disconnects the devices attached to the domain rather than
+/// crashing the kernel.
+#[derive(Debug)]
+pub struct RecoveryDomain {
+    name: Box<str>,
+    devices: UninterruptibleSpinlock<Vec<DeviceWeak<dyn Device>>>,
+    failed: AtomicBool,
+}
+
+impl RecoveryDomain {
+    /// Creates a new recovery domain with the provided name, which has no devices attached to it.
+    pub fn new(name: &str) -> Arc<RecoveryDomain> {
+        Arc::new(RecoveryDomain {
+            name: Box::from(name),
+            devices: UninterruptibleSpinlock::new(Vec::new()),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Gets the name of this domain.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets whether a panic has previously occurred in this domain.
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Attaches a device to this domain, causing it to be disconnected if code running in this domain panics. If the domain has already
+    /// failed, the device is disconnected immediately.
+    pub fn attach(&self, dev: DeviceWeak<dyn Device>) {
+        let mut devices = self.devices.lock();
+
+        if self.has_failed() {
+            drop(devices);
+            disconnect_devices(alloc::vec![dev]);
+        } else {
+            devices.push(dev);
+        }
+    }
+
+    /// Runs the provided function inside this domain, returning its result. If the function panics, execution is rewound back to this call,
+    /// this domain is marked as failed and all devices attached to it are disconnected.
+    ///
+    /// Nothing on the stack of the provided function is dropped if it panics, so it should avoid holding resources other than
+    /// interrupt-disabling guards and spinlocks. See the [module documentation](self) for more details on what is cleaned up. Any spinlocks
+    /// that were held when entering the domain must still be held when it panics, otherwise the wrong spinlocks may be released.
+    pub fn run<R>(&self, f: impl FnOnce() -> R) -> Result<R, RecoveryError> {
+        if self.has_failed() {
+            return Err(RecoveryError::AlreadyFailed);
+        }
+
+        let mut frame = RecoveryFrame {
+            point: RecoveryPoint::new(),
+            domain: self,
+            prev: current_frame(),
+            in_irq_handler: IN_IRQ_HANDLER.get(),
+            in_interrupt: sched::is_handling_interrupt(),
+            interrupts_enabled: interrupt::are_enabled(),
+            num_disablers: InterruptDisabler::num_held(),
+            // SAFETY: Only the length of the returned slice is used
+            num_spinlocks: unsafe { RawSpinlock::held() }.map_or(0, |held| held.len()),
+            level: level::current(),
+            message: MessageBuf::new(),
+        };
+        let frame_ptr: *mut RecoveryFrame = &mut frame;
+        let mut call = (Some(f), None);
+
+        set_current_frame(frame_ptr);
+
+        // SAFETY: The frame is not moved until after this call returns and call_in_domain is called with a pointer of the right type
+        let panicked = unsafe {
+            arch_recovery::call_with_recovery_point(
+                ptr::addr_of_mut!((*frame_ptr).point),
+                call_in_domain::<_, R>,
+                &mut call as *mut (Option<_>, Option<R>) as *mut u8,
+            )
+        };
+
+        if !panicked {
+            set_current_frame(frame.prev);
+            return Ok(call.1.unwrap());
+        }
+
+        // SAFETY: Execution was just rewound back to the point where the frame was recorded, so nothing that acquired these resources can
+        //         run again
+        unsafe {
+            restore_frame(&frame);
+        }
+
+        let msg = Box::from(frame.message.as_str());
+
+        log!(Error, "recovery", "Recovered from panic in {}: {}", self.name, msg);
+        self.fail();
+
+        Err(RecoveryError::Panicked(msg))
+    }
+
+    /// Marks this domain as failed and disconnects all devices attached to it.
+    pub fn fail(&self) {
+        let devices = {
+            let mut devices = self.devices.lock();
+
+            self.failed.store(true, Ordering::Relaxed);
+            core::mem::take(&mut *devices)
+        };
+
+        disconnect_devices(devices);
+    }
+}
+
+/// Attaches a device to the innermost recovery domain that is currently running, if any. This allows code such as device probes to attach
+/// the devices they create to whichever domain they were run in.
+pub fn attach_to_current(dev: DeviceWeak<dyn Device>) {
+    let frame = current_frame();
+
+    if !frame.is_null() {
+        // SAFETY: A non-null frame pointer always points to the frame of a call to RecoveryDomain::run that hasn't returned yet, which
+        //         borrows its domain for the duration of the call
+        unsafe {
+            (*(*frame).domain).attach(dev);
+        }
+    }
+}
+
+/// Wraps an IRQ handler such that it runs in the provided recovery domain. Once the domain has failed, the IRQ is ignored.
+pub fn wrap_irq_handler(domain: Arc<RecoveryDomain>, handler: InterruptHandler) -> InterruptHandler {
+    Box::new(move |frame| {
+        let _ = domain.run(|| handler(frame));
+    })
+}
+
+/// Runs an IRQ handler. This must be called by the architecture's interrupt handling infrastructure, so that panics in IRQ handlers are not
+/// recovered by a domain that the interrupted code was running in.
+pub(crate) fn run_irq_handler(f: impl FnOnce()) {
+    let was_in_irq_handler = IN_IRQ_HANDLER.replace(true);
+
+    f();
+    IN_IRQ_HANDLER.set(was_in_irq_handler);
+}
+
+/// Attempts to recover from a panic by rewinding execution back to the innermost recovery domain that is currently running. Returns
+/// without doing anything if the panic cannot be recovered from.
+///
+/// This should be called by the panic handler before doing anything else.
+pub fn try_recover(info: &PanicInfo) {
+    let frame = current_frame();
+
+    if frame.is_null() || RECOVERING.get() {
+        return;
+    }
+
+    // SAFETY: A non-null frame pointer always points to the frame of a call to RecoveryDomain::run that hasn't returned yet
+    let frame = unsafe { &mut *frame };
+
+    if !arch_recovery::can_resume(&frame.point) {
+        return;
+    }
+
+    // If formatting the panic message panics, recovering again would just cause the same thing to happen again
+    RECOVERING.set(true);
+
+    frame.message.len = 0;
+    let _ = write!(frame.message, "{}", info);
+
+    // SAFETY: The recovery point was just checked to be resumable
+    unsafe {
+        arch_recovery::resume_recovery_point(&frame.point);
+    }
+}
+
+unsafe extern "C" fn call_in_domain<F: FnOnce() -> R, R>(data: *mut u8) {
+    let call = &mut *(data as *mut (Option<F>, Option<R>));
+
+    call.1 = Some((call.0.take().unwrap())());
+}
+
+fn current_frame() -> *mut RecoveryFrame {
+    match Thread::current_interrupted() {
+        Some(thread) if !IN_IRQ_HANDLER.get() => thread.recovery_frame(),
+        _ => IRQ_FRAME.get(),
+    }
+}
+
+fn set_current_frame(frame: *mut RecoveryFrame) {
+    match Thread::current_interrupted() {
+        Some(thread) if !IN_IRQ_HANDLER.get() => thread.set_recovery_frame(frame),
+        _ => IRQ_FRAME.set(frame),
+    }
+}
+
+unsafe fn restore_frame(frame: &RecoveryFrame) {
+    let _ = RawSpinlock::force_unlock_held_since(frame.num_spinlocks);
+    InterruptDisabler::force_reset_num_held(frame.num_disablers);
+    level::force_reset(frame.level);
+    sched::force_set_handling_interrupt(frame.in_interrupt);
+    IN_IRQ_HANDLER.set(frame.in_irq_handler);
+    set_current_frame(frame.prev);
+    RECOVERING.set(false);
+
+    if frame.interrupts_enabled {
+        interrupt::enable();
+    }
+}
+
+fn disconnect_devices(devices: Vec<DeviceWeak<dyn Device>>) {
+    // Disconnecting a device can run arbitrary callbacks, so this is deferred until the IRQ handler (if any) has finished
+    sched::enqueue_soft_interrupt(move || {
+        for dev in devices.iter().filter_map(|dev| dev.upgrade()) {
+            if dev.is_connected() {
+                log!(Warning, "recovery", "Disconnecting failed device {}", dev.full_name());
+                dev.disconnect();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::dev::null::NullDevice;
+    use crate::io::dev::{DeviceNode, DeviceRef};
+
+    #[test_case]
+    fn test_recovery_domain_ok() {
+        let domain = RecoveryDomain::new("test");
+
+        assert_eq!(Ok(42), domain.run(|| 42));
+        assert!(!domain.has_failed());
+    }
+
+    #[test_case]
+    fn test_recovery_domain_panic() {
+        if !cfg!(feature = "real_arch_api") {
+            crate::test_util::skip("recovery is not supported by the simulated architecture");
+            return;
+        }
+
+        let domain = RecoveryDomain::new("test");
+        let dev = DeviceNode::new(Box::from("recovery_test"), NullDevice).connect(<DeviceWeak<NullDevice>>::new());
+        let lock = UninterruptibleSpinlock::new(());
+        let num_disablers = InterruptDisabler::num_held();
+        let interrupts_enabled = interrupt::are_enabled();
+
+        domain.attach(DeviceRef::downgrade(&dev));
+
+        let result = domain.run(|| {
+            let _guard = lock.lock();
+            panic!("oops");
+        });
+
+        assert!(matches!(result, Err(RecoveryError::Panicked(ref msg)) if msg.contains("oops")));
+        assert!(domain.has_failed());
+        assert!(!dev.is_connected());
+        assert!(lock.try_lock().is_some());
+        assert_eq!(num_disablers, InterruptDisabler::num_held());
+        assert_eq!(interrupts_enabled, interrupt::are_enabled());
+
+        assert_eq!(Err(RecoveryError::AlreadyFailed), domain.run(|| ()));
+    }
+}
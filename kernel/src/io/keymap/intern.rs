@@ -0,0 +1,51 @@
+//! Interning of strings produced by keymaps.
+//!
+//! Key actions refer to their strings as `&'static str`, so that looking up the action for a key press never needs to allocate or copy
+//! anything. Keymaps compiled into the kernel use string literals, while keymaps constructed at runtime get their strings from [`intern`],
+//! which allocates each distinct string only once and never frees it. Since many keymaps produce the same strings (e.g. escape sequences
+//! for the arrow keys), loading several similar keymaps only uses a little more memory than loading one.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+
+// Strings are only ever interned while parsing keymaps from thread context, so a plain spinlock is enough here. Avoiding the kernel's own
+// locks keeps this module buildable on the host for the fuzz targets.
+static STRINGS: spin::Mutex<BTreeSet<&'static str>> = spin::Mutex::new(BTreeSet::new());
+
+/// Gets a copy of the provided string that lives forever, allocating one only if an identical string has not already been interned.
+pub fn intern(s: &str) -> &'static str {
+    let mut strings = STRINGS.lock();
+
+    if let Some(&s) = strings.get(s) {
+        return s;
+    }
+
+    let s: &'static str = Box::leak(Box::from(s));
+
+    strings.insert(s);
+    s
+}
+
+/// Gets the number of distinct strings that have been interned and the total number of bytes they take up.
+pub fn stats() -> (usize, usize) {
+    let strings = STRINGS.lock();
+
+    (strings.len(), strings.iter().map(|s| s.len()).sum())
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+
+    use super::*;
+
+    #[test_case]
+    fn test_intern() {
+        let a = intern("\x1b[A");
+        let b = intern(&String::from("\x1b[A"));
+
+        assert_eq!("\x1b[A", a);
+        assert!(core::ptr::eq(a, b));
+        assert!(!core::ptr::eq(a, intern("\x1b[B")));
+    }
+}
@@ -1,13 +1,20 @@
+use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::mem::{self, forget};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
-use super::dev::kbd::{KeyboardLockState, ModifierState};
-
+pub mod intern;
+pub mod parse;
 mod qwerty_us;
 
 #[derive(Debug)]
 pub struct InvalidKeycodeError;
 
+#[derive(Debug)]
+pub struct DuplicateKeymapError;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum CommonKeycode {
@@ -162,12 +169,151 @@ impl TryFrom<usize> for Keycode {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardLockState {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl KeyboardLockState {
+    pub const fn none() -> KeyboardLockState {
+        KeyboardLockState {
+            scroll_lock: false,
+            num_lock: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Checks whether the provided key is one of the lock keys tracked by this state.
+    pub fn is_lock_key(key: Keycode) -> bool {
+        KeyboardLockState::none().handle_key_pressed(key)
+    }
+
+    pub fn handle_key_pressed(&mut self, key: Keycode) -> bool {
+        match key {
+            Keycode::Common(CommonKeycode::ScrollLock) => {
+                self.scroll_lock = !self.scroll_lock;
+                true
+            },
+            Keycode::Common(CommonKeycode::NumLock) => {
+                self.num_lock = !self.num_lock;
+                true
+            },
+            Keycode::Common(CommonKeycode::CapsLock) => {
+                self.caps_lock = !self.caps_lock;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModifierState {
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_super_key: bool,
+    pub right_super_key: bool,
+}
+
+impl ModifierState {
+    pub const fn none() -> ModifierState {
+        ModifierState {
+            left_ctrl: false,
+            right_ctrl: false,
+            left_alt: false,
+            right_alt: false,
+            left_shift: false,
+            right_shift: false,
+            left_super_key: false,
+            right_super_key: false,
+        }
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn super_key(&self) -> bool {
+        self.left_super_key || self.right_super_key
+    }
+
+    /// Gets the modifiers that are held in either this state or the provided state.
+    pub fn union(&self, other: ModifierState) -> ModifierState {
+        ModifierState {
+            left_ctrl: self.left_ctrl || other.left_ctrl,
+            right_ctrl: self.right_ctrl || other.right_ctrl,
+            left_alt: self.left_alt || other.left_alt,
+            right_alt: self.right_alt || other.right_alt,
+            left_shift: self.left_shift || other.left_shift,
+            right_shift: self.right_shift || other.right_shift,
+            left_super_key: self.left_super_key || other.left_super_key,
+            right_super_key: self.right_super_key || other.right_super_key,
+        }
+    }
+
+    /// Checks whether the provided key is one of the modifier keys tracked by this state.
+    pub fn is_modifier(key: Keycode) -> bool {
+        ModifierState::none().handle_key_state_changed(key, true)
+    }
+
+    pub fn handle_key_state_changed(&mut self, key: Keycode, pressed: bool) -> bool {
+        match key {
+            Keycode::Common(CommonKeycode::LeftCtrl) => {
+                self.left_ctrl = pressed;
+                true
+            },
+            Keycode::Common(CommonKeycode::RightCtrl) => {
+                self.right_ctrl = pressed;
+                true
+            },
+            Keycode::Common(CommonKeycode::LeftAlt) => {
+                self.left_alt = pressed;
+                true
+            },
+            Keycode::Common(CommonKeycode::RightAlt) => {
+                self.right_alt = pressed;
+                true
+            },
+            Keycode::Common(CommonKeycode::LeftShift) => {
+                self.left_shift = pressed;
+                true
+            },
+            Keycode::Common(CommonKeycode::RightShift) => {
+                self.right_shift = pressed;
+                true
+            },
+            Keycode::Common(CommonKeycode::LeftSuper) => {
+                self.left_super_key = pressed;
+                true
+            },
+            Keycode::Common(CommonKeycode::RightSuper) => {
+                self.right_super_key = pressed;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum KeyAction {
     None,
     Char(char),
     Str(&'static str),
-    String(String),
 }
 
 impl From<char> for KeyAction {
@@ -183,8 +329,9 @@ impl From<&'static str> for KeyAction {
 }
 
 impl From<String> for KeyAction {
+    /// Converts a string built at runtime into an action, storing it in the [interner](intern) so that it can be shared by all keymaps.
     fn from(value: String) -> Self {
-        KeyAction::String(value)
+        KeyAction::Str(intern::intern(&value))
     }
 }
 
@@ -231,6 +378,10 @@ impl KeycodeMap {
         self.name
     }
 
+    pub fn get_common(&self, k: CommonKeycode) -> &KeycodeMapEntry {
+        &self.common[k as usize]
+    }
+
     pub fn get(&self, k: Keycode, lock_state: KeyboardLockState, mod_state: ModifierState) -> Option<&KeyAction> {
         if mod_state.ctrl() || mod_state.alt() || mod_state.super_key() {
             return None;
@@ -266,9 +417,82 @@ impl KeycodeMap {
     }
 }
 
+static BUILTIN_KEYMAPS: [&KeycodeMap; 1] = [&qwerty_us::KEYMAP];
+
+// Keymaps are only registered and looked up by name from thread context, so a plain spinlock is enough here. Keyboards pick up the default
+// keymap when they are connected, which may happen in an interrupt handler, so it is kept in an atomic instead. Avoiding the kernel's own
+// locks keeps this module buildable on the host for the fuzz targets.
+static LOADED_KEYMAPS: spin::Mutex<Vec<&'static KeycodeMap>> = spin::Mutex::new(Vec::new());
+static DEFAULT_KEYMAP: AtomicPtr<KeycodeMap> = AtomicPtr::new(ptr::addr_of!(qwerty_us::KEYMAP) as *mut KeycodeMap);
+
 pub fn get_keymap(name: &str) -> Option<&'static KeycodeMap> {
-    match name {
-        "qwerty-us" => Some(&qwerty_us::KEYMAP),
-        _ => None,
+    BUILTIN_KEYMAPS
+        .iter()
+        .copied()
+        .chain(LOADED_KEYMAPS.lock().iter().copied())
+        .find(|map| map.name() == name)
+}
+
+/// Gets all keymaps that can be looked up using [`get_keymap`], starting with those built into the kernel and followed by those registered
+/// using [`register_keymap`] in the order they were registered.
+pub fn keymaps() -> Vec<&'static KeycodeMap> {
+    BUILTIN_KEYMAPS
+        .iter()
+        .copied()
+        .chain(LOADED_KEYMAPS.lock().iter().copied())
+        .collect()
+}
+
+/// Registers a keymap constructed at runtime (e.g. by [`parse::parse`]) so that it can be looked up by name using [`get_keymap`].
+///
+/// Since keyboards can hold onto a keymap indefinitely, registered keymaps are never freed and cannot be replaced. An error is returned if
+/// a keymap with the same name already exists.
+pub fn register_keymap(map: Box<KeycodeMap>) -> Result<&'static KeycodeMap, DuplicateKeymapError> {
+    let mut loaded = LOADED_KEYMAPS.lock();
+
+    if BUILTIN_KEYMAPS
+        .iter()
+        .copied()
+        .chain(loaded.iter().copied())
+        .any(|m| m.name() == map.name())
+    {
+        return Err(DuplicateKeymapError);
+    }
+
+    let map = Box::leak(map);
+
+    loaded.push(map);
+    Ok(map)
+}
+
+/// Gets the keymap that newly connected keyboards start out using.
+pub fn default_keymap() -> &'static KeycodeMap {
+    // SAFETY: Only pointers to keymaps with a 'static lifetime are ever stored in DEFAULT_KEYMAP
+    unsafe { &*DEFAULT_KEYMAP.load(Ordering::Acquire) }
+}
+
+/// Sets the keymap that newly connected keyboards start out using. Keyboards that are already connected keep using their current keymap.
+pub fn set_default_keymap(map: &'static KeycodeMap) {
+    DEFAULT_KEYMAP.store(map as *const KeycodeMap as *mut KeycodeMap, Ordering::Release);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_register_keymap() {
+        let map = register_keymap(parse::parse("test-register", "A simple b").unwrap()).unwrap();
+
+        assert!(core::ptr::eq(map, get_keymap("test-register").unwrap()));
+        assert!(keymaps().iter().any(|&m| core::ptr::eq(m, map)));
+        assert!(register_keymap(parse::parse("test-register", "").unwrap()).is_err());
+        assert!(register_keymap(Box::new(KeycodeMap::new("qwerty-us"))).is_err());
+
+        let old_default = default_keymap();
+
+        set_default_keymap(map);
+        assert!(core::ptr::eq(map, default_keymap()));
+        set_default_keymap(old_default);
     }
 }
@@ -0,0 +1,237 @@
+//! Parsing of keymaps from a simple text format, so that keymaps can be constructed at runtime rather than only being compiled into the
+//! kernel.
+//!
+//! Each line of a keymap file either is blank, is a comment starting with `#`, or describes what a single key produces:
+//!
+//! ```text
+//! # key   kind       actions...
+//! A       shiftcaps  a A
+//! Num1    shift      1 !
+//! Enter   simple     "\n"
+//! Numpad8 numlock    "\x1b[A" 8
+//! ```
+//!
+//! The key is the name of a [`CommonKeycode`] (ignoring case), and the kind is one of `simple`, `shift`, `shiftcaps` or `numlock`,
+//! corresponding to the variants of [`KeycodeMapEntry`]. A `simple` entry takes one action, while the others take two: the first is used
+//! when the modifier or lock is not in effect and the second is used when it is.
+//!
+//! An action is either `none`, meaning that the key produces no input, or the string that the key produces. Strings without whitespace,
+//! quotes or backslashes can be written as-is, while other strings must be enclosed in double quotes, where `\n`, `\t`, `\e` (escape),
+//! `\\`, `\"` and `\xHH` escapes can be used. Keys not mentioned in the file produce no input.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{intern, CommonKeycode, KeyAction, KeycodeMap, KeycodeMapEntry};
+
+/// The reason that a keymap file could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapParseErrorKind {
+    /// The key name does not name a [`CommonKeycode`].
+    UnknownKey,
+    /// The entry kind is not one of the recognized kinds.
+    UnknownEntryKind,
+    /// The entry has the wrong number of actions for its kind.
+    WrongActionCount,
+    /// A quoted string is not terminated or contains an invalid escape.
+    InvalidString,
+    /// The key has already been given an entry earlier in the file.
+    DuplicateKey,
+}
+
+/// An error describing where and why a keymap file could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeymapParseError {
+    /// The line on which the error was found, starting at 1.
+    pub line: usize,
+    pub kind: KeymapParseErrorKind,
+}
+
+impl fmt::Display for KeymapParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self.kind {
+            KeymapParseErrorKind::UnknownKey => "unknown key",
+            KeymapParseErrorKind::UnknownEntryKind => "unknown entry kind",
+            KeymapParseErrorKind::WrongActionCount => "wrong number of actions",
+            KeymapParseErrorKind::InvalidString => "invalid string",
+            KeymapParseErrorKind::DuplicateKey => "key already mapped",
+        };
+
+        write!(f, "line {}: {}", self.line, msg)
+    }
+}
+
+enum Token<'a> {
+    Bare(&'a str),
+    Quoted(String),
+}
+
+fn parse_escape(chars: &mut core::str::CharIndices) -> Option<char> {
+    match chars.next()?.1 {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'e' => Some('\x1b'),
+        '\\' => Some('\\'),
+        '"' => Some('"'),
+        'x' => {
+            let hi = chars.next()?.1.to_digit(16)?;
+            let lo = chars.next()?.1.to_digit(16)?;
+
+            char::from_u32(hi * 16 + lo)
+        },
+        _ => None,
+    }
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token<'_>>, KeymapParseErrorKind> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices();
+
+    while let Some((start, ch)) = chars.next() {
+        if ch.is_whitespace() {
+            continue;
+        }
+
+        if ch == '"' {
+            let mut s = String::new();
+
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => s.push(parse_escape(&mut chars).ok_or(KeymapParseErrorKind::InvalidString)?),
+                    Some((_, ch)) => s.push(ch),
+                    None => return Err(KeymapParseErrorKind::InvalidString),
+                }
+            }
+
+            tokens.push(Token::Quoted(s));
+        } else {
+            let end = chars.find(|&(_, ch)| ch.is_whitespace()).map_or(line.len(), |(i, _)| i);
+            let token = &line[start..end];
+
+            if token.contains(['"', '\\']) {
+                return Err(KeymapParseErrorKind::InvalidString);
+            }
+
+            tokens.push(Token::Bare(token));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_action(token: &Token) -> KeyAction {
+    let s = match *token {
+        Token::Bare("none") => return KeyAction::None,
+        Token::Bare(s) => s,
+        Token::Quoted(ref s) => s,
+    };
+    let mut chars = s.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) => KeyAction::Char(ch),
+        (None, _) => KeyAction::None,
+        _ => KeyAction::Str(intern::intern(s)),
+    }
+}
+
+fn parse_key(name: &str) -> Option<CommonKeycode> {
+    (0..CommonKeycode::NUM_KEYCODES)
+        .filter_map(|k| CommonKeycode::try_from(k).ok())
+        .find(|k| format!("{:?}", k).eq_ignore_ascii_case(name))
+}
+
+fn parse_line(keymap: &mut KeycodeMap, tokens: &[Token]) -> Result<(), KeymapParseErrorKind> {
+    let (key, kind, actions) = match tokens {
+        [Token::Bare(key), Token::Bare(kind), actions @ ..] => (*key, *kind, actions),
+        [Token::Bare(_)] => return Err(KeymapParseErrorKind::UnknownEntryKind),
+        _ => return Err(KeymapParseErrorKind::UnknownKey),
+    };
+
+    let key = parse_key(key).ok_or(KeymapParseErrorKind::UnknownKey)?;
+    let entry = match (kind.to_ascii_lowercase().as_str(), actions) {
+        ("simple", [a]) => KeycodeMapEntry::Simple(parse_action(a)),
+        ("shift", [a, b]) => KeycodeMapEntry::Shift(parse_action(a), parse_action(b)),
+        ("shiftcaps", [a, b]) => KeycodeMapEntry::ShiftCaps(parse_action(a), parse_action(b)),
+        ("numlock", [a, b]) => KeycodeMapEntry::NumLock(parse_action(a), parse_action(b)),
+        ("simple" | "shift" | "shiftcaps" | "numlock", _) => return Err(KeymapParseErrorKind::WrongActionCount),
+        _ => return Err(KeymapParseErrorKind::UnknownEntryKind),
+    };
+
+    if *keymap.get_common(key) != KeycodeMapEntry::Simple(KeyAction::None) {
+        return Err(KeymapParseErrorKind::DuplicateKey);
+    }
+
+    keymap.set_common(key, entry);
+    Ok(())
+}
+
+/// Parses a keymap in the format described in the [module documentation](self), giving it the provided name.
+pub fn parse(name: &str, text: &str) -> Result<Box<KeycodeMap>, KeymapParseError> {
+    let mut keymap = Box::new(KeycodeMap::new(intern::intern(name)));
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        tokenize(line)
+            .and_then(|tokens| parse_line(&mut keymap, &tokens))
+            .map_err(|kind| KeymapParseError { line: i + 1, kind })?;
+    }
+
+    Ok(keymap)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::keymap::{KeyboardLockState, Keycode, ModifierState};
+
+    #[test_case]
+    fn test_parse() {
+        let keymap = parse(
+            "test",
+            "# comment\n\
+             A shiftcaps a A\n\
+             \n\
+             enter simple \"\\n\"\n\
+             UpArrow simple \"\\e[A\"\n\
+             Numpad8 numlock none 8\n",
+        )
+        .unwrap();
+
+        let lookup = |k, num_lock| {
+            let lock_state = KeyboardLockState {
+                num_lock,
+                ..KeyboardLockState::none()
+            };
+
+            keymap.get(Keycode::Common(k), lock_state, ModifierState::none()).copied()
+        };
+
+        assert_eq!("test", keymap.name());
+        assert_eq!(Some(KeyAction::Char('a')), lookup(CommonKeycode::A, false));
+        assert_eq!(Some(KeyAction::Char('\n')), lookup(CommonKeycode::Enter, false));
+        assert_eq!(Some(KeyAction::Str("\x1b[A")), lookup(CommonKeycode::UpArrow, false));
+        assert_eq!(Some(KeyAction::None), lookup(CommonKeycode::Numpad8, false));
+        assert_eq!(Some(KeyAction::Char('8')), lookup(CommonKeycode::Numpad8, true));
+        assert_eq!(Some(KeyAction::None), lookup(CommonKeycode::B, false));
+    }
+
+    #[test_case]
+    fn test_parse_errors() {
+        let error = |text| parse("test", text).err().map(|e| (e.line, e.kind));
+
+        assert_eq!(Some((2, KeymapParseErrorKind::UnknownKey)), error("A simple a\nNotAKey simple b"));
+        assert_eq!(Some((1, KeymapParseErrorKind::UnknownEntryKind)), error("A sideways a"));
+        assert_eq!(Some((1, KeymapParseErrorKind::WrongActionCount)), error("A shift a"));
+        assert_eq!(Some((1, KeymapParseErrorKind::InvalidString)), error("A simple \"a"));
+        assert_eq!(Some((2, KeymapParseErrorKind::DuplicateKey)), error("A simple a\na simple b"));
+    }
+}
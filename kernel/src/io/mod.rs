@@ -1,4 +1,5 @@
 pub mod ansi;
+pub mod block;
 pub mod dev;
 pub mod keymap;
 pub mod tty;
@@ -1,19 +1,46 @@
 use alloc::collections::VecDeque;
 use core::fmt;
 
+use bitflags::bitflags;
+
 use crate::sync::future::FutureWriter;
 use crate::sync::Future;
 use crate::util::ArrayDeque;
 
+bitflags! {
+    /// Optional features of the terminal on the other end of a TTY, which writers can use to decide what output to produce.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TtyCapabilities: u32 {
+        /// The terminal interprets ANSI SGR escape sequences to change the color of text.
+        const COLOR = 0x1;
+        /// The terminal interprets ANSI escape sequences to move the cursor and erase text, so output that has already been written can be
+        /// redrawn in place.
+        const CURSOR = 0x2;
+        /// The size of the terminal is known and can be queried using [`Tty::size`].
+        const SIZE = 0x4;
+    }
+}
+
 pub trait Tty: Send + Sync {
     unsafe fn write(&self, bytes: *const [u8]) -> Future<Result<(), ()>>;
     unsafe fn flush(&self) -> Future<Result<(), ()>>;
 
     unsafe fn read(&self, bytes: *mut [u8]) -> Future<Result<usize, ()>>;
 
+    /// Gets the size of the terminal as a (columns, rows) pair, or an error if the size is not known.
     fn size(&self) -> Result<(usize, usize), ()> {
         Err(())
     }
+
+    /// Waits for the size of the terminal to change, resolving to its new size. Resolves to an error if the TTY does not report size
+    /// changes or is disconnected.
+    fn size_changed(&self) -> Future<Result<(usize, usize), ()>> {
+        Future::done(Err(()))
+    }
+
+    fn capabilities(&self) -> TtyCapabilities {
+        TtyCapabilities::empty()
+    }
 }
 
 pub trait TtyExt: Tty {
@@ -79,6 +106,38 @@ impl<'a, T: Tty + ?Sized> TtyCharReader<'a, T> {
     }
 }
 
+/// Keeps track of the futures returned by [`Tty::size_changed`], for use by TTYs whose size can change.
+#[derive(Debug)]
+pub struct TtySizeWatchers {
+    writer: Option<FutureWriter<Result<(usize, usize), ()>>>,
+}
+
+impl TtySizeWatchers {
+    pub const fn new() -> Self {
+        Self { writer: None }
+    }
+
+    /// Gets a future that resolves the next time [`TtySizeWatchers::notify`] or [`TtySizeWatchers::cancel`] is called.
+    pub fn wait(&mut self) -> Future<Result<(usize, usize), ()>> {
+        self.writer.get_or_insert_with(FutureWriter::new).as_future()
+    }
+
+    /// Resolves all waiting futures with the new size of the TTY. Note that callbacks attached to the futures without using
+    /// [`Future::when_resolved_soft`] run before this returns, so they must not lock anything that the caller is holding.
+    pub fn notify(&mut self, size: (usize, usize)) {
+        if let Some(writer) = self.writer.take() {
+            writer.finish(Ok(size));
+        }
+    }
+
+    /// Resolves all waiting futures with an error, e.g. because the TTY is being disconnected.
+    pub fn cancel(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.finish(Err(()));
+        }
+    }
+}
+
 #[derive(Debug)]
 struct TtyReadRequest {
     future: FutureWriter<Result<usize, ()>>,
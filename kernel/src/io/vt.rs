@@ -1,17 +1,22 @@
 use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::fmt::Debug;
+use core::fmt::{self, Debug};
+use core::time::Duration;
 
-use dyn_dyn::dyn_dyn_impl;
+use dyn_dyn::{dyn_dyn_cast, dyn_dyn_impl};
 
 use super::dev::hub::{DeviceHub, DeviceHubLockedError};
 use super::dev::kbd::{KeyPress, Keyboard};
+use super::dev::mouse::Mouse;
+use super::dev::observer::{self, DeviceTreeEvent};
 use super::dev::{Device, DeviceNode};
-use super::tty::TtyReadQueue;
+use super::tty::{TtyReadQueue, TtySizeWatchers};
 use crate::io::ansi::{AnsiColor, AnsiParser, AnsiParserAction, AnsiParserSgrAction};
 use crate::io::dev::{device_root, DeviceRef};
-use crate::io::tty::Tty;
+use crate::io::tty::{Tty, TtyCapabilities};
+use crate::log;
+use crate::sched::timer;
 use crate::sync::uninterruptible::UninterruptibleSpinlockGuard;
 use crate::sync::{Future, UninterruptibleSpinlock};
 use crate::util::OneShotManualInit;
@@ -35,6 +40,7 @@ pub struct VirtualTerminalInternals {
     pub cursor_hidden: bool,
     id: usize,
     read_queue: TtyReadQueue<64>,
+    size_watchers: TtySizeWatchers,
 }
 
 impl VirtualTerminalInternals {
@@ -114,6 +120,38 @@ impl VirtualTerminalInternals {
         };
     }
 
+    /// Changes the size of the terminal, keeping as much of its contents as fits. If the terminal gets shorter, lines are dropped from the
+    /// top so that the line the cursor is on stays visible.
+    fn resize(&mut self, width: usize, height: usize) {
+        assert!(width > 0);
+        assert!(height > 0);
+        assert!(width.checked_mul(height).is_some());
+
+        if (width, height) == self.size {
+            return;
+        }
+
+        let blank = VTChar {
+            ch: ' ',
+            fg_color: AnsiColor::White,
+            bg_color: AnsiColor::Black,
+        };
+        let mut buf = vec![blank; width * height].into_boxed_slice();
+        let first_line = (self.cursor_pos.1 + 1).saturating_sub(height);
+
+        for y in 0..(self.size.1 - first_line).min(height) {
+            for x in 0..self.size.0.min(width) {
+                buf[y * width + x] = self.buf[self.off(x, first_line + y)];
+            }
+        }
+
+        self.buf = buf;
+        self.buf_line = 0;
+        self.size = (width, height);
+        self.cursor_pos = (self.cursor_pos.0.min(width - 1), self.cursor_pos.1 - first_line);
+        self.size_watchers.notify(self.size);
+    }
+
     fn write_char(&mut self, ch: char) {
         match ch {
             '\n' => {
@@ -217,10 +255,22 @@ impl Tty for VirtualTerminal {
     fn size(&self) -> Result<(usize, usize), ()> {
         Ok(self.0.lock().size)
     }
+
+    fn size_changed(&self) -> Future<Result<(usize, usize), ()>> {
+        self.0.lock().size_watchers.wait()
+    }
+
+    fn capabilities(&self) -> TtyCapabilities {
+        TtyCapabilities::COLOR | TtyCapabilities::CURSOR | TtyCapabilities::SIZE
+    }
 }
 
 #[dyn_dyn_impl(Tty)]
-impl Device for VirtualTerminal {}
+impl Device for VirtualTerminal {
+    unsafe fn on_disconnected(&self) {
+        self.0.lock().size_watchers.cancel();
+    }
+}
 
 impl VirtualTerminal {
     pub fn new(id: usize, width: usize, height: usize) -> VirtualTerminal {
@@ -247,9 +297,19 @@ impl VirtualTerminal {
             cursor_hidden: false,
             id,
             read_queue: TtyReadQueue::new(),
+            size_watchers: TtySizeWatchers::new(),
         }))
     }
 
+    /// Changes the size of the terminal, notifying anything waiting on [`Tty::size_changed`]. Terminals are also resized automatically to
+    /// fit the display they are switched to using [`VirtualTerminalManager::switch_display`].
+    pub fn resize(&self, width: usize, height: usize) {
+        let mut vt = self.0.lock();
+
+        vt.resize(width, height);
+        vt.redraw();
+    }
+
     fn handle_key_pressed(&self, keypress: KeyPress) {
         let mut vt = self.0.lock();
 
@@ -259,17 +319,99 @@ impl VirtualTerminal {
     }
 }
 
+/// The power state of a display, from fully on to fully off. Displays that don't support power management are always on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayPowerState {
+    On,
+    /// The display shows nothing, but can be turned back on quickly.
+    Standby,
+    /// The display shows nothing and its output signal is turned off, so that a monitor connected to it can enter its own power saving
+    /// mode.
+    Off,
+}
+
+impl fmt::Display for DisplayPowerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisplayPowerState::On => write!(f, "on"),
+            DisplayPowerState::Standby => write!(f, "standby"),
+            DisplayPowerState::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// An error that can occur when changing the power state or brightness of a display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayPowerError {
+    /// The display does not support the requested power state or does not have adjustable brightness.
+    Unsupported,
+    /// The requested brightness level is greater than the display's maximum brightness level.
+    InvalidLevel,
+}
+
+impl fmt::Display for DisplayPowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisplayPowerError::Unsupported => write!(f, "not supported by display"),
+            DisplayPowerError::InvalidLevel => write!(f, "invalid brightness level"),
+        }
+    }
+}
+
 pub trait TerminalDisplay: Device {
     fn size(&self) -> (usize, usize);
     fn clear(&self);
     fn redraw(&self, vt: &VirtualTerminalInternals);
+
+    fn power_state(&self) -> DisplayPowerState {
+        DisplayPowerState::On
+    }
+
+    fn set_power_state(&self, state: DisplayPowerState) -> Result<(), DisplayPowerError> {
+        if state == DisplayPowerState::On {
+            Ok(())
+        } else {
+            Err(DisplayPowerError::Unsupported)
+        }
+    }
+
+    /// Gets the current and maximum backlight brightness levels of the display, or [`None`] if the hardware does not expose a way to
+    /// control its brightness.
+    fn brightness(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    fn set_brightness(&self, _level: u32) -> Result<(), DisplayPowerError> {
+        Err(DisplayPowerError::Unsupported)
+    }
+}
+
+#[derive(Debug)]
+struct AttachedKeyboard {
+    keyboard: DeviceRef<dyn Keyboard>,
+    enabled: bool,
 }
 
 #[derive(Debug)]
 struct DisplayInfo {
     display: DeviceRef<dyn TerminalDisplay>,
-    keyboard: Option<DeviceRef<dyn Keyboard>>,
+    keyboards: Vec<AttachedKeyboard>,
     terminal_id: usize,
+    blanked: bool,
+}
+
+impl DisplayInfo {
+    fn find_keyboard(&mut self, keyboard: &DeviceRef<dyn Keyboard>) -> Option<&mut AttachedKeyboard> {
+        self.keyboards.iter_mut().find(|k| is_same_device(&k.keyboard, keyboard))
+    }
+}
+
+/// Information about a keyboard attached to a display, as returned by [`VirtualTerminalManager::keyboards`].
+#[derive(Debug, Clone)]
+pub struct KeyboardAttachment {
+    pub keyboard: DeviceRef<dyn Keyboard>,
+    pub display_id: usize,
+    pub enabled: bool,
 }
 
 #[derive(Debug)]
@@ -277,6 +419,10 @@ struct VirtualTerminalManagerInternals {
     this: Option<DeviceRef<VirtualTerminalManager>>,
     terminals: Vec<DeviceRef<VirtualTerminal>>,
     displays: Vec<DisplayInfo>,
+    mice: Vec<DeviceRef<dyn Mouse>>,
+    last_activity: Duration,
+    blank_timeout: Option<Duration>,
+    blank_generation: u64,
 }
 
 impl VirtualTerminalManagerInternals {
@@ -293,6 +439,7 @@ impl VirtualTerminalManagerInternals {
         );
 
         self.displays[0].display.dev().redraw(&self.terminals[0].dev().0.lock());
+        self.schedule_blank();
     }
 
     unsafe fn on_disconnected(&mut self) {
@@ -303,6 +450,47 @@ impl VirtualTerminalManagerInternals {
         self.this = None;
         self.terminals = vec![];
         self.displays = vec![];
+        self.mice = vec![];
+    }
+
+    /// Records that input was just received, turning any displays that were blanked for being idle back on.
+    fn note_activity(&mut self) {
+        self.last_activity = timer::now();
+
+        // While the displays are lit, the pending idle check notices the new activity by itself
+        if self.unblank() {
+            self.schedule_blank();
+        }
+    }
+
+    /// Arranges for [`VirtualTerminalManager::check_idle`] to be called once the blanking timeout has elapsed since the last input, replacing
+    /// any check that was previously scheduled.
+    fn schedule_blank(&mut self) {
+        self.blank_generation += 1;
+
+        let (Some(this), Some(timeout)) = (self.this.clone(), self.blank_timeout) else {
+            return;
+        };
+        let generation = self.blank_generation;
+
+        timer::at(self.last_activity + timeout).when_resolved_soft(move |()| {
+            this.dev().check_idle(generation);
+        });
+    }
+
+    fn unblank(&mut self) -> bool {
+        let mut any_unblanked = false;
+
+        for info in self.displays.iter_mut().filter(|info| info.blanked) {
+            info.blanked = false;
+            any_unblanked = true;
+
+            if let Err(err) = info.display.dev().set_power_state(DisplayPowerState::On) {
+                log!(Warning, "vt", "Failed to unblank {}: {}", info.display.full_name(), err);
+            }
+        }
+
+        any_unblanked
     }
 
     fn for_terminals(&self, f: &mut dyn FnMut(&DeviceRef<dyn Device>) -> bool) -> bool {
@@ -330,9 +518,14 @@ impl VirtualTerminalManager {
                 terminals: vec![],
                 displays: vec![DisplayInfo {
                     display: primary_display,
-                    keyboard: None,
+                    keyboards: vec![],
                     terminal_id: 0,
+                    blanked: false,
                 }],
+                mice: vec![],
+                last_activity: timer::now(),
+                blank_timeout: None,
+                blank_generation: 0,
             }),
         }
     }
@@ -346,11 +539,15 @@ impl VirtualTerminalManager {
 
         if terminal_id < vtmgr.terminals.len() {
             let vtmgr = &mut *vtmgr;
-            let vt = vtmgr.terminals[terminal_id].dev().0.lock();
+            let mut vt = vtmgr.terminals[terminal_id].dev().0.lock();
 
             if display_id < vtmgr.displays.len() {
+                let display = vtmgr.displays[display_id].display.dev();
+                let (width, height) = display.size();
+
                 vtmgr.displays[display_id].terminal_id = terminal_id;
-                vtmgr.displays[display_id].display.dev().redraw(&vt);
+                vt.resize(width, height);
+                display.redraw(&vt);
                 true
             } else {
                 false
@@ -360,35 +557,291 @@ impl VirtualTerminalManager {
         }
     }
 
-    fn handle_key_pressed(&self, display_id: usize, keypress: KeyPress) {
+    fn handle_key_pressed(&self, display_id: usize, keyboard: &DeviceRef<dyn Keyboard>, keypress: KeyPress) {
         let mut vtmgr = self.internal.lock();
 
-        let vt = &vtmgr.terminals[vtmgr.displays[display_id].terminal_id];
+        // The keyboard may have been detached from this display while the key press was being delivered
+        let enabled = match vtmgr.displays[display_id].find_keyboard(keyboard) {
+            Some(attached) => attached.enabled,
+            None => return,
+        };
 
-        vt.dev().handle_key_pressed(keypress);
-        self.listen_for_keypress(&mut vtmgr, display_id);
-    }
+        if enabled {
+            vtmgr.note_activity();
 
-    fn listen_for_keypress(&self, vtmgr: &mut UninterruptibleSpinlockGuard<VirtualTerminalManagerInternals>, display_id: usize) {
-        if let Some(ref keyboard) = vtmgr.displays[display_id].keyboard {
-            let this = vtmgr.this.clone().unwrap();
+            let vt = &vtmgr.terminals[vtmgr.displays[display_id].terminal_id];
 
-            keyboard.dev().next_key().when_resolved_soft(move |keypress| match keypress {
-                Ok(keypress) => {
-                    this.dev().handle_key_pressed(display_id, keypress);
-                },
-                Err(_) => unimplemented!(),
-            });
+            vt.dev().handle_key_pressed(keypress);
         }
+
+        self.listen_for_keypress(&mut vtmgr, display_id, keyboard);
     }
 
+    fn listen_for_keypress(
+        &self,
+        vtmgr: &mut UninterruptibleSpinlockGuard<VirtualTerminalManagerInternals>,
+        display_id: usize,
+        keyboard: &DeviceRef<dyn Keyboard>,
+    ) {
+        let this = vtmgr.this.clone().unwrap();
+        let keyboard_for_callback = keyboard.clone();
+
+        keyboard.dev().next_key().when_resolved_soft(move |keypress| match keypress {
+            Ok(keypress) => {
+                this.dev().handle_key_pressed(display_id, &keyboard_for_callback, keypress);
+            },
+            Err(_) if !keyboard_for_callback.is_connected() => {
+                // The keyboard is gone, so it shouldn't keep showing up as being attached to this display
+                if this.dev().detach_keyboard(&keyboard_for_callback).is_some() {
+                    log!(Info, "vt", "Detached disconnected keyboard {}", keyboard_for_callback.full_name());
+                }
+            },
+            Err(_) => {},
+        });
+    }
+
+    /// Attaches a keyboard to a display, so that key presses from it are sent to the terminal shown on that display. Any number of
+    /// keyboards can be attached to the same display, in which case key presses from all of them are delivered in the order they arrive.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the keyboard is already attached to a display.
     pub fn attach_keyboard(&self, display_id: usize, keyboard: DeviceRef<dyn Keyboard>) {
         let mut vtmgr = self.internal.lock();
 
-        assert!(vtmgr.displays[display_id].keyboard.is_none());
-        vtmgr.displays[display_id].keyboard = Some(keyboard);
-        self.listen_for_keypress(&mut vtmgr, display_id);
+        assert!(vtmgr.displays.iter_mut().all(|d| d.find_keyboard(&keyboard).is_none()));
+        vtmgr.displays[display_id].keyboards.push(AttachedKeyboard {
+            keyboard: keyboard.clone(),
+            enabled: true,
+        });
+        self.listen_for_keypress(&mut vtmgr, display_id, &keyboard);
     }
+
+    /// Detaches the provided keyboard from whichever display it is attached to, returning the ID of that display. Returns [`None`] if the
+    /// keyboard was not attached to any display.
+    pub fn detach_keyboard(&self, keyboard: &DeviceRef<dyn Keyboard>) -> Option<usize> {
+        let mut vtmgr = self.internal.lock();
+        let display_id = vtmgr.displays.iter_mut().position(|d| d.find_keyboard(keyboard).is_some())?;
+
+        vtmgr.displays[display_id]
+            .keyboards
+            .retain(|k| !is_same_device(&k.keyboard, keyboard));
+        Some(display_id)
+    }
+
+    fn handle_mouse_event(&self, mouse: &DeviceRef<dyn Mouse>) {
+        let mut vtmgr = self.internal.lock();
+
+        // The mouse may have been detached while the event was being delivered
+        if !vtmgr.mice.iter().any(|m| is_same_device(m, mouse)) {
+            return;
+        }
+
+        vtmgr.note_activity();
+        self.listen_for_mouse_event(&mut vtmgr, mouse);
+    }
+
+    fn listen_for_mouse_event(
+        &self,
+        vtmgr: &mut UninterruptibleSpinlockGuard<VirtualTerminalManagerInternals>,
+        mouse: &DeviceRef<dyn Mouse>,
+    ) {
+        let this = vtmgr.this.clone().unwrap();
+        let mouse_for_callback = mouse.clone();
+
+        mouse.dev().next_event().when_resolved_soft(move |event| match event {
+            Ok(_) => {
+                this.dev().handle_mouse_event(&mouse_for_callback);
+            },
+            Err(_) if !mouse_for_callback.is_connected() => {
+                if this.dev().detach_mouse(&mouse_for_callback) {
+                    log!(Info, "vt", "Detached disconnected mouse {}", mouse_for_callback.full_name());
+                }
+            },
+            Err(_) => {},
+        });
+    }
+
+    /// Attaches a mouse to the terminals. Terminals have no use for pointer input yet, so mice only count as activity that keeps the
+    /// displays from being blanked.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the mouse is already attached.
+    pub fn attach_mouse(&self, mouse: DeviceRef<dyn Mouse>) {
+        let mut vtmgr = self.internal.lock();
+
+        assert!(vtmgr.mice.iter().all(|m| !is_same_device(m, &mouse)));
+        vtmgr.mice.push(mouse.clone());
+        self.listen_for_mouse_event(&mut vtmgr, &mouse);
+    }
+
+    /// Detaches the provided mouse from the terminals. Returns `false` if the mouse was not attached.
+    pub fn detach_mouse(&self, mouse: &DeviceRef<dyn Mouse>) -> bool {
+        let mut vtmgr = self.internal.lock();
+        let old_len = vtmgr.mice.len();
+
+        vtmgr.mice.retain(|m| !is_same_device(m, mouse));
+        vtmgr.mice.len() != old_len
+    }
+
+    /// Gets all mice that are attached to the terminals.
+    pub fn mice(&self) -> Vec<DeviceRef<dyn Mouse>> {
+        self.internal.lock().mice.clone()
+    }
+
+    /// Enables or disables delivery of key presses from the provided keyboard. Key presses from a disabled keyboard are discarded, but it
+    /// stays attached to its display so that it can be enabled again later. Returns `false` if the keyboard is not attached to any display.
+    pub fn set_keyboard_enabled(&self, keyboard: &DeviceRef<dyn Keyboard>, enabled: bool) -> bool {
+        let mut vtmgr = self.internal.lock();
+
+        match vtmgr.displays.iter_mut().find_map(|d| d.find_keyboard(keyboard)) {
+            Some(attached) => {
+                attached.enabled = enabled;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Gets all keyboards that are attached to a display.
+    pub fn keyboards(&self) -> Vec<KeyboardAttachment> {
+        let vtmgr = self.internal.lock();
+
+        vtmgr
+            .displays
+            .iter()
+            .enumerate()
+            .flat_map(|(display_id, d)| {
+                d.keyboards.iter().map(move |k| KeyboardAttachment {
+                    keyboard: k.keyboard.clone(),
+                    display_id,
+                    enabled: k.enabled,
+                })
+            })
+            .collect()
+    }
+
+    /// Gets the number of displays that terminals can be shown on. Displays are numbered consecutively starting from zero.
+    pub fn num_displays(&self) -> usize {
+        self.internal.lock().displays.len()
+    }
+
+    pub fn get_display(&self, display_id: usize) -> Option<DeviceRef<dyn TerminalDisplay>> {
+        self.internal.lock().displays.get(display_id).map(|d| d.display.clone())
+    }
+
+    /// Gets the ID of the terminal currently shown on the provided display and whether the display has been blanked for being idle.
+    pub fn display_state(&self, display_id: usize) -> Option<(usize, bool)> {
+        self.internal.lock().displays.get(display_id).map(|d| (d.terminal_id, d.blanked))
+    }
+
+    /// Changes the power state of a display. A display that was blanked for being idle is no longer considered blanked afterwards, so it
+    /// won't be turned back on by the next input.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if there is no display with the provided ID.
+    pub fn set_display_power(&self, display_id: usize, state: DisplayPowerState) -> Result<(), DisplayPowerError> {
+        let mut vtmgr = self.internal.lock();
+        let info = &mut vtmgr.displays[display_id];
+
+        info.display.dev().set_power_state(state)?;
+        info.blanked = false;
+        Ok(())
+    }
+
+    /// Gets how long displays are left on without any input before they are put into standby, or [`None`] if idle displays are never
+    /// blanked.
+    pub fn blank_timeout(&self) -> Option<Duration> {
+        self.internal.lock().blank_timeout
+    }
+
+    /// Sets how long displays are left on without any input before they are put into standby. Blanked displays are turned back on by the
+    /// next key press from an enabled keyboard or the next report from an attached mouse.
+    pub fn set_blank_timeout(&self, timeout: Option<Duration>) {
+        let mut vtmgr = self.internal.lock();
+
+        vtmgr.blank_timeout = timeout;
+        vtmgr.schedule_blank();
+    }
+
+    /// Gets the time (as returned by [`timer::now`]) at which input was last received from an enabled keyboard or an attached mouse.
+    pub fn last_activity(&self) -> Duration {
+        self.internal.lock().last_activity
+    }
+
+    fn check_idle(&self, generation: u64) {
+        let mut vtmgr = self.internal.lock();
+
+        // Another check has been scheduled since this one was, e.g. because the timeout was changed
+        if generation != vtmgr.blank_generation {
+            return;
+        }
+
+        let Some(timeout) = vtmgr.blank_timeout else {
+            return;
+        };
+
+        if timer::now() < vtmgr.last_activity + timeout {
+            vtmgr.schedule_blank();
+            return;
+        }
+
+        for info in vtmgr.displays.iter_mut() {
+            if info.blanked || info.display.dev().power_state() != DisplayPowerState::On {
+                continue;
+            }
+
+            match info.display.dev().set_power_state(DisplayPowerState::Standby) {
+                Ok(()) => {
+                    log!(Debug, "vt", "Blanking idle display {}", info.display.full_name());
+                    info.blanked = true;
+                },
+                Err(err) => {
+                    log!(Debug, "vt", "Failed to blank idle display {}: {}", info.display.full_name(), err);
+                },
+            }
+        }
+    }
+
+    /// Gets the ID of the first display that does not currently have a keyboard attached to it.
+    pub fn first_display_without_keyboard(&self) -> Option<usize> {
+        self.internal.lock().displays.iter().position(|d| d.keyboards.is_empty())
+    }
+
+    /// Attaches newly connected keyboards and mice to the terminals, and detaches them again once they are disconnected. New keyboards are
+    /// attached to the first display that doesn't have a keyboard yet.
+    fn handle_device_event(&self, event: &DeviceTreeEvent) {
+        match *event {
+            DeviceTreeEvent::Connected(ref dev) => {
+                if let Ok(keyboard) = dyn_dyn_cast!(move Device => Keyboard, dev.clone()) {
+                    let display_id = self.first_display_without_keyboard().unwrap_or(0);
+
+                    log!(Info, "vt", "Attaching keyboard {} to display {}", keyboard.full_name(), display_id);
+                    self.attach_keyboard(display_id, keyboard);
+                } else if let Ok(mouse) = dyn_dyn_cast!(move Device => Mouse, dev.clone()) {
+                    log!(Info, "vt", "Attaching mouse {}", mouse.full_name());
+                    self.attach_mouse(mouse);
+                }
+            },
+            DeviceTreeEvent::Disconnected(ref dev) => {
+                if let Ok(keyboard) = dyn_dyn_cast!(move Device => Keyboard, dev.clone()) {
+                    if self.detach_keyboard(&keyboard).is_some() {
+                        log!(Info, "vt", "Detached disconnected keyboard {}", keyboard.full_name());
+                    }
+                } else if let Ok(mouse) = dyn_dyn_cast!(move Device => Mouse, dev.clone()) {
+                    if self.detach_mouse(&mouse) {
+                        log!(Info, "vt", "Detached disconnected mouse {}", mouse.full_name());
+                    }
+                }
+            },
+        }
+    }
+}
+
+fn is_same_device<T: ?Sized>(a: &DeviceRef<T>, b: &DeviceRef<T>) -> bool {
+    core::ptr::eq(&**a as *const _ as *const (), &**b as *const _ as *const ())
 }
 
 impl DeviceHub for VirtualTerminalManager {
@@ -423,8 +876,53 @@ pub unsafe fn init(primary_display: DeviceRef<dyn TerminalDisplay>) {
             .dev()
             .add_device(DeviceNode::new(Box::from("vtmgr"), VirtualTerminalManager::new(primary_display))),
     );
+
+    observer::add_observer(
+        "vt",
+        Box::new(|event| {
+            get_global_manager().dev().handle_device_event(event);
+        }),
+    );
 }
 
 pub fn get_global_manager() -> &'static DeviceRef<VirtualTerminalManager> {
     VT_MANAGER.get()
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+
+    use super::*;
+
+    fn write(vt: &mut VirtualTerminalInternals, s: &str) {
+        for b in s.bytes() {
+            vt.write_byte(b);
+        }
+    }
+
+    fn line(vt: &VirtualTerminalInternals, y: usize) -> String {
+        (0..vt.size.0).map(|x| vt.buf[vt.off(x, y)].ch).collect()
+    }
+
+    #[test_case]
+    fn test_resize() {
+        let vt = VirtualTerminal::new(usize::MAX, 4, 3);
+        let mut vt = vt.0.lock();
+        let size_changed = vt.size_watchers.wait();
+
+        write(&mut vt, "ab\ncd\nef");
+        vt.resize(3, 2);
+
+        assert_eq!(Ok((3, 2)), size_changed.unwrap_blocking());
+        assert_eq!("cd ", line(&vt, 0));
+        assert_eq!("ef ", line(&vt, 1));
+        assert_eq!((2, 1), vt.cursor_pos);
+
+        vt.resize(5, 3);
+
+        assert_eq!("cd   ", line(&vt, 0));
+        assert_eq!("     ", line(&vt, 2));
+        assert_eq!((2, 1), vt.cursor_pos);
+    }
+}
@@ -12,6 +12,7 @@
 #![feature(maybe_uninit_uninit_array)]
 #![feature(naked_functions)]
 #![feature(negative_impls)]
+#![cfg_attr(feature = "kasan", feature(no_sanitize))]
 #![feature(ptr_metadata)]
 #![feature(slice_ptr_get)]
 #![feature(sync_unsafe_cell)]
@@ -39,25 +40,35 @@ use bootloader::BootInfo;
 pub mod log;
 
 pub mod arch;
+pub mod boottime;
+pub mod build_info;
 pub mod cmd;
+pub mod compress;
+pub mod initcall;
 pub mod io;
 pub mod mem;
 pub mod options;
 pub mod panic;
 pub mod sched;
+pub mod shutdown;
 pub mod sync;
 pub mod test_util;
 pub mod util;
 
 pub unsafe fn init_phase_1(boot_info: &'static BootInfo) {
+    boottime::start();
     mem::early::init();
     options::init();
     log::init();
+    boottime::mark("early init");
+    initcall::run(initcall::InitLevel::Early);
 
     arch::init_phase_1(boot_info);
+    boottime::mark("arch phase 1");
 
     mem::frame::init(boot_info);
     log::add_tty(io::vt::get_global_manager().dev().get_terminal(0).unwrap());
+    boottime::mark("frame allocator");
 
     arch::interrupt::enable();
     sched::run_soft_interrupts();
@@ -68,7 +79,7 @@ pub unsafe fn init_phase_2() {
     use crate::io::dev::log_device_tree;
     use crate::mem::frame::FrameAllocator;
 
-    log!(Info, "kernel", "Booting HydroxOS v{}", env!("CARGO_PKG_VERSION"));
+    log!(Info, "kernel", "Booting {}", build_info::summary());
     log!(
         Debug,
         "kernel",
@@ -78,18 +89,25 @@ pub unsafe fn init_phase_2() {
     );
 
     arch::init_phase_2();
+    boottime::mark("arch phase 2");
+    initcall::run(initcall::InitLevel::Arch);
 
-    let (early_used, early_total) = mem::early::usage();
-    log!(
-        Debug,
-        "kernel",
-        "Early allocation pool usage: {}KiB/{}KiB",
-        early_used / 1024,
-        early_total / 1024
-    );
+    mem::image::log_report();
 
     sched::init();
+    boottime::mark("scheduler");
+    initcall::run(initcall::InitLevel::Subsys);
+
+    let mut probes = io::dev::probe::ProbeSet::new();
+
+    arch::add_device_probes(&mut probes);
+    probes.run();
+    boottime::mark("device probes");
+    initcall::run(initcall::InitLevel::Device);
+
     log_device_tree();
+    initcall::run(initcall::InitLevel::Late);
+    boottime::finish();
 }
 
 #[cfg(test)]
@@ -1,19 +1,39 @@
-use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::collections::VecDeque;
 use alloc::string::String;
-use alloc::vec;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::ptr;
+use alloc::{format, vec};
+use core::cell::{Cell, RefCell};
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use core::{fmt, mem, ptr};
 
-use crate::io::ansi::AnsiColor;
+use crate::io::ansi::{AnsiColor, AnsiParserSgrAction};
 use crate::io::dev::DeviceRef;
-use crate::io::tty::Tty;
+use crate::io::tty::{Tty, TtyCapabilities};
 use crate::options::{self, InvalidOptionValue, KernelOptionParseable};
-use crate::sched::enqueue_soft_interrupt;
-use crate::sync::{Future, UninterruptibleSpinlock};
+use crate::sched::{enqueue_soft_interrupt, timer};
+use crate::sync::UninterruptibleSpinlock;
 use crate::util::OneShotManualInit;
 
-static OUT_TTY: UninterruptibleSpinlock<Vec<DeviceRef<dyn Tty>>> = UninterruptibleSpinlock::new(vec![]);
+static OUT_TTY: UninterruptibleSpinlock<Vec<LogSink>> = UninterruptibleSpinlock::new(vec![]);
 static LOG_LEVELS: OneShotManualInit<LogLevelOptions> = OneShotManualInit::uninit();
+static LOG_THEME: OneShotManualInit<LogTheme> = OneShotManualInit::uninit();
+static RATE_LIMITER: UninterruptibleSpinlock<RateLimiter> =
+    UninterruptibleSpinlock::new(RateLimiter::new(DEFAULT_RATE_LIMIT_BURST, DEFAULT_RATE_LIMIT_PER_SEC));
+
+const DEFAULT_RATE_LIMIT_BURST: u32 = 50;
+const DEFAULT_RATE_LIMIT_PER_SEC: u32 = 20;
+
+/// The default maximum amount of memory, in bytes, that messages waiting to be written to a single sink can take up.
+const DEFAULT_SINK_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The maximum number of bytes that are written to a sink at once. Any further messages are written once that write completes.
+const MAX_SINK_WRITE_LEN: usize = 4096;
+
+static SINK_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_SINK_BUFFER_SIZE);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogLevel {
@@ -26,6 +46,15 @@ pub enum LogLevel {
 }
 
 impl LogLevel {
+    pub const ALL: [LogLevel; 6] = [
+        LogLevel::Critical,
+        LogLevel::Error,
+        LogLevel::Warning,
+        LogLevel::Notice,
+        LogLevel::Info,
+        LogLevel::Debug,
+    ];
+
     pub fn name(self) -> &'static str {
         match self {
             LogLevel::Critical => "CRIT",
@@ -37,6 +66,7 @@ impl LogLevel {
         }
     }
 
+    /// Gets the color used to highlight the severity of messages at this level unless overridden using the `logcolor.<level>` option.
     pub fn color(self) -> AnsiColor {
         match self {
             LogLevel::Critical => AnsiColor::Red,
@@ -75,6 +105,469 @@ impl LogLevelOptions {
     }
 }
 
+/// The colors that module tags are given when no color has been configured for them. Colors used by default for severities are left out
+/// so that tags can't be mistaken for severities.
+const MODULE_TAG_PALETTE: [AnsiColor; 8] = [
+    AnsiColor::Green,
+    AnsiColor::Blue,
+    AnsiColor::Magenta,
+    AnsiColor::Brown,
+    AnsiColor::LightGreen,
+    AnsiColor::LightBlue,
+    AnsiColor::Pink,
+    AnsiColor::LightCyan,
+];
+
+/// Colors used to highlight parts of log messages written as text to terminals that support color.
+///
+/// The theme is configured using boot options: `log_color=0` disables colors entirely, `logcolor.<level>=<color>` changes the color used
+/// for a severity (e.g. `logcolor.debug=darkgray`) and `logtag.<module>=<color>` gives a module's name a fixed color. Modules without a
+/// configured color are assigned one from a small palette based on their name, so that the same module always gets the same color,
+/// unless `log_auto_tags=0` is given.
+struct LogTheme {
+    enabled: bool,
+    level_colors: [AnsiColor; 6],
+    module_colors: BTreeMap<&'static str, AnsiColor>,
+    auto_module_colors: bool,
+}
+
+impl LogTheme {
+    fn new() -> LogTheme {
+        LogTheme {
+            enabled: true,
+            level_colors: LogLevel::ALL.map(LogLevel::color),
+            module_colors: BTreeMap::new(),
+            auto_module_colors: true,
+        }
+    }
+
+    fn level_color(&self, level: LogLevel) -> AnsiColor {
+        self.level_colors[level as usize]
+    }
+
+    fn module_color(&self, module: &str) -> Option<AnsiColor> {
+        if let Some(&color) = self.module_colors.get(module) {
+            Some(color)
+        } else if self.auto_module_colors {
+            // FNV-1a, which is good enough to spread short module names across the palette
+            let hash = module
+                .bytes()
+                .fold(0x811c9dc5_u32, |hash, b| (hash ^ b as u32).wrapping_mul(0x01000193));
+
+            Some(MODULE_TAG_PALETTE[hash as usize % MODULE_TAG_PALETTE.len()])
+        } else {
+            None
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: u32,
+    last_refill: Duration,
+    suppressed: usize,
+}
+
+/// Limits the rate at which messages can be logged to keep a misbehaving module from flooding the console.
+///
+/// Each module has its own token bucket, which allows short bursts of messages while limiting the sustained rate at which they can be
+/// logged. Messages that are identical to the previous message are folded together into a single "last message repeated" line instead of
+/// consuming tokens. Note that this line is only written once a different message is logged.
+struct RateLimiter {
+    burst: u32,
+    per_sec: u32,
+    buckets: BTreeMap<&'static str, TokenBucket>,
+    last_msg: Option<(LogLevel, &'static str, String)>,
+    last_repeats: usize,
+}
+
+impl RateLimiter {
+    const fn new(burst: u32, per_sec: u32) -> RateLimiter {
+        RateLimiter {
+            burst,
+            per_sec,
+            buckets: BTreeMap::new(),
+            last_msg: None,
+            last_repeats: 0,
+        }
+    }
+
+    fn take_token(&mut self, module: &'static str, now: Duration) -> Result<usize, ()> {
+        let burst = self.burst;
+        let bucket = self.buckets.entry(module).or_insert(TokenBucket {
+            tokens: burst,
+            last_refill: now,
+            suppressed: 0,
+        });
+
+        // Only advance the refill time by the time actually accounted for by new tokens, so that messages logged more often than tokens
+        // are refilled don't prevent the bucket from ever refilling
+        let period = Duration::from_secs(1) / self.per_sec;
+        let new_tokens = (now.saturating_sub(bucket.last_refill).as_nanos() / period.as_nanos()) as u32;
+
+        if new_tokens != 0 {
+            bucket.tokens = bucket.tokens.saturating_add(new_tokens).min(burst);
+            bucket.last_refill = if bucket.tokens == burst {
+                now
+            } else {
+                bucket.last_refill + period * new_tokens
+            };
+        }
+
+        if bucket.tokens != 0 {
+            bucket.tokens -= 1;
+            Ok(core::mem::take(&mut bucket.suppressed))
+        } else {
+            bucket.suppressed += 1;
+            Err(())
+        }
+    }
+
+    /// Decides what should be written when a message is logged, returning the records to write in order.
+    fn filter(&mut self, record: LogRecord) -> Vec<LogRecord> {
+        let mut out = vec![];
+        let now = record.timestamp;
+
+        if matches!(self.last_msg, Some((lvl, module, ref text)) if lvl == record.level && module == record.module && *text == record.text)
+        {
+            self.last_repeats += 1;
+            return out;
+        }
+
+        if self.last_repeats != 0 {
+            let repeats = core::mem::take(&mut self.last_repeats);
+
+            out.push(crate::log_record!(LogLevel::Notice, "log", "last message repeated {} times", repeats).at(now));
+        }
+
+        self.last_msg = Some((record.level, record.module, record.text.clone()));
+
+        // Critical messages are never rate limited, since they may be the only indication of why the system is about to fail
+        if self.per_sec == 0 || record.level == LogLevel::Critical {
+            out.push(record);
+        } else if let Ok(suppressed) = self.take_token(record.module, now) {
+            if suppressed != 0 {
+                out.push(
+                    crate::log_record!(
+                        LogLevel::Warning,
+                        "log",
+                        "{} messages from {} were suppressed",
+                        suppressed,
+                        record.module
+                    )
+                    .at(now),
+                );
+            }
+
+            out.push(record);
+        }
+
+        out
+    }
+}
+
+/// Tracks the position of each argument in the formatted text of a log message as it is being formatted.
+#[doc(hidden)]
+#[derive(Default)]
+pub struct LogArgPositions {
+    len: Cell<usize>,
+    args: RefCell<Vec<Range<usize>>>,
+}
+
+impl LogArgPositions {
+    pub fn format(&self, args: fmt::Arguments) -> String {
+        struct PositionTrackingWriter<'a> {
+            buf: String,
+            len: &'a Cell<usize>,
+        }
+
+        impl fmt::Write for PositionTrackingWriter<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.buf.push_str(s);
+                self.len.set(self.buf.len());
+                Ok(())
+            }
+        }
+
+        let mut writer = PositionTrackingWriter {
+            buf: String::new(),
+            len: &self.len,
+        };
+
+        fmt::write(&mut writer, args).expect("a formatting trait implementation returned an error");
+        writer.buf
+    }
+}
+
+/// Wraps an argument to a log message so that the location of its formatted text is recorded in a [`LogArgPositions`].
+#[doc(hidden)]
+pub struct LogArg<'a, T: ?Sized> {
+    val: &'a T,
+    positions: &'a LogArgPositions,
+}
+
+impl<'a, T: ?Sized> LogArg<'a, T> {
+    pub fn new(val: &'a T, positions: &'a LogArgPositions) -> LogArg<'a, T> {
+        LogArg { val, positions }
+    }
+}
+
+macro_rules! impl_log_arg_fmt {
+    ($($trait:ident),*) => {
+        $(
+            impl<T: fmt::$trait + ?Sized> fmt::$trait for LogArg<'_, T> {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    let start = self.positions.len.get();
+
+                    fmt::$trait::fmt(self.val, f)?;
+                    self.positions.args.borrow_mut().push(start..self.positions.len.get());
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_log_arg_fmt!(Display, Debug, LowerHex, UpperHex, Octal, Binary, LowerExp, UpperExp, Pointer);
+
+/// A single message that has been logged.
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    level: LogLevel,
+    module: &'static str,
+    timestamp: Duration,
+    fmt: &'static str,
+    text: String,
+    args: Vec<Range<usize>>,
+}
+
+impl LogRecord {
+    #[doc(hidden)]
+    pub fn new(level: LogLevel, module: &'static str, fmt: &'static str, text: String, positions: LogArgPositions) -> LogRecord {
+        LogRecord {
+            level,
+            module,
+            timestamp: timer::now(),
+            fmt,
+            text,
+            args: positions.args.into_inner(),
+        }
+    }
+
+    fn at(self, timestamp: Duration) -> LogRecord {
+        LogRecord { timestamp, ..self }
+    }
+
+    /// Gets the formatted text of each of the arguments of this message, in the order in which they appear in the message.
+    pub fn args(&self) -> impl Iterator<Item = &str> + '_ {
+        self.args.iter().map(|range| &self.text[range.clone()])
+    }
+
+    fn to_text_line(&self, theme: Option<&LogTheme>) -> String {
+        let Some(theme) = theme else {
+            return format!("[{}] {}: {}\n", self.level.name(), self.module, self.text);
+        };
+
+        let level_color = AnsiParserSgrAction::SetFgColor(theme.level_color(self.level));
+
+        if let Some(module_color) = theme.module_color(self.module) {
+            format!(
+                "[\x1b[{}m{}\x1b[0m] \x1b[{}m{}\x1b[0m: {}\n",
+                level_color,
+                self.level.name(),
+                AnsiParserSgrAction::SetFgColor(module_color),
+                self.module,
+                self.text
+            )
+        } else {
+            format!(
+                "[\x1b[{}m{}\x1b[0m] {}: {}\n",
+                level_color,
+                self.level.name(),
+                self.module,
+                self.text
+            )
+        }
+    }
+}
+
+/// The format in which log messages are written to a log sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines of text. If the terminal supports color, the severity and module of each message are highlighted using ANSI
+    /// escape sequences.
+    Text,
+    /// Compact binary records intended to be decoded by a tool running on another machine.
+    ///
+    /// Each record starts with a tag byte identifying its type and all multi-byte integers are little-endian. Module names and format
+    /// strings are only sent once per sink in a definition record, after which messages refer to them by ID:
+    ///
+    /// - `0x01` module definition: `u16` module ID, `u8` name length, name
+    /// - `0x02` format definition: `u16` format ID, `u16` format string length, format string
+    /// - `0x03` message: `u16` module ID, `u8` severity (0 is critical), `u64` nanoseconds since boot, `u16` format ID, `u8` argument
+    ///   count, then for each argument a `u16` length followed by its formatted text
+    ///
+    /// Strings are UTF-8, but may be truncated in the middle of a character if they are too long for their length field.
+    Binary,
+}
+
+impl<'a> KernelOptionParseable<'a> for LogFormat {
+    fn try_parse_kopt(s: &'a str) -> Result<Self, InvalidOptionValue> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "binary" => Ok(LogFormat::Binary),
+            _ => Err(InvalidOptionValue),
+        }
+    }
+}
+
+const BINARY_RECORD_MODULE: u8 = 0x01;
+const BINARY_RECORD_FORMAT: u8 = 0x02;
+const BINARY_RECORD_MESSAGE: u8 = 0x03;
+
+#[derive(Default)]
+struct BinaryEncoder {
+    modules: BTreeMap<&'static str, u16>,
+    formats: BTreeMap<&'static str, u16>,
+}
+
+impl BinaryEncoder {
+    fn intern(ids: &mut BTreeMap<&'static str, u16>, s: &'static str) -> (u16, bool) {
+        let next_id = ids.len() as u16;
+
+        match ids.entry(s) {
+            btree_map::Entry::Occupied(e) => (*e.get(), false),
+            btree_map::Entry::Vacant(e) => (*e.insert(next_id), true),
+        }
+    }
+
+    fn push_str(buf: &mut Vec<u8>, s: &str, max_len: usize, len_bytes: usize) {
+        let s = &s.as_bytes()[..s.len().min(max_len)];
+
+        buf.extend_from_slice(&s.len().to_le_bytes()[..len_bytes]);
+        buf.extend_from_slice(s);
+    }
+
+    fn encode(&mut self, record: &LogRecord, buf: &mut Vec<u8>) {
+        let (module_id, new_module) = BinaryEncoder::intern(&mut self.modules, record.module);
+        let (fmt_id, new_fmt) = BinaryEncoder::intern(&mut self.formats, record.fmt);
+
+        if new_module {
+            buf.push(BINARY_RECORD_MODULE);
+            buf.extend_from_slice(&module_id.to_le_bytes());
+            BinaryEncoder::push_str(buf, record.module, u8::MAX as usize, 1);
+        }
+
+        if new_fmt {
+            buf.push(BINARY_RECORD_FORMAT);
+            buf.extend_from_slice(&fmt_id.to_le_bytes());
+            BinaryEncoder::push_str(buf, record.fmt, u16::MAX as usize, 2);
+        }
+
+        buf.push(BINARY_RECORD_MESSAGE);
+        buf.extend_from_slice(&module_id.to_le_bytes());
+        buf.push(record.level as u8);
+        buf.extend_from_slice(&(record.timestamp.as_nanos() as u64).to_le_bytes());
+        buf.extend_from_slice(&fmt_id.to_le_bytes());
+        buf.push(record.args.len().min(u8::MAX as usize) as u8);
+
+        for arg in record.args().take(u8::MAX as usize) {
+            BinaryEncoder::push_str(buf, arg, u16::MAX as usize, 2);
+        }
+    }
+}
+
+/// Messages waiting to be written to a log sink. Since messages can be logged much faster than a slow sink (e.g. a serial port) can write
+/// them out, the amount of memory used by the queue is bounded. Once it is full, the oldest messages are dropped to make room for new
+/// ones and a message saying how many were dropped is written in their place.
+#[derive(Default)]
+struct LogQueue {
+    records: VecDeque<Arc<LogRecord>>,
+    bytes: usize,
+    dropped: usize,
+}
+
+impl LogQueue {
+    fn record_size(record: &LogRecord) -> usize {
+        mem::size_of::<LogRecord>() + record.text.len() + record.args.len() * mem::size_of::<Range<usize>>()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.records.is_empty() && self.dropped == 0
+    }
+
+    /// Adds a message to the back of the queue, dropping messages from the front until the queue takes up no more than `limit` bytes. The
+    /// message being added is always kept, even if it is larger than `limit` on its own.
+    fn push(&mut self, record: Arc<LogRecord>, limit: usize) {
+        self.bytes += LogQueue::record_size(&record);
+        self.records.push_back(record);
+
+        while self.bytes > limit && self.records.len() > 1 {
+            let record = self.records.pop_front().unwrap();
+
+            self.bytes -= LogQueue::record_size(&record);
+            self.dropped += 1;
+        }
+    }
+
+    /// Removes messages from the front of the queue and encodes them into a buffer until it holds at least `max_len` bytes or the queue
+    /// is empty. If any messages were dropped since the last call, a message saying so is encoded first.
+    fn take_batch(&mut self, max_len: usize, mut encode: impl FnMut(&LogRecord, &mut Vec<u8>)) -> Vec<u8> {
+        let mut buf = vec![];
+
+        if self.dropped != 0 {
+            let dropped = mem::take(&mut self.dropped);
+
+            let record = crate::log_record!(
+                LogLevel::Warning,
+                "log",
+                "{} messages were dropped since output could not keep up",
+                dropped
+            );
+
+            encode(&record, &mut buf);
+        }
+
+        while buf.len() < max_len {
+            let Some(record) = self.records.pop_front() else {
+                break;
+            };
+
+            self.bytes -= LogQueue::record_size(&record);
+            encode(&record, &mut buf);
+        }
+
+        buf
+    }
+}
+
+struct LogSink {
+    tty: DeviceRef<dyn Tty>,
+    encoder: Option<BinaryEncoder>,
+    queue: LogQueue,
+    /// Whether a write to the TTY is currently in progress. Only one write is in progress at a time, so that messages are written in order
+    /// and so that a slow TTY holds on to queued messages rather than to an ever-growing number of pending writes.
+    writing: bool,
+}
+
+impl LogSink {
+    fn is_for(&self, tty: &DeviceRef<dyn Tty>) -> bool {
+        ptr::eq(self.tty.dev() as *const _ as *const (), tty.dev() as *const _ as *const ())
+    }
+
+    fn take_batch(&mut self, theme: &LogTheme) -> Vec<u8> {
+        let encoder = &mut self.encoder;
+        let use_color = theme.enabled && self.tty.dev().capabilities().contains(TtyCapabilities::COLOR);
+
+        self.queue.take_batch(MAX_SINK_WRITE_LEN, |record, buf| {
+            if let Some(encoder) = encoder.as_mut() {
+                encoder.encode(record, buf);
+            } else {
+                buf.extend_from_slice(record.to_text_line(use_color.then_some(theme)).as_bytes());
+            }
+        })
+    }
+}
+
 pub fn init() {
     let default_level = options::get().get("loglevel").unwrap_or(LogLevel::Info);
     let levels: BTreeMap<_, _> = options::get()
@@ -83,31 +576,121 @@ pub fn init() {
         .collect();
 
     LOG_LEVELS.set(LogLevelOptions { default_level, levels });
+
+    let mut theme = LogTheme::new();
+
+    theme.enabled = options::get().get_flag("log_color").unwrap_or(true);
+    theme.auto_module_colors = options::get().get_flag("log_auto_tags").unwrap_or(true);
+
+    let mut unknown_levels = vec![];
+
+    for (level, color) in options::get().iter_group::<AnsiColor>("logcolor") {
+        match (LogLevel::try_parse_kopt(level), color) {
+            (Ok(level), Some(color)) => theme.level_colors[level as usize] = color,
+            (Err(_), _) => unknown_levels.push(level),
+            _ => {},
+        }
+    }
+
+    theme.module_colors = options::get()
+        .iter_group("logtag")
+        .filter_map(|(k, v)| if let Some(v) = v { Some((k, v)) } else { None })
+        .collect();
+
+    LOG_THEME.set(theme);
+
+    for level in unknown_levels {
+        crate::log!(Warning, "log", "Unknown log level in option 'logcolor.{}'", level);
+    }
+
+    let mut rate_limiter = RATE_LIMITER.lock();
+
+    rate_limiter.burst = options::get().get("log_burst").unwrap_or(DEFAULT_RATE_LIMIT_BURST).max(1);
+    rate_limiter.per_sec = options::get().get("log_rate").unwrap_or(DEFAULT_RATE_LIMIT_PER_SEC);
+
+    if let Some(size_kib) = options::get().get::<usize>("log_buffer_kib") {
+        SINK_BUFFER_SIZE.store(size_kib * 1024, Ordering::Relaxed);
+    }
 }
 
 pub fn add_tty(out: DeviceRef<dyn Tty>) {
-    OUT_TTY.lock().push(out);
+    add_tty_with_format(out, LogFormat::Text);
+}
+
+pub fn add_tty_with_format(out: DeviceRef<dyn Tty>, format: LogFormat) {
+    OUT_TTY.lock().push(LogSink {
+        tty: out,
+        encoder: match format {
+            LogFormat::Text => None,
+            LogFormat::Binary => Some(BinaryEncoder::default()),
+        },
+        queue: LogQueue::default(),
+        writing: false,
+    });
 }
 
 pub fn remove_tty(out: &DeviceRef<dyn Tty>) -> bool {
     let mut out_tty = OUT_TTY.lock();
 
     let old_len = out_tty.len();
-    out_tty.retain(|tty| !ptr::eq(tty.dev() as *const _ as *const (), out.dev() as *const _ as *const ()));
+    out_tty.retain(|sink| !sink.is_for(out));
 
     out_tty.len() != old_len
 }
 
-pub fn log_msg(msg: String) {
+/// Logs a message, subject to rate limiting. This is normally called through the [`log!`] macro.
+pub fn log_msg(record: LogRecord) {
+    let records = RATE_LIMITER.lock().filter(record);
+
+    for record in records {
+        write_msg(record);
+    }
+}
+
+fn write_msg(record: LogRecord) {
     enqueue_soft_interrupt(move || {
-        Future::all(OUT_TTY.lock().iter().map(|tty| {
-            // SAFETY: Backing memory for msg is kept alive until all writes are completed by moving it into the when_resolved closure
-            unsafe { tty.dev().write(msg.as_bytes()).without_val() }
-        }))
-        .when_resolved(move |_| drop(msg))
+        let record = Arc::new(record);
+        let limit = SINK_BUFFER_SIZE.load(Ordering::Relaxed);
+        let mut out_tty = OUT_TTY.lock();
+
+        for sink in out_tty.iter_mut() {
+            sink.queue.push(record.clone(), limit);
+        }
+
+        start_writes(&mut out_tty);
     });
 }
 
+/// Starts writing queued messages to every sink that has some and isn't already busy writing. Once a write completes, the next batch of
+/// messages for that sink is written, until its queue is empty.
+fn start_writes(sinks: &mut [LogSink]) {
+    let theme = LOG_THEME.get();
+
+    for sink in sinks.iter_mut().filter(|sink| !sink.writing && !sink.queue.is_empty()) {
+        let buf = sink.take_batch(theme);
+        let tty = sink.tty.clone();
+
+        // SAFETY: The buffer is kept alive until the write completes by moving it into the closure below
+        let write = unsafe { tty.dev().write(&buf[..]) };
+
+        sink.writing = true;
+
+        // The write may already have completed, in which case the closure would otherwise run immediately while OUT_TTY is still locked
+        write.when_resolved_soft(move |_| {
+            drop(buf);
+
+            let mut out_tty = OUT_TTY.lock();
+
+            // The sink may have been removed while the write was in progress
+            if let Some(sink) = out_tty.iter_mut().find(|sink| sink.is_for(&tty)) {
+                sink.writing = false;
+            }
+
+            start_writes(&mut out_tty);
+        });
+    }
+}
+
 #[cold]
 #[inline(never)]
 fn should_log_slow(levels: &LogLevelOptions, lvl: LogLevel, module: &'static str) -> bool {
@@ -132,17 +715,22 @@ macro_rules! log {
         let module = $module;
 
         if $crate::log::should_log(lvl, module) {
-            $crate::log::log_msg(::alloc::format!(
-                concat!("[\x1b[{}m{}\x1b[0m] {}: ", $msg, "\n"),
-                $crate::io::ansi::AnsiParserSgrAction::SetFgColor(lvl.color()),
-                lvl.name(),
-                module,
-                $($($arg),*)?
-            ));
+            $crate::log::log_msg($crate::log_record!(lvl, module, $msg $(, $($arg),*)?));
         }
     }
 }
 
+#[doc(hidden)]
+#[macro_export]
+macro_rules! log_record {
+    ($lvl:expr, $module:expr, $msg:expr $(, $($arg:expr),*)?) => {{
+        let positions = <$crate::log::LogArgPositions as ::core::default::Default>::default();
+        let text = positions.format(format_args!($msg $(, $($crate::log::LogArg::new(&$arg, &positions)),*)?));
+
+        $crate::log::LogRecord::new($lvl, $module, $msg, text, positions)
+    }};
+}
+
 #[macro_export]
 macro_rules! dbg {
     () => {
@@ -163,3 +751,140 @@ macro_rules! dbg {
         ($($crate::dbg!($val)),+,)
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(lvl: LogLevel, module: &'static str, msg: &str, now_ms: u64) -> LogRecord {
+        crate::log_record!(lvl, module, "{}", msg).at(Duration::from_millis(now_ms))
+    }
+
+    fn filter(limiter: &mut RateLimiter, module: &'static str, msg: &str, now_ms: u64) -> usize {
+        limiter.filter(record(LogLevel::Info, module, msg, now_ms)).len()
+    }
+
+    #[test_case]
+    fn test_rate_limit_folds_repeats() {
+        let mut limiter = RateLimiter::new(10, 10);
+
+        assert_eq!(1, filter(&mut limiter, "a", "x", 0));
+        assert_eq!(0, filter(&mut limiter, "a", "x", 0));
+        assert_eq!(0, filter(&mut limiter, "a", "x", 0));
+
+        let records = limiter.filter(record(LogLevel::Info, "a", "y", 0));
+
+        assert_eq!(2, records.len());
+        assert_eq!("last message repeated 2 times", records[0].text);
+        assert_eq!("y", records[1].text);
+    }
+
+    #[test_case]
+    fn test_rate_limit_token_bucket() {
+        let mut limiter = RateLimiter::new(2, 10);
+
+        assert_eq!(1, filter(&mut limiter, "a", "1", 0));
+        assert_eq!(1, filter(&mut limiter, "a", "2", 0));
+        assert_eq!(0, filter(&mut limiter, "a", "3", 0));
+        assert_eq!(0, filter(&mut limiter, "a", "4", 50));
+
+        // Other modules have their own buckets
+        assert_eq!(1, filter(&mut limiter, "b", "5", 50));
+
+        // One token is refilled every 100ms
+        let records = limiter.filter(record(LogLevel::Info, "a", "6", 100));
+
+        assert_eq!(2, records.len());
+        assert_eq!("2 messages from a were suppressed", records[0].text);
+        assert_eq!(0, filter(&mut limiter, "a", "7", 150));
+
+        // Critical messages are never suppressed
+        assert_eq!(1, limiter.filter(record(LogLevel::Critical, "a", "8", 150)).len());
+    }
+
+    #[test_case]
+    fn test_log_record_args() {
+        let record = crate::log_record!(LogLevel::Info, "test", "a={:#x} b={:?} c={:>3}", 255, "s", 7);
+
+        assert_eq!("a=0xff b=\"s\" c=  7", record.text);
+        assert_eq!(vec!["0xff", "\"s\"", "  7"], record.args().collect::<Vec<_>>());
+    }
+
+    #[test_case]
+    fn test_text_line_colors() {
+        let mut theme = LogTheme::new();
+        let record = crate::log_record!(LogLevel::Warning, "mod", "x{}", 1);
+
+        assert_eq!("[WARN] mod: x1\n", record.to_text_line(None));
+
+        theme.auto_module_colors = false;
+        assert_eq!("[\x1b[93mWARN\x1b[0m] mod: x1\n", record.to_text_line(Some(&theme)));
+
+        theme.level_colors[LogLevel::Warning as usize] = AnsiColor::Brown;
+        theme.module_colors.insert("mod", AnsiColor::Blue);
+        assert_eq!("[\x1b[33mWARN\x1b[0m] \x1b[34mmod\x1b[0m: x1\n", record.to_text_line(Some(&theme)));
+
+        // Automatically assigned tag colors should be stable and never reuse the default severity colors
+        theme.auto_module_colors = true;
+        assert_eq!(theme.module_color("other"), theme.module_color("other"));
+        assert!(LogLevel::ALL.iter().all(|&lvl| theme.module_color("other") != Some(lvl.color())));
+    }
+
+    #[test_case]
+    fn test_queue_overflow() {
+        let record = |msg: &str| Arc::new(crate::log_record!(LogLevel::Info, "mod", "{}", String::from(msg)));
+        let limit = LogQueue::record_size(&record("x")) * 2;
+        let mut queue = LogQueue::default();
+        let encode = |record: &LogRecord, buf: &mut Vec<u8>| buf.extend_from_slice(record.to_text_line(None).as_bytes());
+
+        assert!(queue.is_empty());
+
+        queue.push(record("1"), limit);
+        queue.push(record("2"), limit);
+        queue.push(record("3"), limit);
+        assert_eq!(1, queue.dropped);
+
+        assert_eq!(
+            &b"[WARN] log: 1 messages were dropped since output could not keep up\n[INFO] mod: 2\n[INFO] mod: 3\n"[..],
+            &queue.take_batch(MAX_SINK_WRITE_LEN, encode)[..]
+        );
+        assert!(queue.is_empty());
+        assert_eq!(0, queue.bytes);
+
+        // A single message is always kept, even if it is too big to fit
+        queue.push(record("too big"), 0);
+        queue.push(record("4"), 0);
+        assert_eq!(1, queue.records.len());
+        queue.take_batch(MAX_SINK_WRITE_LEN, encode);
+
+        // Batches stop once they reach the maximum length, leaving the rest of the messages queued
+        queue.push(record("5"), limit);
+        queue.push(record("6"), limit);
+        assert_eq!(&b"[INFO] mod: 5\n"[..], &queue.take_batch(1, encode)[..]);
+        assert_eq!(1, queue.records.len());
+    }
+
+    #[test_case]
+    fn test_binary_encoding() {
+        let mut encoder = BinaryEncoder::default();
+        let record = crate::log_record!(LogLevel::Error, "m", "x{}", 12).at(Duration::from_nanos(5));
+        let mut buf = vec![];
+
+        let mut expected = vec![BINARY_RECORD_MODULE];
+
+        expected.extend_from_slice(b"\x00\x00\x01m");
+        expected.push(BINARY_RECORD_FORMAT);
+        expected.extend_from_slice(b"\x00\x00\x03\x00x{}");
+        expected.push(BINARY_RECORD_MESSAGE);
+        expected.extend_from_slice(b"\x00\x00\x01\x05\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x02\x0012");
+
+        encoder.encode(&record, &mut buf);
+        assert_eq!(expected, buf);
+
+        // Definitions should only be sent the first time a module or format string is used
+        buf.clear();
+        encoder.encode(&record, &mut buf);
+        assert_eq!(BINARY_RECORD_MESSAGE, buf[0]);
+        assert_eq!(19, buf.len());
+    }
+}
@@ -52,5 +52,6 @@ fn panic(info: &PanicInfo) -> ! {
     use hydroxos_kernel::panic;
 
     interrupt::disable();
+    hydroxos_kernel::io::dev::recovery::try_recover(info);
     panic::show_panic_crash_screen(info);
 }
@@ -0,0 +1,238 @@
+//! Allocation of memory buffers for devices that perform direct memory access (DMA).
+//!
+//! Devices performing DMA access memory by its physical address, so buffers for them must be physically contiguous and some devices can
+//! only address a limited part of the physical address space. A [`DmaBuffer`] is allocated from the buddy frame allocator and provides
+//! both the physical address to program into the device and a virtual mapping through which the kernel can access the buffer.
+
+use core::{fmt, ptr};
+
+use super::frame::{self, ContiguousFrameAllocator, FrameZone, MAX_ORDER};
+use super::tlb::TlbShootdown;
+use super::virt::VirtualAllocRegion;
+use crate::arch::page::{get_phys_mem_ptr_slice, AddressSpace, PageFlags, PhysMemPtr, PAGE_SIZE};
+use crate::arch::PhysAddr;
+
+/// The caching mode used for the kernel's mapping of a DMA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCacheMode {
+    /// Reads and writes are cached normally. Only suitable for devices that snoop the CPU caches.
+    WriteBack,
+    /// Reads are cached, but writes are immediately written through to memory.
+    WriteThrough,
+    /// Neither reads nor writes are cached.
+    Uncached,
+}
+
+impl DmaCacheMode {
+    fn page_flags(self) -> PageFlags {
+        match self {
+            DmaCacheMode::WriteBack => PageFlags::empty(),
+            DmaCacheMode::WriteThrough => PageFlags::WRITE_THROUGH,
+            DmaCacheMode::Uncached => PageFlags::UNCACHED | PageFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// An error that can occur when allocating a DMA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaAllocError {
+    /// The requested size is zero or larger than the largest physically contiguous block that can be allocated.
+    InvalidSize,
+    /// No physically contiguous block of memory satisfying the request was available.
+    OutOfMemory,
+    /// No virtual address space was available to map the buffer.
+    OutOfVirtualMemory,
+}
+
+impl fmt::Display for DmaAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DmaAllocError::InvalidSize => write!(f, "invalid buffer size"),
+            DmaAllocError::OutOfMemory => write!(f, "out of physical memory"),
+            DmaAllocError::OutOfVirtualMemory => write!(f, "out of virtual memory"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum DmaMapping {
+    Direct(PhysMemPtr<[u8]>),
+    Mapped(VirtualAllocRegion),
+}
+
+/// A physically contiguous buffer of memory suitable for use by a device performing DMA. The memory is freed when the buffer is dropped,
+/// so the buffer must not be dropped while a device may still access it.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    order: u32,
+    len: usize,
+    cache: DmaCacheMode,
+    mapping: DmaMapping,
+}
+
+impl DmaBuffer {
+    /// Allocates a new zero-filled DMA buffer of at least `len` bytes. The buffer is aligned in physical memory to its size rounded up to a
+    /// power of two number of pages. If `below_4gib` is set, the entire buffer will lie in the first 4GiB of physical memory for devices that
+    /// can only perform 32-bit DMA.
+    ///
+    /// Buffers using [`DmaCacheMode::WriteBack`] are accessed through the kernel's mapping of physical memory. Buffers using other caching
+    /// modes are given a separate mapping with the requested caching mode. Note that the physical memory mapping is not changed, so such
+    /// buffers must not be accessed through it.
+    pub fn alloc(len: usize, below_4gib: bool, cache: DmaCacheMode) -> Result<DmaBuffer, DmaAllocError> {
+        DmaBuffer::alloc_in(len, if below_4gib { FrameZone::Dma32 } else { FrameZone::Normal }, cache)
+    }
+
+    /// Allocates a new zero-filled DMA buffer of at least `len` bytes that lies entirely within the provided zone, e.g. [`FrameZone::Dma`]
+    /// for legacy ISA devices that can only address the first 16MiB of physical memory. Otherwise behaves like [`DmaBuffer::alloc`].
+    pub fn alloc_in(len: usize, zone: FrameZone, cache: DmaCacheMode) -> Result<DmaBuffer, DmaAllocError> {
+        if len == 0 {
+            return Err(DmaAllocError::InvalidSize);
+        }
+
+        let order = len.div_ceil(PAGE_SIZE).next_power_of_two().trailing_zeros();
+
+        if order > MAX_ORDER {
+            return Err(DmaAllocError::InvalidSize);
+        }
+
+        let phys = frame::get_allocator()
+            .alloc_contiguous_in(order, zone)
+            .ok_or(DmaAllocError::OutOfMemory)?;
+
+        let mapping = if cache == DmaCacheMode::WriteBack {
+            DmaMapping::Direct(get_phys_mem_ptr_slice(phys, PAGE_SIZE << order))
+        } else {
+            let mut addrspace = AddressSpace::kernel();
+            let Some(region) = addrspace.virtual_alloc().alloc(PAGE_SIZE << order) else {
+                // SAFETY: The block was just allocated and has not been handed out to anything else
+                unsafe {
+                    frame::get_allocator().free_contiguous(phys, order);
+                }
+
+                return Err(DmaAllocError::OutOfVirtualMemory);
+            };
+
+            for i in 0..1 << order {
+                let frame = PhysAddr::new(phys.as_u64() + (i * PAGE_SIZE) as u64);
+
+                // SAFETY: The virtual region was just allocated, so nothing else is mapped there
+                unsafe {
+                    addrspace.set_page_kernel(
+                        region.start() + i * PAGE_SIZE,
+                        Some((frame, PageFlags::WRITEABLE | cache.page_flags())),
+                    );
+                }
+            }
+
+            DmaMapping::Mapped(region)
+        };
+
+        let buf = DmaBuffer {
+            phys,
+            order,
+            len,
+            cache,
+            mapping,
+        };
+
+        // SAFETY: The buffer is mapped and owned exclusively by this DmaBuffer
+        unsafe {
+            ptr::write_bytes(buf.as_ptr(), 0, PAGE_SIZE << order);
+        }
+
+        Ok(buf)
+    }
+
+    /// Gets the physical address of the start of this buffer, which should be provided to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Gets a pointer through which the kernel can access this buffer.
+    pub fn as_ptr(&self) -> *mut u8 {
+        match self.mapping {
+            DmaMapping::Direct(ref ptr) => ptr.ptr().cast(),
+            DmaMapping::Mapped(ref region) => region.start().as_mut_ptr(),
+        }
+    }
+
+    /// Gets the size of this buffer in bytes, as originally requested.
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Gets the caching mode used for the kernel's mapping of this buffer.
+    pub fn cache_mode(&self) -> DmaCacheMode {
+        self.cache
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let DmaMapping::Mapped(ref region) = self.mapping {
+            let mut addrspace = AddressSpace::kernel();
+            let mut shootdown = TlbShootdown::new();
+
+            // SAFETY: The region was mapped when this buffer was allocated and is not accessible once it is dropped
+            unsafe {
+                for i in 0..1 << self.order {
+                    addrspace.set_page_kernel(region.start() + i * PAGE_SIZE, None);
+                    shootdown.add(region.start() + i * PAGE_SIZE);
+                }
+
+                shootdown.flush();
+                addrspace.virtual_alloc().free(*region);
+            }
+        }
+
+        // SAFETY: The block was allocated with this order when this buffer was allocated and is no longer mapped through this buffer
+        unsafe {
+            frame::get_allocator().free_contiguous(self.phys, self.order);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mem::frame::FrameAllocator;
+
+    #[test_case]
+    fn test_dma_alloc_uncached_below_4gib() {
+        let frames_before = frame::get_allocator().num_frames_available();
+        let buf = DmaBuffer::alloc(3 * PAGE_SIZE, true, DmaCacheMode::Uncached).unwrap();
+
+        assert_eq!(3 * PAGE_SIZE, buf.size());
+        assert_eq!(0, buf.phys_addr().as_u64() % (4 * PAGE_SIZE) as u64);
+        assert!(buf.phys_addr().as_u64() + (4 * PAGE_SIZE) as u64 <= FrameZone::Dma32.limit().unwrap().as_u64());
+
+        let ptr = buf.as_ptr();
+        let (phys, flags) = AddressSpace::kernel().get_page(crate::arch::VirtAddr::from_ptr(ptr)).unwrap();
+
+        assert_eq!(buf.phys_addr(), phys);
+        assert!(flags.contains(PageFlags::UNCACHED));
+
+        // SAFETY: The buffer is at least 3 pages long
+        unsafe {
+            assert_eq!(0, *ptr.add(2 * PAGE_SIZE));
+            *ptr.add(2 * PAGE_SIZE) = 0x5a;
+            assert_eq!(0x5a, *ptr.add(2 * PAGE_SIZE));
+        }
+
+        drop(buf);
+        assert_eq!(frames_before, frame::get_allocator().num_frames_available());
+    }
+
+    #[test_case]
+    fn test_dma_alloc_invalid_size() {
+        assert_eq!(
+            Some(DmaAllocError::InvalidSize),
+            DmaBuffer::alloc(0, false, DmaCacheMode::WriteBack).err()
+        );
+        assert_eq!(
+            Some(DmaAllocError::InvalidSize),
+            DmaBuffer::alloc((PAGE_SIZE << MAX_ORDER) + 1, false, DmaCacheMode::WriteBack).err()
+        );
+    }
+}
@@ -2,12 +2,23 @@ use core::cell::SyncUnsafeCell;
 use core::sync::atomic::{AtomicPtr, Ordering};
 use core::{cmp, ptr};
 
+use super::frame::{self, FrameAllocator};
+use crate::arch::page::{AddressSpace, PAGE_SIZE};
+use crate::arch::VirtAddr;
+use crate::log;
 use crate::util::PageAligned;
 
-const EARLY_ALLOC_SIZE: usize = 1024 * 1024;
+pub(super) const EARLY_ALLOC_SIZE: usize = 1024 * 1024;
+
+/// The amount of free space left in the early allocation pool when the rest of it is reclaimed. The pool is used again while the kernel
+/// panics, so it must never be reclaimed entirely.
+const EARLY_ALLOC_PANIC_RESERVE: usize = 64 * 1024;
 
 static EARLY_ALLOC_AREA: PageAligned<SyncUnsafeCell<[u8; EARLY_ALLOC_SIZE]>> = PageAligned::new(SyncUnsafeCell::new([0; EARLY_ALLOC_SIZE]));
 static EARLY_ALLOC_MARK: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+static EARLY_ALLOC_END: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+crate::static_footprint!("mem", EARLY_ALLOC_AREA);
 
 pub fn init() {
     if EARLY_ALLOC_MARK
@@ -21,6 +32,11 @@ pub fn init() {
     {
         panic!("Attempt to initialize early memory allocation more than once");
     };
+
+    // SAFETY: The end of the early allocation area is one byte past the end of the same allocation
+    let end = unsafe { (EARLY_ALLOC_AREA.get() as *mut u8).add(EARLY_ALLOC_SIZE) };
+
+    EARLY_ALLOC_END.store(end, Ordering::Relaxed);
 }
 
 fn get_full_size(size: usize) -> u32 {
@@ -40,7 +56,7 @@ pub fn alloc(size: usize, align: usize) -> *mut u8 {
     let size = get_full_size(size);
 
     unsafe {
-        let early_alloc_end = (*EARLY_ALLOC_AREA.get()).as_mut_ptr_range().end;
+        let early_alloc_end = EARLY_ALLOC_END.load(Ordering::Relaxed);
 
         loop {
             let mark = EARLY_ALLOC_MARK.load(Ordering::Relaxed);
@@ -94,7 +110,7 @@ unsafe fn realloc_grow(ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u
     let mark = EARLY_ALLOC_MARK.load(Ordering::Relaxed);
 
     if mark == ptr.add(old_size as usize) {
-        if (*EARLY_ALLOC_AREA.get()).as_mut_ptr_range().end.offset_from(mark)
+        if EARLY_ALLOC_END.load(Ordering::Relaxed).offset_from(mark)
             < isize::try_from(new_size - old_size).expect("Early allocation too large")
         {
             panic!("Out of early allocation memory");
@@ -149,12 +165,85 @@ pub unsafe fn realloc(ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8
 }
 
 pub fn usage() -> (usize, usize) {
-    (
-        unsafe {
+    unsafe {
+        (
             EARLY_ALLOC_MARK
                 .load(Ordering::Relaxed)
-                .byte_offset_from(EARLY_ALLOC_AREA.get() as *const u8) as usize
-        },
-        EARLY_ALLOC_SIZE,
-    )
+                .byte_offset_from(EARLY_ALLOC_AREA.get() as *const u8) as usize,
+            EARLY_ALLOC_END
+                .load(Ordering::Relaxed)
+                .byte_offset_from(EARLY_ALLOC_AREA.get() as *const u8) as usize,
+        )
+    }
+}
+
+/// Returns the unused pages at the end of the early allocation pool to the frame allocator, leaving only a small reserve to be used while
+/// panicking. Allocations that are still live in the pool are left where they are. Returns the number of pages that were reclaimed.
+///
+/// # Safety
+///
+/// This must only be called once the kernel heap has taken over from the early allocation pool, since nothing must be allocating from the
+/// pool while it is being shrunk.
+pub unsafe fn reclaim() -> usize {
+    let start = EARLY_ALLOC_AREA.get() as *mut u8;
+    let end = EARLY_ALLOC_END.load(Ordering::Relaxed);
+    let used = EARLY_ALLOC_MARK.load(Ordering::Relaxed).offset_from(start) as usize;
+    let new_end = start.add((used + EARLY_ALLOC_PANIC_RESERVE).next_multiple_of(PAGE_SIZE).min(EARLY_ALLOC_SIZE));
+
+    if new_end >= end {
+        return 0;
+    }
+
+    // The pool must be shrunk before its pages are unmapped so that nothing can be allocated from them afterwards
+    EARLY_ALLOC_END.store(new_end, Ordering::Relaxed);
+
+    let mut num_reclaimed = 0;
+
+    for offset in (0..end.offset_from(new_end) as usize).step_by(PAGE_SIZE) {
+        let addr = VirtAddr::from_ptr(new_end.add(offset));
+        let mut addrspace = AddressSpace::kernel();
+
+        // Pages of the kernel image that are mapped using huge pages or whose frames lie outside of the memory managed by the frame
+        // allocator cannot be handed to it, so they stay mapped.
+        let frame = match addrspace.get_page(addr) {
+            Some((frame, _)) if !addrspace.is_huge_page(addr) && frame::info(frame).is_some() => frame,
+            _ => continue,
+        };
+
+        addrspace.set_page_kernel(addr, None);
+        drop(addrspace);
+
+        frame::get_allocator().free_one(frame);
+        num_reclaimed += 1;
+    }
+
+    num_reclaimed
+}
+
+unsafe fn init_reclaim() {
+    let num_reclaimed = reclaim();
+
+    if num_reclaimed != 0 {
+        log!(
+            Info,
+            "mem",
+            "Reclaimed {}KiB of unused early allocation pool",
+            num_reclaimed * PAGE_SIZE / 1024
+        );
+    }
+}
+
+crate::initcall!(late, "early pool reclaim", init_reclaim);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_reclaim_keeps_panic_reserve() {
+        let (used, total) = usage();
+
+        assert!(total <= EARLY_ALLOC_SIZE);
+        assert!(total - used >= EARLY_ALLOC_PANIC_RESERVE.min(EARLY_ALLOC_SIZE - used));
+    }
 }
@@ -0,0 +1,252 @@
+//! Architecture-independent handling of page faults.
+//!
+//! When an access to virtual memory faults, the architecture's exception handler describes the fault using a [`PageFault`] and passes it
+//! to [`handle_page_fault`]. Faults that are expected as part of demand paging are resolved by mapping in the missing page, after which the
+//! faulting access can simply be retried:
+//!
+//! - Pages of memory allocated using [`LazyPageAlloc`](super::LazyPageAlloc) are only backed by a page frame once they are first touched.
+//! - Pages of user processes that have been swapped out are read back in from swap (see [`swap`](super::swap)).
+//! - Writes to copy-on-write pages of user processes are resolved by giving the process its own copy of the page (see
+//!   [`resolve_copy_on_write`]).
+//!
+//! Faults on the guard page below a kernel stack are reported as a stack overflow by the architecture, since the registers of the
+//! interrupted thread are needed to describe them usefully. Any other fault is a genuine bug and cannot be resolved.
+
+use core::fmt;
+
+use bitflags::bitflags;
+
+use super::frame::{self, FrameAllocator};
+use super::swap;
+use crate::arch::page::{get_phys_mem_ptr_slice, AddressSpace, PageFlags, PAGE_SIZE, USER_SPACE_END};
+use crate::arch::VirtAddr;
+use crate::log;
+use crate::sched::task::Thread;
+
+bitflags! {
+    /// Information about the access that caused a page fault.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFaultFlags: u32 {
+        /// The faulting page was present, so the fault was caused by the access not being permitted by the page's protection.
+        const PRESENT = 0x01;
+        /// The faulting access was a write.
+        const WRITE = 0x02;
+        /// The faulting access was made from user mode.
+        const USER = 0x04;
+        /// A reserved bit was set in one of the page table entries used to translate the faulting address.
+        const RESERVED = 0x08;
+        /// The faulting access was an instruction fetch.
+        const INSTRUCTION = 0x10;
+    }
+}
+
+/// A page fault raised by an access to virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    /// The virtual address that was being accessed.
+    pub addr: VirtAddr,
+    /// Information about the faulting access.
+    pub flags: PageFaultFlags,
+}
+
+/// The reason that a page fault occurred, as determined by [`classify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultKind {
+    /// A page of lazily allocated kernel memory was touched for the first time.
+    LazyKernel,
+    /// A non-present page in the user half of the address space was accessed, which may be resolved by swapping it back in.
+    UserDemand,
+    /// A present page in the user half of the address space was written to, which may be resolved if the page is copy-on-write.
+    CopyOnWrite,
+    /// The guard page below the kernel stack of the interrupted thread was accessed, so that thread overflowed its stack.
+    StackGuard,
+    /// The access was not valid and the fault cannot be resolved.
+    Invalid,
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = if self.flags.contains(PageFaultFlags::USER) {
+            "user-mode"
+        } else {
+            "kernel-mode"
+        };
+        let access = if self.flags.contains(PageFaultFlags::INSTRUCTION) {
+            "instruction fetch"
+        } else if self.flags.contains(PageFaultFlags::WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let reason = if self.flags.contains(PageFaultFlags::RESERVED) {
+            "with reserved page table bits set"
+        } else if self.flags.contains(PageFaultFlags::PRESENT) {
+            "violating page protection"
+        } else {
+            "to non-present page"
+        };
+
+        write!(f, "{} {} {} at {:#x}", mode, access, reason, self.addr.as_u64())
+    }
+}
+
+/// Determines the reason that a page fault occurred, without attempting to resolve it.
+pub fn classify(fault: &PageFault) -> PageFaultKind {
+    if fault.flags.contains(PageFaultFlags::RESERVED) {
+        return PageFaultKind::Invalid;
+    }
+
+    if fault.flags.contains(PageFaultFlags::PRESENT) {
+        return if fault.flags.contains(PageFaultFlags::WRITE) && fault.addr.as_u64() < USER_SPACE_END {
+            PageFaultKind::CopyOnWrite
+        } else {
+            PageFaultKind::Invalid
+        };
+    }
+
+    if Thread::current_interrupted().is_some_and(|thread| thread.is_stack_guard_page(fault.addr)) {
+        PageFaultKind::StackGuard
+    } else if super::LazyPageAlloc::contains(fault.addr) {
+        PageFaultKind::LazyKernel
+    } else if fault.addr.as_u64() < USER_SPACE_END {
+        PageFaultKind::UserDemand
+    } else {
+        PageFaultKind::Invalid
+    }
+}
+
+/// Attempts to resolve a page fault by mapping in the page that was being accessed. Returns `true` if the fault was resolved and the
+/// faulting access can be retried, or `false` if the fault was caused by an invalid access.
+///
+/// # Lock Ordering
+///
+/// Resolving a fault on lazily allocated kernel memory locks the kernel address space, and resolving a fault on a swapped out or
+/// copy-on-write user page locks the process of the interrupted thread. Such faults must not occur while those locks are held.
+pub fn handle_page_fault(fault: &PageFault) -> bool {
+    match classify(fault) {
+        PageFaultKind::LazyKernel => super::LazyPageAlloc::populate(fault.addr),
+        PageFaultKind::UserDemand => swap::handle_page_fault(fault.addr),
+        PageFaultKind::CopyOnWrite => handle_copy_on_write(fault.addr),
+        PageFaultKind::StackGuard | PageFaultKind::Invalid => false,
+    }
+}
+
+fn handle_copy_on_write(addr: VirtAddr) -> bool {
+    let process = match Thread::current_interrupted().and_then(|t| t.process().upgrade()) {
+        Some(process) if !process.is_kernel_process() => process,
+        _ => {
+            return false;
+        },
+    };
+
+    let mut process_lock = process.lock();
+
+    match process_lock.addr_space() {
+        Some(addrspace) => resolve_copy_on_write(addrspace, addr),
+        None => false,
+    }
+}
+
+/// Makes the copy-on-write page mapped at the provided lower-half virtual address writeable again. If the frame backing the page is still
+/// shared with other mappings, the page is first moved to a private copy of the frame. Returns `false` if the page is not a copy-on-write
+/// page or if no memory was available to copy it.
+pub fn resolve_copy_on_write(addrspace: &mut AddressSpace, addr: VirtAddr) -> bool {
+    let page = VirtAddr::new_truncate(addr.as_u64() & !(PAGE_SIZE as u64 - 1));
+    let (old_frame, flags) = match addrspace.get_page(page) {
+        Some((frame, flags)) if flags.contains(PageFlags::COPY_ON_WRITE) => (frame, flags - PageFlags::COPY_ON_WRITE),
+        _ => {
+            return false;
+        },
+    };
+
+    // Nobody else can start sharing the frame while this address space is locked, so if this is the last reference to the frame, it can
+    // simply be made writeable in place.
+    if frame::ref_count(old_frame) == 1 {
+        // SAFETY: This is the only mapping of the frame, so nothing else can observe writes to it.
+        unsafe {
+            addrspace.set_page_user(page, Some((old_frame, flags)));
+        }
+
+        return true;
+    }
+
+    let Some(new_frame) = frame::get_allocator().alloc_one() else {
+        log!(Error, "fault", "Out of memory copying copy-on-write page at {:#x}", page.as_u64());
+        return false;
+    };
+
+    // SAFETY: The new frame was just allocated, so nothing else can be using it. The old frame is only dropped by this address space once
+    //         the page no longer maps it.
+    unsafe {
+        (*get_phys_mem_ptr_slice::<u8>(new_frame, PAGE_SIZE).ptr())
+            .copy_from_slice(&*get_phys_mem_ptr_slice::<u8>(old_frame, PAGE_SIZE).ptr());
+        addrspace.set_page_user(page, Some((new_frame, flags)));
+        frame::release(old_frame);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_page_fault_display() {
+        let fault = PageFault {
+            addr: VirtAddr::new(0x1000),
+            flags: PageFaultFlags::WRITE | PageFaultFlags::USER,
+        };
+
+        assert_eq!("user-mode write to non-present page at 0x1000", alloc::format!("{}", fault));
+        assert_eq!(PageFaultKind::UserDemand, classify(&fault));
+
+        let fault = PageFault {
+            addr: VirtAddr::new(0x1000),
+            flags: PageFaultFlags::PRESENT,
+        };
+
+        assert_eq!("kernel-mode read violating page protection at 0x1000", alloc::format!("{}", fault));
+        assert_eq!(PageFaultKind::Invalid, classify(&fault));
+    }
+
+    #[test_case]
+    fn test_copy_on_write() {
+        let mut addrspace = AddressSpace::new();
+        let addr = VirtAddr::new(0x1000_0000);
+        let frame = frame::get_allocator().alloc_one().unwrap();
+
+        unsafe {
+            (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).fill(0xaa);
+            addrspace.set_page_user(addr, Some((frame, PageFlags::USER | PageFlags::WRITEABLE)));
+        }
+
+        let (shared, flags) = addrspace.make_page_cow_user(addr).unwrap();
+        let fault = PageFault {
+            addr,
+            flags: PageFaultFlags::PRESENT | PageFaultFlags::WRITE | PageFaultFlags::USER,
+        };
+
+        assert_eq!(frame, shared);
+        assert!(flags.contains(PageFlags::WRITEABLE | PageFlags::COPY_ON_WRITE));
+        assert_eq!(PageFaultKind::CopyOnWrite, classify(&fault));
+
+        // While the frame is shared, a write must move the page to a copy of the frame
+        frame::share(frame);
+        assert!(resolve_copy_on_write(&mut addrspace, addr));
+
+        let (copy, flags) = addrspace.get_page(addr).unwrap();
+
+        assert_ne!(frame, copy);
+        assert_eq!(PageFlags::USER | PageFlags::WRITEABLE, flags);
+        assert_eq!(1, frame::ref_count(frame));
+        assert!(unsafe { (*get_phys_mem_ptr_slice::<u8>(copy, PAGE_SIZE).ptr()).iter().all(|&b| b == 0xaa) });
+        assert!(unsafe { frame::release(frame) });
+
+        // Once nothing else shares the frame, it can be made writeable again without copying it
+        addrspace.make_page_cow_user(addr);
+        assert!(resolve_copy_on_write(&mut addrspace, addr));
+        assert_eq!(Some((copy, PageFlags::USER | PageFlags::WRITEABLE)), addrspace.get_page(addr));
+        assert!(!resolve_copy_on_write(&mut addrspace, addr));
+    }
+}
@@ -1,12 +1,16 @@
 //! Physical frame allocation.
 
 use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
+use bitflags::bitflags;
 use bootloader::bootinfo::MemoryRegionType;
 use bootloader::BootInfo;
 
-use crate::arch::page::{get_phys_mem_ptr, PhysMemPtr, PAGE_SIZE};
+use crate::arch::page::{get_phys_mem_ptr, get_phys_mem_ptr_slice, PhysMemPtr, PAGE_SIZE};
 use crate::arch::PhysAddr;
+use crate::log;
 use crate::sync::uninterruptible::{UninterruptibleSpinlock, UninterruptibleSpinlockGuard};
 use crate::util::OneShotManualInit;
 
@@ -69,6 +73,387 @@ pub trait FrameAllocator {
     }
 }
 
+/// The largest order of block that can be allocated using [`ContiguousFrameAllocator::alloc_contiguous`], i.e. blocks of up to
+/// `2^MAX_ORDER` page frames can be allocated.
+pub const MAX_ORDER: u32 = 10;
+
+/// A zone of physical memory that frames can be allocated from, for devices that can only address part of the physical address space.
+///
+/// Zones are nested, so each zone also contains all frames in the zones before it. Allocating from a zone may return a frame from any zone
+/// that it contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FrameZone {
+    /// Frames below 16MiB, which can be used by legacy ISA DMA.
+    Dma,
+    /// Frames below 4GiB, which can be used by devices that are only capable of 32-bit addressing.
+    Dma32,
+    /// All frames.
+    Normal,
+}
+
+const NUM_ZONES: usize = 3;
+
+impl FrameZone {
+    /// All zones, in order from the smallest zone to the largest.
+    pub const ALL: [FrameZone; NUM_ZONES] = [FrameZone::Dma, FrameZone::Dma32, FrameZone::Normal];
+
+    /// Gets the physical address (exclusive) below which all frames in this zone lie, or [`None`] if this zone is not limited.
+    pub fn limit(self) -> Option<PhysAddr> {
+        match self {
+            FrameZone::Dma => Some(PhysAddr::new(16 << 20)),
+            FrameZone::Dma32 => Some(PhysAddr::new(1 << 32)),
+            FrameZone::Normal => None,
+        }
+    }
+
+    /// Gets the smallest zone containing the provided page frame.
+    pub fn of(frame: PhysAddr) -> FrameZone {
+        FrameZone::ALL
+            .into_iter()
+            .find(|zone| !matches!(zone.limit(), Some(limit) if frame >= limit))
+            .unwrap()
+    }
+
+    /// Gets the largest zone lying entirely below the provided physical address, or [`None`] if even the smallest zone extends beyond it.
+    pub fn below(limit: PhysAddr) -> Option<FrameZone> {
+        FrameZone::ALL
+            .into_iter()
+            .rev()
+            .find(|zone| zone.limit().is_some_and(|zone_limit| zone_limit <= limit))
+    }
+}
+
+/// An allocator that is able to return physically contiguous blocks of page frames, e.g. for buffers used by devices that perform DMA.
+pub trait ContiguousFrameAllocator: FrameAllocator {
+    /// Allocates a physically contiguous block of `2^order` page frames, returning the address of the first frame in the block. The block
+    /// is aligned to its size. Returns [`None`] if no sufficiently large block of page frames is available.
+    ///
+    /// # Safety
+    ///
+    /// This method has the same guarantees with regards to memory initialization as [`FrameAllocator::alloc_one`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if `order` is greater than [`MAX_ORDER`].
+    fn alloc_contiguous(&mut self, order: u32) -> Option<PhysAddr>;
+
+    /// Allocates a physically contiguous block of `2^order` page frames that lies entirely below the physical address `limit`, e.g. for
+    /// devices that can only address the low 4GiB of physical memory. Blocks allocated using this method are freed in the same way as those
+    /// allocated using [`ContiguousFrameAllocator::alloc_contiguous`].
+    ///
+    /// # Safety
+    ///
+    /// This method has the same guarantees with regards to memory initialization as [`FrameAllocator::alloc_one`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if `order` is greater than [`MAX_ORDER`].
+    fn alloc_contiguous_below(&mut self, order: u32, limit: PhysAddr) -> Option<PhysAddr>;
+
+    /// Allocates a physically contiguous block of `2^order` page frames that lies entirely within the provided zone. Blocks allocated using
+    /// this method are freed in the same way as those allocated using [`ContiguousFrameAllocator::alloc_contiguous`].
+    ///
+    /// # Safety
+    ///
+    /// This method has the same guarantees with regards to memory initialization as [`FrameAllocator::alloc_one`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if `order` is greater than [`MAX_ORDER`].
+    fn alloc_contiguous_in(&mut self, order: u32, zone: FrameZone) -> Option<PhysAddr> {
+        match zone.limit() {
+            Some(limit) => self.alloc_contiguous_below(order, limit),
+            None => self.alloc_contiguous(order),
+        }
+    }
+
+    /// Allocates a single page frame that lies within the provided zone. Returns [`None`] if no page frames are available in that zone.
+    ///
+    /// # Safety
+    ///
+    /// This method has the same guarantees with regards to memory initialization as [`FrameAllocator::alloc_one`].
+    fn alloc_one_in(&mut self, zone: FrameZone) -> Option<PhysAddr> {
+        self.alloc_contiguous_in(0, zone)
+    }
+
+    /// Frees a block of `2^order` page frames that was previously allocated using [`ContiguousFrameAllocator::alloc_contiguous`].
+    ///
+    /// # Safety
+    ///
+    /// The provided block must have been allocated from this allocator with the same order and must no longer be in use by anything else.
+    unsafe fn free_contiguous(&mut self, block: PhysAddr, order: u32);
+}
+
+#[repr(C)]
+struct BuddyFreeBlock {
+    prev: Option<PhysAddr>,
+    next: Option<PhysAddr>,
+}
+
+/// A page frame allocator using the buddy system, which is able to allocate physically contiguous blocks of page frames.
+///
+/// Free blocks of each order are kept in intrusive doubly-linked lists stored in the free page frames themselves. A bitmap for each order
+/// records which blocks are currently free so that a block can quickly check whether its buddy is free when deciding whether to merge them.
+///
+/// Each [`FrameZone`] has its own set of free lists. Since zone boundaries are aligned to the largest block size, a block never spans more
+/// than one zone. Allocations that don't need a particular zone are satisfied from the largest zone first, so that frames in the smaller
+/// zones remain available for the devices that need them for as long as possible.
+pub struct BuddyFrameAllocator {
+    num_frames_available: usize,
+    free_lists: [[Option<PhysAddr>; MAX_ORDER as usize + 1]; NUM_ZONES],
+    bitmap: *mut u64,
+    bitmap_offsets: [usize; MAX_ORDER as usize + 1],
+    num_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates a new buddy page frame allocator that cannot hold any page frames until [`BuddyFrameAllocator::init`] is called.
+    pub const fn new() -> BuddyFrameAllocator {
+        BuddyFrameAllocator {
+            num_frames_available: 0,
+            free_lists: [[None; MAX_ORDER as usize + 1]; NUM_ZONES],
+            bitmap: ptr::null_mut(),
+            bitmap_offsets: [0; MAX_ORDER as usize + 1],
+            num_frames: 0,
+        }
+    }
+
+    /// Gets the number of 64-bit words of bitmap that are needed to track the page frames below the provided frame number.
+    pub fn bitmap_words(num_frames: usize) -> usize {
+        (0..=MAX_ORDER).map(|order| (num_frames >> order).div_ceil(64)).sum()
+    }
+
+    /// Sets up this allocator to manage the page frames below the provided frame number. All page frames start out as allocated.
+    ///
+    /// # Safety
+    ///
+    /// The provided bitmap must be zeroed, must be at least [`BuddyFrameAllocator::bitmap_words`] words long and must remain valid and
+    /// otherwise unused for as long as this allocator is in use. This allocator must not have been initialized already.
+    pub unsafe fn init(&mut self, bitmap: *mut u64, num_frames: usize) {
+        assert!(self.bitmap.is_null());
+
+        let mut offset = 0;
+
+        for order in 0..=MAX_ORDER {
+            self.bitmap_offsets[order as usize] = offset;
+            offset += (num_frames >> order).div_ceil(64);
+        }
+
+        self.bitmap = bitmap;
+        self.num_frames = num_frames;
+    }
+
+    fn bit(&self, frame: usize, order: u32) -> (*mut u64, u64) {
+        let idx = frame >> order;
+
+        // SAFETY: The bitmap is large enough to hold a bit for every block of every order
+        let word = unsafe { self.bitmap.add(self.bitmap_offsets[order as usize] + idx / 64) };
+
+        (word, 1 << (idx % 64))
+    }
+
+    fn is_free(&self, frame: usize, order: u32) -> bool {
+        if frame + (1 << order) > self.num_frames {
+            return false;
+        }
+
+        let (word, mask) = self.bit(frame, order);
+
+        // SAFETY: The bitmap is owned by this allocator
+        unsafe { *word & mask != 0 }
+    }
+
+    fn set_free(&mut self, frame: usize, order: u32, free: bool) {
+        let (word, mask) = self.bit(frame, order);
+
+        // SAFETY: The bitmap is owned by this allocator
+        unsafe {
+            if free {
+                *word |= mask;
+            } else {
+                *word &= !mask;
+            }
+        }
+    }
+
+    fn frame_addr(frame: usize) -> PhysAddr {
+        PhysAddr::new((frame * PAGE_SIZE) as u64)
+    }
+
+    fn free_list(&mut self, frame: usize, order: u32) -> &mut Option<PhysAddr> {
+        let zone = FrameZone::of(BuddyFrameAllocator::frame_addr(frame));
+
+        &mut self.free_lists[zone as usize][order as usize]
+    }
+
+    unsafe fn push(&mut self, frame: usize, order: u32) {
+        let addr = BuddyFrameAllocator::frame_addr(frame);
+        let head = *self.free_list(frame, order);
+
+        *get_phys_mem_ptr::<BuddyFreeBlock>(addr).ptr() = BuddyFreeBlock { prev: None, next: head };
+
+        if let Some(head) = head {
+            (*get_phys_mem_ptr::<BuddyFreeBlock>(head).ptr()).prev = Some(addr);
+        }
+
+        *self.free_list(frame, order) = Some(addr);
+        self.set_free(frame, order, true);
+    }
+
+    unsafe fn remove(&mut self, frame: usize, order: u32) {
+        let addr = BuddyFrameAllocator::frame_addr(frame);
+        let block = &*get_phys_mem_ptr::<BuddyFreeBlock>(addr).ptr();
+        let (prev, next) = (block.prev, block.next);
+
+        if let Some(prev) = prev {
+            (*get_phys_mem_ptr::<BuddyFreeBlock>(prev).ptr()).next = next;
+        } else {
+            *self.free_list(frame, order) = next;
+        }
+
+        if let Some(next) = next {
+            (*get_phys_mem_ptr::<BuddyFreeBlock>(next).ptr()).prev = prev;
+        }
+
+        self.set_free(frame, order, false);
+    }
+
+    /// Removes a free block of order `block_order` from its free list and splits it down to order `order`, returning the upper halves to
+    /// the free lists. The first `2^order` frames of the block are then considered allocated.
+    ///
+    /// # Safety
+    ///
+    /// The provided block must currently be on the free list for `block_order`.
+    unsafe fn take(&mut self, frame: usize, mut block_order: u32, order: u32) {
+        self.remove(frame, block_order);
+
+        while block_order > order {
+            block_order -= 1;
+            self.push(frame + (1 << block_order), block_order);
+        }
+
+        self.num_frames_available -= 1 << order;
+    }
+
+    /// Frees all page frames in the provided range of frame numbers, merging them into blocks that are as large as possible.
+    ///
+    /// # Safety
+    ///
+    /// All page frames in the provided range must be valid and must not be in use by anything else.
+    pub unsafe fn free_range(&mut self, start: usize, end: usize) {
+        let mut frame = start;
+
+        while frame < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| frame % (1 << order) == 0 && frame + (1 << order) <= end)
+                .unwrap();
+
+            self.free_contiguous(BuddyFrameAllocator::frame_addr(frame), order);
+            frame += 1 << order;
+        }
+    }
+}
+
+impl ContiguousFrameAllocator for BuddyFrameAllocator {
+    fn alloc_contiguous(&mut self, order: u32) -> Option<PhysAddr> {
+        self.alloc_contiguous_in(order, FrameZone::Normal)
+    }
+
+    fn alloc_contiguous_in(&mut self, order: u32, zone: FrameZone) -> Option<PhysAddr> {
+        assert!(order <= MAX_ORDER);
+
+        let (zone, block_order) = (0..=zone as usize)
+            .rev()
+            .find_map(|zone| Some((zone, (order..=MAX_ORDER).find(|&o| self.free_lists[zone][o as usize].is_some())?)))?;
+        let addr = self.free_lists[zone][block_order as usize].unwrap();
+
+        // SAFETY: The block was just found on the free list for this order
+        unsafe {
+            self.take(addr.as_u64() as usize / PAGE_SIZE, block_order, order);
+        }
+
+        Some(addr)
+    }
+
+    fn alloc_contiguous_below(&mut self, order: u32, limit: PhysAddr) -> Option<PhysAddr> {
+        assert!(order <= MAX_ORDER);
+
+        // Any block in a zone lying entirely below the limit will do, so try those first to avoid walking the free lists
+        if let Some(block) = FrameZone::below(limit).and_then(|zone| self.alloc_contiguous_in(order, zone)) {
+            return Some(block);
+        }
+
+        let limit_frame = limit.as_u64() as usize / PAGE_SIZE;
+        let limit_zone = FrameZone::of(PhysAddr::new(limit.as_u64().saturating_sub(1)));
+
+        // Since blocks are split by handing out their lower half, a larger block can satisfy the request as long as its first 2^order
+        // frames are below the limit. This walks the free lists, but is only expected to be used rarely by device drivers.
+        for block_order in order..=MAX_ORDER {
+            let mut next = self.free_lists[limit_zone as usize][block_order as usize];
+
+            while let Some(addr) = next {
+                let frame = addr.as_u64() as usize / PAGE_SIZE;
+
+                if frame + (1 << order) <= limit_frame {
+                    // SAFETY: The block was just found on the free list for this order
+                    unsafe {
+                        self.take(frame, block_order, order);
+                    }
+
+                    return Some(addr);
+                }
+
+                // SAFETY: All blocks on the free lists are free page frames holding a valid BuddyFreeBlock
+                next = unsafe { (*get_phys_mem_ptr::<BuddyFreeBlock>(addr).ptr()).next };
+            }
+        }
+
+        None
+    }
+
+    unsafe fn free_contiguous(&mut self, block: PhysAddr, order: u32) {
+        assert!(order <= MAX_ORDER);
+
+        let mut frame = block.as_u64() as usize / PAGE_SIZE;
+        let mut block_order = order;
+
+        debug_assert_eq!(0, frame % (1 << order));
+        assert!(frame + (1 << order) <= self.num_frames);
+
+        while block_order < MAX_ORDER {
+            let buddy = frame ^ (1 << block_order);
+
+            if !self.is_free(buddy, block_order) {
+                break;
+            }
+
+            self.remove(buddy, block_order);
+            frame = frame.min(buddy);
+            block_order += 1;
+        }
+
+        self.push(frame, block_order);
+        self.num_frames_available += 1 << order;
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    unsafe fn free_one(&mut self, frame: PhysAddr) {
+        self.free_contiguous(frame, 0);
+    }
+
+    fn alloc_one(&mut self) -> Option<PhysAddr> {
+        self.alloc_contiguous(0)
+    }
+
+    fn num_frames_available(&self) -> usize {
+        self.num_frames_available
+    }
+}
+
+unsafe impl Send for BuddyFrameAllocator {}
+
 #[repr(C)]
 struct StackFrameAllocatorPage {
     frames: [PhysAddr; NUM_FRAMES_PER_PAGE],
@@ -169,11 +554,21 @@ impl<T: FrameAllocator> LockFrameAllocator<T> {
 
 impl<T: FrameAllocator> FrameAllocator for &'_ LockFrameAllocator<T> {
     unsafe fn free_one(&mut self, frame: PhysAddr) {
-        self.lock().free_one(frame);
+        if !quarantine_if_bad(frame) {
+            self.lock().free_one(frame);
+        }
     }
 
     fn alloc_one(&mut self) -> Option<PhysAddr> {
-        self.lock().alloc_one()
+        let mut alloc = self.lock();
+
+        loop {
+            let frame = alloc.alloc_one()?;
+
+            if !quarantine_if_bad(frame) {
+                return Some(frame);
+            }
+        }
     }
 
     fn num_frames_available(&self) -> usize {
@@ -181,17 +576,104 @@ impl<T: FrameAllocator> FrameAllocator for &'_ LockFrameAllocator<T> {
     }
 
     unsafe fn free_many(&mut self, frames: &[PhysAddr]) {
-        self.lock().free_many(frames)
+        let mut alloc = self.lock();
+
+        for &frame in frames {
+            if !quarantine_if_bad(frame) {
+                alloc.free_one(frame);
+            }
+        }
     }
 
     fn alloc_many<'a>(&mut self, frames_out: &'a mut [MaybeUninit<PhysAddr>]) -> Option<&'a mut [PhysAddr]> {
-        self.lock().alloc_many(frames_out)
+        let mut alloc = self.lock();
+        let frames = alloc.alloc_many(frames_out)?;
+
+        if NUM_BAD_FRAMES.load(Ordering::Relaxed) != 0 {
+            for i in 0..frames.len() {
+                while quarantine_if_bad(frames[i]) {
+                    if let Some(frame) = alloc.alloc_one() {
+                        frames[i] = frame;
+                    } else {
+                        // SAFETY: These frames were just allocated and have not yet been handed out to anyone else.
+                        unsafe {
+                            alloc.free_many(&frames[..i]);
+                            alloc.free_many(&frames[i + 1..]);
+                        }
+                        return None;
+                    }
+                }
+            }
+        }
+
+        Some(frames)
+    }
+}
+
+impl<T: ContiguousFrameAllocator> LockFrameAllocator<T> {
+    fn alloc_contiguous_checked(&self, order: u32, mut alloc_fn: impl FnMut(&mut T) -> Option<PhysAddr>) -> Option<PhysAddr> {
+        let mut alloc = self.lock();
+
+        loop {
+            let block = alloc_fn(&mut *alloc)?;
+
+            if NUM_BAD_FRAMES.load(Ordering::Relaxed) == 0 {
+                return Some(block);
+            }
+
+            let frames = (0..1_u64 << order).map(|i| PhysAddr::new(block.as_u64() + i * PAGE_SIZE as u64));
+
+            if !frames.clone().any(|frame| BAD_FRAMES.lock().quarantine(frame)) {
+                return Some(block);
+            }
+
+            // Return the good frames in this block to the allocator one by one and try again, since the bad frames must not be freed
+            for frame in frames {
+                if !quarantine_if_bad(frame) {
+                    // SAFETY: This frame was just allocated and has not yet been handed out to anyone else.
+                    unsafe {
+                        alloc.free_one(frame);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: ContiguousFrameAllocator> ContiguousFrameAllocator for &'_ LockFrameAllocator<T> {
+    fn alloc_contiguous(&mut self, order: u32) -> Option<PhysAddr> {
+        self.alloc_contiguous_checked(order, |alloc| alloc.alloc_contiguous(order))
+    }
+
+    fn alloc_contiguous_below(&mut self, order: u32, limit: PhysAddr) -> Option<PhysAddr> {
+        self.alloc_contiguous_checked(order, |alloc| alloc.alloc_contiguous_below(order, limit))
+    }
+
+    fn alloc_contiguous_in(&mut self, order: u32, zone: FrameZone) -> Option<PhysAddr> {
+        self.alloc_contiguous_checked(order, |alloc| alloc.alloc_contiguous_in(order, zone))
+    }
+
+    unsafe fn free_contiguous(&mut self, block: PhysAddr, order: u32) {
+        let mut alloc = self.lock();
+
+        if NUM_BAD_FRAMES.load(Ordering::Relaxed) == 0 {
+            alloc.free_contiguous(block, order);
+            return;
+        }
+
+        for i in 0..1_u64 << order {
+            let frame = PhysAddr::new(block.as_u64() + i * PAGE_SIZE as u64);
+
+            if !quarantine_if_bad(frame) {
+                alloc.free_one(frame);
+            }
+        }
     }
 }
 
-static FRAME_ALLOC: LockFrameAllocator<StackFrameAllocator> = LockFrameAllocator::new(StackFrameAllocator::new());
+static FRAME_ALLOC: LockFrameAllocator<BuddyFrameAllocator> = LockFrameAllocator::new(BuddyFrameAllocator::new());
 
-pub fn get_allocator() -> &'static LockFrameAllocator<impl FrameAllocator> {
+pub fn get_allocator() -> &'static LockFrameAllocator<impl ContiguousFrameAllocator> {
     &FRAME_ALLOC
 }
 
@@ -220,17 +702,296 @@ fn is_usable(region_ty: MemoryRegionType) -> bool {
 
 static NUM_TOTAL_FRAMES: OneShotManualInit<usize> = OneShotManualInit::uninit();
 
+/// The maximum number of bad page frames that can be tracked. Bad frames are tracked in a fixed-size table rather than on the heap, since
+/// the table must be consulted by the frame allocator and the heap may need to allocate frames.
+pub const MAX_BAD_FRAMES: usize = 64;
+
+/// The source of a report that a page frame is bad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadFrameSource {
+    /// The frame failed a memory test.
+    MemoryTest,
+    /// The processor reported a hardware error (e.g. an uncorrectable ECC error) affecting the frame.
+    MachineCheck,
+    /// A device driver reported that the frame is unreliable.
+    Driver,
+}
+
+/// The current state of a page frame that has been reported as bad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadFrameState {
+    /// The frame was reported as bad, but may still be in use or sitting in the frame allocator's free list. It will be quarantined once it
+    /// is next freed or allocated.
+    Pending,
+    /// The frame has been removed from circulation and will never be allocated again.
+    Quarantined,
+}
+
+/// Information about a page frame that has been reported as bad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadFrame {
+    pub frame: PhysAddr,
+    pub source: BadFrameSource,
+    pub state: BadFrameState,
+}
+
+/// A fixed-size table of page frames that have been reported as bad.
+pub struct BadFrameTable {
+    frames: [Option<BadFrame>; MAX_BAD_FRAMES],
+    len: usize,
+}
+
+impl BadFrameTable {
+    /// Creates a new empty bad frame table.
+    pub const fn new() -> BadFrameTable {
+        BadFrameTable {
+            frames: [None; MAX_BAD_FRAMES],
+            len: 0,
+        }
+    }
+
+    /// Gets the number of bad frames in this table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether this table contains no bad frames.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets an iterator over all bad frames in this table.
+    pub fn iter(&self) -> impl Iterator<Item = &BadFrame> {
+        self.frames[..self.len].iter().map(|f| f.as_ref().unwrap())
+    }
+
+    fn find_mut(&mut self, frame: PhysAddr) -> Option<&mut BadFrame> {
+        self.frames[..self.len]
+            .iter_mut()
+            .map(|f| f.as_mut().unwrap())
+            .find(|f| f.frame == frame)
+    }
+
+    /// Adds a frame to this table in the pending state. Returns `Ok(true)` if the frame was added, `Ok(false)` if the frame was already
+    /// present, or `Err(())` if this table is full.
+    pub fn insert(&mut self, frame: PhysAddr, source: BadFrameSource) -> Result<bool, ()> {
+        if self.find_mut(frame).is_some() {
+            Ok(false)
+        } else if self.len == MAX_BAD_FRAMES {
+            Err(())
+        } else {
+            self.frames[self.len] = Some(BadFrame {
+                frame,
+                source,
+                state: BadFrameState::Pending,
+            });
+            self.len += 1;
+            Ok(true)
+        }
+    }
+
+    /// Checks whether the provided frame is in this table and, if so, moves it into the quarantined state. Returns `true` if the frame is
+    /// bad and `false` otherwise.
+    pub fn quarantine(&mut self, frame: PhysAddr) -> bool {
+        if let Some(bad_frame) = self.find_mut(frame) {
+            bad_frame.state = BadFrameState::Quarantined;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+static BAD_FRAMES: UninterruptibleSpinlock<BadFrameTable> = UninterruptibleSpinlock::new(BadFrameTable::new());
+static NUM_BAD_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+fn quarantine_if_bad(frame: PhysAddr) -> bool {
+    NUM_BAD_FRAMES.load(Ordering::Relaxed) != 0 && BAD_FRAMES.lock().quarantine(frame)
+}
+
+/// Reports that the page frame containing the provided physical address is bad and should no longer be used.
+///
+/// The frame is excluded from all future allocations. If the frame is currently sitting in the frame allocator's free list, it will be
+/// quarantined the next time the allocator would otherwise have handed it out. If it is currently in use, it will be quarantined once it is
+/// freed by its owner.
+///
+/// Returns `false` if the frame could not be recorded because too many bad frames have already been reported.
+pub fn report_bad_frame(addr: PhysAddr, source: BadFrameSource) -> bool {
+    let frame = PhysAddr::new(addr.as_u64() & !(PAGE_SIZE as u64 - 1));
+
+    // TODO Migrate the contents of frames that are currently in use once reverse mappings are available
+    let result = BAD_FRAMES.lock().insert(frame, source);
+
+    match result {
+        Ok(true) => {
+            NUM_BAD_FRAMES.fetch_add(1, Ordering::Relaxed);
+            log!(Warning, "frame", "Frame {:#x} reported as bad by {:?}", frame.as_u64(), source);
+            true
+        },
+        Ok(false) => true,
+        Err(()) => {
+            log!(
+                Error,
+                "frame",
+                "Frame {:#x} reported as bad by {:?}, but the bad frame table is full",
+                frame.as_u64(),
+                source
+            );
+            false
+        },
+    }
+}
+
+/// Calls the provided function for each page frame that has been reported as bad.
+///
+/// The bad frame table is copied before calling the provided function, so it is safe for the function to allocate memory.
+pub fn for_each_bad_frame(mut f: impl FnMut(&BadFrame)) {
+    let table = BAD_FRAMES.lock();
+    let (frames, len) = (table.frames, table.len);
+
+    drop(table);
+
+    for bad_frame in frames[..len].iter().flatten() {
+        f(bad_frame);
+    }
+}
+
+bitflags! {
+    /// Flags describing how an allocated page frame is being used, stored in its [`FrameInfo`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FrameFlags: u32 {
+        /// The frame must stay where it is, e.g. because a device is accessing it using DMA, so its contents must not be swapped out or
+        /// migrated to another frame.
+        const PINNED = 0x1;
+    }
+}
+
+/// Metadata about a single page frame managed by the frame allocator.
+///
+/// The metadata of a frame is not reset when it is allocated, so that allocating frames stays cheap. Instead, it is reset when the frame is
+/// freed using [`release`], which is why any frame with non-default metadata must be freed that way rather than directly through the
+/// frame allocator.
+#[derive(Debug)]
+pub struct FrameInfo {
+    // The number of references to the frame beyond the one held by whoever allocated it, so that a newly allocated frame has a reference
+    // count of 1 without having to initialize anything.
+    extra_refs: AtomicU32,
+    flags: AtomicU32,
+}
+
+impl FrameInfo {
+    /// Gets the number of references to this frame. Frames that have never been passed to [`share`] have a single reference.
+    pub fn ref_count(&self) -> usize {
+        self.extra_refs.load(Ordering::Relaxed) as usize + 1
+    }
+
+    /// Gets the flags describing how this frame is being used.
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::Relaxed))
+    }
+
+    /// Sets the provided flags on this frame.
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::Relaxed);
+    }
+
+    /// Clears the provided flags on this frame.
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::Relaxed);
+    }
+}
+
+// The metadata table has one entry for every frame below the highest frame managed by the frame allocator, including frames that are not
+// usable as RAM, so that the entry of a frame can be found simply by indexing it with the frame number.
+static FRAME_INFO: OneShotManualInit<&'static [FrameInfo]> = OneShotManualInit::uninit();
+
+/// Gets the metadata of the page frame containing the provided physical address, or [`None`] if the frame is not managed by the frame
+/// allocator (e.g. because it is device memory).
+pub fn info(frame: PhysAddr) -> Option<&'static FrameInfo> {
+    FRAME_INFO.get().get((frame.as_u64() / PAGE_SIZE as u64) as usize)
+}
+
+/// Adds a reference to an allocated page frame, e.g. because it is about to be mapped copy-on-write into another address space. Every
+/// reference, including the one held by whoever originally allocated the frame, must eventually be dropped by calling [`release`].
+///
+/// # Panics
+///
+/// This function will panic if the frame is not managed by the frame allocator.
+pub fn share(frame: PhysAddr) {
+    let info = info(frame).unwrap_or_else(|| panic!("Attempt to share unmanaged frame {:#x}", frame.as_u64()));
+
+    info.extra_refs.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Gets the number of references to an allocated page frame. Frames that have never been passed to [`share`] have a single reference, as
+/// do frames that are not managed by the frame allocator.
+pub fn ref_count(frame: PhysAddr) -> usize {
+    info(frame).map_or(1, FrameInfo::ref_count)
+}
+
+/// Drops a reference to an allocated page frame, freeing it and resetting its metadata if this was the last reference. Returns `true` if
+/// the frame was freed.
+///
+/// # Safety
+///
+/// The caller must own one of the references to the frame and must not use the frame through that reference afterwards.
+pub unsafe fn release(frame: PhysAddr) -> bool {
+    if let Some(info) = info(frame) {
+        let dropped = info
+            .extra_refs
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |refs| refs.checked_sub(1));
+
+        if dropped.is_ok() {
+            return false;
+        }
+
+        info.flags.store(0, Ordering::Relaxed);
+    }
+
+    get_allocator().free_one(frame);
+    true
+}
+
 pub(crate) unsafe fn init(boot_info: &BootInfo) {
     let mut num_frames = 0;
-    let mut frame_alloc = get_allocator().lock();
+    let mut frame_alloc = FRAME_ALLOC.lock();
+
+    let free_regions = || {
+        boot_info
+            .memory_map
+            .iter()
+            .filter(|region| is_free(region.region_type))
+            .map(|region| (region.range.start_frame_number as usize, region.range.end_frame_number as usize))
+    };
+    let max_frame = free_regions().map(|(_, end)| end).max().unwrap_or(0);
+
+    // The buddy allocator's bitmap and the frame metadata table are placed at the start of the first free region that is large enough to
+    // hold both of them
+    let bitmap_frames = (BuddyFrameAllocator::bitmap_words(max_frame) * 8).div_ceil(PAGE_SIZE);
+    let info_frames = (max_frame * core::mem::size_of::<FrameInfo>()).div_ceil(PAGE_SIZE);
+    let reserved_start = free_regions()
+        .find(|&(start, end)| end - start >= bitmap_frames + info_frames)
+        .expect("No free region large enough for frame allocator bitmap")
+        .0;
+    let bitmap = get_phys_mem_ptr_slice::<u64>(PhysAddr::new((reserved_start * PAGE_SIZE) as u64), bitmap_frames * PAGE_SIZE / 8);
+    let info_table = get_phys_mem_ptr_slice::<FrameInfo>(PhysAddr::new(((reserved_start + bitmap_frames) * PAGE_SIZE) as u64), max_frame);
+
+    (*bitmap.ptr()).fill(0);
+    frame_alloc.init(bitmap.ptr() as *mut u64, max_frame);
+
+    // SAFETY: A FrameInfo consisting of all zero bytes is valid and describes a frame with a single reference and no flags
+    ptr::write_bytes(info_table.ptr() as *mut FrameInfo, 0, max_frame);
+    FRAME_INFO.set(&*info_table.into_raw());
+
+    for (start, end) in free_regions() {
+        if start == reserved_start {
+            frame_alloc.free_range(start + bitmap_frames + info_frames, end);
+        } else {
+            frame_alloc.free_range(start, end);
+        }
+    }
 
     for region in boot_info.memory_map.iter() {
-        if is_free(region.region_type) {
-            for frame_n in region.range.start_frame_number..region.range.end_frame_number {
-                frame_alloc.free_one(PhysAddr::new(frame_n * PAGE_SIZE as u64));
-            }
-        };
-
         if is_usable(region.region_type) {
             num_frames += region.range.end_frame_number - region.range.start_frame_number;
         };
@@ -245,11 +1006,16 @@ pub fn num_total_frames() -> usize {
 
 #[cfg(test)]
 mod tests {
+    use alloc::vec;
     use core::mem::MaybeUninit;
 
-    use super::{FrameAllocator, StackFrameAllocator, NUM_FRAMES_PER_PAGE};
+    use super::{
+        get_allocator, info, ref_count, release, share, BadFrameSource, BadFrameState, BadFrameTable, BuddyFrameAllocator,
+        ContiguousFrameAllocator, FrameAllocator, FrameFlags, FrameZone, StackFrameAllocator, MAX_BAD_FRAMES, NUM_FRAMES_PER_PAGE,
+    };
     use crate::arch::page::{get_phys_mem_ptr, PAGE_SIZE};
     use crate::arch::PhysAddr;
+    use crate::test_util::skip;
     use crate::util::PageAligned;
 
     static TEST_AREA: PageAligned<[[u8; PAGE_SIZE]; 10]> = PageAligned::new([[0; PAGE_SIZE]; 10]);
@@ -353,4 +1119,135 @@ mod tests {
             assert_eq!(get_test_page(0), allocator.stack_top.as_ref().unwrap().phys_addr());
         }
     }
+
+    /// Finds a block of pages in the test area that are physically contiguous and aligned to form a block of the provided order.
+    unsafe fn find_test_block(order: u32) -> Option<usize> {
+        let frames = 1 << order;
+
+        (0..=(TEST_AREA.len() - frames)).find(|&i| {
+            get_test_page(i).as_u64() % (frames * PAGE_SIZE) as u64 == 0
+                && (1..frames).all(|j| get_test_page(i + j).as_u64() == get_test_page(i).as_u64() + (j * PAGE_SIZE) as u64)
+        })
+    }
+
+    #[test_case]
+    fn test_buddy_split_merge() {
+        unsafe {
+            let Some(base) = find_test_block(2) else {
+                skip("test area is not physically contiguous");
+                return;
+            };
+            let num_frames = get_test_page(base + 3).as_u64() as usize / PAGE_SIZE + 1;
+            let mut bitmap = vec![0_u64; BuddyFrameAllocator::bitmap_words(num_frames)];
+            let mut allocator = BuddyFrameAllocator::new();
+
+            allocator.init(bitmap.as_mut_ptr(), num_frames);
+            assert_eq!(None, allocator.alloc_one());
+
+            // Freeing all four pages one at a time should merge them back into a single order 2 block
+            for i in 0..4 {
+                allocator.free_one(get_test_page(base + i));
+            }
+
+            assert_eq!(4, allocator.num_frames_available());
+            assert_eq!(None, allocator.alloc_contiguous(3));
+            assert_eq!(Some(get_test_page(base)), allocator.alloc_contiguous(2));
+            assert_eq!(None, allocator.alloc_one());
+
+            // Allocating a single page should split the block, leaving a block of each smaller order
+            allocator.free_contiguous(get_test_page(base), 2);
+            assert_eq!(Some(get_test_page(base)), allocator.alloc_one());
+            assert_eq!(Some(get_test_page(base + 2)), allocator.alloc_contiguous(1));
+            assert_eq!(Some(get_test_page(base + 1)), allocator.alloc_one());
+            assert_eq!(0, allocator.num_frames_available());
+
+            // Pages whose buddies are still allocated must not be merged
+            allocator.free_one(get_test_page(base + 1));
+            allocator.free_contiguous(get_test_page(base + 2), 1);
+            assert_eq!(None, allocator.alloc_contiguous(2));
+
+            allocator.free_one(get_test_page(base));
+            assert_eq!(Some(get_test_page(base)), allocator.alloc_contiguous(2));
+        }
+    }
+
+    #[test_case]
+    fn test_frame_zones() {
+        assert_eq!(FrameZone::Dma, FrameZone::of(PhysAddr::new(0)));
+        assert_eq!(FrameZone::Dma, FrameZone::of(PhysAddr::new((16 << 20) - PAGE_SIZE as u64)));
+        assert_eq!(FrameZone::Dma32, FrameZone::of(PhysAddr::new(16 << 20)));
+        assert_eq!(FrameZone::Normal, FrameZone::of(PhysAddr::new(1 << 32)));
+
+        assert_eq!(None, FrameZone::below(PhysAddr::new(1 << 20)));
+        assert_eq!(Some(FrameZone::Dma), FrameZone::below(PhysAddr::new(16 << 20)));
+        assert_eq!(Some(FrameZone::Dma32), FrameZone::below(PhysAddr::new(u64::MAX)));
+
+        let Some(frame) = get_allocator().alloc_one_in(FrameZone::Dma) else {
+            skip("no free frames below 16MiB");
+            return;
+        };
+
+        assert_eq!(FrameZone::Dma, FrameZone::of(frame));
+
+        // SAFETY: The frame was just allocated and is not in use by anything else
+        unsafe {
+            get_allocator().free_one(frame);
+        }
+    }
+
+    #[test_case]
+    fn test_bad_frame_table() {
+        let mut table = BadFrameTable::new();
+        let frame = PhysAddr::new(0x1000);
+
+        assert!(!table.quarantine(frame));
+        assert_eq!(Ok(true), table.insert(frame, BadFrameSource::Driver));
+        assert_eq!(Ok(false), table.insert(frame, BadFrameSource::MemoryTest));
+        assert_eq!(1, table.len());
+        assert_eq!(BadFrameState::Pending, table.iter().next().unwrap().state);
+
+        assert!(table.quarantine(frame));
+        assert_eq!(BadFrameState::Quarantined, table.iter().next().unwrap().state);
+        assert_eq!(BadFrameSource::Driver, table.iter().next().unwrap().source);
+    }
+
+    #[test_case]
+    fn test_bad_frame_table_full() {
+        let mut table = BadFrameTable::new();
+
+        for i in 0..MAX_BAD_FRAMES {
+            assert_eq!(
+                Ok(true),
+                table.insert(PhysAddr::new((i * PAGE_SIZE) as u64), BadFrameSource::MemoryTest)
+            );
+        }
+
+        assert_eq!(
+            Err(()),
+            table.insert(PhysAddr::new((MAX_BAD_FRAMES * PAGE_SIZE) as u64), BadFrameSource::MemoryTest)
+        );
+        assert_eq!(Ok(false), table.insert(PhysAddr::new(0), BadFrameSource::MemoryTest));
+    }
+
+    #[test_case]
+    fn test_shared_frame_ref_count() {
+        let frame = get_allocator().alloc_one().unwrap();
+
+        assert_eq!(1, ref_count(frame));
+
+        share(frame);
+        share(frame);
+        info(frame).unwrap().insert_flags(FrameFlags::PINNED);
+        assert_eq!(3, ref_count(frame));
+        assert_eq!(FrameFlags::PINNED, info(frame).unwrap().flags());
+
+        unsafe {
+            assert!(!release(frame));
+            assert!(!release(frame));
+            assert_eq!(1, ref_count(frame));
+            assert!(release(frame));
+        }
+
+        assert_eq!(FrameFlags::empty(), info(frame).unwrap().flags());
+    }
 }
@@ -0,0 +1,198 @@
+//! Reporting of the kernel's fixed memory footprint.
+//!
+//! The sizes of the kernel image's sections are found using symbols placed by the linker script, so that growth in the amount of memory
+//! the kernel uses before allocating anything can be tracked as features are added. Large statics can additionally be registered using the
+//! [`static_footprint!`](crate::static_footprint) macro, which places an entry in the `.static_footprints` section so that their sizes can
+//! be attributed to the subsystem that owns them.
+
+use alloc::collections::BTreeMap;
+use core::{ptr, slice};
+
+use super::early;
+use crate::arch::tls;
+use crate::log;
+
+/// A static registered using the [`static_footprint!`](crate::static_footprint) macro.
+#[derive(Debug)]
+pub struct StaticFootprint {
+    #[doc(hidden)]
+    pub subsys: &'static str,
+    #[doc(hidden)]
+    pub name: &'static str,
+    #[doc(hidden)]
+    pub size: usize,
+}
+
+impl StaticFootprint {
+    /// Gets the name of the subsystem that owns this static.
+    pub fn subsys(&self) -> &'static str {
+        self.subsys
+    }
+
+    /// Gets the name of this static.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Gets the size of this static in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[doc(hidden)]
+pub const fn size_of_static<T>(_: &T) -> usize {
+    core::mem::size_of::<T>()
+}
+
+/// Registers a static so that its size is included in the kernel's static footprint report, attributed to the provided subsystem.
+#[macro_export]
+macro_rules! static_footprint {
+    ($subsys:expr, $name:path) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".static_footprints"]
+            static FOOTPRINT: $crate::mem::image::StaticFootprint = $crate::mem::image::StaticFootprint {
+                subsys: $subsys,
+                name: stringify!($name),
+                size: $crate::mem::image::size_of_static(&$name),
+            };
+        };
+    };
+}
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+    static __static_footprints_start: u8;
+    static __static_footprints_end: u8;
+}
+
+/// The sizes of the sections of the kernel image, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSize {
+    pub text: usize,
+    pub rodata: usize,
+    pub data: usize,
+    pub bss: usize,
+    /// The size of the initialized part of the TLS template, which is copied into each thread's and core's TLS block.
+    pub tdata: usize,
+    /// The size of the zero-initialized part of the TLS template.
+    pub tbss: usize,
+}
+
+impl ImageSize {
+    /// Gets the total size of the sections that are loaded once, excluding the TLS template.
+    pub fn total(&self) -> usize {
+        self.text + self.rodata + self.data + self.bss
+    }
+
+    /// Gets the size of each TLS block created from the TLS template.
+    pub fn tls(&self) -> usize {
+        self.tdata + self.tbss
+    }
+}
+
+fn section_size(start: *const u8, end: *const u8) -> usize {
+    end as usize - start as usize
+}
+
+/// Gets the sizes of the sections of the kernel image.
+pub fn image_size() -> ImageSize {
+    let (tdata, tbss) = tls::template_size();
+
+    // SAFETY: These symbols are placed by the linker script and are never dereferenced
+    unsafe {
+        ImageSize {
+            text: section_size(ptr::addr_of!(__text_start), ptr::addr_of!(__text_end)),
+            rodata: section_size(ptr::addr_of!(__rodata_start), ptr::addr_of!(__rodata_end)),
+            data: section_size(ptr::addr_of!(__data_start), ptr::addr_of!(__data_end)),
+            bss: section_size(ptr::addr_of!(__bss_start), ptr::addr_of!(__bss_end)),
+            tdata,
+            tbss,
+        }
+    }
+}
+
+/// Gets all statics registered using the [`static_footprint!`](crate::static_footprint) macro.
+pub fn static_footprints() -> &'static [StaticFootprint] {
+    // SAFETY: The linker script places these symbols at the start and end of the section containing all StaticFootprint statics
+    //         registered using the static_footprint! macro.
+    unsafe {
+        let start = ptr::addr_of!(__static_footprints_start) as *const StaticFootprint;
+        let end = ptr::addr_of!(__static_footprints_end) as *const StaticFootprint;
+
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Gets the total size in bytes of the registered statics owned by each subsystem, sorted by subsystem name.
+pub fn static_footprint_by_subsys() -> BTreeMap<&'static str, usize> {
+    let mut totals = BTreeMap::new();
+
+    for footprint in static_footprints() {
+        *totals.entry(footprint.subsys).or_insert(0) += footprint.size;
+    }
+
+    totals
+}
+
+/// Writes a summary of the kernel's fixed memory footprint to the log.
+pub fn log_report() {
+    let size = image_size();
+    let (early_used, early_total) = early::usage();
+
+    log!(
+        Debug,
+        "mem",
+        "Kernel image: {}KiB text, {}KiB rodata, {}KiB data, {}KiB bss, {}B TLS",
+        size.text / 1024,
+        size.rodata / 1024,
+        size.data / 1024,
+        size.bss / 1024,
+        size.tls()
+    );
+    log!(
+        Debug,
+        "mem",
+        "Early allocation pool usage: {}KiB/{}KiB",
+        early_used / 1024,
+        early_total / 1024
+    );
+
+    for (subsys, total) in static_footprint_by_subsys() {
+        log!(Debug, "mem", "Static footprint of {}: {}KiB", subsys, total / 1024);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_image_size() {
+        let size = image_size();
+
+        assert!(size.text > 0);
+        assert!(size.rodata > 0);
+        assert!(size.bss >= early::EARLY_ALLOC_SIZE);
+    }
+
+    #[test_case]
+    fn test_static_footprints() {
+        let early_pool = static_footprints()
+            .iter()
+            .find(|footprint| footprint.name() == "EARLY_ALLOC_AREA")
+            .unwrap();
+
+        assert_eq!("mem", early_pool.subsys());
+        assert_eq!(early::EARLY_ALLOC_SIZE, early_pool.size());
+        assert!(static_footprint_by_subsys()["mem"] >= early::EARLY_ALLOC_SIZE);
+    }
+}
@@ -0,0 +1,528 @@
+//! A lightweight kernel address sanitizer for the kernel heap.
+//!
+//! When the `kasan` feature is enabled, every 8-byte granule of the kernel heap is described by a byte of shadow memory recording whether
+//! the granule can be accessed. Allocations made through [`DefaultAlloc`](super::DefaultAlloc) unpoison the requested bytes and poison the
+//! unused slack after them up to the end of their slab object or page, and freed allocations are poisoned entirely. Freed allocations are
+//! then held in a quarantine for a while before actually being freed, so that a dangling pointer keeps pointing at poisoned memory rather
+//! than at a new allocation.
+//!
+//! Accesses are checked against the shadow memory by [`check_read`] and [`check_write`]. Building the kernel with
+//! `-Zsanitizer=kernel-address -Cllvm-args=-asan-instrumentation-with-call-threshold=0 -Cllvm-args=-asan-stack=0
+//! -Cllvm-args=-asan-globals=0` makes the compiler call these checks before every memory access. Without compiler instrumentation, the
+//! allocator still catches double and invalid frees, and the poisoned memory is filled with a pattern that is validated when it is freed or
+//! leaves the quarantine, catching writes past the end of an allocation or after it has been freed.
+//!
+//! The shadow memory only covers the first [`SHADOW_COVERAGE`] bytes of kernel virtual address space available when it is set up, which is
+//! where the kernel heap lives since kernel virtual memory is allocated first-fit. Pages of shadow memory are only backed by a page frame
+//! once something in the granules they describe is poisoned. This mode cannot be combined with the `slab_debug` feature, whose redzones
+//! would be flagged as poisoned when the slab allocators validate them.
+
+#[cfg(all(feature = "kasan", feature = "slab_debug"))]
+compile_error!("the kasan and slab_debug features cannot be enabled at the same time");
+
+use core::alloc::Layout;
+use core::fmt;
+
+/// Whether the kernel heap is checked using shadow memory.
+pub const ENABLED: bool = cfg!(feature = "kasan");
+
+/// The number of bytes of kernel virtual address space described by each byte of shadow memory.
+pub const GRANULE_SIZE: usize = 8;
+
+/// The size of the range of kernel virtual address space covered by shadow memory.
+pub const SHADOW_COVERAGE: u64 = 64 << 30;
+
+/// The number of freed allocations held in quarantine before actually being freed.
+pub const QUARANTINE_SIZE: usize = 1024;
+
+/// The largest allocation that is held in quarantine when freed. Larger allocations are freed immediately to avoid holding on to too much
+/// memory, so using them after they have been freed is not detected.
+pub const QUARANTINE_MAX_SIZE: usize = 64 * 1024;
+
+const SHADOW_REDZONE: u8 = 0xfa;
+const SHADOW_FREED: u8 = 0xfb;
+
+const REDZONE_BYTE: u8 = 0xbb;
+const POISON_BYTE: u8 = 0x6b;
+
+/// The kind of invalid heap access detected by the address sanitizer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadAccessKind {
+    OutOfBounds,
+    UseAfterFree,
+    DoubleFree,
+    InvalidFree,
+}
+
+impl fmt::Display for BadAccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BadAccessKind::OutOfBounds => write!(f, "heap-out-of-bounds"),
+            BadAccessKind::UseAfterFree => write!(f, "use-after-free"),
+            BadAccessKind::DoubleFree => write!(f, "double-free"),
+            BadAccessKind::InvalidFree => write!(f, "invalid-free"),
+        }
+    }
+}
+
+impl BadAccessKind {
+    fn from_shadow(shadow: u8) -> BadAccessKind {
+        if shadow == SHADOW_FREED {
+            BadAccessKind::UseAfterFree
+        } else {
+            BadAccessKind::OutOfBounds
+        }
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "kasan")] {
+        mod shadow {
+            use core::alloc::Layout;
+            use core::ptr;
+            use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+            use super::*;
+            use crate::arch::page::{get_phys_mem_ptr_slice, AddressSpace, PageFlags, PAGE_SIZE};
+            use crate::arch::VirtAddr;
+            use crate::mem::frame::{self, FrameAllocator};
+            use crate::sync::UninterruptibleSpinlock;
+
+            const SHADOW_SIZE: u64 = SHADOW_COVERAGE / GRANULE_SIZE as u64;
+            const SHADOW_PAGES: usize = SHADOW_SIZE as usize / PAGE_SIZE;
+
+            static ACTIVE: AtomicBool = AtomicBool::new(false);
+            static COVERED_START: AtomicU64 = AtomicU64::new(0);
+            static SHADOW_START: AtomicU64 = AtomicU64::new(0);
+
+            /// A bitmap of which pages of shadow memory are backed by a page frame. Pages that are not backed describe granules that have
+            /// never been poisoned, so they do not need to be read.
+            static POPULATED: [AtomicU64; SHADOW_PAGES / 64] = [const { AtomicU64::new(0) }; SHADOW_PAGES / 64];
+            static POPULATE_LOCK: UninterruptibleSpinlock<()> = UninterruptibleSpinlock::new(());
+
+            crate::static_footprint!("kasan", POPULATED);
+
+            #[derive(Clone, Copy)]
+            struct QuarantineEntry {
+                ptr: *mut u8,
+                layout: Layout,
+                usable_size: usize,
+            }
+
+            struct Quarantine {
+                entries: [Option<QuarantineEntry>; QUARANTINE_SIZE],
+                next: usize,
+            }
+
+            unsafe impl Send for Quarantine {}
+
+            static QUARANTINE: UninterruptibleSpinlock<Quarantine> = UninterruptibleSpinlock::new(Quarantine {
+                entries: [None; QUARANTINE_SIZE],
+                next: 0,
+            });
+
+            #[no_sanitize(address)]
+            fn shadow_offset(addr: usize) -> Option<usize> {
+                let covered_start = COVERED_START.load(Ordering::Relaxed);
+
+                (addr as u64)
+                    .checked_sub(covered_start)
+                    .filter(|&offset| offset < SHADOW_COVERAGE)
+                    .map(|offset| (offset / GRANULE_SIZE as u64) as usize)
+            }
+
+            #[no_sanitize(address)]
+            fn is_populated(offset: usize) -> bool {
+                let page = offset / PAGE_SIZE;
+
+                POPULATED[page / 64].load(Ordering::Acquire) & (1 << (page % 64)) != 0
+            }
+
+            /// Ensures that the page of shadow memory containing the provided offset is backed by a page frame.
+            #[no_sanitize(address)]
+            fn populate(offset: usize) {
+                if is_populated(offset) {
+                    return;
+                }
+
+                let _guard = POPULATE_LOCK.lock();
+                let page = offset / PAGE_SIZE;
+
+                if is_populated(offset) {
+                    return;
+                }
+
+                let frame = frame::get_allocator()
+                    .alloc_one()
+                    .expect("Out of memory populating kernel address sanitizer shadow memory");
+
+                // SAFETY: The frame was just allocated and the shadow page was reserved by init, but isn't mapped yet
+                unsafe {
+                    (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).fill(0);
+                    AddressSpace::kernel().set_page_kernel(
+                        VirtAddr::new(SHADOW_START.load(Ordering::Relaxed) + (page * PAGE_SIZE) as u64),
+                        Some((frame, PageFlags::WRITEABLE)),
+                    );
+                }
+
+                POPULATED[page / 64].fetch_or(1 << (page % 64), Ordering::Release);
+            }
+
+            #[no_sanitize(address)]
+            fn read_shadow(addr: usize) -> u8 {
+                match shadow_offset(addr) {
+                    Some(offset) if is_populated(offset) => unsafe {
+                        *((SHADOW_START.load(Ordering::Relaxed) as usize + offset) as *const u8)
+                    },
+                    _ => 0,
+                }
+            }
+
+            /// Sets the shadow of every granule overlapping `len` bytes starting at the granule-aligned address `addr`.
+            #[no_sanitize(address)]
+            fn write_shadow(addr: usize, len: usize, val: u8) {
+                for granule in (addr..addr + len).step_by(GRANULE_SIZE) {
+                    if let Some(offset) = shadow_offset(granule) {
+                        populate(offset);
+
+                        // SAFETY: The shadow page was just populated
+                        unsafe {
+                            *((SHADOW_START.load(Ordering::Relaxed) as usize + offset) as *mut u8) = val;
+                        }
+                    }
+                }
+            }
+
+            #[no_sanitize(address)]
+            pub fn find_bad_access(addr: usize, size: usize) -> Option<(usize, BadAccessKind)> {
+                if !ACTIVE.load(Ordering::Relaxed) || size == 0 {
+                    return None;
+                }
+
+                let end = addr.checked_add(size)?;
+                let mut granule = addr - addr % GRANULE_SIZE;
+
+                while granule < end {
+                    let shadow = read_shadow(granule);
+                    let accessible_end = match shadow {
+                        0 => granule + GRANULE_SIZE,
+                        1..=7 => granule + shadow as usize,
+                        _ => granule,
+                    };
+
+                    if accessible_end < end.min(granule + GRANULE_SIZE) {
+                        return Some((addr.max(accessible_end), BadAccessKind::from_shadow(shadow)));
+                    }
+
+                    granule += GRANULE_SIZE;
+                }
+
+                None
+            }
+
+            #[no_sanitize(address)]
+            pub fn report(addr: usize, size: usize, is_write: bool, kind: BadAccessKind) -> ! {
+                ACTIVE.store(false, Ordering::Relaxed);
+                panic!(
+                    "KASAN: {} {} of size {} at {:#x}",
+                    kind,
+                    if is_write { "write" } else { "read" },
+                    size,
+                    addr
+                );
+            }
+
+            #[no_sanitize(address)]
+            fn check_pattern(addr: usize, len: usize, pattern: u8) -> Option<usize> {
+                // SAFETY: The caller only passes in memory belonging to a live or quarantined allocation
+                (0..len).map(|i| addr + i).find(|&a| unsafe { *(a as *const u8) } != pattern)
+            }
+
+            #[no_sanitize(address)]
+            pub fn on_alloc(ptr: *mut u8, size: usize, usable_size: usize) {
+                if !ACTIVE.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let addr = ptr as usize;
+                let accessible = size.next_multiple_of(GRANULE_SIZE);
+
+                write_shadow(addr, accessible, 0);
+
+                if size % GRANULE_SIZE != 0 {
+                    write_shadow(addr + size - size % GRANULE_SIZE, 1, (size % GRANULE_SIZE) as u8);
+                }
+
+                if usable_size > size {
+                    write_shadow(addr + accessible, usable_size - accessible, SHADOW_REDZONE);
+
+                    // SAFETY: The slack after the allocation belongs to it and isn't used by anything else
+                    unsafe {
+                        ptr::write_bytes(ptr.add(size), REDZONE_BYTE, usable_size - size);
+                    }
+                }
+            }
+
+            #[no_sanitize(address)]
+            pub fn on_dealloc(ptr: *mut u8, layout: Layout, usable_size: usize) -> Option<(*mut u8, Layout)> {
+                if !ACTIVE.load(Ordering::Relaxed) {
+                    return Some((ptr, layout));
+                }
+
+                let addr = ptr as usize;
+
+                match read_shadow(addr) {
+                    SHADOW_FREED => report(addr, layout.size(), true, BadAccessKind::DoubleFree),
+                    SHADOW_REDZONE => report(addr, layout.size(), true, BadAccessKind::InvalidFree),
+                    _ => {},
+                }
+
+                // Allocations made before the heap started being checked don't have a redzone to validate
+                let size = layout.size();
+                let has_redzone = if size % GRANULE_SIZE != 0 {
+                    read_shadow(addr + size - size % GRANULE_SIZE) == (size % GRANULE_SIZE) as u8
+                } else {
+                    usable_size > size && read_shadow(addr + size) == SHADOW_REDZONE
+                };
+
+                if has_redzone {
+                    if let Some(bad) = check_pattern(addr + size, usable_size - size, REDZONE_BYTE) {
+                        report(bad, 1, true, BadAccessKind::OutOfBounds);
+                    }
+                }
+
+                if usable_size > QUARANTINE_MAX_SIZE {
+                    write_shadow(addr, usable_size, 0);
+                    return Some((ptr, layout));
+                }
+
+                write_shadow(addr, usable_size, SHADOW_FREED);
+
+                // SAFETY: The allocation is being freed, so nothing should be using it anymore
+                unsafe {
+                    ptr::write_bytes(ptr, POISON_BYTE, usable_size);
+                }
+
+                let evicted = {
+                    let mut quarantine = QUARANTINE.lock();
+                    let next = quarantine.next;
+
+                    quarantine.next = (next + 1) % QUARANTINE_SIZE;
+                    quarantine.entries[next].replace(QuarantineEntry {
+                        ptr,
+                        layout,
+                        usable_size,
+                    })
+                }?;
+
+                if let Some(bad) = check_pattern(evicted.ptr as usize, evicted.usable_size, POISON_BYTE) {
+                    report(bad, 1, true, BadAccessKind::UseAfterFree);
+                }
+
+                // Memory that has actually been freed may be reused by something other than the heap, so it can't be left poisoned
+                write_shadow(evicted.ptr as usize, evicted.usable_size, 0);
+
+                Some((evicted.ptr, evicted.layout))
+            }
+
+            pub unsafe fn init() {
+                let covered = {
+                    let mut addrspace = AddressSpace::kernel();
+                    let Some(covered) = addrspace.virtual_alloc().free_regions().next() else {
+                        return;
+                    };
+
+                    covered.start().as_u64()
+                };
+
+                let Some(shadow) = AddressSpace::kernel().virtual_alloc().alloc(SHADOW_SIZE as usize) else {
+                    crate::log!(Warning, "kasan", "Not enough kernel address space for shadow memory, heap checking is disabled");
+                    return;
+                };
+
+                COVERED_START.store(covered, Ordering::Relaxed);
+                SHADOW_START.store(shadow.start().as_u64(), Ordering::Relaxed);
+                ACTIVE.store(true, Ordering::Release);
+
+                crate::log!(
+                    Info,
+                    "kasan",
+                    "Checking heap accesses between {:#x} and {:#x}",
+                    covered,
+                    covered + SHADOW_COVERAGE
+                );
+            }
+
+            macro_rules! asan_callbacks {
+                ($($size:literal => $load:ident, $load_noabort:ident, $store:ident, $store_noabort:ident;)*) => {
+                    $(
+                        #[no_mangle]
+                        #[no_sanitize(address)]
+                        pub extern "C" fn $load(addr: usize) {
+                            super::check_read(addr as *const u8, $size);
+                        }
+
+                        #[no_mangle]
+                        #[no_sanitize(address)]
+                        pub extern "C" fn $load_noabort(addr: usize) {
+                            super::check_read(addr as *const u8, $size);
+                        }
+
+                        #[no_mangle]
+                        #[no_sanitize(address)]
+                        pub extern "C" fn $store(addr: usize) {
+                            super::check_write(addr as *mut u8, $size);
+                        }
+
+                        #[no_mangle]
+                        #[no_sanitize(address)]
+                        pub extern "C" fn $store_noabort(addr: usize) {
+                            super::check_write(addr as *mut u8, $size);
+                        }
+                    )*
+                };
+            }
+
+            asan_callbacks! {
+                1 => __asan_load1, __asan_load1_noabort, __asan_store1, __asan_store1_noabort;
+                2 => __asan_load2, __asan_load2_noabort, __asan_store2, __asan_store2_noabort;
+                4 => __asan_load4, __asan_load4_noabort, __asan_store4, __asan_store4_noabort;
+                8 => __asan_load8, __asan_load8_noabort, __asan_store8, __asan_store8_noabort;
+                16 => __asan_load16, __asan_load16_noabort, __asan_store16, __asan_store16_noabort;
+            }
+
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn __asan_loadN(addr: usize, size: usize) {
+                super::check_read(addr as *const u8, size);
+            }
+
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+                super::check_read(addr as *const u8, size);
+            }
+
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn __asan_storeN(addr: usize, size: usize) {
+                super::check_write(addr as *mut u8, size);
+            }
+
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+                super::check_write(addr as *mut u8, size);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn __asan_handle_no_return() {}
+        }
+    } else {
+        mod shadow {
+            use core::alloc::Layout;
+
+            use super::BadAccessKind;
+
+            pub fn find_bad_access(_: usize, _: usize) -> Option<(usize, BadAccessKind)> {
+                None
+            }
+
+            pub fn report(_: usize, _: usize, _: bool, _: BadAccessKind) -> ! {
+                unreachable!()
+            }
+
+            pub fn on_alloc(_: *mut u8, _: usize, _: usize) {}
+
+            pub fn on_dealloc(ptr: *mut u8, layout: Layout, _: usize) -> Option<(*mut u8, Layout)> {
+                Some((ptr, layout))
+            }
+
+            pub unsafe fn init() {}
+        }
+    }
+}
+
+/// Unpoisons a new allocation of `size` bytes whose slab object or pages are `usable_size` bytes long, poisoning the slack after it.
+#[inline(always)]
+pub(super) fn on_alloc(ptr: *mut u8, size: usize, usable_size: usize) {
+    shadow::on_alloc(ptr, size, usable_size);
+}
+
+/// Checks and poisons an allocation that is being freed and places it in quarantine. Returns the allocation that should actually be freed
+/// now, which is either the provided allocation if the heap isn't being checked or the oldest allocation evicted from the quarantine.
+#[inline(always)]
+pub(super) fn on_dealloc(ptr: *mut u8, layout: Layout, usable_size: usize) -> Option<(*mut u8, Layout)> {
+    shadow::on_dealloc(ptr, layout, usable_size)
+}
+
+/// Checks whether reading `size` bytes starting at `ptr` would access poisoned heap memory. Returns the first address that is poisoned
+/// along with the reason it is poisoned, or [`None`] if the access is allowed or the heap isn't being checked.
+#[cfg_attr(feature = "kasan", no_sanitize(address))]
+pub fn find_bad_access(ptr: *const u8, size: usize) -> Option<(usize, BadAccessKind)> {
+    shadow::find_bad_access(ptr as usize, size)
+}
+
+/// Panics if reading `size` bytes starting at `ptr` would access poisoned heap memory.
+#[inline]
+#[cfg_attr(feature = "kasan", no_sanitize(address))]
+pub fn check_read(ptr: *const u8, size: usize) {
+    if let Some((addr, kind)) = find_bad_access(ptr, size) {
+        shadow::report(addr, size, false, kind);
+    }
+}
+
+/// Panics if writing `size` bytes starting at `ptr` would access poisoned heap memory.
+#[inline]
+#[cfg_attr(feature = "kasan", no_sanitize(address))]
+pub fn check_write(ptr: *mut u8, size: usize) {
+    if let Some((addr, kind)) = find_bad_access(ptr, size) {
+        shadow::report(addr, size, true, kind);
+    }
+}
+
+/// Reserves the shadow memory and starts checking the heap. This can only be done once the kernel heap has been set up.
+unsafe fn init() {
+    shadow::init();
+}
+
+crate::initcall!(arch, "kasan", init);
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+
+    use super::*;
+
+    #[test_case]
+    fn test_kasan_out_of_bounds() {
+        if !ENABLED {
+            crate::test_util::skip("kasan feature is not enabled");
+            return;
+        }
+
+        let buf = Box::new([0_u8; 20]);
+        let ptr = buf.as_ptr();
+
+        assert_eq!(None, find_bad_access(ptr, 20));
+        assert_eq!(Some((ptr as usize + 20, BadAccessKind::OutOfBounds)), find_bad_access(ptr, 21));
+        assert_eq!(
+            Some((ptr as usize + 24, BadAccessKind::OutOfBounds)),
+            find_bad_access(unsafe { ptr.add(24) }, 1)
+        );
+    }
+
+    #[test_case]
+    fn test_kasan_use_after_free() {
+        if !ENABLED {
+            crate::test_util::skip("kasan feature is not enabled");
+            return;
+        }
+
+        let buf = Box::new([0_u64; 4]);
+        let ptr = buf.as_ptr() as *const u8;
+
+        drop(buf);
+        assert_eq!(Some((ptr as usize, BadAccessKind::UseAfterFree)), find_bad_access(ptr, 8));
+    }
+}
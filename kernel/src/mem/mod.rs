@@ -1,22 +1,219 @@
-use alloc::alloc::handle_alloc_error;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::mem::MaybeUninit;
 use core::ptr::{self, NonNull};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use frame::FrameAllocator;
+use frame::{ContiguousFrameAllocator, FrameAllocator};
+use tlb::TlbShootdown;
 use virt::VirtualAllocRegion;
 
-use crate::arch::page::{AddressSpace, PageFlags, PAGE_SIZE};
-use crate::arch::VirtAddr;
+use crate::arch::page::{get_phys_mem_ptr_slice, AddressSpace, PageFlags, HUGE_PAGE_SIZE, PAGE_SIZE};
+use crate::arch::{PhysAddr, VirtAddr};
+use crate::log;
+use crate::sync::UninterruptibleSpinlock;
 
+pub mod dma;
 pub mod early;
+pub mod fault;
 pub mod frame;
+pub mod image;
+pub mod kasan;
+pub mod oom;
+pub mod profile;
+pub mod region;
 pub mod slab;
+pub mod swap;
+pub mod tlb;
+pub mod usermap;
 pub mod virt;
+pub mod zram;
+
+/// The order of the physically contiguous blocks of page frames used to back huge pages.
+const HUGE_PAGE_ORDER: u32 = (HUGE_PAGE_SIZE / PAGE_SIZE).trailing_zeros();
 
 pub struct PageBasedAlloc;
 
+/// The number of pages currently mapped by [`PageBasedAlloc`] and [`LazyPageAlloc`].
+static PAGE_ALLOC_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+impl PageBasedAlloc {
+    /// Gets the number of pages currently mapped to back allocations made using this allocator.
+    pub fn pages_in_use() -> usize {
+        PAGE_ALLOC_PAGES.load(Ordering::Relaxed)
+    }
+
+    /// Attempts to allocate and map `num_pages` pages in a virtual region aligned to a huge page, using huge pages to map as much of the
+    /// region as possible to reduce TLB pressure and the amount of memory used by page tables. Returns [`None`] without allocating anything
+    /// if not enough physically contiguous memory is available or the page tables for the huge pages could not be allocated, in which case
+    /// the region should be mapped using regular pages instead.
+    fn allocate_huge(addrspace: &mut AddressSpace, num_pages: usize) -> Option<VirtAddr> {
+        let size = num_pages * PAGE_SIZE;
+        let padded_region = addrspace.virtual_alloc().alloc(size + HUGE_PAGE_SIZE - PAGE_SIZE)?;
+        let start = VirtAddr::new((padded_region.start().as_u64() + HUGE_PAGE_SIZE as u64 - 1) & !(HUGE_PAGE_SIZE as u64 - 1));
+
+        // SAFETY: Both of these regions lie within the region that was just allocated and will not be used
+        unsafe {
+            if start != padded_region.start() {
+                addrspace
+                    .virtual_alloc()
+                    .free(VirtualAllocRegion::new(padded_region.start(), start));
+            }
+
+            if start + size != padded_region.end() {
+                addrspace
+                    .virtual_alloc()
+                    .free(VirtualAllocRegion::new(start + size, padded_region.end()));
+            }
+        }
+
+        let num_huge_pages = size / HUGE_PAGE_SIZE;
+        let mut num_pages_mapped = 0;
+
+        while num_pages_mapped < num_pages {
+            let page_ptr = start + num_pages_mapped * PAGE_SIZE;
+            let is_huge = num_pages_mapped < num_huge_pages * (HUGE_PAGE_SIZE / PAGE_SIZE);
+            let frame = if is_huge {
+                frame::get_allocator().alloc_contiguous(HUGE_PAGE_ORDER)
+            } else {
+                frame::get_allocator().alloc_one()
+            };
+
+            let mapped = match frame {
+                Some(frame) if is_huge => {
+                    // SAFETY: The virtual region was just allocated, so nothing else is mapped there
+                    let result = unsafe { addrspace.set_huge_page_kernel(page_ptr, Some((frame, PageFlags::WRITEABLE))) };
+
+                    if result.is_err() {
+                        // SAFETY: The frames were just allocated and failed to be mapped, so nothing else can be using them
+                        unsafe { frame::get_allocator().free_contiguous(frame, HUGE_PAGE_ORDER) };
+                        false
+                    } else {
+                        num_pages_mapped += HUGE_PAGE_SIZE / PAGE_SIZE;
+                        true
+                    }
+                },
+                Some(frame) => {
+                    // SAFETY: The virtual region was just allocated, so nothing else is mapped there
+                    unsafe { addrspace.set_page_kernel(page_ptr, Some((frame, PageFlags::WRITEABLE))) };
+                    num_pages_mapped += 1;
+                    true
+                },
+                None => false,
+            };
+
+            if !mapped {
+                // SAFETY: Only the pages that were just mapped are unmapped and nothing else has access to them yet
+                unsafe {
+                    PageBasedAlloc::unmap_and_free(addrspace, start, num_pages_mapped);
+                    addrspace.virtual_alloc().free(VirtualAllocRegion::new(start, start + size));
+                }
+
+                return None;
+            }
+        }
+
+        Some(start)
+    }
+
+    /// Unmaps `num_pages` pages starting at the provided address and frees the page frames that were mapped there, including any huge
+    /// pages. The virtual region itself is not freed, but the pages are invalidated on all CPU cores before this returns, so it can be
+    /// freed immediately afterwards.
+    ///
+    /// # Safety
+    ///
+    /// All of the pages must be mapped and must have been allocated by this allocator, and nothing may access them afterwards.
+    unsafe fn unmap_and_free(addrspace: &mut AddressSpace, start: VirtAddr, num_pages: usize) {
+        let mut shootdown = TlbShootdown::new();
+        let mut frames = [MaybeUninit::uninit(); tlb::MAX_SHOOTDOWN_PAGES];
+        let mut num_frames = 0;
+        let mut i = 0;
+
+        while i < num_pages {
+            let page = start + i * PAGE_SIZE;
+            let frame = addrspace.get_page(page).unwrap().0;
+
+            if addrspace.is_huge_page(page) {
+                addrspace
+                    .set_huge_page_kernel(page, None)
+                    .expect("unmapping a huge page never allocates");
+                shootdown.add(page);
+                shootdown.flush();
+                frame::get_allocator().free_contiguous(frame, HUGE_PAGE_ORDER);
+                i += HUGE_PAGE_SIZE / PAGE_SIZE;
+            } else {
+                addrspace.set_page_kernel(page, None);
+                shootdown.add(page);
+                frames[num_frames] = MaybeUninit::new(frame);
+                num_frames += 1;
+                i += 1;
+
+                if num_frames == frames.len() {
+                    flush_and_free(&mut shootdown, &frames[..num_frames]);
+                    num_frames = 0;
+                }
+            }
+        }
+
+        flush_and_free(&mut shootdown, &frames[..num_frames]);
+    }
+
+    /// Allocates and maps `num_pages` pages preceded by a single unmapped guard page, returning the address of the first mapped page. Unlike
+    /// allocations made through [`Allocator::allocate`], these pages are never mapped using huge pages, so the guard page is never part of a
+    /// huge page.
+    pub fn allocate_guarded(num_pages: usize) -> Result<VirtAddr, AllocError> {
+        let mut addrspace = AddressSpace::kernel();
+        let region = addrspace.virtual_alloc().alloc((num_pages + 1) * PAGE_SIZE).ok_or(AllocError)?;
+        let start = region.start() + PAGE_SIZE;
+
+        for i in 0..num_pages {
+            let Some(frame) = frame::get_allocator().alloc_one() else {
+                // SAFETY: Only the pages that were just mapped are unmapped and nothing else has access to them yet
+                unsafe {
+                    PageBasedAlloc::unmap_and_free(&mut addrspace, start, i);
+                    addrspace.virtual_alloc().free(region);
+                }
+
+                return Err(AllocError);
+            };
+
+            // SAFETY: The virtual region was just allocated, so nothing else is mapped there
+            unsafe { addrspace.set_page_kernel(start + i * PAGE_SIZE, Some((frame, PageFlags::WRITEABLE))) };
+        }
+
+        PAGE_ALLOC_PAGES.fetch_add(num_pages, Ordering::Relaxed);
+        Ok(start)
+    }
+
+    /// Frees pages allocated using [`PageBasedAlloc::allocate_guarded`] along with their guard page.
+    ///
+    /// # Safety
+    ///
+    /// The pages must have been allocated using [`PageBasedAlloc::allocate_guarded`] with the same number of pages, and nothing may access
+    /// them afterwards.
+    pub unsafe fn deallocate_guarded(start: VirtAddr, num_pages: usize) {
+        let mut addrspace = AddressSpace::kernel();
+
+        PageBasedAlloc::unmap_and_free(&mut addrspace, start, num_pages);
+        PAGE_ALLOC_PAGES.fetch_sub(num_pages, Ordering::Relaxed);
+
+        addrspace
+            .virtual_alloc()
+            .free(VirtualAllocRegion::new(start - PAGE_SIZE as u64, start + num_pages * PAGE_SIZE));
+    }
+}
+
+/// Invalidates the pages in the provided shootdown on all CPU cores, then frees the provided page frames that they used to map.
+///
+/// # Safety
+///
+/// The page frames must have been unmapped from every page that mapped them and all such pages must be part of the shootdown.
+unsafe fn flush_and_free(shootdown: &mut TlbShootdown, frames: &[MaybeUninit<PhysAddr>]) {
+    shootdown.flush();
+    frame::get_allocator().free_many(MaybeUninit::slice_assume_init_ref(frames));
+}
+
 unsafe impl Allocator for PageBasedAlloc {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         if layout.size() == 0 {
@@ -31,6 +228,16 @@ unsafe impl Allocator for PageBasedAlloc {
         let mut frames = [MaybeUninit::uninit(); 16];
         let num_pages = layout.size().div_ceil(PAGE_SIZE);
 
+        if num_pages * PAGE_SIZE >= HUGE_PAGE_SIZE {
+            if let Some(start_ptr) = PageBasedAlloc::allocate_huge(&mut addrspace, num_pages) {
+                PAGE_ALLOC_PAGES.fetch_add(num_pages, Ordering::Relaxed);
+                return Ok(NonNull::from_raw_parts(
+                    NonNull::new(start_ptr.as_mut_ptr()).unwrap(),
+                    num_pages * PAGE_SIZE,
+                ));
+            }
+        }
+
         let virt_region = if let Some(virt_region) = addrspace.virtual_alloc().alloc(num_pages * PAGE_SIZE) {
             virt_region
         } else {
@@ -81,6 +288,7 @@ unsafe impl Allocator for PageBasedAlloc {
             num_pages_allocated += batch_num_pages;
         }
 
+        PAGE_ALLOC_PAGES.fetch_add(num_pages, Ordering::Relaxed);
         Ok(NonNull::from_raw_parts(
             NonNull::new(start_ptr.as_mut_ptr()).unwrap(),
             num_pages * PAGE_SIZE,
@@ -90,25 +298,10 @@ unsafe impl Allocator for PageBasedAlloc {
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         let ptr = VirtAddr::from_ptr(ptr.as_ptr());
         let mut addrspace = AddressSpace::kernel();
-        let mut frames = [MaybeUninit::uninit(); 16];
         let num_pages = layout.size().div_ceil(PAGE_SIZE);
 
-        let mut num_pages_freed = 0;
-        while num_pages_freed < num_pages {
-            let batch_num_frames = (num_pages - num_pages_freed).min(16);
-
-            for (i, f) in frames.iter_mut().enumerate().take(batch_num_frames) {
-                let page = ptr + (num_pages_freed + i) * PAGE_SIZE;
-                *f = MaybeUninit::new(addrspace.get_page(page).unwrap().0);
-                addrspace.set_page_kernel(page, None);
-            }
-
-            unsafe {
-                frame::get_allocator().free_many(MaybeUninit::slice_assume_init_ref(&frames[..batch_num_frames]));
-            }
-
-            num_pages_freed += batch_num_frames;
-        }
+        PageBasedAlloc::unmap_and_free(&mut addrspace, ptr, num_pages);
+        PAGE_ALLOC_PAGES.fetch_sub(num_pages, Ordering::Relaxed);
 
         unsafe {
             addrspace
@@ -147,6 +340,18 @@ unsafe impl Allocator for PageBasedAlloc {
         let num_pages_old = old_layout.size().div_ceil(PAGE_SIZE);
         let num_pages_new = new_layout.size().div_ceil(PAGE_SIZE);
 
+        // Allocations that may be mapped using huge pages cannot simply have pages unmapped from their end, so they are reallocated instead
+        if num_pages_new != num_pages_old && num_pages_old * PAGE_SIZE >= HUGE_PAGE_SIZE {
+            let new_ptr = self.allocate(new_layout)?;
+
+            unsafe {
+                ptr::copy_nonoverlapping::<u8>(ptr.as_ptr(), new_ptr.as_mut_ptr(), new_layout.size());
+                self.deallocate(ptr, old_layout);
+            }
+
+            return Ok(new_ptr);
+        }
+
         if num_pages_new != num_pages_old {
             let end_ptr = VirtAddr::from_ptr(ptr.as_ptr()) + num_pages_new * PAGE_SIZE;
             let mut addrspace = AddressSpace::kernel();
@@ -173,12 +378,242 @@ unsafe impl Allocator for PageBasedAlloc {
                     .virtual_alloc()
                     .free(VirtualAllocRegion::new(end_ptr, end_ptr + num_pages * PAGE_SIZE));
             }
+
+            PAGE_ALLOC_PAGES.fetch_sub(num_pages, Ordering::Relaxed);
         }
 
         Ok(NonNull::from_raw_parts(ptr.cast(), num_pages_new * PAGE_SIZE))
     }
 }
 
+/// An allocator for large regions of kernel memory that are only backed by page frames once they are touched.
+///
+/// Allocating memory using this allocator only reserves a region of virtual memory. Each page in the region is mapped to a zeroed page frame
+/// by the page fault handler when it is first accessed, so memory that is reserved but never used (e.g. the unused part of a large buffer
+/// sized for the worst case) does not take up any physical memory. Since the page fault handler cannot fail gracefully, running out of
+/// physical memory while touching a page causes a panic.
+///
+/// Lazily allocated memory must not be touched for the first time while the kernel address space is locked.
+pub struct LazyPageAlloc;
+
+/// The regions allocated by [`LazyPageAlloc`], mapping the start address of each region to its end address.
+static LAZY_REGIONS: UninterruptibleSpinlock<BTreeMap<u64, u64>> = UninterruptibleSpinlock::new(BTreeMap::new());
+
+impl LazyPageAlloc {
+    /// Checks whether the provided address lies in a region allocated by this allocator.
+    pub fn contains(addr: VirtAddr) -> bool {
+        let addr = addr.as_u64();
+
+        LAZY_REGIONS.lock().range(..=addr).next_back().is_some_and(|(_, &end)| addr < end)
+    }
+
+    /// Maps a zeroed page frame to the page containing the provided address, which must lie in a region allocated by this allocator.
+    /// Returns `false` if no page frame could be allocated.
+    fn populate(addr: VirtAddr) -> bool {
+        let page = VirtAddr::new(addr.as_u64() & !(PAGE_SIZE as u64 - 1));
+        let mut addrspace = AddressSpace::kernel();
+
+        // Another core may have touched the same page at the same time
+        if addrspace.get_page(page).is_some() {
+            return true;
+        }
+
+        let Some(frame) = frame::get_allocator().alloc_one() else {
+            log!(Error, "mem", "Out of memory populating lazily allocated page {:#x}", page.as_u64());
+            return false;
+        };
+
+        // SAFETY: The frame was just allocated, so nothing else is using it, and the page is not yet mapped
+        unsafe {
+            (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).fill(0);
+            addrspace.set_page_kernel(page, Some((frame, PageFlags::WRITEABLE)));
+        }
+
+        PAGE_ALLOC_PAGES.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+unsafe impl Allocator for LazyPageAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return Ok(NonNull::from_raw_parts(NonNull::dangling(), 0));
+        }
+
+        if layout.align() > PAGE_SIZE {
+            return Err(AllocError);
+        }
+
+        let size = layout.size().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let region = AddressSpace::kernel().virtual_alloc().alloc(size).ok_or(AllocError)?;
+
+        LAZY_REGIONS.lock().insert(region.start().as_u64(), region.end().as_u64());
+
+        Ok(NonNull::from_raw_parts(NonNull::new(region.start().as_mut_ptr()).unwrap(), size))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        let start = VirtAddr::from_ptr(ptr.as_ptr());
+        let end = LAZY_REGIONS
+            .lock()
+            .remove(&start.as_u64())
+            .expect("Attempt to free memory not allocated by LazyPageAlloc");
+        let end = VirtAddr::new(end);
+        let mut addrspace = AddressSpace::kernel();
+        let mut shootdown = TlbShootdown::new();
+        let mut frames = [MaybeUninit::uninit(); tlb::MAX_SHOOTDOWN_PAGES];
+        let mut num_frames = 0;
+        let mut page = start;
+
+        while page < end {
+            if let Some((frame, _)) = addrspace.get_page(page) {
+                addrspace.set_page_kernel(page, None);
+                shootdown.add(page);
+                frames[num_frames] = MaybeUninit::new(frame);
+                num_frames += 1;
+
+                if num_frames == frames.len() {
+                    flush_and_free(&mut shootdown, &frames[..num_frames]);
+                    PAGE_ALLOC_PAGES.fetch_sub(num_frames, Ordering::Relaxed);
+                    num_frames = 0;
+                }
+            }
+
+            page += PAGE_SIZE;
+        }
+
+        flush_and_free(&mut shootdown, &frames[..num_frames]);
+        PAGE_ALLOC_PAGES.fetch_sub(num_frames, Ordering::Relaxed);
+
+        addrspace.virtual_alloc().free(VirtualAllocRegion::new(start, end));
+    }
+}
+
+/// Usage statistics for a single slab allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    /// The name of the slab allocator.
+    pub name: &'static str,
+    /// The size of each object, in bytes.
+    pub object_size: usize,
+    /// The number of objects currently allocated, including objects cached in per-CPU magazines.
+    pub allocated: usize,
+    /// The total number of objects in all slabs owned by the allocator.
+    pub total: usize,
+}
+
+/// A snapshot of how much memory is in use by each of the kernel's allocators.
+#[derive(Debug, Clone)]
+pub struct MemStats {
+    /// Statistics for each registered slab allocator.
+    pub slabs: Vec<SlabStats>,
+    /// The number of pages currently mapped by [`PageBasedAlloc`] and [`LazyPageAlloc`].
+    pub page_alloc_pages: usize,
+    /// The number of bytes of the early allocation pool currently in use.
+    pub early_used: usize,
+    /// The total size of the early allocation pool, in bytes.
+    pub early_total: usize,
+    /// The number of physical page frames available for allocation.
+    pub frames_available: usize,
+    /// The total number of physical page frames managed by the frame allocator.
+    pub frames_total: usize,
+}
+
+impl MemStats {
+    /// Gets the total number of bytes in use by objects allocated from slab allocators.
+    pub fn slab_bytes_allocated(&self) -> usize {
+        self.slabs.iter().map(|slab| slab.allocated * slab.object_size).sum()
+    }
+
+    /// Gets the total number of bytes reserved by slab allocators for objects, whether allocated or not.
+    pub fn slab_bytes_total(&self) -> usize {
+        self.slabs.iter().map(|slab| slab.total * slab.object_size).sum()
+    }
+}
+
+/// Gets a snapshot of how much memory is in use by each of the kernel's allocators. The individual values are read at slightly different
+/// times, so they may not be entirely consistent with each other.
+pub fn stats() -> MemStats {
+    let (early_used, early_total) = early::usage();
+    let mut slabs = Vec::new();
+
+    for alloc in slab::registered_slab_allocs() {
+        let (allocated, total) = alloc.lock().count();
+
+        slabs.push(SlabStats {
+            name: alloc.name(),
+            object_size: alloc.object_size(),
+            allocated,
+            total,
+        });
+    }
+
+    MemStats {
+        slabs,
+        page_alloc_pages: PageBasedAlloc::pages_in_use(),
+        early_used,
+        early_total,
+        frames_available: frame::get_allocator().num_frames_available(),
+        frames_total: frame::num_total_frames(),
+    }
+}
+
+/// Copies kernel memory starting at the virtual address `addr` into `buf`, stopping early at the first page that is not mapped or that is
+/// mapped uncached (since reading device memory can have side effects). Returns the number of bytes that were copied.
+///
+/// Memory is read by translating `addr` through the kernel's page tables and reading the underlying page frames, so arbitrary addresses
+/// (e.g. typed into the debug console) can be passed in without risking a page fault.
+pub fn read_kernel_mem(addr: u64, buf: &mut [u8]) -> usize {
+    read_mem_in(&AddressSpace::kernel(), addr, buf)
+}
+
+/// Like [`read_kernel_mem`], but copies nothing instead of waiting if the kernel's page tables are currently locked. This is meant for
+/// use by debugging code that may run while the lock is held, e.g. when panicking.
+pub fn try_read_kernel_mem(addr: u64, buf: &mut [u8]) -> usize {
+    AddressSpace::try_kernel().map_or(0, |addrspace| read_mem_in(&addrspace, addr, buf))
+}
+
+fn read_mem_in(addrspace: &AddressSpace, addr: u64, buf: &mut [u8]) -> usize {
+    let mut copied = 0;
+
+    while copied < buf.len() {
+        let Some(page_addr) = addr.checked_add(copied as u64) else {
+            break;
+        };
+
+        if VirtAddr::new_truncate(page_addr).as_u64() != page_addr {
+            break;
+        }
+
+        let Some((phys, flags)) = addrspace.get_page(VirtAddr::new(page_addr)) else {
+            break;
+        };
+
+        if flags.contains(PageFlags::UNCACHED) {
+            break;
+        }
+
+        let len = (PAGE_SIZE - (page_addr as usize % PAGE_SIZE)).min(buf.len() - copied);
+
+        // SAFETY: The physical memory backing a mapped kernel page is always accessible through the physical memory map
+        unsafe {
+            ptr::copy_nonoverlapping(
+                get_phys_mem_ptr_slice::<u8>(phys, len).ptr() as *const u8,
+                buf[copied..].as_mut_ptr(),
+                len,
+            );
+        }
+
+        copied += len;
+    }
+
+    copied
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum AllocType {
     Early,
@@ -231,11 +666,39 @@ fn get_existing_alloc_type(ptr: *mut u8, layout: Layout) -> AllocType {
     }
 }
 
+/// Gets the number of bytes actually reserved for an allocation of the provided type, or [`None`] for early allocations, which are not
+/// checked by [`kasan`].
+fn usable_size(ty: AllocType, layout: Layout) -> Option<usize> {
+    match ty {
+        AllocType::Early => None,
+        AllocType::Slab8 => Some(8),
+        AllocType::Slab16 => Some(16),
+        AllocType::Slab32 => Some(32),
+        AllocType::Slab64 => Some(64),
+        AllocType::Slab128 => Some(128),
+        AllocType::Slab256 => Some(256),
+        AllocType::Slab512 => Some(512),
+        AllocType::Slab1024 => Some(1024),
+        AllocType::Slab2048 => Some(2048),
+        AllocType::Page => Some(layout.size().div_ceil(PAGE_SIZE) * PAGE_SIZE),
+    }
+}
+
+/// Retries a failed allocation once if running the reclaim hooks managed to release any memory.
+fn with_reclaim<T>(layout: Layout, mut f: impl FnMut() -> Result<T, AllocError>) -> Result<T, AllocError> {
+    match f() {
+        Err(AllocError) if oom::reclaim(layout) => f(),
+        result => result,
+    }
+}
+
+/// The kernel's global allocator. Allocation failures are reported by returning a null pointer rather than panicking, so fallible
+/// allocation APIs such as [`Box::try_new`](alloc::boxed::Box::try_new) can be used to handle running out of memory gracefully.
 pub struct DefaultAlloc;
 
-unsafe impl GlobalAlloc for DefaultAlloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let result = match get_new_alloc_type(layout) {
+impl DefaultAlloc {
+    unsafe fn alloc_internal(&self, layout: Layout) -> *mut u8 {
+        let result = with_reclaim(layout, || match get_new_alloc_type(layout) {
             AllocType::Early => Ok(NonNull::from_raw_parts(
                 NonNull::new(early::alloc(layout.size(), layout.align())).unwrap().cast(),
                 layout.size(),
@@ -250,17 +713,12 @@ unsafe impl GlobalAlloc for DefaultAlloc {
             AllocType::Slab1024 => slab::SLAB_1024.allocate(layout),
             AllocType::Slab2048 => slab::SLAB_2048.allocate(layout),
             AllocType::Page => PageBasedAlloc.allocate(layout),
-        };
+        });
 
-        match result {
-            Ok(ptr) => ptr.as_mut_ptr(),
-            Err(_) => {
-                handle_alloc_error(layout);
-            },
-        }
+        result.map_or(ptr::null_mut(), |ptr| ptr.as_mut_ptr())
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_internal(&self, ptr: *mut u8, layout: Layout) {
         let ptr = NonNull::new(ptr).unwrap();
 
         match get_existing_alloc_type(ptr.as_ptr(), layout) {
@@ -278,7 +736,7 @@ unsafe impl GlobalAlloc for DefaultAlloc {
         }
     }
 
-    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    unsafe fn realloc_internal(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         unsafe fn realloc<A: Allocator>(alloc: &A, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Result<NonNull<[u8]>, AllocError> {
             if new_size >= layout.size() {
                 alloc.grow(ptr, layout, Layout::from_size_align_unchecked(new_size, layout.align()))
@@ -287,12 +745,13 @@ unsafe impl GlobalAlloc for DefaultAlloc {
             }
         }
 
+        let new_layout = Layout::from_size_align(new_size, layout.align()).unwrap();
         let old_ty = get_existing_alloc_type(ptr, layout);
-        let new_ty = get_new_alloc_type(Layout::from_size_align(new_size, layout.align()).unwrap());
+        let new_ty = get_new_alloc_type(new_layout);
 
         if new_ty == old_ty {
             let ptr = NonNull::new(ptr).unwrap();
-            let result = match get_existing_alloc_type(ptr.as_ptr(), layout) {
+            let result = with_reclaim(new_layout, || match get_existing_alloc_type(ptr.as_ptr(), layout) {
                 AllocType::Early => Ok(NonNull::from_raw_parts(
                     NonNull::new(early::realloc(ptr.as_ptr(), layout.size(), new_size)).unwrap().cast(),
                     layout.size(),
@@ -307,24 +766,179 @@ unsafe impl GlobalAlloc for DefaultAlloc {
                 AllocType::Slab1024 => realloc(&slab::SLAB_1024, ptr, layout, new_size),
                 AllocType::Slab2048 => realloc(&slab::SLAB_2048, ptr, layout, new_size),
                 AllocType::Page => realloc(&PageBasedAlloc, ptr, layout, new_size),
-            };
+            });
 
-            match result {
-                Ok(ptr) => ptr.as_mut_ptr(),
-                Err(_) => {
-                    handle_alloc_error(layout);
-                },
-            }
+            result.map_or(ptr::null_mut(), |ptr| ptr.as_mut_ptr())
         } else {
-            let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+            let new_ptr = self.alloc_internal(new_layout);
+
+            if new_ptr.is_null() {
+                return new_ptr;
+            }
 
             ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
-            self.dealloc(ptr, layout);
+            self.dealloc_internal(ptr, layout);
 
             new_ptr
         }
     }
 }
 
+unsafe impl GlobalAlloc for DefaultAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_internal(layout);
+
+        if !ptr.is_null() {
+            profile::record_alloc(ptr, layout.size());
+
+            if let Some(usable_size) = usable_size(get_existing_alloc_type(ptr, layout), layout) {
+                kasan::on_alloc(ptr, layout.size(), usable_size);
+            }
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        profile::record_dealloc(ptr);
+
+        match usable_size(get_existing_alloc_type(ptr, layout), layout) {
+            Some(usable_size) => {
+                if let Some((ptr, layout)) = kasan::on_dealloc(ptr, layout, usable_size) {
+                    self.dealloc_internal(ptr, layout);
+                }
+            },
+            None => self.dealloc_internal(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if kasan::ENABLED {
+            // Always move the allocation, so that the old allocation goes through the quarantine and stale pointers to it are caught
+            let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+
+            return new_ptr;
+        }
+
+        let new_ptr = self.realloc_internal(ptr, layout, new_size);
+
+        if !new_ptr.is_null() {
+            profile::record_dealloc(ptr);
+            profile::record_alloc(new_ptr, new_size);
+        }
+
+        new_ptr
+    }
+}
+
 #[global_allocator]
 pub static ALLOCATOR: DefaultAlloc = DefaultAlloc;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_page_alloc_huge_pages() {
+        let layout = Layout::from_size_align(HUGE_PAGE_SIZE + PAGE_SIZE, PAGE_SIZE).unwrap();
+        let ptr = PageBasedAlloc.allocate(layout).unwrap();
+        let start = VirtAddr::from_ptr(ptr.as_mut_ptr());
+
+        if !AddressSpace::kernel().is_huge_page(start) {
+            unsafe {
+                PageBasedAlloc.deallocate(ptr.cast(), layout);
+            }
+
+            crate::test_util::skip("no physically contiguous memory available for a huge page");
+            return;
+        }
+
+        assert_eq!(0, start.as_u64() % HUGE_PAGE_SIZE as u64);
+        assert!(!AddressSpace::kernel().is_huge_page(start + HUGE_PAGE_SIZE));
+
+        unsafe {
+            *ptr.as_mut_ptr() = 0x12;
+            *ptr.as_mut_ptr().add(HUGE_PAGE_SIZE + PAGE_SIZE - 1) = 0x34;
+
+            PageBasedAlloc.deallocate(ptr.cast(), layout);
+        }
+
+        assert_eq!(None, AddressSpace::kernel().get_page(start));
+        assert!(!AddressSpace::kernel().is_huge_page(start));
+    }
+
+    #[test_case]
+    fn test_page_alloc_pages_in_use() {
+        let layout = Layout::from_size_align(4 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let before = PageBasedAlloc::pages_in_use();
+        let ptr = PageBasedAlloc.allocate(layout).unwrap();
+
+        assert_eq!(before + 4, PageBasedAlloc::pages_in_use());
+
+        unsafe {
+            PageBasedAlloc.deallocate(ptr.cast(), layout);
+        }
+
+        assert_eq!(before, PageBasedAlloc::pages_in_use());
+    }
+
+    #[test_case]
+    fn test_page_alloc_guarded() {
+        let num_pages = HUGE_PAGE_SIZE / PAGE_SIZE;
+        let before = PageBasedAlloc::pages_in_use();
+        let start = PageBasedAlloc::allocate_guarded(num_pages).unwrap();
+
+        assert_eq!(before + num_pages, PageBasedAlloc::pages_in_use());
+        assert_eq!(None, AddressSpace::kernel().get_page(start - PAGE_SIZE as u64));
+        assert!(!AddressSpace::kernel().is_huge_page(start));
+        assert!(AddressSpace::kernel().get_page(start + (num_pages - 1) * PAGE_SIZE).is_some());
+
+        unsafe {
+            PageBasedAlloc::deallocate_guarded(start, num_pages);
+        }
+
+        assert_eq!(before, PageBasedAlloc::pages_in_use());
+        assert_eq!(None, AddressSpace::kernel().get_page(start));
+    }
+
+    #[test_case]
+    fn test_mem_stats() {
+        let stats = stats();
+
+        assert!(stats.slabs.iter().any(|slab| slab.name == "SLAB_8"));
+        assert!(stats.slab_bytes_allocated() <= stats.slab_bytes_total());
+        assert!(stats.early_used <= stats.early_total);
+        assert!(stats.frames_available <= stats.frames_total);
+    }
+
+    #[test_case]
+    fn test_lazy_alloc_populates_on_touch() {
+        let layout = Layout::from_size_align(64 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let ptr = LazyPageAlloc.allocate(layout).unwrap();
+        let start = VirtAddr::from_ptr(ptr.as_mut_ptr());
+        let touched = start + 10 * PAGE_SIZE;
+
+        assert!(LazyPageAlloc::contains(touched));
+        assert_eq!(None, AddressSpace::kernel().get_page(touched));
+
+        unsafe {
+            assert_eq!(0, *touched.as_ptr::<u8>());
+            *touched.as_mut_ptr::<u8>() = 0x12;
+        }
+
+        assert!(AddressSpace::kernel().get_page(touched).is_some());
+        assert_eq!(None, AddressSpace::kernel().get_page(start));
+
+        unsafe {
+            LazyPageAlloc.deallocate(ptr.cast(), layout);
+        }
+
+        assert!(!LazyPageAlloc::contains(touched));
+        assert_eq!(None, AddressSpace::kernel().get_page(touched));
+    }
+}
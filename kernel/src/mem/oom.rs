@@ -0,0 +1,85 @@
+//! Handling of out-of-memory conditions.
+//!
+//! When a heap allocation fails, the registered reclaim hooks are run to give subsystems holding memory that can be released on demand (e.g.
+//! caches) a chance to free some of it, after which the allocation is retried once. If the allocation still fails, it is reported back to
+//! the caller: infallible allocations such as [`Box::new`](alloc::boxed::Box::new) will panic, while fallible allocations such as
+//! [`Box::try_new`](alloc::boxed::Box::try_new) or the helpers in [`crate::util`] return an error that drivers can handle gracefully.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::sync::UninterruptibleSpinlock;
+
+const MAX_RECLAIM_HOOKS: usize = 16;
+
+/// A function that attempts to release memory when an allocation fails. Returns the number of bytes that were released.
+///
+/// Reclaim hooks can be run from any context in which memory is allocated, so they must not block and must not take any locks that might
+/// be held while allocating memory. Any allocations made by a reclaim hook will not run the reclaim hooks again if they fail.
+pub type ReclaimHook = fn(layout: Layout) -> usize;
+
+static RECLAIM_HOOKS: UninterruptibleSpinlock<[Option<ReclaimHook>; MAX_RECLAIM_HOOKS]> =
+    UninterruptibleSpinlock::new([None; MAX_RECLAIM_HOOKS]);
+static IN_RECLAIM: AtomicBool = AtomicBool::new(false);
+static NUM_OOM_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Registers a function to be called to release memory when an allocation fails.
+///
+/// # Panics
+///
+/// This function will panic if too many reclaim hooks have already been registered.
+pub fn register_reclaim_hook(hook: ReclaimHook) {
+    let mut hooks = RECLAIM_HOOKS.lock();
+    let slot = hooks.iter_mut().find(|h| h.is_none()).expect("too many reclaim hooks registered");
+
+    *slot = Some(hook);
+}
+
+/// Gets the number of times an allocation has failed and the reclaim hooks were run since boot.
+pub fn num_oom_events() -> u64 {
+    NUM_OOM_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Runs all registered reclaim hooks after an allocation with the provided layout has failed. Returns `true` if any memory was released,
+/// in which case the allocation should be retried.
+pub(super) fn reclaim(layout: Layout) -> bool {
+    if IN_RECLAIM.swap(true, Ordering::Acquire) {
+        return false;
+    }
+
+    NUM_OOM_EVENTS.fetch_add(1, Ordering::Relaxed);
+
+    // The hooks are copied out so that they can register further hooks or allocate memory without deadlocking. Nothing is logged here,
+    // since logging may itself need to allocate memory.
+    let hooks = *RECLAIM_HOOKS.lock();
+    let released: usize = hooks.iter().flatten().map(|hook| hook(layout)).sum();
+
+    IN_RECLAIM.store(false, Ordering::Release);
+    released != 0
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    static TEST_HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn test_hook(_layout: Layout) -> usize {
+        TEST_HOOK_CALLS.fetch_add(1, Ordering::Relaxed);
+        0
+    }
+
+    #[test_case]
+    fn test_reclaim_hooks() {
+        let layout = Layout::new::<u64>();
+        let events_before = num_oom_events();
+
+        register_reclaim_hook(test_hook);
+
+        assert!(!reclaim(layout));
+        assert_eq!(1, TEST_HOOK_CALLS.load(Ordering::Relaxed));
+        assert_eq!(events_before + 1, num_oom_events());
+    }
+}
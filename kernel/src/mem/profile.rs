@@ -0,0 +1,214 @@
+//! Tracking of live kernel heap allocations by the call site that made them.
+//!
+//! When the `alloc_profile` feature is enabled, every allocation made through [`DefaultAlloc`](super::DefaultAlloc) records the return
+//! addresses of the functions that made it, and [`top_call_sites`] can then be used to find the call sites holding the most memory. This is
+//! mainly useful for hunting down memory leaks: a call site whose live allocation count keeps growing is likely leaking.
+//!
+//! The first few return addresses of each call site usually belong to generic allocation code (e.g. [`Box::new`](alloc::boxed::Box::new)
+//! or [`Vec`] growth) that was not inlined, which is why more than one address is recorded. Allocations made before tracking starts during
+//! boot are not tracked, and neither are allocations made by the tracking code itself.
+
+use alloc::vec::Vec;
+
+/// The number of return addresses recorded for each call site.
+pub const CALL_SITE_DEPTH: usize = 4;
+
+/// The return addresses of the functions that made an allocation, innermost first. Unused entries at the end are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CallSite(pub [usize; CALL_SITE_DEPTH]);
+
+impl CallSite {
+    /// Gets the non-zero return addresses making up this call site.
+    pub fn addrs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().copied().take_while(|&addr| addr != 0)
+    }
+}
+
+/// The allocations made from a single call site that have not yet been freed.
+#[derive(Debug, Clone, Copy)]
+pub struct CallSiteStats {
+    /// The call site that made the allocations.
+    pub site: CallSite,
+    /// The number of live allocations made from this call site.
+    pub count: usize,
+    /// The total size of the live allocations made from this call site, in bytes.
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocProfilingDisabledError;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "alloc_profile")] {
+        mod tracking {
+            use alloc::collections::BTreeMap;
+            use alloc::vec::Vec;
+            use core::cell::Cell;
+            use core::sync::atomic::{AtomicBool, Ordering};
+
+            use super::{AllocProfilingDisabledError, CallSite, CallSiteStats, CALL_SITE_DEPTH};
+            use crate::arch;
+            use crate::sync::uninterruptible::InterruptDisabler;
+            use crate::sync::UninterruptibleSpinlock;
+
+            struct Profile {
+                live: BTreeMap<usize, (CallSite, usize)>,
+                sites: BTreeMap<CallSite, (usize, usize)>,
+            }
+
+            static ENABLED: AtomicBool = AtomicBool::new(false);
+            static PROFILE: UninterruptibleSpinlock<Profile> = UninterruptibleSpinlock::new(Profile {
+                live: BTreeMap::new(),
+                sites: BTreeMap::new(),
+            });
+
+            crate::cpu_local! {
+                static IN_PROFILER: Cell<bool> = Cell::new(false);
+            }
+
+            /// Runs the provided function unless the current core is already updating the profile, in which case the allocation being
+            /// recorded was made by the profile itself and is ignored.
+            fn with_profile(f: impl FnOnce(&mut Profile)) {
+                if !ENABLED.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let _interrupts_disabled = InterruptDisabler::new();
+
+                if IN_PROFILER.replace(true) {
+                    return;
+                }
+
+                f(&mut PROFILE.lock());
+                IN_PROFILER.set(false);
+            }
+
+            fn remove(profile: &mut Profile, ptr: usize) {
+                let Some((site, size)) = profile.live.remove(&ptr) else {
+                    return;
+                };
+
+                let stats = profile.sites.get_mut(&site).unwrap();
+
+                stats.0 -= 1;
+                stats.1 -= size;
+
+                if stats.0 == 0 {
+                    profile.sites.remove(&site);
+                }
+            }
+
+            #[inline(never)]
+            pub fn record_alloc(ptr: *mut u8, size: usize) {
+                let mut site = CallSite([0; CALL_SITE_DEPTH]);
+
+                // Skip the return addresses into this function and into DefaultAlloc
+                arch::backtrace(2, &mut site.0);
+
+                with_profile(|profile| {
+                    remove(profile, ptr as usize);
+                    profile.live.insert(ptr as usize, (site, size));
+
+                    let stats = profile.sites.entry(site).or_insert((0, 0));
+
+                    stats.0 += 1;
+                    stats.1 += size;
+                });
+            }
+
+            pub fn record_dealloc(ptr: *mut u8) {
+                with_profile(|profile| remove(profile, ptr as usize));
+            }
+
+            pub fn call_sites() -> Result<Vec<CallSiteStats>, AllocProfilingDisabledError> {
+                let _interrupts_disabled = InterruptDisabler::new();
+                let was_in_profiler = IN_PROFILER.replace(true);
+                let profile = PROFILE.lock();
+                let mut result = Vec::with_capacity(profile.sites.len());
+
+                result.extend(profile.sites.iter().map(|(&site, &(count, bytes))| CallSiteStats { site, count, bytes }));
+
+                drop(profile);
+                IN_PROFILER.set(was_in_profiler);
+                Ok(result)
+            }
+
+            pub fn start() {
+                ENABLED.store(true, Ordering::Relaxed);
+            }
+        }
+    } else {
+        mod tracking {
+            use alloc::vec::Vec;
+
+            use super::{AllocProfilingDisabledError, CallSiteStats};
+
+            pub fn record_alloc(_: *mut u8, _: usize) {}
+            pub fn record_dealloc(_: *mut u8) {}
+
+            pub fn call_sites() -> Result<Vec<CallSiteStats>, AllocProfilingDisabledError> {
+                Err(AllocProfilingDisabledError)
+            }
+
+            pub fn start() {}
+        }
+    }
+}
+
+/// Records that an allocation of the provided size was made at the provided address. This must be called directly from the
+/// [`GlobalAlloc`](core::alloc::GlobalAlloc) implementation so that the correct call site is recorded.
+#[inline(always)]
+pub(super) fn record_alloc(ptr: *mut u8, size: usize) {
+    tracking::record_alloc(ptr, size);
+}
+
+/// Records that the allocation at the provided address is about to be freed.
+#[inline(always)]
+pub(super) fn record_dealloc(ptr: *mut u8) {
+    tracking::record_dealloc(ptr);
+}
+
+/// Gets the `n` call sites whose live allocations take up the most memory, largest first.
+///
+/// Allocation profiling has a significant performance and memory cost, so it is only done when the `alloc_profile` feature is enabled. If
+/// it is not enabled, this function returns an error.
+pub fn top_call_sites(n: usize) -> Result<Vec<CallSiteStats>, AllocProfilingDisabledError> {
+    let mut sites = tracking::call_sites()?;
+
+    sites.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.count.cmp(&a.count)));
+    sites.truncate(n);
+    Ok(sites)
+}
+
+/// Starts tracking allocations. This can only be done once CPU-local storage and the kernel heap have been set up.
+unsafe fn init() {
+    tracking::start();
+}
+
+crate::initcall!(arch, "alloc profile", init);
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+
+    use super::*;
+
+    #[test_case]
+    fn test_profile_tracks_live_allocations() {
+        if top_call_sites(0).is_err() {
+            crate::test_util::skip("allocation profiling is not enabled");
+            return;
+        }
+
+        let sum_bytes = || top_call_sites(usize::MAX).unwrap().iter().map(|s| s.bytes).sum::<usize>();
+
+        let before = sum_bytes();
+        let allocated = Box::new([0u8; 4096]);
+        let during = sum_bytes();
+
+        drop(allocated);
+
+        assert!(during >= before + 4096);
+        assert!(sum_bytes() < during);
+    }
+}
@@ -0,0 +1,264 @@
+//! Bookkeeping of the memory regions mapped into user address spaces.
+//!
+//! Page tables only record how individual pages are mapped, which makes it impossible to tell which pages belong together or what they are
+//! being used for. Each user process therefore keeps a [`RegionMap`] describing the contiguous regions of its address space that have been
+//! mapped, which is used when reporting a process's memory layout and when unmapping memory.
+
+use alloc::collections::btree_map::{self, BTreeMap};
+use core::fmt;
+
+use super::virt::VirtualAllocRegion;
+use crate::arch::page::{AddressSpace, PageFlags, PAGE_SIZE, USER_SPACE_END};
+use crate::arch::{PhysAddr, VirtAddr};
+
+/// An error that can occur when mapping a new region of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The requested range is not page-aligned, is empty, or is not in the user part of the address space.
+    InvalidRange,
+    /// The requested range overlaps a region that is already mapped.
+    Overlap,
+    /// There was not enough physical memory available to back the region.
+    OutOfMemory,
+    /// There was no range of free virtual memory large enough to hold the region.
+    OutOfVirtualMemory,
+    /// No region is mapped at the requested address.
+    NotMapped,
+    /// The process does not have a user address space, e.g. because it has exited.
+    NoAddressSpace,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapError::InvalidRange => write!(f, "invalid address range"),
+            MapError::Overlap => write!(f, "range overlaps an existing region"),
+            MapError::OutOfMemory => write!(f, "out of physical memory"),
+            MapError::OutOfVirtualMemory => write!(f, "out of virtual memory"),
+            MapError::NotMapped => write!(f, "no region is mapped at the address"),
+            MapError::NoAddressSpace => write!(f, "no address space"),
+        }
+    }
+}
+
+/// The kind of memory backing a mapped region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionBacking {
+    /// Zero-filled memory allocated on behalf of the process that can be swapped out.
+    Anonymous,
+    /// Zero-filled memory used as the stack of a user thread. The lowest page of the region is a guard page that is never mapped, so that
+    /// overflowing the stack causes a page fault.
+    Stack,
+    /// A fixed range of physical memory starting at the provided address, e.g. device memory.
+    Physical(PhysAddr),
+}
+
+impl fmt::Display for RegionBacking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegionBacking::Anonymous => write!(f, "anon"),
+            RegionBacking::Stack => write!(f, "stack"),
+            RegionBacking::Physical(addr) => write!(f, "phys@{:#x}", addr.as_u64()),
+        }
+    }
+}
+
+/// Counts of how many pages of a region are currently backed by physical memory or swapped out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegionUsage {
+    pub resident_pages: usize,
+    pub swapped_pages: usize,
+}
+
+/// A contiguous range of pages in a user address space that were mapped together.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    range: VirtualAllocRegion,
+    flags: PageFlags,
+    backing: RegionBacking,
+    name: &'static str,
+}
+
+impl MemoryRegion {
+    /// Creates a new description of a region covering the provided range of the user part of an address space.
+    pub fn new(range: VirtualAllocRegion, flags: PageFlags, backing: RegionBacking, name: &'static str) -> Result<MemoryRegion, MapError> {
+        if range.size() == 0 || !range.is_page_aligned() || range.end().as_u64() > USER_SPACE_END {
+            return Err(MapError::InvalidRange);
+        }
+
+        Ok(MemoryRegion {
+            range,
+            flags,
+            backing,
+            name,
+        })
+    }
+
+    /// Gets the range of virtual addresses covered by this region.
+    pub fn range(&self) -> VirtualAllocRegion {
+        self.range
+    }
+
+    /// Gets the number of pages covered by this region.
+    pub fn num_pages(&self) -> usize {
+        self.range.size() as usize / PAGE_SIZE
+    }
+
+    /// Gets an iterator over the addresses of all pages in this region.
+    pub fn pages(&self) -> impl Iterator<Item = VirtAddr> {
+        let start = self.range.start();
+
+        (0..self.num_pages()).map(move |i| start + (i * PAGE_SIZE) as u64)
+    }
+
+    /// Gets the flags with which the pages in this region are mapped.
+    pub fn flags(&self) -> PageFlags {
+        self.flags
+    }
+
+    /// Changes the flags with which the pages in this region are mapped. This only updates the description of the region; the pages
+    /// themselves must be remapped separately.
+    pub fn set_flags(&mut self, flags: PageFlags) {
+        self.flags = flags;
+    }
+
+    /// Gets the kind of memory backing this region.
+    pub fn backing(&self) -> RegionBacking {
+        self.backing
+    }
+
+    /// Gets a short human-readable description of what this region is used for.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Counts how many pages of this region are currently resident in physical memory or swapped out in the provided address space.
+    pub fn usage(&self, addrspace: &mut AddressSpace) -> RegionUsage {
+        let mut usage = RegionUsage::default();
+
+        for addr in self.pages() {
+            if addrspace.get_page(addr).is_some() {
+                usage.resident_pages += 1;
+            } else if addrspace.get_swap_entry(addr).is_some() {
+                usage.swapped_pages += 1;
+            }
+        }
+
+        usage
+    }
+}
+
+/// The set of regions mapped into a user address space, ordered by address.
+pub struct RegionMap {
+    regions: BTreeMap<VirtAddr, MemoryRegion>,
+}
+
+impl RegionMap {
+    /// Creates a new empty region map.
+    pub const fn new() -> RegionMap {
+        RegionMap { regions: BTreeMap::new() }
+    }
+
+    /// Finds the region containing the provided address, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<&MemoryRegion> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| addr < region.range.end())
+    }
+
+    /// Records a newly mapped region. Fails with [`MapError::Overlap`] if the region overlaps one that is already recorded.
+    pub fn insert(&mut self, region: MemoryRegion) -> Result<(), MapError> {
+        let overlaps_prev = self.find(region.range.start()).is_some();
+        let overlaps_next = self
+            .regions
+            .range(region.range.start()..)
+            .next()
+            .map_or(false, |(&start, _)| start < region.range.end());
+
+        if overlaps_prev || overlaps_next {
+            return Err(MapError::Overlap);
+        }
+
+        self.regions.insert(region.range.start(), region);
+        Ok(())
+    }
+
+    /// Gets a mutable reference to the region starting at the provided address, if any.
+    pub fn get_mut(&mut self, start: VirtAddr) -> Option<&mut MemoryRegion> {
+        self.regions.get_mut(&start)
+    }
+
+    /// Removes the region starting at the provided address from this map and returns it.
+    pub fn remove(&mut self, start: VirtAddr) -> Option<MemoryRegion> {
+        self.regions.remove(&start)
+    }
+
+    /// Gets the number of regions in this map.
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Checks whether this map contains no regions.
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Gets an iterator over all regions in this map in order of address.
+    pub fn iter(&self) -> btree_map::Values<VirtAddr, MemoryRegion> {
+        self.regions.values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region(start: u64, end: u64) -> MemoryRegion {
+        MemoryRegion::new(
+            VirtualAllocRegion::new(VirtAddr::new(start), VirtAddr::new(end)),
+            PageFlags::USER,
+            RegionBacking::Anonymous,
+            "test",
+        )
+        .unwrap()
+    }
+
+    #[test_case]
+    fn test_region_map() {
+        let mut map = RegionMap::new();
+
+        map.insert(region(0x10000, 0x12000)).unwrap();
+        map.insert(region(0x14000, 0x15000)).unwrap();
+
+        assert_eq!(Err(MapError::Overlap), map.insert(region(0x11000, 0x13000)));
+        assert_eq!(Err(MapError::Overlap), map.insert(region(0x13000, 0x15000)));
+        assert_eq!(Err(MapError::Overlap), map.insert(region(0xf000, 0x16000)));
+        map.insert(region(0x12000, 0x14000)).unwrap();
+
+        assert_eq!(
+            Some(VirtAddr::new(0x10000)),
+            map.find(VirtAddr::new(0x11fff)).map(|r| r.range().start())
+        );
+        assert_eq!(
+            Some(VirtAddr::new(0x12000)),
+            map.find(VirtAddr::new(0x12000)).map(|r| r.range().start())
+        );
+        assert!(map.find(VirtAddr::new(0x15000)).is_none());
+        assert!(map.find(VirtAddr::new(0xf000)).is_none());
+
+        assert!(map.remove(VirtAddr::new(0x12000)).is_some());
+        assert_eq!(2, map.len());
+        assert_eq!(
+            Err(MapError::InvalidRange),
+            MemoryRegion::new(
+                VirtualAllocRegion::new(VirtAddr::new(0x10001), VirtAddr::new(0x12000)),
+                PageFlags::USER,
+                RegionBacking::Anonymous,
+                "test"
+            )
+            .map(|_| ())
+        );
+    }
+}
@@ -1,12 +1,25 @@
+//! Slab allocators for small fixed-size objects.
+//!
+//! When the `slab_debug` feature is enabled, every object is followed by a redzone filled with a known pattern and freed objects are
+//! poisoned with another pattern. Both patterns are validated whenever an object is allocated or freed, so that writes past the end of an
+//! object or writes to an object after it has been freed cause a panic rather than silently corrupting other objects. Since the redzone
+//! also precedes the next object in the slab, underflowing an object is detected as well. Note that objects then only have an alignment
+//! of at most [`MAX_REDZONE_SIZE`] bytes, so allocations requiring greater alignment fail in this mode.
+//!
+//! To avoid taking a slab allocator's lock on every allocation, the kernel's general-purpose slab allocators keep a per-CPU magazine of
+//! free objects in front of their slabs. Allocations and frees only need to disable interrupts on the current core while the magazine can
+//! satisfy them, and the slabs are only locked to refill or drain a magazine in batches. Objects held in a magazine are counted as allocated
+//! by [`SlabAllocLock::count`]. Magazines are not used when [`DEBUG`] is set, so that every free is validated immediately.
+
 use core::alloc::{AllocError, Allocator, Layout};
-use core::cell::SyncUnsafeCell;
+use core::cell::{SyncUnsafeCell, UnsafeCell};
 use core::marker::PhantomData;
 use core::mem;
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
 
 use super::PageBasedAlloc;
 use crate::arch::page::PAGE_SIZE;
-use crate::sync::uninterruptible::UninterruptibleSpinlockGuard;
+use crate::sync::uninterruptible::{InterruptDisabler, UninterruptibleSpinlockGuard};
 use crate::sync::UninterruptibleSpinlock;
 use crate::util::FixedBitVector;
 
@@ -38,6 +51,106 @@ impl SlabInfo {
     }
 }
 
+/// Whether objects are surrounded by redzones and poisoned when freed.
+pub const DEBUG: bool = cfg!(feature = "slab_debug");
+
+/// The largest redzone placed after each object when [`DEBUG`] is set.
+pub const MAX_REDZONE_SIZE: usize = 64;
+
+const REDZONE_BYTE: u8 = 0xbb;
+const POISON_BYTE: u8 = 0x6b;
+
+/// Gets the size of the redzone following each object of the provided size. The redzone is a power of two no larger than the object, so
+/// that objects remain aligned to their size up to [`MAX_REDZONE_SIZE`] bytes.
+pub const fn redzone_size(obj_size: usize) -> usize {
+    if DEBUG {
+        let align = 1 << obj_size.trailing_zeros();
+
+        if align < MAX_REDZONE_SIZE {
+            align
+        } else {
+            MAX_REDZONE_SIZE
+        }
+    } else {
+        0
+    }
+}
+
+/// Gets the amount of space taken up in a slab by each object of the provided size, including its redzone.
+pub const fn slot_size(obj_size: usize) -> usize {
+    obj_size + redzone_size(obj_size)
+}
+
+/// Fills the provided memory with a debug pattern.
+///
+/// # Safety
+///
+/// The provided memory must be valid for writes.
+unsafe fn fill_pattern(ptr: NonNull<()>, len: usize, pattern: u8) {
+    ptr::write_bytes(ptr.as_ptr() as *mut u8, pattern, len);
+}
+
+/// Finds the offset of the first byte in the provided memory that does not match a debug pattern.
+///
+/// # Safety
+///
+/// The provided memory must be valid for reads.
+unsafe fn check_pattern(ptr: NonNull<()>, len: usize, pattern: u8) -> Option<usize> {
+    (0..len).find(|&i| *(ptr.as_ptr() as *const u8).add(i) != pattern)
+}
+
+/// The number of slab allocators that can have per-CPU magazines.
+const NUM_MAGAZINES: usize = 9;
+
+/// The maximum number of free objects held in each per-CPU magazine.
+const MAGAZINE_SIZE: usize = 32;
+
+/// The number of objects moved between a magazine and its slabs at once when the magazine becomes empty or full.
+const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+
+#[derive(Clone, Copy)]
+struct Magazine {
+    objs: [*mut (); MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Magazine {
+            objs: [ptr::null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, obj: NonNull<()>) {
+        self.objs[self.len] = obj.as_ptr();
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<NonNull<()>> {
+        if self.len == 0 {
+            None
+        } else {
+            self.len -= 1;
+            NonNull::new(self.objs[self.len])
+        }
+    }
+}
+
+crate::cpu_local! {
+    static MAGAZINES: UnsafeCell<[Magazine; NUM_MAGAZINES]> = UnsafeCell::new([Magazine::new(); NUM_MAGAZINES]);
+}
+
+/// Gets the current CPU core's magazine with the provided index.
+///
+/// # Safety
+///
+/// Interrupts must be disabled on the current core for as long as the returned reference is in use, and no other references to the same
+/// magazine may exist.
+unsafe fn magazine(idx: usize) -> &'static mut Magazine {
+    &mut (*MAGAZINES.get())[idx]
+}
+
 pub const fn pages_per_slab(size: usize) -> usize {
     assert!((size as usize) < PAGE_SIZE);
 
@@ -76,15 +189,17 @@ unsafe impl Sync for SlabAllocListInfo {}
 pub struct SlabAllocAny {
     name: &'static str,
     obj_size: usize,
+    magazine: Option<usize>,
     list_info: SyncUnsafeCell<SlabAllocListInfo>,
     slabs: UninterruptibleSpinlock<SlabList>,
 }
 
 impl SlabAllocAny {
-    const fn new(name: &'static str, obj_size: usize) -> Self {
+    const fn new(name: &'static str, obj_size: usize, magazine: Option<usize>) -> Self {
         Self {
             name,
             obj_size,
+            magazine,
             list_info: SyncUnsafeCell::new(SlabAllocListInfo {
                 registered: false,
                 next: None,
@@ -98,7 +213,7 @@ impl SlabAllocAny {
     }
 
     pub fn objects_per_slab(&self) -> usize {
-        (pages_per_slab(self.obj_size) * PAGE_SIZE) / self.obj_size
+        (pages_per_slab(slot_size(self.obj_size)) * PAGE_SIZE) / slot_size(self.obj_size)
     }
 
     pub fn name(&self) -> &str {
@@ -153,19 +268,32 @@ pub struct SlabAlloc<T, const OWN_INFO: bool = false> {
 
 impl<T, const OWN_INFO: bool> SlabAlloc<T, OWN_INFO> {
     const OBJECT_SIZE: usize = mem::size_of::<T>();
+    const REDZONE_SIZE: usize = redzone_size(Self::OBJECT_SIZE);
+    const SLOT_SIZE: usize = slot_size(Self::OBJECT_SIZE);
 
-    const PAGES_PER_SLAB: usize = pages_per_slab(Self::OBJECT_SIZE);
+    const PAGES_PER_SLAB: usize = pages_per_slab(Self::SLOT_SIZE);
     const SLAB_SIZE: usize = Self::PAGES_PER_SLAB * PAGE_SIZE;
-    const OBJECTS_PER_SLAB: usize = (Self::SLAB_SIZE / Self::OBJECT_SIZE);
+    const OBJECTS_PER_SLAB: usize = (Self::SLAB_SIZE / Self::SLOT_SIZE);
 
     pub const fn new(name: &'static str) -> Self {
+        Self::new_internal(name, None)
+    }
+
+    /// Creates a slab allocator that caches free objects in the per-CPU magazine with the provided index. Each index must only be used by a
+    /// single slab allocator.
+    const fn with_magazine(name: &'static str, magazine: usize) -> Self {
+        assert!(magazine < NUM_MAGAZINES);
+        Self::new_internal(name, if DEBUG { None } else { Some(magazine) })
+    }
+
+    const fn new_internal(name: &'static str, magazine: Option<usize>) -> Self {
         if OWN_INFO {
             assert!(Self::OBJECT_SIZE >= mem::size_of::<SlabInfo>());
             assert!(Self::OBJECT_SIZE % mem::align_of::<SlabInfo>() == 0);
         }
 
         Self {
-            inner: SlabAllocAny::new(name, Self::OBJECT_SIZE),
+            inner: SlabAllocAny::new(name, Self::OBJECT_SIZE, magazine),
             _data: PhantomData,
         }
     }
@@ -203,26 +331,69 @@ impl<T, const OWN_INFO: bool> SlabAlloc<T, OWN_INFO> {
             slabs: self.inner.slabs.lock(),
         }
     }
+
+    fn alloc_cached(&self) -> Option<NonNull<T>> {
+        let Some(idx) = self.inner.magazine else {
+            return self.lock().alloc();
+        };
+
+        let _interrupts_disabled = InterruptDisabler::new();
+
+        // SAFETY: Interrupts are disabled and only this slab allocator uses the magazine with this index
+        let magazine = unsafe { magazine(idx) };
+
+        if magazine.len == 0 {
+            let mut lock = self.lock();
+
+            while magazine.len < MAGAZINE_BATCH {
+                match lock.alloc() {
+                    Some(obj) => magazine.push(obj.cast()),
+                    None => break,
+                }
+            }
+        }
+
+        magazine.pop().map(NonNull::cast)
+    }
+
+    unsafe fn free_cached(&self, ptr: NonNull<T>) {
+        let Some(idx) = self.inner.magazine else {
+            return self.lock().free(ptr);
+        };
+
+        let _interrupts_disabled = InterruptDisabler::new();
+        let magazine = magazine(idx);
+
+        if magazine.len == MAGAZINE_SIZE {
+            let mut lock = self.lock();
+
+            for _ in 0..MAGAZINE_BATCH {
+                lock.free(magazine.pop().unwrap().cast());
+            }
+        }
+
+        magazine.push(ptr.cast());
+    }
 }
 
 unsafe impl<T, const OWN_INFO: bool> Allocator for SlabAlloc<T, OWN_INFO> {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        if layout.size() > Self::OBJECT_SIZE || Self::OBJECT_SIZE.next_multiple_of(layout.align()) != Self::OBJECT_SIZE {
+        if layout.size() > Self::OBJECT_SIZE || Self::SLOT_SIZE.next_multiple_of(layout.align()) != Self::SLOT_SIZE {
             return Err(AllocError);
         }
 
-        match self.lock().alloc() {
+        match self.alloc_cached() {
             Some(ptr) => Ok(NonNull::from_raw_parts(ptr.cast(), Self::OBJECT_SIZE)),
             None => Err(AllocError),
         }
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
-        self.lock().free(ptr.cast())
+        self.free_cached(ptr.cast())
     }
 
     unsafe fn grow(&self, ptr: NonNull<u8>, _old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        if new_layout.size() > Self::OBJECT_SIZE || Self::OBJECT_SIZE.next_multiple_of(new_layout.align()) != Self::OBJECT_SIZE {
+        if new_layout.size() > Self::OBJECT_SIZE || Self::SLOT_SIZE.next_multiple_of(new_layout.align()) != Self::SLOT_SIZE {
             return Err(AllocError);
         }
 
@@ -230,7 +401,7 @@ unsafe impl<T, const OWN_INFO: bool> Allocator for SlabAlloc<T, OWN_INFO> {
     }
 
     unsafe fn shrink(&self, ptr: NonNull<u8>, _old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        if Self::OBJECT_SIZE.next_multiple_of(new_layout.align()) != Self::OBJECT_SIZE {
+        if Self::SLOT_SIZE.next_multiple_of(new_layout.align()) != Self::SLOT_SIZE {
             return Err(AllocError);
         }
 
@@ -322,6 +493,32 @@ impl<'a, T, const OWN_INFO: bool> SlabAllocLock<'a, T, OWN_INFO> {
                 return None;
             };
 
+            if DEBUG {
+                let first_obj = if OWN_INFO { 1 } else { 0 };
+
+                for idx in first_obj..SlabAlloc::<T, OWN_INFO>::OBJECTS_PER_SLAB {
+                    // SAFETY: The slab was just allocated, so none of the objects other than its own info are in use yet
+                    unsafe {
+                        fill_pattern(
+                            self.obj_ptr(&*slab.as_ptr(), idx),
+                            SlabAlloc::<T, OWN_INFO>::OBJECT_SIZE,
+                            POISON_BYTE,
+                        );
+                    }
+                }
+
+                for idx in 0..SlabAlloc::<T, OWN_INFO>::OBJECTS_PER_SLAB {
+                    // SAFETY: Redzones are never handed out as part of an object
+                    unsafe {
+                        fill_pattern(
+                            self.redzone_ptr(&*slab.as_ptr(), idx),
+                            SlabAlloc::<T, OWN_INFO>::REDZONE_SIZE,
+                            REDZONE_BYTE,
+                        );
+                    }
+                }
+            }
+
             unsafe {
                 (*slab.as_ptr()).next = self.slabs.first;
                 (*slab.as_ptr()).next_free = self.slabs.first_free;
@@ -343,7 +540,49 @@ impl<'a, T, const OWN_INFO: bool> SlabAllocLock<'a, T, OWN_INFO> {
             first_free.next_free = None;
         }
 
-        Some(unsafe { first_free.get_obj(idx, SlabAlloc::<T, OWN_INFO>::OBJECT_SIZE).cast() })
+        if DEBUG {
+            // SAFETY: The object was free until now, so nothing else can be accessing it or its redzone
+            unsafe {
+                self.check_redzone(first_free, idx);
+
+                if let Some(off) = check_pattern(self.obj_ptr(first_free, idx), SlabAlloc::<T, OWN_INFO>::OBJECT_SIZE, POISON_BYTE) {
+                    panic!(
+                        "{}: object at {:p} was written to at offset {} after being freed",
+                        self.alloc.name(),
+                        self.obj_ptr(first_free, idx),
+                        off
+                    );
+                }
+            }
+        }
+
+        Some(unsafe { first_free.get_obj(idx, SlabAlloc::<T, OWN_INFO>::SLOT_SIZE).cast() })
+    }
+
+    fn obj_ptr(&self, slab: &SlabInfo, idx: usize) -> NonNull<()> {
+        // SAFETY: The index is always less than the number of objects in the slab
+        unsafe { slab.get_obj(idx, SlabAlloc::<T, OWN_INFO>::SLOT_SIZE) }
+    }
+
+    fn redzone_ptr(&self, slab: &SlabInfo, idx: usize) -> NonNull<()> {
+        // SAFETY: The redzone of an object lies between the end of the object and the end of its slot
+        unsafe { self.obj_ptr(slab, idx).byte_add(SlabAlloc::<T, OWN_INFO>::OBJECT_SIZE) }
+    }
+
+    /// Panics if the redzone following the provided object has been overwritten.
+    ///
+    /// # Safety
+    ///
+    /// The provided index must refer to an object in the provided slab.
+    unsafe fn check_redzone(&self, slab: &SlabInfo, idx: usize) {
+        if let Some(off) = check_pattern(self.redzone_ptr(slab, idx), SlabAlloc::<T, OWN_INFO>::REDZONE_SIZE, REDZONE_BYTE) {
+            panic!(
+                "{}: redzone after object at {:p} was overwritten at offset {}",
+                self.alloc.name(),
+                self.obj_ptr(slab, idx),
+                off
+            );
+        }
     }
 
     pub unsafe fn free(&mut self, ptr: NonNull<T>) {
@@ -354,9 +593,9 @@ impl<'a, T, const OWN_INFO: bool> SlabAllocLock<'a, T, OWN_INFO> {
             let slab = &mut *slab.as_ptr();
             if ptr >= slab.ptr && ptr < slab.ptr.byte_add(SlabAlloc::<T, OWN_INFO>::SLAB_SIZE) {
                 let slab_off = ptr.byte_offset_from(slab.ptr) as usize;
-                let idx = slab_off / SlabAlloc::<T, OWN_INFO>::OBJECT_SIZE;
+                let idx = slab_off / SlabAlloc::<T, OWN_INFO>::SLOT_SIZE;
 
-                if slab_off != idx * SlabAlloc::<T, OWN_INFO>::OBJECT_SIZE {
+                if slab_off != idx * SlabAlloc::<T, OWN_INFO>::SLOT_SIZE {
                     panic!("attempt to free misaligned pointer");
                 }
 
@@ -364,6 +603,11 @@ impl<'a, T, const OWN_INFO: bool> SlabAllocLock<'a, T, OWN_INFO> {
                     panic!("double free detected");
                 }
 
+                if DEBUG {
+                    self.check_redzone(slab, idx);
+                    fill_pattern(self.obj_ptr(slab, idx), SlabAlloc::<T, OWN_INFO>::OBJECT_SIZE, POISON_BYTE);
+                }
+
                 slab.num_free += 1;
                 if slab.num_free == 1 {
                     slab.next_free = self.slabs.first_free;
@@ -429,15 +673,15 @@ static SLAB_ALLOCS: UninterruptibleSpinlock<SlabAllocList> = UninterruptibleSpin
 
 static SLAB_INFO: SlabAlloc<SlabInfo, true> = SlabAlloc::new("SLAB_INFO");
 
-pub static SLAB_8: SlabAlloc<[u8; 8]> = SlabAlloc::new("SLAB_8");
-pub static SLAB_16: SlabAlloc<[u8; 16]> = SlabAlloc::new("SLAB_16");
-pub static SLAB_32: SlabAlloc<[u8; 32]> = SlabAlloc::new("SLAB_32");
-pub static SLAB_64: SlabAlloc<[u8; 64]> = SlabAlloc::new("SLAB_64");
-pub static SLAB_128: SlabAlloc<[u8; 128]> = SlabAlloc::new("SLAB_128");
-pub static SLAB_256: SlabAlloc<[u8; 256]> = SlabAlloc::new("SLAB_256");
-pub static SLAB_512: SlabAlloc<[u8; 512]> = SlabAlloc::new("SLAB_512");
-pub static SLAB_1024: SlabAlloc<[u8; 1024]> = SlabAlloc::new("SLAB_1024");
-pub static SLAB_2048: SlabAlloc<[u8; 2048]> = SlabAlloc::new("SLAB_2048");
+pub static SLAB_8: SlabAlloc<[u8; 8]> = SlabAlloc::with_magazine("SLAB_8", 0);
+pub static SLAB_16: SlabAlloc<[u8; 16]> = SlabAlloc::with_magazine("SLAB_16", 1);
+pub static SLAB_32: SlabAlloc<[u8; 32]> = SlabAlloc::with_magazine("SLAB_32", 2);
+pub static SLAB_64: SlabAlloc<[u8; 64]> = SlabAlloc::with_magazine("SLAB_64", 3);
+pub static SLAB_128: SlabAlloc<[u8; 128]> = SlabAlloc::with_magazine("SLAB_128", 4);
+pub static SLAB_256: SlabAlloc<[u8; 256]> = SlabAlloc::with_magazine("SLAB_256", 5);
+pub static SLAB_512: SlabAlloc<[u8; 512]> = SlabAlloc::with_magazine("SLAB_512", 6);
+pub static SLAB_1024: SlabAlloc<[u8; 1024]> = SlabAlloc::with_magazine("SLAB_1024", 7);
+pub static SLAB_2048: SlabAlloc<[u8; 2048]> = SlabAlloc::with_magazine("SLAB_2048", 8);
 
 pub fn registered_slab_allocs() -> SlabAllocListIter {
     let guard = SLAB_ALLOCS.lock();
@@ -552,7 +796,7 @@ mod test {
             assert!(!slab_info.free.get(0));
             assert!(!slab_info.free.get(1));
             assert_eq!(ptr_a.cast(), slab_info.ptr);
-            assert_eq!(ptr_b.cast(), unsafe { slab_info.ptr.byte_add(8) });
+            assert_eq!(ptr_b.cast(), unsafe { slab_info.ptr.byte_add(SlabAlloc::<[u8; 8]>::SLOT_SIZE) });
 
             assert_eq!(alloc.count(), (2, SlabAlloc::<[u8; 8]>::OBJECTS_PER_SLAB));
         }
@@ -609,7 +853,10 @@ mod test {
         let ptr_a = alloc.allocate(Layout::new::<u64>()).expect("allocation failure in slab");
 
         for i in 1..SlabAlloc::<[u8; 8]>::OBJECTS_PER_SLAB {
-            assert_eq!(Ok(unsafe { ptr_a.byte_add(i * 8) }), alloc.allocate(Layout::new::<u64>()));
+            assert_eq!(
+                Ok(unsafe { ptr_a.byte_add(i * SlabAlloc::<[u8; 8]>::SLOT_SIZE) }),
+                alloc.allocate(Layout::new::<u64>())
+            );
         }
 
         {
@@ -764,8 +1011,61 @@ mod test {
 
         unsafe {
             for i in 0..SlabAlloc::<[u8; 8]>::OBJECTS_PER_SLAB {
-                alloc.deallocate(ptr_a.byte_add(i * 8).cast(), Layout::new::<u64>());
+                alloc.deallocate(ptr_a.byte_add(i * SlabAlloc::<[u8; 8]>::SLOT_SIZE).cast(), Layout::new::<u64>());
             }
         }
     }
+
+    #[test_case]
+    fn test_debug_redzone_and_poison() {
+        if !DEBUG {
+            crate::test_util::skip("slab_debug feature is not enabled");
+            return;
+        }
+
+        let alloc = create_alloc::<16, false>();
+        let ptr = alloc
+            .allocate(Layout::new::<u64>())
+            .expect("allocation failure in slab")
+            .cast::<()>();
+
+        unsafe {
+            assert_eq!(None, check_pattern(ptr.byte_add(16), redzone_size(16), REDZONE_BYTE));
+
+            fill_pattern(ptr, 16, 0);
+            alloc.deallocate(ptr.cast(), Layout::new::<u64>());
+
+            assert_eq!(None, check_pattern(ptr, 16, POISON_BYTE));
+            assert_eq!(None, check_pattern(ptr.byte_add(16), redzone_size(16), REDZONE_BYTE));
+        }
+    }
+
+    #[test_case]
+    fn test_magazine_reuses_freed_object() {
+        if DEBUG {
+            crate::test_util::skip("magazines are disabled by the slab_debug feature");
+            return;
+        }
+
+        let _interrupts_disabled = InterruptDisabler::new();
+        let layout = Layout::new::<[u8; 64]>();
+
+        let ptr_a = SLAB_64.allocate(layout).expect("allocation failure in slab");
+        let count = SLAB_64.lock().count();
+
+        unsafe {
+            SLAB_64.deallocate(ptr_a.cast(), layout);
+        }
+
+        assert_eq!(count, SLAB_64.lock().count());
+
+        let ptr_b = SLAB_64.allocate(layout).expect("allocation failure in slab");
+
+        assert_eq!(ptr_a, ptr_b);
+        assert_eq!(count, SLAB_64.lock().count());
+
+        unsafe {
+            SLAB_64.deallocate(ptr_b.cast(), layout);
+        }
+    }
 }
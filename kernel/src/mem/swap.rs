@@ -0,0 +1,972 @@
+//! Swapping of anonymous user pages to a backing store.
+//!
+//! When enabled by calling [`enable`] with a [`SwapBackend`], anonymous pages mapped into user address spaces can be written out to the
+//! backing store to free up physical memory. Each user process tracks its anonymous pages using an [`AnonymousPages`] structure, which
+//! approximates least-recently-used ordering by periodically aging pages based on the processor's accessed bits. A background thread
+//! ages pages and swaps out the oldest ones whenever free physical memory runs low.
+//!
+//! A page that has been swapped out is left with a non-present page table entry recording the swap slot that holds its contents. When the
+//! page is next accessed, the resulting page fault is resolved by [`handle_page_fault`], which reads the page back into a newly allocated
+//! frame.
+//!
+//! Swap space can be backed by a block device using [`BlockSwapBackend`], which lays slots out one after another on the device. Once no
+//! pages are swapped out any more, swapping can be turned off again using [`disable`], which also stops the background thread.
+
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use core::{fmt, hint};
+
+use super::frame::{self, FrameAllocator, FrameFlags};
+use super::tlb;
+use crate::arch::page::{get_phys_mem_ptr_slice, AddressSpace, PageFlags, MAX_SWAP_SLOTS, PAGE_SIZE};
+use crate::arch::VirtAddr;
+use crate::io::block::BlockDevice;
+use crate::io::dev::iostat::{self, IoDirection, IoStats};
+use crate::io::dev::DeviceRef;
+use crate::sched::task::{self, Process, Thread};
+use crate::sync::UninterruptibleSpinlock;
+use crate::{log, util};
+
+const SWAPPER_STACK_SIZE: usize = 4 * 4096;
+const SWAPPER_INTERVAL: Duration = Duration::from_millis(500);
+
+/// When fewer than this many frames are free, the swapper will begin swapping out pages.
+const LOW_WATERMARK_FRAMES: usize = 1024;
+
+/// The swapper will try to swap out pages until at least this many frames are free.
+const HIGH_WATERMARK_FRAMES: usize = 2048;
+
+/// How many times [`BlockSwapBackend`] polls a request for completion before giving up on it.
+const MAX_BLOCK_POLL_SPINS: u32 = 10_000_000;
+
+/// An error that can occur when swapping pages in or out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    /// Swapping has not been enabled.
+    NotEnabled,
+    /// Swapping has already been enabled with a different backend.
+    AlreadyEnabled,
+    /// All slots in the swap space are in use.
+    SwapFull,
+    /// The page is not currently swapped out.
+    NotSwapped,
+    /// No physical memory was available to swap a page back in.
+    OutOfMemory,
+    /// The swap backend failed to read or write a slot.
+    IoError,
+    /// Swapping cannot be disabled while pages are still swapped out.
+    InUse,
+    /// The device cannot be used as swap space, e.g. because it is read-only or too small to hold a single page.
+    UnsupportedDevice,
+}
+
+impl fmt::Display for SwapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SwapError::NotEnabled => write!(f, "swap is not enabled"),
+            SwapError::AlreadyEnabled => write!(f, "swap is already enabled"),
+            SwapError::SwapFull => write!(f, "swap space is full"),
+            SwapError::NotSwapped => write!(f, "page is not swapped out"),
+            SwapError::OutOfMemory => write!(f, "out of physical memory"),
+            SwapError::IoError => write!(f, "swap I/O error"),
+            SwapError::InUse => write!(f, "swap space is in use"),
+            SwapError::UnsupportedDevice => write!(f, "device cannot be used as swap space"),
+        }
+    }
+}
+
+/// A backing store made up of page-sized slots to which swapped out pages are written.
+///
+/// Since reading and writing slots happens while handling page faults, implementations must not block.
+pub trait SwapBackend: Send {
+    /// Gets the number of page-sized slots available in this backing store.
+    fn num_slots(&self) -> u64;
+
+    /// Reads the contents of the provided slot into a page-sized buffer.
+    fn read_slot(&mut self, slot: u64, buf: &mut [u8]) -> Result<(), SwapError>;
+
+    /// Writes the contents of a page-sized buffer to the provided slot.
+    fn write_slot(&mut self, slot: u64, buf: &[u8]) -> Result<(), SwapError>;
+
+    /// Notifies this backing store that the provided slot no longer holds any useful data.
+    fn discard_slot(&mut self, slot: u64) {
+        let _ = slot;
+    }
+}
+
+/// A heap buffer that a block device request transfers to or from. It is only accessed through a raw pointer so that it can be handed to
+/// the request's completion callback while the device may still be accessing it, and is freed when dropped.
+struct BounceBuffer(*mut [u8]);
+
+// SAFETY: The buffer is uniquely owned, so it can be freed from whichever context ends up dropping it
+unsafe impl Send for BounceBuffer {}
+
+impl BounceBuffer {
+    fn new(src: &[u8]) -> Result<BounceBuffer, SwapError> {
+        util::try_boxed_slice(src)
+            .map(|buf| BounceBuffer(Box::into_raw(buf)))
+            .map_err(|_| SwapError::OutOfMemory)
+    }
+}
+
+impl Drop for BounceBuffer {
+    fn drop(&mut self) {
+        // SAFETY: The buffer was allocated by BounceBuffer::new and nothing else is using it by the time it is dropped
+        unsafe {
+            drop(Box::from_raw(self.0));
+        }
+    }
+}
+
+/// A swap backend that stores slots on a block device, with slot `n` occupying the page-sized run of sectors starting at byte offset
+/// `n * PAGE_SIZE`. Whatever the device contained beforehand is overwritten.
+///
+/// Since slots are read and written while handling page faults with process locks held, requests cannot be waited on by blocking. Each
+/// request is instead polled until it completes. A device that only completes requests from an interrupt handler running on the current
+/// core will never complete a request while it is being polled, so such a request is abandoned after a while and reported as an I/O error.
+/// Its slot then cannot be used until the device eventually completes the request.
+pub struct BlockSwapBackend {
+    dev: DeviceRef<dyn BlockDevice>,
+    sectors_per_slot: u64,
+    num_slots: u64,
+    in_flight: Arc<UninterruptibleSpinlock<BTreeSet<u64>>>,
+}
+
+impl BlockSwapBackend {
+    /// Creates a swap backend storing slots on the provided block device.
+    pub fn new(dev: DeviceRef<dyn BlockDevice>) -> Result<BlockSwapBackend, SwapError> {
+        let sector_size = dev.dev().sector_size();
+
+        if dev.dev().is_read_only() || sector_size > PAGE_SIZE {
+            return Err(SwapError::UnsupportedDevice);
+        }
+
+        let sectors_per_slot = (PAGE_SIZE / sector_size) as u64;
+        let num_slots = dev.dev().num_sectors() / sectors_per_slot;
+
+        if num_slots == 0 {
+            return Err(SwapError::UnsupportedDevice);
+        }
+
+        Ok(BlockSwapBackend {
+            dev,
+            sectors_per_slot,
+            num_slots,
+            in_flight: Arc::new(UninterruptibleSpinlock::new(BTreeSet::new())),
+        })
+    }
+
+    /// Gets the block device on which slots are stored.
+    pub fn dev(&self) -> &DeviceRef<dyn BlockDevice> {
+        &self.dev
+    }
+
+    fn transfer(&self, slot: u64, dir: IoDirection, buf: BounceBuffer) -> Result<BounceBuffer, SwapError> {
+        if slot >= self.num_slots || !self.in_flight.lock().insert(slot) {
+            return Err(SwapError::IoError);
+        }
+
+        let sector = slot * self.sectors_per_slot;
+
+        // SAFETY: The bounce buffer is not accessed or freed until the request has completed
+        let mut future = unsafe {
+            match dir {
+                IoDirection::Read => self.dev.dev().read_sectors(sector, buf.0),
+                IoDirection::Write => self.dev.dev().write_sectors(sector, buf.0),
+            }
+        };
+
+        for _ in 0..MAX_BLOCK_POLL_SPINS {
+            if future.update_readiness() {
+                break;
+            }
+
+            tlb::poll();
+            hint::spin_loop();
+        }
+
+        match future.try_unwrap_without_update() {
+            Ok(result) => {
+                self.in_flight.lock().remove(&slot);
+                result.map(|()| buf).map_err(|_| SwapError::IoError)
+            },
+            Err(future) => {
+                let in_flight = self.in_flight.clone();
+
+                log!(Warning, "swap", "Request for slot {} on {} timed out", slot, self.dev.name());
+                future.when_resolved(move |_| {
+                    drop(buf);
+                    in_flight.lock().remove(&slot);
+                });
+                Err(SwapError::IoError)
+            },
+        }
+    }
+}
+
+impl SwapBackend for BlockSwapBackend {
+    fn num_slots(&self) -> u64 {
+        self.num_slots
+    }
+
+    fn read_slot(&mut self, slot: u64, buf: &mut [u8]) -> Result<(), SwapError> {
+        let bounce = self.transfer(slot, IoDirection::Read, BounceBuffer::new(buf)?)?;
+
+        // SAFETY: The request has completed, so nothing else is accessing the bounce buffer any more
+        buf.copy_from_slice(unsafe { &*bounce.0 });
+        Ok(())
+    }
+
+    fn write_slot(&mut self, slot: u64, buf: &[u8]) -> Result<(), SwapError> {
+        self.transfer(slot, IoDirection::Write, BounceBuffer::new(buf)?).map(|_| ())
+    }
+}
+
+/// Usage statistics for the swap space.
+#[derive(Debug, Clone, Copy)]
+pub struct SwapStats {
+    pub total_slots: u64,
+    pub used_slots: u64,
+}
+
+struct SwapSpace {
+    backend: Box<dyn SwapBackend>,
+    io_stats: Arc<IoStats>,
+    used: Vec<u64>,
+    num_slots: u64,
+    num_used: u64,
+    next_search: usize,
+}
+
+impl SwapSpace {
+    fn alloc_slot(&mut self) -> Option<u64> {
+        let num_words = self.used.len();
+
+        for i in 0..num_words {
+            let word_idx = (self.next_search + i) % num_words;
+            let word = self.used[word_idx];
+
+            if word != !0 {
+                let slot = (word_idx as u64) * 64 + u64::from(word.trailing_ones());
+
+                if slot >= self.num_slots {
+                    continue;
+                }
+
+                self.used[word_idx] |= 1 << (slot % 64);
+                self.num_used += 1;
+                self.next_search = word_idx;
+
+                return Some(slot);
+            }
+        }
+
+        None
+    }
+
+    fn free_slot(&mut self, slot: u64) {
+        let word = &mut self.used[(slot / 64) as usize];
+
+        assert!(*word & (1 << (slot % 64)) != 0, "Attempt to free unused swap slot {}", slot);
+        *word &= !(1 << (slot % 64));
+        self.num_used -= 1;
+
+        self.backend.discard_slot(slot);
+    }
+}
+
+static SWAP: UninterruptibleSpinlock<Option<SwapSpace>> = UninterruptibleSpinlock::new(None);
+static SWAPPER_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Enables swapping using the provided backing store and starts the background thread responsible for aging and swapping out pages.
+pub fn enable(backend: Box<dyn SwapBackend>) -> Result<(), SwapError> {
+    let num_slots = backend.num_slots().min(MAX_SWAP_SLOTS);
+    let start_swapper;
+
+    {
+        let mut swap = SWAP.lock();
+
+        if swap.is_some() {
+            return Err(SwapError::AlreadyEnabled);
+        }
+
+        let io_stats = Arc::new(IoStats::new());
+
+        iostat::register("swap", &io_stats);
+        *swap = Some(SwapSpace {
+            backend,
+            io_stats,
+            used: vec![0; num_slots.div_ceil(64) as usize],
+            num_slots,
+            num_used: 0,
+            next_search: 0,
+        });
+
+        // The swapper only checks whether it should exit while holding this lock, so it cannot exit after this without seeing that swap
+        // has been enabled again
+        start_swapper = !SWAPPER_STARTED.swap(true, Ordering::Relaxed);
+    }
+
+    log!(Info, "swap", "Enabled swap space with {} KiB", num_slots * PAGE_SIZE as u64 / 1024);
+
+    if start_swapper {
+        Process::kernel()
+            .lock()
+            .create_kernel_thread("swapper", run_swapper, SWAPPER_STACK_SIZE)
+            .lock()
+            .wake();
+    }
+
+    Ok(())
+}
+
+/// Disables swapping, returning the backing store that was being used. The background thread exits the next time it wakes up. Swapping
+/// cannot be disabled while any pages are still swapped out.
+pub fn disable() -> Result<Box<dyn SwapBackend>, SwapError> {
+    let backend = {
+        let mut swap = SWAP.lock();
+
+        match *swap {
+            None => return Err(SwapError::NotEnabled),
+            Some(ref space) if space.num_used != 0 => return Err(SwapError::InUse),
+            Some(_) => swap.take().unwrap().backend,
+        }
+    };
+
+    log!(Info, "swap", "Disabled swap space");
+    Ok(backend)
+}
+
+/// Gets usage statistics for the swap space, or [`None`] if swapping is not enabled.
+pub fn stats() -> Option<SwapStats> {
+    SWAP.lock().as_ref().map(|swap| SwapStats {
+        total_slots: swap.num_slots,
+        used_slots: swap.num_used,
+    })
+}
+
+/// Releases a swap slot that is no longer referred to by any page table entry.
+pub(crate) fn free_slot(slot: u64) {
+    if let Some(ref mut swap) = *SWAP.lock() {
+        swap.free_slot(slot);
+    }
+}
+
+/// Copies the contents of a swap slot into a newly allocated slot, so that a swapped out page can be duplicated into another address
+/// space without swapping it back in. Returns the new slot, which must eventually be released using [`free_slot`].
+pub(crate) fn duplicate_slot(slot: u64) -> Result<u64, SwapError> {
+    let mut buf = vec![0; PAGE_SIZE];
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().ok_or(SwapError::NotEnabled)?;
+    let new_slot = swap.alloc_slot().ok_or(SwapError::SwapFull)?;
+
+    let request = swap.io_stats.begin(IoDirection::Read);
+
+    if let Err(err) = swap.backend.read_slot(slot, &mut buf) {
+        request.fail();
+        swap.free_slot(new_slot);
+        return Err(err);
+    }
+
+    request.finish(PAGE_SIZE);
+
+    let request = swap.io_stats.begin(IoDirection::Write);
+
+    if let Err(err) = swap.backend.write_slot(new_slot, &buf) {
+        request.fail();
+        swap.free_slot(new_slot);
+        return Err(err);
+    }
+
+    request.finish(PAGE_SIZE);
+    Ok(new_slot)
+}
+
+fn write_page(addrspace: &mut AddressSpace, addr: VirtAddr) -> Result<(), SwapError> {
+    let (frame, _) = addrspace.get_page(addr).ok_or(SwapError::NotSwapped)?;
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().ok_or(SwapError::NotEnabled)?;
+    let slot = swap.alloc_slot().ok_or(SwapError::SwapFull)?;
+    let request = swap.io_stats.begin(IoDirection::Write);
+
+    // SAFETY: The frame is mapped into the address space, so it is valid memory. Since this address space is locked, nothing else can be
+    //         modifying its mappings.
+    let result = unsafe {
+        swap.backend
+            .write_slot(slot, &*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr())
+    };
+
+    if let Err(err) = result {
+        request.fail();
+        swap.free_slot(slot);
+        return Err(err);
+    }
+
+    request.finish(PAGE_SIZE);
+
+    // SAFETY: The contents of the frame have been saved to the swap slot, so the frame can be unmapped and this address space's reference
+    //         to it dropped.
+    unsafe {
+        addrspace.set_swap_entry_user(addr, slot);
+        frame::release(frame);
+    }
+
+    Ok(())
+}
+
+fn read_page(addrspace: &mut AddressSpace, addr: VirtAddr, flags: PageFlags) -> Result<(), SwapError> {
+    let slot = addrspace.get_swap_entry(addr).ok_or(SwapError::NotSwapped)?;
+    let frame = frame::get_allocator().alloc_one().ok_or(SwapError::OutOfMemory)?;
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().ok_or(SwapError::NotEnabled)?;
+
+    let request = swap.io_stats.begin(IoDirection::Read);
+
+    // SAFETY: The frame was just allocated, so nothing else can be using it.
+    let result = unsafe {
+        swap.backend
+            .read_slot(slot, &mut *get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr())
+    };
+
+    if let Err(err) = result {
+        request.fail();
+
+        unsafe {
+            frame::get_allocator().free_one(frame);
+        }
+
+        return Err(err);
+    }
+
+    request.finish(PAGE_SIZE);
+
+    // SAFETY: The frame now holds the contents of the page, so it can replace the swap entry.
+    unsafe {
+        addrspace.set_page_user(addr, Some((frame, flags)));
+    }
+
+    swap.free_slot(slot);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AnonymousPage {
+    addr: VirtAddr,
+    flags: PageFlags,
+    age: u8,
+    swapped: bool,
+}
+
+/// Tracks the anonymous pages mapped into a user address space so that the least recently used ones can be chosen to be swapped out.
+pub struct AnonymousPages {
+    pages: VecDeque<AnonymousPage>,
+}
+
+impl AnonymousPages {
+    /// The age at which a page stops getting older. Pages that have not been accessed for this many aging passes are all considered equally
+    /// good candidates for being swapped out.
+    const MAX_AGE: u8 = 16;
+
+    /// Creates a new empty list of anonymous pages.
+    pub const fn new() -> AnonymousPages {
+        AnonymousPages { pages: VecDeque::new() }
+    }
+
+    /// Gets the number of anonymous pages being tracked, including those that are currently swapped out.
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Checks whether no anonymous pages are being tracked.
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Gets the number of tracked anonymous pages that are currently swapped out.
+    pub fn num_swapped(&self) -> usize {
+        self.pages.iter().filter(|p| p.swapped).count()
+    }
+
+    /// Starts tracking an anonymous page that has been mapped at the provided address with the provided flags, making it eligible for
+    /// being swapped out.
+    pub fn track(&mut self, addr: VirtAddr, flags: PageFlags) {
+        debug_assert!(!self.pages.iter().any(|p| p.addr == addr));
+
+        self.pages.push_back(AnonymousPage {
+            addr,
+            flags,
+            age: 0,
+            swapped: false,
+        });
+    }
+
+    /// Stops tracking the anonymous page at the provided address, e.g. because it is about to be unmapped. If the page is currently swapped
+    /// out, its swap slot is released and its page table entry is cleared. Returns `false` if the page was not being tracked.
+    pub fn untrack(&mut self, addrspace: &mut AddressSpace, addr: VirtAddr) -> bool {
+        if let Some(idx) = self.pages.iter().position(|p| p.addr == addr) {
+            let page = self.pages.remove(idx).unwrap();
+
+            if page.swapped {
+                if let Some(slot) = addrspace.get_swap_entry(addr) {
+                    // SAFETY: A swapped out page has no frame mapped to it.
+                    unsafe {
+                        addrspace.set_page_user(addr, None);
+                    }
+                    free_slot(slot);
+                }
+            }
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Changes the flags with which the tracked anonymous page at the provided address is mapped when it is swapped back in. Returns
+    /// `false` if the page is not being tracked.
+    pub fn set_flags(&mut self, addr: VirtAddr, flags: PageFlags) -> bool {
+        match self.pages.iter_mut().find(|p| p.addr == addr) {
+            Some(page) => {
+                page.flags = flags;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Performs one aging pass over all resident pages. Pages that have been accessed since the last pass become young again, while all
+    /// other pages grow older.
+    pub fn age(&mut self, addrspace: &mut AddressSpace) {
+        for page in self.pages.iter_mut().filter(|p| !p.swapped) {
+            if addrspace.test_and_clear_accessed(page.addr) {
+                page.age = 0;
+            } else {
+                page.age = (page.age + 1).min(AnonymousPages::MAX_AGE);
+            }
+        }
+    }
+
+    /// Swaps out up to `max_pages` resident pages, starting with the oldest ones. Pages backed by pinned frames are never swapped out, nor
+    /// are pages backed by frames shared copy-on-write with another address space, since swapping those out would use up a swap slot
+    /// without freeing any memory. Returns the number of pages that were swapped out.
+    pub fn swap_out(&mut self, addrspace: &mut AddressSpace, max_pages: usize) -> Result<usize, SwapError> {
+        let is_swappable = |addr| {
+            addrspace.get_page(addr).is_some_and(|(frame, _)| {
+                frame::info(frame).map_or(true, |info| !info.flags().contains(FrameFlags::PINNED) && info.ref_count() == 1)
+            })
+        };
+        let mut candidates: Vec<usize> = (0..self.pages.len())
+            .filter(|&i| !self.pages[i].swapped && is_swappable(self.pages[i].addr))
+            .collect();
+
+        candidates.sort_by_key(|&i| core::cmp::Reverse(self.pages[i].age));
+        candidates.truncate(max_pages);
+
+        let mut num_swapped = 0;
+
+        for i in candidates {
+            let page = &mut self.pages[i];
+
+            match write_page(addrspace, page.addr) {
+                Ok(()) => {
+                    page.swapped = true;
+                    num_swapped += 1;
+                },
+                Err(err) if num_swapped == 0 => {
+                    return Err(err);
+                },
+                Err(_) => {
+                    break;
+                },
+            }
+        }
+
+        Ok(num_swapped)
+    }
+
+    /// Reads a swapped out page at the provided address back into memory.
+    pub fn swap_in(&mut self, addrspace: &mut AddressSpace, addr: VirtAddr) -> Result<(), SwapError> {
+        let page = self
+            .pages
+            .iter_mut()
+            .find(|p| p.addr == addr && p.swapped)
+            .ok_or(SwapError::NotSwapped)?;
+
+        read_page(addrspace, addr, page.flags)?;
+
+        page.swapped = false;
+        page.age = 0;
+        Ok(())
+    }
+}
+
+impl Default for AnonymousPages {
+    fn default() -> Self {
+        AnonymousPages::new()
+    }
+}
+
+/// Attempts to resolve a page fault at the provided address by swapping in the corresponding page of the interrupted thread's process.
+/// Returns `true` if the page was swapped in and the faulting access can be retried.
+///
+/// # Lock Ordering
+///
+/// This locks the process of the interrupted thread, so page faults on swapped out pages must not occur while that lock is held.
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let process = match Thread::current_interrupted().and_then(|t| t.process().upgrade()) {
+        Some(process) if !process.is_kernel_process() => process,
+        _ => {
+            return false;
+        },
+    };
+
+    let mut process_lock = process.lock();
+    let page = VirtAddr::new_truncate(addr.as_u64() & !(PAGE_SIZE as u64 - 1));
+
+    match process_lock.memory() {
+        Some((addrspace, anon_pages)) => match anon_pages.swap_in(addrspace, page) {
+            Ok(()) => true,
+            Err(SwapError::NotSwapped) => false,
+            Err(err) => {
+                log!(
+                    Error,
+                    "swap",
+                    "Failed to swap in page {:#x} of pid {}: {}",
+                    page.as_u64(),
+                    process.pid(),
+                    err
+                );
+                false
+            },
+        },
+        None => false,
+    }
+}
+
+fn user_processes() -> Vec<Pin<Arc<Process>>> {
+    task::all_processes().into_iter().filter(|p| !p.is_kernel_process()).collect()
+}
+
+/// Performs an aging pass over the anonymous pages of all user processes and, if free physical memory is running low, swaps out the
+/// oldest pages until enough memory is free again. Returns the number of pages swapped out.
+pub fn reclaim() -> usize {
+    let processes = user_processes();
+
+    for process in processes.iter() {
+        if let Some((addrspace, anon_pages)) = process.lock().memory() {
+            anon_pages.age(addrspace);
+        }
+    }
+
+    let num_free = frame::get_allocator().num_frames_available();
+
+    if num_free >= LOW_WATERMARK_FRAMES || stats().is_none() {
+        return 0;
+    }
+
+    let mut needed = HIGH_WATERMARK_FRAMES - num_free;
+    let mut num_swapped = 0;
+
+    for process in processes.iter() {
+        if needed == 0 {
+            break;
+        }
+
+        if let Some((addrspace, anon_pages)) = process.lock().memory() {
+            match anon_pages.swap_out(addrspace, needed) {
+                Ok(n) => {
+                    needed -= n;
+                    num_swapped += n;
+                },
+                Err(SwapError::SwapFull) => {
+                    log!(Warning, "swap", "Swap space is full");
+                    break;
+                },
+                Err(err) => {
+                    log!(Error, "swap", "Failed to swap out pages of pid {}: {}", process.pid(), err);
+                },
+            }
+        }
+    }
+
+    num_swapped
+}
+
+fn run_swapper() {
+    loop {
+        let num_swapped = reclaim();
+
+        if num_swapped != 0 {
+            log!(Debug, "swap", "Swapped out {} pages", num_swapped);
+        }
+
+        Thread::sleep(SWAPPER_INTERVAL);
+
+        let swap = SWAP.lock();
+
+        if swap.is_none() {
+            SWAPPER_STARTED.store(false, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+
+    use super::*;
+    use crate::io::block::ramdisk::{self, RAMDISK_SECTOR_SIZE};
+    use crate::io::block::BlockDeviceExt;
+
+    struct TestBackend(Vec<u8>);
+
+    impl SwapBackend for TestBackend {
+        fn num_slots(&self) -> u64 {
+            (self.0.len() / PAGE_SIZE) as u64
+        }
+
+        fn read_slot(&mut self, slot: u64, buf: &mut [u8]) -> Result<(), SwapError> {
+            let start = slot as usize * PAGE_SIZE;
+
+            buf.copy_from_slice(&self.0[start..start + PAGE_SIZE]);
+            Ok(())
+        }
+
+        fn write_slot(&mut self, slot: u64, buf: &[u8]) -> Result<(), SwapError> {
+            let start = slot as usize * PAGE_SIZE;
+
+            self.0[start..start + PAGE_SIZE].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    /// Runs the provided function with swapping enabled using the provided backend, then disables swapping again. Any swap space that was
+    /// already enabled is set aside in the meantime, so that tests neither depend on each other's backends nor leave theirs behind.
+    fn with_backend(backend: Box<dyn SwapBackend>, f: impl FnOnce()) {
+        let prev = match disable() {
+            Ok(prev) => Some(prev),
+            Err(SwapError::NotEnabled) => None,
+            Err(_) => {
+                crate::test_util::skip("swap space is in use");
+                return;
+            },
+        };
+
+        enable(backend).unwrap();
+        f();
+        disable().unwrap();
+
+        if let Some(prev) = prev {
+            enable(prev).unwrap();
+        }
+    }
+
+    #[test_case]
+    fn test_swap_out_and_in() {
+        with_backend(Box::new(TestBackend(vec![0; 4 * PAGE_SIZE])), || {
+            let process = Process::create(vec![String::from("test")]);
+            let addr = VirtAddr::new(0x1000_0000);
+            let flags = PageFlags::USER | PageFlags::WRITEABLE;
+
+            {
+                let mut process_lock = process.lock();
+                let (addrspace, anon_pages) = process_lock.memory().unwrap();
+                let frame = frame::get_allocator().alloc_one().unwrap();
+
+                unsafe {
+                    (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).fill(0xa5);
+                    addrspace.set_page_user(addr, Some((frame, flags)));
+                }
+                anon_pages.track(addr, flags);
+
+                let used_before = stats().unwrap().used_slots;
+
+                assert_eq!(Ok(1), anon_pages.swap_out(addrspace, 1));
+                assert_eq!(None, addrspace.get_page(addr));
+                assert!(addrspace.get_swap_entry(addr).is_some());
+                assert_eq!(1, anon_pages.num_swapped());
+                assert_eq!(used_before + 1, stats().unwrap().used_slots);
+
+                assert_eq!(Ok(()), anon_pages.swap_in(addrspace, addr));
+                assert_eq!(Err(SwapError::NotSwapped), anon_pages.swap_in(addrspace, addr));
+
+                let (frame, mapped_flags) = addrspace.get_page(addr).unwrap();
+
+                assert_eq!(flags, mapped_flags);
+                assert!(unsafe { (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).iter().all(|&b| b == 0xa5) });
+                assert_eq!(used_before, stats().unwrap().used_slots);
+                assert_eq!(0, anon_pages.num_swapped());
+            }
+
+            process.exit(0);
+        });
+    }
+
+    #[test_case]
+    fn test_swap_out_skips_shared() {
+        with_backend(Box::new(TestBackend(vec![0; 4 * PAGE_SIZE])), || {
+            let process = Process::create(vec![String::from("test")]);
+            let addr = VirtAddr::new(0x1000_0000);
+            let flags = PageFlags::USER | PageFlags::WRITEABLE;
+
+            {
+                let mut process_lock = process.lock();
+                let (addrspace, anon_pages) = process_lock.memory().unwrap();
+                let frame = frame::get_allocator().alloc_one().unwrap();
+
+                unsafe {
+                    addrspace.set_page_user(addr, Some((frame, flags)));
+                }
+                anon_pages.track(addr, flags);
+
+                frame::share(frame);
+                assert_eq!(Ok(0), anon_pages.swap_out(addrspace, 1));
+                assert_eq!(Some((frame, flags)), addrspace.get_page(addr));
+
+                unsafe {
+                    frame::release(frame);
+                }
+                assert_eq!(Ok(1), anon_pages.swap_out(addrspace, 1));
+                assert_eq!(Ok(()), anon_pages.swap_in(addrspace, addr));
+            }
+
+            process.exit(0);
+        });
+    }
+
+    #[test_case]
+    fn test_clone_address_space() {
+        with_backend(Box::new(TestBackend(vec![0; 4 * PAGE_SIZE])), || {
+            let process = Process::create(vec![String::from("test")]);
+            let resident = VirtAddr::new(0x1000_0000);
+            let swapped = VirtAddr::new(0x2000_0000);
+            let flags = PageFlags::USER | PageFlags::WRITEABLE;
+
+            {
+                let mut process_lock = process.lock();
+                let (addrspace, anon_pages) = process_lock.memory().unwrap();
+
+                for addr in [resident, swapped] {
+                    let frame = frame::get_allocator().alloc_one().unwrap();
+
+                    unsafe {
+                        (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).fill(0x5a);
+                        addrspace.set_page_user(addr, Some((frame, flags)));
+                    }
+                }
+
+                anon_pages.track(swapped, flags);
+                assert_eq!(Ok(1), anon_pages.swap_out(addrspace, 1));
+
+                let used_before = stats().unwrap().used_slots;
+                let mut clone = addrspace.clone_for_new_process().unwrap();
+                let (frame, parent_flags) = addrspace.get_page(resident).unwrap();
+
+                assert_eq!(Some((frame, parent_flags)), clone.get_page(resident));
+                assert!(parent_flags.contains(PageFlags::COPY_ON_WRITE));
+                assert_eq!(2, frame::ref_count(frame));
+                assert_ne!(addrspace.get_swap_entry(swapped), clone.get_swap_entry(swapped));
+                assert_eq!(used_before + 1, stats().unwrap().used_slots);
+
+                drop(clone);
+
+                assert_eq!(1, frame::ref_count(frame));
+                assert_eq!(used_before, stats().unwrap().used_slots);
+                assert_eq!(Ok(()), anon_pages.swap_in(addrspace, swapped));
+            }
+
+            process.exit(0);
+        });
+    }
+
+    #[test_case]
+    fn test_disable_in_use() {
+        with_backend(Box::new(TestBackend(vec![0; 4 * PAGE_SIZE])), || {
+            assert_eq!(Err(SwapError::AlreadyEnabled), enable(Box::new(TestBackend(vec![]))));
+
+            let slot = duplicate_slot(0).unwrap();
+
+            assert_eq!(Some(SwapError::InUse), disable().err());
+            free_slot(slot);
+        });
+    }
+
+    #[test_case]
+    fn test_swap_out_to_block_device() {
+        let dev = ramdisk::create("test-swap2", 4 * PAGE_SIZE).unwrap();
+
+        with_backend(Box::new(BlockSwapBackend::new(dev.clone()).unwrap()), || {
+            let process = Process::create(vec![String::from("test")]);
+            let addr = VirtAddr::new(0x1000_0000);
+            let flags = PageFlags::USER | PageFlags::WRITEABLE;
+
+            {
+                let mut process_lock = process.lock();
+                let (addrspace, anon_pages) = process_lock.memory().unwrap();
+                let frame = frame::get_allocator().alloc_one().unwrap();
+
+                unsafe {
+                    (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).fill(0x3c);
+                    addrspace.set_page_user(addr, Some((frame, flags)));
+                }
+                anon_pages.track(addr, flags);
+
+                assert_eq!(Ok(1), anon_pages.swap_out(addrspace, 1));
+
+                let sector = addrspace.get_swap_entry(addr).unwrap() * (PAGE_SIZE / RAMDISK_SECTOR_SIZE) as u64;
+                let mut sectors = vec![0; PAGE_SIZE];
+
+                assert_eq!(Ok(()), dev.dev().read_blocking(sector, &mut sectors));
+                assert!(sectors.iter().all(|&b| b == 0x3c));
+
+                assert_eq!(Ok(()), anon_pages.swap_in(addrspace, addr));
+
+                let (frame, _) = addrspace.get_page(addr).unwrap();
+
+                assert!(unsafe { (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).iter().all(|&b| b == 0x3c) });
+            }
+
+            process.exit(0);
+        });
+
+        assert!(ramdisk::remove("test-swap2"));
+    }
+
+    #[test_case]
+    fn test_block_backend() {
+        let dev = ramdisk::create("test-swap0", 2 * PAGE_SIZE + RAMDISK_SECTOR_SIZE).unwrap();
+        let mut backend = BlockSwapBackend::new(dev.clone()).unwrap();
+        let data: Vec<u8> = (0..PAGE_SIZE).map(|i| i as u8).collect();
+        let mut buf = vec![0; PAGE_SIZE];
+        let mut sector = [0; RAMDISK_SECTOR_SIZE];
+
+        assert_eq!(2, backend.num_slots());
+        assert_eq!(Ok(()), backend.write_slot(1, &data));
+        assert_eq!(Ok(()), backend.read_slot(1, &mut buf));
+        assert_eq!(data, buf);
+        assert_eq!(Err(SwapError::IoError), backend.read_slot(2, &mut buf));
+
+        // Slot 1 starts right after the end of slot 0 on the device
+        let slot_1_sector = (PAGE_SIZE / RAMDISK_SECTOR_SIZE) as u64;
+
+        assert_eq!(Ok(()), dev.dev().read_blocking(slot_1_sector, &mut sector));
+        assert_eq!(&data[..RAMDISK_SECTOR_SIZE], &sector[..]);
+
+        drop(backend);
+        assert!(ramdisk::remove("test-swap0"));
+
+        let image = vec![0; PAGE_SIZE].into_boxed_slice();
+        let dev = ramdisk::create_from_image("test-swap1", image, true).unwrap();
+
+        assert_eq!(Some(SwapError::UnsupportedDevice), BlockSwapBackend::new(dev).err());
+        assert!(ramdisk::remove("test-swap1"));
+    }
+}
@@ -0,0 +1,247 @@
+//! Invalidation of cached translations on all CPU cores (TLB shootdowns).
+//!
+//! Changing a page table entry only invalidates the translation cached in the current CPU core's TLB, so other cores may keep using a
+//! stale translation until it happens to be evicted. Whenever a kernel mapping is removed or made more restrictive, every other core must
+//! invalidate it before the page frame or virtual address it referred to can be reused. Pages to invalidate are collected in a
+//! [`TlbShootdown`], which invalidates them on all cores at once when flushed, so that unmapping a large region only interrupts the other
+//! cores once.
+//!
+//! Each shootdown is assigned a generation number and each online core records the generation of the last shootdown it has processed.
+//! The core starting a shootdown publishes the pages to invalidate, bumps the generation and sends a call IPI to every other core, then
+//! waits until every core's generation has caught up. Only one shootdown is in flight at a time.
+//!
+//! Shootdowns are used by the heap allocator while it holds its locks, so flushing a [`TlbShootdown`] never allocates memory. Since the
+//! core starting a shootdown waits with interrupts disabled, a core with interrupts disabled spinning on a lock that it holds would never
+//! handle the IPI. To avoid this, cores spinning on a lock [`poll`] for shootdowns while they wait.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::arch::page::{self, PAGE_SIZE};
+use crate::arch::{self, VirtAddr};
+use crate::sched::smp;
+use crate::sync::uninterruptible::InterruptDisabler;
+use crate::sync::UninterruptibleSpinlock;
+
+/// The maximum number of pages that a single shootdown invalidates individually. Shootdowns of more pages than this invalidate all
+/// translations instead.
+pub const MAX_SHOOTDOWN_PAGES: usize = 16;
+
+/// A value for [`NUM_PAGES`] indicating that all translations should be invalidated.
+const FLUSH_ALL: usize = usize::MAX;
+
+/// Set while a shootdown is in flight.
+static IN_FLIGHT: AtomicBool = AtomicBool::new(false);
+
+/// The generation of the most recently started shootdown.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The pages to be invalidated by the shootdown currently in flight. These are only written while no shootdown is in flight and are
+/// published to other cores by the release store to [`GENERATION`].
+static PAGES: [AtomicU64; MAX_SHOOTDOWN_PAGES] = [const { AtomicU64::new(0) }; MAX_SHOOTDOWN_PAGES];
+static NUM_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+crate::static_footprint!("mem", PAGES);
+
+/// The generation of the last shootdown processed by each online core, keyed by hardware ID.
+static CPU_GENERATIONS: UninterruptibleSpinlock<BTreeMap<u32, &'static AtomicU64>> = UninterruptibleSpinlock::new(BTreeMap::new());
+
+crate::cpu_local! {
+    static LOCAL_GENERATION: Cell<Option<&'static AtomicU64>> = Cell::new(None);
+}
+
+/// A batch of kernel pages whose translations need to be invalidated on all CPU cores.
+///
+/// Pages are invalidated on the current core as soon as they are added, since that's where they are most likely to be cached. Other cores
+/// only invalidate them once the batch is flushed, so page frames and virtual addresses that were mapped by pages in the batch must not be
+/// reused until then. A batch that is dropped without having been flushed is flushed automatically.
+#[derive(Debug)]
+pub struct TlbShootdown {
+    pages: [VirtAddr; MAX_SHOOTDOWN_PAGES],
+    num_pages: usize,
+}
+
+impl TlbShootdown {
+    /// Creates a new empty batch.
+    pub fn new() -> TlbShootdown {
+        TlbShootdown {
+            pages: [VirtAddr::zero(); MAX_SHOOTDOWN_PAGES],
+            num_pages: 0,
+        }
+    }
+
+    /// Checks whether any pages have been added to this batch since it was last flushed.
+    pub fn is_empty(&self) -> bool {
+        self.num_pages == 0
+    }
+
+    /// Adds the page containing the provided virtual address to this batch, invalidating its translation on the current CPU core. Huge
+    /// pages only need to be added once, using any address that they map.
+    ///
+    /// Once more than [`MAX_SHOOTDOWN_PAGES`] pages have been added, flushing this batch will invalidate all translations instead.
+    pub fn add(&mut self, addr: VirtAddr) {
+        page::flush_tlb_local(addr);
+
+        if self.num_pages < MAX_SHOOTDOWN_PAGES {
+            self.pages[self.num_pages] = VirtAddr::new(addr.as_u64() & !(PAGE_SIZE as u64 - 1));
+            self.num_pages += 1;
+        } else {
+            self.num_pages = FLUSH_ALL;
+        }
+    }
+
+    /// Invalidates the translations of all pages in this batch on every online CPU core, waiting until all of them have done so. The batch
+    /// is empty afterwards.
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+
+        let _interrupts_disabled = InterruptDisabler::new();
+
+        while IN_FLIGHT
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            poll();
+            core::hint::spin_loop();
+        }
+
+        if self.num_pages == FLUSH_ALL {
+            page::flush_tlb_all_local();
+        } else {
+            for (page, &addr) in PAGES.iter().zip(self.pages[..self.num_pages].iter()) {
+                page.store(addr.as_u64(), Ordering::Relaxed);
+            }
+        }
+
+        NUM_PAGES.store(self.num_pages, Ordering::Relaxed);
+        self.num_pages = 0;
+
+        let generation = GENERATION.fetch_add(1, Ordering::Release) + 1;
+        let current = smp::current_cpu();
+
+        if let Some(local) = LOCAL_GENERATION.get() {
+            local.store(generation, Ordering::Release);
+        }
+
+        {
+            let cpus = CPU_GENERATIONS.lock();
+
+            for &cpu in cpus.keys() {
+                // Only online cores are ever added here, and the pages can't be reused until every one of them has invalidated them, so
+                // there is no way to carry on safely if one of them can't be reached
+                if cpu != current && !arch::interrupt::send_call_ipi(cpu) {
+                    panic!("Failed to send TLB shootdown IPI to cpu {} (generation {})", cpu, generation);
+                }
+            }
+
+            while cpus
+                .values()
+                .any(|cpu_generation| cpu_generation.load(Ordering::Acquire) < generation)
+            {
+                core::hint::spin_loop();
+            }
+        }
+
+        IN_FLIGHT.store(false, Ordering::Release);
+    }
+}
+
+impl Default for TlbShootdown {
+    fn default() -> Self {
+        TlbShootdown::new()
+    }
+}
+
+impl Drop for TlbShootdown {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Processes the shootdown currently in flight if the current CPU core has not done so already. This is called by the handler for the
+/// call IPI and should also be called by any code that spins with interrupts disabled while waiting for another core.
+pub fn poll() {
+    let Some(local) = LOCAL_GENERATION.get() else {
+        return;
+    };
+    let generation = GENERATION.load(Ordering::Acquire);
+
+    if local.load(Ordering::Relaxed) >= generation {
+        return;
+    }
+
+    match NUM_PAGES.load(Ordering::Relaxed) {
+        FLUSH_ALL => page::flush_tlb_all_local(),
+        num_pages => {
+            for page in PAGES[..num_pages].iter() {
+                page::flush_tlb_local(VirtAddr::new(page.load(Ordering::Relaxed)));
+            }
+        },
+    }
+
+    local.store(generation, Ordering::Release);
+}
+
+/// Marks the CPU core that this is called on as taking part in TLB shootdowns.
+///
+/// # Safety
+///
+/// This must only be called once on each core after it is ready to handle call IPIs, with its TLB not holding any translations that are
+/// stale.
+pub(crate) unsafe fn init_cpu() {
+    let local = Box::leak(Box::new(AtomicU64::new(0)));
+    let mut cpus = CPU_GENERATIONS.lock();
+
+    // Holding the lock prevents any shootdown from waiting for this core before its generation is caught up
+    local.store(GENERATION.load(Ordering::Acquire), Ordering::Relaxed);
+    LOCAL_GENERATION.set(Some(local));
+
+    let prev = cpus.insert(smp::current_cpu(), local);
+
+    assert!(prev.is_none());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_shootdown_advances_generation() {
+        let Some(local) = LOCAL_GENERATION.get() else {
+            crate::test_util::skip("tlb shootdowns are not initialized on this cpu");
+            return;
+        };
+        let before = GENERATION.load(Ordering::Relaxed);
+        let mut shootdown = TlbShootdown::new();
+
+        shootdown.flush();
+        assert_eq!(before, GENERATION.load(Ordering::Relaxed));
+
+        shootdown.add(VirtAddr::new(0x1234_5000));
+        assert!(!shootdown.is_empty());
+
+        shootdown.flush();
+        assert!(shootdown.is_empty());
+        assert_eq!(before + 1, GENERATION.load(Ordering::Relaxed));
+        assert_eq!(before + 1, local.load(Ordering::Relaxed));
+        assert!(!IN_FLIGHT.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn test_shootdown_overflow_flushes_all() {
+        let mut shootdown = TlbShootdown::new();
+
+        for i in 0..=MAX_SHOOTDOWN_PAGES {
+            shootdown.add(VirtAddr::new((i * PAGE_SIZE) as u64));
+        }
+
+        assert_eq!(FLUSH_ALL, shootdown.num_pages);
+
+        shootdown.flush();
+        assert!(shootdown.is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+//! Management of the memory regions mapped into user address spaces.
+//!
+//! A [`UserMap`] owns the address space of a user process along with the bookkeeping needed to manage it: the [`RegionMap`] describing
+//! which regions have been mapped and the [`AnonymousPages`] that are eligible for being swapped out. Regions are allocated from the
+//! address space's [`VirtualAllocator`](super::virt::VirtualAllocator), so they can either be placed at a fixed address or wherever enough
+//! free virtual memory is available, and the virtual memory they used is returned to it once they are unmapped.
+
+use alloc::vec::Vec;
+
+use super::frame::{self, FrameAllocator};
+use super::region::{MapError, MemoryRegion, RegionBacking, RegionMap, RegionUsage};
+use super::swap::AnonymousPages;
+use super::virt::VirtualAllocRegion;
+use crate::arch::page::{get_phys_mem_ptr_slice, AddressSpace, PageFlags, PAGE_SIZE};
+use crate::arch::VirtAddr;
+
+/// The memory mapped into the address space of a user process.
+pub struct UserMap {
+    addr_space: AddressSpace,
+    anon_pages: AnonymousPages,
+    regions: RegionMap,
+}
+
+impl UserMap {
+    /// Creates a new map managing the provided user address space, which must not have any regions mapped yet.
+    pub fn new(addr_space: AddressSpace) -> UserMap {
+        UserMap {
+            addr_space,
+            anon_pages: AnonymousPages::new(),
+            regions: RegionMap::new(),
+        }
+    }
+
+    /// Gets the address space managed by this map.
+    pub fn addr_space(&mut self) -> &mut AddressSpace {
+        &mut self.addr_space
+    }
+
+    /// Gets the address space managed by this map along with the list of anonymous pages mapped into it that are eligible for swapping.
+    pub fn memory(&mut self) -> (&mut AddressSpace, &mut AnonymousPages) {
+        (&mut self.addr_space, &mut self.anon_pages)
+    }
+
+    /// Gets the map of regions that have been mapped into the address space.
+    pub fn regions(&self) -> &RegionMap {
+        &self.regions
+    }
+
+    /// Gets a description of each mapped region along with how many of its pages are currently resident or swapped out.
+    pub fn region_usage(&mut self) -> Vec<(MemoryRegion, RegionUsage)> {
+        let addr_space = &mut self.addr_space;
+
+        self.regions.iter().map(|r| (*r, r.usage(addr_space))).collect()
+    }
+
+    /// Reserves `num_pages` pages of virtual memory, either at the provided page-aligned address or wherever enough free virtual memory is
+    /// available, and records a region covering them.
+    fn reserve(
+        &mut self,
+        start: Option<VirtAddr>,
+        num_pages: usize,
+        flags: PageFlags,
+        backing: RegionBacking,
+        name: &'static str,
+    ) -> Result<MemoryRegion, MapError> {
+        let size = num_pages.checked_mul(PAGE_SIZE).ok_or(MapError::InvalidRange)?;
+        let range = match start {
+            Some(start) => {
+                let range = VirtualAllocRegion::new(start, start + size as u64);
+                let region = MemoryRegion::new(range, flags | PageFlags::USER, backing, name)?;
+
+                if !self.addr_space.virtual_alloc().reserve(range) {
+                    return Err(MapError::Overlap);
+                }
+
+                return self.insert_reserved(region);
+            },
+            None if size == 0 => {
+                return Err(MapError::InvalidRange);
+            },
+            None => self.addr_space.virtual_alloc().alloc(size).ok_or(MapError::OutOfVirtualMemory)?,
+        };
+
+        match MemoryRegion::new(range, flags | PageFlags::USER, backing, name) {
+            Ok(region) => self.insert_reserved(region),
+            Err(err) => {
+                // SAFETY: The range was just allocated and nothing has been mapped in it
+                unsafe {
+                    self.addr_space.virtual_alloc().free(range);
+                }
+
+                Err(err)
+            },
+        }
+    }
+
+    fn insert_reserved(&mut self, region: MemoryRegion) -> Result<MemoryRegion, MapError> {
+        if let Err(err) = self.regions.insert(region) {
+            // SAFETY: The range was reserved by the caller and nothing has been mapped in it
+            unsafe {
+                self.addr_space.virtual_alloc().free(region.range());
+            }
+
+            return Err(err);
+        }
+
+        Ok(region)
+    }
+
+    /// Backs the provided pages of a newly reserved region with zero-filled frames, unmapping the whole region if not enough memory is
+    /// available.
+    fn populate(&mut self, region: &MemoryRegion, pages: impl Iterator<Item = VirtAddr>) -> Result<(), MapError> {
+        for addr in pages {
+            let frame = match frame::get_allocator().alloc_one() {
+                Some(frame) => frame,
+                None => {
+                    self.unmap(region.range().start());
+                    return Err(MapError::OutOfMemory);
+                },
+            };
+
+            // SAFETY: The frame was just allocated, so nothing else can be using it, and the region was checked not to overlap any existing
+            //         mappings.
+            unsafe {
+                (*get_phys_mem_ptr_slice::<u8>(frame, PAGE_SIZE).ptr()).fill(0);
+                self.addr_space.set_page_user(addr, Some((frame, region.flags())));
+            }
+            self.anon_pages.track(addr, region.flags());
+        }
+
+        Ok(())
+    }
+
+    /// Maps a new region of `num_pages` pages of zero-filled anonymous memory, either starting at the provided page-aligned address or
+    /// wherever enough free virtual memory is available. The pages of the region are eligible for being swapped out. Returns the address
+    /// of the start of the region.
+    pub fn map_anonymous(
+        &mut self,
+        start: Option<VirtAddr>,
+        num_pages: usize,
+        flags: PageFlags,
+        name: &'static str,
+    ) -> Result<VirtAddr, MapError> {
+        let region = self.reserve(start, num_pages, flags, RegionBacking::Anonymous, name)?;
+
+        self.populate(&region, region.pages())?;
+        Ok(region.range().start())
+    }
+
+    /// Maps a new stack of at least `size` bytes wherever enough free virtual memory is available. The lowest page of the region is left
+    /// unmapped as a guard page, so that overflowing the stack results in a page fault. Returns the new region, whose end is the top of the
+    /// stack.
+    pub fn map_stack(&mut self, size: usize) -> Result<MemoryRegion, MapError> {
+        let num_pages = size.div_ceil(PAGE_SIZE).max(1);
+        let region = self.reserve(None, num_pages + 1, PageFlags::WRITEABLE, RegionBacking::Stack, "stack")?;
+
+        self.populate(&region, region.pages().skip(1))?;
+        Ok(region)
+    }
+
+    /// Unmaps the region starting at the provided address and returns its description. Frames backing anonymous regions are released, as
+    /// are any swap slots holding their swapped out pages, and the region's virtual memory becomes free to be used by other regions.
+    pub fn unmap(&mut self, start: VirtAddr) -> Option<MemoryRegion> {
+        let region = self.regions.remove(start)?;
+
+        for addr in region.pages() {
+            if let Some((frame, _)) = self.addr_space.get_page(addr) {
+                // SAFETY: Pages in a region are only ever mapped through the region, so nothing else refers to this mapping.
+                unsafe {
+                    self.addr_space.set_page_user(addr, None);
+
+                    if !matches!(region.backing(), RegionBacking::Physical(_)) {
+                        frame::release(frame);
+                    }
+                }
+            }
+
+            self.anon_pages.untrack(&mut self.addr_space, addr);
+        }
+
+        // SAFETY: All pages of the region were just unmapped
+        unsafe {
+            self.addr_space.virtual_alloc().free(region.range());
+        }
+
+        Some(region)
+    }
+
+    /// Changes the flags with which the pages of the region starting at the provided address are mapped.
+    ///
+    /// Pages whose frames are still shared with another address space are made copy-on-write rather than writeable, so that they are
+    /// copied before being written to.
+    pub fn protect(&mut self, start: VirtAddr, flags: PageFlags) -> Result<(), MapError> {
+        let flags = flags | PageFlags::USER;
+        let region = self.regions.get_mut(start).ok_or(MapError::NotMapped)?;
+
+        region.set_flags(flags);
+
+        for addr in region.pages() {
+            if let Some((frame, _)) = self.addr_space.get_page(addr) {
+                let page_flags = if flags.contains(PageFlags::WRITEABLE) && frame::ref_count(frame) > 1 {
+                    flags | PageFlags::COPY_ON_WRITE
+                } else {
+                    flags
+                };
+
+                // SAFETY: The page remains mapped to the same frame, only its protection changes.
+                unsafe {
+                    self.addr_space.set_page_user(addr, Some((frame, page_flags)));
+                }
+            }
+
+            self.anon_pages.set_flags(addr, flags);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_map_stack_and_protect() {
+        let mut map = UserMap::new(AddressSpace::new());
+        let region = map.map_stack(3 * PAGE_SIZE - 1).unwrap();
+        let (guard, top) = (region.range().start(), region.range().end());
+
+        assert_eq!(RegionBacking::Stack, region.backing());
+        assert_eq!(4, region.num_pages());
+        assert!(map.addr_space().get_page(guard).is_none());
+        assert_eq!(
+            Some(PageFlags::USER | PageFlags::WRITEABLE),
+            map.addr_space().get_page(top - PAGE_SIZE as u64).map(|(_, flags)| flags)
+        );
+        assert_eq!(3, map.region_usage()[0].1.resident_pages);
+
+        map.protect(guard, PageFlags::empty()).unwrap();
+        assert_eq!(
+            Some(PageFlags::USER),
+            map.addr_space().get_page(top - PAGE_SIZE as u64).map(|(_, flags)| flags)
+        );
+        assert_eq!(Err(MapError::NotMapped), map.protect(top, PageFlags::empty()));
+
+        // Once the stack is unmapped, its virtual memory can be reused
+        assert!(map.unmap(guard).is_some());
+        assert_eq!(Ok(guard), map.map_anonymous(Some(guard), 4, PageFlags::WRITEABLE, "heap"));
+        assert_eq!(
+            Err(MapError::Overlap),
+            map.map_anonymous(Some(top - PAGE_SIZE as u64), 1, PageFlags::empty(), "heap")
+        );
+    }
+}
@@ -6,8 +6,11 @@ use core::{mem, ptr};
 use static_assertions::const_assert;
 
 use super::frame::{self, FrameAllocator};
-use crate::arch::page::{get_phys_mem_ptr, PhysMemPtr, IS_PHYS_MEM_ALWAYS_MAPPED, PAGE_SIZE};
-use crate::arch::VirtAddr;
+use super::tlb::TlbShootdown;
+use crate::arch::page::{
+    get_phys_mem_ptr, AddressSpace, PageFlags, PhysMemPtr, HUGE_PAGE_SIZE, IS_PHYS_MEM_ALWAYS_MAPPED, KERNEL_VIRT_ALLOC_RANGE, PAGE_SIZE,
+};
+use crate::arch::{PhysAddr, VirtAddr};
 
 #[derive(Debug, Clone, Copy)]
 enum VirtualAllocSplitIndex {
@@ -448,6 +451,58 @@ impl VirtualAllocator {
         None
     }
 
+    /// Allocates a new region of virtual memory of `size` bytes in this address space whose starting address is a multiple of `align`
+    /// bytes, e.g. so that it can be mapped using huge pages. If no such region can be found, `None` is returned.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the requested size is not a multiple of the system's page size or if the requested alignment is not a
+    /// power of two.
+    pub fn alloc_aligned(&mut self, size: usize, align: usize) -> Option<VirtualAllocRegion> {
+        assert!(align.is_power_of_two());
+
+        if align <= PAGE_SIZE {
+            return self.alloc(size);
+        }
+
+        assert_eq!(0, size & (PAGE_SIZE - 1));
+
+        if size == 0 {
+            return Some(VirtualAllocRegion::empty());
+        }
+
+        let align = align as u64;
+        let region = self.free_regions().find_map(|region| {
+            let start = region.start().as_u64().checked_add(align - 1)? & !(align - 1);
+            let end = start.checked_add(size as u64)?;
+
+            if end <= region.end().as_u64() {
+                Some(VirtualAllocRegion::new(VirtAddr::new(start), VirtAddr::new(end)))
+            } else {
+                None
+            }
+        })?;
+
+        assert!(self.reserve(region));
+        Some(region)
+    }
+
+    /// Allocates the region of virtual memory of `size` bytes starting at the provided address in this address space. If any part of that
+    /// region has already been allocated, `None` is returned.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the provided address or size is not aligned to the system's page size.
+    pub fn alloc_at(&mut self, addr: VirtAddr, size: usize) -> Option<VirtualAllocRegion> {
+        let region = VirtualAllocRegion::new(addr, addr + size);
+
+        if self.reserve(region) {
+            Some(region)
+        } else {
+            None
+        }
+    }
+
     /// Removes the provided region of virtual memory from this virtual memory allocator if no part of it has already been allocated.
     /// Returns `true` on success. If one or more pages of the range passed in have already been allocated, then this function does not
     /// perform any modifications and returns `false`.
@@ -561,6 +616,59 @@ impl VirtualAllocator {
             0,
         )
     }
+
+    /// Gets statistics about the free regions in this allocator. Unlike collecting [`VirtualAllocator::free_regions`], this does not
+    /// allocate and is thus safe to call on the kernel's virtual allocator.
+    pub fn stats(&self) -> VirtualAllocStats {
+        let mut stats = VirtualAllocStats::default();
+
+        for region in self.free_regions() {
+            stats.free_regions += 1;
+            stats.free_bytes += region.size();
+            stats.largest_free_region = stats.largest_free_region.max(region.size());
+        }
+
+        stats
+    }
+}
+
+/// Statistics about the free regions of a [`VirtualAllocator`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtualAllocStats {
+    /// The number of disjoint free regions.
+    pub free_regions: usize,
+    /// The total size of all free regions in bytes.
+    pub free_bytes: u64,
+    /// The size of the largest free region in bytes, which bounds the largest allocation that can currently succeed.
+    pub largest_free_region: u64,
+}
+
+impl VirtualAllocStats {
+    /// Gets the percentage of free address space that lies outside of the largest free region. This is 0 when all free address space is
+    /// contiguous and approaches 100 as free address space gets split into many small regions.
+    pub fn fragmentation_percent(&self) -> u64 {
+        if self.free_bytes == 0 {
+            0
+        } else {
+            100 - (self.largest_free_region * 100 / self.free_bytes)
+        }
+    }
+}
+
+impl Drop for VirtualAllocator {
+    fn drop(&mut self) {
+        let mut page = self.head;
+
+        while !page.is_null() {
+            // SAFETY: The pages used to track free regions are owned exclusively by this allocator.
+            unsafe {
+                let next = (*page).header.next;
+
+                VirtualAllocPage::free(page);
+                page = next;
+            }
+        }
+    }
 }
 
 unsafe impl Send for VirtualAllocator {}
@@ -588,6 +696,244 @@ impl<'a> Iterator for VirtualAllocatorRegionIter<'a> {
     }
 }
 
+/// The way in which a range of kernel virtual address space is being used, as reported by [`kernel_virt_map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelVirtUsage {
+    /// The range is free in the kernel's virtual allocator.
+    Free,
+    /// The range is allocated and mapped to memory.
+    Mapped,
+    /// The range is allocated and mapped uncached, as is done for memory-mapped I/O.
+    Mmio,
+    /// The range is allocated but not currently mapped, e.g. because it is a guard page or lazily populated memory that has not been
+    /// touched yet.
+    Reserved,
+}
+
+/// A contiguous range of kernel virtual address space with uniform usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelVirtMapEntry {
+    pub region: VirtualAllocRegion,
+    pub usage: KernelVirtUsage,
+}
+
+/// The maximum number of pages whose mappings are looked up each time the kernel address space is locked while walking it, so that walking
+/// large mapped ranges doesn't keep the kernel address space locked for too long.
+const KERNEL_VIRT_MAP_PAGES_PER_LOCK: usize = 512;
+
+/// An iterator over the ranges of kernel virtual address space in ascending order, as returned by [`kernel_virt_map`].
+pub struct KernelVirtMap {
+    next_addr: u64,
+    pending: Option<KernelVirtMapEntry>,
+}
+
+impl KernelVirtMap {
+    fn next_chunk(&self) -> KernelVirtMapEntry {
+        let start = self.next_addr;
+        let mut addrspace = AddressSpace::kernel();
+        let next_free = addrspace.virtual_alloc().free_regions().find(|r| r.end().as_u64() > start);
+
+        let end = match next_free {
+            Some(region) if region.start().as_u64() <= start => {
+                return KernelVirtMapEntry {
+                    region: VirtualAllocRegion::new(VirtAddr::new(start), region.end()),
+                    usage: KernelVirtUsage::Free,
+                };
+            },
+            Some(region) => region.start().as_u64(),
+            None => KERNEL_VIRT_ALLOC_RANGE.end,
+        };
+
+        let classify = |addr: u64| {
+            let virt = VirtAddr::new(addr);
+            let usage = match addrspace.get_page(virt) {
+                Some((_, flags)) if flags.contains(PageFlags::UNCACHED) => KernelVirtUsage::Mmio,
+                Some(_) => KernelVirtUsage::Mapped,
+                None => KernelVirtUsage::Reserved,
+            };
+            let next = if addrspace.is_huge_page(virt) {
+                (addr & !(HUGE_PAGE_SIZE as u64 - 1)) + HUGE_PAGE_SIZE as u64
+            } else {
+                addr + PAGE_SIZE as u64
+            };
+
+            (usage, next.min(end))
+        };
+
+        let (usage, mut addr) = classify(start);
+
+        for _ in 1..KERNEL_VIRT_MAP_PAGES_PER_LOCK {
+            if addr == end {
+                break;
+            }
+
+            let (next_usage, next_addr) = classify(addr);
+
+            if next_usage != usage {
+                break;
+            }
+
+            addr = next_addr;
+        }
+
+        KernelVirtMapEntry {
+            region: VirtualAllocRegion::new(VirtAddr::new(start), VirtAddr::new(addr)),
+            usage,
+        }
+    }
+}
+
+impl Iterator for KernelVirtMap {
+    type Item = KernelVirtMapEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next_addr < KERNEL_VIRT_ALLOC_RANGE.end {
+            let chunk = self.next_chunk();
+
+            self.next_addr = chunk.region.end().as_u64();
+
+            match self.pending {
+                Some(ref mut pending) if pending.usage == chunk.usage => {
+                    pending.region = VirtualAllocRegion::new(pending.region.start(), chunk.region.end());
+                },
+                _ => {
+                    if let Some(pending) = self.pending.replace(chunk) {
+                        return Some(pending);
+                    }
+                },
+            }
+        }
+
+        self.pending.take()
+    }
+}
+
+/// Walks the kernel's virtual allocator and page tables to describe how each part of the kernel's virtual address space is being used.
+///
+/// The kernel address space is only locked while looking at small parts of it at a time, so the resulting map is not an atomic snapshot
+/// if the kernel address space is being modified concurrently. It is however safe to allocate memory while iterating over it.
+pub fn kernel_virt_map() -> KernelVirtMap {
+    KernelVirtMap {
+        next_addr: KERNEL_VIRT_ALLOC_RANGE.start,
+        pending: None,
+    }
+}
+
+/// Gets statistics about the free regions of the kernel's virtual allocator.
+pub fn kernel_virt_stats() -> VirtualAllocStats {
+    AddressSpace::kernel().virtual_alloc().stats()
+}
+
+/// A mapping of a range of physical memory-mapped I/O addresses (e.g. a PCI BAR) into kernel virtual address space. The range is mapped
+/// uncached so that all accesses go directly to the device, and is unmapped when the mapping is dropped.
+#[derive(Debug)]
+pub struct MmioMapping {
+    region: VirtualAllocRegion,
+    phys: PhysAddr,
+    offset: usize,
+    size: usize,
+}
+
+impl MmioMapping {
+    /// Gets the physical address at the start of the mapped range.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Gets a pointer to the start of the mapped range.
+    pub fn as_ptr(&self) -> *mut u8 {
+        (self.region.start() + self.offset).as_mut_ptr()
+    }
+
+    /// Gets the size of the mapped range in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Performs a volatile read of a value at the provided byte offset into the mapped range.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the value would not lie entirely within the mapped range or if the offset is not suitably aligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.check_access::<T>(offset);
+
+        // SAFETY: The access was just checked to be within the mapped range and aligned
+        unsafe { ptr::read_volatile(self.as_ptr().add(offset) as *const T) }
+    }
+
+    /// Performs a volatile write of a value at the provided byte offset into the mapped range.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the value would not lie entirely within the mapped range or if the offset is not suitably aligned.
+    pub fn write<T: Copy>(&self, offset: usize, val: T) {
+        self.check_access::<T>(offset);
+
+        // SAFETY: The access was just checked to be within the mapped range and aligned
+        unsafe { ptr::write_volatile(self.as_ptr().add(offset) as *mut T, val) }
+    }
+
+    fn check_access<T>(&self, offset: usize) {
+        assert!(offset.checked_add(mem::size_of::<T>()).map_or(false, |end| end <= self.size));
+        assert_eq!(0, (self.as_ptr() as usize + offset) % mem::align_of::<T>());
+    }
+}
+
+impl Drop for MmioMapping {
+    fn drop(&mut self) {
+        let mut addrspace = AddressSpace::kernel();
+        let mut shootdown = TlbShootdown::new();
+
+        // SAFETY: The region was mapped by map_mmio and nothing can access it through this mapping once it has been dropped
+        unsafe {
+            for i in 0..(self.region.size() as usize / PAGE_SIZE) {
+                addrspace.set_page_kernel(self.region.start() + i * PAGE_SIZE, None);
+                shootdown.add(self.region.start() + i * PAGE_SIZE);
+            }
+
+            shootdown.flush();
+            addrspace.virtual_alloc().free(self.region);
+        }
+    }
+}
+
+/// Maps `len` bytes of physical memory-mapped I/O space starting at `phys` into kernel virtual address space with caching disabled. The
+/// physical address does not need to be page-aligned. Returns [`None`] if `len` is zero or if no kernel virtual address space is available.
+///
+/// # Safety
+///
+/// The provided range should not contain general-purpose RAM, since it would then be mapped with conflicting caching modes.
+pub unsafe fn map_mmio(phys: PhysAddr, len: usize) -> Option<MmioMapping> {
+    if len == 0 {
+        return None;
+    }
+
+    let offset = phys.as_u64() as usize % PAGE_SIZE;
+    let phys_start = phys.as_u64() - offset as u64;
+    let num_pages = (offset + len).div_ceil(PAGE_SIZE);
+
+    let mut addrspace = AddressSpace::kernel();
+    let region = addrspace.virtual_alloc().alloc(num_pages * PAGE_SIZE)?;
+
+    for i in 0..num_pages {
+        addrspace.set_page_kernel(
+            region.start() + i * PAGE_SIZE,
+            Some((
+                PhysAddr::new(phys_start + (i * PAGE_SIZE) as u64),
+                PageFlags::WRITEABLE | PageFlags::UNCACHED | PageFlags::WRITE_THROUGH,
+            )),
+        );
+    }
+
+    Some(MmioMapping {
+        region,
+        phys,
+        offset,
+        size: len,
+    })
+}
+
 #[cfg(test)]
 mod test {
     use alloc::vec;
@@ -603,6 +949,62 @@ mod test {
         VirtualAllocRegion::new(start_addr, start_addr + (num_pages * PAGE_SIZE))
     }
 
+    #[test_case]
+    fn test_map_mmio() {
+        let phys = PhysAddr::new(0xfec0_0010);
+        let mapping = unsafe { map_mmio(phys, PAGE_SIZE) }.unwrap();
+        let start = VirtAddr::from_ptr(mapping.as_ptr());
+
+        assert_eq!(PAGE_SIZE, mapping.size());
+        assert_eq!(0x10, start.as_u64() as usize % PAGE_SIZE);
+
+        let (first_frame, flags) = AddressSpace::kernel().get_page(start).unwrap();
+        let (second_frame, _) = AddressSpace::kernel().get_page(start + PAGE_SIZE).unwrap();
+
+        assert_eq!(PhysAddr::new(0xfec0_0000), first_frame);
+        assert_eq!(PhysAddr::new(0xfec0_1000), second_frame);
+        assert!(flags.contains(PageFlags::UNCACHED | PageFlags::WRITEABLE));
+
+        drop(mapping);
+        assert_eq!(None, AddressSpace::kernel().get_page(start));
+    }
+
+    #[test_case]
+    fn test_kernel_virt_map_mmio() {
+        let mapping = unsafe { map_mmio(PhysAddr::new(0xfec0_0000), PAGE_SIZE) }.unwrap();
+        let start = VirtAddr::from_ptr(mapping.as_ptr());
+
+        let entry = kernel_virt_map()
+            .find(|entry| entry.region.start() <= start && start < entry.region.end())
+            .unwrap();
+        assert_eq!(KernelVirtUsage::Mmio, entry.usage);
+
+        drop(mapping);
+
+        let entry = kernel_virt_map()
+            .find(|entry| entry.region.start() <= start && start < entry.region.end())
+            .unwrap();
+        assert_eq!(KernelVirtUsage::Free, entry.usage);
+    }
+
+    #[test_case]
+    fn test_stats() {
+        unsafe {
+            let mut allocator = VirtualAllocator::new();
+
+            assert_eq!(0, allocator.stats().fragmentation_percent());
+
+            allocator.free(fake_region(0, 1));
+            allocator.free(fake_region(2, 3));
+
+            let stats = allocator.stats();
+            assert_eq!(2, stats.free_regions);
+            assert_eq!((PAGE_SIZE * 4) as u64, stats.free_bytes);
+            assert_eq!((PAGE_SIZE * 3) as u64, stats.largest_free_region);
+            assert_eq!(25, stats.fragmentation_percent());
+        }
+    }
+
     #[test_case]
     fn test_basics() {
         unsafe {
@@ -936,4 +1338,40 @@ mod test {
             );
         }
     }
+
+    #[test_case]
+    fn test_alloc_aligned() {
+        unsafe {
+            let mut allocator = VirtualAllocator::new();
+
+            allocator.free(fake_region(0, 2));
+            allocator.free(fake_region(4, 6));
+
+            // Neither free region starts at a suitably aligned address, so allocations must be carved out of the middle or end of them
+            assert_eq!(Some(fake_region(7, 2)), allocator.alloc_aligned(PAGE_SIZE * 2, PAGE_SIZE * 4));
+            assert_eq!(Some(fake_region(1, 1)), allocator.alloc_aligned(PAGE_SIZE, PAGE_SIZE * 2));
+            assert_eq!(None, allocator.alloc_aligned(PAGE_SIZE * 2, PAGE_SIZE * 8));
+            assert_eq!(
+                vec![fake_region(0, 1), fake_region(4, 3), fake_region(9, 1)],
+                allocator.free_regions().collect_vec()
+            );
+        }
+    }
+
+    #[test_case]
+    fn test_alloc_at() {
+        unsafe {
+            let mut allocator = VirtualAllocator::new();
+
+            allocator.free(fake_region(0, 4));
+
+            assert_eq!(
+                Some(fake_region(1, 2)),
+                allocator.alloc_at(fake_region(1, 2).start(), PAGE_SIZE * 2)
+            );
+            assert_eq!(None, allocator.alloc_at(fake_region(2, 1).start(), PAGE_SIZE));
+            assert_eq!(None, allocator.alloc_at(fake_region(3, 2).start(), PAGE_SIZE * 2));
+            assert_eq!(vec![fake_region(0, 1), fake_region(3, 1)], allocator.free_regions().collect_vec());
+        }
+    }
 }
@@ -0,0 +1,260 @@
+//! A compressed in-memory swap backend.
+//!
+//! Rather than writing swapped out pages to a disk, a zram device compresses them and keeps the compressed data in kernel heap memory. Since
+//! anonymous pages are often highly compressible, this allows more data to be kept in memory than would otherwise fit, without requiring any
+//! block devices to be present. Pages consisting entirely of zeroes are recognized and stored without allocating any memory at all.
+//!
+//! A zram device is set up as the swap space during boot if the `zram_size_mib` kernel option is set to a non-zero value, which gives the
+//! maximum amount of uncompressed data that can be stored in it.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::swap::{self, SwapBackend, SwapError};
+use crate::arch::page::PAGE_SIZE;
+use crate::compress::lz4;
+use crate::util::{self, OneShotManualInit};
+use crate::{log, options};
+
+enum ZramSlot {
+    Empty,
+    Zero,
+    Uncompressed(Box<[u8]>),
+    Compressed(Box<[u8]>),
+}
+
+/// Statistics about the data stored in a zram device.
+#[derive(Debug, Default)]
+pub struct ZramStats {
+    pages_stored: AtomicU64,
+    zero_pages: AtomicU64,
+    incompressible_pages: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl ZramStats {
+    /// Gets the number of pages currently stored in the device, including zero pages.
+    pub fn pages_stored(&self) -> u64 {
+        self.pages_stored.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of stored pages that consist entirely of zeroes and take up no memory.
+    pub fn zero_pages(&self) -> u64 {
+        self.zero_pages.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of stored pages that could not be compressed and are stored as-is.
+    pub fn incompressible_pages(&self) -> u64 {
+        self.incompressible_pages.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of bytes of uncompressed data stored in the device.
+    pub fn original_bytes(&self) -> u64 {
+        self.pages_stored() * PAGE_SIZE as u64
+    }
+
+    /// Gets the number of bytes of memory used to hold the stored data after compression.
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Gets the ratio between the size of the stored data before and after compression, or `None` if nothing has been stored yet.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match (self.original_bytes(), self.stored_bytes()) {
+            (0, _) => None,
+            (_, 0) => Some(f64::INFINITY),
+            (original, stored) => Some(original as f64 / stored as f64),
+        }
+    }
+}
+
+/// A swap backend that stores pages compressed in memory.
+pub struct ZramDevice {
+    slots: Vec<ZramSlot>,
+    scratch: Box<[u8]>,
+    stats: Arc<ZramStats>,
+}
+
+impl ZramDevice {
+    /// Creates a new zram device that can hold up to `num_pages` pages of uncompressed data.
+    pub fn new(num_pages: usize) -> ZramDevice {
+        let mut slots = Vec::with_capacity(num_pages);
+
+        slots.resize_with(num_pages, || ZramSlot::Empty);
+
+        ZramDevice {
+            slots,
+            scratch: vec![0; lz4::compress_bound(PAGE_SIZE)].into_boxed_slice(),
+            stats: Arc::new(ZramStats::default()),
+        }
+    }
+
+    /// Gets a handle to the statistics of this device that remains valid after it has been handed off to the swap subsystem.
+    pub fn stats(&self) -> Arc<ZramStats> {
+        self.stats.clone()
+    }
+
+    fn slot_mut(&mut self, slot: u64) -> Result<&mut ZramSlot, SwapError> {
+        usize::try_from(slot)
+            .ok()
+            .and_then(|slot| self.slots.get_mut(slot))
+            .ok_or(SwapError::IoError)
+    }
+
+    fn clear_slot(&mut self, slot: u64) -> Result<(), SwapError> {
+        let stats = self.stats.clone();
+        let old = core::mem::replace(self.slot_mut(slot)?, ZramSlot::Empty);
+
+        match old {
+            ZramSlot::Empty => {
+                return Ok(());
+            },
+            ZramSlot::Zero => {
+                stats.zero_pages.fetch_sub(1, Ordering::Relaxed);
+            },
+            ZramSlot::Uncompressed(ref data) => {
+                stats.incompressible_pages.fetch_sub(1, Ordering::Relaxed);
+                stats.stored_bytes.fetch_sub(data.len() as u64, Ordering::Relaxed);
+            },
+            ZramSlot::Compressed(ref data) => {
+                stats.stored_bytes.fetch_sub(data.len() as u64, Ordering::Relaxed);
+            },
+        }
+
+        stats.pages_stored.fetch_sub(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl SwapBackend for ZramDevice {
+    fn num_slots(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    fn read_slot(&mut self, slot: u64, buf: &mut [u8]) -> Result<(), SwapError> {
+        assert_eq!(PAGE_SIZE, buf.len());
+
+        match *self.slot_mut(slot)? {
+            ZramSlot::Empty => Err(SwapError::IoError),
+            ZramSlot::Zero => {
+                buf.fill(0);
+                Ok(())
+            },
+            ZramSlot::Uncompressed(ref data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            },
+            ZramSlot::Compressed(ref data) => match lz4::decompress(data, buf) {
+                Ok(len) if len == PAGE_SIZE => Ok(()),
+                _ => Err(SwapError::IoError),
+            },
+        }
+    }
+
+    fn write_slot(&mut self, slot: u64, buf: &[u8]) -> Result<(), SwapError> {
+        assert_eq!(PAGE_SIZE, buf.len());
+
+        self.clear_slot(slot)?;
+
+        let new = if buf.iter().all(|&b| b == 0) {
+            self.stats.zero_pages.fetch_add(1, Ordering::Relaxed);
+            ZramSlot::Zero
+        } else {
+            // Pages are usually swapped out because memory is running low, so running out of memory here must not bring down the kernel
+            match lz4::compress(buf, &mut self.scratch) {
+                Some(len) if len < PAGE_SIZE => {
+                    let data = util::try_boxed_slice(&self.scratch[..len]).map_err(|_| SwapError::IoError)?;
+
+                    self.stats.stored_bytes.fetch_add(len as u64, Ordering::Relaxed);
+                    ZramSlot::Compressed(data)
+                },
+                _ => {
+                    let data = util::try_boxed_slice(buf).map_err(|_| SwapError::IoError)?;
+
+                    self.stats.incompressible_pages.fetch_add(1, Ordering::Relaxed);
+                    self.stats.stored_bytes.fetch_add(PAGE_SIZE as u64, Ordering::Relaxed);
+                    ZramSlot::Uncompressed(data)
+                },
+            }
+        };
+
+        self.stats.pages_stored.fetch_add(1, Ordering::Relaxed);
+        *self.slot_mut(slot)? = new;
+
+        Ok(())
+    }
+
+    fn discard_slot(&mut self, slot: u64) {
+        let _ = self.clear_slot(slot);
+    }
+}
+
+static ZRAM_STATS: OneShotManualInit<Arc<ZramStats>> = OneShotManualInit::uninit();
+
+/// Gets the statistics of the zram device being used as swap space, or `None` if no zram device was set up.
+pub fn stats() -> Option<&'static ZramStats> {
+    ZRAM_STATS.try_get().map(|stats| &**stats)
+}
+
+/// Sets up a zram device as the swap space if the `zram_size_mib` kernel option is set.
+fn init() {
+    let size_mib = options::get().get::<usize>("zram_size_mib").unwrap_or(0);
+
+    if size_mib == 0 {
+        return;
+    }
+
+    let device = ZramDevice::new(size_mib * 1024 * 1024 / PAGE_SIZE);
+    let stats = device.stats();
+
+    match swap::enable(Box::new(device)) {
+        Ok(()) => {
+            ZRAM_STATS.set(stats);
+            log!(Info, "zram", "Using {} MiB zram device as swap space", size_mib);
+        },
+        Err(err) => {
+            log!(Warning, "zram", "Failed to enable zram swap: {}", err);
+        },
+    }
+}
+
+crate::initcall!(device, "zram", init);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_zram_read_write() {
+        let mut device = ZramDevice::new(4);
+        let stats = device.stats();
+        let compressible: Vec<u8> = (0..PAGE_SIZE).map(|i| (i % 16) as u8).collect();
+        let mut buf = vec![0xff; PAGE_SIZE];
+
+        assert_eq!(Err(SwapError::IoError), device.read_slot(0, &mut buf));
+        assert_eq!(Err(SwapError::IoError), device.write_slot(4, &compressible));
+
+        device.write_slot(0, &compressible).unwrap();
+        device.write_slot(1, &vec![0; PAGE_SIZE]).unwrap();
+
+        assert_eq!(2, stats.pages_stored());
+        assert_eq!(1, stats.zero_pages());
+        assert!(stats.stored_bytes() < PAGE_SIZE as u64 / 4);
+        assert!(stats.compression_ratio().unwrap() > 4.0);
+
+        device.read_slot(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        device.read_slot(0, &mut buf).unwrap();
+        assert_eq!(compressible, buf);
+
+        device.discard_slot(0);
+        device.discard_slot(1);
+
+        assert_eq!(0, stats.pages_stored());
+        assert_eq!(0, stats.stored_bytes());
+        assert_eq!(Err(SwapError::IoError), device.read_slot(0, &mut buf));
+    }
+}
@@ -4,8 +4,17 @@ use core::panic::PanicInfo;
 pub fn show_panic_crash_screen(info: &PanicInfo) -> ! {
     use core::fmt::Write;
 
+    use crate::arch::interrupt;
+    use crate::arch::regs::GeneralRegister;
     use crate::arch::x86_64::dev::vgabuf::{Color, VgaTextBuffer, Writer};
 
+    // Make sure nothing else can run and modify shared state while the crash screen is being rendered
+    interrupt::disable();
+    let num_stopped = interrupt::stop_other_cpus();
+
+    // Anything still waiting to be sent by the serial port's IRQ would otherwise never make it out
+    crate::arch::x86_64::dev::serial::flush_polled();
+
     crate::mem::set_use_early_alloc(true);
 
     let mut vga_buf = unsafe { VgaTextBuffer::for_primary_display() };
@@ -14,13 +23,106 @@ pub fn show_panic_crash_screen(info: &PanicInfo) -> ! {
     w.set_color(Color::White, Color::Red);
     w.clear();
 
-    let _ = write!(w, "{}", info);
+    let _ = write!(w, "{}\n\n{}", crate::build_info::summary(), info);
+
+    if num_stopped != 0 {
+        let _ = write!(w, "\n\n{} other CPU cores were stopped", num_stopped);
+
+        for cpu in interrupt::stopped_cpus() {
+            let _ = write!(
+                w,
+                "\n  cpu {}: rip={:#018x} rsp={:#018x} rbp={:#018x}",
+                cpu.hw_id,
+                cpu.regs.rip,
+                cpu.regs.gpr(GeneralRegister::Rsp),
+                cpu.regs.gpr(GeneralRegister::Rbp)
+            );
+        }
+    }
+
+    #[cfg(any(feature = "unwind", feature = "replay_log"))]
+    {
+        // SAFETY: The serial port was already initialized during boot, and all other cores have been stopped, so nothing else can be
+        //         using it concurrently
+        let mut serial = unsafe { uart_16550::SerialPort::new(0x3f8) };
+
+        #[cfg(feature = "unwind")]
+        {
+            write_backtraces(&mut serial);
+            let _ = write!(w, "\n\nBacktraces of all threads were written to the serial port");
+        }
+
+        #[cfg(feature = "replay_log")]
+        {
+            crate::sched::replay::dump(&mut serial);
+            let _ = write!(w, "\n\nThe replay log was written to the serial port");
+        }
+    }
+
+    crate::shutdown::run_panic_hooks();
 
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+/// Writes a backtrace of the panicking context, followed by backtraces of every thread that isn't currently running, so that a panic
+/// caused by a deadlock or hang shows what every part of the system was waiting on. Only raw addresses are written, since the kernel does
+/// not have a symbol table.
+#[cfg(all(feature = "unwind", not(feature = "check_arch_api")))]
+fn write_backtraces(w: &mut impl core::fmt::Write) {
+    use crate::arch::unwind::{Backtrace, UnwindRegs};
+    use crate::sched::task::{self, ThreadState};
+
+    fn read_word(addr: u64) -> Option<u64> {
+        let mut buf = [0; 8];
+
+        (crate::mem::try_read_kernel_mem(addr, &mut buf) == 8).then(|| u64::from_le_bytes(buf))
+    }
+
+    fn write_frames(w: &mut impl core::fmt::Write, regs: UnwindRegs) {
+        for pc in Backtrace::new(regs, read_word) {
+            let _ = writeln!(w, "  {:#018x}", pc);
+        }
+    }
+
+    let _ = writeln!(w, "\nBacktrace of panicking context:");
+    write_frames(w, UnwindRegs::current());
+
+    for cpu in crate::arch::interrupt::stopped_cpus() {
+        let _ = writeln!(w, "\nBacktrace of stopped cpu {}:", cpu.hw_id);
+        write_frames(w, UnwindRegs::from_saved(&cpu.regs));
+    }
+
+    let Some(processes) = task::try_all_processes() else {
+        let _ = writeln!(w, "\nCannot show other threads, since the process list is locked");
+        return;
+    };
+
+    for process in processes {
+        let Some(process_lock) = process.try_lock() else {
+            let _ = writeln!(w, "\nCannot show threads of pid {}, since it is locked", process.pid());
+            continue;
+        };
+
+        for thread in process_lock.threads() {
+            let Some(thread_lock) = thread.try_lock() else {
+                let _ = writeln!(w, "\nCannot show thread {}, since it is locked", thread.debug_name());
+                continue;
+            };
+
+            match thread_lock.state() {
+                // Running threads' saved registers are stale, and they were already shown above as the panicking context or a stopped cpu
+                ThreadState::Running | ThreadState::Dead => {},
+                state => {
+                    let _ = writeln!(w, "\nBacktrace of thread {} ({:?}):", thread.debug_name(), state);
+                    write_frames(w, UnwindRegs::from_saved(&thread_lock.regs().basic));
+                },
+            }
+        }
+    }
+}
+
 #[cfg(feature = "check_arch_api")]
 pub fn show_panic_crash_screen(_info: &PanicInfo) -> ! {
     crate::arch::halt()
@@ -0,0 +1,252 @@
+//! Abstraction over the hardware timers used to generate scheduler ticks.
+//!
+//! A clock event device is a piece of hardware that can raise an interrupt after a programmed amount of time, either periodically or once
+//! (one-shot). Architecture code registers every such device it finds (e.g. the PIT, local APIC timer, or HPET) using [`register`], and the
+//! device with the highest rating that supports periodic mode is then chosen to generate the system tick. The tick frequency is set by the
+//! `tick_hz` boot option.
+//!
+//! When a clock event device raises an interrupt, its driver calls [`handle_event`], which advances the system clock by the amount of time
+//! that the device was programmed for. Code that wants to stop the periodic tick (e.g. while idle) can instead program the active device
+//! in one-shot mode using [`set_oneshot`] if it supports it, and later return to periodic mode using [`set_periodic`].
+
+use alloc::vec::Vec;
+use core::time::Duration;
+use core::{fmt, ptr};
+
+use bitflags::bitflags;
+
+use crate::sync::UninterruptibleSpinlock;
+use crate::{log, options, sched};
+
+/// The default frequency of the system tick, in Hz.
+pub const DEFAULT_TICK_FREQUENCY: u32 = 1000;
+
+const MIN_TICK_FREQUENCY: u32 = 10;
+const MAX_TICK_FREQUENCY: u32 = 10_000;
+
+bitflags! {
+    /// The modes of operation supported by a clock event device.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ClockEventFeatures: u32 {
+        /// The device can raise interrupts periodically.
+        const PERIODIC = 0x1;
+        /// The device can raise a single interrupt after a programmed delay.
+        const ONESHOT = 0x2;
+    }
+}
+
+/// An error that can occur when programming a clock event device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockEventError {
+    /// No clock event device has been selected to generate the system tick.
+    NoDevice,
+    /// The device does not support the requested mode.
+    Unsupported,
+}
+
+impl fmt::Display for ClockEventError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClockEventError::NoDevice => write!(f, "no clock event device"),
+            ClockEventError::Unsupported => write!(f, "mode not supported by clock event device"),
+        }
+    }
+}
+
+/// A hardware timer that can raise interrupts after a programmed amount of time.
+pub trait ClockEventDevice: Send + Sync {
+    /// Gets a short name identifying this device.
+    fn name(&self) -> &'static str;
+
+    /// Gets how desirable this device is as the source of the system tick. When multiple devices are registered, the one with the highest
+    /// rating is used.
+    fn rating(&self) -> u32;
+
+    /// Gets the modes of operation that this device supports.
+    fn features(&self) -> ClockEventFeatures;
+
+    /// Gets the longest delay or period that this device can be programmed with.
+    fn max_delta(&self) -> Duration;
+
+    /// Programs this device to raise an interrupt periodically with approximately the provided period, which is clamped to the range the
+    /// device supports. Returns the actual period that the device was programmed with.
+    ///
+    /// # Safety
+    ///
+    /// This must only be called by this module while this device is selected as the system tick source.
+    unsafe fn set_periodic(&self, period: Duration) -> Duration;
+
+    /// Gets the amount of time that has elapsed since the previous interrupt while this device is in periodic mode with the provided
+    /// period, as returned by [`ClockEventDevice::set_periodic`]. This is called once per periodic interrupt.
+    ///
+    /// Devices whose real period is not a whole number of nanoseconds should override this to carry the rounding error over from one tick
+    /// to the next, since the system clock would otherwise slowly drift.
+    fn periodic_elapsed(&self, period: Duration) -> Duration {
+        period
+    }
+
+    /// Programs this device to raise a single interrupt after approximately the provided delay, which is clamped to the range the device
+    /// supports. Returns the actual delay that the device was programmed with.
+    ///
+    /// # Safety
+    ///
+    /// This must only be called by this module while this device is selected as the system tick source.
+    unsafe fn set_oneshot(&self, delay: Duration) -> Duration;
+
+    /// Stops this device from raising any further interrupts.
+    ///
+    /// # Safety
+    ///
+    /// This must only be called by this module.
+    unsafe fn shutdown(&self);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClockEventMode {
+    Periodic(Duration),
+    OneShot(Duration),
+    Stopped,
+}
+
+struct ActiveDevice {
+    dev: &'static dyn ClockEventDevice,
+    mode: ClockEventMode,
+}
+
+static DEVICES: UninterruptibleSpinlock<Vec<&'static dyn ClockEventDevice>> = UninterruptibleSpinlock::new(Vec::new());
+static ACTIVE: UninterruptibleSpinlock<Option<ActiveDevice>> = UninterruptibleSpinlock::new(None);
+
+/// Gets the frequency of the system tick in Hz, as set by the `tick_hz` boot option.
+pub fn tick_frequency() -> u32 {
+    options::get()
+        .get::<u32>("tick_hz")
+        .unwrap_or(DEFAULT_TICK_FREQUENCY)
+        .clamp(MIN_TICK_FREQUENCY, MAX_TICK_FREQUENCY)
+}
+
+/// Gets the nominal period of the system tick.
+pub fn tick_period() -> Duration {
+    Duration::from_secs(1) / tick_frequency()
+}
+
+/// Registers a clock event device that can be used to generate the system tick.
+pub fn register(dev: &'static dyn ClockEventDevice) {
+    log!(
+        Info,
+        "clockevent",
+        "Registered clock event device {} (rating {}, features {:?})",
+        dev.name(),
+        dev.rating(),
+        dev.features()
+    );
+    DEVICES.lock().push(dev);
+}
+
+/// Selects the best registered clock event device that supports periodic mode and starts the system tick on it.
+///
+/// # Safety
+///
+/// This should only be called once during the boot process, after the architecture has registered its clock event devices.
+pub unsafe fn start() {
+    let dev = DEVICES
+        .lock()
+        .iter()
+        .copied()
+        .filter(|dev| dev.features().contains(ClockEventFeatures::PERIODIC))
+        .max_by_key(|dev| dev.rating())
+        .expect("no clock event device supports periodic mode");
+
+    let period = dev.set_periodic(tick_period());
+
+    log!(
+        Info,
+        "clockevent",
+        "Using {} for the system tick with a period of {:?}",
+        dev.name(),
+        period
+    );
+    *ACTIVE.lock() = Some(ActiveDevice {
+        dev,
+        mode: ClockEventMode::Periodic(period),
+    });
+}
+
+/// Gets the name of the clock event device currently generating the system tick, if any.
+pub fn active_device_name() -> Option<&'static str> {
+    ACTIVE.lock().as_ref().map(|active| active.dev.name())
+}
+
+/// Notifies the scheduler that the provided clock event device has raised an interrupt. This should be called by the device's interrupt
+/// handler. Interrupts from devices other than the one currently generating the system tick are ignored.
+pub fn handle_event(dev: &dyn ClockEventDevice) {
+    let mut guard = ACTIVE.lock();
+    let Some(active) = guard.as_mut().filter(|active| ptr::addr_eq(active.dev, dev)) else {
+        return;
+    };
+
+    let elapsed = match active.mode {
+        ClockEventMode::Periodic(period) => active.dev.periodic_elapsed(period),
+        ClockEventMode::OneShot(delay) => {
+            active.mode = ClockEventMode::Stopped;
+            delay
+        },
+        ClockEventMode::Stopped => return,
+    };
+
+    drop(guard);
+    sched::timer_tick(elapsed);
+}
+
+/// Stops the periodic system tick and instead programs the active clock event device to raise a single interrupt after approximately the
+/// provided delay, returning the actual delay. The system clock only advances by the programmed delay once the interrupt arrives.
+///
+/// # Safety
+///
+/// Interrupts must be disabled on the current core while calling this, and [`set_periodic`] must be called after the interrupt has arrived
+/// to resume the periodic tick.
+pub unsafe fn set_oneshot(delay: Duration) -> Result<Duration, ClockEventError> {
+    let mut active = ACTIVE.lock();
+    let active = active.as_mut().ok_or(ClockEventError::NoDevice)?;
+
+    if !active.dev.features().contains(ClockEventFeatures::ONESHOT) {
+        return Err(ClockEventError::Unsupported);
+    }
+
+    let delay = active.dev.set_oneshot(delay.min(active.dev.max_delta()));
+
+    active.mode = ClockEventMode::OneShot(delay);
+    Ok(delay)
+}
+
+/// Resumes the periodic system tick on the active clock event device after it was programmed using [`set_oneshot`].
+///
+/// # Safety
+///
+/// Interrupts must be disabled on the current core while calling this.
+pub unsafe fn set_periodic() -> Result<Duration, ClockEventError> {
+    let mut active = ACTIVE.lock();
+    let active = active.as_mut().ok_or(ClockEventError::NoDevice)?;
+
+    if let ClockEventMode::OneShot(_) = active.mode {
+        active.dev.shutdown();
+    }
+
+    let period = active.dev.set_periodic(tick_period());
+
+    active.mode = ClockEventMode::Periodic(period);
+    Ok(period)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_tick_frequency_in_range() {
+        let freq = tick_frequency();
+
+        assert!((MIN_TICK_FREQUENCY..=MAX_TICK_FREQUENCY).contains(&freq));
+        assert!(!tick_period().is_zero());
+        assert!(active_device_name().is_some());
+    }
+}
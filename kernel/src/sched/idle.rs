@@ -0,0 +1,56 @@
+//! The per-CPU idle thread.
+//!
+//! When no other thread is ready to run, the scheduler switches to an idle thread that waits for interrupts while using as little power as
+//! the processor allows. The idle thread is never placed on a ready queue, since the scheduler switches to it directly and away from it as
+//! soon as any other thread becomes ready. Since it is a real thread, the time spent idle is accounted for separately from the time spent
+//! running other threads and can be seen in the same way as the CPU usage of any other thread.
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::pin::Pin;
+use core::ptr;
+
+use super::task::{Process, Thread, ThreadPriority, ThreadState};
+use crate::arch;
+
+const IDLE_STACK_SIZE: usize = 4 * 4096;
+
+crate::cpu_local! {
+    static IDLE_THREAD: UnsafeCell<Option<Pin<Arc<Thread>>>> = UnsafeCell::new(None);
+}
+
+fn run_idle() -> ! {
+    loop {
+        arch::idle_wait();
+    }
+}
+
+/// Gets the idle thread for the current CPU core, or [`None`] if it has not been created yet.
+pub(super) fn get() -> Option<&'static Pin<Arc<Thread>>> {
+    // SAFETY: IDLE_THREAD is CPU-local and is only modified once while the idle thread is being created
+    unsafe { (*IDLE_THREAD.get()).as_ref() }
+}
+
+/// Checks whether the provided thread is the idle thread for the current CPU core.
+pub(super) fn is_idle_thread(thread: &Thread) -> bool {
+    get().map_or(false, |idle| ptr::eq(&**idle, thread))
+}
+
+/// Creates the idle thread for the current CPU core. Until this is called, the scheduler will fall back to waiting for interrupts outside
+/// the context of any thread when there is nothing else to run.
+///
+/// # Safety
+///
+/// This function should only be called once on each CPU core.
+pub(super) unsafe fn init() {
+    let idle = Process::kernel()
+        .lock()
+        .create_kernel_thread("idle", || run_idle(), IDLE_STACK_SIZE);
+    let mut idle_lock = idle.lock();
+
+    idle_lock.set_priority(ThreadPriority::Background);
+    *idle_lock.state_mut() = ThreadState::Ready;
+    drop(idle_lock);
+
+    *IDLE_THREAD.get() = Some(idle);
+}
@@ -5,13 +5,24 @@
 
 use alloc::boxed::Box;
 use alloc::collections::vec_deque::VecDeque;
-use core::cell::UnsafeCell;
+use core::cell::{Cell, UnsafeCell};
+use core::time::Duration;
 
 use self::task::{Process, Thread};
 use crate::arch::interrupt::{self, InterruptFrame};
+use crate::options;
+use crate::sync::level::{self, InterruptLevel};
 use crate::sync::uninterruptible::InterruptDisabler;
+use crate::util::OneShotManualInit;
 
+pub mod clockevent;
+mod idle;
+mod reaper;
+pub mod replay;
+pub mod smp;
 pub mod task;
+pub mod timer;
+pub mod topology;
 pub mod wait;
 
 /// Initializes the scheduler data structures.
@@ -20,14 +31,25 @@ pub mod wait;
 ///
 /// This function should only be called once from the bootstrap process early during the boot process.
 pub unsafe fn init() {
+    TIMESLICE.set(Duration::from_millis(
+        options::get().get::<u64>("timeslice_ms").unwrap_or(DEFAULT_TIMESLICE_MS),
+    ));
     task::Process::init_kernel_process();
+    idle::init();
+    reaper::init();
 }
 
-#[thread_local]
-static IN_INTERRUPT: UnsafeCell<bool> = UnsafeCell::new(false);
+const DEFAULT_TIMESLICE_MS: u64 = 10;
 
-#[thread_local]
-static SOFT_INTERRUPTS: UnsafeCell<VecDeque<Box<dyn FnOnce()>>> = UnsafeCell::new(VecDeque::new());
+static TIMESLICE: OneShotManualInit<Duration> = OneShotManualInit::uninit();
+
+crate::cpu_local! {
+    static TIMESLICE_REMAINING: Cell<Duration> = Cell::new(Duration::ZERO);
+    static TIMESLICE_EXPIRED: Cell<bool> = Cell::new(false);
+    static IN_INTERRUPT: UnsafeCell<bool> = UnsafeCell::new(false);
+    static YIELD_TARGET: Cell<Option<core::pin::Pin<alloc::sync::Arc<Thread>>>> = Cell::new(None);
+    static SOFT_INTERRUPTS: UnsafeCell<VecDeque<Box<dyn FnOnce()>>> = UnsafeCell::new(VecDeque::new());
+}
 
 /// Notifies the scheduler that an asynchronous hardware interrupt handler has begun.
 ///
@@ -54,13 +76,72 @@ pub(crate) unsafe fn end_interrupt(interrupt_frame: &mut InterruptFrame) {
 
     // The interrupt may have caused a Thread to wake up, so if this core is currently idle, attempt a context switch immediately to
     // ensure we aren't sitting around doing nothing for no reason.
-    if Thread::current_interrupted().is_none() && Process::is_initialized() {
+    if level::current() != InterruptLevel::Passive {
+        // The interrupted code is in a critical section, so it can't be preempted until the interrupt level is lowered again
+    } else if Thread::current_interrupted().is_none() && Process::is_initialized() {
         perform_context_switch_interrupt(None, interrupt_frame);
+    } else if let Some(thread) = Thread::current_interrupted() {
+        // Similarly, if the interrupt woke up a thread with a higher priority than the one that was interrupted or the interrupted thread
+        // has used up its time slice, then the interrupted thread should be preempted so that another thread can run.
+        if should_preempt(&thread, TIMESLICE_EXPIRED.get()) {
+            let mut thread_lock = thread.lock();
+
+            if matches!(*thread_lock.state(), task::ThreadState::Running) {
+                *thread_lock.state_mut() = task::ThreadState::Ready;
+                perform_context_switch_interrupt(Some(thread_lock), interrupt_frame);
+            }
+        } else if TIMESLICE_EXPIRED.get() {
+            // There's nothing else for this core to run right now, so the interrupted thread gets a new time slice.
+            reset_timeslice();
+        }
     }
 
     *IN_INTERRUPT.get() = false;
 }
 
+/// Forcibly sets whether an asynchronous hardware interrupt is being serviced on the current CPU core, without performing any of the other
+/// work normally done when an interrupt ends.
+///
+/// # Safety
+///
+/// This is only intended to be used when execution is being rewound past the start or end of an interrupt handler, such as when recovering
+/// from a panic. The provided value must match the context that execution is being rewound to.
+pub(crate) unsafe fn force_set_handling_interrupt(in_interrupt: bool) {
+    *IN_INTERRUPT.get() = in_interrupt;
+}
+
+/// Notifies the scheduler that a periodic timer tick has occurred on the current CPU core and that the provided amount of time has elapsed
+/// since the last tick. If the time slice of the currently running thread has expired, it will be preempted at the end of the current
+/// interrupt in favour of any other ready threads of the same or higher priority.
+///
+/// This should be called by the architecture's timer interrupt handler.
+pub(crate) fn timer_tick(elapsed: Duration) {
+    assert!(is_handling_interrupt());
+
+    timer::tick(elapsed);
+
+    let remaining = TIMESLICE_REMAINING.get().saturating_sub(elapsed);
+
+    TIMESLICE_REMAINING.set(remaining);
+    if remaining.is_zero() {
+        TIMESLICE_EXPIRED.set(true);
+    }
+}
+
+fn reset_timeslice() {
+    TIMESLICE_REMAINING.set(TIMESLICE.try_get().copied().unwrap_or(Duration::ZERO));
+    TIMESLICE_EXPIRED.set(false);
+}
+
+/// Blocks the current thread until the system clock (as returned by [`timer::now`]) reaches the provided deadline.
+///
+/// # Panics
+///
+/// This function will panic if called from an interrupt handler or from a context in which the current thread cannot block.
+pub fn sleep_until(deadline: Duration) {
+    timer::at(deadline).unwrap_blocking();
+}
+
 /// Enqueues a soft interrupt to be run later (either when interrupts would be re-enabled by dropping an InterruptDisabler or at the end
 /// of handling the current interrupt). The soft interrupt is always run with interrupts disabled.
 ///
@@ -71,7 +152,7 @@ pub(crate) unsafe fn end_interrupt(interrupt_frame: &mut InterruptFrame) {
 ///
 /// A panic will occur when running the soft interrupt if it attempts to perform a blocking operation.
 pub fn enqueue_soft_interrupt<F: FnOnce() + 'static>(f: F) {
-    if !is_handling_interrupt() && interrupt::are_enabled() {
+    if !is_handling_interrupt() && interrupt::are_enabled() && level::current() == InterruptLevel::Passive {
         let _interrupts_disabled = InterruptDisabler::new();
         f();
     } else {
@@ -82,6 +163,11 @@ pub fn enqueue_soft_interrupt<F: FnOnce() + 'static>(f: F) {
 
 /// Runs all pending soft interrupts enqueued by [`enqueue_soft_interrupt`].
 pub(crate) fn run_soft_interrupts() {
+    // Soft interrupts could touch data that a raised interrupt level is protecting, so they're held back until the level is lowered
+    if level::current() != InterruptLevel::Passive {
+        return;
+    }
+
     let _interrupts_disabled = InterruptDisabler::new();
 
     // SAFETY: No references to SOFT_INTERRUPTS can ever leak and no user-provided code runs while it is in use
@@ -97,8 +183,28 @@ pub fn is_handling_interrupt() -> bool {
     unsafe { *IN_INTERRUPT.get() }
 }
 
+fn should_preempt(thread: &Thread, timeslice_expired: bool) -> bool {
+    let priority = thread.lock().priority();
+    let is_idle = idle::is_idle_thread(thread);
+
+    // TODO Support user-mode processes
+    Process::kernel().lock().highest_ready_priority().map_or(false, |ready_priority| {
+        is_idle || ready_priority > priority || (timeslice_expired && ready_priority == priority)
+    })
+}
+
 pub unsafe fn perform_context_switch_interrupt(old_thread_lock: Option<task::ThreadLock>, interrupt_frame: &mut InterruptFrame) {
     assert!(is_handling_interrupt());
+    assert_eq!(
+        InterruptLevel::Passive,
+        level::current(),
+        "Cannot switch threads while the interrupt level is raised"
+    );
+
+    let old_thread_id = old_thread_lock
+        .as_ref()
+        .filter(|_| replay::ENABLED)
+        .map(|lock| replay::ThreadId::of(lock.thread()));
 
     if let Some(mut old_thread_lock) = old_thread_lock {
         debug_assert!(!matches!(*old_thread_lock.state(), task::ThreadState::Running));
@@ -106,6 +212,9 @@ pub unsafe fn perform_context_switch_interrupt(old_thread_lock: Option<task::Thr
         old_thread_lock.save_cpu_state(interrupt_frame);
 
         match *old_thread_lock.state() {
+            task::ThreadState::Ready if idle::is_idle_thread(old_thread_lock.thread()) => {
+                // The idle thread is never placed on a ready queue, since it is only ever switched to when nothing else is ready
+            },
             task::ThreadState::Ready => {
                 let old_thread = old_thread_lock.thread();
                 let old_process = old_thread.process().upgrade().unwrap();
@@ -117,14 +226,36 @@ pub unsafe fn perform_context_switch_interrupt(old_thread_lock: Option<task::Thr
                 old_process_lock.enqueue_ready_thread(old_thread_lock);
             },
             task::ThreadState::Dead => {
-                // TODO Free thread memory
+                let old_thread = old_thread_lock.thread().as_arc();
+                let join_writer = old_thread_lock.take_join_writer();
+
+                drop(old_thread_lock);
+
+                if let Some(join_writer) = join_writer {
+                    join_writer.finish(());
+                }
+
+                reaper::enqueue_dead_thread(old_thread);
             },
             _ => {},
         }
     }
 
     // TODO Support user-mode processes
-    let thread = task::Process::kernel().lock().dequeue_ready_thread();
+    let thread = {
+        let mut kernel_process = task::Process::kernel().lock();
+
+        YIELD_TARGET
+            .take()
+            .filter(|target| kernel_process.remove_ready_thread(target))
+            .or_else(|| kernel_process.dequeue_ready_thread())
+            .or_else(|| idle::get().cloned())
+    };
+
+    replay::record(|| replay::ReplayEvent::ContextSwitch {
+        from: old_thread_id,
+        to: thread.as_deref().map(replay::ThreadId::of),
+    });
 
     if let Some(ref thread) = thread {
         let mut thread = thread.lock();
@@ -133,6 +264,7 @@ pub unsafe fn perform_context_switch_interrupt(old_thread_lock: Option<task::Thr
 
         *thread.state_mut() = task::ThreadState::Running;
         thread.restore_cpu_state(interrupt_frame);
+        reset_timeslice();
     } else {
         interrupt_frame.set_to_idle();
     }
@@ -143,11 +275,13 @@ pub unsafe fn perform_context_switch_interrupt(old_thread_lock: Option<task::Thr
 #[cfg(test)]
 mod test {
     use alloc::rc::Rc;
+    use alloc::vec::Vec;
     use core::cell::Cell;
     use core::sync::atomic::{AtomicBool, Ordering};
 
     use super::task::*;
     use crate::sync::uninterruptible::InterruptDisabler;
+    use crate::sync::UninterruptibleSpinlock;
     use crate::test_util::TEST_THREAD_STACK_SIZE;
 
     #[test_case]
@@ -164,7 +298,7 @@ mod test {
         let thread = unsafe {
             Process::kernel()
                 .lock()
-                .create_kernel_thread_unchecked(thread_fn, TEST_THREAD_STACK_SIZE)
+                .create_kernel_thread_unchecked("test", thread_fn, TEST_THREAD_STACK_SIZE)
         };
         thread.lock().wake();
 
@@ -176,6 +310,404 @@ mod test {
         assert!(matches!(*thread.lock().state(), ThreadState::Dead));
     }
 
+    #[test_case]
+    fn test_thread_cpu_stats() {
+        let thread = Process::kernel().lock().create_kernel_thread(
+            "test",
+            || {
+                Thread::yield_current();
+            },
+            TEST_THREAD_STACK_SIZE,
+        );
+
+        assert_eq!(ThreadCpuStats::default(), thread.lock().cpu_stats());
+
+        let join = thread.lock().join();
+
+        thread.lock().wake();
+        join.unwrap_blocking();
+
+        let stats = thread.lock().cpu_stats();
+
+        assert_eq!(1, stats.wakeups);
+        assert!(stats.context_switches >= 2);
+        assert!(Process::kernel().cpu_time() >= stats.cpu_time);
+    }
+
+    #[test_case]
+    fn test_thread_priority() {
+        let order = UninterruptibleSpinlock::new(Vec::new());
+
+        let low_thread = unsafe {
+            Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
+                || order.lock().push(ThreadPriority::Background),
+                TEST_THREAD_STACK_SIZE,
+            )
+        };
+        let high_thread = unsafe {
+            Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
+                || order.lock().push(ThreadPriority::High),
+                TEST_THREAD_STACK_SIZE,
+            )
+        };
+
+        assert_eq!(ThreadPriority::Normal, low_thread.lock().priority());
+
+        low_thread.lock().set_priority(ThreadPriority::Background);
+        high_thread.lock().set_priority(ThreadPriority::High);
+
+        low_thread.lock().wake();
+        high_thread.lock().wake();
+
+        Thread::current().lock().set_priority(ThreadPriority::Background);
+        Thread::yield_current();
+        Thread::yield_current();
+        Thread::current().lock().set_priority(ThreadPriority::Normal);
+
+        assert_eq!(&[ThreadPriority::High, ThreadPriority::Background][..], &order.lock()[..]);
+        assert!(matches!(*low_thread.lock().state(), ThreadState::Dead));
+        assert!(matches!(*high_thread.lock().state(), ThreadState::Dead));
+    }
+
+    #[test_case]
+    fn test_thread_yield_to() {
+        let order = UninterruptibleSpinlock::new(Vec::new());
+
+        let first_thread = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", || order.lock().push(1), TEST_THREAD_STACK_SIZE)
+        };
+        let second_thread = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", || order.lock().push(2), TEST_THREAD_STACK_SIZE)
+        };
+
+        first_thread.lock().wake();
+        second_thread.lock().wake();
+
+        // The second thread should run first despite being behind the first thread in the ready queue
+        Thread::yield_to(&second_thread);
+        assert_eq!(&[2, 1][..], &order.lock()[..]);
+
+        // Yielding to a thread that is not ready should fall back to a normal yield
+        Thread::yield_to(&second_thread);
+
+        assert!(matches!(*first_thread.lock().state(), ThreadState::Dead));
+        assert!(matches!(*second_thread.lock().state(), ThreadState::Dead));
+    }
+
+    #[test_case]
+    fn test_thread_yield_to_after_set_priority() {
+        let order = UninterruptibleSpinlock::new(Vec::new());
+
+        let first_thread = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", || order.lock().push(1), TEST_THREAD_STACK_SIZE)
+        };
+        let second_thread = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", || order.lock().push(2), TEST_THREAD_STACK_SIZE)
+        };
+
+        first_thread.lock().wake();
+        second_thread.lock().wake();
+
+        // The second thread is still on the normal priority ready queue, which is where yielding to it needs to find it
+        second_thread.lock().set_priority(ThreadPriority::High);
+        assert_eq!(ThreadPriority::High, second_thread.lock().priority());
+
+        Thread::yield_to(&second_thread);
+        assert_eq!(&[2, 1][..], &order.lock()[..]);
+
+        Thread::yield_current();
+
+        assert!(matches!(*first_thread.lock().state(), ThreadState::Dead));
+        assert!(matches!(*second_thread.lock().state(), ThreadState::Dead));
+    }
+
+    #[test_case]
+    fn test_idle_thread() {
+        use core::time::Duration;
+
+        let idle = super::idle::get().unwrap();
+        let before = idle.lock().cpu_stats().cpu_time;
+
+        assert_eq!(Some("idle"), idle.name());
+
+        Thread::sleep(Duration::from_millis(10));
+        assert!(idle.lock().cpu_stats().cpu_time > before);
+        assert!(!matches!(*idle.lock().state(), ThreadState::Running));
+    }
+
+    #[test_case]
+    fn test_thread_name() {
+        use alloc::format;
+
+        let thread = Process::kernel()
+            .lock()
+            .create_kernel_thread("named worker", || {}, TEST_THREAD_STACK_SIZE);
+
+        assert_eq!(Some("named worker"), thread.name());
+        assert!(format!("{}", thread.debug_name()).ends_with("\"named worker\")"));
+
+        let join = thread.lock().join();
+        thread.lock().wake();
+        join.unwrap_blocking();
+    }
+
+    #[thread_local]
+    static TEST_THREAD_LOCAL: Cell<u32> = Cell::new(1);
+
+    #[test_case]
+    fn test_thread_locals_per_thread() {
+        TEST_THREAD_LOCAL.set(2);
+
+        let thread = Process::kernel().lock().create_kernel_thread(
+            "test",
+            || {
+                assert_eq!(1, TEST_THREAD_LOCAL.get());
+                TEST_THREAD_LOCAL.set(3);
+            },
+            TEST_THREAD_STACK_SIZE,
+        );
+
+        let join = thread.lock().join();
+        thread.lock().wake();
+        join.unwrap_blocking();
+
+        assert_eq!(2, TEST_THREAD_LOCAL.get());
+    }
+
+    #[test_case]
+    fn test_dead_thread_reaped() {
+        let thread = Process::kernel().lock().create_kernel_thread("test", || {}, TEST_THREAD_STACK_SIZE);
+
+        assert!(thread.lock().has_kernel_stack());
+
+        let join = thread.lock().join();
+        thread.lock().wake();
+        join.unwrap_blocking();
+
+        for _ in 0..16 {
+            if !thread.lock().has_kernel_stack() {
+                break;
+            }
+
+            Thread::yield_current();
+        }
+
+        assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+        assert!(!thread.lock().has_kernel_stack());
+    }
+
+    #[test_case]
+    fn test_kill_suspended() {
+        let thread = Process::kernel().lock().create_kernel_thread(
+            "test",
+            || {
+                panic!("Killed thread was run");
+            },
+            TEST_THREAD_STACK_SIZE,
+        );
+        let join = thread.lock().join();
+
+        thread.kill();
+
+        assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+        assert!(thread.lock().is_kill_requested());
+        join.unwrap_blocking();
+
+        // Waking a thread after it has been killed should have no effect
+        thread.lock().wake();
+        assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+    }
+
+    #[test_case]
+    fn test_process_exit() {
+        use alloc::string::String;
+        use alloc::vec;
+
+        let process = Process::create(vec![String::from("test")]);
+        let pid = process.pid();
+        let thread = process.lock().create_user_thread(0, 0, 0);
+        let join = process.lock().join();
+
+        assert!(Process::list().get(pid).is_some());
+
+        process.exit(3);
+        process.exit(4);
+
+        assert_eq!(3, join.unwrap_blocking());
+        assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+        assert!(Process::list().get(pid).is_none());
+        assert_eq!(Some(3), process.lock().exit_code());
+        assert!(process.lock().addr_space().is_none());
+        assert_eq!(3, process.lock().join().unwrap_blocking());
+    }
+
+    #[test_case]
+    fn test_wait_for_child() {
+        use alloc::string::String;
+        use alloc::vec;
+
+        let parent = Process::create(vec![String::from("parent")]);
+        let child_a = parent.create_child(vec![String::from("a")]);
+        let child_b = parent.create_child(vec![String::from("b")]);
+        let grandchild = child_b.create_child(vec![String::from("c")]);
+
+        assert_eq!(Some(0), parent.parent_pid());
+        assert_eq!(Some(parent.pid()), child_a.parent_pid());
+        assert_eq!(vec![child_a.pid(), child_b.pid()], parent.child_pids());
+
+        let wait_a = parent.wait_for_child().unwrap();
+
+        child_a.exit(1);
+        assert_eq!(
+            Some(ExitStatus {
+                pid: child_a.pid(),
+                code: 1
+            }),
+            wait_a.unwrap_blocking()
+        );
+
+        child_b.exit(2);
+        assert_eq!(Some(0), grandchild.parent_pid());
+        assert_eq!(vec![child_b.pid()], parent.child_pids());
+        assert_eq!(
+            Some(ExitStatus {
+                pid: child_b.pid(),
+                code: 2
+            }),
+            parent.wait_for_child().unwrap().unwrap_blocking()
+        );
+        assert!(parent.wait_for_child().is_none());
+        assert!(parent.child_pids().is_empty());
+
+        grandchild.exit(0);
+        parent.exit(0);
+        assert_eq!(None, parent.parent_pid());
+    }
+
+    #[test_case]
+    fn test_wait_for_child_of_exited_process() {
+        use alloc::string::String;
+        use alloc::vec;
+
+        let parent = Process::create(vec![String::from("parent")]);
+        let child = parent.create_child(vec![String::from("child")]);
+        let wait = parent.wait_for_child().unwrap();
+
+        parent.exit(0);
+        assert_eq!(None, wait.unwrap_blocking());
+        assert_eq!(Some(0), child.parent_pid());
+
+        child.exit(1);
+        assert_eq!(None, child.parent_pid());
+    }
+
+    #[test_case]
+    fn test_map_anonymous() {
+        use alloc::string::String;
+        use alloc::vec;
+
+        use crate::arch::page::PageFlags;
+        use crate::arch::VirtAddr;
+        use crate::mem::frame::{self, FrameAllocator};
+        use crate::mem::region::{MapError, RegionBacking};
+
+        let process = Process::create(vec![String::from("test")]);
+        let start = VirtAddr::new(0x40_0000);
+
+        // Page tables are not freed when a region is unmapped, so make sure they've already been allocated before counting free frames
+        process.lock().map_anonymous(start, 1, PageFlags::empty(), "warmup").unwrap();
+        process.lock().unmap_region(start).unwrap();
+
+        let frames_before = frame::get_allocator().num_frames_available();
+
+        process.lock().map_anonymous(start, 3, PageFlags::WRITEABLE, "heap").unwrap();
+        assert_eq!(
+            Err(MapError::Overlap),
+            process.lock().map_anonymous(start + 0x2000_u64, 2, PageFlags::WRITEABLE, "heap")
+        );
+
+        {
+            let mut process_lock = process.lock();
+            let usage = process_lock.region_usage();
+
+            assert_eq!(1, usage.len());
+            assert_eq!(RegionBacking::Anonymous, usage[0].0.backing());
+            assert_eq!(PageFlags::USER | PageFlags::WRITEABLE, usage[0].0.flags());
+            assert_eq!(3, usage[0].1.resident_pages);
+            assert_eq!(3, process_lock.memory().unwrap().1.len());
+        }
+
+        assert!(process.lock().unmap_region(start).is_some());
+        assert!(process.lock().regions().is_empty());
+        assert_eq!(frames_before, frame::get_allocator().num_frames_available());
+
+        process.exit(0);
+    }
+
+    #[test_case]
+    fn test_process_lookup() {
+        use alloc::string::String;
+        use alloc::vec;
+
+        let process = Process::create(vec![String::from("test")]);
+        let pid = process.pid();
+
+        assert!(core::ptr::eq(&**Process::kernel(), &*Process::by_pid(0).unwrap()));
+        assert!(core::ptr::eq(&*process, &*Process::by_pid(pid).unwrap()));
+        assert!(all_processes().iter().any(|p| p.pid() == pid));
+
+        process.exit(0);
+
+        assert!(Process::by_pid(pid).is_none());
+        assert!(!all_processes().iter().any(|p| p.pid() == pid));
+    }
+
+    #[test_case]
+    fn test_kernel_stack_guard_page() {
+        use crate::arch::page::{AddressSpace, PAGE_SIZE};
+        use crate::arch::VirtAddr;
+
+        let thread = Process::kernel().lock().create_kernel_thread(
+            "test",
+            || {
+                let local = 0_u8;
+                let thread = Thread::current();
+                let mut page = VirtAddr::from_ptr(&local).as_u64() & !(PAGE_SIZE as u64 - 1);
+
+                assert!(!thread.is_stack_guard_page(VirtAddr::from_ptr(&local)));
+
+                for _ in 0..(TEST_THREAD_STACK_SIZE / PAGE_SIZE) {
+                    assert!(AddressSpace::kernel().get_page(VirtAddr::new(page)).is_some());
+                    page -= PAGE_SIZE as u64;
+
+                    if thread.is_stack_guard_page(VirtAddr::new(page)) {
+                        break;
+                    }
+                }
+
+                assert!(thread.is_stack_guard_page(VirtAddr::new(page)));
+                assert_eq!(None, AddressSpace::kernel().get_page(VirtAddr::new(page)));
+            },
+            TEST_THREAD_STACK_SIZE,
+        );
+
+        assert!(!Thread::current().is_stack_guard_page(VirtAddr::zero()));
+
+        let join = thread.lock().join();
+        thread.lock().wake();
+        join.unwrap_blocking();
+    }
+
     #[test_case]
     fn test_soft_interrupt_in_interrupt_disabler() {
         let flag = Rc::new(Cell::new(false));
@@ -0,0 +1,81 @@
+//! Deferred freeing of resources belonging to dead threads.
+//!
+//! When a thread dies, the context switch away from it happens in an interrupt handler that is still executing on the dead thread's
+//! kernel-mode stack, so the stack cannot be freed at that point. Instead, dead threads are handed off to a dedicated reaper thread, which
+//! frees their remaining resources and drops the scheduler's final references to them. Once the last thread of an exiting process has been
+//! reaped, the reaper also finishes tearing down that process.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::pin::Pin;
+
+use super::task::{Process, Thread, ThreadState};
+use super::wait::ThreadWaitList;
+use crate::sync::UninterruptibleSpinlock;
+
+const REAPER_STACK_SIZE: usize = 4 * 4096;
+
+static DEAD_THREADS: UninterruptibleSpinlock<Vec<Pin<Arc<Thread>>>> = UninterruptibleSpinlock::new(Vec::new());
+static REAPER_WAIT: ThreadWaitList = ThreadWaitList::new();
+
+/// Hands off a dead thread to the reaper thread so that its resources can be freed.
+pub(super) fn enqueue_dead_thread(thread: Pin<Arc<Thread>>) {
+    debug_assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+
+    DEAD_THREADS.lock().push(thread);
+    REAPER_WAIT.wake_one();
+}
+
+fn reap(thread: Pin<Arc<Thread>>) {
+    let (stack, tls) = {
+        let mut thread_lock = thread.lock();
+        (thread_lock.take_kernel_stack(), thread_lock.take_tls())
+    };
+
+    drop(tls);
+
+    // TODO Once other CPU cores can run threads, make sure the core that switched away from this thread is no longer running its
+    //      interrupt handler on this stack before freeing it.
+    if let Some(stack) = stack {
+        // SAFETY: The thread is dead and the context switch away from it has completed, so nothing is executing on this stack anymore.
+        unsafe {
+            stack.free();
+        }
+    }
+
+    if let Some(process) = thread.process().upgrade() {
+        drop(thread);
+        process.try_finish_exit();
+    }
+}
+
+fn run_reaper() -> ! {
+    loop {
+        let mut dead_threads = DEAD_THREADS.lock();
+
+        if dead_threads.is_empty() {
+            let wait = REAPER_WAIT.wait();
+
+            drop(dead_threads);
+            wait.suspend();
+        } else {
+            let threads = mem::take(&mut *dead_threads);
+
+            drop(dead_threads);
+
+            for thread in threads {
+                reap(thread);
+            }
+        }
+    }
+}
+
+/// Starts the reaper thread. Until this is called, dead threads will be queued but their resources will not be freed.
+pub(super) fn init() {
+    let reaper = Process::kernel()
+        .lock()
+        .create_kernel_thread("reaper", || run_reaper(), REAPER_STACK_SIZE);
+
+    reaper.lock().wake();
+}
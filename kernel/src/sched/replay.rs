@@ -0,0 +1,249 @@
+//! Recording of interrupt and scheduling events for reconstructing the order in which things happened after a failure.
+//!
+//! When the `replay_log` feature is enabled, every hardware IRQ, context switch and timer expiry is recorded into a large ring buffer along
+//! with a global sequence number, the CPU core it happened on and the value of the cycle counter. Once something has gone wrong, the most
+//! recent events can be retrieved using [`events`] (e.g. from the debug console) or are written to the serial port when the kernel panics,
+//! so that rare bugs that depend on the exact interleaving of interrupts and threads can be reconstructed offline.
+//!
+//! Only the events themselves are recorded and not the data that was being processed, so the log is meant to narrow down which orderings
+//! to look at rather than to replay execution exactly.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::task::Thread;
+
+/// Identifies a thread in the replay log by its process and thread IDs. The IDs are recorded rather than a reference to the thread so that
+/// recording an event never keeps a thread alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId {
+    pub pid: u64,
+    pub tid: u64,
+}
+
+impl ThreadId {
+    /// Gets the IDs identifying the provided thread. Threads whose process has already been freed are given a PID of 0.
+    pub fn of(thread: &Thread) -> ThreadId {
+        ThreadId {
+            pid: thread.process().upgrade().map_or(0, |process| process.pid()),
+            tid: thread.thread_id(),
+        }
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.pid, self.tid)
+    }
+}
+
+/// An event recorded in the replay log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayEvent {
+    /// A handler for the provided hardware IRQ line is about to run.
+    Irq(u8),
+    /// The scheduler switched from one thread to another. A missing thread means the core was or will be idle without running any thread.
+    ContextSwitch { from: Option<ThreadId>, to: Option<ThreadId> },
+    /// A timer whose deadline was the provided number of nanoseconds after the system timer started has expired.
+    TimerExpired { deadline_ns: u64 },
+}
+
+impl fmt::Display for ReplayEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn fmt_thread(f: &mut fmt::Formatter, thread: Option<ThreadId>) -> fmt::Result {
+            match thread {
+                Some(thread) => write!(f, "{}", thread),
+                None => write!(f, "idle"),
+            }
+        }
+
+        match *self {
+            ReplayEvent::Irq(irq) => write!(f, "irq {}", irq),
+            ReplayEvent::ContextSwitch { from, to } => {
+                write!(f, "switch ")?;
+                fmt_thread(f, from)?;
+                write!(f, " -> ")?;
+                fmt_thread(f, to)
+            },
+            ReplayEvent::TimerExpired { deadline_ns } => write!(f, "timer expired (deadline {} ns)", deadline_ns),
+        }
+    }
+}
+
+/// A single entry in the replay log.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayRecord {
+    /// The global sequence number of this event. Events recorded on different CPU cores are ordered by their sequence numbers.
+    pub seq: u64,
+    /// The CPU core on which this event happened.
+    pub cpu: u32,
+    /// The value of the current CPU core's cycle counter when this event happened.
+    pub cycles: u64,
+    pub event: ReplayEvent,
+}
+
+impl fmt::Display for ReplayRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} cpu {} @{}: {}", self.seq, self.cpu, self.cycles, self.event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayLogDisabledError;
+
+/// Whether events are being recorded, i.e. whether the `replay_log` feature is enabled. Callers can check this to avoid gathering
+/// information for an event ahead of time when it would never be recorded.
+pub const ENABLED: bool = cfg!(feature = "replay_log");
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "replay_log")] {
+        mod recorder {
+            use alloc::vec::Vec;
+
+            use super::{ReplayEvent, ReplayLogDisabledError, ReplayRecord};
+            use crate::sched::smp;
+            use crate::sync::UninterruptibleSpinlock;
+            use crate::{arch, static_footprint};
+
+            /// The number of events kept in the ring buffer before the oldest ones start being overwritten.
+            const RING_SIZE: usize = 16384;
+
+            struct Ring {
+                records: [ReplayRecord; RING_SIZE],
+                next_seq: u64,
+            }
+
+            impl Ring {
+                fn iter(&self) -> impl Iterator<Item = &ReplayRecord> {
+                    let (newer, older) = if self.next_seq < RING_SIZE as u64 {
+                        (&self.records[..0], &self.records[..self.next_seq as usize])
+                    } else {
+                        self.records.split_at(self.next_seq as usize % RING_SIZE)
+                    };
+
+                    older.iter().chain(newer.iter())
+                }
+            }
+
+            // This is all zeroes, so that the ring buffer ends up in .bss rather than taking up space in the kernel image
+            const NO_RECORD: ReplayRecord = ReplayRecord {
+                seq: 0,
+                cpu: 0,
+                cycles: 0,
+                event: ReplayEvent::Irq(0),
+            };
+
+            static RING: UninterruptibleSpinlock<Ring> = UninterruptibleSpinlock::new(Ring {
+                records: [NO_RECORD; RING_SIZE],
+                next_seq: 0,
+            });
+
+            static_footprint!("sched", RING);
+
+            pub fn record(event: impl FnOnce() -> ReplayEvent) {
+                let event = event();
+                let cpu = smp::current_cpu();
+                let mut ring = RING.lock();
+                let seq = ring.next_seq;
+
+                ring.records[seq as usize % RING_SIZE] = ReplayRecord {
+                    seq,
+                    cpu,
+                    cycles: arch::cycle_counter(),
+                    event,
+                };
+                ring.next_seq += 1;
+            }
+
+            pub fn events(n: usize) -> Result<Vec<ReplayRecord>, ReplayLogDisabledError> {
+                let ring = RING.lock();
+                let len = ring.iter().count();
+
+                Ok(ring.iter().skip(len.saturating_sub(n)).copied().collect())
+            }
+
+            pub fn try_for_each(f: impl FnMut(&ReplayRecord)) -> bool {
+                match RING.try_lock() {
+                    Some(ring) => {
+                        ring.iter().for_each(f);
+                        true
+                    },
+                    None => false,
+                }
+            }
+        }
+    } else {
+        mod recorder {
+            use alloc::vec::Vec;
+
+            use super::{ReplayEvent, ReplayLogDisabledError, ReplayRecord};
+
+            #[inline(always)]
+            pub fn record(_: impl FnOnce() -> ReplayEvent) {}
+
+            pub fn events(_: usize) -> Result<Vec<ReplayRecord>, ReplayLogDisabledError> {
+                Err(ReplayLogDisabledError)
+            }
+
+            pub fn try_for_each(_: impl FnMut(&ReplayRecord)) -> bool {
+                false
+            }
+        }
+    }
+}
+
+/// Records an event in the replay log. The event is only constructed if the `replay_log` feature is enabled, so that gathering the
+/// information needed to describe it costs nothing otherwise.
+#[inline(always)]
+pub fn record(event: impl FnOnce() -> ReplayEvent) {
+    recorder::record(event);
+}
+
+/// Gets up to `n` of the most recently recorded events, oldest first.
+///
+/// Recording events has a performance and memory cost, so it is only done when the `replay_log` feature is enabled. If it is not enabled,
+/// this function returns an error.
+pub fn events(n: usize) -> Result<Vec<ReplayRecord>, ReplayLogDisabledError> {
+    recorder::events(n)
+}
+
+/// Writes every event still in the replay log to the provided writer, oldest first, without allocating or waiting for any locks. This is
+/// meant to be called while panicking. Does nothing if the `replay_log` feature is not enabled.
+pub fn dump(w: &mut impl fmt::Write) {
+    if !ENABLED {
+        return;
+    }
+
+    let _ = writeln!(w, "\nReplay log:");
+
+    if !recorder::try_for_each(|record| {
+        let _ = writeln!(w, "  {}", record);
+    }) {
+        let _ = writeln!(w, "  Cannot show replay log, since it is locked");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_record_event() {
+        if !ENABLED {
+            crate::test_util::skip("replay log is not enabled");
+            return;
+        }
+
+        let event = ReplayEvent::TimerExpired { deadline_ns: u64::MAX };
+
+        record(|| event);
+        record(|| ReplayEvent::Irq(15));
+
+        // Other events may have been recorded in between by interrupts or other cores
+        let events = events(usize::MAX).unwrap();
+        let first = events.iter().rposition(|r| r.event == event).unwrap();
+
+        assert!(events[first..].iter().any(|r| r.event == ReplayEvent::Irq(15)));
+        assert!(events.windows(2).all(|w| w[0].seq < w[1].seq));
+    }
+}
@@ -0,0 +1,231 @@
+//! Running functions on other CPU cores.
+//!
+//! Some operations can only be performed by the core whose state they affect, such as invalidating TLB entries or draining per-CPU caches.
+//! [`call_on`] queues a function to be run on a particular core and sends it an inter-processor interrupt (IPI), returning a [`Future`] that
+//! resolves once the function has run. [`call_all`] does the same for every online core, including the current one.
+//!
+//! Queued functions are run from the IPI handler with interrupts disabled, so they must not block. The queue for a core is emptied before
+//! any of its functions are run and its lock is not held while running them, so a queued function is free to call [`call_on`] or
+//! [`call_all`] itself. Functions targeting the current core are run immediately rather than being queued.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::{fmt, mem};
+
+use crate::sync::future::FutureWriter;
+use crate::sync::uninterruptible::InterruptDisabler;
+use crate::sync::{Future, UninterruptibleSpinlock};
+use crate::{arch, log};
+
+/// An error that can occur when calling a function on another CPU core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpCallError {
+    /// The target core does not exist or has not been brought online.
+    CpuOffline,
+    /// The inter-processor interrupt could not be sent to the target core.
+    IpiFailed,
+}
+
+impl fmt::Display for SmpCallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SmpCallError::CpuOffline => write!(f, "cpu is offline"),
+            SmpCallError::IpiFailed => write!(f, "failed to send inter-processor interrupt"),
+        }
+    }
+}
+
+struct PendingCall {
+    id: u64,
+    f: Box<dyn FnOnce() + Send>,
+    done: FutureWriter<()>,
+}
+
+impl PendingCall {
+    fn run(self) {
+        (self.f)();
+        self.done.finish(());
+    }
+}
+
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The queues of pending calls for each online core, keyed by hardware ID. A core is online if and only if it has an entry here.
+static CALL_QUEUES: UninterruptibleSpinlock<BTreeMap<u32, Vec<PendingCall>>> = UninterruptibleSpinlock::new(BTreeMap::new());
+
+/// The number of entries in [`CALL_QUEUES`], kept separately so that it can be read without taking any locks.
+static NUM_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+crate::cpu_local! {
+    static CURRENT_CPU: Cell<Option<u32>> = Cell::new(None);
+}
+
+/// Gets the hardware ID of the CPU core that this is called on.
+pub fn current_cpu() -> u32 {
+    if let Some(hw_id) = CURRENT_CPU.get() {
+        return hw_id;
+    }
+
+    let hw_id = arch::topology::current_hw_id();
+
+    CURRENT_CPU.set(Some(hw_id));
+    hw_id
+}
+
+/// Gets the hardware IDs of all CPU cores that are online and can be called using [`call_on`].
+pub fn online_cpus() -> Vec<u32> {
+    CALL_QUEUES.lock().keys().copied().collect()
+}
+
+/// Gets the number of CPU cores that are online. Unlike [`online_cpus`], this does not take any locks and so is safe to call while
+/// panicking.
+pub fn num_online_cpus() -> usize {
+    NUM_ONLINE.load(Ordering::Acquire)
+}
+
+/// Marks the CPU core that this is called on as online, allowing functions to be called on it.
+///
+/// # Safety
+///
+/// This must only be called once on each core after it is ready to handle inter-processor interrupts.
+pub unsafe fn mark_online() {
+    let hw_id = current_cpu();
+    let prev = CALL_QUEUES.lock().insert(hw_id, Vec::new());
+
+    assert!(prev.is_none(), "cpu {} was already online", hw_id);
+    NUM_ONLINE.fetch_add(1, Ordering::Release);
+    crate::mem::tlb::init_cpu();
+    log!(Debug, "smp", "cpu {} is online", hw_id);
+}
+
+/// Runs the provided function on the CPU core with the provided hardware ID, returning a [`Future`] that resolves once it has finished
+/// running. The function is run with interrupts disabled and must not block.
+///
+/// If the target is the current core, the function is run immediately before this returns.
+pub fn call_on(cpu: u32, f: impl FnOnce() + Send + 'static) -> Result<Future<()>, SmpCallError> {
+    let _interrupts_disabled = InterruptDisabler::new();
+
+    if cpu == current_cpu() {
+        if !CALL_QUEUES.lock().contains_key(&cpu) {
+            return Err(SmpCallError::CpuOffline);
+        }
+
+        f();
+        return Ok(Future::done(()));
+    }
+
+    let (future, done) = Future::new();
+    let id = NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed);
+
+    CALL_QUEUES
+        .lock()
+        .get_mut(&cpu)
+        .ok_or(SmpCallError::CpuOffline)?
+        .push(PendingCall { id, f: Box::new(f), done });
+
+    if !arch::interrupt::send_call_ipi(cpu) {
+        // The target never heard about the call, but it may have picked it up anyway while handling an earlier IPI. Only report failure if
+        // the call is still sitting in its queue.
+        let mut queues = CALL_QUEUES.lock();
+
+        if let Some(queue) = queues.get_mut(&cpu) {
+            if let Some(idx) = queue.iter().position(|call| call.id == id) {
+                drop(queue.remove(idx));
+                return Err(SmpCallError::IpiFailed);
+            }
+        }
+    }
+
+    Ok(future)
+}
+
+/// Runs the provided function on every online CPU core, including the current one, returning a [`Future`] that resolves once it has
+/// finished running on all of them. The function is run with interrupts disabled and must not block.
+///
+/// Cores that cannot be reached are skipped with a warning, since a core going offline has nothing left for the function to act on.
+pub fn call_all(f: impl Fn() + Send + Sync + 'static) -> Future<()> {
+    let f = Arc::new(f);
+
+    Future::all(online_cpus().into_iter().filter_map(|cpu| {
+        let f = f.clone();
+
+        match call_on(cpu, move || f()) {
+            Ok(future) => Some(future),
+            Err(err) => {
+                log!(Warning, "smp", "Failed to call function on cpu {}: {}", cpu, err);
+                None
+            },
+        }
+    }))
+}
+
+/// Runs all functions queued for the current CPU core and processes any TLB shootdown in flight. This should be called by the architecture's
+/// handler for the call IPI.
+///
+/// # Safety
+///
+/// This must only be called from an interrupt handler.
+pub unsafe fn handle_call_ipi() {
+    crate::mem::tlb::poll();
+
+    let calls = match CALL_QUEUES.lock().get_mut(&current_cpu()) {
+        Some(queue) => mem::take(queue),
+        None => return,
+    };
+
+    for call in calls {
+        call.run();
+    }
+}
+
+unsafe fn init() {
+    mark_online();
+}
+
+crate::initcall!(arch, "smp", init);
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test_case]
+    fn test_call_on_current_cpu() {
+        let ran = Arc::new(AtomicUsize::new(0));
+        let ran_clone = ran.clone();
+
+        call_on(current_cpu(), move || {
+            ran_clone.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap()
+        .unwrap_blocking();
+
+        assert_eq!(1, ran.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn test_call_all() {
+        let ran = Arc::new(AtomicUsize::new(0));
+        let ran_clone = ran.clone();
+
+        call_all(move || {
+            ran_clone.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap_blocking();
+
+        assert_eq!(online_cpus().len(), ran.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn test_call_on_offline_cpu() {
+        let online = online_cpus();
+        let offline = (0..).find(|cpu| !online.contains(cpu)).unwrap();
+
+        assert_eq!(Some(SmpCallError::CpuOffline), call_on(offline, || {}).err());
+    }
+}
@@ -1,6 +1,9 @@
 //! Data structures used by the scheduler to track processes and threads.
 
 use alloc::boxed::Box;
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::collections::btree_set::BTreeSet;
+use alloc::collections::vec_deque::VecDeque;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
@@ -10,16 +13,28 @@ use core::cell::{SyncUnsafeCell, UnsafeCell};
 use core::marker::PhantomData;
 use core::mem::{self, MaybeUninit};
 use core::pin::Pin;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
 use core::{fmt, ptr};
 
+use super::timer;
 use super::wait::{ThreadWaitList, ThreadWaitState};
 use crate::arch::interrupt::InterruptFrame;
-use crate::arch::page::AddressSpace;
+use crate::arch::page::{AddressSpace, PageFlags, PAGE_SIZE};
 use crate::arch::regs::SavedRegisters;
+use crate::arch::tls::TlsBlock;
+use crate::arch::VirtAddr;
+use crate::io::dev::recovery::RecoveryFrame;
+use crate::mem::region::{MapError, MemoryRegion, RegionMap, RegionUsage};
+use crate::mem::swap::AnonymousPages;
+use crate::mem::usermap::UserMap;
+use crate::mem::virt::VirtualAllocRegion;
+use crate::mem::PageBasedAlloc;
 use crate::sync::future::FutureWriter;
+use crate::sync::mutex::MutexLock;
 use crate::sync::uninterruptible::{InterruptDisabler, UninterruptibleSpinlock, UninterruptibleSpinlockGuard};
-use crate::sync::Future;
+use crate::sync::{lock_class, Future};
 use crate::util::{OneShotManualInit, PinWeak};
 
 static NEXT_PID: AtomicU64 = AtomicU64::new(0);
@@ -27,19 +42,84 @@ static KERNEL_PROCESS: OneShotManualInit<Pin<Arc<Process>>> = OneShotManualInit:
 
 /// The top-level list of processes on the machine
 #[non_exhaustive]
-pub struct ProcessList;
+pub struct ProcessList {
+    processes: BTreeMap<u64, Pin<Arc<Process>>>,
+    families: BTreeMap<u64, ProcessFamily>,
+}
+
+/// The exit status of a child process, as returned by [`Process::wait_for_child`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub pid: u64,
+    pub code: i32,
+}
+
+/// The writer for a future returned by [`Process::wait_for_child`].
+type ChildWaiter = FutureWriter<Option<ExitStatus>>;
+
+/// Tracks the parent/child relationships of a live process. These are kept in the global list of processes rather than in the processes
+/// themselves so that both ends of a relationship can be updated atomically without needing to lock multiple processes at once.
+struct ProcessFamily {
+    parent: Option<u64>,
+    children: BTreeSet<u64>,
+    exited_children: VecDeque<ExitStatus>,
+    child_waiters: VecDeque<ChildWaiter>,
+}
+
+impl ProcessFamily {
+    fn new(parent: Option<u64>) -> ProcessFamily {
+        ProcessFamily {
+            parent,
+            children: BTreeSet::new(),
+            exited_children: VecDeque::new(),
+            child_waiters: VecDeque::new(),
+        }
+    }
+}
 
 impl ProcessList {
     pub fn get(&self, pid: u64) -> Option<&Pin<Arc<Process>>> {
-        if pid == 0 {
-            Some(Process::kernel())
+        self.processes.get(&pid)
+    }
+
+    /// Removes the family tree entry of a process that has finished exiting, handing its children off to the kernel process and reporting
+    /// its exit status to its parent. If a call to [`Process::wait_for_child`] on the parent is waiting for this status, its writer is
+    /// returned so that it can be resolved once the list is no longer locked. The writers of any calls to [`Process::wait_for_child`] on
+    /// the exiting process itself are also returned, so that they can be resolved with `None`.
+    fn remove_family(&mut self, pid: u64, code: i32) -> (Option<(ChildWaiter, ExitStatus)>, VecDeque<ChildWaiter>) {
+        let family = self.families.remove(&pid).expect("exiting process has no family");
+
+        // TODO Once an init process exists, orphaned processes should be handed off to it instead
+        for &child in family.children.iter() {
+            self.families.get_mut(&child).unwrap().parent = Some(0);
+        }
+
+        let kernel_family = self.families.get_mut(&0).unwrap();
+
+        // Nothing waits for children of the kernel process, so outstanding waits on this process are not moved along with its children
+        kernel_family.children.extend(family.children);
+
+        let parent = self.families.get_mut(&family.parent.unwrap()).unwrap();
+        let status = ExitStatus { pid, code };
+
+        parent.children.remove(&pid);
+
+        let child_waiter = if let Some(waiter) = parent.child_waiters.pop_front() {
+            Some((waiter, status))
         } else {
+            // Nothing ever waits for children of the kernel process, so they are reaped immediately to avoid leaking their exit statuses
+            if family.parent != Some(0) {
+                parent.exited_children.push_back(status);
+            }
+
             None
-        }
+        };
+
+        (child_waiter, family.child_waiters)
     }
 
     pub fn iter(&self) -> ProcessListIterator {
-        ProcessListIterator(self, false)
+        ProcessListIterator(self.processes.values())
     }
 }
 
@@ -52,32 +132,59 @@ impl<'a> IntoIterator for &'a ProcessList {
     }
 }
 
-pub struct ProcessListIterator<'a>(#[allow(dead_code)] &'a ProcessList, bool);
+pub struct ProcessListIterator<'a>(btree_map::Values<'a, u64, Pin<Arc<Process>>>);
 
 impl<'a> Iterator for ProcessListIterator<'a> {
     type Item = &'a Pin<Arc<Process>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let ProcessListIterator(_, ref mut state) = *self;
-
-        if !*state {
-            *state = true;
-            Some(Process::kernel())
-        } else {
-            None
-        }
+        self.0.next()
     }
 }
 
-static PROCESS_LIST: UninterruptibleSpinlock<ProcessList> = UninterruptibleSpinlock::new(ProcessList {});
+static PROCESS_LIST: UninterruptibleSpinlock<ProcessList> = UninterruptibleSpinlock::with_class(
+    ProcessList {
+        processes: BTreeMap::new(),
+        families: BTreeMap::new(),
+    },
+    &lock_class::PROCESS_LIST,
+);
+
+/// Gets a snapshot of all live processes on the machine, ordered by PID.
+///
+/// Unlike [`Process::list`], this does not keep the global list of processes locked, so the returned processes can be freely locked by the
+/// caller. Processes may be created or finish exiting after the snapshot is taken.
+pub fn all_processes() -> Vec<Pin<Arc<Process>>> {
+    PROCESS_LIST.lock().iter().cloned().collect()
+}
+
+/// Like [`all_processes`], but returns [`None`] instead of waiting if the global list of processes is currently locked. This is meant for
+/// use by debugging code that may run while the lock is held, e.g. when panicking.
+pub fn try_all_processes() -> Option<Vec<Pin<Arc<Process>>>> {
+    Some(PROCESS_LIST.try_lock()?.iter().cloned().collect())
+}
+
+#[derive(Clone, Copy)]
+struct ReadyQueue {
+    head: *const Thread,
+    tail: *const Thread,
+}
+
+impl ReadyQueue {
+    const EMPTY: ReadyQueue = ReadyQueue {
+        head: ptr::null(),
+        tail: ptr::null(),
+    };
+}
 
 struct ProcessInternal {
     next_thread_id: u64,
     threads_head: Option<Pin<Arc<Thread>>>,
     threads_tail: *const Thread,
-    ready_head: *const Thread,
-    ready_tail: *const Thread,
-    addr_space: Option<AddressSpace>,
+    ready_queues: [ReadyQueue; ThreadPriority::COUNT],
+    user_map: Option<UserMap>,
+    exit_code: Option<i32>,
+    exit_writer: Option<FutureWriter<i32>>,
 }
 
 unsafe impl Send for ProcessInternal {}
@@ -91,6 +198,7 @@ impl !Unpin for ProcessInternal {}
 pub struct Process {
     pid: u64,
     cmd: Vec<String>,
+    cpu_time_ns: AtomicU64,
     internal: UninterruptibleSpinlock<ProcessInternal>,
 }
 
@@ -101,14 +209,19 @@ impl Process {
         Arc::pin(Process {
             pid,
             cmd,
-            internal: UninterruptibleSpinlock::new(ProcessInternal {
-                next_thread_id: 0,
-                threads_head: None,
-                threads_tail: ptr::null(),
-                ready_head: ptr::null(),
-                ready_tail: ptr::null(),
-                addr_space,
-            }),
+            cpu_time_ns: AtomicU64::new(0),
+            internal: UninterruptibleSpinlock::with_class(
+                ProcessInternal {
+                    next_thread_id: 0,
+                    threads_head: None,
+                    threads_tail: ptr::null(),
+                    ready_queues: [ReadyQueue::EMPTY; ThreadPriority::COUNT],
+                    user_map: addr_space.map(UserMap::new),
+                    exit_code: None,
+                    exit_writer: Some(FutureWriter::new()),
+                },
+                &lock_class::PROCESS,
+            ),
         })
     }
 
@@ -119,10 +232,21 @@ impl Process {
     /// This method must only be called once during startup from the bootstrap processor. This should be called early during the startup
     /// process, as calling [`Process::kernel`] is technically unsafe until this method is called.
     pub(super) unsafe fn init_kernel_process() {
-        KERNEL_PROCESS.set(Process::create_internal(0, vec![String::from("(kernel)")], None));
+        let kernel_process = KERNEL_PROCESS.set(Process::create_internal(0, vec![String::from("(kernel)")], None));
         NEXT_PID.store(1, Ordering::Relaxed);
 
-        let init_thread = Thread::create_internal(&mut Process::kernel().lock(), SavedRegisters::new());
+        let mut list = PROCESS_LIST.lock();
+
+        list.processes.insert(0, kernel_process.clone());
+        list.families.insert(0, ProcessFamily::new(None));
+        drop(list);
+
+        let init_thread = Thread::create_internal(
+            &mut Process::kernel().lock(),
+            Some(String::from("init")),
+            SavedRegisters::new(),
+            None,
+        );
         init_thread.lock().guard.state = ThreadState::Running;
         *CURRENT_THREAD.get() = Some(init_thread);
     }
@@ -142,6 +266,155 @@ impl Process {
         PROCESS_LIST.lock()
     }
 
+    /// Looks up the live process with the provided PID. Returns `None` if no such process exists or if it has already finished exiting.
+    pub fn by_pid(pid: u64) -> Option<Pin<Arc<Process>>> {
+        PROCESS_LIST.lock().get(pid).cloned()
+    }
+
+    /// Creates a new user-mode process with an empty address space and no threads and adds it to the global list of processes. The new
+    /// process is a child of the kernel process.
+    pub fn create(cmd: Vec<String>) -> Pin<Arc<Process>> {
+        Process::kernel().create_child(cmd)
+    }
+
+    /// Creates a new user-mode process with an empty address space and no threads as a child of this process and adds it to the global
+    /// list of processes.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if this process has already finished exiting.
+    pub fn create_child(&self, cmd: Vec<String>) -> Pin<Arc<Process>> {
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        let process = Process::create_internal(pid, cmd, Some(AddressSpace::new()));
+        let mut list = PROCESS_LIST.lock();
+
+        list.families
+            .get_mut(&self.pid)
+            .expect("Attempt to create child of a process that has exited")
+            .children
+            .insert(pid);
+        list.families.insert(pid, ProcessFamily::new(Some(self.pid)));
+        list.processes.insert(pid, process.clone());
+
+        process
+    }
+
+    /// Gets the PID of this process's parent, or `None` for the kernel process and for processes that have finished exiting.
+    ///
+    /// When a process exits, its children are adopted by the kernel process.
+    pub fn parent_pid(&self) -> Option<u64> {
+        PROCESS_LIST.lock().families.get(&self.pid).and_then(|f| f.parent)
+    }
+
+    /// Gets the PIDs of all children of this process that have not yet been reaped, including those that have exited but whose exit status
+    /// has not yet been retrieved using [`Process::wait_for_child`].
+    pub fn child_pids(&self) -> Vec<u64> {
+        PROCESS_LIST.lock().families.get(&self.pid).map_or_else(Vec::new, |f| {
+            f.children.iter().copied().chain(f.exited_children.iter().map(|s| s.pid)).collect()
+        })
+    }
+
+    /// Returns a future that will resolve to the exit status of the next child of this process to finish exiting. Each exited child is
+    /// reported exactly once, after which it has been fully reaped. If this process finishes exiting before any more of its children do,
+    /// the future resolves to `None` instead.
+    ///
+    /// Returns `None` if this process has no children whose exit status has not already been claimed by an earlier call, since the returned
+    /// future would then never resolve.
+    pub fn wait_for_child(&self) -> Option<Future<Option<ExitStatus>>> {
+        let mut list = PROCESS_LIST.lock();
+        let family = list.families.get_mut(&self.pid)?;
+
+        if let Some(status) = family.exited_children.pop_front() {
+            Some(Future::done(Some(status)))
+        } else if family.children.len() > family.child_waiters.len() {
+            let (future, writer) = Future::new();
+
+            family.child_waiters.push_back(writer);
+            Some(future)
+        } else {
+            None
+        }
+    }
+
+    /// Terminates this process with the provided exit code.
+    ///
+    /// All threads in this process are killed using [`Thread::kill`]. Once all of them have died, the process's address space is torn down,
+    /// the process is removed from the global list of processes, and the future returned by [`ProcessLock::join`] is resolved with the exit
+    /// code. If this process is already exiting, this method does nothing and the original exit code is kept.
+    ///
+    /// Once the process has finished exiting, its exit status is reported to its parent through [`Process::wait_for_child`] and any of its
+    /// own children are adopted by the kernel process.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method should not be called while any scheduler locks, such as thread and process locks, are held. Doing so may result in a
+    /// deadlock occurring.
+    ///
+    /// # Panics
+    ///
+    /// The kernel process cannot exit, so this method will panic if called on the kernel process.
+    pub fn exit(&self, code: i32) {
+        assert!(!self.is_kernel_process(), "Attempt to exit the kernel process");
+
+        let threads: Vec<_> = {
+            let mut process_lock = self.lock();
+
+            if process_lock.guard.exit_code.is_some() {
+                return;
+            }
+
+            process_lock.guard.exit_code = Some(code);
+            process_lock.threads().collect()
+        };
+
+        // TODO Threads that are currently executing in user mode will never check for the kill request. They should be killed when they
+        //      next enter the kernel.
+        for thread in threads {
+            thread.kill();
+        }
+
+        self.try_finish_exit();
+    }
+
+    /// Finishes tearing down this process if it is exiting and none of its threads remain alive. This is called by the scheduler whenever a
+    /// thread's resources are reaped.
+    pub(super) fn try_finish_exit(&self) {
+        let mut list = PROCESS_LIST.lock();
+        let mut process_lock = self.lock();
+
+        if process_lock.guard.threads_head.is_some() {
+            return;
+        }
+
+        let (code, exit_writer) = match (process_lock.guard.exit_code, process_lock.guard.exit_writer.take()) {
+            (Some(code), Some(exit_writer)) => (code, exit_writer),
+            (_, exit_writer) => {
+                process_lock.guard.exit_writer = exit_writer;
+                return;
+            },
+        };
+        let user_map = process_lock.guard.user_map.take();
+
+        drop(process_lock);
+
+        let process = list.processes.remove(&self.pid);
+        let (child_waiter, orphaned_waiters) = list.remove_family(self.pid, code);
+
+        drop(list);
+        drop(user_map);
+        drop(process);
+
+        exit_writer.finish(code);
+
+        if let Some((child_waiter, status)) = child_waiter {
+            child_waiter.finish(Some(status));
+        }
+
+        for waiter in orphaned_waiters {
+            waiter.finish(None);
+        }
+    }
+
     /// Gets this process's PID.
     pub fn pid(&self) -> u64 {
         self.pid
@@ -152,6 +425,12 @@ impl Process {
         &self.cmd
     }
 
+    /// Gets the total amount of time that threads in this process have spent running, including threads that have since died. Time spent
+    /// by threads that are currently running is only included once they are next switched away from.
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_nanos(self.cpu_time_ns.load(Ordering::Relaxed))
+    }
+
     /// Checks whether this process is the kernel process.
     pub fn is_kernel_process(&self) -> bool {
         self.pid == 0
@@ -164,7 +443,7 @@ impl Process {
     /// In general, the only other scheduler lock that is safe to hold while calling this method is the lock on the list of processes.
     ///
     /// This method must not be called while any other processes or threads are locked by the current core. Doing so could result in a
-    /// deadlock occurring.
+    /// deadlock occurring. These rules are encoded by [`lock_class::PROCESS`] and are checked when debug assertions are enabled.
     pub fn lock(&self) -> ProcessLock {
         ProcessLock {
             guard: self.internal.lock(),
@@ -172,6 +451,15 @@ impl Process {
         }
     }
 
+    /// Locks this process's mutable state if it is not already locked, returning [`None`] instead of waiting otherwise. This is meant for
+    /// use by debugging code that may run while the lock is held, e.g. when panicking.
+    pub fn try_lock(&self) -> Option<ProcessLock> {
+        Some(ProcessLock {
+            guard: self.internal.try_lock()?,
+            process: self,
+        })
+    }
+
     fn as_arc(&self) -> Pin<Arc<Process>> {
         // SAFETY: All processes must be in an Arc. This is true since the only way to create a process is via Process::create_internal,
         //         which returns a Pin<Arc<Process>>. Since processes created in this way must be in an Arc and cannot be moved out due to
@@ -200,24 +488,50 @@ impl<'a> ProcessLock<'a> {
         ProcessThreadIterator(self.guard.threads_head.clone(), PhantomData)
     }
 
-    fn create_kernel_thread_internal(&mut self, f: extern "C" fn(*mut u8) -> !, arg: *mut u8, stack_size: usize) -> Pin<Arc<Thread>> {
-        let stack = crate::mem::early::alloc(stack_size, 16); // TODO Allocate pages instead. Place guard page.
-        Thread::create_internal(self, SavedRegisters::new_kernel_thread(f, arg, unsafe { stack.add(stack_size) }))
+    fn create_kernel_thread_internal(
+        &mut self,
+        name: String,
+        f: extern "C" fn(*mut u8) -> !,
+        arg: *mut u8,
+        stack_size: usize,
+    ) -> Pin<Arc<Thread>> {
+        let stack = KernelStack::alloc(stack_size);
+        let tls = TlsBlock::alloc();
+        let thread = Thread::create_internal(
+            self,
+            Some(name),
+            SavedRegisters::new_kernel_thread(f, arg, stack.top()),
+            Some(stack.guard_page()),
+        );
+
+        let mut thread_lock = thread.lock();
+
+        thread_lock.guard.kernel_stack = Some(stack);
+        thread_lock.guard.tls = Some(tls);
+        drop(thread_lock);
+
+        thread
     }
 
     /// Creates a new kernel-mode thread in this process that executes the provided function. The stack of the new thread will be at least
-    /// `stack_size` bytes large.
+    /// `stack_size` bytes large. The provided name is used to identify the thread in debug output and does not need to be unique.
     ///
     /// # Panics
     ///
     /// This method can only be used on the kernel process. For safety reasons, creating kernel-mode threads in user-space processes is not
     /// allowed and attempting to do so will cause a panic.
-    pub fn create_kernel_thread<F: FnOnce() + Send + 'static>(&mut self, f: F, stack_size: usize) -> Pin<Arc<Thread>> {
-        unsafe { self.create_kernel_thread_unchecked(f, stack_size) }
+    pub fn create_kernel_thread<F: FnOnce() + Send + 'static>(
+        &mut self,
+        name: impl Into<String>,
+        f: F,
+        stack_size: usize,
+    ) -> Pin<Arc<Thread>> {
+        unsafe { self.create_kernel_thread_unchecked(name, f, stack_size) }
     }
 
     /// Creates a new kernel-mode thread in this process that executes the provided function without checking the lifetime of the provided
-    /// closure. The stack of the new thread will be at least `stack_size` bytes large.
+    /// closure. The stack of the new thread will be at least `stack_size` bytes large. The provided name is used to identify the thread in
+    /// debug output and does not need to be unique.
     ///
     /// # Safety
     ///
@@ -228,7 +542,12 @@ impl<'a> ProcessLock<'a> {
     ///
     /// This method can only be used on the kernel process. For safety reasons, creating kernel-mode threads in user-space processes is not
     /// allowed and attempting to do so will cause a panic.
-    pub unsafe fn create_kernel_thread_unchecked<F: FnOnce() + Send>(&mut self, f: F, stack_size: usize) -> Pin<Arc<Thread>> {
+    pub unsafe fn create_kernel_thread_unchecked<F: FnOnce() + Send>(
+        &mut self,
+        name: impl Into<String>,
+        f: F,
+        stack_size: usize,
+    ) -> Pin<Arc<Thread>> {
         extern "C" fn run<F: FnOnce()>(ptr: *mut u8) -> ! {
             unsafe {
                 let f = *Box::from_raw(ptr as *mut F);
@@ -239,7 +558,7 @@ impl<'a> ProcessLock<'a> {
         }
 
         assert!(self.process.is_kernel_process());
-        self.create_kernel_thread_internal(run::<F>, Box::into_raw(Box::new(f)) as *mut u8, stack_size)
+        self.create_kernel_thread_internal(name.into(), run::<F>, Box::into_raw(Box::new(f)) as *mut u8, stack_size)
     }
 
     /// Creates a new user-mode thread in this process that executes a function at the provided user-mode address. The stack of the new
@@ -247,14 +566,24 @@ impl<'a> ProcessLock<'a> {
     ///
     /// # Panics
     ///
-    /// This method cannot be used on the kernel process and attempting to do so will cause a panic.
+    /// This method cannot be used on the kernel process or on a process that has started exiting, and attempting to do so will cause a
+    /// panic.
     pub fn create_user_thread(&mut self, f: u64, arg: u64, stack_size: usize) -> Pin<Arc<Thread>> {
         assert!(!self.process.is_kernel_process());
+        assert!(self.guard.exit_code.is_none(), "Attempt to create thread in exiting process");
+
+        let stack = match self.guard.user_map.as_mut().unwrap().map_stack(stack_size) {
+            Ok(stack) => stack.range(),
+            Err(err) => panic!("Failed to allocate user stack of {} bytes: {}", stack_size, err),
+        };
+        let thread = Thread::create_internal(self, None, SavedRegisters::new_user_thread(f, arg, stack.end().as_u64()), None);
 
-        // TODO Actually allocate a user-mode stack
-        let _ = stack_size;
+        // SAFETY: Conceptually, the process owns its threads' ThreadProcessInternal data
+        unsafe {
+            (*thread.process_internal.get()).user_stack = Some(stack.start());
+        }
 
-        Thread::create_internal(self, SavedRegisters::new_user_thread(f, arg, 0))
+        thread
     }
 
     unsafe fn remove_thread(&mut self, thread: &Pin<Arc<Thread>>) {
@@ -262,6 +591,12 @@ impl<'a> ProcessLock<'a> {
 
         let process_internal = &mut *thread.process_internal.get();
 
+        if let Some(user_stack) = process_internal.user_stack.take() {
+            if let Some(ref mut user_map) = self.guard.user_map {
+                user_map.unmap(user_stack);
+            }
+        }
+
         debug_assert_eq!(ptr::null(), process_internal.prev_ready);
         debug_assert_eq!(ptr::null(), process_internal.next_ready);
 
@@ -293,35 +628,82 @@ impl<'a> ProcessLock<'a> {
         process_internal.prev = ptr::null();
     }
 
-    /// Attempts to dequeue a thread from this process's queue of threads that are in the ready state. If this process does not have any
-    /// threads in the ready state, returns [`None`].
+    /// Attempts to dequeue a thread from this process's queues of threads that are in the ready state. Threads are dequeued from the
+    /// highest priority queue that is not empty, in the order in which they became ready. If this process does not have any threads in the
+    /// ready state, returns [`None`].
     pub(super) fn dequeue_ready_thread(&mut self) -> Option<Pin<Arc<Thread>>> {
-        if !self.guard.ready_head.is_null() {
-            // SAFETY: Since we have locked the process owning these threads, we have also conceptually locked their ThreadProcessInternal
-            //         data. So long as the ready list is in a valid state, dequeueing a thread from it is perfectly safe.
-            unsafe {
-                let thread = &*self.guard.ready_head;
-                let process_internal = &mut *thread.process_internal.get();
+        let queue = self.guard.ready_queues.iter_mut().rev().find(|q| !q.head.is_null())?;
+
+        // SAFETY: Since we have locked the process owning these threads, we have also conceptually locked their ThreadProcessInternal
+        //         data. So long as the ready list is in a valid state, dequeueing a thread from it is perfectly safe.
+        unsafe {
+            let thread = &*queue.head;
+            let process_internal = &mut *thread.process_internal.get();
+
+            queue.head = if !process_internal.next_ready.is_null() {
+                (*(*process_internal.next_ready).process_internal.get()).prev_ready = ptr::null();
+                process_internal.next_ready
+            } else {
+                queue.tail = ptr::null();
+                ptr::null()
+            };
 
-                self.guard.ready_head = if !process_internal.next_ready.is_null() {
-                    (*(*process_internal.next_ready).process_internal.get()).prev_ready = ptr::null();
-                    process_internal.next_ready
-                } else {
-                    self.guard.ready_tail = ptr::null();
-                    ptr::null()
-                };
+            process_internal.prev_ready = ptr::null();
+            process_internal.next_ready = ptr::null();
 
-                process_internal.prev_ready = ptr::null();
-                process_internal.next_ready = ptr::null();
+            Some(thread.as_arc())
+        }
+    }
 
-                Some(thread.as_arc())
+    /// Removes the provided thread from this process's queues of threads that are in the ready state, returning `false` if the thread does
+    /// not belong to this process or is not currently on a ready queue.
+    pub(super) fn remove_ready_thread(&mut self, thread: &Thread) -> bool {
+        if !ptr::eq(self.process, thread.process.as_ptr()) || !matches!(thread.lock().guard.state, ThreadState::Ready) {
+            return false;
+        }
+
+        // SAFETY: Since we have locked the process owning this thread, we have also conceptually locked the ThreadProcessInternal data of
+        //         all of its threads. So long as the ready list is in a valid state, removing a thread from it is perfectly safe.
+        unsafe {
+            let process_internal = &mut *thread.process_internal.get();
+
+            // The thread's priority may have changed since it was enqueued, so the queue it was actually placed on must be used here
+            let queue = &mut self.guard.ready_queues[process_internal.ready_priority.index()];
+
+            if process_internal.prev_ready.is_null() && !ptr::eq(queue.head, thread) {
+                return false;
             }
-        } else {
-            None
+
+            if !process_internal.prev_ready.is_null() {
+                (*(*process_internal.prev_ready).process_internal.get()).next_ready = process_internal.next_ready;
+            } else {
+                queue.head = process_internal.next_ready;
+            }
+
+            if !process_internal.next_ready.is_null() {
+                (*(*process_internal.next_ready).process_internal.get()).prev_ready = process_internal.prev_ready;
+            } else {
+                queue.tail = process_internal.prev_ready;
+            }
+
+            process_internal.prev_ready = ptr::null();
+            process_internal.next_ready = ptr::null();
         }
+
+        true
+    }
+
+    /// Gets the priority of the highest priority thread in this process that is currently in the ready state, or [`None`] if this process
+    /// does not have any threads in the ready state.
+    pub(super) fn highest_ready_priority(&self) -> Option<ThreadPriority> {
+        ThreadPriority::ALL
+            .into_iter()
+            .rev()
+            .find(|&priority| !self.guard.ready_queues[priority.index()].head.is_null())
     }
 
-    /// Enqueues the provided thread on this process's queue of threads that are in the ready state.
+    /// Enqueues the provided thread on this process's queue of threads that are in the ready state. The thread is placed at the back of the
+    /// queue corresponding to its current priority.
     ///
     /// # Safety
     ///
@@ -329,30 +711,90 @@ impl<'a> ProcessLock<'a> {
     /// ready threads.
     pub(super) unsafe fn enqueue_ready_thread(&mut self, thread_lock: ThreadLock) {
         let thread = thread_lock.thread;
+        let priority = thread_lock.guard.effective_priority();
+        let queue = &mut self.guard.ready_queues[priority.index()];
 
         debug_assert_eq!(self.process as *const _, thread.process.as_ptr());
         debug_assert!(matches!(thread_lock.guard.state, ThreadState::Ready));
         debug_assert!((*thread_lock.thread.process_internal.get()).next_ready.is_null());
-        debug_assert!(!ptr::eq(self.guard.ready_tail, thread));
+        debug_assert!(!ptr::eq(queue.tail, thread));
 
         drop(thread_lock);
 
         let process_internal = &mut *thread.process_internal.get();
 
+        process_internal.ready_priority = priority;
         process_internal.next_ready = ptr::null();
-        if !self.guard.ready_tail.is_null() {
-            process_internal.prev_ready = self.guard.ready_tail;
-            (*(*self.guard.ready_tail).process_internal.get()).next_ready = thread as *const _;
+        if !queue.tail.is_null() {
+            process_internal.prev_ready = queue.tail;
+            (*(*queue.tail).process_internal.get()).next_ready = thread as *const _;
         } else {
             process_internal.prev_ready = ptr::null();
-            self.guard.ready_head = thread as *const _;
+            queue.head = thread as *const _;
         };
-        self.guard.ready_tail = thread as *const _;
+        queue.tail = thread as *const _;
     }
 
-    /// Gets a mutable reference to the address space used by this process. For the kernel process, `None` is returned.
+    /// Gets a mutable reference to the address space used by this process. For the kernel process and for processes that have finished
+    /// exiting, `None` is returned.
     pub fn addr_space(&mut self) -> Option<&mut AddressSpace> {
-        self.guard.addr_space.as_mut()
+        self.guard.user_map.as_mut().map(|user_map| user_map.addr_space())
+    }
+
+    /// Gets mutable references to the address space used by this process along with the list of anonymous pages mapped into it that are
+    /// eligible for swapping. For the kernel process and for processes that have finished exiting, `None` is returned.
+    pub fn memory(&mut self) -> Option<(&mut AddressSpace, &mut AnonymousPages)> {
+        self.guard.user_map.as_mut().map(|user_map| user_map.memory())
+    }
+
+    /// Gets the map of the memory mapped into this process's address space, which can be used to map and unmap regions. For the kernel
+    /// process and for processes that have finished exiting, `None` is returned.
+    pub fn user_map(&mut self) -> Option<&mut UserMap> {
+        self.guard.user_map.as_mut()
+    }
+
+    /// Gets the map of regions that have been mapped into this process's address space.
+    pub fn regions(&self) -> &RegionMap {
+        static NO_REGIONS: RegionMap = RegionMap::new();
+
+        self.guard.user_map.as_ref().map_or(&NO_REGIONS, |user_map| user_map.regions())
+    }
+
+    /// Gets a description of each region mapped into this process's address space along with how many of its pages are currently resident
+    /// or swapped out.
+    pub fn region_usage(&mut self) -> Vec<(MemoryRegion, RegionUsage)> {
+        match self.guard.user_map {
+            Some(ref mut user_map) => user_map.region_usage(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Maps a new region of zero-filled anonymous memory into this process's address space, starting at the provided page-aligned address.
+    /// The pages of the region are eligible for being swapped out.
+    pub fn map_anonymous(&mut self, start: VirtAddr, num_pages: usize, flags: PageFlags, name: &'static str) -> Result<(), MapError> {
+        let user_map = self.guard.user_map.as_mut().ok_or(MapError::NoAddressSpace)?;
+
+        user_map.map_anonymous(Some(start), num_pages, flags, name).map(|_| ())
+    }
+
+    /// Unmaps the region starting at the provided address from this process's address space and returns its description. Frames backing
+    /// anonymous regions are freed, as are any swap slots holding their swapped out pages.
+    pub fn unmap_region(&mut self, start: VirtAddr) -> Option<MemoryRegion> {
+        self.guard.user_map.as_mut()?.unmap(start)
+    }
+
+    /// Gets the exit code passed to [`Process::exit`] if this process has started exiting.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.guard.exit_code
+    }
+
+    /// Returns a future that will resolve to this process's exit code once it has finished exiting.
+    pub fn join(&self) -> Future<i32> {
+        match (self.guard.exit_writer.as_ref(), self.guard.exit_code) {
+            (Some(exit_writer), _) => exit_writer.as_future(),
+            (None, Some(code)) => Future::done(code),
+            (None, None) => unreachable!(),
+        }
     }
 
     /// Gets a reference to the Process structure that this guard has locked.
@@ -392,26 +834,148 @@ pub enum ThreadState {
     Dead,
 }
 
+/// An error indicating that a blocking operation was interrupted because another thread requested that the current thread be killed using
+/// [`Thread::kill`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadKilled;
+
+impl fmt::Display for ThreadKilled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread was killed")
+    }
+}
+
+/// Represents the scheduling priority of a thread.
+///
+/// Whenever a context switch occurs, the scheduler will always pick a ready thread with the highest available priority. Threads of equal
+/// priority are scheduled in a round-robin fashion. When an interrupt causes a thread with a higher priority than the currently running
+/// thread to become ready, the running thread is preempted at the end of the interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThreadPriority {
+    /// Background work that should only run when no other threads are ready to run.
+    Background,
+    /// The default priority for newly created threads.
+    Normal,
+    /// Latency-sensitive work, e.g. interrupt bottom halves or TTY processing.
+    High,
+}
+
+impl ThreadPriority {
+    /// The number of distinct thread priorities.
+    pub const COUNT: usize = 3;
+
+    /// All thread priorities, ordered from lowest to highest.
+    pub const ALL: [ThreadPriority; ThreadPriority::COUNT] = [ThreadPriority::Background, ThreadPriority::Normal, ThreadPriority::High];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for ThreadPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ThreadPriority::Background => "background",
+            ThreadPriority::Normal => "normal",
+            ThreadPriority::High => "high",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// A kernel-mode stack allocated for a thread.
+///
+/// Kernel stacks are allocated from whole pages and are always preceded by a single unmapped guard page, so that overflowing the stack
+/// results in a page fault rather than silently corrupting whatever memory happens to be below it.
+pub(super) struct KernelStack {
+    base: *mut u8,
+    size: usize,
+}
+
+impl KernelStack {
+    fn alloc(size: usize) -> KernelStack {
+        let size = size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+        let base = match PageBasedAlloc::allocate_guarded(size / PAGE_SIZE) {
+            Ok(base) => base,
+            Err(_) => panic!("Failed to allocate kernel stack of {} bytes", size),
+        };
+
+        KernelStack {
+            base: base.as_mut_ptr(),
+            size,
+        }
+    }
+
+    fn guard_page(&self) -> VirtualAllocRegion {
+        VirtualAllocRegion::new(VirtAddr::from_ptr(self.base.wrapping_sub(PAGE_SIZE)), VirtAddr::from_ptr(self.base))
+    }
+
+    fn top(&self) -> *mut u8 {
+        // SAFETY: The stack was allocated with the given size, so its top is one byte past the end of the same allocation.
+        unsafe { self.base.add(self.size) }
+    }
+
+    /// Frees the memory backing this stack.
+    ///
+    /// # Safety
+    ///
+    /// The thread that was using this stack must never resume execution and must not be executing on any CPU core, including in the
+    /// context of an interrupt handler.
+    pub(super) unsafe fn free(self) {
+        PageBasedAlloc::deallocate_guarded(VirtAddr::from_ptr(self.base), self.size / PAGE_SIZE);
+    }
+}
+
 struct ThreadInternal {
     state: ThreadState,
+    priority: ThreadPriority,
+    inherited_priority: Option<ThreadPriority>,
+    blocked_on: Option<NonNull<MutexLock>>,
     regs: SavedRegisters,
     join_writer: Option<FutureWriter<()>>,
     err_on_block: bool,
+    kill_requested: bool,
+    kernel_stack: Option<KernelStack>,
+    tls: Option<TlsBlock>,
+    cpu_stats: ThreadCpuStats,
+    running_since: Option<Duration>,
 }
 
 unsafe impl Send for ThreadInternal {}
 
+impl ThreadInternal {
+    fn effective_priority(&self) -> ThreadPriority {
+        self.inherited_priority
+            .map_or(self.priority, |priority| priority.max(self.priority))
+    }
+}
+
+/// Statistics about how a thread has made use of the CPU since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThreadCpuStats {
+    /// The total amount of time that the thread has spent running.
+    pub cpu_time: Duration,
+    /// The number of times that the thread has been switched to.
+    pub context_switches: u64,
+    /// The number of times that the thread has been woken up after being suspended.
+    pub wakeups: u64,
+}
+
 struct ThreadProcessInternal {
     prev: *const Thread,
     next: Option<Pin<Arc<Thread>>>,
     prev_ready: *const Thread,
     next_ready: *const Thread,
+    ready_priority: ThreadPriority,
+    user_stack: Option<VirtAddr>,
 }
 
 unsafe impl Send for ThreadProcessInternal {}
 
-#[thread_local]
-pub(super) static CURRENT_THREAD: UnsafeCell<Option<Pin<Arc<Thread>>>> = UnsafeCell::new(None);
+crate::cpu_local! {
+    pub(super) static CURRENT_THREAD: UnsafeCell<Option<Pin<Arc<Thread>>>> = UnsafeCell::new(None);
+}
 
 /// A structure containing state information for a thread.
 ///
@@ -421,31 +985,57 @@ pub(super) static CURRENT_THREAD: UnsafeCell<Option<Pin<Arc<Thread>>>> = UnsafeC
 pub struct Thread {
     process: PinWeak<Process>,
     thread_id: u64,
+    name: Option<String>,
+    stack_guard: Option<VirtualAllocRegion>,
     internal: UninterruptibleSpinlock<ThreadInternal>,
     process_internal: SyncUnsafeCell<ThreadProcessInternal>,
     wait_state: SyncUnsafeCell<ThreadWaitState>,
+    held_mutexes: AtomicUsize,
+    recovery_frame: AtomicPtr<RecoveryFrame>,
 }
 
 impl !Unpin for Thread {}
 
 impl Thread {
-    fn create_internal(process_lock: &mut ProcessLock, regs: SavedRegisters) -> Pin<Arc<Thread>> {
+    fn create_internal(
+        process_lock: &mut ProcessLock,
+        name: Option<String>,
+        regs: SavedRegisters,
+        stack_guard: Option<VirtualAllocRegion>,
+    ) -> Pin<Arc<Thread>> {
         let thread = Arc::pin(Thread {
             process: PinWeak::downgrade(&process_lock.process.as_arc()),
             thread_id: process_lock.guard.next_thread_id,
-            internal: UninterruptibleSpinlock::new(ThreadInternal {
-                state: ThreadState::Suspended,
-                regs,
-                join_writer: Some(FutureWriter::new()),
-                err_on_block: false,
-            }),
+            name,
+            stack_guard,
+            internal: UninterruptibleSpinlock::with_class(
+                ThreadInternal {
+                    state: ThreadState::Suspended,
+                    priority: ThreadPriority::Normal,
+                    inherited_priority: None,
+                    blocked_on: None,
+                    regs,
+                    join_writer: Some(FutureWriter::new()),
+                    err_on_block: false,
+                    kill_requested: false,
+                    kernel_stack: None,
+                    tls: None,
+                    cpu_stats: ThreadCpuStats::default(),
+                    running_since: None,
+                },
+                &lock_class::THREAD,
+            ),
             process_internal: SyncUnsafeCell::new(ThreadProcessInternal {
                 prev: process_lock.guard.threads_tail,
                 next: None,
                 prev_ready: ptr::null(),
                 next_ready: ptr::null(),
+                ready_priority: ThreadPriority::Normal,
+                user_stack: None,
             }),
             wait_state: SyncUnsafeCell::new(ThreadWaitState::new()),
+            held_mutexes: AtomicUsize::new(0),
+            recovery_frame: AtomicPtr::new(ptr::null_mut()),
         });
 
         process_lock.guard.next_thread_id += 1;
@@ -467,7 +1057,7 @@ impl Thread {
     /// Gets the thread that was executing on the current core before an interrupt occurred. If the idle thread was executing, this method
     /// will return [`None`]. If an interrupt is not currently being handled, then this method will return the currently executing thread.
     pub fn current_interrupted() -> Option<Pin<Arc<Thread>>> {
-        // SAFETY: CURRENT_THREAD is thread-local and no references to it ever escape this module
+        // SAFETY: CURRENT_THREAD is CPU-local and no references to it ever escape this module
         unsafe { (*CURRENT_THREAD.get()).clone() }
     }
 
@@ -540,6 +1130,7 @@ impl Thread {
             panic!("Attempt to call Thread::suspend_thread in a non-blocking context");
         }
 
+        // TODO Go through the architecture API here, so that the simulated architecture can switch threads without a software interrupt
         let thread_lock = MaybeUninit::new(thread_lock);
         asm!(
             "int 0x30",
@@ -566,8 +1157,42 @@ impl Thread {
         }
     }
 
+    /// Suspends the currently executing thread and performs a directed context switch to the provided thread, leaving the current thread in
+    /// the ready state. If the provided thread is not in the ready state by the time the context switch occurs, this behaves the same as
+    /// [`Thread::yield_current`].
+    ///
+    /// The directed switch ignores the priorities of other ready threads, so this should only be used to hand off execution to a thread
+    /// that is expected to immediately act on something the current thread has just done, e.g. a thread waiting on a future that was just
+    /// resolved or for a reply to a request that was just made.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if any [`InterruptDisabler`](InterruptDisabler) values currently exist on this thread. Context switching
+    /// while an uninterruptible lock guard is held could result in a deadlock due to the new thread trying to acquire a lock that was held
+    /// prior to a context switch.
+    pub fn yield_to(target: &Thread) {
+        let thread = Thread::current();
+        let mut thread = thread.lock();
+
+        assert!(matches!(*thread.state(), ThreadState::Running));
+        super::YIELD_TARGET.set(Some(target.as_arc()));
+        unsafe {
+            *thread.state_mut() = ThreadState::Ready;
+            Thread::suspend_current(thread);
+        }
+    }
+
+    /// Blocks the currently executing thread for at least the provided amount of time.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called from an interrupt handler or from a context in which the current thread cannot block.
+    pub fn sleep(duration: Duration) {
+        super::sleep_until(super::timer::now().saturating_add(duration));
+    }
+
     /// Kills the current thread and ends execution immediately. All kernel-mode stack memory and other scheduler managed resources used by
-    /// this thread will be freed immediately.
+    /// this thread will be freed shortly afterwards by the scheduler's reaper thread.
     ///
     /// # Safety
     ///
@@ -591,12 +1216,111 @@ impl Thread {
 
         drop(process_lock);
 
-        thread_lock.guard.join_writer.take().unwrap().finish(());
-
         Thread::suspend_current(thread_lock);
         panic!("Dead thread was resurrected");
     }
 
+    /// Requests that this thread be terminated.
+    ///
+    /// Since a thread executing kernel code cannot be safely stopped at an arbitrary point, termination is cooperative: a flag is set on the
+    /// thread which it is expected to check at its next killable block point (see [`Thread::check_killed`] and
+    /// [`ThreadWaitList::wait_killable`](super::wait::ThreadWaitList::wait_killable)), after which it should clean up and return from its
+    /// entry point. If the thread is currently waiting on a [`ThreadWaitList`](super::wait::ThreadWaitList) in a killable manner, it is
+    /// removed from the wait list and woken up so that it can notice the request. Threads in non-killable waits will only notice the request
+    /// once they are woken up normally.
+    ///
+    /// Threads that are in the [`ThreadState::Suspended`] state are not executing anything and have no way of observing the request, so they
+    /// are instead torn down immediately. In this case, no destructors are run for any objects on the thread's stack.
+    ///
+    /// Calling this method on the currently running thread simply sets the flag. A thread that wants to terminate itself immediately should
+    /// instead return from its entry point.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method should not be called while any scheduler locks, such as thread and process locks, are held. Doing so may result in a
+    /// deadlock occurring.
+    pub fn kill(&self) {
+        // TODO Once other CPU cores can run threads, a wait list could be freed by another core between reading it from the thread's state
+        //      and cancelling the wait below.
+        let _interrupts_disabled = InterruptDisabler::new();
+        let mut thread_lock = self.lock();
+
+        thread_lock.guard.kill_requested = true;
+
+        loop {
+            match *thread_lock.state() {
+                ThreadState::Waiting(list) => {
+                    drop(thread_lock);
+
+                    // SAFETY: A thread cannot stop waiting on a wait list without that wait list waking it, which can't happen while
+                    //         interrupts are disabled. Since a non-empty wait list cannot be dropped, the list must still be alive at this
+                    //         point. If the thread was requeued onto another wait list in the meantime, the wait is cancelled there instead.
+                    if unsafe { (*list).cancel_wait(self) } {
+                        break;
+                    }
+
+                    thread_lock = self.lock();
+                },
+                ThreadState::Suspended => {
+                    drop(thread_lock);
+                    self.force_kill_suspended();
+                    break;
+                },
+                ThreadState::Ready | ThreadState::Running | ThreadState::Dead => break,
+            }
+        }
+    }
+
+    fn force_kill_suspended(&self) {
+        let thread = self.as_arc();
+        let process = match thread.process.upgrade() {
+            Some(process) => process,
+            None => {
+                return;
+            },
+        };
+
+        let mut process_lock = process.lock();
+        let mut thread_lock = thread.lock();
+
+        // The thread may have been woken up while its lock was released, in which case it will notice the kill request on its own
+        if !matches!(*thread_lock.state(), ThreadState::Suspended) {
+            return;
+        }
+
+        // SAFETY: A suspended thread is not running on any CPU core and is not on any ready queue or wait list, so nothing else can be
+        //         referring to its saved state or its stack.
+        unsafe {
+            *thread_lock.state_mut() = ThreadState::Dead;
+            process_lock.remove_thread(&thread);
+        }
+
+        let join_writer = thread_lock.take_join_writer();
+
+        drop(thread_lock);
+        drop(process_lock);
+
+        if let Some(join_writer) = join_writer {
+            join_writer.finish(());
+        }
+
+        super::reaper::enqueue_dead_thread(thread);
+    }
+
+    /// Checks whether another thread has requested that the currently running thread be killed using [`Thread::kill`]. If so, returns
+    /// [`ThreadKilled`], which should be propagated up to the thread's entry point so that it can terminate.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if called from an asynchronous interrupt handler.
+    pub fn check_killed() -> Result<(), ThreadKilled> {
+        if Thread::current().lock().is_kill_requested() {
+            Err(ThreadKilled)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Gets a reference to the process in which this thread is running.
     ///
     /// The returned weak reference will always be present so long as this thread is not dead. In the event that this thread is dead, the
@@ -610,15 +1334,33 @@ impl Thread {
         self.thread_id
     }
 
+    /// Gets the name that was given to this thread when it was created, if any. Only kernel-mode threads are currently given names.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Checks whether the provided address falls within the unmapped guard page placed below this thread's kernel-mode stack. An access to
+    /// such an address indicates that the thread has overflowed its stack.
+    ///
+    /// This does not require locking the thread, so it is safe to call from exception handlers that may have interrupted code holding the
+    /// thread's lock.
+    pub fn is_stack_guard_page(&self, addr: VirtAddr) -> bool {
+        match self.stack_guard {
+            Some(guard) => guard.start() <= addr && addr < guard.end(),
+            None => false,
+        }
+    }
+
     /// Locks this thread's mutable state.
     ///
     /// # Lock Ordering
     ///
-    /// This method may be called while holding the lock of the process in which it exists or the lock of a wait queue on which this thread
-    /// is waiting, as well as any locks that were held when such a lock was acquired.
+    /// This method may be called while holding the lock of the process in which it exists, as well as any locks that were held when such a
+    /// lock was acquired. The lock of a wait queue on which this thread is waiting may be acquired while holding the returned lock, but
+    /// not the other way around.
     ///
     /// This method must not be called while any other threads are locked by the current core. Doing so could result in a deadlock
-    /// occurring.
+    /// occurring. These rules are encoded by [`lock_class::THREAD`] and are checked when debug assertions are enabled.
     pub fn lock(&self) -> ThreadLock {
         ThreadLock {
             guard: self.internal.lock(),
@@ -626,6 +1368,15 @@ impl Thread {
         }
     }
 
+    /// Locks this thread's mutable state if it is not already locked, returning [`None`] instead of waiting otherwise. This is meant for
+    /// use by debugging code that may run while the lock is held, e.g. when panicking.
+    pub fn try_lock(&self) -> Option<ThreadLock> {
+        Some(ThreadLock {
+            guard: self.internal.try_lock()?,
+            thread: self,
+        })
+    }
+
     /// Gets a unique identifiable name for this thread for use in kernel debug messages. This name is meant to be human-readable and is not
     /// guaranteed to remain exactly the same throughout the thread's lifecycle.
     pub fn debug_name(&self) -> impl fmt::Display + '_ {
@@ -636,6 +1387,89 @@ impl Thread {
         self.wait_state.get()
     }
 
+    /// Raises the priority that this thread has inherited from threads waiting on a [`Mutex`](crate::sync::mutex::Mutex) that it holds
+    /// to the provided priority. Returns `false` without doing anything if this thread is already running at that priority or higher.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method should not be called while any scheduler locks, such as thread and process locks, are held. Doing so may result in a
+    /// deadlock occurring.
+    pub(crate) fn inherit_priority(&self, priority: ThreadPriority) -> bool {
+        if self.lock().priority() >= priority {
+            return false;
+        }
+
+        self.set_inherited_priority(Some(priority));
+        true
+    }
+
+    /// Drops any priority that this thread has inherited, returning it to its base priority.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method has the same lock ordering requirements as [`Thread::inherit_priority`].
+    pub(crate) fn clear_inherited_priority(&self) {
+        if self.lock().guard.inherited_priority.is_none() {
+            return;
+        }
+
+        self.set_inherited_priority(None);
+    }
+
+    fn set_inherited_priority(&self, priority: Option<ThreadPriority>) {
+        let Some(process) = self.process.upgrade() else {
+            let mut thread_lock = self.lock();
+
+            thread_lock.guard.inherited_priority = priority;
+            thread_lock.priority_changed();
+            return;
+        };
+
+        // A thread sitting in a ready queue needs to be moved to the queue for its new priority, otherwise it would be dequeued at the
+        // priority it had when it became ready and could not be found by Process::remove_ready_thread.
+        let mut process_lock = process.lock();
+        let was_queued = process_lock.remove_ready_thread(self);
+        let mut thread_lock = self.lock();
+
+        thread_lock.guard.inherited_priority = priority;
+        thread_lock.priority_changed();
+
+        if was_queued {
+            // SAFETY: The thread was just removed from this process's ready queue while it was locked, so it is still ready and is not
+            //         on any ready queue.
+            unsafe {
+                process_lock.enqueue_ready_thread(thread_lock);
+            }
+        }
+    }
+
+    /// Records that this thread has acquired a [`Mutex`](crate::sync::mutex::Mutex).
+    pub(crate) fn mutex_acquired(&self) {
+        self.held_mutexes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that this thread has released a [`Mutex`](crate::sync::mutex::Mutex). Once it no longer holds any, it stops running with
+    /// any priority it inherited while holding them.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method has the same lock ordering requirements as [`Thread::inherit_priority`].
+    pub(crate) fn mutex_released(&self) {
+        if self.held_mutexes.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.clear_inherited_priority();
+        }
+    }
+
+    /// Gets the innermost recovery domain frame that this thread is running in, or null if it isn't running in one.
+    pub(crate) fn recovery_frame(&self) -> *mut RecoveryFrame {
+        self.recovery_frame.load(Ordering::Relaxed)
+    }
+
+    /// Sets the innermost recovery domain frame that this thread is running in.
+    pub(crate) fn set_recovery_frame(&self, frame: *mut RecoveryFrame) {
+        self.recovery_frame.store(frame, Ordering::Relaxed);
+    }
+
     pub fn as_arc(&self) -> Pin<Arc<Thread>> {
         // SAFETY: All thread must be in an Arc. This is true since the only way to create a thread is via Thread::create_internal, which
         //         returns a Pin<Arc<Thread>>. Since threads created in this way must be in an Arc and cannot be moved out due to being in a
@@ -687,9 +1521,15 @@ impl fmt::Display for ThreadDebugName<'_> {
         let thread = self.0;
 
         if let Some(process) = thread.process.upgrade() {
-            write!(f, "(pid {}, thread {})", process.pid(), thread.thread_id())
+            write!(f, "(pid {}, thread {}", process.pid(), thread.thread_id())?;
+        } else {
+            write!(f, "(disconnected thread {:p}", thread)?;
+        }
+
+        if let Some(name) = thread.name() {
+            write!(f, " \"{}\")", name)
         } else {
-            write!(f, "(disconnected thread {:p})", thread)
+            write!(f, ")")
         }
     }
 }
@@ -736,6 +1576,54 @@ impl<'a> ThreadLock<'a> {
         &mut self.guard.state
     }
 
+    /// Gets the current scheduling priority of this thread. This is the higher of its base priority and any priority it has inherited from
+    /// higher-priority threads waiting on a [`Mutex`](crate::sync::mutex::Mutex) that it holds.
+    pub fn priority(&self) -> ThreadPriority {
+        self.guard.effective_priority()
+    }
+
+    /// Gets the scheduling priority of this thread that was last set using [`ThreadLock::set_priority`], ignoring any inherited priority.
+    pub fn base_priority(&self) -> ThreadPriority {
+        self.guard.priority
+    }
+
+    /// Gets the priority that this thread has inherited from threads waiting on a [`Mutex`](crate::sync::mutex::Mutex) that it holds, if
+    /// any. The thread keeps running with this priority until it has released every mutex it holds.
+    pub fn inherited_priority(&self) -> Option<ThreadPriority> {
+        self.guard.inherited_priority
+    }
+
+    /// Sets the base scheduling priority of this thread.
+    ///
+    /// If this thread is currently sitting in a ready queue, it stays on the queue for its old priority and the new priority only takes
+    /// effect the next time it is enqueued, i.e. after it next runs. Removing it from the ready queue in the meantime, e.g. by yielding to
+    /// it, still finds it on the queue it was actually placed on.
+    pub fn set_priority(&mut self, priority: ThreadPriority) {
+        self.guard.priority = priority;
+        self.priority_changed();
+    }
+
+    /// Updates the priority recorded by the wait list that this thread is waiting on, if any, after its effective priority has changed.
+    fn priority_changed(&self) {
+        if let ThreadState::Waiting(list) = self.guard.state {
+            // SAFETY: The thread is locked and in the waiting state for this wait list, so the wait list cannot have been dropped.
+            unsafe {
+                (*list).update_priority(self);
+            }
+        }
+    }
+
+    /// Gets the mutex that this thread is currently blocked on, if any.
+    pub(crate) fn blocked_on(&self) -> Option<NonNull<MutexLock>> {
+        self.guard.blocked_on
+    }
+
+    /// Sets the mutex that this thread is currently blocked on, which is used to pass inherited priorities along chains of threads waiting
+    /// on each other.
+    pub(crate) fn set_blocked_on(&mut self, lock: Option<NonNull<MutexLock>>) {
+        self.guard.blocked_on = lock;
+    }
+
     /// Saves the CPU state of a thread in preparation to potentially perform a context switch.
     ///
     /// # Safety
@@ -747,6 +1635,17 @@ impl<'a> ThreadLock<'a> {
 
         interrupt_frame.save(&mut regs.basic);
         regs.ext.save();
+
+        if let Some(running_since) = self.guard.running_since.take() {
+            let elapsed = timer::now().saturating_sub(running_since);
+
+            self.guard.cpu_stats.cpu_time += elapsed;
+
+            // Time spent in the idle thread isn't really spent running anything, so it shouldn't count towards the kernel's CPU time
+            if let Some(process) = self.thread.process.upgrade().filter(|_| !super::idle::is_idle_thread(self.thread)) {
+                process.cpu_time_ns.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+            }
+        }
     }
 
     /// Restores the CPU state of a thread so that it will run it once the current interrupt is finished.
@@ -760,14 +1659,17 @@ impl<'a> ThreadLock<'a> {
     /// If this thread really will resume execution on the current core, then it must be manually updated to be in the running state _before
     /// this lock is released_. If this lock is released without marking the thread as running, the restored state could become stale as
     /// other CPU cores may update it and expect the changes to be reflected when the thread is next resumed.
-    pub(super) unsafe fn restore_cpu_state(&self, interrupt_frame: &mut InterruptFrame) {
+    pub(super) unsafe fn restore_cpu_state(&mut self, interrupt_frame: &mut InterruptFrame) {
         let regs = self.regs();
 
         interrupt_frame.restore(&regs.basic);
         regs.ext.restore();
 
+        self.guard.running_since = Some(timer::now());
+        self.guard.cpu_stats.context_switches += 1;
+
         if self.thread().process().upgrade().unwrap().is_kernel_process() {
-            interrupt_frame.setup_kernel_mode_thread_locals();
+            interrupt_frame.setup_kernel_mode_thread_locals(self.guard.tls.as_ref());
         }
     }
 
@@ -776,15 +1678,75 @@ impl<'a> ThreadLock<'a> {
         self.thread
     }
 
-    /// Wakes this thread up from a suspended state and moves it to the ready state.
+    /// Checks whether this thread has been asked to terminate by a call to [`Thread::kill`].
+    pub fn is_kill_requested(&self) -> bool {
+        self.guard.kill_requested
+    }
+
+    /// Wakes this thread up from a suspended state and moves it to the ready state. If the thread was already killed using [`Thread::kill`]
+    /// while it was suspended, this does nothing.
+    ///
+    /// Since the lock on a thread's process must be acquired before the lock on the thread itself, this lock is briefly released while
+    /// the thread is placed onto its process's ready queue.
     pub fn wake(mut self) {
+        if matches!(self.guard.state, ThreadState::Dead) && self.guard.kill_requested {
+            return;
+        }
+
         assert!(matches!(self.guard.state, ThreadState::Suspended));
 
         self.guard.state = ThreadState::Ready;
+        self.guard.cpu_stats.wakeups += 1;
 
-        unsafe {
-            self.thread.process.upgrade().unwrap().lock().enqueue_ready_thread(self);
-        };
+        let thread = self.thread;
+        let process = thread.process.upgrade().unwrap();
+        let _interrupts_disabled = InterruptDisabler::new();
+
+        drop(self);
+
+        let mut process_lock = process.lock();
+        let thread_lock = thread.lock();
+
+        // SAFETY: Nothing other than this method places a thread into the ready state without also placing it on a ready queue, so the
+        //         thread cannot have been enqueued while its lock was released.
+        if matches!(thread_lock.guard.state, ThreadState::Ready) {
+            unsafe {
+                process_lock.enqueue_ready_thread(thread_lock);
+            };
+        }
+    }
+
+    /// Gets statistics about this thread's CPU usage. If this thread is currently running, the time it has spent running since it was last
+    /// switched to is included.
+    pub fn cpu_stats(&self) -> ThreadCpuStats {
+        let mut stats = self.guard.cpu_stats;
+
+        if let Some(running_since) = self.guard.running_since {
+            stats.cpu_time += timer::now().saturating_sub(running_since);
+        }
+
+        stats
+    }
+
+    /// Takes the kernel-mode stack that was allocated for this thread, if any, so that it can be freed once the thread is dead.
+    pub(super) fn take_kernel_stack(&mut self) -> Option<KernelStack> {
+        self.guard.kernel_stack.take()
+    }
+
+    /// Takes the thread-local storage block that was allocated for this thread, if any, so that it can be freed once the thread is dead.
+    pub(super) fn take_tls(&mut self) -> Option<TlsBlock> {
+        self.guard.tls.take()
+    }
+
+    /// Checks whether this thread still owns a kernel-mode stack allocated by the scheduler.
+    pub fn has_kernel_stack(&self) -> bool {
+        self.guard.kernel_stack.is_some()
+    }
+
+    /// Takes the writer used to resolve the future returned by [`ThreadLock::join`]. This should be resolved by the scheduler after the
+    /// thread has died and its lock has been released.
+    pub(super) fn take_join_writer(&mut self) -> Option<FutureWriter<()>> {
+        self.guard.join_writer.take()
     }
 
     /// Gets a reference to the register values of this thread. These values are only updated when a thread stops running. If this thread is
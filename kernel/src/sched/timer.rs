@@ -0,0 +1,150 @@
+//! A timer subsystem used to wake threads and resolve futures after a period of time has elapsed.
+//!
+//! Pending timers are kept in a hashed timer wheel with a fixed resolution. Each slot in the wheel holds the timers whose deadlines fall
+//! within a given window of time modulo the length of the wheel, so that only a single slot needs to be examined on each timer tick.
+//! Timers whose deadlines are further away than the length of the wheel simply remain in their slot until the wheel has rotated enough times
+//! for their deadline to arrive.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::replay::{self, ReplayEvent};
+use crate::sync::future::FutureWriter;
+use crate::sync::{Future, UninterruptibleSpinlock};
+
+const WHEEL_SLOTS: usize = 256;
+const WHEEL_RESOLUTION_NS: u64 = 1_000_000;
+
+static NOW_NS: AtomicU64 = AtomicU64::new(0);
+
+struct TimerEntry {
+    deadline: Duration,
+    writer: FutureWriter<()>,
+}
+
+struct TimerWheel {
+    slots: [Vec<TimerEntry>; WHEEL_SLOTS],
+    last_processed: u64,
+    num_pending: usize,
+}
+
+const EMPTY_SLOT: Vec<TimerEntry> = Vec::new();
+
+static WHEEL: UninterruptibleSpinlock<TimerWheel> = UninterruptibleSpinlock::new(TimerWheel {
+    slots: [EMPTY_SLOT; WHEEL_SLOTS],
+    last_processed: 0,
+    num_pending: 0,
+});
+
+fn to_wheel_time_ceil(t: Duration) -> u64 {
+    let ns = t.as_nanos().min(u128::from(u64::MAX)) as u64;
+    ns.div_ceil(WHEEL_RESOLUTION_NS)
+}
+
+fn to_wheel_time_floor(t: Duration) -> u64 {
+    let ns = t.as_nanos().min(u128::from(u64::MAX)) as u64;
+    ns / WHEEL_RESOLUTION_NS
+}
+
+/// Gets the amount of time that has elapsed since the system timer was started.
+///
+/// This clock is monotonic, but only advances when timer ticks are received, so it has a resolution no better than the period of the
+/// system timer.
+pub fn now() -> Duration {
+    Duration::from_nanos(NOW_NS.load(Ordering::Relaxed))
+}
+
+/// Returns a future that will resolve once the system clock (as returned by [`now`]) reaches the provided deadline. If the deadline has
+/// already passed, the returned future is resolved immediately.
+pub fn at(deadline: Duration) -> Future<()> {
+    let mut wheel = WHEEL.lock();
+
+    if deadline <= now() {
+        return Future::done(());
+    }
+
+    let (future, writer) = Future::new();
+    let slot = (to_wheel_time_ceil(deadline) % WHEEL_SLOTS as u64) as usize;
+
+    wheel.slots[slot].push(TimerEntry { deadline, writer });
+    wheel.num_pending += 1;
+
+    future
+}
+
+/// Returns a future that will resolve once at least the provided amount of time has elapsed.
+pub fn after(duration: Duration) -> Future<()> {
+    at(now().saturating_add(duration))
+}
+
+/// Gets the number of timers that are waiting for their deadlines to arrive.
+pub fn num_pending() -> usize {
+    WHEEL.lock().num_pending
+}
+
+/// Advances the system clock by the provided amount of time, resolving the futures of any timers whose deadlines have now passed.
+///
+/// This must only be called from the system timer's interrupt handler on a single CPU core.
+pub(super) fn tick(elapsed: Duration) {
+    let mut fired = Vec::new();
+    let mut wheel = WHEEL.lock();
+
+    let now_ns = NOW_NS.load(Ordering::Relaxed).saturating_add(elapsed.as_nanos() as u64);
+    let now = Duration::from_nanos(now_ns);
+    let now_wheel_time = to_wheel_time_floor(now);
+
+    NOW_NS.store(now_ns, Ordering::Relaxed);
+
+    if wheel.num_pending != 0 {
+        let first = if now_wheel_time - wheel.last_processed > WHEEL_SLOTS as u64 {
+            now_wheel_time - WHEEL_SLOTS as u64 + 1
+        } else {
+            wheel.last_processed + 1
+        };
+
+        for t in first..=now_wheel_time {
+            let slot = &mut wheel.slots[(t % WHEEL_SLOTS as u64) as usize];
+            let mut i = 0;
+
+            while i < slot.len() {
+                if slot[i].deadline <= now {
+                    fired.push(slot.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        wheel.num_pending -= fired.len();
+    }
+
+    wheel.last_processed = now_wheel_time;
+    drop(wheel);
+
+    for entry in fired {
+        replay::record(|| ReplayEvent::TimerExpired {
+            deadline_ns: entry.deadline.as_nanos() as u64,
+        });
+        entry.writer.finish(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_at_past_deadline() {
+        assert!(at(Duration::ZERO).is_ready());
+        assert!(at(now()).is_ready());
+    }
+
+    #[test_case]
+    fn test_after() {
+        let start = now();
+
+        after(Duration::from_millis(5)).unwrap_blocking();
+        assert!(now() >= start + Duration::from_millis(5));
+    }
+}
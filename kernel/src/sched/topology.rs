@@ -0,0 +1,164 @@
+//! Information about how the logical processors in the machine are grouped into cores and packages.
+//!
+//! The topology is detected once during boot by the architecture-specific code and is then available through [`get`]. Logical processors
+//! are identified by the hardware ID that the architecture uses to address them (e.g. the local APIC ID on x86_64), which is then split
+//! into the package, core and thread that it belongs to.
+
+use alloc::vec::Vec;
+
+use crate::util::OneShotManualInit;
+use crate::{arch, log};
+
+/// A single logical processor (i.e. hardware thread) in the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogicalCpu {
+    /// The ID used by the hardware to address this logical processor.
+    pub hw_id: u32,
+    /// The physical package (socket) that this logical processor is part of.
+    pub package: u32,
+    /// The core within its package that this logical processor is part of.
+    pub core: u32,
+    /// The hardware thread within its core that this logical processor represents.
+    pub thread: u32,
+    /// Whether this logical processor is usable. Disabled processors may be brought online later by hotplugging them.
+    pub enabled: bool,
+}
+
+impl LogicalCpu {
+    /// Checks whether this logical processor shares a core with another logical processor.
+    pub fn is_sibling_of(&self, other: &LogicalCpu) -> bool {
+        self.package == other.package && self.core == other.core
+    }
+}
+
+/// A map of all logical processors in the machine, sorted by package, core and thread.
+#[derive(Debug, Clone)]
+pub struct CpuTopology {
+    cpus: Vec<LogicalCpu>,
+}
+
+impl CpuTopology {
+    /// Creates a new topology map from a list of logical processors in any order.
+    pub fn new(mut cpus: Vec<LogicalCpu>) -> CpuTopology {
+        cpus.sort_by_key(|cpu| (cpu.package, cpu.core, cpu.thread));
+        CpuTopology { cpus }
+    }
+
+    /// Gets all logical processors in the machine, including disabled ones.
+    pub fn cpus(&self) -> &[LogicalCpu] {
+        &self.cpus
+    }
+
+    /// Gets all enabled logical processors in the machine.
+    pub fn enabled_cpus(&self) -> impl Iterator<Item = &LogicalCpu> + '_ {
+        self.cpus.iter().filter(|cpu| cpu.enabled)
+    }
+
+    /// Gets the logical processor with the provided hardware ID, if it exists.
+    pub fn by_hw_id(&self, hw_id: u32) -> Option<&LogicalCpu> {
+        self.cpus.iter().find(|cpu| cpu.hw_id == hw_id)
+    }
+
+    /// Gets all enabled logical processors that are part of the provided package.
+    pub fn package_cpus(&self, package: u32) -> impl Iterator<Item = &LogicalCpu> + '_ {
+        self.enabled_cpus().filter(move |cpu| cpu.package == package)
+    }
+
+    /// Gets all enabled logical processors that share a core with the provided logical processor, including itself.
+    pub fn siblings<'a>(&'a self, cpu: &'a LogicalCpu) -> impl Iterator<Item = &'a LogicalCpu> + 'a {
+        self.enabled_cpus().filter(move |other| other.is_sibling_of(cpu))
+    }
+
+    /// Gets the number of packages that have at least one enabled logical processor.
+    pub fn num_packages(&self) -> usize {
+        let mut packages: Vec<_> = self.enabled_cpus().map(|cpu| cpu.package).collect();
+
+        packages.dedup();
+        packages.len()
+    }
+
+    /// Gets the number of cores that have at least one enabled logical processor.
+    pub fn num_cores(&self) -> usize {
+        let mut cores: Vec<_> = self.enabled_cpus().map(|cpu| (cpu.package, cpu.core)).collect();
+
+        cores.dedup();
+        cores.len()
+    }
+
+    /// Gets the number of enabled logical processors.
+    pub fn num_threads(&self) -> usize {
+        self.enabled_cpus().count()
+    }
+}
+
+static TOPOLOGY: OneShotManualInit<CpuTopology> = OneShotManualInit::uninit();
+
+/// Detects the processor topology of the machine.
+///
+/// # Safety
+///
+/// This function should only be called once from the bootstrap processor during the boot process.
+unsafe fn init() {
+    let topology = arch::topology::detect();
+
+    log!(
+        Info,
+        "topology",
+        "Detected {} packages, {} cores, {} threads",
+        topology.num_packages(),
+        topology.num_cores(),
+        topology.num_threads()
+    );
+
+    TOPOLOGY.set(topology);
+}
+
+crate::initcall!(arch, "topology", init);
+
+/// Gets the processor topology of the machine.
+pub fn get() -> &'static CpuTopology {
+    TOPOLOGY.get()
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    fn cpu(hw_id: u32, package: u32, core: u32, thread: u32, enabled: bool) -> LogicalCpu {
+        LogicalCpu {
+            hw_id,
+            package,
+            core,
+            thread,
+            enabled,
+        }
+    }
+
+    #[test_case]
+    fn test_topology_counts() {
+        let topology = CpuTopology::new(vec![
+            cpu(5, 1, 0, 1, true),
+            cpu(0, 0, 0, 0, true),
+            cpu(1, 0, 0, 1, true),
+            cpu(2, 0, 1, 0, true),
+            cpu(3, 0, 1, 1, false),
+            cpu(4, 1, 0, 0, true),
+            cpu(6, 1, 1, 0, false),
+        ]);
+
+        assert_eq!(2, topology.num_packages());
+        assert_eq!(3, topology.num_cores());
+        assert_eq!(5, topology.num_threads());
+        assert_eq!(
+            &[0, 1, 2, 3, 4, 5, 6],
+            &topology.cpus().iter().map(|cpu| cpu.hw_id).collect::<Vec<_>>()[..]
+        );
+
+        let cpu_4 = *topology.by_hw_id(4).unwrap();
+
+        assert_eq!(vec![4, 5], topology.siblings(&cpu_4).map(|cpu| cpu.hw_id).collect::<Vec<_>>());
+        assert_eq!(vec![0, 1, 2], topology.package_cpus(0).map(|cpu| cpu.hw_id).collect::<Vec<_>>());
+    }
+}
@@ -4,11 +4,14 @@ use alloc::sync::Arc;
 use core::marker::PhantomData;
 use core::mem::ManuallyDrop;
 use core::pin::Pin;
+use core::time::Duration;
 use core::{fmt, mem, ptr};
 
-use super::task::{Thread, ThreadLock, ThreadState};
-use crate::sync::UninterruptibleSpinlock;
-use crate::util::DisplayAsDebug;
+use super::task::{Thread, ThreadKilled, ThreadLock, ThreadPriority, ThreadState};
+use super::timer;
+use crate::sync::uninterruptible::InterruptDisabler;
+use crate::sync::{lock_class, UninterruptibleSpinlock};
+use crate::util::{DisplayAsDebug, PinWeak};
 
 /// State information for a thread which is waiting on a wait list.
 #[derive(Debug)]
@@ -16,6 +19,10 @@ pub(super) struct ThreadWaitState {
     prev: *const Thread,
     next: *const Thread,
     valid: bool,
+    killable: bool,
+    seq: u64,
+    timed_out: bool,
+    priority: ThreadPriority,
 }
 
 unsafe impl Send for ThreadWaitState {}
@@ -26,6 +33,10 @@ impl ThreadWaitState {
             prev: ptr::null(),
             next: ptr::null(),
             valid: false,
+            killable: false,
+            seq: 0,
+            timed_out: false,
+            priority: ThreadPriority::Normal,
         }
     }
 }
@@ -62,9 +73,59 @@ impl ThreadWaitListInternal {
         }
     }
 
-    unsafe fn enqueue(&mut self, thread: Pin<Arc<Thread>>) {
+    /// Removes a specific thread from anywhere in this wait list, returning the reference that was held by the wait list.
+    ///
+    /// # Safety
+    ///
+    /// The provided thread must currently be on this wait list.
+    unsafe fn remove(&mut self, thread: &Thread) -> Pin<Arc<Thread>> {
+        let wait_state = &mut *thread.wait_state();
+
+        assert!(wait_state.valid);
+        wait_state.valid = false;
+
+        if wait_state.prev.is_null() {
+            debug_assert_eq!(self.head, thread as *const _);
+            self.head = wait_state.next;
+        } else {
+            (*(*wait_state.prev).wait_state()).next = wait_state.next;
+        }
+
+        if wait_state.next.is_null() {
+            debug_assert_eq!(self.tail, thread as *const _);
+            self.tail = wait_state.prev;
+        } else {
+            (*(*wait_state.next).wait_state()).prev = wait_state.prev;
+        }
+
+        wait_state.prev = ptr::null();
+        wait_state.next = ptr::null();
+
+        // SAFETY: When the thread was enqueued, into_raw was called on it exactly one time.
+        Thread::from_raw(thread)
+    }
+
+    /// Adds a thread with the provided priority to the end of this wait list. Returns a sequence number that uniquely identifies this
+    /// particular wait of the thread, which can be used to make sure that a delayed timeout does not affect a later wait.
+    unsafe fn enqueue(&mut self, thread: Pin<Arc<Thread>>, killable: bool, priority: ThreadPriority) -> u64 {
         assert!(!(*thread.wait_state()).valid);
 
+        let seq = (*thread.wait_state()).seq.wrapping_add(1);
+
+        (*thread.wait_state()).killable = killable;
+        (*thread.wait_state()).seq = seq;
+        (*thread.wait_state()).timed_out = false;
+        self.link_tail(thread, priority);
+
+        seq
+    }
+
+    /// Links a thread that is not on any wait list onto the end of this wait list, recording its current priority but leaving the rest of
+    /// its wait state untouched.
+    unsafe fn link_tail(&mut self, thread: Pin<Arc<Thread>>, priority: ThreadPriority) {
+        assert!(!(*thread.wait_state()).valid);
+
+        (*thread.wait_state()).priority = priority;
         (*thread.wait_state()).prev = self.tail;
         (*thread.wait_state()).next = ptr::null();
         (*thread.wait_state()).valid = true;
@@ -118,9 +179,60 @@ impl<'a> ThreadWait<'a> {
             Thread::suspend_current(ManuallyDrop::take(&mut self.0));
         };
     }
+
+    /// Suspends the current thread and consumes this guard. Once the thread is woken up, returns [`ThreadKilled`] if the thread was woken
+    /// because of a call to [`Thread::kill`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic under the same conditions as [`ThreadWait::suspend`].
+    pub fn suspend_killable(self) -> Result<(), ThreadKilled> {
+        self.suspend();
+        Thread::check_killed()
+    }
+}
+
+/// The reason that a thread which waited using [`ThreadWaitList::wait_timeout`] stopped waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// The thread was woken up by a call to [`ThreadWaitList::wake_one`] or [`ThreadWaitList::wake_all`].
+    Woken,
+    /// The timeout elapsed before the thread was woken up.
+    TimedOut,
+}
+
+/// A [`ThreadWait`] which will stop waiting once a timeout has elapsed, even if the thread has not been woken up by then.
+///
+/// # Panics
+///
+/// Causing a value of this type to be dropped without running [`ThreadTimedWait::suspend`] will cause a panic for the same reasons as
+/// [`ThreadWait`].
+pub struct ThreadTimedWait<'a>(ThreadWait<'a>);
+
+impl<'a> ThreadTimedWait<'a> {
+    /// Suspends the current thread and consumes this guard. Once the thread stops waiting, returns whether it was woken up or whether the
+    /// timeout elapsed first.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic under the same conditions as [`ThreadWait::suspend`].
+    pub fn suspend(self) -> WaitResult {
+        self.0.suspend();
+
+        // SAFETY: The current thread is no longer on any wait list, so nothing else can be accessing its wait state.
+        if unsafe { (*Thread::current().wait_state()).timed_out } {
+            WaitResult::TimedOut
+        } else {
+            WaitResult::Woken
+        }
+    }
 }
 
 /// A wait list onto which threads can enqueue themselves to be woken up later.
+///
+/// Wait lists are strictly first-in, first-out: [`ThreadWaitList::wake_one`] always wakes the thread that has been waiting the longest, and
+/// [`ThreadWaitList::wake_all`] and [`ThreadWaitList::requeue`] process threads in the order in which they started waiting. Threads moved
+/// onto a wait list using [`ThreadWaitList::requeue`] are placed behind the threads that were already waiting on it.
 pub struct ThreadWaitList {
     internal: UninterruptibleSpinlock<ThreadWaitListInternal>,
 }
@@ -131,10 +243,13 @@ impl ThreadWaitList {
     /// Creates an empty wait list.
     pub const fn new() -> ThreadWaitList {
         ThreadWaitList {
-            internal: UninterruptibleSpinlock::new(ThreadWaitListInternal {
-                head: ptr::null(),
-                tail: ptr::null(),
-            }),
+            internal: UninterruptibleSpinlock::with_class(
+                ThreadWaitListInternal {
+                    head: ptr::null(),
+                    tail: ptr::null(),
+                },
+                &lock_class::WAIT_LIST,
+            ),
         }
     }
 
@@ -147,6 +262,9 @@ impl ThreadWaitList {
     /// deadlock occurring. The returned [`ThreadWait`] will hold the spinlock for the current thread until [`ThreadWait::suspend`] is
     /// called.
     ///
+    /// The lock on the current thread is acquired before the lock on this wait list, in accordance with the ordering of
+    /// [`lock_class::THREAD`] and [`lock_class::WAIT_LIST`].
+    ///
     /// # Panics
     ///
     /// This method cannot suspend a thread from the context of an asynchronous hardware interrupt and will panic if it is called from an
@@ -157,7 +275,6 @@ impl ThreadWaitList {
             // SAFETY: This thread reference never leaves the current thread. Since this references the current thread, it must continue to
             //         exist while this thread is still executing, so extending its lifetime like this is safe.
             let mut thread = (*(&*Thread::current() as *const Thread)).lock();
-            let mut internal = self.internal.lock();
 
             assert!(matches!(*thread.state(), ThreadState::Running));
             *thread.state_mut() = ThreadState::Waiting(self);
@@ -166,11 +283,169 @@ impl ThreadWaitList {
             //         drop the returned ThreadWait, which will unconditionally panic. If the returned ThreadWait is leaked, then the thread
             //         is never unlocked and the improper state updates can never be observed. Obviously, this is undesirable but does not
             //         have any implications for safety guarantees.
-            internal.enqueue(thread.thread().as_arc());
+            self.internal.lock().enqueue(thread.thread().as_arc(), false, thread.priority());
             ThreadWait(ManuallyDrop::new(thread), ThreadWaitDropGuard, PhantomData)
         }
     }
 
+    /// Adds the current thread to the wait list in the same manner as [`ThreadWaitList::wait`], unless the current thread has been killed
+    /// using [`Thread::kill`], in which case [`ThreadKilled`] is returned without adding it to the wait list. The returned [`ThreadWait`]
+    /// should be suspended using [`ThreadWait::suspend_killable`] to also detect kill requests made while waiting.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method has the same lock ordering requirements as [`ThreadWaitList::wait`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic under the same conditions as [`ThreadWaitList::wait`].
+    pub fn wait_killable(&self) -> Result<ThreadWait, ThreadKilled> {
+        unsafe {
+            // SAFETY: See ThreadWaitList::wait
+            let mut thread = (*(&*Thread::current() as *const Thread)).lock();
+
+            if thread.is_kill_requested() {
+                return Err(ThreadKilled);
+            }
+
+            assert!(matches!(*thread.state(), ThreadState::Running));
+            *thread.state_mut() = ThreadState::Waiting(self);
+
+            self.internal.lock().enqueue(thread.thread().as_arc(), true, thread.priority());
+            Ok(ThreadWait(ManuallyDrop::new(thread), ThreadWaitDropGuard, PhantomData))
+        }
+    }
+
+    /// Adds the current thread to the wait list in the same manner as [`ThreadWaitList::wait`], but arranges for it to be removed from the
+    /// wait list and woken up once the provided amount of time has elapsed if nothing has woken it up by then. The returned
+    /// [`ThreadTimedWait`] should be used to suspend the current thread after releasing any held spinlocks.
+    ///
+    /// Since timeouts are driven by the system timer, the thread may remain waiting for up to one timer tick longer than requested.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method has the same lock ordering requirements as [`ThreadWaitList::wait`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic under the same conditions as [`ThreadWaitList::wait`].
+    #[must_use]
+    pub fn wait_timeout(&self, timeout: Duration) -> ThreadTimedWait {
+        unsafe {
+            // SAFETY: See ThreadWaitList::wait
+            let mut thread = (*(&*Thread::current() as *const Thread)).lock();
+
+            assert!(matches!(*thread.state(), ThreadState::Running));
+            *thread.state_mut() = ThreadState::Waiting(self);
+
+            let seq = self.internal.lock().enqueue(thread.thread().as_arc(), false, thread.priority());
+            // Only a weak reference is held until the timeout elapses so that a thread which is woken up well before then and exits isn't
+            // kept alive by the pending timer.
+            let timed_out_thread = PinWeak::downgrade(&thread.thread().as_arc());
+
+            // The timeout is handled in a soft interrupt rather than directly from the timer so that it cannot run until the locks held
+            // here have been released, even if the timeout has already elapsed.
+            timer::after(timeout).when_resolved_soft(move |()| {
+                // A thread that no longer exists can't still be waiting
+                let Some(timed_out_thread) = timed_out_thread.upgrade() else {
+                    return;
+                };
+
+                // The thread may have been requeued onto another wait list in the meantime, in which case the timeout applies there
+                loop {
+                    let thread_lock = timed_out_thread.lock();
+                    let ThreadState::Waiting(current_list) = *thread_lock.state() else {
+                        break;
+                    };
+
+                    drop(thread_lock);
+
+                    // SAFETY: A thread cannot stop waiting on a wait list without that wait list waking it, which can't happen while
+                    //         interrupts are disabled. Since a non-empty wait list cannot be dropped, the list must still be alive.
+                    if (*current_list).time_out_wait(&timed_out_thread, seq) {
+                        break;
+                    }
+                }
+            });
+
+            ThreadTimedWait(ThreadWait(ManuallyDrop::new(thread), ThreadWaitDropGuard, PhantomData))
+        }
+    }
+
+    /// Removes the provided thread from this wait list and wakes it up after its timeout has elapsed. Does nothing if the thread is no
+    /// longer performing the wait identified by the provided sequence number. Returns `false` if the thread was found to be waiting on
+    /// another wait list instead or to be in the middle of leaving this one, in which case the caller should retry.
+    fn time_out_wait(&self, thread: &Thread, seq: u64) -> bool {
+        let mut thread_lock = thread.lock();
+
+        match *thread_lock.state() {
+            ThreadState::Waiting(list) if list == self => {},
+            ThreadState::Waiting(_) => return false,
+            _ => return true,
+        }
+
+        let mut internal = self.internal.lock();
+
+        // SAFETY: The wait list effectively has a mutable borrow of the wait states of all threads that appear on it, and the sequence
+        //         number is only changed by the thread itself while it is locked.
+        unsafe {
+            if (*thread.wait_state()).seq != seq {
+                return true;
+            } else if !(*thread.wait_state()).valid {
+                // The thread was just dequeued by another core that is about to wake it up or move it onto another wait list. The timeout
+                // still applies in the latter case, so check again once that core has locked the thread.
+                return false;
+            }
+
+            let thread_ref = internal.remove(thread);
+            drop(internal);
+
+            (*thread.wait_state()).timed_out = true;
+            *thread_lock.state_mut() = ThreadState::Suspended;
+            thread_lock.wake();
+            drop(thread_ref);
+        }
+
+        true
+    }
+
+    /// Removes the provided thread from this wait list and wakes it up, regardless of whether the event it was waiting for has occurred.
+    /// Does nothing if the thread is no longer waiting or if it started waiting using [`ThreadWaitList::wait`] rather than
+    /// [`ThreadWaitList::wait_killable`], since callers of the former may rely on not being woken until the event actually occurs. Returns
+    /// `false` if the thread was found to be waiting on another wait list instead or to be in the middle of leaving this one, in which case
+    /// the caller should retry.
+    pub(super) fn cancel_wait(&self, thread: &Thread) -> bool {
+        let mut thread_lock = thread.lock();
+
+        match *thread_lock.state() {
+            ThreadState::Waiting(list) if list == self => {},
+            ThreadState::Waiting(_) => return false,
+            _ => return true,
+        }
+
+        let mut internal = self.internal.lock();
+
+        // SAFETY: The wait list effectively has a mutable borrow of the wait states of all threads that appear on it, and whether the wait
+        //         is killable is only changed by the thread itself while it is locked.
+        unsafe {
+            if !(*thread.wait_state()).killable {
+                return true;
+            } else if !(*thread.wait_state()).valid {
+                // See ThreadWaitList::time_out_wait
+                return false;
+            }
+
+            let thread_ref = internal.remove(thread);
+            drop(internal);
+
+            *thread_lock.state_mut() = ThreadState::Suspended;
+            thread_lock.wake();
+            drop(thread_ref);
+        }
+
+        true
+    }
+
     unsafe fn try_wake(&self, mut thread: ThreadLock) -> bool {
         match *thread.state() {
             ThreadState::Dead => false,
@@ -197,19 +472,17 @@ impl ThreadWaitList {
     /// This method should not be called while any scheduler locks, such as thread and process locks, are held. Doing so may result in a
     /// deadlock occurring.
     pub fn wake_one(&self) -> Option<Pin<Arc<Thread>>> {
+        // Since threads must be locked before wait lists, the lock on this wait list is released before the dequeued thread is locked.
+        // Interrupts are kept disabled in between so that a timeout on this core cannot spin waiting for the thread to be woken.
+        let _interrupts_disabled = InterruptDisabler::new();
+
         loop {
-            break if let Some(thread) = self.internal.lock().dequeue() {
-                // SAFETY: A waiting -> ready transition is safe since the event the thread was waiting on has now occurred.
-                unsafe {
-                    if !self.try_wake(thread.lock()) {
-                        continue;
-                    }
-                };
+            let thread = self.internal.lock().dequeue()?;
 
-                Some(thread)
-            } else {
-                None
-            };
+            // SAFETY: A waiting -> ready transition is safe since the event the thread was waiting on has now occurred.
+            if unsafe { self.try_wake(thread.lock()) } {
+                return Some(thread);
+            }
         }
     }
 
@@ -220,9 +493,15 @@ impl ThreadWaitList {
     /// This method should not be called while any scheduler locks, such as thread and process locks, are held. Doing so may result in a
     /// deadlock occurring.
     pub fn wake_all(&self) -> usize {
+        // See ThreadWaitList::wake_one
+        let _interrupts_disabled = InterruptDisabler::new();
         let mut num_woken = 0;
 
-        while let Some(thread) = self.internal.lock().dequeue() {
+        loop {
+            let Some(thread) = self.internal.lock().dequeue() else {
+                break;
+            };
+
             // SAFETY: A waiting -> ready transition (via suspended) is safe since the event the thread was waiting on has now occurred.
             unsafe {
                 if self.try_wake(thread.lock()) {
@@ -233,6 +512,102 @@ impl ThreadWaitList {
 
         num_woken
     }
+
+    /// Gets the highest scheduling priority of any thread currently waiting on this wait list, or [`None`] if no threads are waiting on
+    /// it.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method has the same lock ordering requirements as [`ThreadWaitList::wake_one`].
+    pub fn highest_priority(&self) -> Option<ThreadPriority> {
+        let internal = self.internal.lock();
+        let mut highest = None;
+        let mut thread = internal.head;
+
+        while !thread.is_null() {
+            // SAFETY: The wait list holds a reference to every thread on it and effectively has a mutable borrow of their wait states, so
+            //         walking the list while it is locked is safe. The threads themselves can't be locked here, so the priority recorded
+            //         in their wait states is used instead.
+            unsafe {
+                highest = highest.max(Some((*(*thread).wait_state()).priority));
+                thread = (*(*thread).wait_state()).next;
+            }
+        }
+
+        highest
+    }
+
+    /// Records a new priority for the provided thread, which is in the waiting state for this wait list, so that it is taken into account
+    /// by [`ThreadWaitList::highest_priority`].
+    ///
+    /// # Safety
+    ///
+    /// The provided thread lock must belong to a thread in the waiting state for this wait list.
+    pub(super) unsafe fn update_priority(&self, thread: &ThreadLock) {
+        let _internal = self.internal.lock();
+        let wait_state = &mut *thread.thread().wait_state();
+
+        // A thread that isn't on the list is being woken or moved onto another wait list, which records its priority once it has locked
+        // the thread.
+        if wait_state.valid {
+            wait_state.priority = thread.priority();
+        }
+    }
+
+    /// Moves up to `max` threads from the front of this wait list onto the back of the provided wait list without waking them up, keeping
+    /// them in the same order. Returns the number of threads that were moved.
+    ///
+    /// This allows a thread that is about to wake several waiters which would all immediately contend for some other resource to hand them
+    /// over to the wait list for that resource instead, e.g. waking a single waiter of a condition variable and moving the rest onto the
+    /// wait list of the associated mutex so that they are woken one at a time as it is released. Moved threads behave exactly as though
+    /// they had started waiting on the provided wait list, except that any timeouts they were waiting with continue to apply.
+    ///
+    /// # Lock Ordering
+    ///
+    /// This method has the same lock ordering requirements as [`ThreadWaitList::wake_one`]. Threads are moved one at a time and the two
+    /// wait lists are never locked at the same time, so a concurrent requeue in the opposite direction cannot deadlock with this one.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the provided wait list is this wait list.
+    pub fn requeue(&self, target: &ThreadWaitList, max: usize) -> usize {
+        assert!(!ptr::eq(self, target), "cannot requeue threads onto the same wait list");
+
+        // See ThreadWaitList::wake_one
+        let _interrupts_disabled = InterruptDisabler::new();
+        let mut num_moved = 0;
+
+        while num_moved < max {
+            let Some(thread) = self.internal.lock().dequeue() else {
+                break;
+            };
+            let mut thread_lock = thread.lock();
+
+            match *thread_lock.state() {
+                ThreadState::Dead => continue,
+                ThreadState::Waiting(list) if list == self => {},
+                ref state => {
+                    panic!(
+                        "Thread {} is in unexpected state {:?} after dequeueing from wait list",
+                        thread.debug_name(),
+                        state
+                    );
+                },
+            }
+
+            *thread_lock.state_mut() = ThreadState::Waiting(target);
+
+            // SAFETY: The thread was just removed from this wait list and is now in the waiting state for the target wait list. It stays
+            //         locked until it is on the target wait list so that its recorded priority can't miss an update.
+            unsafe {
+                target.internal.lock().link_tail(thread.clone(), thread_lock.priority());
+            }
+
+            num_moved += 1;
+        }
+
+        num_moved
+    }
 }
 
 impl Drop for ThreadWaitList {
@@ -292,7 +667,7 @@ mod test {
         let thread = unsafe {
             Process::kernel()
                 .lock()
-                .create_kernel_thread_unchecked(thread_fn, TEST_THREAD_STACK_SIZE)
+                .create_kernel_thread_unchecked("test", thread_fn, TEST_THREAD_STACK_SIZE)
         };
         thread.lock().wake();
 
@@ -305,6 +680,33 @@ mod test {
         assert!(matches!(*thread.lock().state(), ThreadState::Dead));
     }
 
+    #[test_case]
+    fn test_kill_waiting() {
+        let result = UninterruptibleSpinlock::new(None);
+        let waitlist = Box::pin(ThreadWaitList::new());
+
+        let thread_fn = || {
+            *result.lock() = Some(waitlist.as_ref().wait_killable().and_then(|wait| wait.suspend_killable()));
+        };
+
+        let thread = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", thread_fn, TEST_THREAD_STACK_SIZE)
+        };
+        thread.lock().wake();
+
+        Thread::yield_current();
+        assert!(matches!(*thread.lock().state(), ThreadState::Waiting(_)));
+
+        thread.kill();
+        Thread::yield_current();
+
+        assert_eq!(Some(Err(ThreadKilled)), *result.lock());
+        assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+        assert_eq!(None, waitlist.wake_one());
+    }
+
     #[test_case]
     fn test_wake_one_order() {
         let val = AtomicI32::new(0);
@@ -317,7 +719,7 @@ mod test {
         let thread_1 = unsafe {
             Process::kernel()
                 .lock()
-                .create_kernel_thread_unchecked(thread_fn_1, TEST_THREAD_STACK_SIZE)
+                .create_kernel_thread_unchecked("test", thread_fn_1, TEST_THREAD_STACK_SIZE)
         };
         thread_1.lock().wake();
         Thread::yield_current();
@@ -329,7 +731,7 @@ mod test {
         let thread_2 = unsafe {
             Process::kernel()
                 .lock()
-                .create_kernel_thread_unchecked(thread_fn_2, TEST_THREAD_STACK_SIZE)
+                .create_kernel_thread_unchecked("test", thread_fn_2, TEST_THREAD_STACK_SIZE)
         };
         thread_2.lock().wake();
         Thread::yield_current();
@@ -359,7 +761,7 @@ mod test {
         let thread_1 = unsafe {
             Process::kernel()
                 .lock()
-                .create_kernel_thread_unchecked(thread_fn_1, TEST_THREAD_STACK_SIZE)
+                .create_kernel_thread_unchecked("test", thread_fn_1, TEST_THREAD_STACK_SIZE)
         };
         thread_1.lock().wake();
         Thread::yield_current();
@@ -371,7 +773,7 @@ mod test {
         let thread_2 = unsafe {
             Process::kernel()
                 .lock()
-                .create_kernel_thread_unchecked(thread_fn_2, TEST_THREAD_STACK_SIZE)
+                .create_kernel_thread_unchecked("test", thread_fn_2, TEST_THREAD_STACK_SIZE)
         };
         thread_2.lock().wake();
         Thread::yield_current();
@@ -384,4 +786,128 @@ mod test {
         assert!(matches!(*thread_1.lock().state(), ThreadState::Dead));
         assert!(matches!(*thread_2.lock().state(), ThreadState::Dead));
     }
+
+    #[test_case]
+    fn test_wait_timeout_elapsed() {
+        let waitlist = Box::pin(ThreadWaitList::new());
+        let start = timer::now();
+
+        assert_eq!(
+            WaitResult::TimedOut,
+            waitlist.as_ref().wait_timeout(Duration::from_millis(5)).suspend()
+        );
+        assert!(timer::now() >= start + Duration::from_millis(5));
+        assert_eq!(None, waitlist.wake_one());
+    }
+
+    #[test_case]
+    fn test_wait_timeout_woken() {
+        let result = UninterruptibleSpinlock::new(None);
+        let waitlist = Box::pin(ThreadWaitList::new());
+
+        let thread_fn = || {
+            *result.lock() = Some(waitlist.as_ref().wait_timeout(Duration::from_secs(60)).suspend());
+        };
+
+        let thread = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", thread_fn, TEST_THREAD_STACK_SIZE)
+        };
+        thread.lock().wake();
+
+        Thread::yield_current();
+        assert!(matches!(*thread.lock().state(), ThreadState::Waiting(_)));
+
+        waitlist.wake_one();
+        Thread::yield_current();
+
+        assert_eq!(Some(WaitResult::Woken), *result.lock());
+        assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+    }
+
+    #[test_case]
+    fn test_requeue() {
+        let val = AtomicI32::new(0);
+        let waitlist = Box::pin(ThreadWaitList::new());
+        let target = Box::pin(ThreadWaitList::new());
+
+        let thread_fn_1 = || {
+            waitlist.as_ref().wait().suspend();
+            val.store(1, Ordering::Relaxed);
+        };
+        let thread_1 = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", thread_fn_1, TEST_THREAD_STACK_SIZE)
+        };
+        thread_1.lock().wake();
+        Thread::yield_current();
+
+        let thread_fn_2 = || {
+            waitlist.as_ref().wait().suspend();
+            val.store(2, Ordering::Relaxed);
+        };
+        let thread_2 = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", thread_fn_2, TEST_THREAD_STACK_SIZE)
+        };
+        thread_2.lock().wake();
+        Thread::yield_current();
+
+        let thread_fn_3 = || {
+            target.as_ref().wait().suspend();
+            val.store(3, Ordering::Relaxed);
+        };
+        let thread_3 = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", thread_fn_3, TEST_THREAD_STACK_SIZE)
+        };
+        thread_3.lock().wake();
+        Thread::yield_current();
+
+        // Requeued threads should keep their order and end up behind the threads already waiting on the target
+        assert_eq!(2, waitlist.requeue(&target, usize::MAX));
+        assert_eq!(0, waitlist.requeue(&target, usize::MAX));
+        assert!(matches!(*thread_1.lock().state(), ThreadState::Waiting(list) if list == &*target as *const _));
+        assert_eq!(None, waitlist.wake_one());
+
+        for (i, thread) in [&thread_3, &thread_1, &thread_2].into_iter().enumerate() {
+            target.wake_one();
+            Thread::yield_current();
+            assert_eq!([3, 1, 2][i], val.load(Ordering::Relaxed));
+            assert!(matches!(*thread.lock().state(), ThreadState::Dead));
+        }
+    }
+
+    #[test_case]
+    fn test_requeue_timeout() {
+        let result = UninterruptibleSpinlock::new(None);
+        let waitlist = Box::pin(ThreadWaitList::new());
+        let target = Box::pin(ThreadWaitList::new());
+
+        let thread_fn = || {
+            *result.lock() = Some(waitlist.as_ref().wait_timeout(Duration::from_millis(5)).suspend());
+        };
+
+        let thread = unsafe {
+            Process::kernel()
+                .lock()
+                .create_kernel_thread_unchecked("test", thread_fn, TEST_THREAD_STACK_SIZE)
+        };
+        thread.lock().wake();
+
+        Thread::yield_current();
+        assert_eq!(1, waitlist.requeue(&target, 1));
+
+        // The timeout should still apply after the thread has been moved to another wait list
+        while result.lock().is_none() {
+            Thread::yield_current();
+        }
+
+        assert_eq!(Some(WaitResult::TimedOut), *result.lock());
+        assert_eq!(None, target.wake_one());
+    }
 }
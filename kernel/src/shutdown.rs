@@ -0,0 +1,118 @@
+//! Orderly kernel shutdown.
+//!
+//! Subsystems that need to perform cleanup before the machine is powered off or rebooted (e.g. flushing dirty data to disk or quiescing
+//! devices) can register shutdown hooks using [`register_hook`]. When a shutdown is requested, hooks are run one stage at a time in the
+//! order given by [`ShutdownStage`], and in registration order within a stage.
+//!
+//! Hooks are also run on a best-effort basis when the kernel panics. In this case, the kernel may be in an inconsistent state, so hooks
+//! should check the provided [`ShutdownKind`] and avoid doing anything that may block or depend on kernel data structures being consistent.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sched::task::Thread;
+use crate::sync::UninterruptibleSpinlock;
+use crate::{arch, log};
+
+/// The reason for which the kernel is shutting down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownKind {
+    /// The machine is about to be powered off.
+    PowerOff,
+    /// The machine is about to be rebooted.
+    Reboot,
+    /// The kernel has panicked and will halt once hooks have been run.
+    Panic,
+}
+
+/// The stages of the shutdown sequence. All hooks in a stage are run before any hooks in later stages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownStage {
+    /// Filesystems should be flushed and unmounted.
+    Filesystems,
+    /// Network interfaces should be stopped.
+    Network,
+    /// Application processors should be parked.
+    Processors,
+    /// Devices should be quiesced and disabled.
+    Devices,
+}
+
+/// A function to be called during the shutdown sequence.
+pub type ShutdownHook = Box<dyn Fn(ShutdownKind) + Send + Sync>;
+
+struct ShutdownHookEntry {
+    stage: ShutdownStage,
+    name: &'static str,
+    hook: ShutdownHook,
+}
+
+static HOOKS: UninterruptibleSpinlock<Vec<ShutdownHookEntry>> = UninterruptibleSpinlock::new(Vec::new());
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Registers a hook to be run during the provided stage of the shutdown sequence.
+pub fn register_hook(stage: ShutdownStage, name: &'static str, hook: ShutdownHook) {
+    HOOKS.lock().push(ShutdownHookEntry { stage, name, hook });
+}
+
+/// Checks whether a shutdown is currently in progress.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+fn run_hooks(mut hooks: Vec<ShutdownHookEntry>, kind: ShutdownKind) {
+    hooks.sort_by_key(|h| h.stage);
+
+    for h in hooks.iter() {
+        if kind != ShutdownKind::Panic {
+            log!(Debug, "shutdown", "Running {:?} shutdown hook '{}'", h.stage, h.name);
+        }
+
+        (h.hook)(kind);
+    }
+}
+
+/// Runs all registered shutdown hooks and then powers off or reboots the machine.
+///
+/// # Panics
+///
+/// This function cannot be used to handle a kernel panic and will panic if called with [`ShutdownKind::Panic`]. Use [`run_panic_hooks`]
+/// instead.
+pub fn shutdown(kind: ShutdownKind) -> ! {
+    assert_ne!(kind, ShutdownKind::Panic);
+
+    if SHUTTING_DOWN.swap(true, Ordering::Relaxed) {
+        log!(Warning, "shutdown", "Shutdown requested while already shutting down");
+        arch::halt();
+    }
+
+    log!(Notice, "shutdown", "Shutting down ({:?})", kind);
+
+    let hooks = mem::take(&mut *HOOKS.lock());
+    Thread::run_non_blocking(|| run_hooks(hooks, kind));
+
+    match kind {
+        ShutdownKind::PowerOff => arch::power_off(),
+        ShutdownKind::Reboot => arch::reboot(),
+        ShutdownKind::Panic => unreachable!(),
+    }
+}
+
+/// Runs all registered shutdown hooks on a best-effort basis after a kernel panic. If a shutdown was already in progress (including if a
+/// shutdown hook itself panicked) or the list of hooks cannot be accessed, no hooks are run.
+pub fn run_panic_hooks() {
+    if SHUTTING_DOWN.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let hooks = match HOOKS.try_lock() {
+        Some(mut hooks) => mem::take(&mut *hooks),
+        None => {
+            return;
+        },
+    };
+
+    run_hooks(hooks, ShutdownKind::Panic);
+}
@@ -6,7 +6,9 @@ use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::marker::PhantomData;
 use core::mem::MaybeUninit;
-use core::{fmt, mem, ptr};
+use core::panic::Location;
+use core::time::Duration;
+use core::{any, fmt, mem, ptr};
 
 use crate::sched;
 use crate::sched::task::Thread;
@@ -16,11 +18,309 @@ use crate::util::SyncPtr;
 
 type FutureWaitAction = dyn FnOnce(*const (), &mut FutureWaitGenericLock) + Send;
 
+/// Information about a future that has not yet been resolved, as returned by [`pending_futures`].
+#[derive(Debug, Clone, Copy)]
+pub struct PendingFutureInfo {
+    /// The address of the internal state shared by the future and its writer.
+    pub addr: usize,
+    /// The type of value that the future will resolve to.
+    pub type_name: &'static str,
+    /// The location of the code that created the future.
+    pub created_at: &'static Location<'static>,
+    /// The value of the system clock when the future was created.
+    pub created_time: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FutureTrackingDisabledError;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "future_tracking")] {
+        mod tracking {
+            use alloc::collections::BTreeMap;
+            use alloc::vec::Vec;
+            use core::panic::Location;
+
+            use super::{FutureTrackingDisabledError, PendingFutureInfo};
+            use crate::sched::timer;
+            use crate::sync::UninterruptibleSpinlock;
+
+            static PENDING_FUTURES: UninterruptibleSpinlock<BTreeMap<usize, PendingFutureInfo>> =
+                UninterruptibleSpinlock::new(BTreeMap::new());
+
+            pub fn register(addr: usize, type_name: &'static str, created_at: &'static Location<'static>) {
+                PENDING_FUTURES.lock().insert(
+                    addr,
+                    PendingFutureInfo {
+                        addr,
+                        type_name,
+                        created_at,
+                        created_time: timer::now(),
+                    },
+                );
+            }
+
+            pub fn unregister(addr: usize) {
+                PENDING_FUTURES.lock().remove(&addr);
+            }
+
+            pub fn pending_futures() -> Result<Vec<PendingFutureInfo>, FutureTrackingDisabledError> {
+                Ok(PENDING_FUTURES.lock().values().copied().collect())
+            }
+        }
+    } else {
+        mod tracking {
+            use alloc::vec::Vec;
+            use core::panic::Location;
+
+            use super::{FutureTrackingDisabledError, PendingFutureInfo};
+
+            pub fn register(_: usize, _: &'static str, _: &'static Location<'static>) {}
+            pub fn unregister(_: usize) {}
+
+            pub fn pending_futures() -> Result<Vec<PendingFutureInfo>, FutureTrackingDisabledError> {
+                Err(FutureTrackingDisabledError)
+            }
+        }
+    }
+}
+
+/// Gets information about all futures that have been created but not yet resolved, ordered by the address of their internal state. This is
+/// useful for tracking down stuck I/O requests and [`FutureWriter`]s that were leaked without ever being resolved.
+///
+/// Tracking pending futures has a noticeable performance cost, so it is only done when the `future_tracking` feature is enabled. If it is
+/// not enabled, this function returns an error.
+pub fn pending_futures() -> Result<Vec<PendingFutureInfo>, FutureTrackingDisabledError> {
+    tracking::pending_futures()
+}
+
+/// Counters describing how futures have been used since boot, as returned by [`future_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FutureStats {
+    /// The number of futures whose internal state has been created.
+    pub created: usize,
+    /// The number of futures whose internal state has been freed.
+    pub destroyed: usize,
+    /// The number of futures that have been resolved.
+    pub resolved: usize,
+    /// The number of callbacks that have been registered to run when a future is resolved.
+    pub actions_added: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FutureVerifyDisabledError;
+
+/// An operation that changes the internal state shared by a future and its writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FutureOp {
+    Create,
+    AddWaitRef,
+    DropWaitRef,
+    AddValRef,
+    DropValRef,
+    AddAction,
+    Resolve,
+    Destroy,
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "future_verify")] {
+        mod verify {
+            use core::fmt;
+            use core::sync::atomic::{AtomicUsize, Ordering};
+
+            use super::{FutureOp, FutureStats, FutureVerifyDisabledError};
+            use crate::util::ArrayDeque;
+
+            pub const ENABLED: bool = true;
+
+            /// The number of operations remembered for each future.
+            const HISTORY_LEN: usize = 16;
+
+            static CREATED: AtomicUsize = AtomicUsize::new(0);
+            static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+            static RESOLVED: AtomicUsize = AtomicUsize::new(0);
+            static ACTIONS_ADDED: AtomicUsize = AtomicUsize::new(0);
+
+            /// The most recent operations performed on a future along with the reference counts they left behind, so that the sequence of
+            /// events leading up to a broken invariant can be shown.
+            pub struct History(ArrayDeque<(FutureOp, usize, usize), HISTORY_LEN>);
+
+            impl History {
+                pub fn new() -> History {
+                    History(ArrayDeque::new())
+                }
+
+                pub fn record(&mut self, op: FutureOp, wait_refs: usize, val_refs: usize) {
+                    if self.0.is_full() {
+                        self.0.pop_front();
+                    }
+
+                    let _ = self.0.push_back((op, wait_refs, val_refs));
+
+                    let counter = match op {
+                        FutureOp::Create => &CREATED,
+                        FutureOp::Destroy => &DESTROYED,
+                        FutureOp::Resolve => &RESOLVED,
+                        FutureOp::AddAction => &ACTIONS_ADDED,
+                        _ => return,
+                    };
+
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            impl fmt::Display for History {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    for &(op, wait_refs, val_refs) in self.0.iter() {
+                        write!(f, "\n  {:?} (wait_refs {}, val_refs {})", op, wait_refs, val_refs)?;
+                    }
+
+                    Ok(())
+                }
+            }
+
+            pub fn stats() -> Result<FutureStats, FutureVerifyDisabledError> {
+                Ok(FutureStats {
+                    created: CREATED.load(Ordering::Relaxed),
+                    destroyed: DESTROYED.load(Ordering::Relaxed),
+                    resolved: RESOLVED.load(Ordering::Relaxed),
+                    actions_added: ACTIONS_ADDED.load(Ordering::Relaxed),
+                })
+            }
+        }
+    } else {
+        mod verify {
+            use core::fmt;
+
+            use super::{FutureOp, FutureStats, FutureVerifyDisabledError};
+
+            pub const ENABLED: bool = false;
+
+            pub struct History;
+
+            impl History {
+                pub fn new() -> History {
+                    History
+                }
+
+                #[inline(always)]
+                pub fn record(&mut self, _: FutureOp, _: usize, _: usize) {}
+            }
+
+            impl fmt::Display for History {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    write!(f, " (build with the future_verify feature to see the history)")
+                }
+            }
+
+            pub fn stats() -> Result<FutureStats, FutureVerifyDisabledError> {
+                Err(FutureVerifyDisabledError)
+            }
+        }
+    }
+}
+
+/// Gets counters describing how futures have been used since boot.
+///
+/// Keeping these counters requires recording every operation on every future, so it is only done when the `future_verify` feature is
+/// enabled. If it is not enabled, this function returns an error.
+pub fn future_stats() -> Result<FutureStats, FutureVerifyDisabledError> {
+    verify::stats()
+}
+
 struct FutureWaitGenericState {
     wait_refs: usize,
     val_refs: usize,
     resolved: bool,
     actions: Vec<Box<FutureWaitAction>>,
+    history: verify::History,
+}
+
+// All changes to the reference counts and resolution state of a future go through these methods, so that the invariants the manual
+// reference counting relies on can be checked. The cheap checks that guard against memory unsafety are always performed, while the others
+// are only performed with debug assertions or the future_verify feature enabled. When a check fails, the most recent operations performed
+// on the future are included in the panic message if the future_verify feature is enabled.
+impl FutureWaitGenericState {
+    fn record(&mut self, op: FutureOp) {
+        self.history.record(op, self.wait_refs, self.val_refs);
+
+        // Callbacks added by Future::when_resolved hold a value reference without a wait reference until they run
+        self.verify(
+            op,
+            self.resolved || self.val_refs <= self.wait_refs + self.actions.len(),
+            "more value references than wait references",
+        );
+    }
+
+    #[track_caller]
+    fn check(&self, op: FutureOp, cond: bool, what: &str) {
+        if !cond {
+            panic!("Future invariant violated by {:?}: {}, history:{}", op, what, self.history);
+        }
+    }
+
+    #[track_caller]
+    fn verify(&self, op: FutureOp, cond: bool, what: &str) {
+        if cfg!(debug_assertions) || verify::ENABLED {
+            self.check(op, cond, what);
+        }
+    }
+
+    #[track_caller]
+    fn add_wait_ref(&mut self) {
+        self.check(FutureOp::AddWaitRef, self.wait_refs > 0, "reference added to a freed future");
+        self.wait_refs += 1;
+        self.record(FutureOp::AddWaitRef);
+    }
+
+    /// Drops a wait reference, returning `true` if it was the last one and the future should be freed.
+    #[track_caller]
+    fn drop_wait_ref(&mut self) -> bool {
+        self.check(FutureOp::DropWaitRef, self.wait_refs > 0, "wait reference count underflow");
+        self.wait_refs -= 1;
+        self.record(FutureOp::DropWaitRef);
+        self.wait_refs == 0
+    }
+
+    #[track_caller]
+    fn add_val_ref(&mut self) {
+        self.verify(FutureOp::AddValRef, !self.resolved, "value reference added after resolution");
+        self.val_refs += 1;
+        self.record(FutureOp::AddValRef);
+    }
+
+    /// Drops a value reference, returning `true` if it was the last one and the value is no longer needed.
+    #[track_caller]
+    fn drop_val_ref(&mut self) -> bool {
+        self.check(FutureOp::DropValRef, self.val_refs > 0, "value reference count underflow");
+        self.val_refs -= 1;
+        self.record(FutureOp::DropValRef);
+        self.val_refs == 0
+    }
+
+    #[track_caller]
+    fn add_action(&mut self, action: Box<FutureWaitAction>) {
+        self.check(FutureOp::AddAction, !self.resolved, "callback added after resolution");
+        self.actions.push(action);
+        self.record(FutureOp::AddAction);
+    }
+
+    #[track_caller]
+    fn resolve(&mut self) -> Vec<Box<FutureWaitAction>> {
+        self.check(FutureOp::Resolve, !self.resolved, "future resolved twice");
+        self.resolved = true;
+        self.record(FutureOp::Resolve);
+        mem::take(&mut self.actions)
+    }
+
+    #[track_caller]
+    fn verify_destroy(&mut self) {
+        self.history.record(FutureOp::Destroy, self.wait_refs, self.val_refs);
+        self.verify(FutureOp::Destroy, self.wait_refs == 0, "freed while still referenced");
+        self.verify(FutureOp::Destroy, self.val_refs == 0, "freed while its value is still referenced");
+        self.verify(FutureOp::Destroy, self.actions.is_empty(), "freed with callbacks that never ran");
+    }
 }
 
 struct FutureWaitGeneric {
@@ -61,42 +361,51 @@ pub struct FutureWait<T> {
 }
 
 impl<T> FutureWait<T> {
+    #[track_caller]
     fn new(wait_refs: usize, val_refs: usize) -> *mut FutureWait<T> {
-        Box::into_raw(Box::new(FutureWait {
+        let ptr = Box::into_raw(Box::new(FutureWait {
             generic: FutureWaitGeneric {
                 state: UninterruptibleSpinlock::new(FutureWaitGenericState {
                     wait_refs,
                     val_refs,
                     resolved: false,
                     actions: vec![],
+                    history: verify::History::new(),
                 }),
                 wait: ThreadWaitList::new(),
             },
             val: UnsafeCell::new(MaybeUninit::uninit()),
-        }))
+        }));
+
+        // SAFETY: The future was just created, so nothing else can be using it
+        unsafe {
+            (*ptr).generic.state.lock().record(FutureOp::Create);
+        }
+
+        tracking::register(ptr as usize, any::type_name::<T>(), Location::caller());
+        ptr
     }
 
     unsafe fn destroy(ptr: *const FutureWait<T>) {
+        // A future is normally unregistered when it's resolved, but unresolved futures can still be destroyed if there are no readers left
+        tracking::unregister(ptr as usize);
+        (*ptr).generic.state.lock().verify_destroy();
         drop(Box::from_raw(ptr as *mut FutureWait<T>));
     }
 
     unsafe fn dec_val_ref(&self, lock: &mut FutureWaitGenericLock) {
         assert!(self.generic.state.is_guarded_by(&lock.state));
-        assert!(lock.state.val_refs > 0);
 
-        lock.state.val_refs -= 1;
-        if lock.state.resolved && lock.state.val_refs == 0 {
+        if lock.state.drop_val_ref() && lock.state.resolved {
             ptr::read((*self.val.get()).as_ptr());
         }
     }
 
     unsafe fn take_val(&self, lock: &mut FutureWaitGenericLock) -> T {
         assert!(self.generic.state.is_guarded_by(&lock.state));
-        assert!(lock.state.val_refs > 0);
         assert!(lock.state.resolved);
 
-        lock.state.val_refs -= 1;
-        if lock.state.val_refs == 0 {
+        if lock.state.drop_val_ref() {
             ptr::read((*self.val.get()).as_ptr())
         } else {
             crate::util::clone_or_panic(&*(*self.val.get()).as_ptr())
@@ -205,8 +514,8 @@ impl<T: Send + Sync + Clone> Clone for FutureInternal<T> {
                 if lock.state.resolved {
                     FutureInternal::Done(crate::util::clone_or_panic((*(*ptr).val.get()).assume_init_ref()))
                 } else {
-                    lock.state.wait_refs += 1;
-                    lock.state.val_refs += 1;
+                    lock.state.add_wait_ref();
+                    lock.state.add_val_ref();
                     FutureInternal::Unresolved(FutureInternalUnresolved::WithVal(ptr))
                 }
             },
@@ -216,7 +525,7 @@ impl<T: Send + Sync + Clone> Clone for FutureInternal<T> {
                 if lock.state.resolved {
                     FutureInternal::Done(crate::util::unit_or_panic())
                 } else {
-                    lock.state.wait_refs += 1;
+                    lock.state.add_wait_ref();
                     FutureInternal::Unresolved(FutureInternalUnresolved::WithoutVal(ptr, free))
                 }
             },
@@ -247,6 +556,7 @@ impl<T> Future<T> {
     ///
     /// If there's a need to create a [`FutureWriter`] without immediately requiring an associated [`Future`], it's generally preferred to
     /// call [`FutureWriter::new`].
+    #[track_caller]
     pub fn new() -> (Future<T>, FutureWriter<T>) {
         let wait = FutureWait::new(2, 1);
 
@@ -376,7 +686,7 @@ impl<T> Future<T> {
                 match unsafe { unresolved.try_resolve() } {
                     Ok(val) => f(val),
                     Err((unresolved, mut lock)) => {
-                        lock.state.actions.push(Box::new(move |ptr, lock| {
+                        lock.state.add_action(Box::new(move |ptr, lock| {
                             let val = if has_val {
                                 unsafe { (*(ptr as *const FutureWait<T>)).take_val(lock) }
                             } else {
@@ -386,7 +696,11 @@ impl<T> Future<T> {
                             f(val);
                         }));
 
-                        lock.state.wait_refs -= 1;
+                        // The callback keeps this future's value reference, but not its wait reference, since the writer's wait
+                        // reference keeps the state alive until the callback has run
+                        let was_last = lock.state.drop_wait_ref();
+
+                        lock.state.verify(FutureOp::DropWaitRef, !was_last, "callback outlives its future");
                         mem::forget(unresolved);
                     },
                 }
@@ -408,7 +722,7 @@ impl<T> Future<T> {
                 if lock.state.resolved {
                     Future::done(())
                 } else {
-                    lock.state.wait_refs += 1;
+                    lock.state.add_wait_ref();
                     let generic_ptr = &(*ptr).generic as *const _;
                     assert_eq!(generic_ptr as *const (), ptr as *const ());
                     Future(FutureInternal::Unresolved(FutureInternalUnresolved::WithoutVal(
@@ -418,7 +732,7 @@ impl<T> Future<T> {
                 }
             },
             FutureInternal::Unresolved(FutureInternalUnresolved::WithoutVal(ptr, free)) => unsafe {
-                (*ptr).lock().state.wait_refs += 1;
+                (*ptr).lock().state.add_wait_ref();
                 Future(FutureInternal::Unresolved(FutureInternalUnresolved::WithoutVal(ptr, free)))
             },
             FutureInternal::Done(_) => Future::done(()),
@@ -427,8 +741,7 @@ impl<T> Future<T> {
     }
 
     unsafe fn dec_wait_ref(ptr: *const FutureWait<T>, mut wait: FutureWaitGenericLock) {
-        wait.state.wait_refs -= 1;
-        if wait.state.wait_refs == 0 {
+        if wait.state.drop_wait_ref() {
             drop(wait);
             FutureWait::destroy(ptr);
         }
@@ -452,8 +765,7 @@ impl<T: Send + 'static> Future<T> {
 
 impl Future<()> {
     unsafe fn dec_wait_ref_generic(ptr: *const FutureWaitGeneric, free: fn(*const FutureWaitGeneric), mut wait: FutureWaitGenericLock) {
-        wait.state.wait_refs -= 1;
-        if wait.state.wait_refs == 0 {
+        if wait.state.drop_wait_ref() {
             drop(wait);
             (free)(ptr);
         }
@@ -514,7 +826,7 @@ impl Future<()> {
             was_empty = false;
 
             unsafe {
-                (*wait.unwrap()).generic.lock().state.wait_refs += 1;
+                (*wait.unwrap()).generic.lock().state.add_wait_ref();
             }
 
             f.when_resolved(move |_| unsafe {
@@ -530,7 +842,9 @@ impl Future<()> {
         }
 
         unsafe {
-            (*wait.unwrap()).generic.lock().state.wait_refs -= 1;
+            let was_last = (*wait.unwrap()).generic.lock().state.drop_wait_ref();
+
+            assert!(!was_last);
         }
 
         if !was_empty {
@@ -584,9 +898,10 @@ pub struct FutureWriter<T> {
 
 impl<T> FutureWriter<T> {
     unsafe fn finish_internal(ptr: *const FutureWait<T>, mut wait: FutureWaitGenericLock) {
-        wait.state.resolved = true;
+        let actions = wait.state.resolve();
+
+        tracking::unregister(ptr as usize);
 
-        let actions = mem::take(&mut wait.state.actions);
         if !actions.is_empty() {
             Thread::run_non_blocking(|| {
                 for a in actions.into_iter() {
@@ -606,6 +921,7 @@ impl<T> FutureWriter<T> {
     /// create a [`Future`] for the returned writer.
     ///
     /// If a [`Future`] that would resolve from this writer is desired immediately, it's preferred to call [`Future::new`] instead.
+    #[track_caller]
     pub fn new() -> FutureWriter<T> {
         FutureWriter {
             wait: FutureWait::new(1, 0),
@@ -645,8 +961,8 @@ impl<T: Send + Sync + Clone> FutureWriter<T> {
     pub fn as_future(&self) -> Future<T> {
         let mut guard = unsafe { (*self.wait).generic.lock() };
 
-        guard.state.wait_refs += 1;
-        guard.state.val_refs += 1;
+        guard.state.add_wait_ref();
+        guard.state.add_val_ref();
 
         Future(FutureInternal::Unresolved(FutureInternalUnresolved::WithVal(self.wait)))
     }
@@ -692,6 +1008,27 @@ mod test {
         assert!(ACTION_RUN_FLAG.load(Ordering::Relaxed));
     }
 
+    #[test_case]
+    fn test_pending_futures() {
+        let (future, writer) = Future::<u32>::new();
+        let addr = writer.wait as usize;
+
+        if let Ok(pending) = pending_futures() {
+            let info = pending.iter().find(|f| f.addr == addr).expect("future was not registered");
+
+            assert_eq!(file!(), info.created_at.file());
+            assert_eq!("u32", info.type_name);
+        }
+
+        writer.finish(0xdead);
+
+        if let Ok(pending) = pending_futures() {
+            assert!(!pending.iter().any(|f| f.addr == addr));
+        }
+
+        assert_eq!(0xdead, future.unwrap_blocking());
+    }
+
     #[test_case]
     fn test_is_ready() {
         let (mut future, writer) = Future::new();
@@ -883,4 +1220,28 @@ mod test {
     fn test_any_empty() {
         assert!(Future::any([]).is_err());
     }
+
+    #[test_case]
+    fn test_stats() {
+        let before = match future_stats() {
+            Ok(stats) => stats,
+            Err(_) => {
+                crate::test_util::skip("future verification is not enabled");
+                return;
+            },
+        };
+
+        let (future, writer) = Future::new();
+
+        future.when_resolved(|_: u32| {});
+        writer.finish(0);
+
+        // Other threads may be creating and resolving futures at the same time
+        let after = future_stats().unwrap();
+
+        assert!(after.created > before.created);
+        assert!(after.destroyed > before.destroyed);
+        assert!(after.resolved > before.resolved);
+        assert!(after.actions_added > before.actions_added);
+    }
 }
@@ -0,0 +1,179 @@
+//! Interrupt priority levels.
+//!
+//! Every IRQ is assigned an [`InterruptLevel`] using [`interrupt::set_irq_level`] and is only delivered while the current level is below
+//! its own. Code that shares data with the handlers of low-priority IRQs can therefore raise the level using [`raise`] rather than disabling
+//! interrupts entirely using an [`InterruptDisabler`](super::uninterruptible::InterruptDisabler), which keeps more urgent IRQs (e.g. the
+//! timer or audio DMA) from being delayed by the critical section.
+//!
+//! Running above [`InterruptLevel::Passive`] is still a critical section: the current thread must not block, it is never preempted, and
+//! soft interrupts are held back until the level is lowered again, since they could touch the data that the raised level is protecting.
+//! IRQ handlers themselves run with interrupts disabled, so a handler is never preempted by an IRQ of a higher level.
+
+use core::cell::Cell;
+
+use crate::arch::interrupt;
+use crate::sched;
+
+/// A priority level for IRQs and the code that they can interrupt, from lowest to highest priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InterruptLevel {
+    /// The level at which normal code runs, where every IRQ can be delivered.
+    Passive,
+    /// The level of most device IRQs, e.g. from keyboards, serial ports and disks. IRQs are at this level unless assigned another one.
+    Device,
+    /// The level of IRQs from devices that must be serviced with low latency, e.g. audio and video DMA engines.
+    Realtime,
+    /// The level of the timer IRQ that drives the system clock and the scheduler.
+    Clock,
+}
+
+impl InterruptLevel {
+    /// The number of distinct interrupt levels.
+    pub const COUNT: usize = 4;
+}
+
+crate::cpu_local! {
+    static CURRENT_LEVEL: Cell<InterruptLevel> = Cell::new(InterruptLevel::Passive);
+}
+
+/// Gets the interrupt level that the current CPU core is running at.
+pub fn current() -> InterruptLevel {
+    CURRENT_LEVEL.get()
+}
+
+/// A guard that keeps the current CPU core at a raised interrupt level while it exists. Guards must be dropped in the reverse order of
+/// their creation.
+#[must_use]
+pub struct InterruptLevelGuard {
+    level: InterruptLevel,
+    prev: InterruptLevel,
+}
+
+/// Raises the interrupt level of the current CPU core to the provided level until the returned guard is dropped. Raising the level to the
+/// current level has no effect.
+///
+/// # Panics
+///
+/// This function will panic if the current level is above the provided level, since code further up the stack relies on the IRQs at the
+/// current level being held off.
+pub fn raise(level: InterruptLevel) -> InterruptLevelGuard {
+    let prev = current();
+
+    assert!(level >= prev, "Cannot lower the interrupt level from {:?} to {:?}", prev, level);
+
+    if level != prev {
+        // An IRQ arriving before the interrupt controller is updated must already see the raised level, so that it doesn't run soft
+        // interrupts or preempt this thread
+        CURRENT_LEVEL.set(level);
+
+        // SAFETY: The current level was just changed from prev to level
+        unsafe {
+            interrupt::change_level(prev, level);
+        }
+    }
+
+    InterruptLevelGuard { level, prev }
+}
+
+/// Forcibly sets the interrupt level of the current CPU core, as if all guards raising it past the provided level had been leaked.
+///
+/// # Safety
+///
+/// This is only intended to be used when execution is being rewound past code holding [`InterruptLevelGuard`]s, such as when recovering
+/// from a panic. None of the forgotten guards may ever be dropped.
+pub(crate) unsafe fn force_reset(level: InterruptLevel) {
+    let old_level = current();
+
+    if old_level != level {
+        interrupt::change_level(old_level, level);
+        CURRENT_LEVEL.set(level);
+    }
+}
+
+impl !Send for InterruptLevelGuard {}
+
+impl Drop for InterruptLevelGuard {
+    fn drop(&mut self) {
+        assert_eq!(self.level, current(), "Interrupt level guards must be dropped in reverse order");
+
+        if self.level != self.prev {
+            // SAFETY: The current level is about to be changed from self.level to self.prev
+            unsafe {
+                interrupt::change_level(self.level, self.prev);
+            }
+
+            CURRENT_LEVEL.set(self.prev);
+
+            if self.prev == InterruptLevel::Passive && !sched::is_handling_interrupt() && interrupt::are_enabled() {
+                sched::run_soft_interrupts();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_raise_level() {
+        assert_eq!(InterruptLevel::Passive, current());
+
+        {
+            let _device = raise(InterruptLevel::Device);
+
+            assert_eq!(InterruptLevel::Device, current());
+
+            {
+                let _clock = raise(InterruptLevel::Clock);
+                let _same = raise(InterruptLevel::Clock);
+
+                assert_eq!(InterruptLevel::Clock, current());
+            }
+
+            assert_eq!(InterruptLevel::Device, current());
+        }
+
+        assert_eq!(InterruptLevel::Passive, current());
+    }
+
+    #[cfg(not(feature = "real_arch_api"))]
+    #[test_case]
+    fn test_irqs_held_off() {
+        use alloc::boxed::Box;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        const IRQ: usize = 9;
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe {
+            interrupt::register_irq(
+                IRQ,
+                Box::new(|_| {
+                    COUNT.fetch_add(1, Ordering::Relaxed);
+                }),
+            );
+        }
+
+        interrupt::set_irq_level(IRQ, InterruptLevel::Realtime);
+
+        {
+            let _device = raise(InterruptLevel::Device);
+
+            interrupt::inject_irq(IRQ);
+            assert_eq!(1, COUNT.load(Ordering::Relaxed));
+
+            let _realtime = raise(InterruptLevel::Realtime);
+
+            interrupt::inject_irq(IRQ);
+            assert_eq!(1, COUNT.load(Ordering::Relaxed));
+        }
+
+        assert_eq!(2, COUNT.load(Ordering::Relaxed));
+
+        interrupt::set_irq_level(IRQ, InterruptLevel::Device);
+        unsafe {
+            interrupt::unregister_irq(IRQ);
+        }
+    }
+}
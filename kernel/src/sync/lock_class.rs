@@ -0,0 +1,102 @@
+//! Lock classes used to verify the order in which spinlocks are acquired.
+//!
+//! Spinlocks protecting related kernel data structures frequently need to be held at the same time, e.g. the scheduler must lock a process
+//! and one of its threads together when enqueueing the thread as ready to run. In order to avoid deadlocks, all code must agree on the order
+//! in which such locks are acquired. Rather than leaving these rules documented only in comments, spinlocks can be assigned a
+//! [`LockClass`] when they are created. Each lock class has a level, and a lock may only be acquired while holding locks from classes with
+//! a strictly lower level. In particular, this means that two locks of the same class may never be held at the same time.
+//!
+//! When the kernel is compiled with debug assertions and spinlock tracking enabled, attempting to acquire a spinlock in violation of these
+//! rules will result in a panic, even if no deadlock actually occurs. Spinlocks that are not assigned a class are not checked.
+
+use core::fmt;
+
+/// A class of spinlocks that share the same position in the kernel's lock ordering.
+pub struct LockClass {
+    name: &'static str,
+    level: u32,
+}
+
+impl LockClass {
+    /// Creates a new lock class with the provided name and level. Lock classes should generally be declared using [`lock_classes!`]
+    /// rather than by calling this directly, so that the relative ordering of all classes is visible in one place.
+    pub const fn new(name: &'static str, level: u32) -> LockClass {
+        LockClass { name, level }
+    }
+
+    /// Gets the human-readable name of this lock class.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Gets the level of this lock class. Locks from this class may only be acquired while holding locks from classes of lower levels.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// Checks whether it is permissible to acquire a lock of this class while a lock of the provided class is held.
+    pub fn may_nest_in(&self, held: &LockClass) -> bool {
+        self.level > held.level
+    }
+}
+
+impl fmt::Debug for LockClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LockClass({}, level {})", self.name, self.level)
+    }
+}
+
+/// Declares a list of lock classes, assigning each a level according to the order in which it was declared. Locks belonging to classes
+/// declared earlier in the list must be acquired before locks belonging to classes declared later.
+///
+/// ```ignore
+/// lock_classes! {
+///     /// Acquired first.
+///     pub OUTER;
+///     /// May be acquired while holding `OUTER`.
+///     pub INNER;
+/// }
+/// ```
+macro_rules! lock_classes {
+    (@level $level:expr;) => {};
+    (@level $level:expr; $(#[$attr:meta])* $vis:vis $name:ident; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis const $name: $crate::sync::lock_class::LockClass = $crate::sync::lock_class::LockClass::new(stringify!($name), $level);
+
+        lock_classes!(@level $level + 1; $($rest)*);
+    };
+    ($($rest:tt)*) => {
+        lock_classes!(@level 0; $($rest)*);
+    };
+}
+
+pub(crate) use lock_classes;
+
+lock_classes! {
+    /// The lock on the global list of processes.
+    pub PROCESS_LIST;
+    /// The locks protecting the mutable state of a [`Process`](crate::sched::task::Process).
+    pub PROCESS;
+    /// The locks protecting the mutable state of a [`Thread`](crate::sched::task::Thread).
+    pub THREAD;
+    /// The locks on [`ThreadWaitList`](crate::sched::wait::ThreadWaitList)s. A thread is locked while it is added to or removed from a
+    /// wait list, so threads being woken are dequeued first and only locked once the wait list has been released.
+    pub WAIT_LIST;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_lock_class_order() {
+        assert!(PROCESS.may_nest_in(&PROCESS_LIST));
+        assert!(THREAD.may_nest_in(&PROCESS));
+        assert!(WAIT_LIST.may_nest_in(&THREAD));
+
+        assert!(!PROCESS.may_nest_in(&THREAD));
+        assert!(!THREAD.may_nest_in(&THREAD));
+        assert!(!THREAD.may_nest_in(&WAIT_LIST));
+        assert!(!PROCESS.may_nest_in(&WAIT_LIST));
+    }
+}
@@ -5,6 +5,8 @@
 //! between different threads/cores running kernel code.
 
 pub mod future;
+pub mod level;
+pub mod lock_class;
 pub mod mutex;
 pub mod uninterruptible;
 
@@ -2,10 +2,10 @@ use alloc::sync::Arc;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::pin::Pin;
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::sched::task::Thread;
+use crate::sched::task::{Thread, ThreadPriority};
 use crate::sched::wait::ThreadWaitList;
 use crate::sync::uninterruptible::InterruptDisabler;
 
@@ -49,7 +49,19 @@ impl MutexLockState {
     }
 }
 
-struct MutexLock {
+/// The maximum number of threads along a chain of threads waiting on each other's mutexes that an inherited priority is passed to. This
+/// bounds the work done when blocking on a mutex, including when a chain loops back on itself because of a deadlock.
+const MAX_INHERITANCE_DEPTH: usize = 8;
+
+/// The lock underlying a [`Mutex`].
+///
+/// To prevent priority inversion, a thread that blocks on a mutex lends its priority to the thread holding it. If that thread is itself
+/// blocked on another mutex, the priority is passed on to that mutex's owner and so on. A thread keeps any priority it inherited until it
+/// has released every mutex it holds, at which point it returns to its base priority. When a mutex is handed over to the next waiter,
+/// that waiter inherits the highest priority of the threads still waiting behind it.
+///
+/// Uninterruptible spinlocks need no such handling, since a thread holding one cannot be preempted.
+pub(crate) struct MutexLock {
     state: AtomicUsize,
     wait: ThreadWaitList,
 }
@@ -107,6 +119,18 @@ impl MutexLock {
                         .map_err(MutexLockState::from_usize)
                     {
                         Ok(_) => {
+                            let priority = {
+                                let mut thread_lock = thread.lock();
+
+                                thread_lock.set_blocked_on(Some(NonNull::from(self)));
+                                thread_lock.priority()
+                            };
+
+                            // SAFETY: This mutex was just placed in the LockedWaitersLocked state by the current thread
+                            unsafe {
+                                self.propagate_priority(priority);
+                            }
+
                             let suspend = self.wait.wait();
 
                             match self
@@ -153,6 +177,83 @@ impl MutexLock {
         }
     }
 
+    /// Lends the provided priority to the owner of this mutex, then passes it along the chain of mutexes that the owner is blocked on.
+    ///
+    /// Each owner is boosted while the mutex it holds is in the LockedWaitersLocked state, so it can neither release that mutex nor exit
+    /// until it has been boosted. Further along the chain, a mutex whose wait list is already in use by another thread is skipped rather
+    /// than waited for, since that thread may need a thread lock held here or may be part of a deadlocked chain. In that case, the priority
+    /// is not passed any further.
+    ///
+    /// # Safety
+    ///
+    /// This mutex must be in the LockedWaitersLocked state, having been placed in it by the current thread.
+    unsafe fn propagate_priority(&self, priority: ThreadPriority) {
+        let mut lock = self;
+
+        for _ in 0..MAX_INHERITANCE_DEPTH {
+            let owner = lock.get_state().owner().unwrap();
+
+            // SAFETY: The owner cannot release this mutex while it is in the LockedWaitersLocked state, and a thread cannot exit while it
+            //         still holds a mutex, so the owner must still be alive.
+            let owner = unsafe { owner.as_ref() };
+            let next = if owner.inherit_priority(priority) {
+                let owner_lock = owner.lock();
+
+                // SAFETY: A mutex cannot be dropped while a thread is blocked on it, and the owner cannot stop being blocked on it while
+                //         the owner is locked, since handing the mutex over to it requires locking it.
+                owner_lock
+                    .blocked_on()
+                    .map(|next| unsafe { next.as_ref() })
+                    .filter(|next| next.try_lock_waiters())
+            } else {
+                None
+            };
+
+            if !ptr::eq(lock, self) {
+                lock.unlock_waiters();
+            }
+
+            match next {
+                Some(next) => {
+                    lock = next;
+                },
+                None => return,
+            }
+        }
+
+        if !ptr::eq(lock, self) {
+            lock.unlock_waiters();
+        }
+    }
+
+    /// Attempts to move this mutex from the LockedWithWaiters state into the LockedWaitersLocked state without waiting, returning `false`
+    /// if it is in any other state. The mutex must later be returned to the LockedWithWaiters state by calling
+    /// [`MutexLock::unlock_waiters`].
+    fn try_lock_waiters(&self) -> bool {
+        let MutexLockState::LockedWithWaiters(owner) = self.get_state() else {
+            return false;
+        };
+
+        self.state
+            .compare_exchange(
+                MutexLockState::LockedWithWaiters(owner).into_usize(),
+                MutexLockState::LockedWaitersLocked(owner).into_usize(),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    /// Returns this mutex to the LockedWithWaiters state after a successful call to [`MutexLock::try_lock_waiters`].
+    fn unlock_waiters(&self) {
+        let MutexLockState::LockedWaitersLocked(owner) = self.get_state() else {
+            panic!("Mutex state modified from {:?} while its wait list was locked", self.get_state());
+        };
+
+        self.state
+            .store(MutexLockState::LockedWithWaiters(owner).into_usize(), Ordering::Release);
+    }
+
     fn acquire(&self) {
         let thread = Thread::current();
 
@@ -162,10 +263,19 @@ impl MutexLock {
                 self.acquire_slow(&thread, state);
             },
         }
+
+        thread.mutex_acquired();
     }
 
     fn try_acquire(&self) -> bool {
-        self.try_acquire_fast(&Thread::current()).is_ok()
+        let thread = Thread::current();
+
+        if self.try_acquire_fast(&thread).is_ok() {
+            thread.mutex_acquired();
+            true
+        } else {
+            false
+        }
     }
 
     #[cold]
@@ -190,6 +300,12 @@ impl MutexLock {
                     {
                         Ok(_) => {
                             let new_state = if let Some(next_owner) = self.wait.wake_one() {
+                                next_owner.lock().set_blocked_on(None);
+
+                                if let Some(priority) = self.wait.highest_priority() {
+                                    next_owner.inherit_priority(priority);
+                                }
+
                                 MutexLockState::LockedWithWaiters(NonNull::from(&*next_owner))
                             } else {
                                 MutexLockState::Unlocked
@@ -234,6 +350,8 @@ impl MutexLock {
                 self.release_slow(&thread, state);
             },
         }
+
+        thread.mutex_released();
     }
 }
 
@@ -311,8 +429,11 @@ impl<'a, T: Send> Drop for MutexGuard<'a, T> {
 
 #[cfg(test)]
 mod test {
+    use alloc::vec::Vec;
+
     use super::*;
     use crate::sched::task::{Process, ThreadState};
+    use crate::sync::UninterruptibleSpinlock;
 
     #[test_case]
     fn test_basics() {
@@ -339,6 +460,7 @@ mod test {
         let mutex = Mutex::new(0);
         let thread = unsafe {
             Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
                 || {
                     let mut guard = mutex.lock();
                     *guard = 1;
@@ -373,4 +495,149 @@ mod test {
         assert!(!mutex.is_locked());
         assert_eq!(ThreadState::Dead, *thread.lock().state());
     }
+
+    #[test_case]
+    fn test_priority_inheritance() {
+        let mutex = Mutex::new(());
+        let guard = mutex.lock();
+        let thread = unsafe {
+            Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
+                || {
+                    drop(mutex.lock());
+                },
+                4096,
+            )
+        };
+
+        thread.lock().set_priority(ThreadPriority::High);
+        thread.lock().wake();
+        Thread::yield_current();
+
+        assert!(matches!(*thread.lock().state(), ThreadState::Waiting(_)));
+        assert_eq!(ThreadPriority::High, Thread::current().lock().priority());
+        assert_eq!(ThreadPriority::Normal, Thread::current().lock().base_priority());
+
+        drop(guard);
+        assert_eq!(ThreadPriority::Normal, Thread::current().lock().priority());
+        assert_eq!(None, Thread::current().lock().inherited_priority());
+
+        Thread::yield_current();
+        assert_eq!(ThreadState::Dead, *thread.lock().state());
+    }
+
+    #[test_case]
+    fn test_chained_priority_inheritance() {
+        let outer = Mutex::new(());
+        let inner = Mutex::new(());
+        let order = UninterruptibleSpinlock::new(Vec::new());
+        let guard = outer.lock();
+
+        let mid_thread = unsafe {
+            Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
+                || {
+                    let inner_guard = inner.lock();
+                    let outer_guard = outer.lock();
+
+                    order.lock().push(ThreadPriority::Normal);
+                    drop(outer_guard);
+                    drop(inner_guard);
+                },
+                4096,
+            )
+        };
+        let high_thread = unsafe {
+            Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
+                || {
+                    drop(inner.lock());
+                    order.lock().push(ThreadPriority::High);
+                },
+                4096,
+            )
+        };
+
+        mid_thread.lock().wake();
+        Thread::yield_current();
+        assert!(matches!(*mid_thread.lock().state(), ThreadState::Waiting(_)));
+        assert_eq!(None, Thread::current().lock().inherited_priority());
+
+        // The high priority thread blocks on the inner mutex held by the middle thread, which is itself blocked on the outer mutex held by
+        // the current thread, so both should inherit its priority
+        high_thread.lock().set_priority(ThreadPriority::High);
+        high_thread.lock().wake();
+        Thread::yield_current();
+        assert!(matches!(*high_thread.lock().state(), ThreadState::Waiting(_)));
+        assert_eq!(ThreadPriority::High, mid_thread.lock().priority());
+        assert_eq!(ThreadPriority::High, Thread::current().lock().priority());
+
+        drop(guard);
+        assert_eq!(ThreadPriority::Normal, Thread::current().lock().priority());
+
+        Thread::yield_current();
+        Thread::yield_current();
+
+        assert_eq!(&[ThreadPriority::Normal, ThreadPriority::High][..], &order.lock()[..]);
+        assert_eq!(ThreadState::Dead, *mid_thread.lock().state());
+        assert_eq!(ThreadState::Dead, *high_thread.lock().state());
+        assert_eq!(ThreadPriority::Normal, mid_thread.lock().priority());
+    }
+
+    #[test_case]
+    fn test_priority_inheritance_ready_owner() {
+        let mutex = Mutex::new(());
+        let order = UninterruptibleSpinlock::new(Vec::new());
+
+        let owner_thread = unsafe {
+            Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
+                || {
+                    let guard = mutex.lock();
+
+                    order.lock().push(1);
+                    Thread::yield_current();
+                    order.lock().push(2);
+                    drop(guard);
+                },
+                4096,
+            )
+        };
+        let high_thread = unsafe {
+            Process::kernel().lock().create_kernel_thread_unchecked(
+                "test",
+                || {
+                    drop(mutex.lock());
+                    order.lock().push(3);
+                },
+                4096,
+            )
+        };
+
+        owner_thread.lock().wake();
+        Thread::yield_current();
+        assert!(mutex.is_locked());
+        assert_eq!(ThreadState::Ready, *owner_thread.lock().state());
+
+        // The owner inherits the high priority thread's priority while it is sitting in a ready queue, which must move it onto the queue
+        // for its new priority so that yielding to it can find it there
+        Thread::current().lock().set_priority(ThreadPriority::High);
+        high_thread.lock().set_priority(ThreadPriority::High);
+        high_thread.lock().wake();
+        Thread::yield_current();
+        assert!(matches!(*high_thread.lock().state(), ThreadState::Waiting(_)));
+        assert_eq!(ThreadState::Ready, *owner_thread.lock().state());
+        assert_eq!(ThreadPriority::High, owner_thread.lock().priority());
+
+        Thread::yield_to(&owner_thread);
+        assert_eq!(ThreadState::Dead, *owner_thread.lock().state());
+        assert_eq!(ThreadPriority::Normal, owner_thread.lock().priority());
+
+        Thread::current().lock().set_priority(ThreadPriority::Normal);
+        Thread::yield_current();
+
+        assert_eq!(&[1, 2, 3][..], &order.lock()[..]);
+        assert_eq!(ThreadState::Dead, *high_thread.lock().state());
+        assert!(!mutex.is_locked());
+    }
 }
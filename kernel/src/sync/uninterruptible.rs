@@ -29,12 +29,14 @@ use core::cell::{Cell, SyncUnsafeCell};
 use core::ops::{Deref, DerefMut};
 use core::{mem, ptr};
 
+use super::lock_class::LockClass;
 use crate::arch::interrupt;
 use crate::sched;
 use crate::util::DebugOrDefault;
 
-#[thread_local]
-static INTERRUPT_DISABLER_STATE: Cell<(usize, bool)> = Cell::new((0, false));
+crate::cpu_local! {
+    static INTERRUPT_DISABLER_STATE: Cell<(usize, bool)> = Cell::new((0, false));
+}
 
 /// A guard that keeps interrupts disabled on the current CPU core while it exists.
 pub struct InterruptDisabler(());
@@ -98,6 +100,19 @@ impl InterruptDisabler {
         interrupt::enable();
     }
 
+    /// Forcibly resets the number of interrupt-disabling guards held on the current CPU core to `n`, as if all guards created since the
+    /// count was last `n` had been leaked. Interrupts are left disabled, even if `n` is zero.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that no code that created one of the forgotten guards will ever run again, since the guards would otherwise be
+    /// dropped twice.
+    pub unsafe fn force_reset_num_held(n: usize) {
+        let (_, was_enabled) = INTERRUPT_DISABLER_STATE.get();
+
+        INTERRUPT_DISABLER_STATE.set((n, was_enabled));
+    }
+
     /// Drops this interrupt-disabling guard without actually enabling interrupts. Returns `true` if interrupts would have been enabled had
     /// this guard been dropped normally and `false` otherwise.
     pub fn drop_without_enable(self) -> bool {
@@ -145,11 +160,10 @@ cfg_if::cfg_if! {
 
             const MAX_HELD_LOCKS: usize = 64;
 
-            #[thread_local]
-            static HELD_LOCKS: UnsafeCell<[*const RawSpinlock; MAX_HELD_LOCKS]> = UnsafeCell::new([ptr::null(); MAX_HELD_LOCKS]);
-
-            #[thread_local]
-            static HELD_LOCKS_LEN: Cell<usize> = Cell::new(0);
+            crate::cpu_local! {
+                static HELD_LOCKS: UnsafeCell<[*const RawSpinlock; MAX_HELD_LOCKS]> = UnsafeCell::new([ptr::null(); MAX_HELD_LOCKS]);
+                static HELD_LOCKS_LEN: Cell<usize> = Cell::new(0);
+            }
 
             pub unsafe fn held_spinlocks() -> Result<&'static [*const RawSpinlock], SpinlockTrackingDisabledError> {
                 Ok(unsafe { &(*HELD_LOCKS.get())[..HELD_LOCKS_LEN.get()] })
@@ -161,6 +175,30 @@ cfg_if::cfg_if! {
                 }
             }
 
+            pub fn check_spinlock_order(lock: *const RawSpinlock) {
+                if !cfg!(debug_assertions) {
+                    return;
+                }
+
+                let Some(class) = (unsafe { (*lock).1 }) else {
+                    return;
+                };
+
+                for &held in unsafe { &(*HELD_LOCKS.get())[..HELD_LOCKS_LEN.get()] } {
+                    if let Some(held_class) = unsafe { (*held).1 } {
+                        if !class.may_nest_in(held_class) {
+                            panic!(
+                                "Lock order violation: attempt to acquire spinlock {:?} of class {} while holding spinlock {:?} of class {}",
+                                lock,
+                                class.name(),
+                                held,
+                                held_class.name()
+                            );
+                        }
+                    }
+                }
+            }
+
             pub fn push_spinlock(lock: *const RawSpinlock) {
                 if HELD_LOCKS_LEN.get() == MAX_HELD_LOCKS {
                     panic!("Acquired too many spinlocks!");
@@ -185,12 +223,13 @@ cfg_if::cfg_if! {
         }
     } else {
         mod tracking {
-            use super::SpinlockTrackingDisabledError;
+            use super::{RawSpinlock, SpinlockTrackingDisabledError};
 
             pub unsafe fn held_spinlocks() -> Result<&'static [*const RawSpinlock], SpinlockTrackingDisabledError> {
                 Err(SpinlockTrackingDisabledError)
             }
             pub fn check_spinlock_for_deadlock(_: *const RawSpinlock) {}
+            pub fn check_spinlock_order(_: *const RawSpinlock) {}
             pub fn push_spinlock(_: *const RawSpinlock) {}
             pub fn pop_spinlock(_: *const RawSpinlock) {}
         }
@@ -202,12 +241,23 @@ cfg_if::cfg_if! {
 /// Note that this implementation **does not** automatically disable interrupts when it is held, so
 /// it should not be used to protect access to any data that may be needed from within an interrupt
 /// handler unless a separate [`InterruptDisabler`] is used.
-pub struct RawSpinlock(spin::Mutex<()>);
+pub struct RawSpinlock(spin::Mutex<()>, Option<&'static LockClass>);
 
 impl RawSpinlock {
     /// Creates a new unlocked spinlock.
     pub const fn new() -> RawSpinlock {
-        RawSpinlock(spin::Mutex::new(()))
+        RawSpinlock(spin::Mutex::new(()), None)
+    }
+
+    /// Creates a new unlocked spinlock belonging to the provided [`LockClass`]. See the documentation of the
+    /// [`lock_class`](super::lock_class) module for more information on how lock classes are used.
+    pub const fn with_class(class: &'static LockClass) -> RawSpinlock {
+        RawSpinlock(spin::Mutex::new(()), Some(class))
+    }
+
+    /// Gets the [`LockClass`] to which this spinlock belongs, if any.
+    pub fn class(&self) -> Option<&'static LockClass> {
+        self.1
     }
 
     /// Gets a list of spinlocks held by the current CPU core for debugging purposes.
@@ -251,13 +301,54 @@ impl RawSpinlock {
         Ok(())
     }
 
+    /// Unlocks all spinlocks acquired by the current CPU core after the first `n` spinlocks it currently holds.
+    ///
+    /// In the event that the kernel has been compiled with spinlock tracking disabled, this method will return
+    /// `Err(SpinlockTrackingDisabledError)` and no spinlocks will be unlocked.
+    ///
+    /// # Safety
+    ///
+    /// This has all of the same dangers as [`RawSpinlock::force_unlock_held`], but limited to the spinlocks that are unlocked. Control must
+    /// never be returned to any code that holds a guard for one of these spinlocks.
+    pub unsafe fn force_unlock_held_since(n: usize) -> Result<(), SpinlockTrackingDisabledError> {
+        while let Some(&held_spinlock) = Self::held()?.get(n..).and_then(|held| held.last()) {
+            tracking::pop_spinlock(held_spinlock);
+            (*held_spinlock).force_unlock();
+        }
+
+        Ok(())
+    }
+
     /// Locks this spinlock and returns a guard that will automatically unlock it when dropped.
     pub fn lock(&self) -> RawSpinlockGuard {
+        tracking::check_spinlock_order(self);
+
+        // SAFETY: The lock ordering was just checked
+        unsafe { self.lock_nested() }
+    }
+
+    /// Locks this spinlock in the same manner as [`RawSpinlock::lock`], but without checking that doing so respects the ordering of lock
+    /// classes. This allows multiple spinlocks of the same class to be held at the same time.
+    ///
+    /// # Safety
+    ///
+    /// All code holding multiple spinlocks of this spinlock's class at the same time must agree on the order in which they are acquired
+    /// (e.g. by address), since a deadlock may occur otherwise.
+    pub unsafe fn lock_nested(&self) -> RawSpinlockGuard {
         let guard = if let Some(guard) = self.0.try_lock() {
             guard
         } else {
             tracking::check_spinlock_for_deadlock(self);
-            self.0.lock()
+
+            loop {
+                if let Some(guard) = self.0.try_lock() {
+                    break guard;
+                }
+
+                // The core holding this lock may be waiting for this one to process a TLB shootdown
+                crate::mem::tlb::poll();
+                core::hint::spin_loop();
+            }
         };
 
         tracking::push_spinlock(self);
@@ -356,6 +447,11 @@ impl<T> UninterruptibleSpinlock<T> {
         UninterruptibleSpinlock(RawSpinlock::new(), SyncUnsafeCell::new(val))
     }
 
+    /// Creates a new uninterruptible spinlock belonging to the provided [`LockClass`] containing the provided value.
+    pub const fn with_class(val: T, class: &'static LockClass) -> UninterruptibleSpinlock<T> {
+        UninterruptibleSpinlock(RawSpinlock::with_class(class), SyncUnsafeCell::new(val))
+    }
+
     /// Consumes this [`UninterruptibleSpinlock`], returning the underlying data.
     pub fn into_inner(self) -> T {
         let val = unsafe { ptr::read(self.1.get()) };
@@ -7,7 +7,7 @@ use dyn_dyn::{dyn_dyn_cast, dyn_dyn_impl};
 
 use crate::arch::interrupt;
 use crate::io::dev::{device_root, Device, DeviceNode, DeviceRef};
-use crate::io::tty::{Tty, TtyExt, TtyWriter};
+use crate::io::tty::{Tty, TtyCapabilities, TtyExt, TtyWriter};
 use crate::sched::is_handling_interrupt;
 use crate::sched::task::{Process, Thread};
 use crate::sync::uninterruptible::InterruptDisabler;
@@ -49,6 +49,14 @@ impl Tty for TestLogTty {
     unsafe fn read(&self, _bytes: *mut [u8]) -> Future<Result<usize, ()>> {
         Future::done(Err(()))
     }
+
+    fn size(&self) -> Result<(usize, usize), ()> {
+        TEST_SERIAL.get().dev().size()
+    }
+
+    fn capabilities(&self) -> TtyCapabilities {
+        TEST_SERIAL.get().dev().capabilities()
+    }
 }
 
 #[dyn_dyn_impl(Tty)]
@@ -102,9 +110,10 @@ pub fn run_tests(tests: &'static [&dyn Test]) -> ! {
             break;
         }
 
-        let test_thread = Process::kernel()
-            .lock()
-            .create_kernel_thread(move || run_tests_thread(tests), TEST_THREAD_STACK_SIZE);
+        let test_thread =
+            Process::kernel()
+                .lock()
+                .create_kernel_thread("test runner", move || run_tests_thread(tests), TEST_THREAD_STACK_SIZE);
         let test_thread_complete = test_thread.lock().join();
 
         TEST_THREAD.store(&*test_thread as *const _ as *mut _, Ordering::Relaxed);
@@ -139,7 +148,10 @@ pub fn has_test_failed() -> bool {
 #[cfg(not(feature = "check_arch_api"))]
 pub fn exit(code: u32) -> ! {
     use crate::arch::x86_64::dev::qemu_dbg_exit::QemuExitDevice;
+    use crate::arch::x86_64::dev::serial;
 
+    // Test results are written to the serial port, so make sure they've all been sent before the emulator exits
+    serial::flush_polled();
     unsafe { QemuExitDevice::new(0xf4).exit(code) }
 }
 
@@ -149,6 +161,8 @@ pub fn exit(_code: u32) -> ! {
 }
 
 pub fn handle_test_panic(info: &PanicInfo) -> ! {
+    crate::io::dev::recovery::try_recover(info);
+
     let mut serial = TtyWriter::new(TEST_SERIAL.get().dev());
     let is_testing = IS_TESTING.swap(false, Ordering::Relaxed);
 
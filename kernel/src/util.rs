@@ -1,4 +1,7 @@
+use alloc::boxed::Box;
+use alloc::collections::TryReserveError;
 use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use core::cell::SyncUnsafeCell;
 use core::fmt;
 use core::mem::MaybeUninit;
@@ -81,6 +84,104 @@ pub fn unit_or_panic<T>() -> T {
     UnitOrPanic::unit_or_panic()
 }
 
+/// Creates a new empty vector with space for exactly `capacity` elements, returning an error instead of panicking if there is not enough
+/// memory.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, TryReserveError> {
+    let mut vec = Vec::new();
+
+    vec.try_reserve_exact(capacity)?;
+    Ok(vec)
+}
+
+/// Allocates a boxed slice containing a copy of the provided slice, returning an error instead of panicking if there is not enough memory.
+pub fn try_boxed_slice<T: Clone>(src: &[T]) -> Result<Box<[T]>, TryReserveError> {
+    let mut vec = try_vec_with_capacity(src.len())?;
+
+    vec.extend_from_slice(src);
+    Ok(vec.into_boxed_slice())
+}
+
+/// Extension methods for [`Vec`] that return an error instead of panicking when there is not enough memory to grow the vector.
+pub trait TryVecExt<T> {
+    /// Appends an element to the end of the vector. If there is not enough memory, the element is handed back instead.
+    fn try_push(&mut self, val: T) -> Result<(), T>;
+
+    /// Appends clones of all elements of the provided slice to the end of the vector. The vector is left unchanged on failure.
+    fn try_extend_from_slice(&mut self, src: &[T]) -> Result<(), TryReserveError>
+    where
+        T: Clone;
+}
+
+impl<T> TryVecExt<T> for Vec<T> {
+    fn try_push(&mut self, val: T) -> Result<(), T> {
+        match self.try_reserve(1) {
+            Ok(()) => {
+                self.push(val);
+                Ok(())
+            },
+            Err(_) => Err(val),
+        }
+    }
+
+    fn try_extend_from_slice(&mut self, src: &[T]) -> Result<(), TryReserveError>
+    where
+        T: Clone,
+    {
+        self.try_reserve(src.len())?;
+        self.extend_from_slice(src);
+        Ok(())
+    }
+}
+
+/// A static that has a separate copy for each CPU core rather than for each thread. Declared using the [`cpu_local!`](crate::cpu_local)
+/// macro.
+///
+/// Each thread has its own copy of `#[thread_local]` statics, so state belonging to the core a thread is running on (e.g. the currently
+/// running thread) must use this instead. Since the value is shared with every thread that runs on the same core, it must only be accessed
+/// in ways that cannot be interrupted by a context switch, e.g. with interrupts disabled.
+pub struct CpuLocal<T: 'static> {
+    template: fn() -> *const T,
+}
+
+// SAFETY: Each core only ever accesses its own copy of the value
+unsafe impl<T: 'static> Sync for CpuLocal<T> {}
+
+impl<T: 'static> CpuLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(template: fn() -> *const T) -> CpuLocal<T> {
+        CpuLocal { template }
+    }
+}
+
+impl<T: 'static> Deref for CpuLocal<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The template function returns a pointer to a #[thread_local] static, which has a copy in every core's TLS block
+        unsafe { &*crate::arch::tls::cpu_local_ptr((self.template)()) }
+    }
+}
+
+/// Declares statics that have a separate copy for each CPU core. See [`CpuLocal`] for details.
+#[macro_export]
+macro_rules! cpu_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::util::CpuLocal<$ty> = {
+                #[thread_local]
+                static TEMPLATE: $ty = $init;
+
+                fn template() -> *const $ty {
+                    ::core::ptr::addr_of!(TEMPLATE)
+                }
+
+                $crate::util::CpuLocal::new(template)
+            };
+        )*
+    };
+}
+
 #[derive(Debug, Clone)]
 pub struct PinWeak<T: ?Sized>(Weak<T>);
 